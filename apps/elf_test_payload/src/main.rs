@@ -0,0 +1,34 @@
+// ELFローダーのテストで読み込んで実行する小さなプログラム
+// カーネルのテスト（src/elf.rs）がビルドして埋め込む
+#![no_std]
+#![no_main]
+
+use core::ptr::addr_of_mut;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+
+// 正しく実行できたときに返す値（"WASABIEL"）
+const MAGIC: u64 = 0x5741_5341_4249_454c;
+
+// 0で初期化される領域（.bssに置かれる）
+// ローダーがファイルにない部分を0で埋めているか確かめる
+static mut SCRATCH: [u64; 512] = [0; 512];
+
+#[no_mangle]
+pub extern "sysv64" fn _start() -> u64 {
+    let scratch = addr_of_mut!(SCRATCH) as *mut u64;
+    for i in 0..512 {
+        unsafe {
+            if read_volatile(scratch.add(i)) != 0 {
+                return 0;
+            }
+            write_volatile(scratch.add(i), i as u64);
+        }
+    }
+    MAGIC
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,119 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+const BLOCK_SIZE: usize = 512;
+
+// ELFローダーのテストで読み込むプログラム（apps/elf_test_payload）をビルドして、
+// initrd/以下のファイルと一緒にUSTAR形式のアーカイブにまとめ、そのパスをINITRDとしてカーネルに渡す
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let payload = build_elf_test_payload(&manifest_dir, &out_dir);
+
+    let initrd_dir = manifest_dir.join("initrd");
+    println!("cargo:rerun-if-changed={}", initrd_dir.display());
+    let mut files = Vec::new();
+    collect_files(&initrd_dir, "", &mut files);
+    files.push(("bin/elf_test_payload".to_string(), payload));
+    // 実行するたびに同じアーカイブになるように、パスの順に並べる
+    files.sort();
+
+    let mut archive = Vec::new();
+    for (name, path) in &files {
+        let data = fs::read(path).unwrap_or_else(|e| panic!("failed to read {path:?}: {e}"));
+        append_file(&mut archive, name, &data);
+    }
+    // アーカイブの終わりは0で埋めたブロック2つ
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    let initrd = out_dir.join("initrd.tar");
+    fs::write(&initrd, archive).expect("failed to write initrd.tar");
+    println!("cargo:rustc-env=INITRD={}", initrd.display());
+}
+
+fn build_elf_test_payload(manifest_dir: &Path, out_dir: &Path) -> PathBuf {
+    let payload_dir = manifest_dir.join("apps/elf_test_payload");
+    let target_dir = out_dir.join("elf_test_payload");
+    println!("cargo:rerun-if-changed={}", payload_dir.display());
+
+    // ターゲットやフラグはapps/elf_test_payload/.cargo/config.tomlで決めるので、
+    // カーネルのビルド用に渡されているものは引き継がない
+    let status = Command::new(env::var("CARGO").unwrap())
+        .current_dir(&payload_dir)
+        .args(["build", "--release", "--target-dir"])
+        .arg(&target_dir)
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_TARGET_DIR")
+        .env_remove("CARGO_BUILD_TARGET")
+        .status()
+        .expect("failed to run cargo for elf_test_payload");
+    assert!(status.success(), "failed to build elf_test_payload");
+
+    target_dir.join("x86_64-unknown-linux-gnu/release/elf_test_payload")
+}
+
+// dir以下の通常のファイルを（アーカイブ内のパス, 実際のパス）の組として集める
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries {
+        let entry = entry.unwrap();
+        let name = entry
+            .file_name()
+            .into_string()
+            .expect("non UTF-8 file name");
+        let path = entry.path();
+        let archive_name = format!("{prefix}{name}");
+        if path.is_dir() {
+            collect_files(&path, &format!("{archive_name}/"), files);
+        } else {
+            files.push((archive_name, path));
+        }
+    }
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    // 最後の1バイトはNULにする
+    let s = format!("{value:0width$o}", width = field.len() - 1);
+    assert!(s.len() < field.len(), "value does not fit in the field");
+    field[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+// 100バイトに収まらないパスは、'/'で区切ってprefixフィールド（155バイト）に前半を入れる
+fn split_name(name: &str) -> (&str, &str) {
+    if name.len() <= 100 {
+        return ("", name);
+    }
+    name.match_indices('/')
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100)
+        .unwrap_or_else(|| panic!("path is too long for USTAR: {name}"))
+}
+
+fn append_file(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let (prefix, name) = split_name(name);
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], data.len() as u64);
+    // 再現性のために更新時刻は0にしておく
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // チェックサムはチェックサム欄を空白とみなして全バイトを足したもの
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|b| *b as u32).sum();
+    write_octal(&mut header[148..155], sum as u64);
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+}
@@ -0,0 +1,41 @@
+#!/bin/bash -e
+# 終了経路ごとにテストをビルドして実行し、QEMUの終了ステータスがそれぞれ違うことを確かめる
+PROJ_ROOT="$(dirname $(dirname ${BASH_SOURCE:-$0}))"
+cd "${PROJ_ROOT}"
+
+FAILED=0
+check() {
+    EXPECTED="$1"
+    shift
+    set +e
+    OUTPUT=$(env "$@" 2>&1)
+    set -e
+    STATUS=$(printf "%s\n" "$OUTPUT" | sed -n 's/^QEMU exited with status \([0-9]*\)$/\1/p' | tail -n 1)
+    if [ "$STATUS" = "$EXPECTED" ]; then
+        printf "ok:   status $STATUS from $*\n"
+    else
+        printf "FAIL: expected status $EXPECTED but got '$STATUS' from $*\n"
+        FAILED=1
+    fi
+}
+# 終了ステータスに加えて、出力にPATTERNに一致する行があるかも確かめる
+check_output() {
+    PATTERN="$1"
+    shift
+    check "$@"
+    if ! printf "%s\n" "$OUTPUT" | grep -q "$PATTERN"; then
+        printf "FAIL: '$PATTERN' was not printed by ${*:2}\n"
+        FAILED=1
+    fi
+}
+
+check 3 cargo test
+check 7 KERNEL_TEST_FILTER=no_such_test cargo test
+# panicハンドラがメッセージと場所を報告してから終了することも確かめる
+check_output "PANIC at src/test_runner.rs:[0-9]*:[0-9]*: forced by KERNEL_TEST_FORCE_EXIT" \
+    9 KERNEL_TEST_FORCE_EXIT=panic cargo test
+check 11 cargo test --features failing_tests
+check 13 KERNEL_TEST_FORCE_EXIT=skip cargo test
+check 15 KERNEL_TEST_FORCE_EXIT=oom cargo test
+check 0 KERNEL_TEST_FORCE_EXIT=poweroff cargo test
+exit $FAILED
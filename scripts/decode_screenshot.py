@@ -0,0 +1,52 @@
+#!/usr/bin/env python3
+# シェルのscreenshotコマンドの出力をログから取り出して、PPMファイルに戻す
+# 使い方: scripts/decode_screenshot.py log/com1.txt [出力先の接頭辞]
+# （screenshot --rawの場合はlog/debugcon.txtを渡す）
+# ログに複数あれば、screenshot_0.ppm, screenshot_1.ppm, ...の順に書き出す
+import base64
+import re
+import sys
+import zlib
+
+BEGIN = re.compile(rb"-----BEGIN WASABI SCREENSHOT (RAW (\d+)|\d+x\d+)-----\r?\n")
+END = re.compile(rb"\r?\n?-----END WASABI SCREENSHOT crc32=([0-9a-f]{8})-----")
+
+
+def extract(log):
+    pos = 0
+    while (begin := BEGIN.search(log, pos)) is not None:
+        if begin.group(2) is not None:
+            length = int(begin.group(2))
+            ppm = log[begin.end() : begin.end() + length]
+            end = END.match(log, begin.end() + length)
+        else:
+            end = END.search(log, begin.end())
+            if end is not None:
+                ppm = base64.b64decode(re.sub(rb"\s", b"", log[begin.end() : end.start()]))
+        if end is None:
+            sys.exit("screenshot is truncated")
+        if zlib.crc32(ppm) != int(end.group(1), 16):
+            sys.exit("screenshot is corrupted (crc32 mismatch)")
+        yield ppm
+        pos = end.end()
+
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit(f"usage: {sys.argv[0]} <log> [prefix]")
+    prefix = sys.argv[2] if len(sys.argv) > 2 else "screenshot"
+    with open(sys.argv[1], "rb") as f:
+        log = f.read()
+    count = 0
+    for i, ppm in enumerate(extract(log)):
+        path = f"{prefix}_{i}.ppm"
+        with open(path, "wb") as f:
+            f.write(ppm)
+        print(f"wrote {path}")
+        count += 1
+    if count == 0:
+        sys.exit("no screenshot found")
+
+
+if __name__ == "__main__":
+    main()
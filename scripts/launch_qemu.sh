@@ -10,19 +10,42 @@ set +e
 mkdir -p log
 qemu-system-x86_64 \
     -m 4G \
+    -smp 4 \
     -bios third_party/ovmf/RELEASEX64_OVMF.fd \
     -drive file=fat:rw:mnt,format=raw \
     -chardev stdio,id=char_com1,mux=on,logfile=log/com1.txt \
     -serial chardev:char_com1 \
+    -debugcon file:log/debugcon.txt \
     -device isa-debug-exit,iobase=0xf4,iosize=0x01
 RETCODE=$?
 set -e
-if [ $RETCODE -eq 0 ]; then
-    exit 0
-elif [ $RETCODE -eq 3 ]; then
-    printf "\nPASS!\n"
-    exit 0
-else
-    printf "\nFAIL: QEMU returned $RETCODE\n"
-    exit 1
-fi
\ No newline at end of file
+# 終了ステータスはsrc/qemu.rsのQemuExitCode::status()に合わせる
+printf "\nQEMU exited with status $RETCODE\n"
+case $RETCODE in
+    0)
+        exit 0 ;;
+    3)
+        # debugconに出力したテストの行が、debugconの出力先にも届いているか確かめる
+        if grep -q "debugcon capture marker" log/com1.txt \
+            && ! grep -q "debugcon capture marker" log/debugcon.txt; then
+            printf "FAIL: log output is missing from log/debugcon.txt\n"
+            exit 1
+        fi
+        printf "PASS!\n"
+        exit 0 ;;
+    5)
+        printf "FAIL\n" ;;
+    7)
+        printf "FAIL: no tests matched KERNEL_TEST_FILTER\n" ;;
+    9)
+        printf "FAIL: kernel panicked outside of tests\n" ;;
+    11)
+        printf "FAIL: some tests failed\n" ;;
+    13)
+        printf "SKIPPED\n" ;;
+    15)
+        printf "FAIL: out of memory\n" ;;
+    *)
+        printf "FAIL: QEMU returned $RETCODE\n" ;;
+esac
+exit 1
@@ -0,0 +1,905 @@
+extern crate alloc;
+
+use crate::info;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use crate::x86::read_io_port_u16;
+use crate::x86::write_io_port_u16;
+use crate::x86::write_io_port_u8;
+use alloc::vec::Vec;
+use core::slice;
+use spin::Once;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// ACPI 1.0のRSDPの大きさ（チェックサムはここまで）と、2.0以降の大きさ
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+const SDT_HEADER_LEN: usize = 36;
+// 壊れた長さのテーブルを読もうとしないように、これより大きいテーブルは受け付けない
+const MAX_TABLE_LEN: usize = 1 << 20;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+// HPETテーブルのGeneric Address Structureのアドレス空間とアドレスの位置
+const HPET_ADDRESS_SPACE_OFFSET: usize = SDT_HEADER_LEN + 4;
+const HPET_ADDRESS_OFFSET: usize = SDT_HEADER_LEN + 8;
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+// MADTのヘッダの後ろにあるLocal APICのアドレスとフラグ、その後ろから各エントリが並ぶ
+const MADT_ENTRIES_OFFSET: usize = SDT_HEADER_LEN + 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const DSDT_SIGNATURE: &[u8; 4] = b"DSDT";
+// FADTの中のDSDTのアドレス、ACPIモードへの切り替え、PM1制御レジスタのポートの位置
+const FADT_DSDT_OFFSET: usize = 40;
+const FADT_SMI_CMD_OFFSET: usize = 48;
+const FADT_ACPI_ENABLE_OFFSET: usize = 52;
+const FADT_PM1A_CNT_BLK_OFFSET: usize = 64;
+const FADT_PM1B_CNT_BLK_OFFSET: usize = 68;
+// ACPI 2.0以降のFADTにある64ビットのDSDTのアドレス
+const FADT_X_DSDT_OFFSET: usize = 140;
+// PM1制御レジスタのビット
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_TYP_MASK: u16 = 0b111 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u16 = 1 << 13;
+// SMI_CMDにACPI_ENABLEを書いてから、SCI_ENが立つまで待つ回数の上限
+const ACPI_ENABLE_POLL_LIMIT: usize = 1_000_000;
+// _S5_の定義を読むのに使うAMLのオペコード
+const AML_NAME_OP: u8 = 0x08;
+const AML_ROOT_CHAR: u8 = b'\\';
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+
+// バイト列の指定した位置から、リトルエンディアンの値を読む（範囲外ならエラー）
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N]> {
+    bytes
+        .get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .ok_or("ACPI: table is truncated")
+}
+fn u8_at(bytes: &[u8], offset: usize) -> Result<u8> {
+    Ok(read_le::<1>(bytes, offset)?[0])
+}
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(read_le(bytes, offset)?))
+}
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(read_le(bytes, offset)?))
+}
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64> {
+    Ok(u64::from_le_bytes(read_le(bytes, offset)?))
+}
+
+// 全バイトの和が0になっていれば正しい
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+// ACPIのテーブルが置かれている物理メモリを読む
+// テストでは、組み立てたバイト列を返すモックを使う
+pub trait AcpiMemory {
+    fn read(&self, addr: u64, len: usize) -> Result<&[u8]>;
+}
+
+// 物理アドレスをそのまま仮想アドレスとして読む（ページングはアイデンティティマップ）
+pub struct IdentityMapped;
+impl AcpiMemory for IdentityMapped {
+    fn read(&self, addr: u64, len: usize) -> Result<&[u8]> {
+        if addr == 0 {
+            return Err("ACPI: null table address");
+        }
+        Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+    }
+}
+
+// System Description Tableのヘッダを確かめて、テーブル全体を返す
+// ヘッダに書かれた長さより後ろは読まない
+fn read_sdt(mem: &impl AcpiMemory, addr: u64) -> Result<&[u8]> {
+    let header = mem.read(addr, SDT_HEADER_LEN)?;
+    let len = u32_at(header, 4)? as usize;
+    if !(SDT_HEADER_LEN..=MAX_TABLE_LEN).contains(&len) {
+        return Err("ACPI: invalid table length");
+    }
+    let table = mem.read(addr, len)?;
+    if table.len() < len {
+        return Err("ACPI: table is truncated");
+    }
+    if !checksum_ok(table) {
+        return Err("ACPI: table checksum mismatch");
+    }
+    Ok(table)
+}
+
+// RSDPからXSDT（なければRSDT）をたどって、全てのテーブルのアドレスを返す
+fn table_addresses(mem: &impl AcpiMemory, rsdp_addr: u64) -> Result<Vec<u64>> {
+    let rsdp = mem.read(rsdp_addr, RSDP_V1_LEN)?;
+    if rsdp.get(..8) != Some(RSDP_SIGNATURE) {
+        return Err("ACPI: invalid RSDP signature");
+    }
+    if !checksum_ok(rsdp) {
+        return Err("ACPI: RSDP checksum mismatch");
+    }
+    let revision = u8_at(rsdp, 15)?;
+    if revision >= 2 {
+        let rsdp = mem.read(rsdp_addr, RSDP_V2_LEN)?;
+        if !checksum_ok(rsdp) {
+            return Err("ACPI: extended RSDP checksum mismatch");
+        }
+        let xsdt_addr = u64_at(rsdp, 24)?;
+        if xsdt_addr != 0 {
+            let xsdt = read_sdt(mem, xsdt_addr)?;
+            return xsdt[SDT_HEADER_LEN..]
+                .chunks_exact(8)
+                .map(|e| u64_at(e, 0))
+                .collect();
+        }
+    }
+    let rsdt = read_sdt(mem, u32_at(rsdp, 16)? as u64)?;
+    rsdt[SDT_HEADER_LEN..]
+        .chunks_exact(4)
+        .map(|e| Ok(u32_at(e, 0)? as u64))
+        .collect()
+}
+
+// 指定したシグネチャのテーブルを探す
+fn find_table<'a>(
+    mem: &'a impl AcpiMemory,
+    rsdp_addr: u64,
+    signature: &[u8; 4],
+) -> Result<&'a [u8]> {
+    // 関係のないテーブルが壊れていても探せるように、シグネチャが一致したものだけを確かめる
+    for addr in table_addresses(mem, rsdp_addr)? {
+        if mem.read(addr, 4)? == signature {
+            return read_sdt(mem, addr);
+        }
+    }
+    Err("ACPI: table not found")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    // 無効なCPUは起動しない
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub addr: u32,
+    // このI/O APICの最初の入力に対応するGlobal System Interrupt
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    // バスの規格に従う（ISAならアクティブハイ）
+    Conforms,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    // バスの規格に従う（ISAならエッジ）
+    Conforms,
+    Edge,
+    Level,
+}
+
+// レガシーなIRQが、別のGSIにつながっていることを表す（PITのIRQ0がGSI2になっているなど）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub source_irq: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+impl InterruptOverride {
+    pub fn polarity(&self) -> Polarity {
+        match self.flags & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::Conforms,
+        }
+    }
+    pub fn trigger(&self) -> Trigger {
+        match (self.flags >> 2) & 0b11 {
+            0b01 => Trigger::Edge,
+            0b11 => Trigger::Level,
+            _ => Trigger::Conforms,
+        }
+    }
+}
+
+// MADTから読み取った割り込みコントローラとCPUの構成
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlatformInfo {
+    pub lapic_addr: u64,
+    pub cpus: Vec<CpuEntry>,
+    pub ioapics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+    // HPETのレジスタの物理アドレス（HPETテーブルがなければNone）
+    pub hpet_addr: Option<u64>,
+    // ACPIで電源を切るための情報（FADTやDSDTから読めなければ、その理由）
+    pub s5: Result<SleepControl>,
+}
+impl PlatformInfo {
+    // legacy_irqがつながっているGSI（上書きされていなければ同じ番号）
+    pub fn gsi_for_irq(&self, legacy_irq: u8) -> u32 {
+        self.overrides
+            .iter()
+            .find(|o| o.source_irq == legacy_irq)
+            .map_or(legacy_irq as u32, |o| o.gsi)
+    }
+}
+
+// MADTの各エントリを読む（知らない種類のエントリは飛ばす）
+fn parse_madt(madt: &[u8]) -> Result<PlatformInfo> {
+    let mut info = PlatformInfo {
+        lapic_addr: u32_at(madt, SDT_HEADER_LEN)? as u64,
+        cpus: Vec::new(),
+        ioapics: Vec::new(),
+        overrides: Vec::new(),
+        hpet_addr: None,
+        s5: Err("ACPI: poweroff unsupported: FADT is not read"),
+    };
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset < madt.len() {
+        let kind = u8_at(madt, offset)?;
+        let len = u8_at(madt, offset + 1)? as usize;
+        if len < 2 {
+            return Err("ACPI: invalid MADT entry length");
+        }
+        // エントリがテーブルの終わりをはみ出していたら、それ以上読まない
+        let entry = madt
+            .get(offset..offset + len)
+            .ok_or("ACPI: MADT entry is truncated")?;
+        match kind {
+            MADT_LOCAL_APIC => info.cpus.push(CpuEntry {
+                processor_id: u8_at(entry, 2)?,
+                apic_id: u8_at(entry, 3)?,
+                enabled: u32_at(entry, 4)? & LOCAL_APIC_ENABLED != 0,
+            }),
+            MADT_IO_APIC => info.ioapics.push(IoApicEntry {
+                id: u8_at(entry, 2)?,
+                addr: u32_at(entry, 4)?,
+                gsi_base: u32_at(entry, 8)?,
+            }),
+            MADT_INTERRUPT_SOURCE_OVERRIDE => info.overrides.push(InterruptOverride {
+                bus: u8_at(entry, 2)?,
+                source_irq: u8_at(entry, 3)?,
+                gsi: u32_at(entry, 4)?,
+                flags: u16_at(entry, 8)?,
+            }),
+            MADT_LOCAL_APIC_ADDRESS_OVERRIDE => info.lapic_addr = u64_at(entry, 4)?,
+            _ => {}
+        }
+        offset += len;
+    }
+    Ok(info)
+}
+
+// HPETのレジスタの物理アドレスを読む
+fn parse_hpet(hpet: &[u8]) -> Result<u64> {
+    if u8_at(hpet, HPET_ADDRESS_SPACE_OFFSET)? != ADDRESS_SPACE_MEMORY {
+        return Err("ACPI: HPET is not memory mapped");
+    }
+    u64_at(hpet, HPET_ADDRESS_OFFSET)
+}
+
+// FADTに書かれたI/Oポートの番号を読む（0はレジスタがないことを表す）
+fn fadt_port(fadt: &[u8], offset: usize) -> Result<Option<u16>> {
+    match u32_at(fadt, offset).map_err(|_| "ACPI: poweroff unsupported: FADT is truncated")? {
+        0 => Ok(None),
+        port => u16::try_from(port)
+            .map(Some)
+            .map_err(|_| "ACPI: poweroff unsupported: invalid I/O port in FADT"),
+    }
+}
+
+// AMLのPkgLengthを読んで、(PkgLength自身の大きさ, パッケージの長さ)を返す
+fn aml_pkg_length(aml: &[u8]) -> Option<(usize, usize)> {
+    let lead = *aml.first()?;
+    let follow = (lead >> 6) as usize;
+    if follow == 0 {
+        return Some((1, (lead & 0x3f) as usize));
+    }
+    let mut len = (lead & 0x0f) as usize;
+    for i in 0..follow {
+        len |= (*aml.get(1 + i)? as usize) << (4 + 8 * i);
+    }
+    Some((1 + follow, len))
+}
+
+// AMLの整数の定数を読んで、(値, 読んだバイト数)を返す
+fn aml_integer(aml: &[u8]) -> Option<(u64, usize)> {
+    match *aml.first()? {
+        AML_ZERO_OP => Some((0, 1)),
+        AML_ONE_OP => Some((1, 1)),
+        AML_BYTE_PREFIX => Some((*aml.get(1)? as u64, 2)),
+        AML_WORD_PREFIX => Some((u16_at(aml, 1).ok()? as u64, 3)),
+        AML_DWORD_PREFIX => Some((u32_at(aml, 1).ok()? as u64, 5)),
+        _ => None,
+    }
+}
+
+// "_S5_"の直後にあるPackage(){SLP_TYPa, SLP_TYPb, ...}を読む
+fn parse_s5_package(aml: &[u8]) -> Option<(u8, u8)> {
+    if *aml.first()? != AML_PACKAGE_OP {
+        return None;
+    }
+    let (pkg_len_size, pkg_len) = aml_pkg_length(&aml[1..])?;
+    // パッケージの長さはPkgLength自身を含む
+    let package = aml.get(1 + pkg_len_size..1 + pkg_len)?;
+    let (&num_elements, mut rest) = package.split_first()?;
+    if num_elements < 2 {
+        return None;
+    }
+    let mut slp_typ = [0u8; 2];
+    for typ in &mut slp_typ {
+        let (value, len) = aml_integer(rest)?;
+        // SLP_TYPは3ビットの値
+        *typ = u8::try_from(value).ok().filter(|v| *v <= 0b111)?;
+        rest = &rest[len..];
+    }
+    Some((slp_typ[0], slp_typ[1]))
+}
+
+// DSDTから\_S5_オブジェクトを探して、S5のSLP_TYPaとSLP_TYPbを読む
+// AMLを全て解釈するのは大変なので、Name(_S5_, Package(){...})の形だけを探す
+fn parse_s5(dsdt: &[u8]) -> Result<(u8, u8)> {
+    let aml = &dsdt[SDT_HEADER_LEN..];
+    let mut found = false;
+    for (i, name) in aml.windows(4).enumerate() {
+        if name != b"_S5_" {
+            continue;
+        }
+        // 定義ではなく参照している場所は飛ばす
+        let is_definition = match i {
+            0 => false,
+            1 => aml[0] == AML_NAME_OP,
+            _ => {
+                aml[i - 1] == AML_NAME_OP
+                    || (aml[i - 1] == AML_ROOT_CHAR && aml[i - 2] == AML_NAME_OP)
+            }
+        };
+        if !is_definition {
+            continue;
+        }
+        found = true;
+        if let Some(slp_typ) = parse_s5_package(&aml[i + 4..]) {
+            return Ok(slp_typ);
+        }
+    }
+    if found {
+        Err("ACPI: poweroff unsupported: malformed _S5_ package in DSDT")
+    } else {
+        Err("ACPI: poweroff unsupported: no _S5_ object in DSDT")
+    }
+}
+
+fn read_sleep_control(mem: &impl AcpiMemory, rsdp_addr: u64) -> Result<SleepControl> {
+    let fadt = find_table(mem, rsdp_addr, FADT_SIGNATURE)
+        .map_err(|_| "ACPI: poweroff unsupported: FADT is not available")?;
+    let pm1a_cnt = fadt_port(fadt, FADT_PM1A_CNT_BLK_OFFSET)?
+        .ok_or("ACPI: poweroff unsupported: no PM1a control port")?;
+    let pm1b_cnt = fadt_port(fadt, FADT_PM1B_CNT_BLK_OFFSET)?;
+    let smi_cmd = fadt_port(fadt, FADT_SMI_CMD_OFFSET)?;
+    let acpi_enable = u8_at(fadt, FADT_ACPI_ENABLE_OFFSET)
+        .map_err(|_| "ACPI: poweroff unsupported: FADT is truncated")?;
+    // 64ビットのアドレスがあればそちらを使う
+    let dsdt_addr = match u64_at(fadt, FADT_X_DSDT_OFFSET) {
+        Ok(addr) if addr != 0 => addr,
+        _ => u32_at(fadt, FADT_DSDT_OFFSET)
+            .map_err(|_| "ACPI: poweroff unsupported: FADT is truncated")? as u64,
+    };
+    let dsdt =
+        read_sdt(mem, dsdt_addr).map_err(|_| "ACPI: poweroff unsupported: DSDT is not readable")?;
+    if dsdt.get(..4) != Some(DSDT_SIGNATURE) {
+        return Err("ACPI: poweroff unsupported: invalid DSDT signature");
+    }
+    let (slp_typ_a, slp_typ_b) = parse_s5(dsdt)?;
+    Ok(SleepControl {
+        pm1a_cnt,
+        pm1b_cnt,
+        slp_typ_a,
+        slp_typ_b,
+        smi_cmd,
+        acpi_enable,
+    })
+}
+
+fn read_platform_info(mem: &impl AcpiMemory, rsdp_addr: u64) -> Result<PlatformInfo> {
+    let mut info = parse_madt(find_table(mem, rsdp_addr, MADT_SIGNATURE)?)?;
+    // HPETはなくても動けるので、テーブルがなかったり壊れていたりしたら使わない
+    info.hpet_addr = find_table(mem, rsdp_addr, HPET_SIGNATURE)
+        .and_then(parse_hpet)
+        .ok();
+    info.s5 = read_sleep_control(mem, rsdp_addr);
+    Ok(info)
+}
+
+// PM1制御レジスタなどのI/Oポートを読み書きする
+// テストでは、書き込みを記録するモックを使う
+pub trait PmPorts {
+    fn read_u16(&mut self, port: u16) -> u16;
+    fn write_u16(&mut self, port: u16, value: u16);
+    fn write_u8(&mut self, port: u16, value: u8);
+}
+
+pub struct IoPorts;
+impl PmPorts for IoPorts {
+    fn read_u16(&mut self, port: u16) -> u16 {
+        read_io_port_u16(port)
+    }
+    fn write_u16(&mut self, port: u16, value: u16) {
+        write_io_port_u16(port, value)
+    }
+    fn write_u8(&mut self, port: u16, value: u8) {
+        write_io_port_u8(port, value)
+    }
+}
+
+// S5（ソフトオフ）に入るために必要な、FADTとDSDTの\_S5_から読んだ値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepControl {
+    pub pm1a_cnt: u16,
+    pub pm1b_cnt: Option<u16>,
+    pub slp_typ_a: u8,
+    pub slp_typ_b: u8,
+    // ACPIモードに切り替えるためのポートと値（なければ最初からACPIモード）
+    pub smi_cmd: Option<u16>,
+    pub acpi_enable: u8,
+}
+impl SleepControl {
+    // SCI_ENが立っていなければ、SMI_CMDにACPI_ENABLEを書いてACPIモードに切り替える
+    fn enable_acpi_mode(&self, ports: &mut impl PmPorts) -> Result<()> {
+        if ports.read_u16(self.pm1a_cnt) & PM1_SCI_EN != 0 {
+            return Ok(());
+        }
+        let Some(smi_cmd) = self.smi_cmd.filter(|_| self.acpi_enable != 0) else {
+            return Ok(());
+        };
+        ports.write_u8(smi_cmd, self.acpi_enable);
+        for _ in 0..ACPI_ENABLE_POLL_LIMIT {
+            if ports.read_u16(self.pm1a_cnt) & PM1_SCI_EN != 0 {
+                return Ok(());
+            }
+            busy_loop_hint();
+        }
+        Err("ACPI: failed to enable ACPI mode")
+    }
+
+    // PM1制御レジスタにSLP_TYPとSLP_ENを書いてS5に入る
+    // 成功すればこの関数から戻る前に電源が切れる
+    pub fn enter_s5(&self, ports: &mut impl PmPorts) -> Result<()> {
+        self.enable_acpi_mode(ports)?;
+        let regs = [
+            Some((self.pm1a_cnt, self.slp_typ_a)),
+            self.pm1b_cnt.map(|port| (port, self.slp_typ_b)),
+        ];
+        // 先にSLP_TYPだけを書いてから、SLP_ENを立てる
+        for (port, typ) in regs.iter().flatten() {
+            let value = ports.read_u16(*port) & !(PM1_SLP_TYP_MASK | PM1_SLP_EN);
+            ports.write_u16(*port, value | ((*typ as u16) << PM1_SLP_TYP_SHIFT));
+        }
+        for (port, _) in regs.iter().flatten() {
+            let value = ports.read_u16(*port);
+            ports.write_u16(*port, value | PM1_SLP_EN);
+        }
+        Ok(())
+    }
+}
+
+static PLATFORM_INFO: Once<Result<PlatformInfo>> = Once::new();
+
+// RSDPからMADTを読んで、結果を覚えておく（2回目以降は何もしない）
+pub fn init(rsdp_addr: Option<u64>) -> Result<&'static PlatformInfo> {
+    PLATFORM_INFO
+        .call_once(|| read_platform_info(&IdentityMapped, rsdp_addr.ok_or("ACPI: no RSDP")?))
+        .as_ref()
+        .map_err(|e| *e)
+}
+
+// initで読んだ構成（読めなかったか、まだ読んでいなければエラー）
+pub fn platform_info() -> Result<&'static PlatformInfo> {
+    match PLATFORM_INFO.get() {
+        Some(info) => info.as_ref().map_err(|e| *e),
+        None => Err("ACPI: not initialized"),
+    }
+}
+
+pub fn log_platform_info(info: &PlatformInfo) {
+    let enabled = info.cpus.iter().filter(|c| c.enabled).count();
+    info!(
+        "ACPI: LAPIC at {:#x}, {} CPUs ({enabled} enabled), {} I/O APICs",
+        info.lapic_addr,
+        info.cpus.len(),
+        info.ioapics.len()
+    );
+    if let Some(addr) = info.hpet_addr {
+        info!("  HPET at {addr:#x}");
+    }
+    match &info.s5 {
+        Ok(s5) => info!(
+            "  S5: PM1a_CNT {:#x}, SLP_TYPa {}, SLP_TYPb {}",
+            s5.pm1a_cnt, s5.slp_typ_a, s5.slp_typ_b
+        ),
+        Err(e) => info!("  {e}"),
+    }
+    for ioapic in &info.ioapics {
+        info!(
+            "  I/O APIC {} at {:#x}, GSI base {}",
+            ioapic.id, ioapic.addr, ioapic.gsi_base
+        );
+    }
+    for o in &info.overrides {
+        info!(
+            "  IRQ{} -> GSI{} ({:?}, {:?})",
+            o.source_irq,
+            o.gsi,
+            o.polarity(),
+            o.trigger()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    // アドレスとバイト列の組でメモリを表すモック
+    struct Blobs(Vec<(u64, Vec<u8>)>);
+    impl AcpiMemory for Blobs {
+        fn read(&self, addr: u64, len: usize) -> Result<&[u8]> {
+            let (start, blob) = self
+                .0
+                .iter()
+                .find(|(start, blob)| (*start..*start + blob.len() as u64).contains(&addr))
+                .ok_or("ACPI: unmapped address")?;
+            let from = (addr - start) as usize;
+            // 用意したバイト列より後ろは返さない（短いテーブルを表せるようにする）
+            Ok(&blob[from..(from + len).min(blob.len())])
+        }
+    }
+
+    // 全体の和が0になるように、offsetのバイトを決める
+    fn fix_checksum(bytes: &mut [u8], offset: usize) {
+        bytes[offset] = 0;
+        let sum = bytes.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        bytes[offset] = 0u8.wrapping_sub(sum);
+    }
+    fn sdt(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut t = vec![0u8; SDT_HEADER_LEN];
+        t[..4].copy_from_slice(signature);
+        t.extend_from_slice(body);
+        let len = t.len() as u32;
+        t[4..8].copy_from_slice(&len.to_le_bytes());
+        fix_checksum(&mut t, 9);
+        t
+    }
+    fn rsdp(revision: u8, rsdt: u32, xsdt: u64) -> Vec<u8> {
+        let mut r = vec![0u8; RSDP_V2_LEN];
+        r[..8].copy_from_slice(RSDP_SIGNATURE);
+        r[15] = revision;
+        r[16..20].copy_from_slice(&rsdt.to_le_bytes());
+        r[20..24].copy_from_slice(&(RSDP_V2_LEN as u32).to_le_bytes());
+        r[24..32].copy_from_slice(&xsdt.to_le_bytes());
+        fix_checksum(&mut r[..RSDP_V1_LEN], 8);
+        fix_checksum(&mut r, 32);
+        r
+    }
+    // 有効なCPUと無効なCPU、I/O APIC、割り込みの上書き2つを持つMADT
+    fn madt_body() -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        b.extend_from_slice(&1u32.to_le_bytes());
+        b.extend_from_slice(&[MADT_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        b.extend_from_slice(&[MADT_LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+        b.extend_from_slice(&[MADT_IO_APIC, 12, 0, 0]);
+        b.extend_from_slice(&0xfec0_0000u32.to_le_bytes());
+        b.extend_from_slice(&0u32.to_le_bytes());
+        // PITのIRQ0はGSI2につながっている
+        b.extend_from_slice(&[MADT_INTERRUPT_SOURCE_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        // SCIのIRQ9はレベルトリガでアクティブハイ
+        b.extend_from_slice(&[
+            MADT_INTERRUPT_SOURCE_OVERRIDE,
+            10,
+            0,
+            9,
+            9,
+            0,
+            0,
+            0,
+            0x0d,
+            0,
+        ]);
+        b
+    }
+    // HPETのレジスタが0xfed00000にあるHPETテーブル
+    fn hpet_table() -> Vec<u8> {
+        let mut b = vec![0u8; 20];
+        b[4] = ADDRESS_SPACE_MEMORY;
+        b[8..16].copy_from_slice(&0xfed0_0000u64.to_le_bytes());
+        sdt(HPET_SIGNATURE, &b)
+    }
+    // PM1a_CNTが0x604、SMI_CMDが0xb2で、DSDTが0x7000にあるFADT
+    fn fadt() -> Vec<u8> {
+        let mut b = vec![0u8; 244 - SDT_HEADER_LEN];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            let offset = offset - SDT_HEADER_LEN;
+            b[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(FADT_DSDT_OFFSET, &0x7000u32.to_le_bytes());
+        put(FADT_SMI_CMD_OFFSET, &0xb2u32.to_le_bytes());
+        put(FADT_ACPI_ENABLE_OFFSET, &[0xf1]);
+        put(FADT_PM1A_CNT_BLK_OFFSET, &0x604u32.to_le_bytes());
+        put(FADT_X_DSDT_OFFSET, &0x7000u64.to_le_bytes());
+        sdt(FADT_SIGNATURE, &b)
+    }
+    // Name(\_S5_, Package(4){5, 5, 0, 0})の前に、_S5_を参照するだけのAMLを置く
+    fn s5_aml() -> Vec<u8> {
+        let mut aml = vec![0x70];
+        aml.extend_from_slice(b"_S5_");
+        aml.extend_from_slice(&[AML_NAME_OP, AML_ROOT_CHAR]);
+        aml.extend_from_slice(b"_S5_");
+        aml.extend_from_slice(&[AML_PACKAGE_OP, 8, 4, AML_BYTE_PREFIX, 5]);
+        aml.extend_from_slice(&[AML_BYTE_PREFIX, 5, AML_ZERO_OP, AML_ZERO_OP]);
+        aml
+    }
+    fn platform_with_dsdt(rsdp_revision: u8, madt: Vec<u8>, dsdt: Vec<u8>) -> Blobs {
+        let tables = [0x3000u64, 0x5000, 0x6000];
+        let rsdt: Vec<u8> = tables
+            .iter()
+            .flat_map(|t| (*t as u32).to_le_bytes())
+            .collect();
+        let xsdt: Vec<u8> = tables.iter().flat_map(|t| t.to_le_bytes()).collect();
+        Blobs(vec![
+            (0x1000, rsdp(rsdp_revision, 0x4000, 0x2000)),
+            (0x2000, sdt(b"XSDT", &xsdt)),
+            (0x3000, madt),
+            (0x4000, sdt(b"RSDT", &rsdt)),
+            (0x5000, hpet_table()),
+            (0x6000, fadt()),
+            (0x7000, dsdt),
+        ])
+    }
+    fn platform(rsdp_revision: u8, madt: Vec<u8>) -> Blobs {
+        platform_with_dsdt(rsdp_revision, madt, sdt(DSDT_SIGNATURE, &s5_aml()))
+    }
+    fn s5_with_aml(aml: &[u8]) -> Result<SleepControl> {
+        let mem = platform_with_dsdt(
+            2,
+            sdt(MADT_SIGNATURE, &madt_body()),
+            sdt(DSDT_SIGNATURE, aml),
+        );
+        read_platform_info(&mem, 0x1000).unwrap().s5
+    }
+
+    // 書き込みを記録するI/Oポートのモック
+    // enable_on_smiならSMI_CMDへの書き込みでSCI_ENが立つ
+    struct MockPorts {
+        pm1a: u16,
+        writes: Vec<(u16, u16)>,
+        enable_on_smi: bool,
+    }
+    impl MockPorts {
+        fn new(pm1a: u16, enable_on_smi: bool) -> Self {
+            Self {
+                pm1a,
+                writes: Vec::new(),
+                enable_on_smi,
+            }
+        }
+    }
+    impl PmPorts for MockPorts {
+        fn read_u16(&mut self, port: u16) -> u16 {
+            assert_eq!(port, 0x604);
+            self.pm1a
+        }
+        fn write_u16(&mut self, port: u16, value: u16) {
+            assert_eq!(port, 0x604);
+            self.pm1a = value;
+            self.writes.push((port, value));
+        }
+        fn write_u8(&mut self, port: u16, value: u8) {
+            self.writes.push((port, value as u16));
+            if self.enable_on_smi {
+                self.pm1a |= PM1_SCI_EN;
+            }
+        }
+    }
+
+    #[test_case]
+    fn madt_is_parsed_from_xsdt() {
+        let mem = platform(2, sdt(MADT_SIGNATURE, &madt_body()));
+        let info = read_platform_info(&mem, 0x1000).unwrap();
+        assert_eq!(info.lapic_addr, 0xfee0_0000);
+        assert_eq!(
+            info.cpus,
+            [
+                CpuEntry {
+                    processor_id: 0,
+                    apic_id: 0,
+                    enabled: true
+                },
+                CpuEntry {
+                    processor_id: 1,
+                    apic_id: 1,
+                    enabled: false
+                },
+            ]
+        );
+        assert_eq!(
+            info.ioapics,
+            [IoApicEntry {
+                id: 0,
+                addr: 0xfec0_0000,
+                gsi_base: 0
+            }]
+        );
+        assert_eq!(info.overrides.len(), 2);
+        assert_eq!(info.gsi_for_irq(0), 2);
+        assert_eq!(info.gsi_for_irq(1), 1);
+        let sci = info.overrides[1];
+        assert_eq!((sci.source_irq, sci.gsi), (9, 9));
+        assert_eq!(sci.polarity(), Polarity::ActiveHigh);
+        assert_eq!(sci.trigger(), Trigger::Level);
+        assert_eq!(info.overrides[0].polarity(), Polarity::Conforms);
+        assert_eq!(info.hpet_addr, Some(0xfed0_0000));
+        assert_eq!(
+            info.s5,
+            Ok(SleepControl {
+                pm1a_cnt: 0x604,
+                pm1b_cnt: None,
+                slp_typ_a: 5,
+                slp_typ_b: 5,
+                smi_cmd: Some(0xb2),
+                acpi_enable: 0xf1,
+            })
+        );
+    }
+    #[test_case]
+    fn s5_package_encodings() {
+        let header = [0u8; SDT_HEADER_LEN];
+        let parse = |aml: &[u8]| parse_s5(&[&header[..], aml].concat());
+        // ルートの\がなく、ZeroOpとOneOpで書かれている
+        let mut aml = vec![AML_NAME_OP];
+        aml.extend_from_slice(b"_S5_");
+        aml.extend_from_slice(&[AML_PACKAGE_OP, 4, 2, AML_ONE_OP, AML_ZERO_OP]);
+        assert_eq!(parse(&aml), Ok((1, 0)));
+        // 2バイトのPkgLengthとWordPrefix
+        let mut aml = vec![AML_NAME_OP];
+        aml.extend_from_slice(b"_S5_");
+        aml.extend_from_slice(&[AML_PACKAGE_OP, 0x40 | 11, 0, 2]);
+        aml.extend_from_slice(&[AML_WORD_PREFIX, 7, 0, AML_DWORD_PREFIX, 6, 0, 0, 0]);
+        assert_eq!(parse(&aml), Ok((7, 6)));
+    }
+    #[test_case]
+    fn malformed_dsdt_disables_poweroff() {
+        let malformed = Err("ACPI: poweroff unsupported: malformed _S5_ package in DSDT");
+        let s5 = |package: &[u8]| {
+            let mut aml = vec![AML_NAME_OP];
+            aml.extend_from_slice(b"_S5_");
+            aml.extend_from_slice(package);
+            s5_with_aml(&aml)
+        };
+        // パッケージの長さがテーブルの終わりを越えている
+        assert_eq!(s5(&[AML_PACKAGE_OP, 0x3f, 2, 0, 0]), malformed);
+        // 要素が1つしかない
+        assert_eq!(s5(&[AML_PACKAGE_OP, 3, 1, AML_ONE_OP]), malformed);
+        // SLP_TYPが3ビットに収まらない
+        assert_eq!(
+            s5(&[AML_PACKAGE_OP, 5, 2, AML_BYTE_PREFIX, 8, AML_ZERO_OP]),
+            malformed
+        );
+        // 整数ではない要素
+        assert_eq!(s5(&[AML_PACKAGE_OP, 4, 2, 0x70, 0x70]), malformed);
+        // パッケージではない
+        assert_eq!(s5(&[AML_BYTE_PREFIX, 5]), malformed);
+        assert_eq!(s5(&[]), malformed);
+        // 参照しかない
+        assert_eq!(
+            s5_with_aml(&s5_aml()[..5]),
+            Err("ACPI: poweroff unsupported: no _S5_ object in DSDT")
+        );
+        // DSDTが壊れている
+        let mut dsdt = sdt(DSDT_SIGNATURE, &s5_aml());
+        dsdt[SDT_HEADER_LEN] ^= 1;
+        let mem = platform_with_dsdt(2, sdt(MADT_SIGNATURE, &madt_body()), dsdt);
+        assert_eq!(
+            read_platform_info(&mem, 0x1000).unwrap().s5,
+            Err("ACPI: poweroff unsupported: DSDT is not readable")
+        );
+        let mem = platform_with_dsdt(
+            2,
+            sdt(MADT_SIGNATURE, &madt_body()),
+            sdt(b"SSDT", &s5_aml()),
+        );
+        assert_eq!(
+            read_platform_info(&mem, 0x1000).unwrap().s5,
+            Err("ACPI: poweroff unsupported: invalid DSDT signature")
+        );
+    }
+    #[test_case]
+    fn enter_s5_writes_slp_typ_then_slp_en() {
+        let s5 = s5_with_aml(&s5_aml()).unwrap();
+        // 既にACPIモードなので、SMI_CMDには書かない
+        let mut ports = MockPorts::new(PM1_SCI_EN | PM1_SLP_EN | (7 << PM1_SLP_TYP_SHIFT), false);
+        s5.enter_s5(&mut ports).unwrap();
+        let typ = PM1_SCI_EN | (5 << PM1_SLP_TYP_SHIFT);
+        assert_eq!(ports.writes, [(0x604, typ), (0x604, typ | PM1_SLP_EN)]);
+        // SMI_CMDに書いてACPIモードに切り替えてから書き込む
+        let mut ports = MockPorts::new(0, true);
+        s5.enter_s5(&mut ports).unwrap();
+        assert_eq!(
+            ports.writes,
+            [(0xb2, 0xf1), (0x604, typ), (0x604, typ | PM1_SLP_EN)]
+        );
+        // ACPIモードにならなければ、PM1制御レジスタには何も書かない
+        let mut ports = MockPorts::new(0, false);
+        assert_eq!(
+            s5.enter_s5(&mut ports),
+            Err("ACPI: failed to enable ACPI mode")
+        );
+        assert_eq!(ports.writes, [(0xb2, 0xf1)]);
+    }
+    #[test_case]
+    fn rsdt_is_used_for_acpi_1() {
+        let mem = platform(0, sdt(MADT_SIGNATURE, &madt_body()));
+        let info = read_platform_info(&mem, 0x1000).unwrap();
+        assert_eq!(info.cpus.len(), 2);
+    }
+    #[test_case]
+    fn corrupt_tables_are_rejected() {
+        let mut madt = sdt(MADT_SIGNATURE, &madt_body());
+        madt[SDT_HEADER_LEN] ^= 1;
+        let mem = platform(2, madt);
+        assert_eq!(
+            read_platform_info(&mem, 0x1000),
+            Err("ACPI: table checksum mismatch")
+        );
+        // ヘッダの長さよりもデータが短い
+        let mut madt = sdt(MADT_SIGNATURE, &madt_body());
+        madt.truncate(madt.len() - 4);
+        let mem = platform(2, madt);
+        assert_eq!(
+            read_platform_info(&mem, 0x1000),
+            Err("ACPI: table is truncated")
+        );
+        // 最後のエントリがテーブルの終わりをはみ出している
+        let mut body = madt_body();
+        body.extend_from_slice(&[MADT_IO_APIC, 12, 0, 0]);
+        let mem = platform(2, sdt(MADT_SIGNATURE, &body));
+        assert_eq!(
+            read_platform_info(&mem, 0x1000),
+            Err("ACPI: MADT entry is truncated")
+        );
+        let mem = platform(2, sdt(b"FACP", &[]));
+        assert_eq!(
+            read_platform_info(&mem, 0x1000),
+            Err("ACPI: table not found")
+        );
+    }
+    #[test_case]
+    fn platform_info_is_available_under_qemu() {
+        let info = platform_info().unwrap();
+        assert!(info.cpus.iter().any(|c| c.enabled));
+        assert!(!info.ioapics.is_empty());
+        assert_ne!(info.lapic_addr, 0);
+        // QEMUのDSDTには\_S5_がある
+        assert!(info.s5.is_ok(), "{:?}", info.s5);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::sync::SpinLock;
+use crate::warn;
+use crate::watchdog::walk_frames;
+use crate::x86::read_rbp_rsp;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+// 確保されたままのメモリを1つずつ記録して、解放し忘れた場所を探すための仕組み
+// alloc_trackingフィーチャーを有効にしてビルドしたときだけ、ALLOCATORから呼ばれる
+// 記録を置く表はヒープを使わずに静的に持つ（表を確保するとまた記録することになるため）
+
+// 記録できる確保の数（これを超えた分は記録せずに数だけ数える）
+const MAX_TRACKED: usize = 4096;
+// 確保した場所として覚えておく戻りアドレスの数
+// アロケータ自身のフレームも含むので、少し多めに取る
+pub const MAX_CALLER_FRAMES: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocRecord {
+    pub ptr: usize,
+    pub size: usize,
+    // 何番目の確保か（checkpoint()と比べる）
+    pub seq: u64,
+    // 確保したときの戻りアドレス（addr2lineなどで関数と行が分かる）
+    pub callers: [u64; MAX_CALLER_FRAMES],
+}
+
+// ptrをキーにした開番地法のハッシュ表
+// 消すときは後ろの要素を詰め直すので、削除済みの印は使わない
+struct Table<const N: usize> {
+    slots: [Option<AllocRecord>; N],
+    len: usize,
+}
+impl<const N: usize> Table<N> {
+    const fn new() -> Self {
+        Self {
+            slots: [None; N],
+            len: 0,
+        }
+    }
+
+    fn home(ptr: usize) -> usize {
+        // 下位ビットは揃っていることが多いので、かき混ぜてから使う
+        (ptr.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) % N
+    }
+
+    // 一杯ならfalseを返す
+    fn insert(&mut self, record: AllocRecord) -> bool {
+        if self.len == N {
+            return false;
+        }
+        let mut i = Self::home(record.ptr);
+        while self.slots[i].is_some() {
+            i = (i + 1) % N;
+        }
+        self.slots[i] = Some(record);
+        self.len += 1;
+        true
+    }
+
+    fn find(&self, ptr: usize) -> Option<usize> {
+        let mut i = Self::home(ptr);
+        for _ in 0..N {
+            match self.slots[i] {
+                None => return None,
+                Some(r) if r.ptr == ptr => return Some(i),
+                Some(_) => i = (i + 1) % N,
+            }
+        }
+        None
+    }
+
+    fn remove(&mut self, ptr: usize) -> Option<AllocRecord> {
+        let mut hole = self.find(ptr)?;
+        let removed = self.slots[hole].take();
+        self.len -= 1;
+        // 空いた場所より後ろにある要素のうち、本来の位置が空いた場所以前のものを詰める
+        let mut j = hole;
+        loop {
+            j = (j + 1) % N;
+            let Some(r) = self.slots[j] else {
+                break;
+            };
+            let home = Self::home(r.ptr);
+            // homeが(hole, j]の範囲（一周していれば折り返す）になければ動かせる
+            let stays = if hole < j {
+                hole < home && home <= j
+            } else {
+                hole < home || home <= j
+            };
+            if !stays {
+                self.slots[hole] = self.slots[j].take();
+                hole = j;
+            }
+        }
+        removed
+    }
+
+    fn get_mut(&mut self, ptr: usize) -> Option<&mut AllocRecord> {
+        let i = self.find(ptr)?;
+        self.slots[i].as_mut()
+    }
+}
+
+static TABLE: SpinLock<Table<MAX_TRACKED>> = SpinLock::new(Table::new());
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+// 表が一杯で記録できなかった確保の数
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// ここより後の確保を調べるための印
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Checkpoint(u64);
+
+pub fn checkpoint() -> Checkpoint {
+    Checkpoint(NEXT_SEQ.load(Ordering::SeqCst))
+}
+
+// ALLOCATORが確保に成功したときに呼ぶ
+#[inline(always)]
+pub fn on_alloc(ptr: *mut u8, size: usize) {
+    let (rbp, rsp) = read_rbp_rsp();
+    let mut callers = [0; MAX_CALLER_FRAMES];
+    walk_frames(rbp, rsp, &mut callers);
+    let record = AllocRecord {
+        ptr: ptr as usize,
+        size,
+        seq: NEXT_SEQ.fetch_add(1, Ordering::SeqCst),
+        callers,
+    };
+    if !TABLE.lock().insert(record) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// ALLOCATORが解放する前に呼ぶ（記録していない確保は無視する）
+pub fn on_dealloc(ptr: *mut u8) {
+    TABLE.lock().remove(ptr as usize);
+}
+
+// その場で大きさを変えたときに呼ぶ
+pub fn on_resize(ptr: *mut u8, size: usize) {
+    if let Some(record) = TABLE.lock().get_mut(ptr as usize) {
+        record.size = size;
+    }
+}
+
+// checkpointより後に確保されて、まだ解放されていないものを順に渡す
+// fの中で確保してもよいように、ロックは1つ読むたびに外す
+// （そのため、fの中の確保と解放で表が詰め直されると、読み飛ばしや重複が起こりうる）
+pub fn for_each_live_since(since: Checkpoint, mut f: impl FnMut(&AllocRecord)) {
+    for i in 0..MAX_TRACKED {
+        let record = TABLE.lock().slots[i];
+        if let Some(record) = record.filter(|r| r.seq >= since.0) {
+            f(&record);
+        }
+    }
+}
+
+// checkpointより後に確保されて、まだ解放されていないものを表示し、その数を返す
+pub fn dump_leaks(since: Checkpoint) -> usize {
+    let mut count = 0;
+    let mut bytes = 0;
+    for_each_live_since(since, |r| {
+        count += 1;
+        bytes += r.size;
+        warn!(
+            "leak #{}: {} bytes at {:#018X}, allocated from {:X?}",
+            r.seq, r.size, r.ptr, r.callers
+        );
+    });
+    if count != 0 {
+        warn!("{count} allocations ({bytes} bytes) are still live");
+    }
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if dropped != 0 {
+        warn!("{dropped} allocations were not tracked because the table was full");
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::boxed::Box;
+
+    fn record(ptr: usize) -> AllocRecord {
+        AllocRecord {
+            ptr,
+            size: 8,
+            seq: 0,
+            callers: [0; MAX_CALLER_FRAMES],
+        }
+    }
+
+    #[test_case]
+    fn removing_keeps_colliding_records_reachable() {
+        let mut table = Table::<8>::new();
+        // 同じ位置に集まるキーを探す
+        let home = Table::<8>::home(0x1000);
+        let keys: alloc::vec::Vec<usize> = (0x1000..)
+            .step_by(16)
+            .filter(|k| Table::<8>::home(*k) == home)
+            .take(4)
+            .collect();
+        for k in &keys {
+            assert!(table.insert(record(*k)));
+        }
+        assert_eq!(table.remove(keys[1]).map(|r| r.ptr), Some(keys[1]));
+        assert!(table.remove(keys[1]).is_none());
+        for k in [keys[0], keys[2], keys[3]] {
+            assert!(table.find(k).is_some());
+        }
+        for k in 0..4 {
+            table.insert(record(0x8000 + k * 16));
+        }
+        assert_eq!(table.len, 7);
+        table.insert(record(0x9000));
+        assert!(!table.insert(record(0xa000)));
+    }
+    #[test_case]
+    fn live_allocations_after_a_checkpoint_are_reported() {
+        // 結果を入れるVec自身を数えないように、先に確保しておく
+        let mut found = alloc::vec::Vec::with_capacity(16);
+        let before = checkpoint();
+        let leaked = Box::new(0x1234u64);
+        let freed = Box::new(0u64);
+        drop(freed);
+        let leaked_addr = &*leaked as *const u64 as usize;
+        for_each_live_since(before, |r| found.push((r.ptr, r.size)));
+        assert!(found.contains(&(leaked_addr, 8)));
+        assert_eq!(found.len(), 1);
+        drop(leaked);
+        assert_eq!(dump_leaks(before), 0);
+    }
+}
@@ -1,19 +1,41 @@
 extern crate alloc;
 
+#[cfg(feature = "alloc_tracking")]
+use crate::alloc_track;
+use crate::buddy::BuddyAllocator;
+use crate::buddy::FreeBlocks;
+use crate::cpu_cache::class_layout;
+use crate::cpu_cache::size_class;
+use crate::cpu_cache::CacheStats;
+use crate::cpu_cache::CpuCaches;
+use crate::error;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::PAGE_SIZE;
+use crate::panic::halt_or_reboot;
+use crate::qemu::QemuExitCode;
+use crate::reserve;
 use crate::result::Result;
-use crate::uefi::EfiMemoryDescriptor;
+use crate::serial::switch_to_sync_tx;
+use crate::sync::SpinLock;
+use crate::sync::SpinLockGuard;
 use crate::uefi::EfiMemoryType;
-use crate::uefi::MemoryMapHolder;
+use crate::uefi::MemoryRegions;
+use crate::x86::fill_zero;
 use alloc::alloc::GlobalAlloc;
 use alloc::alloc::Layout;
 use alloc::boxed::Box;
-use core::borrow::BorrowMut;
-use core::cell::RefCell;
 use core::cmp::max;
+use core::cmp::min;
 use core::fmt;
+use core::mem::align_of;
 use core::mem::size_of;
-use core::ops::DerefMut;
+use core::ops::Range;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::null;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
 
 // v以上の最も近い2のべき乗を求める関数
 pub fn round_up_to_nearest_pow2(v: usize) -> Result<usize> {
@@ -27,23 +49,62 @@ struct Header {
     next_header: Option<Box<Header>>, // 次の空きブロックへのスマートポインタ
     size: usize,                      // このHeaderが管理するメモリブロックの「データ領域」のサイズ
     is_allocated: bool,               // このブロックが割り当て済み（true）か空き（false）か
-    _reserved: usize,
+    is_padding: bool, // アラインメントの隙間を埋めるブロック（直前のブロックと一緒に解放する）
+    magic: u32,       // Headerが壊れていないかを確かめるための印（HEADER_MAGIC）
+    requested: usize, // 要求されたデータ領域のサイズ（レッドゾーンの位置。なければNO_REDZONE）
 }
 const HEADER_SIZE: usize = size_of::<Header>(); // Header構造体自体のサイズ (32バイト)
 const _: () = assert!(HEADER_SIZE == 32); // ヘッダーサイズが32バイトであることを保証
 const _: () = assert!(HEADER_SIZE.count_ones() == 1); // ヘッダーサイズが2のべき乗であることを保証
 pub const LAYOUT_PAGE_4K: Layout = unsafe { Layout::from_size_align_unchecked(4096, 4096) }; // 4KBページのレイアウト定義
 
-impl Header {
-    // メモリ割り当てが可能かどうかの確認
-    // size: ユーザーが要求しているデータ領域のサイズ
-    // align: ユーザーが要求しているメモリのアラインメント
-    // 純粋な要求サイズ(size)と、アライメント調整による最大オーバーヘッド(align)、
-    // そして新しく切り出すブロック用とパディング領域用の2つのヘッダー(HEADER_SIZE * 2)
-    // の合計を含めても、現在の空き領域は十分かどうかの判定（安全側の概算チェック）
-    fn can_provide(&self, size: usize, align: usize) -> bool {
-        self.size >= size + HEADER_SIZE * 2 + align
+// 壊れたHeaderを見分けるための値
+const HEADER_MAGIC: u32 = 0x5041_4548; // "HEAP"
+
+// heap_poisonフィーチャーを有効にすると、割り当てたデータ領域の後ろにレッドゾーンを置き、
+// 解放したメモリをPOISONで埋める（verify_heap()で書き換えられていないかを調べられる）
+const POISON_ENABLED: bool = cfg!(feature = "heap_poison");
+const POISON: u64 = 0xDEAD_BEEF_DEAD_BEEF;
+// データ領域の直後に置く、書き込まれてはいけない領域
+const REDZONE_SIZE: usize = if POISON_ENABLED { 16 } else { 0 };
+const REDZONE_BYTE: u8 = 0xFD;
+const NO_REDZONE: usize = usize::MAX;
+
+// [start, end)をPOISONで埋める（どちらも8バイトに揃っていること）
+unsafe fn poison(start: usize, end: usize) {
+    let mut p = start as *mut u64;
+    while (p as usize) < end {
+        p.write(POISON);
+        p = p.add(1);
     }
+}
+
+// [start, end)のうち、POISONでなくなっている最初のアドレス
+unsafe fn find_unpoisoned(start: usize, end: usize) -> Option<usize> {
+    (start..end)
+        .step_by(size_of::<u64>())
+        .find(|addr| (*addr as *const u64).read() != POISON)
+}
+
+// Headerを置くアドレスの揃え方 (8バイト)
+const HEADER_ALIGN: usize = align_of::<Header>();
+// これより小さい空きブロックは、ヘッダーを除くとほとんど何も入らないので残さない
+const MIN_BLOCK_SIZE: usize = HEADER_SIZE + 32;
+
+// 空きブロックのどこに割り当てるか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Placement {
+    // 割り当てるブロックのHeaderのアドレス
+    header_addr: usize,
+    // ユーザーに返すデータ領域のアドレス
+    data_addr: usize,
+    // 割り当てるブロックの終わり（空きブロックの末尾と違えば、そこからはパディング用のブロック）
+    block_end: usize,
+    // 前に残る空きが小さすぎるので、空きブロックをまるごと渡す
+    whole_block: bool,
+}
+
+impl Header {
     fn is_allocated(&self) -> bool {
         self.is_allocated
     }
@@ -53,6 +114,45 @@ impl Header {
     fn end_addr(&self) -> usize {
         self as *const Header as usize + self.size
     }
+    fn data_addr(&self) -> usize {
+        self as *const Header as usize + HEADER_SIZE
+    }
+    // Headerが壊れていればpanicする
+    fn check(&self) {
+        let addr = self as *const Header as usize;
+        if self.magic != HEADER_MAGIC {
+            panic!("heap corruption: header at {addr:#X} is broken");
+        }
+        if self.size < HEADER_SIZE || !self.size.is_multiple_of(HEADER_ALIGN) {
+            panic!(
+                "heap corruption: header at {addr:#X} has a bad size {:#X}",
+                self.size
+            );
+        }
+    }
+    // 割り当てたサイズを記録し、その後ろにレッドゾーンを置く
+    fn set_requested(&mut self, size: usize) {
+        self.requested = size;
+        if POISON_ENABLED {
+            let redzone = self.data_addr() + size;
+            unsafe { (redzone as *mut u8).write_bytes(REDZONE_BYTE, REDZONE_SIZE) };
+        }
+    }
+    // レッドゾーンが書き換えられていればpanicする
+    fn check_redzone(&self) {
+        if !POISON_ENABLED || self.requested == NO_REDZONE {
+            return;
+        }
+        let redzone = self.data_addr() + self.requested;
+        if let Some(addr) = (redzone..redzone + REDZONE_SIZE)
+            .find(|addr| unsafe { (*addr as *const u8).read() } != REDZONE_BYTE)
+        {
+            panic!(
+                "heap corruption: block at {:#X} was written past its end at {addr:#X}",
+                self as *const Header as usize
+            );
+        }
+    }
     // 指定されたアドレスに新しいHeader構造体を配置・初期化する (unsafe操作)
     // addr: Headerを配置したいメモリ上のアドレス
     unsafe fn new_from_addr(addr: usize) -> Box<Header> {
@@ -61,7 +161,9 @@ impl Header {
             next_header: None,
             size: 0,
             is_allocated: false,
-            _reserved: 0,
+            is_padding: false,
+            magic: HEADER_MAGIC,
+            requested: NO_REDZONE,
         });
         Box::from_raw(addr as *mut Header)
     }
@@ -73,47 +175,187 @@ impl Header {
         let header = addr.sub(HEADER_SIZE) as *mut Header;
         Box::from_raw(header)
     }
+    // 割り当てる場所を実際のアドレスで求める（割り当てられなければNone）
+    // size: ユーザーが要求しているデータ領域のサイズ
+    // align: ユーザーが要求しているメモリのアラインメント
+    // 空きブロックの末尾から切り出すので、前にはselfのHeaderを含む空きが残る
+    fn placement(&self, size: usize, align: usize) -> Option<Placement> {
+        if self.is_allocated() {
+            return None;
+        }
+        // 後ろに置くHeaderが揃うように、サイズとアラインメントはHEADER_ALIGNの倍数にする
+        let size = max(size, 1).checked_next_multiple_of(HEADER_ALIGN)?;
+        let align = max(align, HEADER_ALIGN);
+        let start = self as *const Header as usize;
+        let end = self.end_addr();
+
+        let data_addr = end.checked_sub(size)? & !(align - 1);
+        let header_addr = data_addr.checked_sub(HEADER_SIZE)?;
+        // アラインメントで後ろに空いた隙間は、Headerが入るときだけパディング用のブロックにする
+        // Headerが入らない隙間は、割り当てるブロックに含めてしまう
+        let block_end = if end - (data_addr + size) >= HEADER_SIZE {
+            data_addr + size
+        } else {
+            end
+        };
+        let remaining = header_addr.checked_sub(start)?;
+        if remaining < MIN_BLOCK_SIZE {
+            // 前に残る空きが小さければ、selfをそのまま割り当てたブロックにする
+            let data_addr = start + HEADER_SIZE;
+            if data_addr.is_multiple_of(align) && data_addr + size <= end {
+                return Some(Placement {
+                    header_addr: start,
+                    data_addr,
+                    block_end: end,
+                    whole_block: true,
+                });
+            }
+            // selfのHeaderが残せなければ割り当てられない
+            if remaining < HEADER_SIZE {
+                return None;
+            }
+        }
+        Some(Placement {
+            header_addr,
+            data_addr,
+            block_end,
+            whole_block: false,
+        })
+    }
     // メモリ割り当てのメインロジック
     fn provide(&mut self, size: usize, align: usize) -> Option<*mut u8> {
-        // sizeとalignをHEADER_SIZEの倍数などに丸める
-        let size = max(round_up_to_nearest_pow2(size).ok()?, HEADER_SIZE);
-        let align = max(align, HEADER_SIZE);
+        // 後ろに解放されたブロックが続いていれば、先にまとめてから割り当てる
+        if !self.is_allocated() {
+            self.merge_following_free_blocks();
+        }
+        let placement = self.placement(size, align)?;
+        if placement.whole_block {
+            self.is_allocated = true;
+            return Some(placement.data_addr as *mut u8);
+        }
+        let end = self.end_addr();
 
-        // 現在のブロックが割り当て済みか、必要なサイズ・アライメントを満たさない場合は割り当て不可
-        if self.is_allocated() || !self.can_provide(size, align) {
-            None
-        } else {
-            // 使用したサイズ
-            let mut size_used = 0;
-
-            // 割り当て開始アドレスの計算: 空きブロックの末尾から領域を切り出し、alignの倍数に切り下げる
-            let allocated_addr = (self.end_addr() - size) & !(align - 1);
-
-            // 割り当てる領域用のHeaderを、allocated_addrの直前（- HEADER_SIZE）に配置
-            let mut header_for_allocated =
-                unsafe { Self::new_from_addr(allocated_addr - HEADER_SIZE) };
-            header_for_allocated.is_allocated = true;
-            size_used += header_for_allocated.size;
-            header_for_allocated.next_header = self.next_header.take();
-
-            // 隙間（パディング）の処理: アライメント調整によってselfの末尾と新ブロックの間に隙間ができた場合
-            if header_for_allocated.end_addr() != self.end_addr() {
-                // 隙間（パディング領域）用のHeaderを作成し、割り当て済みとしてマーク
-                let mut header_for_padding =
-                    unsafe { Self::new_from_addr(header_for_allocated.end_addr()) };
-                header_for_padding.is_allocated = true;
-                // パディング領域のサイズを計算 (selfの末尾 - 新ブロックの末尾)
-                header_for_padding.size = self.end_addr() - header_for_allocated.end_addr();
-                size_used += header_for_padding.size;
-                header_for_padding.next_header = header_for_allocated.next_header.take();
-                header_for_allocated.next_header = Some(header_for_padding);
-            }
-            // 元の空きブロック (self) を縮小し、新しく切り出したブロックをリストに繋ぐ
-            assert!(self.size >= size_used + HEADER_SIZE);
-            self.size -= size_used;
-            self.next_header = Some(header_for_allocated);
-            Some(allocated_addr as *mut u8)
+        // 割り当てる領域用のHeaderを、データ領域の直前（- HEADER_SIZE）に配置
+        let mut header_for_allocated = unsafe { Self::new_from_addr(placement.header_addr) };
+        header_for_allocated.is_allocated = true;
+        header_for_allocated.size = placement.block_end - placement.header_addr;
+        header_for_allocated.next_header = self.next_header.take();
+
+        // 隙間（パディング）の処理: アライメント調整によってselfの末尾と新ブロックの間に隙間ができた場合
+        if placement.block_end != end {
+            // 隙間（パディング領域）用のHeaderを作成し、割り当て済みとしてマーク
+            let mut header_for_padding = unsafe { Self::new_from_addr(placement.block_end) };
+            header_for_padding.is_allocated = true;
+            header_for_padding.is_padding = true;
+            header_for_padding.size = end - placement.block_end;
+            header_for_padding.next_header = header_for_allocated.next_header.take();
+            header_for_allocated.next_header = Some(header_for_padding);
+        }
+        // 元の空きブロック (self) を縮小し、新しく切り出したブロックをリストに繋ぐ
+        self.size = placement.header_addr - self as *const Header as usize;
+        self.next_header = Some(header_for_allocated);
+        Some(placement.data_addr as *mut u8)
+    }
+    // 割り当て済みブロックを空きに戻す
+    // ptr: allocが返したデータ領域の開始アドレス
+    unsafe fn release(ptr: *mut u8) {
+        // 1. データアドレスから、その直前のHeaderを逆算して取得し、Boxで管理下に置く。
+        let mut region = Header::from_allocated_region(ptr);
+        region.check();
+        region.check_redzone();
+        region.requested = NO_REDZONE;
+
+        // 2. 割り当てフラグを解除し、空きに戻す。
+        //    ブロックは割り当てたときからリストに繋がったままなので、次のallocの探索で再び使われる。
+        region.is_allocated = false;
+
+        // 3. アラインメントのために後ろに作ったパディングも空きに戻し、後ろの空きブロックと1つにまとめる。
+        //    前の空きブロックとは、呼び出し側がリストを辿ってまとめる。
+        if let Some(padding) = region.next_header.as_mut() {
+            if padding.is_padding {
+                padding.is_padding = false;
+                padding.is_allocated = false;
+            }
+        }
+        region.merge_following_free_blocks();
+        if POISON_ENABLED {
+            poison(region.data_addr(), region.end_addr());
+        }
+
+        // 4. Boxの所有権を意図的に放棄（leak）することで、Headerのdrop（panic!）を防ぎ、
+        //    Header構造体をメモリ上に残す。
+        Box::leak(region);
+    }
+    // 直後に隣接している空きブロックを自分に取り込み、取り込んだ数を返す
+    // 解放されたブロックは切り出したときの位置のままリストに繋がっているので、
+    // 前の空きブロックとまとめ直さないと、同じ大きさ以下の要求にしか使われず、ヒープが細切れになっていく
+    fn merge_following_free_blocks(&mut self) -> usize {
+        let mut merged = 0;
+        while let Some(next) = self.next_header.as_ref() {
+            if next.is_allocated() || &**next as *const Header as usize != self.end_addr() {
+                break;
+            }
+            let mut next = self.next_header.take().unwrap();
+            self.size += next.size;
+            self.next_header = next.next_header.take();
+            // 取り込んだHeaderはただのデータ領域になるので、dropせずに捨てる
+            let next_addr = Box::leak(next) as *mut Header as usize;
+            if POISON_ENABLED {
+                unsafe { poison(next_addr, next_addr + HEADER_SIZE) };
+            }
+            merged += 1;
+        }
+        merged
+    }
+    // 割り当て済みのブロックを後ろの空きブロックに広げて、データ領域をnew_sizeバイト以上にする
+    // 後ろが空いていなければ何もせずにfalseを返す（小さくするときは何もせずにtrueを返す）
+    fn grow_in_place(&mut self, new_size: usize) -> bool {
+        let data_addr = self as *const Header as usize + HEADER_SIZE;
+        let Some(new_end) = new_size
+            .checked_next_multiple_of(HEADER_ALIGN)
+            .and_then(|size| data_addr.checked_add(size))
+        else {
+            return false;
+        };
+        if new_end <= self.end_addr() {
+            return true;
         }
+        // 直後に隣接している空きブロック（自分のパディングを含む）だけで足りるかを先に調べる
+        let mut end = self.end_addr();
+        let mut next = self.next_header.as_deref();
+        while end < new_end {
+            match next {
+                Some(h)
+                    if h as *const Header as usize == end
+                        && (!h.is_allocated() || h.is_padding) =>
+                {
+                    end = h.end_addr();
+                    next = h.next_header.as_deref();
+                }
+                _ => return false,
+            }
+        }
+        while self.end_addr() < new_end {
+            let mut next = self.next_header.take().unwrap();
+            self.size += next.size;
+            self.next_header = next.next_header.take();
+            // 取り込んだHeaderはただのデータ領域になるので、dropせずに捨てる
+            Box::leak(next);
+        }
+        // 取り込みすぎた分が十分に大きければ、空きブロックとして切り離す
+        let rest = self.end_addr() - new_end;
+        if rest >= MIN_BLOCK_SIZE {
+            let mut header_for_rest = unsafe { Self::new_from_addr(new_end) };
+            header_for_rest.size = rest;
+            if POISON_ENABLED {
+                unsafe { poison(header_for_rest.data_addr(), header_for_rest.end_addr()) };
+            }
+            header_for_rest.next_header = self.next_header.take();
+            header_for_rest.merge_following_free_blocks();
+            self.size -= rest;
+            self.next_header = Some(header_for_rest);
+        }
+        true
     }
 }
 // panicさせることによって自動解放を防ぐ
@@ -135,22 +377,212 @@ impl fmt::Debug for Header {
     }
 }
 
+// 確保されたまま解放されていないメモリの量（要求されたサイズの合計）と個数
+// テストの前後で比べると、解放し忘れたメモリが分かる
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    pub allocated_bytes: usize,
+    pub live_allocation_count: usize,
+}
+
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static LIVE_ALLOCATION_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// 空きリストに登録したメモリの合計（0ならヒープは使えない）
+static HEAP_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// reclaim_boot_memoryを呼んだか、と加えたバイト数
+static BOOT_MEMORY_RECLAIMED: AtomicBool = AtomicBool::new(false);
+static RECLAIMED_BOOT_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+pub fn reclaimed_boot_memory() -> usize {
+    RECLAIMED_BOOT_MEMORY.load(Ordering::SeqCst)
+}
+
+// init_with_pagesで最初にヒープに加えた範囲の先頭
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+// ヒープが足りなくなったときに、ページアロケータから一度にもらう最小の大きさ
+const HEAP_GROW_BYTES: usize = 1 << 20;
+
+pub fn heap_size() -> usize {
+    HEAP_BYTES.load(Ordering::SeqCst)
+}
+
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::SeqCst)
+}
+
+pub fn heap_available() -> bool {
+    heap_size() != 0
+}
+
+// panicでヒープのロックを持ったまま抜けたときに外す（panicの後も動き続けるテストランナー用）
+// panicした確保や解放には戻らないので、ロックを持っていた側とぶつかることはない
+pub fn reset_after_panic() {
+    unsafe {
+        ALLOCATOR.first_fit.force_unlock();
+        ALLOCATOR.buddy.force_unlock();
+        ALLOCATOR.caches.force_unlock();
+    }
+}
+
+pub fn alloc_stats() -> AllocStats {
+    AllocStats {
+        allocated_bytes: ALLOCATED_BYTES.load(Ordering::SeqCst),
+        live_allocation_count: LIVE_ALLOCATION_COUNT.load(Ordering::SeqCst),
+    }
+}
+
+// ヒープの中のブロック（First-FitではHeaderを含む大きさ）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapBlock {
+    pub addr: usize,
+    pub size: usize,
+    pub is_allocated: bool,
+}
+
+// ヒープ全体の様子（バイト数は管理用のHeaderなども含む）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub total_bytes: usize,
+    pub used_bytes: usize,
+    pub free_bytes: usize,
+    // これより大きい確保は、ページアロケータからページをもらわないとできない
+    pub largest_free_block: usize,
+    // 使用中と空きのブロックの数（バディアロケータでは使用中のブロックは確保の数で数える）
+    pub block_count: usize,
+    pub free_block_count: usize,
+}
+impl HeapStats {
+    pub fn from_blocks(total_bytes: usize, blocks: impl Iterator<Item = HeapBlock>) -> Self {
+        let mut stats = Self {
+            total_bytes,
+            ..Default::default()
+        };
+        for block in blocks {
+            stats.block_count += 1;
+            if block.is_allocated {
+                stats.used_bytes += block.size;
+            } else {
+                stats.free_bytes += block.size;
+                stats.free_block_count += 1;
+                stats.largest_free_block = stats.largest_free_block.max(block.size);
+            }
+        }
+        stats
+    }
+
+    // 空きのうち、一番大きな空きブロックに入っていない割合（%）
+    // 0なら空きが1つにまとまっていて、100に近いほど細切れになっている
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        100 - self.largest_free_block * 100 / self.free_bytes
+    }
+}
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} KiB used, {} KiB free of {} KiB, largest free block {} KiB ({}% fragmented), {} blocks ({} free)",
+            self.used_bytes / 1024,
+            self.free_bytes / 1024,
+            self.total_bytes / 1024,
+            self.largest_free_block / 1024,
+            self.fragmentation_percent(),
+            self.block_count,
+            self.free_block_count
+        )
+    }
+}
+
+// FirstFitAllocatorのブロックを、リストの順に返す
+// 返している間はリストのロックを持っているので、その間に確保や解放をしないこと（デッドロックする）
+pub struct FirstFitBlocks<'a> {
+    _list: SpinLockGuard<'a, FirstFitList>,
+    next: *const Header,
+}
+impl Iterator for FirstFitBlocks<'_> {
+    type Item = HeapBlock;
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = unsafe { self.next.as_ref()? };
+        self.next = header
+            .next_header
+            .as_deref()
+            .map_or(null(), |h| h as *const Header);
+        Some(HeapBlock {
+            addr: header as *const Header as usize,
+            size: header.size,
+            is_allocated: header.is_allocated(),
+        })
+    }
+}
+
+// ALLOCATOR.blocks()が返すイテレータ（バディアロケータは空きブロックだけを返す）
+pub enum HeapBlocks<'a> {
+    FirstFit(FirstFitBlocks<'a>),
+    Buddy(FreeBlocks<'a>),
+}
+impl Iterator for HeapBlocks<'_> {
+    type Item = HeapBlock;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::FirstFit(blocks) => blocks.next(),
+            Self::Buddy(blocks) => blocks.next().map(|(addr, size)| HeapBlock {
+                addr,
+                size,
+                is_allocated: false,
+            }),
+        }
+    }
+}
+
 // ヒープメモリ全体を管理するコンテナ
 pub struct FirstFitAllocator {
+    // 割り込みハンドラや他のCPUからも確保・解放されるので、リストはスピンロックで守る
+    // （ロックを持っている間は割り込みが禁止されるので、同じCPUの割り込みハンドラとはぶつからない）
+    list: SpinLock<FirstFitList>,
+}
+
+// スピンロックで守る、FirstFitAllocatorの中身
+struct FirstFitList {
     // 空きメモリブロックの連結リストの先頭 (Headerへのスマートポインタ) を格納。
-    // RefCellにより、静的変数（イミュータブル）でも内部のデータを可変に扱う（書き換える）ことを可能にしている。
-    first_header: RefCell<Option<Box<Header>>>,
+    first_header: Option<Box<Header>>,
+    policy: FitPolicy,
+    // Next-Fitで前回割り当てた空きブロックのアドレス（リストから消えていれば先頭から探す）
+    next_fit_cursor: usize,
 }
 
-// ここでglobal_allocatorアトリビュートを設定することによって、
-// Rustプログラム全体（Box, Vec, Stringなど）のメモリの確保・解放をこの静的変数ALLOCATORに依頼するようになる。
-#[global_allocator]
-pub static ALLOCATOR: FirstFitAllocator = FirstFitAllocator {
-    first_header: RefCell::new(None),
-};
+// FirstFitAllocatorが空きブロックを選ぶ方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FitPolicy {
+    // リストの先頭から探し、最初に入るブロックを使う
+    #[default]
+    FirstFit,
+    // 全ての空きブロックを調べ、入るもののうち一番小さいブロックを使う
+    BestFit,
+    // 前回割り当てたブロックから探し始め、末尾まで行ったら先頭に戻る
+    NextFit,
+}
+impl FitPolicy {
+    pub const ALL: [Self; 3] = [Self::FirstFit, Self::BestFit, Self::NextFit];
 
-// 複数のスレッドから安全に共有できるとコンパイラに宣言するためのトレイト（ここではunsafeで仮定）
-unsafe impl Sync for FirstFitAllocator {}
+    // コマンドラインのheap_fit=first|best|nextを読む
+    pub fn from_command_line(command_line: &str) -> Self {
+        command_line
+            .split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("heap_fit="))
+            .find_map(|v| match v {
+                "first" => Some(Self::FirstFit),
+                "best" => Some(Self::BestFit),
+                "next" => Some(Self::NextFit),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
 
 unsafe impl GlobalAlloc for FirstFitAllocator {
     // メモリの確保（GlobalAllocインターフェース）
@@ -161,37 +593,545 @@ unsafe impl GlobalAlloc for FirstFitAllocator {
     // メモリの解放（GlobalAllocインターフェース）
     // ptr: ユーザーから返されたデータ領域の開始アドレス
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // 1. データアドレスから、その直前のHeaderを逆算して取得し、Boxで管理下に置く。
-        let mut region = Header::from_allocated_region(ptr);
+        let mut list = self.list.lock();
+        Header::release(ptr);
+        list.merge_into_previous(ptr as usize - HEADER_SIZE);
+    }
 
-        // 2. 解放処理の第一段階として、割り当てフラグを解除し、空きに戻す。
-        region.is_allocated = false;
+    // 後ろの空きブロックに広げられれば、コピーせずにそのまま返す
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.grow_in_place(ptr, new_size) {
+            return ptr;
+        }
+        move_allocation(self, ptr, layout, new_size)
+    }
+}
 
-        // 3. Boxの所有権を意図的に放棄（leak）することで、Headerのdrop（panic!）を防ぎ、
-        //    Header構造体をメモリ上に残し、後で空きリストに再挿入できるようにする。
-        Box::leak(region);
-        // Note: この後、`dealloc`メソッドの続きで空きリストへの再挿入処理が行われるはず。
+// 新しい場所を確保して中身をコピーし、元の場所を解放する（GlobalAllocのreallocの既定の動作）
+unsafe fn move_allocation(
+    allocator: &impl GlobalAlloc,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        copy_nonoverlapping(ptr, new_ptr, min(layout.size(), new_size));
+        allocator.dealloc(ptr, layout);
+    }
+    new_ptr
+}
+
+// ヒープの実装
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeapBackend {
+    // Header付きのブロックのリストを先頭から探す
+    #[default]
+    FirstFit,
+    // 2のべき乗の大きさのブロックをオーダーごとの空きリストで管理する
+    Buddy,
+}
+impl HeapBackend {
+    // コマンドラインにheap=buddyがあればバディアロケータを使う
+    pub fn from_command_line(command_line: &str) -> Self {
+        command_line
+            .split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("heap="))
+            .find_map(|v| match v {
+                "first-fit" => Some(Self::FirstFit),
+                "buddy" => Some(Self::Buddy),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+}
+
+// カーネルのヒープ
+// 初期化のときに選んだ実装に、確保と解放をそのまま渡す
+// 小さな確保は、CPUごとのキャッシュ（cpu_cache）を通す
+pub struct KernelAllocator {
+    first_fit: FirstFitAllocator,
+    buddy: BuddyAllocator,
+    use_buddy: AtomicBool,
+    caches: CpuCaches,
+}
+
+// これ以上の大きさの確保は、ヒープを通さずにページ単位でページアロケータからもらう
+pub const LARGE_ALLOC_BYTES: usize = 128 * 1024;
+
+fn is_large(layout: Layout) -> bool {
+    layout.size() >= LARGE_ALLOC_BYTES && layout.align() <= PAGE_SIZE
+}
+
+// 解放されたブロックのポイズンや境界の検査を効かせるため、heap_poisonではキャッシュを使わない
+const CACHE_ENABLED: bool = !POISON_ENABLED;
+
+// CPUごとのキャッシュを通す確保なら、その大きさの区分
+fn cached_class(layout: Layout) -> Option<usize> {
+    if !CACHE_ENABLED {
+        return None;
+    }
+    size_class(layout)
+}
+
+fn pages_for(size: usize) -> usize {
+    size.div_ceil(PAGE_SIZE)
+}
+
+// 確保に成功したときの統計と記録
+#[inline(always)]
+fn note_alloc(p: *mut u8, layout: Layout) {
+    ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::SeqCst);
+    LIVE_ALLOCATION_COUNT.fetch_add(1, Ordering::SeqCst);
+    #[cfg(feature = "alloc_tracking")]
+    alloc_track::on_alloc(p, layout.size());
+    #[cfg(not(feature = "alloc_tracking"))]
+    let _ = p;
+}
+
+// ここでglobal_allocatorアトリビュートを設定することによって、
+// Rustプログラム全体（Box, Vec, Stringなど）のメモリの確保・解放をこの静的変数ALLOCATORに依頼するようになる。
+#[global_allocator]
+pub static ALLOCATOR: KernelAllocator = KernelAllocator {
+    first_fit: FirstFitAllocator::new(),
+    buddy: BuddyAllocator::new(),
+    use_buddy: AtomicBool::new(false),
+    caches: CpuCaches::new(),
+};
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with_options(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::SeqCst);
+        LIVE_ALLOCATION_COUNT.fetch_sub(1, Ordering::SeqCst);
+        #[cfg(feature = "alloc_tracking")]
+        alloc_track::on_dealloc(ptr);
+        if is_large(layout) {
+            PAGE_ALLOCATOR.free_pages(ptr, pages_for(layout.size()));
+            return;
+        }
+        if let Some(class) = cached_class(layout) {
+            self.caches.dealloc(class, ptr, |p| {
+                self.dealloc_to_backend(p, class_layout(class))
+            });
+            return;
+        }
+        self.dealloc_to_backend(ptr, layout)
+    }
+
+    // 0で埋めたメモリを確保する
+    // 大きな確保は、空いている間に0で埋めておいたページをそのまま使い、埋め直さない
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if is_large(layout) && heap_available() {
+            let Ok(p) = PAGE_ALLOCATOR.alloc_zeroed_pages(pages_for(layout.size())) else {
+                return null_mut();
+            };
+            note_alloc(p, layout);
+            return p;
+        }
+        let p = self.alloc_with_options(layout);
+        if !p.is_null() {
+            fill_zero(p, layout.size());
+        }
+        p
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // ページアロケータから直接もらったものは、ヒープのブロックではないので移す
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let crosses_pages = is_large(layout) || is_large(new_layout);
+        // キャッシュのブロックは区分の大きさで確保してあるので、同じ区分の中ならそのまま使える
+        // 区分をまたぐときは、ブロックの大きさが区分と食い違わないように移す
+        let resized_in_place = match (cached_class(layout), cached_class(new_layout)) {
+            (None, None) => {
+                !crosses_pages
+                    && self.backend() == HeapBackend::FirstFit
+                    && self.first_fit.grow_in_place(ptr, new_size)
+            }
+            (old, new) => old == new,
+        };
+        if resized_in_place {
+            ALLOCATED_BYTES.fetch_add(new_size, Ordering::SeqCst);
+            ALLOCATED_BYTES.fetch_sub(layout.size(), Ordering::SeqCst);
+            #[cfg(feature = "alloc_tracking")]
+            alloc_track::on_resize(ptr, new_size);
+            return ptr;
+        }
+        // 移すときは、確保と解放の統計やヒープの拡張をalloc/deallocに任せる
+        move_allocation(self, ptr, layout, new_size)
     }
 }
 
+impl KernelAllocator {
+    pub fn backend(&self) -> HeapBackend {
+        if self.use_buddy.load(Ordering::Relaxed) {
+            HeapBackend::Buddy
+        } else {
+            HeapBackend::FirstFit
+        }
+    }
+
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        // 大きな確保はヒープを細切れにしないように、ページアロケータから直接もらう
+        if is_large(layout) && heap_available() {
+            let Ok(p) = PAGE_ALLOCATOR.alloc_pages(pages_for(layout.size())) else {
+                return null_mut();
+            };
+            note_alloc(p, layout);
+            return p;
+        }
+        let p = match cached_class(layout) {
+            // マガジンが空なら、区分の大きさのブロックをまとめてヒープからもらう
+            Some(class) => self
+                .caches
+                .alloc(class, |layout| self.alloc_or_grow(layout)),
+            None => self.alloc_or_grow(layout),
+        };
+        if !p.is_null() {
+            note_alloc(p, layout);
+        }
+        p
+    }
+
+    fn alloc_or_grow(&self, layout: Layout) -> *mut u8 {
+        let mut p = self.alloc_from_backend(layout);
+        // 空きがなければページアロケータからページをもらって、もう一度だけ試す
+        if p.is_null() && heap_available() && self.grow(layout) {
+            p = self.alloc_from_backend(layout);
+        }
+        p
+    }
+
+    fn alloc_from_backend(&self, layout: Layout) -> *mut u8 {
+        match self.backend() {
+            HeapBackend::FirstFit => self.first_fit.alloc_with_options(layout),
+            HeapBackend::Buddy => self.buddy.alloc_with_options(layout),
+        }
+    }
+
+    unsafe fn dealloc_to_backend(&self, ptr: *mut u8, layout: Layout) {
+        match self.backend() {
+            HeapBackend::FirstFit => self.first_fit.dealloc(ptr, layout),
+            HeapBackend::Buddy => self.buddy.dealloc(ptr, layout),
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.caches.stats()
+    }
+
+    // CPUごとのキャッシュに取ってあるブロックを全てヒープに返し、返した数を返す
+    // ヒープの断片化を調べる前などに使う
+    pub fn drain_caches(&self) -> usize {
+        self.caches
+            .drain(|p, layout| unsafe { self.dealloc_to_backend(p, layout) })
+    }
+
+    // ヒープのブロックを順に返す（デバッグ用）
+    // 返している間はヒープのロックを持っているので、その間に確保や解放をしないこと（デッドロックする）
+    pub fn blocks(&self) -> HeapBlocks<'_> {
+        match self.backend() {
+            HeapBackend::FirstFit => HeapBlocks::FirstFit(self.first_fit.blocks()),
+            HeapBackend::Buddy => HeapBlocks::Buddy(self.buddy.free_blocks()),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats::from_blocks(heap_size(), self.blocks());
+        // バディアロケータは割り当てたブロックを覚えていないので、空きから求める
+        if self.backend() == HeapBackend::Buddy {
+            stats.used_bytes = stats.total_bytes - stats.free_bytes;
+            // キャッシュに取ってあるブロックも、ヒープから見ると確保されている
+            stats.block_count +=
+                LIVE_ALLOCATION_COUNT.load(Ordering::SeqCst) + self.caches.stats().cached_objects;
+        }
+        stats
+    }
+
+    // ヒープが壊れていないかを調べ、調べたブロックの数を返す
+    // バディアロケータは割り当てたブロックにHeaderを付けていないので、何も調べずに0を返す
+    pub fn verify_heap(&self) -> usize {
+        match self.backend() {
+            HeapBackend::FirstFit => self.first_fit.verify(),
+            HeapBackend::Buddy => 0,
+        }
+    }
+
+    // 隣り合った空きブロックをまとめる（バディアロケータは解放するたびにまとめているので何もしない）
+    pub fn defragment(&self) -> usize {
+        match self.backend() {
+            HeapBackend::FirstFit => self.first_fit.defragment(),
+            HeapBackend::Buddy => 0,
+        }
+    }
+
+    // ページアロケータから空きページの半分を受け取ってヒープを初期化する
+    // 残りはページテーブルなどのために取っておき、ヒープが足りなくなったらそこから広げる
+    // 確保を始めた後で実装を切り替えることはできないので、一度だけ呼ぶこと
+    // First-Fitの実装では、空きブロックの選び方をpolicyにする
+    pub fn init_with_pages(&self, backend: HeapBackend, policy: FitPolicy) {
+        self.use_buddy
+            .store(backend == HeapBackend::Buddy, Ordering::Relaxed);
+        self.first_fit.set_policy(policy);
+        let mut remaining = PAGE_ALLOCATOR.free_page_count() / 2;
+        while remaining > 0 {
+            let Some(run) = PAGE_ALLOCATOR.alloc_largest_run(remaining) else {
+                break;
+            };
+            remaining -= run.len() / PAGE_SIZE;
+            let start = run.start;
+            if self.add_pages(run) {
+                let _ = HEAP_START.compare_exchange(0, start, Ordering::SeqCst, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // ブートサービスを抜けた後は誰も使わないメモリ（BOOT_SERVICES_CODE/DATAとLOADER_DATA）を空きに加え、
+    // 加えたバイト数を返す
+    // ページアロケータが管理できる範囲はページアロケータに、その外はヒープに直接加える
+    // カーネルのイメージ（LOADER_CODE）と、reserve_regionで取っておいた範囲は加えない
+    // UEFIのスタック（BOOT_SERVICES_DATA）から自前のスタックに移った後に呼ぶこと（2回目以降は何もしない）
+    pub fn reclaim_boot_memory(&self, memory_regions: &MemoryRegions) -> usize {
+        if BOOT_MEMORY_RECLAIMED.swap(true, Ordering::SeqCst) {
+            return 0;
+        }
+        let managed = PAGE_ALLOCATOR.managed_range();
+        let mut reclaimed = 0;
+        let boot_memory = memory_regions.iter().filter(|r| {
+            matches!(
+                r.kind,
+                EfiMemoryType::BOOT_SERVICES_CODE
+                    | EfiMemoryType::BOOT_SERVICES_DATA
+                    | EfiMemoryType::LOADER_DATA
+            )
+        });
+        for region in boot_memory {
+            reserve::for_each_unreserved(region.start..region.end(), |range| {
+                let start = (range.start as usize).next_multiple_of(PAGE_SIZE);
+                let end = range.end as usize & !(PAGE_SIZE - 1);
+                if start >= end {
+                    return;
+                }
+                let inside = start.max(managed.start)..end.min(managed.end);
+                if inside.start < inside.end {
+                    reclaimed += PAGE_ALLOCATOR.add_free_memory(inside) * PAGE_SIZE;
+                }
+                for outside in [start..end.min(managed.start), start.max(managed.end)..end] {
+                    if outside.start < outside.end
+                        && self.add_free_range(outside.start, outside.end)
+                    {
+                        reclaimed += outside.len();
+                    }
+                }
+            });
+        }
+        RECLAIMED_BOOT_MEMORY.store(reclaimed, Ordering::SeqCst);
+        reclaimed
+    }
+
+    // layoutを確保できるだけのページをページアロケータから受け取ってヒープに加える
+    fn grow(&self, layout: Layout) -> bool {
+        // ヘッダーやバディアロケータの管理領域、アラインメントの分だけ余分に取る
+        let Ok(size) = round_up_to_nearest_pow2(layout.size().max(layout.align())) else {
+            return false;
+        };
+        let bytes = (size * 2).max(HEAP_GROW_BYTES);
+        let pages = bytes.div_ceil(PAGE_SIZE);
+        let Ok(start) = PAGE_ALLOCATOR.alloc_pages(pages) else {
+            return false;
+        };
+        let start = start as usize;
+        self.add_pages(start..start + pages * PAGE_SIZE)
+    }
+
+    // ページアロケータから受け取った範囲をヒープに加える
+    // ヒープが受け取れなかったときは、ページアロケータに返してfalseを返す
+    fn add_pages(&self, run: Range<usize>) -> bool {
+        if self.add_free_range(run.start, run.end) {
+            return true;
+        }
+        unsafe { PAGE_ALLOCATOR.free_pages(run.start as *mut u8, run.len() / PAGE_SIZE) };
+        false
+    }
+
+    // [start_addr, end)を空きとして登録し、登録できたかを返す
+    fn add_free_range(&self, start_addr: usize, end: usize) -> bool {
+        if end.saturating_sub(start_addr) <= 4096 {
+            return false; // 4KB以下の領域は無視
+        }
+        let added = match self.backend() {
+            HeapBackend::FirstFit => self.first_fit.add_free_range(start_addr, end),
+            HeapBackend::Buddy => self.buddy.add_free_range(start_addr, end),
+        };
+        HEAP_BYTES.fetch_add(added, Ordering::SeqCst);
+        added != 0
+    }
+}
+
+// Box::newなどでメモリを確保できなかったときに呼ばれる
+// panicと区別できるように、専用の終了コードでQEMUを終了する
+#[alloc_error_handler]
+fn on_alloc_error(layout: Layout) -> ! {
+    switch_to_sync_tx();
+    let stats = alloc_stats();
+    error!(
+        "out of memory: failed to allocate {} bytes (align {}), {} bytes in {} allocations are live",
+        layout.size(),
+        layout.align(),
+        stats.allocated_bytes,
+        stats.live_allocation_count
+    );
+    halt_or_reboot(QemuExitCode::OutOfMemory)
+}
+
 impl FirstFitAllocator {
+    const fn new() -> Self {
+        Self {
+            list: SpinLock::new(FirstFitList {
+                first_header: None,
+                policy: FitPolicy::FirstFit,
+                next_fit_cursor: 0,
+            }),
+        }
+    }
+
+    pub fn policy(&self) -> FitPolicy {
+        self.list.lock().policy
+    }
+
+    pub fn set_policy(&self, policy: FitPolicy) {
+        self.list.lock().policy = policy;
+    }
+
+    // 選んでいる方法で空きブロックを探して割り当てる
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        let size = layout.size() + REDZONE_SIZE;
+        let mut list = self.list.lock();
+        let p = match list.policy {
+            FitPolicy::FirstFit => list.alloc_first_fit(size, layout.align()),
+            FitPolicy::BestFit => list.alloc_best_fit(size, layout.align()),
+            FitPolicy::NextFit => list.alloc_next_fit(size, layout.align()),
+        };
+        if !p.is_null() {
+            let header = unsafe { &mut *(p.sub(HEADER_SIZE) as *mut Header) };
+            header.set_requested(layout.size());
+        }
+        p
+    }
+
+    pub fn blocks(&self) -> FirstFitBlocks<'_> {
+        let list = self.list.lock();
+        let next = list
+            .first_header
+            .as_deref()
+            .map_or(null(), |h| h as *const Header);
+        FirstFitBlocks { _list: list, next }
+    }
+
+    // リストを辿って、Header、レッドゾーン、解放したメモリが書き換えられていないかを調べ、ブロックの数を返す
+    // 壊れていれば、そのブロックのHeaderのアドレスを付けてpanicする
+    pub fn verify(&self) -> usize {
+        let list = self.list.lock();
+        let mut header = list.first_header.as_deref();
+        let mut count = 0;
+        while let Some(h) = header {
+            h.check();
+            if h.is_allocated() {
+                h.check_redzone();
+            } else if POISON_ENABLED {
+                if let Some(addr) = unsafe { find_unpoisoned(h.data_addr(), h.end_addr()) } {
+                    panic!(
+                        "heap corruption: free block at {:#X} was written at {addr:#X} (use after free?)",
+                        h as *const Header as usize
+                    );
+                }
+            }
+            count += 1;
+            header = h.next_header.as_deref();
+        }
+        count
+    }
+
+    // ptrのブロックを後ろに広げて、new_sizeバイトを入れられるようにする（できなければfalse）
+    fn grow_in_place(&self, ptr: *mut u8, new_size: usize) -> bool {
+        let _list = self.list.lock();
+        let mut region = unsafe { Header::from_allocated_region(ptr) };
+        let grown = region.grow_in_place(new_size.saturating_add(REDZONE_SIZE));
+        if grown {
+            region.set_requested(new_size);
+        }
+        // ブロックはリストに繋がったままなので、dropせずに捨てる
+        Box::leak(region);
+        grown
+    }
+
+    // 隣り合った空きブロックを全て1つにまとめ、まとめて減ったブロックの数を返す
+    pub fn defragment(&self) -> usize {
+        let mut list = self.list.lock();
+        let mut header = list.first_header.as_deref_mut();
+        let mut merged = 0;
+        while let Some(h) = header {
+            if !h.is_allocated() {
+                merged += h.merge_following_free_blocks();
+            }
+            header = h.next_header.as_deref_mut();
+        }
+        merged
+    }
+
+    // [start_addr, end)を1つの空きブロックとして空きリストに登録し、登録したバイト数を返す
+    fn add_free_range(&self, start_addr: usize, end: usize) -> usize {
+        let size = end - start_addr;
+        self.push_free_block(start_addr, size);
+        size
+    }
+
+    // [start_addr, start_addr + size)を1つの空きブロックとして空きリストの先頭に加える
+    fn push_free_block(&self, start_addr: usize, size: usize) {
+        // 1. 物理アドレスの先頭に、新しい空きブロック用のHeaderを強制的に書き込む。
+        let mut header = unsafe { Header::new_from_addr(start_addr) };
+        header.next_header = None;
+        header.is_allocated = false; // 空きとしてマーク
+        header.size = size; // 記述子から得たサイズをHeaderに設定
+        if POISON_ENABLED {
+            unsafe { poison(header.data_addr(), header.end_addr()) };
+        }
+
+        // 2. 新しいブロックを空きリストの先頭に挿入（プッシュ）し、
+        //    新しい先頭のnext_headerを、以前の先頭に繋ぎ直す。
+        let mut list = self.list.lock();
+        header.next_header = list.first_header.take();
+        list.first_header = Some(header);
+    }
+
+    /// # Safety
+    /// ロックを持っていた側が二度と戻らない（panicしたなど）ことを保証すること
+    unsafe fn force_unlock(&self) {
+        self.list.force_unlock();
+    }
+}
+
+impl FirstFitList {
     // 最初の割り当てられるブロックの探索と割り当てを実行するメソッド。
     // 連結リストを先頭から順に辿り、要求サイズを格納できる空きブロックの探索（First-Fitアルゴリズム）。
-    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
-        // RefCellからfirst_headerへの可変参照を取得。ループでポインタを更新するため複雑な手続きが必要。
-        let mut header = self.first_header.borrow_mut();
-        let mut header = header.deref_mut();
+    fn alloc_first_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        // first_headerへの可変参照を取得し、ループで次のHeaderへの参照に付け替えていく。
+        let mut header = &mut self.first_header;
 
         loop {
             match header {
                 // 空きブロック（Header）が存在する場合
-                Some(e) => match e.provide(layout.size(), layout.align()) {
+                Some(e) => match e.provide(size, align) {
                     // provideが成功した場合
                     Some(p) => break p, // 割り当てられたデータ領域のアドレスを返す。
                     // provideが失敗した場合（サイズ不足など）
                     None => {
                         // 次の空きブロックのHeaderへポインタを移動し、ループ続行。
-                        header = e.next_header.borrow_mut();
+                        header = &mut e.next_header;
                         continue;
                     }
                 },
@@ -201,46 +1141,149 @@ impl FirstFitAllocator {
         }
     }
 
-    // OSが起動した直後、ブートローダから渡されたメモリマップを基にヒープを初期化し、
-    // 利用可能な物理メモリ領域をアロケータの空きリストに登録する。
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
-        for e in memory_map.iter() {
-            // CONVENTIONAL_MEMORY（OSが自由に使える空きメモリ）だけを選別する。
-            if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-                continue;
+    // 入る空きブロックのうち一番小さいものを探し、そこから割り当てる
+    // 後ろの空きブロックとまとめてから大きさを比べるので、provideと同じ大きさで選べる
+    fn alloc_best_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut best: Option<(usize, usize)> = None;
+        let mut header = self.first_header.as_deref_mut();
+        while let Some(h) = header {
+            if !h.is_allocated() {
+                h.merge_following_free_blocks();
+                if h.placement(size, align).is_some()
+                    && best.is_none_or(|(best_size, _)| h.size < best_size)
+                {
+                    best = Some((h.size, h as *const Header as usize));
+                }
             }
-            self.add_free_from_descriptor(e);
+            header = h.next_header.as_deref_mut();
         }
+        let Some((_, addr)) = best else {
+            return null_mut();
+        };
+        // 見つけたブロックまでもう一度辿って割り当てる
+        let mut header = self.first_header.as_deref_mut();
+        while let Some(h) = header {
+            if h as *const Header as usize == addr {
+                return h.provide(size, align).unwrap_or(null_mut());
+            }
+            header = h.next_header.as_deref_mut();
+        }
+        null_mut()
     }
 
-    // UEFIのメモリ記述子（Descriptor）を基に、実際の物理アドレスにHeaderを割り当て、空きリストに登録する。
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
-        let mut start_addr = desc.physical_start() as usize;
-        let mut size = desc.number_of_pages() as usize * 4096;
-
-        // アドレス0からの割り当てを防ぐための処理（最初の4KBは予約または問題があることが多いため）
-        if start_addr == 0 {
-            start_addr += 4096;
-            size = size.saturating_add(4096);
+    // 前回割り当てたブロックから末尾まで探し、見つからなければ先頭から前回の位置まで探す
+    // ヒープの先頭付近に小さな空きが溜まりにくくなる
+    fn alloc_next_fit(&mut self, size: usize, align: usize) -> *mut u8 {
+        let cursor = self.next_fit_cursor;
+        let mut found = None;
+        let mut header = self.first_header.as_deref_mut();
+        let mut started = false;
+        while let Some(h) = header {
+            let addr = h as *const Header as usize;
+            started |= addr == cursor;
+            if started {
+                if let Some(p) = h.provide(size, align) {
+                    found = Some((addr, p));
+                    break;
+                }
+            }
+            header = h.next_header.as_deref_mut();
         }
-        if size <= 4096 {
-            return; // 4KB以下の領域は無視
+        if found.is_none() {
+            let mut header = self.first_header.as_deref_mut();
+            while let Some(h) = header {
+                let addr = h as *const Header as usize;
+                // 前回の位置から後ろはもう調べた
+                if started && addr == cursor {
+                    break;
+                }
+                if let Some(p) = h.provide(size, align) {
+                    found = Some((addr, p));
+                    break;
+                }
+                header = h.next_header.as_deref_mut();
+            }
         }
+        let Some((addr, p)) = found else {
+            return null_mut();
+        };
+        self.next_fit_cursor = addr;
+        p
+    }
 
-        // 1. 物理アドレスの先頭に、新しい空きブロック用のHeaderを強制的に書き込む。
-        let mut header = unsafe { Header::new_from_addr(start_addr) };
-        header.next_header = None;
-        header.is_allocated = false; // 空きとしてマーク
-        header.size = size; // 記述子から得たサイズをHeaderに設定
+    // addrにあるブロックを、リスト上で直前にある空きブロックに取り込む
+    // 前のブロックへのリンクは持っていないので、先頭から辿って探す
+    fn merge_into_previous(&mut self, addr: usize) {
+        let mut header = self.first_header.as_deref_mut();
+        while let Some(h) = header {
+            let next_addr = h
+                .next_header
+                .as_deref()
+                .map(|next| next as *const Header as usize);
+            if next_addr == Some(addr) {
+                if !h.is_allocated() {
+                    h.merge_following_free_blocks();
+                }
+                return;
+            }
+            header = h.next_header.as_deref_mut();
+        }
+    }
+}
 
-        // 2. 新しいブロックを空きリストの先頭に挿入（プッシュ）。
-        let mut first_header = self.first_header.borrow_mut();
-        let prev_last = first_header.replace(header); // 現在の先頭を退避させ、新しいHeaderを先頭に設定
-        drop(first_header); // 一時的な可変参照を解放
+// heap_poisonフィーチャーのテスト
+// 壊したヒープは元に戻せないので、ALLOCATORではなく捨ててもよい小さなヒープを使う
+#[cfg(all(test, feature = "heap_poison"))]
+mod poison_test {
+    use super::*;
+
+    fn leaked_heap() -> &'static FirstFitAllocator {
+        let layout = Layout::from_size_align(4096, 4096).unwrap();
+        let arena = ALLOCATOR.alloc_with_options(layout);
+        assert!(!arena.is_null());
+        let heap: &'static FirstFitAllocator = Box::leak(Box::new(FirstFitAllocator::new()));
+        heap.push_free_block(arena as usize, layout.size());
+        heap
+    }
 
-        // 3. 新しい先頭のnext_headerを、以前の先頭（prev_last）に繋ぎ直す。
-        let mut header = self.first_header.borrow_mut();
-        header.as_mut().unwrap().next_header = prev_last;
+    crate::kernel_test! {
+        #[allow_leaks]
+        fn freed_memory_is_poisoned() {
+            let heap = leaked_heap();
+            let layout = Layout::from_size_align(64, 8).unwrap();
+            let p = heap.alloc_with_options(layout);
+            unsafe {
+                p.write_bytes(0, layout.size());
+                heap.dealloc(p, layout);
+                assert_eq!((p as *const u64).read(), POISON);
+            }
+            assert_eq!(heap.verify(), 1);
+        }
+    }
+    crate::kernel_test! {
+        #[should_panic(expected = "heap corruption")]
+        fn writing_past_the_end_is_caught_on_free() {
+            let heap = leaked_heap();
+            let layout = Layout::from_size_align(10, 1).unwrap();
+            let p = heap.alloc_with_options(layout);
+            unsafe {
+                p.add(layout.size()).write(0);
+                heap.dealloc(p, layout);
+            }
+        }
+    }
+    crate::kernel_test! {
+        #[should_panic(expected = "use after free")]
+        fn writing_after_free_is_caught_by_verify() {
+            let heap = leaked_heap();
+            let layout = Layout::from_size_align(32, 8).unwrap();
+            let p = heap.alloc_with_options(layout);
+            unsafe {
+                heap.dealloc(p, layout);
+                p.write(1);
+            }
+            heap.verify();
+        }
     }
 }
 
@@ -263,7 +1306,544 @@ fn round_up_to_nearest_pow2_tests() {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::rand::SeededRng;
+    use crate::timer::set_tick_hook;
     use alloc::vec;
+    use core::hint::black_box;
+    use core::ptr::write_bytes;
+
+    // テスト用の空きブロックを置く領域
+    #[repr(C, align(256))]
+    struct Arena([u8; 2048]);
+
+    // arenaの先頭にsizeバイトの空きブロックを作る
+    // Headerはdropするとpanicするので、使い終わったらBox::leakする
+    fn free_block(arena: &mut Arena, size: usize) -> Box<Header> {
+        let mut header = unsafe { Header::new_from_addr(arena.0.as_mut_ptr() as usize) };
+        header.size = size;
+        header
+    }
+
+    #[test_case]
+    fn exact_fit_block_is_handed_out_whole() {
+        let mut arena = Arena([0; 2048]);
+        let mut header = free_block(&mut arena, HEADER_SIZE + 64);
+        let start = &*header as *const Header as usize;
+        let p = header.provide(64, 8).unwrap();
+        assert_eq!(p as usize, start + HEADER_SIZE);
+        assert!(header.is_allocated());
+        assert!(header.next_header.is_none());
+        assert_eq!(header.size, HEADER_SIZE + 64);
+        assert!(header.provide(8, 8).is_none());
+        Box::leak(header);
+
+        // 64バイトの空きブロックでも32バイトなら割り当てられる
+        let mut header = free_block(&mut arena, 64);
+        assert!(header.provide(32, 8).is_some());
+        Box::leak(header);
+        let mut header = free_block(&mut arena, 64);
+        assert!(header.provide(33, 8).is_none());
+        Box::leak(header);
+    }
+    #[test_case]
+    fn small_allocations_use_only_one_header() {
+        let mut arena = Arena([0; 2048]);
+        let mut header = free_block(&mut arena, 1024);
+        let start = &*header as *const Header as usize;
+        let p = header.provide(40, 8).unwrap() as usize;
+        assert_eq!(p, start + 1024 - 40);
+        // 切り出したブロックはHeaderとデータ領域だけの大きさになる
+        assert_eq!(header.size, 1024 - 40 - HEADER_SIZE);
+        let allocated = header.next_header.as_ref().unwrap();
+        assert!(allocated.is_allocated());
+        assert_eq!(allocated.size, HEADER_SIZE + 40);
+        assert!(allocated.next_header.is_none());
+        // 8バイトに揃わない大きさは、次のHeaderが揃うように切り上げる
+        let q = header.provide(3, 1).unwrap() as usize;
+        assert_eq!(q, p - HEADER_SIZE - 8);
+        Box::leak(header);
+    }
+    #[test_case]
+    fn released_blocks_are_merged_back() {
+        let mut arena = Arena([0; 2048]);
+        let mut header = free_block(&mut arena, 1024);
+        let start = &*header as *const Header as usize;
+        let p = header.provide(40, 8).unwrap();
+        let q = header.provide(8, 256).unwrap();
+        unsafe { Header::release(q) };
+        // パディングも一緒に空きに戻り、1つのブロックにまとまる
+        let freed = header.next_header.as_ref().unwrap();
+        assert!(!freed.is_allocated());
+        assert!(freed.next_header.as_ref().unwrap().is_allocated());
+        unsafe { Header::release(p) };
+        let freed = header.next_header.as_ref().unwrap();
+        assert!(!freed.next_header.as_ref().unwrap().is_allocated());
+        // 前の空きブロックとは、次に割り当てるときにまとまる
+        let r = header.provide(1024 - HEADER_SIZE, 8).unwrap();
+        assert_eq!(r as usize, start + HEADER_SIZE);
+        assert_eq!(header.size, 1024);
+        assert!(header.next_header.is_none());
+        Box::leak(header);
+    }
+    // 細かい確保と解放を繰り返した後でも、まとめ直された空きから大きな領域を確保できる
+    #[test_case]
+    fn large_allocation_succeeds_after_small_alloc_free_cycles() {
+        // 64KiBの小さなヒープを作る
+        let arena_layout = Layout::from_size_align(64 * 1024, 4096).unwrap();
+        let arena = ALLOCATOR.alloc_with_options(arena_layout);
+        assert!(!arena.is_null());
+        let heap = FirstFitAllocator::new();
+        heap.push_free_block(arena as usize, arena_layout.size());
+        let small = Layout::from_size_align(200, 8).unwrap();
+        let mut pointers = [null_mut::<u8>(); 64];
+        for _ in 0..100 {
+            for p in pointers.iter_mut() {
+                *p = heap.alloc_with_options(small);
+                assert!(!p.is_null());
+            }
+            // 偶数番目、奇数番目の順に解放して、前後どちらの隣とも繋がるようにする
+            for p in pointers
+                .iter()
+                .step_by(2)
+                .chain(pointers.iter().skip(1).step_by(2))
+            {
+                unsafe { heap.dealloc(*p, small) };
+            }
+        }
+        // 解放するたびにまとめられているので、何もまとめる必要がない
+        assert_eq!(heap.defragment(), 0);
+        let large = Layout::from_size_align(60 * 1024, 8).unwrap();
+        let p = heap.alloc_with_options(large);
+        assert!(!p.is_null());
+        unsafe { heap.dealloc(p, large) };
+        // Headerはdropするとpanicするので、アロケータはdropせずに捨てる
+        core::mem::forget(heap);
+        unsafe { ALLOCATOR.dealloc(arena, arena_layout) };
+    }
+    #[test_case]
+    fn defragment_merges_blocks_freed_without_coalescing() {
+        let mut arena = Arena([0; 2048]);
+        let heap = FirstFitAllocator::new();
+        heap.push_free_block(arena.0.as_mut_ptr() as usize, arena.0.len());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = heap.alloc_with_options(layout);
+        let q = heap.alloc_with_options(layout);
+        // 前のブロックとまとめずに空きに戻す
+        unsafe {
+            Header::release(q);
+            Header::release(p);
+        }
+        assert_eq!(heap.defragment(), 2);
+        assert_eq!(heap.defragment(), 0);
+        {
+            let list = heap.list.lock();
+            let whole = list.first_header.as_ref().unwrap();
+            assert_eq!(whole.size, 2048);
+            assert!(whole.next_header.is_none());
+        }
+        core::mem::forget(heap);
+    }
+    // レッドゾーンの分だけブロックが大きくなるので、heap_poisonでは大きさが合わない
+    #[cfg(not(feature = "heap_poison"))]
+    #[test_case]
+    fn realloc_grows_into_the_following_free_block() {
+        let mut arena = Arena([0; 2048]);
+        let heap = FirstFitAllocator::new();
+        let start = arena.0.as_mut_ptr() as usize;
+        heap.push_free_block(start, arena.0.len());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let a = heap.alloc_with_options(layout);
+        let b = heap.alloc_with_options(layout);
+        unsafe { b.write_bytes(0x5a, layout.size()) };
+        // 直後のaが使われているので、新しい場所に移る
+        let c = unsafe { heap.realloc(b, layout, 200) };
+        assert!(!c.is_null());
+        assert_ne!(c, b);
+        assert!(unsafe { core::slice::from_raw_parts(c, 100) }
+            .iter()
+            .all(|v| *v == 0x5a));
+        // aを解放すると、cの後ろにbとaの分の空きが続く
+        unsafe { heap.dealloc(a, layout) };
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        let d = unsafe { heap.realloc(c, layout, 400) };
+        assert_eq!(d, c);
+        assert!(unsafe { core::slice::from_raw_parts(d, 100) }
+            .iter()
+            .all(|v| *v == 0x5a));
+        {
+            // 取り込みすぎた分は空きブロックとして残る
+            let grown = unsafe { Header::from_allocated_region(d) };
+            assert_eq!(grown.size, HEADER_SIZE + 400);
+            let rest = grown.next_header.as_ref().unwrap();
+            assert!(!rest.is_allocated());
+            assert_eq!(rest.end_addr(), start + 2048);
+            Box::leak(grown);
+        }
+        // 足りなければ移る
+        let layout = Layout::from_size_align(400, 8).unwrap();
+        let e = unsafe { heap.realloc(d, layout, 1024) };
+        assert!(!e.is_null());
+        assert_ne!(e, d);
+        core::mem::forget(heap);
+    }
+    static IRQ_ROUNDS: AtomicUsize = AtomicUsize::new(0);
+    static IRQ_MISMATCHES: AtomicUsize = AtomicUsize::new(0);
+    // タイマー割り込みの中で確保と解放をする
+    fn alloc_from_timer() {
+        let round = IRQ_ROUNDS.fetch_add(1, Ordering::Relaxed);
+        let v: vec::Vec<usize> = (0..round % 48 + 1).collect();
+        let b = Box::new([round as u8; 300]);
+        if v.iter().enumerate().any(|(i, x)| i != *x) || b.iter().any(|x| *x != round as u8) {
+            IRQ_MISMATCHES.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    // 割り込みハンドラとメインループが同時に確保・解放しても、ヒープが壊れないことを確認する
+    #[test_case]
+    fn allocations_from_interrupts_do_not_corrupt_the_heap() {
+        IRQ_ROUNDS.store(0, Ordering::Relaxed);
+        IRQ_MISMATCHES.store(0, Ordering::Relaxed);
+        let before = alloc_stats();
+        set_tick_hook(Some(alloc_from_timer));
+        let mut main_rounds = 0;
+        while IRQ_ROUNDS.load(Ordering::Relaxed) < 50 && main_rounds < 1_000_000 {
+            let v: vec::Vec<usize> = (0..main_rounds % 64 + 1).collect();
+            let mut b = Box::new([0u8; 200]);
+            b.fill(main_rounds as u8);
+            assert!(v.iter().enumerate().all(|(i, x)| i == *x));
+            assert!(b.iter().all(|x| *x == main_rounds as u8));
+            main_rounds += 1;
+        }
+        set_tick_hook(None);
+        let irq_rounds = IRQ_ROUNDS.load(Ordering::Relaxed);
+        assert!(irq_rounds >= 50, "irq_rounds = {irq_rounds}");
+        assert_eq!(IRQ_MISMATCHES.load(Ordering::Relaxed), 0);
+        assert_eq!(alloc_stats(), before);
+        ALLOCATOR.verify_heap();
+    }
+    #[test_case]
+    fn realloc_keeps_the_alloc_stats() {
+        let before = alloc_stats();
+        let mut v: alloc::vec::Vec<u8> = alloc::vec::Vec::with_capacity(16);
+        for i in 0..10000 {
+            v.push(i as u8);
+        }
+        assert_eq!(
+            alloc_stats().allocated_bytes,
+            before.allocated_bytes + v.capacity()
+        );
+        assert_eq!(
+            alloc_stats().live_allocation_count,
+            before.live_allocation_count + 1
+        );
+        assert!(v.iter().enumerate().all(|(i, b)| *b == i as u8));
+        drop(v);
+        assert_eq!(alloc_stats(), before);
+    }
+    #[test_case]
+    fn small_allocations_are_recycled_by_the_cpu_cache() {
+        if !CACHE_ENABLED {
+            return;
+        }
+        let layout = Layout::from_size_align(40, 8).unwrap();
+        let before = ALLOCATOR.cache_stats();
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p.is_null());
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        // 解放したブロックはマガジンに入り、次の同じ区分の確保にそのまま渡される
+        let q = ALLOCATOR.alloc_with_options(Layout::from_size_align(64, 16).unwrap());
+        assert_eq!(q, p);
+        let after = ALLOCATOR.cache_stats();
+        assert!(after.alloc_hits > before.alloc_hits);
+        assert!(after.free_hits > before.free_hits);
+        // 同じ区分の中でのreallocは移さない
+        let r = unsafe { ALLOCATOR.realloc(q, Layout::from_size_align(64, 16).unwrap(), 50) };
+        assert_eq!(r, q);
+        unsafe { ALLOCATOR.dealloc(r, Layout::from_size_align(50, 16).unwrap()) };
+        let free_before = ALLOCATOR.stats().free_bytes;
+        assert!(ALLOCATOR.drain_caches() > 0);
+        assert_eq!(ALLOCATOR.cache_stats().cached_objects, 0);
+        assert!(ALLOCATOR.stats().free_bytes > free_before);
+    }
+    #[test_case]
+    fn alloc_zeroed_returns_zeroed_memory_of_any_size() {
+        let before = alloc_stats();
+        for size in [24, 4000, LARGE_ALLOC_BYTES, LARGE_ALLOC_BYTES * 2 + 100] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            // 前に使われたメモリが0で埋め直されることを確かめるため、一度書き込んでから解放する
+            let p = unsafe { ALLOCATOR.alloc(layout) };
+            assert!(!p.is_null());
+            unsafe {
+                write_bytes(p, 0xcc, size);
+                ALLOCATOR.dealloc(p, layout);
+            }
+            let p = unsafe { ALLOCATOR.alloc_zeroed(layout) };
+            assert!(!p.is_null());
+            let bytes = unsafe { core::slice::from_raw_parts(p, size) };
+            assert!(bytes.iter().all(|b| *b == 0));
+            unsafe { ALLOCATOR.dealloc(p, layout) };
+        }
+        assert_eq!(alloc_stats(), before);
+    }
+    #[test_case]
+    fn large_allocations_come_from_the_page_allocator() {
+        let layout = Layout::from_size_align(LARGE_ALLOC_BYTES, 8).unwrap();
+        let free_pages = PAGE_ALLOCATOR.free_page_count();
+        let p = unsafe { ALLOCATOR.alloc(layout) };
+        assert!((p as usize).is_multiple_of(PAGE_SIZE));
+        assert_eq!(
+            PAGE_ALLOCATOR.free_page_count(),
+            free_pages - LARGE_ALLOC_BYTES / PAGE_SIZE
+        );
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free_pages);
+    }
+    #[cfg(not(feature = "heap_poison"))]
+    #[test_case]
+    fn blocks_walk_the_list_in_address_order() {
+        let mut arena = Arena([0; 2048]);
+        let heap = FirstFitAllocator::new();
+        let start = arena.0.as_mut_ptr() as usize;
+        heap.push_free_block(start, arena.0.len());
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let p = heap.alloc_with_options(layout) as usize;
+        let blocks: alloc::vec::Vec<HeapBlock> = heap.blocks().collect();
+        assert_eq!(
+            blocks,
+            [
+                HeapBlock {
+                    addr: start,
+                    size: p - HEADER_SIZE - start,
+                    is_allocated: false
+                },
+                HeapBlock {
+                    addr: p - HEADER_SIZE,
+                    size: HEADER_SIZE + 104,
+                    is_allocated: true
+                },
+            ]
+        );
+        core::mem::forget(heap);
+    }
+    #[test_case]
+    fn heap_stats_add_up() {
+        let before = ALLOCATOR.stats();
+        assert_eq!(before.used_bytes + before.free_bytes, before.total_bytes);
+        assert!(before.largest_free_block <= before.free_bytes);
+        assert!(before.free_block_count <= before.block_count);
+        let layout = Layout::from_size_align(64 * 1024, 8).unwrap();
+        let p = ALLOCATOR.alloc_with_options(layout);
+        assert!(!p.is_null());
+        let during = ALLOCATOR.stats();
+        assert_eq!(during.total_bytes, before.total_bytes);
+        assert!(during.used_bytes >= before.used_bytes + layout.size());
+        unsafe { ALLOCATOR.dealloc(p, layout) };
+        assert_eq!(ALLOCATOR.stats().used_bytes, before.used_bytes);
+    }
+    #[test_case]
+    fn verify_heap_accepts_a_healthy_heap() {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let pointers: alloc::vec::Vec<*mut u8> = (0..16)
+            .map(|_| ALLOCATOR.alloc_with_options(layout))
+            .collect();
+        for p in pointers.iter().step_by(2) {
+            unsafe { ALLOCATOR.dealloc(*p, layout) };
+        }
+        assert!(ALLOCATOR.verify_heap() > 0);
+        for p in pointers.iter().skip(1).step_by(2) {
+            unsafe { ALLOCATOR.dealloc(*p, layout) };
+        }
+    }
+    #[test_case]
+    fn heap_backend_is_chosen_from_the_command_line() {
+        assert_eq!(HeapBackend::from_command_line(""), HeapBackend::FirstFit);
+        assert_eq!(
+            HeapBackend::from_command_line("video=800x600 heap=buddy"),
+            HeapBackend::Buddy
+        );
+        assert_eq!(
+            HeapBackend::from_command_line("heap=slab heap=first-fit"),
+            HeapBackend::FirstFit
+        );
+    }
+    #[test_case]
+    fn fit_policy_is_chosen_from_the_command_line() {
+        assert_eq!(FitPolicy::from_command_line(""), FitPolicy::FirstFit);
+        assert_eq!(
+            FitPolicy::from_command_line("heap=first-fit heap_fit=best"),
+            FitPolicy::BestFit
+        );
+        assert_eq!(
+            FitPolicy::from_command_line("heap_fit=worst heap_fit=next"),
+            FitPolicy::NextFit
+        );
+    }
+
+    // 小さい空きブロック（512バイト）の後ろに大きい空きブロック（1408バイト）があるヒープで、
+    // first_sizeとsecond_sizeを順に確保し、それぞれ大きいブロックから割り当てられたかを返す
+    fn allocate_from_two_blocks(
+        policy: FitPolicy,
+        first_size: usize,
+        second_size: usize,
+    ) -> (bool, bool) {
+        let mut arena = Arena([0; 2048]);
+        let start = arena.0.as_mut_ptr() as usize;
+        let heap = FirstFitAllocator::new();
+        heap.set_policy(policy);
+        // 後から加えたブロックがリストの先頭になる
+        // 隣り合っているとまとめられてしまうので、間を空けておく
+        heap.push_free_block(start + 640, 1408);
+        heap.push_free_block(start, 512);
+        let in_large = |size| {
+            let p = heap.alloc_with_options(Layout::from_size_align(size, 8).unwrap());
+            assert!(!p.is_null());
+            p as usize >= start + 640
+        };
+        let result = (in_large(first_size), in_large(second_size));
+        // Headerはdropするとpanicするので、ヒープごと捨てる
+        core::mem::forget(heap);
+        result
+    }
+    #[test_case]
+    fn fit_policies_pick_different_blocks() {
+        // 先頭の小さいブロックに入る確保
+        assert_eq!(
+            allocate_from_two_blocks(FitPolicy::FirstFit, 64, 64),
+            (false, false)
+        );
+        assert_eq!(
+            allocate_from_two_blocks(FitPolicy::BestFit, 64, 64),
+            (false, false)
+        );
+        // 大きいブロックにしか入らない確保の後では、Next-Fitはそこから探し始める
+        assert_eq!(
+            allocate_from_two_blocks(FitPolicy::FirstFit, 1024, 64),
+            (true, false)
+        );
+        assert_eq!(
+            allocate_from_two_blocks(FitPolicy::NextFit, 1024, 64),
+            (true, true)
+        );
+        // Best-Fitは、大きいブロックの残りの方が小さいのでそちらを使う
+        assert_eq!(
+            allocate_from_two_blocks(FitPolicy::BestFit, 1024, 64),
+            (true, true)
+        );
+    }
+    #[test_case]
+    fn best_fit_uses_the_smallest_block_that_fits() {
+        let mut arena = Arena([0; 2048]);
+        let start = arena.0.as_mut_ptr() as usize;
+        let heap = FirstFitAllocator::new();
+        heap.set_policy(FitPolicy::BestFit);
+        // 先頭に大きいブロックを置いても、入る中で一番小さいブロックが使われる
+        heap.push_free_block(start, 512);
+        heap.push_free_block(start + 640, 1408);
+        let p = heap.alloc_with_options(Layout::from_size_align(64, 8).unwrap()) as usize;
+        assert!((start..start + 512).contains(&p));
+        core::mem::forget(heap);
+    }
+
+    // 大きさの違う確保と解放を乱数で繰り返し、方法ごとの細切れ具合を表示する
+    #[test_case]
+    fn fragmentation_under_random_workload_per_policy() {
+        const HEAP_SIZE: usize = 512 * 1024;
+        const MAX_LIVE: usize = 256;
+        let arena_layout = Layout::from_size_align(HEAP_SIZE, PAGE_SIZE).unwrap();
+        for policy in FitPolicy::ALL {
+            let arena = unsafe { ALLOCATOR.alloc(arena_layout) };
+            assert!(!arena.is_null());
+            let heap = FirstFitAllocator::new();
+            heap.set_policy(policy);
+            heap.push_free_block(arena as usize, HEAP_SIZE);
+            let mut rng = SeededRng::new(1);
+            let mut live: vec::Vec<(*mut u8, Layout)> = vec::Vec::with_capacity(MAX_LIVE);
+            let mut failures = 0;
+            for i in 0..4000 {
+                let r = rng.next_u64() as usize;
+                if live.len() == MAX_LIVE || (!live.is_empty() && r.is_multiple_of(3)) {
+                    let (p, layout) = live.swap_remove(r / 3 % live.len());
+                    // 他の確保に書き換えられていない
+                    assert_eq!(unsafe { *p }, layout.size() as u8);
+                    unsafe { heap.dealloc(p, layout) };
+                    continue;
+                }
+                // たいていは小さく、ときどき大きい確保をする
+                let size = if i % 16 == 0 {
+                    1024 + r % 8192
+                } else {
+                    16 + r % 256
+                };
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                let p = heap.alloc_with_options(layout);
+                if p.is_null() {
+                    failures += 1;
+                    continue;
+                }
+                unsafe { *p = size as u8 };
+                live.push((p, layout));
+            }
+            let stats = HeapStats::from_blocks(HEAP_SIZE, heap.blocks());
+            crate::info!("{policy:?}: {stats}, {failures} failed allocations");
+            assert_eq!(stats.used_bytes + stats.free_bytes, HEAP_SIZE);
+            for (p, layout) in live {
+                unsafe { heap.dealloc(p, layout) };
+            }
+            heap.defragment();
+            // 全て解放すれば、空きは1つにまとまる
+            let stats = HeapStats::from_blocks(HEAP_SIZE, heap.blocks());
+            assert_eq!(stats.largest_free_block, HEAP_SIZE);
+            assert_eq!(stats.fragmentation_percent(), 0);
+            core::mem::forget(heap);
+            unsafe { ALLOCATOR.dealloc(arena, arena_layout) };
+        }
+    }
+    #[test_case]
+    fn alignment_gap_becomes_a_padding_block() {
+        let mut arena = Arena([0; 2048]);
+        let mut header = free_block(&mut arena, 1024);
+        let start = &*header as *const Header as usize;
+        // 末尾から8バイトでは256に揃わないので、後ろに隙間ができる
+        let p = header.provide(8, 256).unwrap() as usize;
+        assert_eq!(p, start + 1024 - 256);
+        let allocated = header.next_header.as_ref().unwrap();
+        assert_eq!(allocated.size, HEADER_SIZE + 8);
+        let padding = allocated.next_header.as_ref().unwrap();
+        assert!(padding.is_allocated());
+        assert_eq!(padding.end_addr(), start + 1024);
+        assert_eq!(padding.size, 256 - 8);
+        // Headerが入らない隙間は割り当てたブロックに含める
+        let q = header.provide(8, 32).unwrap() as usize;
+        assert_eq!(q, p - HEADER_SIZE - 32);
+        let allocated = header.next_header.as_ref().unwrap();
+        assert_eq!(allocated.size, HEADER_SIZE + 32);
+        Box::leak(header);
+    }
+
+    // ヒープのブロックの数
+    fn count_blocks() -> usize {
+        ALLOCATOR.first_fit.blocks().count()
+    }
+
+    // 確保と解放を繰り返しても、同じ場所が使い回されてブロックが増えていかない
+    #[test_case]
+    fn alloc_free_loop_reuses_the_same_block() {
+        for layout in [
+            Layout::from_size_align(256, 8).unwrap(),
+            Layout::from_size_align(100, 4096).unwrap(),
+            Layout::from_size_align(64 * 1024, 64).unwrap(),
+        ] {
+            let first = ALLOCATOR.alloc_with_options(layout);
+            assert!(!first.is_null());
+            unsafe { ALLOCATOR.dealloc(first, layout) };
+            let blocks = count_blocks();
+            for _ in 0..1000 {
+                let p = ALLOCATOR.alloc_with_options(layout);
+                assert_eq!(p, first);
+                unsafe { ALLOCATOR.dealloc(p, layout) };
+            }
+            assert_eq!(count_blocks(), blocks);
+        }
+    }
 
     // 大量の確保と解放を繰り返しテスト（Dropによる自動解放を検証）
     #[test_case]
@@ -431,4 +2011,69 @@ mod test {
             }
         }
     }
+
+    // 実装を比べるベンチマーク用の、ALLOCATORとは別のヒープの大きさ
+    const BENCH_HEAP_SIZE: usize = 1024 * 1024;
+
+    // ベンチマーク用のヒープの範囲をALLOCATORから借りる（返さない）
+    fn bench_heap_range() -> (usize, usize) {
+        let layout = Layout::from_size_align(BENCH_HEAP_SIZE, BENCH_HEAP_SIZE).unwrap();
+        let start = ALLOCATOR.alloc_with_options(layout) as usize;
+        assert!(start != 0);
+        (start, start + BENCH_HEAP_SIZE)
+    }
+
+    // 小さなブロックを確保して1つおきに解放し、確保したままのブロックが間に挟まった状態にする
+    fn fragment(alloc: impl Fn(Layout) -> *mut u8, dealloc: impl Fn(*mut u8, Layout)) {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let pointers: vec::Vec<*mut u8> = (0..512).map(|_| alloc(layout)).collect();
+        for p in pointers.iter().step_by(2) {
+            dealloc(*p, layout);
+        }
+    }
+
+    // 大きさの違うブロックを32個確保してから、全て解放する
+    fn alloc_free_mixed(alloc: impl Fn(Layout) -> *mut u8, dealloc: impl Fn(*mut u8, Layout)) {
+        const SIZES: [usize; 8] = [16, 48, 100, 256, 600, 1024, 3000, 4096];
+        let mut allocated = [(null_mut(), LAYOUT_PAGE_4K); 32];
+        for (i, e) in allocated.iter_mut().enumerate() {
+            let layout = Layout::from_size_align(SIZES[i % SIZES.len()], 8).unwrap();
+            let p = alloc(black_box(layout));
+            assert!(!p.is_null());
+            *e = (p, layout);
+        }
+        for (p, layout) in allocated {
+            dealloc(black_box(p), layout);
+        }
+    }
+
+    crate::kernel_bench!(first_fit_alloc_free_mixed, {
+        let (start, end) = bench_heap_range();
+        // Headerはdropするとpanicするので、ヒープは捨てずに残す
+        let heap: &'static FirstFitAllocator = Box::leak(Box::new(FirstFitAllocator::new()));
+        heap.add_free_range(start, end);
+        let alloc = |layout| heap.alloc_with_options(layout);
+        let dealloc = |p, layout| unsafe { heap.dealloc(p, layout) };
+        fragment(alloc, dealloc);
+        move || alloc_free_mixed(alloc, dealloc)
+    });
+
+    crate::kernel_bench!(buddy_alloc_free_mixed, {
+        let (start, end) = bench_heap_range();
+        let heap: &'static BuddyAllocator = Box::leak(Box::new(BuddyAllocator::new()));
+        heap.add_free_range(start, end);
+        let alloc = |layout| heap.alloc_with_options(layout);
+        let dealloc = |p, layout| unsafe { heap.dealloc(p, layout) };
+        fragment(alloc, dealloc);
+        move || alloc_free_mixed(alloc, dealloc)
+    });
+
+    crate::kernel_bench!(alloc_free_4k, {
+        let layout = Layout::from_size_align(4096, 8).expect("invalid layout");
+        move || unsafe {
+            let p = ALLOCATOR.alloc(black_box(layout));
+            assert!(!p.is_null());
+            ALLOCATOR.dealloc(black_box(p), layout);
+        }
+    });
 }
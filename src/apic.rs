@@ -0,0 +1,185 @@
+use crate::msr::ApicBase;
+use crate::result::Result;
+use crate::x86::busy_loop_hint;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+// Local APIC
+// CPUごとにある割り込みコントローラで、タイマーも内蔵している
+// レジスタはMMIOで、ベースアドレスはIA32_APIC_BASE MSRから取得する
+
+// レジスタのオフセット
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xb0;
+const REG_SVR: u64 = 0xf0; // Spurious Interrupt Vector Register
+const REG_ICR_LOW: u64 = 0x300; // Interrupt Command Register
+const REG_ICR_HIGH: u64 = 0x310;
+const REG_LVT_TIMER: u64 = 0x320;
+const REG_TIMER_INITIAL_COUNT: u64 = 0x380;
+const REG_TIMER_CURRENT_COUNT: u64 = 0x390;
+const REG_TIMER_DIVIDE_CONFIG: u64 = 0x3e0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// タイマーのカウンタは入力クロックを16分周した速さで減る
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+pub const TIMER_DIVISOR: u64 = 16;
+
+// ICRに書くIPIの種類とフラグ
+const ICR_DELIVERY_FIXED: u32 = 0b000 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+// IPIが送り出されるのを待つ回数の上限
+const ICR_POLL_LIMIT: usize = 100_000;
+
+// LAPICタイマーの割り込みベクタ（PICのIRQ0~15の直後）
+pub const TIMER_VECTOR: usize = 0x30;
+// 他のCPUにTLBを捨てさせるIPIのベクタ
+pub const TLB_SHOOTDOWN_VECTOR: usize = 0x31;
+// 偽の割り込みのベクタ（下位4bitは1である必要がある）
+pub const SPURIOUS_VECTOR: usize = 0xff;
+
+// 割り込みハンドラからEOIを送るためにベースアドレスを覚えておく（0なら未初期化）
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+type TimerHandler = fn();
+static TIMER_HANDLER: Mutex<Option<TimerHandler>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    base: u64,
+}
+impl LocalApic {
+    // Local APICを有効にする（使えないCPUならNone）
+    // UEFIがメモリをアイデンティティマップしているので、物理アドレスをそのまま使う
+    pub fn init() -> Option<Self> {
+        let apic_base = ApicBase::read().ok()?;
+        apic_base.enable().ok()?;
+        let apic = Self {
+            base: apic_base.base(),
+        };
+        apic.write(REG_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+        apic.write(REG_LVT_TIMER, LVT_MASKED);
+        LAPIC_BASE.store(apic.base, Ordering::SeqCst);
+        Some(apic)
+    }
+
+    // 初期化済みのLocal APIC
+    pub fn current() -> Option<Self> {
+        match LAPIC_BASE.load(Ordering::SeqCst) {
+            0 => None,
+            base => Some(Self { base }),
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        self.base
+    }
+
+    pub fn id(&self) -> u32 {
+        self.read(REG_ID) >> 24
+    }
+
+    // MMIOのレジスタはコンパイラに最適化されないようにvolatileでアクセスする
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    pub fn send_eoi(&self) {
+        self.write(REG_EOI, 0);
+    }
+
+    // apic_idのCPUにIPIを送って、送り出されるまで待つ
+    fn send_ipi(&self, apic_id: u8, command: u32) -> Result<()> {
+        self.write(REG_ICR_HIGH, (apic_id as u32) << 24);
+        // 下位の書き込みで送信が始まるので、宛先を先に書いておく
+        self.write(REG_ICR_LOW, command);
+        for _ in 0..ICR_POLL_LIMIT {
+            if self.read(REG_ICR_LOW) & ICR_SEND_PENDING == 0 {
+                return Ok(());
+            }
+            busy_loop_hint();
+        }
+        Err("APIC: IPI was not sent")
+    }
+
+    // INIT IPIを送って、CPUをSIPI（Startup IPI）を待つ状態にする
+    pub fn send_init(&self, apic_id: u8) -> Result<()> {
+        self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT)
+    }
+
+    // Startup IPIを送って、物理アドレスvector * 0x1000からリアルモードで実行を始めさせる
+    pub fn send_startup(&self, apic_id: u8, vector: u8) -> Result<()> {
+        self.send_ipi(
+            apic_id,
+            ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | vector as u32,
+        )
+    }
+
+    // apic_idのCPUに、vectorの割り込みを起こさせる
+    pub fn send_fixed(&self, apic_id: u8, vector: u8) -> Result<()> {
+        self.send_ipi(
+            apic_id,
+            ICR_DELIVERY_FIXED | ICR_LEVEL_ASSERT | vector as u32,
+        )
+    }
+
+    // タイマーを止めた状態で初期値を入れてカウントダウンを始める（キャリブレーション用）
+    fn start_one_shot(&self, initial_count: u32) {
+        self.write(REG_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        self.write(REG_TIMER_INITIAL_COUNT, initial_count);
+    }
+
+    fn stop_timer(&self) {
+        self.write(REG_LVT_TIMER, LVT_MASKED);
+        self.write(REG_TIMER_INITIAL_COUNT, 0);
+    }
+
+    // 1msあたりにタイマーのカウンタがいくつ減るかを測る
+    // wait_msは既に動いている別のタイマー（PITなど）で指定したミリ秒待つ関数
+    // ばらつきを減らすために何回か測って平均をとる
+    pub fn calibrate_timer(&self, wait_ms: fn(u64)) -> u32 {
+        const ROUNDS: u64 = 4;
+        const WAIT_MS: u64 = 10;
+        let mut total = 0u64;
+        for _ in 0..ROUNDS {
+            // 1ms待ってティックの境目に揃えてから測り始める
+            wait_ms(1);
+            self.start_one_shot(u32::MAX);
+            wait_ms(WAIT_MS);
+            let remaining = self.read(REG_TIMER_CURRENT_COUNT);
+            self.stop_timer();
+            total += (u32::MAX - remaining) as u64;
+        }
+        (total / ROUNDS / WAIT_MS) as u32
+    }
+
+    // 周期モードでタイマー割り込みを発生させる
+    pub fn start_periodic_timer(&self, initial_count: u32, handler: TimerHandler) {
+        *TIMER_HANDLER.lock() = Some(handler);
+        self.write(REG_TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+        self.write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        self.write(REG_TIMER_INITIAL_COUNT, initial_count);
+    }
+}
+
+// IDTから呼ばれるLAPICタイマーの処理
+// EOIはPICではなくLocal APICに送る
+pub fn dispatch_timer() {
+    let handler = *TIMER_HANDLER.lock();
+    if let Some(handler) = handler {
+        handler();
+    }
+    if let Some(apic) = LocalApic::current() {
+        apic.send_eoi();
+    }
+}
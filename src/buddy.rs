@@ -0,0 +1,424 @@
+use crate::sync::SpinLock;
+use crate::sync::SpinLockGuard;
+use core::alloc::Layout;
+use core::ptr::null_mut;
+
+// バディアロケータ
+// メモリを2のべき乗の大きさのブロックに分けて、大きさ（オーダー）ごとの空きリストで管理する
+// ブロックはアドレスがその大きさに揃うように置くので、相方（バディ）のアドレスはaddr ^ sizeで求まる
+// 割り当てたブロックにはHeaderを付けないので、解放するときはLayoutから大きさを求め直す
+
+// 一番小さいブロックの大きさ（空きリストのノードが入る大きさ）
+const MIN_ORDER: usize = 5;
+const MIN_BLOCK_SIZE: usize = 1 << MIN_ORDER;
+// 一番大きいブロックの大きさ（1TiB）
+const MAX_ORDER: usize = 40;
+const NUM_ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
+// 管理できる連続した領域の数（UEFIのメモリマップの空き領域の数）
+const MAX_ZONES: usize = 64;
+// 空きリストの終わり
+const NIL: usize = 0;
+
+// 空きブロックの先頭に置く双方向リストのノード
+#[repr(C)]
+struct FreeNode {
+    next: usize,
+    prev: usize,
+}
+const _: () = assert!(size_of::<FreeNode>() <= MIN_BLOCK_SIZE);
+
+fn block_size(order: usize) -> usize {
+    1 << order
+}
+
+// layoutを満たすブロックのオーダー（大きすぎればNone）
+fn order_for(layout: Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_BLOCK_SIZE)
+        .checked_next_power_of_two()?;
+    let order = size.trailing_zeros() as usize;
+    (order <= MAX_ORDER).then_some(order)
+}
+
+// 1つの連続した領域
+// 領域の先頭には、最小ブロックごとに「ここから始まる空きブロックのオーダー + 1（空きでなければ0）」を置く
+// これでバディが空いているかを、空きリストを辿らずに調べられる
+struct Zone {
+    // 管理する範囲（mapの後ろから）
+    base: usize,
+    end: usize,
+    map: *mut u8,
+    // オーダーごとの空きリストの先頭（MIN_ORDERが0番目）
+    free: [usize; NUM_ORDERS],
+}
+// mapと空きブロックは、この領域を持つBuddyAllocatorのロックを取ってからしか触らない
+unsafe impl Send for Zone {}
+impl Zone {
+    // [start, end)を1つの領域にして、入るだけ大きなブロックに分けて空きリストに入れる
+    // 小さすぎて何も入らなければNone
+    unsafe fn new(start: usize, end: usize) -> Option<Self> {
+        let start = start.checked_next_multiple_of(MIN_BLOCK_SIZE)?;
+        let map_len = end.checked_sub(start)? / MIN_BLOCK_SIZE;
+        let base = (start + map_len).next_multiple_of(MIN_BLOCK_SIZE);
+        if base + MIN_BLOCK_SIZE > end {
+            return None;
+        }
+        let map = start as *mut u8;
+        map.write_bytes(0, map_len);
+        let mut zone = Self {
+            base,
+            end,
+            map,
+            free: [NIL; NUM_ORDERS],
+        };
+        let mut addr = base;
+        while addr + MIN_BLOCK_SIZE <= end {
+            let order = (MIN_ORDER..=MAX_ORDER)
+                .rev()
+                .find(|order| {
+                    addr.is_multiple_of(block_size(*order)) && addr + block_size(*order) <= end
+                })
+                .unwrap_or(MIN_ORDER);
+            zone.push(addr, order);
+            addr += block_size(order);
+        }
+        Some(zone)
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        (self.base..self.end).contains(&addr)
+    }
+
+    // 空きブロックとして管理しているバイト数
+    fn managed_bytes(&self) -> usize {
+        self.end - self.base
+    }
+
+    fn mark(&self, addr: usize, value: u8) {
+        let index = (addr - self.base) / MIN_BLOCK_SIZE;
+        unsafe { self.map.add(index).write(value) }
+    }
+
+    // addrからorderの空きブロックが始まっていればtrue
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        if addr < self.base || addr + block_size(order) > self.end {
+            return false;
+        }
+        let index = (addr - self.base) / MIN_BLOCK_SIZE;
+        unsafe { self.map.add(index).read() as usize == order - MIN_ORDER + 1 }
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let head = &mut self.free[order - MIN_ORDER];
+        unsafe {
+            (addr as *mut FreeNode).write(FreeNode {
+                next: *head,
+                prev: NIL,
+            });
+            if *head != NIL {
+                (*(*head as *mut FreeNode)).prev = addr;
+            }
+        }
+        *head = addr;
+        self.mark(addr, (order - MIN_ORDER + 1) as u8);
+    }
+
+    fn remove(&mut self, addr: usize, order: usize) {
+        let node = unsafe { (addr as *const FreeNode).read() };
+        if node.prev == NIL {
+            self.free[order - MIN_ORDER] = node.next;
+        } else {
+            unsafe { (*(node.prev as *mut FreeNode)).next = node.next };
+        }
+        if node.next != NIL {
+            unsafe { (*(node.next as *mut FreeNode)).prev = node.prev };
+        }
+        self.mark(addr, 0);
+    }
+
+    // orderのブロックを1つ取り出す（なければ大きいブロックを半分ずつに分ける）
+    fn alloc(&mut self, order: usize) -> Option<usize> {
+        let mut found = (order..=MAX_ORDER).find(|o| self.free[o - MIN_ORDER] != NIL)?;
+        let addr = self.free[found - MIN_ORDER];
+        self.remove(addr, found);
+        // 後ろ半分を空きに戻していく
+        while found > order {
+            found -= 1;
+            self.push(addr + block_size(found), found);
+        }
+        Some(addr)
+    }
+
+    // ブロックを空きに戻し、バディも空いていれば1つ大きなブロックにまとめる
+    fn free(&mut self, addr: usize, order: usize) {
+        let mut addr = addr;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    // オーダーごとの空きブロックの数
+    #[cfg(test)]
+    fn count_free(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut addr = self.free[order - MIN_ORDER];
+        while addr != NIL {
+            count += 1;
+            addr = unsafe { (*(addr as *const FreeNode)).next };
+        }
+        count
+    }
+}
+
+pub struct BuddyAllocator {
+    // FirstFitAllocatorと同じく、割り込みハンドラや他のCPUとの排他にスピンロックを使う
+    zones: SpinLock<[Option<Zone>; MAX_ZONES]>,
+}
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            zones: SpinLock::new([const { None }; MAX_ZONES]),
+        }
+    }
+
+    // [start, end)を管理する領域に加え、空きブロックとして使えるバイト数を返す
+    // 領域の先頭の一部はブロックの状態を記録するのに使う
+    // 領域が多すぎる、または小さすぎるときは使わずに0を返す
+    pub fn add_free_range(&self, start: usize, end: usize) -> usize {
+        let mut zones = self.zones.lock();
+        let Some(slot) = zones.iter_mut().find(|z| z.is_none()) else {
+            return 0;
+        };
+        let Some(zone) = (unsafe { Zone::new(start, end) }) else {
+            return 0;
+        };
+        let bytes = zone.managed_bytes();
+        *slot = Some(zone);
+        bytes
+    }
+
+    pub fn alloc_with_options(&self, layout: Layout) -> *mut u8 {
+        let Some(order) = order_for(layout) else {
+            return null_mut();
+        };
+        let mut zones = self.zones.lock();
+        zones
+            .iter_mut()
+            .flatten()
+            .find_map(|zone| zone.alloc(order))
+            .map_or(null_mut(), |addr| addr as *mut u8)
+    }
+
+    /// # Safety
+    /// ptrはこのアロケータがlayoutで割り当てたものであること
+    pub unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(order) = order_for(layout) else {
+            return;
+        };
+        let addr = ptr as usize;
+        let mut zones = self.zones.lock();
+        if let Some(zone) = zones.iter_mut().flatten().find(|z| z.contains(addr)) {
+            zone.free(addr, order);
+        }
+    }
+
+    /// # Safety
+    /// ロックを持っていた側が二度と戻らない（panicしたなど）ことを保証すること
+    pub(crate) unsafe fn force_unlock(&self) {
+        self.zones.force_unlock();
+    }
+
+    // 全ての空きブロックを順に返す
+    pub fn free_blocks(&self) -> FreeBlocks<'_> {
+        let zones = self.zones.lock();
+        let next = zones[0].as_ref().map_or(NIL, |z| z.free[0]);
+        FreeBlocks {
+            zones,
+            zone: 0,
+            order: MIN_ORDER,
+            next,
+        }
+    }
+
+    // オーダーごとの空きブロックの数の合計
+    #[cfg(test)]
+    fn count_free(&self, order: usize) -> usize {
+        self.zones
+            .lock()
+            .iter()
+            .flatten()
+            .map(|z| z.count_free(order))
+            .sum()
+    }
+}
+// 空きブロックの（アドレス, 大きさ）を、領域ごと、オーダーごとに返す
+// 返している間はアロケータのロックを持っているので、その間に確保や解放をしないこと（デッドロックする）
+pub struct FreeBlocks<'a> {
+    zones: SpinLockGuard<'a, [Option<Zone>; MAX_ZONES]>,
+    zone: usize,
+    order: usize,
+    next: usize,
+}
+impl Iterator for FreeBlocks<'_> {
+    type Item = (usize, usize);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next != NIL {
+                let addr = self.next;
+                self.next = unsafe { (*(addr as *const FreeNode)).next };
+                return Some((addr, block_size(self.order)));
+            }
+            // 次のオーダー、次の領域の空きリストに進む（領域は前から詰めて使っている）
+            self.order += 1;
+            if self.order > MAX_ORDER {
+                self.order = MIN_ORDER;
+                self.zone += 1;
+            }
+            let zone = self.zones.get(self.zone)?.as_ref()?;
+            self.next = zone.free[self.order - MIN_ORDER];
+        }
+    }
+}
+
+impl Default for BuddyAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use crate::allocator::ALLOCATOR;
+    use alloc::alloc::GlobalAlloc;
+    use alloc::vec::Vec;
+
+    // テスト用の領域をヒープから借りてバディアロケータに渡す
+    struct TestHeap {
+        buddy: BuddyAllocator,
+        arena: *mut u8,
+        arena_layout: Layout,
+    }
+    impl TestHeap {
+        fn new(size: usize) -> Self {
+            // 大きなブロックが作れるように、大きさと同じだけ揃える
+            let arena_layout = Layout::from_size_align(size, size).unwrap();
+            let arena = ALLOCATOR.alloc_with_options(arena_layout);
+            assert!(!arena.is_null());
+            let buddy = BuddyAllocator::new();
+            let start = arena as usize;
+            assert!(buddy.add_free_range(start, start + size) > 0);
+            Self {
+                buddy,
+                arena,
+                arena_layout,
+            }
+        }
+        fn free_counts(&self) -> Vec<usize> {
+            (MIN_ORDER..=MAX_ORDER)
+                .map(|o| self.buddy.count_free(o))
+                .collect()
+        }
+    }
+    impl Drop for TestHeap {
+        fn drop(&mut self) {
+            unsafe { ALLOCATOR.dealloc(self.arena, self.arena_layout) };
+        }
+    }
+
+    #[test_case]
+    fn order_is_the_next_power_of_two() {
+        let order = |size, align| order_for(Layout::from_size_align(size, align).unwrap());
+        assert_eq!(order(1, 1), Some(MIN_ORDER));
+        assert_eq!(order(33, 1), Some(6));
+        assert_eq!(order(4096, 8), Some(12));
+        assert_eq!(order(100, 4096), Some(12));
+        assert_eq!(order(1 << MAX_ORDER, 8), Some(MAX_ORDER));
+        assert_eq!(order((1 << MAX_ORDER) + 1, 8), None);
+    }
+    #[test_case]
+    fn blocks_are_aligned_to_their_size_and_do_not_overlap() {
+        let heap = TestHeap::new(256 * 1024);
+        let layouts = [(100, 8), (4096, 4096), (32, 32), (3000, 64), (1, 1)];
+        let mut blocks: Vec<(usize, usize)> = Vec::new();
+        for (size, align) in layouts.iter().cycle().take(40) {
+            let layout = Layout::from_size_align(*size, *align).unwrap();
+            let p = heap.buddy.alloc_with_options(layout) as usize;
+            assert!(p != 0);
+            let size = block_size(order_for(layout).unwrap());
+            assert!(p.is_multiple_of(size));
+            assert!(blocks.iter().all(|(q, s)| p + size <= *q || q + s <= p));
+            blocks.push((p, size));
+        }
+        for (p, size) in blocks {
+            unsafe {
+                heap.buddy
+                    .dealloc(p as *mut u8, Layout::from_size_align(size, 8).unwrap())
+            };
+        }
+    }
+    #[test_case]
+    fn freed_buddies_merge_back_to_the_initial_blocks() {
+        let heap = TestHeap::new(64 * 1024);
+        let initial = heap.free_counts();
+        let layout = Layout::from_size_align(48, 8).unwrap();
+        let pointers: Vec<*mut u8> = (0..200)
+            .map(|_| heap.buddy.alloc_with_options(layout))
+            .collect();
+        assert!(pointers.iter().all(|p| !p.is_null()));
+        assert_ne!(heap.free_counts(), initial);
+        // 偶数番目、奇数番目の順に解放して、バディが後から空く場合もまとめる
+        for p in pointers
+            .iter()
+            .step_by(2)
+            .chain(pointers.iter().skip(1).step_by(2))
+        {
+            unsafe { heap.buddy.dealloc(*p, layout) };
+        }
+        assert_eq!(heap.free_counts(), initial);
+    }
+    #[test_case]
+    fn free_blocks_cover_the_free_bytes() {
+        let heap = TestHeap::new(64 * 1024);
+        let managed = heap.buddy.zones.lock()[0].as_ref().unwrap().managed_bytes();
+        let free_bytes = || {
+            heap.buddy
+                .free_blocks()
+                .map(|(_, size)| size)
+                .sum::<usize>()
+        };
+        assert_eq!(free_bytes(), managed);
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        let p = heap.buddy.alloc_with_options(layout);
+        assert_eq!(free_bytes(), managed - 1024);
+        assert!(heap
+            .buddy
+            .free_blocks()
+            .all(|(addr, size)| addr + size <= p as usize || p as usize + 1024 <= addr));
+        unsafe { heap.buddy.dealloc(p, layout) };
+        assert_eq!(free_bytes(), managed);
+    }
+    #[test_case]
+    fn alloc_fails_when_no_block_is_large_enough() {
+        let heap = TestHeap::new(64 * 1024);
+        // 先頭はブロックの状態の記録に使うので、64KiBのブロックは作れない
+        let too_large = Layout::from_size_align(64 * 1024, 8).unwrap();
+        assert!(heap.buddy.alloc_with_options(too_large).is_null());
+        let half = Layout::from_size_align(32 * 1024, 8).unwrap();
+        let p = heap.buddy.alloc_with_options(half);
+        assert!(!p.is_null());
+        unsafe { heap.buddy.dealloc(p, half) };
+    }
+}
@@ -0,0 +1,338 @@
+use crate::apic::LocalApic;
+use crate::sync::SpinLock;
+use core::alloc::Layout;
+use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+// 小さな確保のためのCPUごとのキャッシュ（マガジン）
+// 大きさの区分ごとに、解放されたブロックをCPUごとの配列に取っておき、次の確保にそのまま渡す
+// ヒープのロックを取るのは、マガジンが空になったときと一杯になったときだけで、
+// そのときはBATCH個ずつまとめてヒープからもらう（返す）
+// 取っておいたブロックは、ヒープから見ると確保されたままになっている
+
+// キャッシュを持てるCPUの数（Local APIC IDがこれを超えるCPUは、同じキャッシュを分け合う）
+pub const MAX_CPUS: usize = 16;
+// 大きさの区分（この大きさのブロックとしてヒープから確保する）
+pub const SIZE_CLASSES: [usize; 6] = [16, 32, 64, 128, 256, 512];
+// 区分のブロックのアラインメント（これより大きいアラインメントの確保はキャッシュしない）
+const CLASS_ALIGN: usize = 16;
+// 1つのマガジンに取っておける数
+const MAGAZINE_SIZE: usize = 32;
+// 空や一杯になったときに、まとめてヒープとやり取りする数
+const BATCH: usize = MAGAZINE_SIZE / 2;
+
+// layoutを受け持つ区分（キャッシュしない大きさならNone）
+pub fn size_class(layout: Layout) -> Option<usize> {
+    if layout.align() > CLASS_ALIGN {
+        return None;
+    }
+    SIZE_CLASSES.iter().position(|size| layout.size() <= *size)
+}
+
+// 区分のブロックをヒープから確保、解放するときのレイアウト
+pub fn class_layout(class: usize) -> Layout {
+    Layout::from_size_align(SIZE_CLASSES[class], CLASS_ALIGN).unwrap()
+}
+
+// 今動いているCPUのキャッシュの番号（Local APICを初期化する前は0）
+fn current_cpu() -> usize {
+    LocalApic::current().map_or(0, |apic| apic.id() as usize % MAX_CPUS)
+}
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    len: usize,
+}
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [null_mut(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+    fn pop(&mut self) -> Option<*mut u8> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.objects[self.len])
+    }
+    fn push(&mut self, p: *mut u8) -> bool {
+        if self.len == MAGAZINE_SIZE {
+            return false;
+        }
+        self.objects[self.len] = p;
+        self.len += 1;
+        true
+    }
+}
+
+struct CpuCache {
+    magazines: [Magazine; SIZE_CLASSES.len()],
+}
+// マガジンのブロックは、ロックを持っている間しか触らない
+unsafe impl Send for CpuCache {}
+
+// キャッシュの当たり外れの数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    // マガジンから渡せた確保と、ヒープからもらう必要があった確保
+    pub alloc_hits: usize,
+    pub alloc_misses: usize,
+    // マガジンに入った解放と、一杯でヒープに返す必要があった解放
+    pub free_hits: usize,
+    pub free_misses: usize,
+    // 今マガジンに取ってあるブロックの数
+    pub cached_objects: usize,
+}
+impl CacheStats {
+    pub fn hit_percent(&self) -> usize {
+        let hits = self.alloc_hits + self.free_hits;
+        let total = hits + self.alloc_misses + self.free_misses;
+        if total == 0 {
+            return 0;
+        }
+        hits * 100 / total
+    }
+}
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}% hits (alloc {}/{}, free {}/{}), {} cached objects",
+            self.hit_percent(),
+            self.alloc_hits,
+            self.alloc_hits + self.alloc_misses,
+            self.free_hits,
+            self.free_hits + self.free_misses,
+            self.cached_objects
+        )
+    }
+}
+
+pub struct CpuCaches {
+    caches: [SpinLock<CpuCache>; MAX_CPUS],
+    alloc_hits: AtomicUsize,
+    alloc_misses: AtomicUsize,
+    free_hits: AtomicUsize,
+    free_misses: AtomicUsize,
+    cached_objects: AtomicUsize,
+}
+impl CpuCaches {
+    pub const fn new() -> Self {
+        Self {
+            caches: [const {
+                SpinLock::new(CpuCache {
+                    magazines: [Magazine::new(); SIZE_CLASSES.len()],
+                })
+            }; MAX_CPUS],
+            alloc_hits: AtomicUsize::new(0),
+            alloc_misses: AtomicUsize::new(0),
+            free_hits: AtomicUsize::new(0),
+            free_misses: AtomicUsize::new(0),
+            cached_objects: AtomicUsize::new(0),
+        }
+    }
+
+    // classのブロックを1つ渡す
+    // マガジンが空なら、refillでヒープからBATCH個までまとめて確保して詰める（1つももらえなければnull）
+    // マガジンのロックを持ったままrefillを呼ぶので、refillからキャッシュを使ってはいけない
+    pub fn alloc(&self, class: usize, mut refill: impl FnMut(Layout) -> *mut u8) -> *mut u8 {
+        self.alloc_on(current_cpu(), class, &mut refill)
+    }
+
+    // classのブロックpを返す
+    // マガジンが一杯なら、BATCH個をreleaseでヒープに返してから入れる
+    /// # Safety
+    /// pはallocでclassから受け取って、まだ返していないブロックであること
+    pub unsafe fn dealloc(&self, class: usize, p: *mut u8, mut release: impl FnMut(*mut u8)) {
+        self.dealloc_on(current_cpu(), class, p, &mut release)
+    }
+
+    fn alloc_on(
+        &self,
+        cpu: usize,
+        class: usize,
+        refill: &mut impl FnMut(Layout) -> *mut u8,
+    ) -> *mut u8 {
+        let mut cache = self.caches[cpu].lock();
+        let magazine = &mut cache.magazines[class];
+        if let Some(p) = magazine.pop() {
+            self.alloc_hits.fetch_add(1, Ordering::Relaxed);
+            self.cached_objects.fetch_sub(1, Ordering::Relaxed);
+            return p;
+        }
+        self.alloc_misses.fetch_add(1, Ordering::Relaxed);
+        let layout = class_layout(class);
+        for _ in 0..BATCH {
+            let p = refill(layout);
+            if p.is_null() {
+                break;
+            }
+            magazine.push(p);
+            self.cached_objects.fetch_add(1, Ordering::Relaxed);
+        }
+        match magazine.pop() {
+            Some(p) => {
+                self.cached_objects.fetch_sub(1, Ordering::Relaxed);
+                p
+            }
+            None => null_mut(),
+        }
+    }
+
+    fn dealloc_on(&self, cpu: usize, class: usize, p: *mut u8, release: &mut impl FnMut(*mut u8)) {
+        let mut cache = self.caches[cpu].lock();
+        let magazine = &mut cache.magazines[class];
+        if magazine.push(p) {
+            self.free_hits.fetch_add(1, Ordering::Relaxed);
+            self.cached_objects.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.free_misses.fetch_add(1, Ordering::Relaxed);
+        for _ in 0..BATCH {
+            if let Some(q) = magazine.pop() {
+                release(q);
+                self.cached_objects.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        magazine.push(p);
+        self.cached_objects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 全てのCPUのマガジンを空にして、取っておいたブロックをreleaseでヒープに返し、返した数を返す
+    // releaseには区分のレイアウトも渡す
+    pub fn drain(&self, mut release: impl FnMut(*mut u8, Layout)) -> usize {
+        let mut drained = 0;
+        for cache in &self.caches {
+            let mut cache = cache.lock();
+            for (class, magazine) in cache.magazines.iter_mut().enumerate() {
+                while let Some(p) = magazine.pop() {
+                    release(p, class_layout(class));
+                    self.cached_objects.fetch_sub(1, Ordering::Relaxed);
+                    drained += 1;
+                }
+            }
+        }
+        drained
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            alloc_hits: self.alloc_hits.load(Ordering::Relaxed),
+            alloc_misses: self.alloc_misses.load(Ordering::Relaxed),
+            free_hits: self.free_hits.load(Ordering::Relaxed),
+            free_misses: self.free_misses.load(Ordering::Relaxed),
+            cached_objects: self.cached_objects.load(Ordering::Relaxed),
+        }
+    }
+
+    // panicでロックを持ったまま抜けたときに外す（allocator::reset_after_panic用）
+    /// # Safety
+    /// ロックを持っていた側が二度と戻らないこと
+    pub unsafe fn force_unlock(&self) {
+        for cache in &self.caches {
+            cache.force_unlock();
+        }
+    }
+}
+impl Default for CpuCaches {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use core::cell::Cell;
+
+    // ヒープの代わりに、配列のブロックを順に渡す
+    #[repr(C, align(16))]
+    struct Pool([[u8; 64]; 64]);
+
+    #[test_case]
+    fn layouts_are_sorted_into_size_classes() {
+        let layout = |size, align| Layout::from_size_align(size, align).unwrap();
+        assert_eq!(size_class(layout(1, 1)), Some(0));
+        assert_eq!(size_class(layout(16, 8)), Some(0));
+        assert_eq!(size_class(layout(17, 8)), Some(1));
+        assert_eq!(size_class(layout(512, 16)), Some(5));
+        assert_eq!(size_class(layout(513, 8)), None);
+        assert_eq!(size_class(layout(32, 32)), None);
+        assert_eq!(class_layout(2), layout(64, 16));
+    }
+    #[test_case]
+    fn magazines_are_refilled_and_drained_in_batches() {
+        let caches = CpuCaches::new();
+        let mut pool = Pool([[0; 64]; 64]);
+        let base = pool.0.as_mut_ptr() as *mut u8;
+        let mut next = 0;
+        let refills = Cell::new(0);
+        let mut refill = |layout: Layout| {
+            assert_eq!(layout, class_layout(2));
+            refills.set(refills.get() + 1);
+            if next == pool.0.len() {
+                return null_mut();
+            }
+            next += 1;
+            unsafe { base.add((next - 1) * 64) }
+        };
+        // 最初の確保でBATCH個をまとめてもらい、残りはマガジンから渡す
+        let mut pointers = [null_mut(); BATCH + 1];
+        for p in pointers.iter_mut() {
+            *p = caches.alloc_on(1, 2, &mut refill);
+            assert!(!p.is_null());
+        }
+        assert_eq!(refills.get(), 2 * BATCH);
+        let stats = caches.stats();
+        assert_eq!((stats.alloc_hits, stats.alloc_misses), (BATCH - 1, 2));
+        assert_eq!(stats.cached_objects, BATCH - 1);
+        // 他のCPUのマガジンは別になっている
+        let other = caches.alloc_on(2, 2, &mut refill);
+        assert!(!other.is_null());
+        assert_eq!(caches.stats().cached_objects, 2 * BATCH - 2);
+        // 他のCPUで確保したブロックも返せて、一杯になるとBATCH個をまとめてヒープに返す
+        let mut released = 0;
+        let mut release = |_| released += 1;
+        for p in pointers.into_iter().chain([other]) {
+            caches.dealloc_on(1, 2, p, &mut release);
+        }
+        assert_eq!(released, BATCH);
+        assert_eq!(caches.stats().free_misses, 1);
+        let mut drained = 0;
+        assert_eq!(
+            caches.drain(|_, layout| {
+                assert_eq!(layout, class_layout(2));
+                drained += 1;
+            }),
+            drained
+        );
+        assert_eq!(released + drained, 2 * BATCH + BATCH);
+        assert_eq!(caches.stats().cached_objects, 0);
+    }
+    #[test_case]
+    fn an_empty_heap_is_reported_as_null() {
+        let caches = CpuCaches::new();
+        assert!(caches.alloc_on(0, 0, &mut |_| null_mut()).is_null());
+        assert_eq!(caches.stats().cached_objects, 0);
+    }
+    #[test_case]
+    fn hit_rates_are_reported() {
+        let stats = CacheStats {
+            alloc_hits: 6,
+            alloc_misses: 2,
+            free_hits: 1,
+            free_misses: 1,
+            cached_objects: 3,
+        };
+        assert_eq!(stats.hit_percent(), 70);
+        assert_eq!(CacheStats::default().hit_percent(), 0);
+        assert_eq!(
+            alloc::format!("{stats}"),
+            "70% hits (alloc 6/8, free 1/2), 3 cached objects"
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::x86::cpuid;
+use core::fmt;
+use core::str;
+use spin::Once;
+
+const LEAF_VENDOR: u32 = 0x0000_0000;
+const LEAF_FEATURES: u32 = 0x0000_0001;
+const LEAF_STRUCTURED_FEATURES: u32 = 0x0000_0007;
+// ハイパーバイザーのベンダー名（CPUID.1:ECXのビット31が立っているときだけ意味がある）
+const LEAF_HYPERVISOR: u32 = 0x4000_0000;
+const LEAF_EXT_MAX: u32 = 0x8000_0000;
+const LEAF_EXT_FEATURES: u32 = 0x8000_0001;
+const LEAF_BRAND_STRING: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+const LEAF_ADDRESS_SIZES: u32 = 0x8000_0008;
+
+// CPUIDで調べたCPUの情報
+#[derive(Debug, Clone, Copy)]
+pub struct CpuInfo {
+    max_leaf: u32,
+    max_ext_leaf: u32,
+    vendor: [u8; 12],
+    hypervisor_vendor: Option<[u8; 12]>,
+    brand: [u8; 48],
+    features_ecx: u32,
+    features_edx: u32,
+    structured_features_ebx: u32,
+    ext_features_edx: u32,
+    phys_addr_bits: u8,
+}
+impl CpuInfo {
+    pub fn read() -> Self {
+        let r = cpuid(LEAF_VENDOR, 0);
+        let max_leaf = r.eax;
+        // ベンダー名はEBX, EDX, ECXの順にリトルエンディアンで4文字ずつ入っている
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&r.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&r.ecx.to_le_bytes());
+
+        let (features_ecx, features_edx) = if max_leaf >= LEAF_FEATURES {
+            let r = cpuid(LEAF_FEATURES, 0);
+            (r.ecx, r.edx)
+        } else {
+            (0, 0)
+        };
+        let structured_features_ebx = if max_leaf >= LEAF_STRUCTURED_FEATURES {
+            cpuid(LEAF_STRUCTURED_FEATURES, 0).ebx
+        } else {
+            0
+        };
+
+        let hypervisor_vendor = (features_ecx & (1 << 31) != 0).then(|| {
+            // リーフ0と違って、EBX, ECX, EDXの順に入っている
+            let r = cpuid(LEAF_HYPERVISOR, 0);
+            let mut vendor = [0u8; 12];
+            vendor[0..4].copy_from_slice(&r.ebx.to_le_bytes());
+            vendor[4..8].copy_from_slice(&r.ecx.to_le_bytes());
+            vendor[8..12].copy_from_slice(&r.edx.to_le_bytes());
+            vendor
+        });
+
+        // 拡張リーフに対応していないCPUでは、最大値として0x80000000未満の値が返ってくる
+        let max_ext_leaf = cpuid(LEAF_EXT_MAX, 0).eax;
+        let max_ext_leaf = if max_ext_leaf >= LEAF_EXT_MAX {
+            max_ext_leaf
+        } else {
+            0
+        };
+        let ext_features_edx = if max_ext_leaf >= LEAF_EXT_FEATURES {
+            cpuid(LEAF_EXT_FEATURES, 0).edx
+        } else {
+            0
+        };
+
+        // ブランド名は3つのリーフのEAX, EBX, ECX, EDXに16文字ずつ入っている
+        let mut brand = [0u8; 48];
+        if max_ext_leaf >= LEAF_BRAND_STRING[2] {
+            for (i, leaf) in LEAF_BRAND_STRING.iter().enumerate() {
+                let r = cpuid(*leaf, 0);
+                for (j, reg) in [r.eax, r.ebx, r.ecx, r.edx].iter().enumerate() {
+                    let ofs = i * 16 + j * 4;
+                    brand[ofs..ofs + 4].copy_from_slice(&reg.to_le_bytes());
+                }
+            }
+        }
+
+        // 情報がなければ、64bit CPUでも最低限保証されている36bitとみなす
+        let phys_addr_bits = if max_ext_leaf >= LEAF_ADDRESS_SIZES {
+            (cpuid(LEAF_ADDRESS_SIZES, 0).eax & 0xff) as u8
+        } else {
+            36
+        };
+
+        Self {
+            max_leaf,
+            max_ext_leaf,
+            vendor,
+            hypervisor_vendor,
+            brand,
+            features_ecx,
+            features_edx,
+            structured_features_ebx,
+            ext_features_edx,
+            phys_addr_bits,
+        }
+    }
+
+    pub fn max_leaf(&self) -> u32 {
+        self.max_leaf
+    }
+    pub fn max_ext_leaf(&self) -> u32 {
+        self.max_ext_leaf
+    }
+
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("(invalid)")
+    }
+
+    // 仮想マシンの中で動いていれば、ハイパーバイザーのベンダー名（末尾のNULは除く）
+    pub fn hypervisor_vendor(&self) -> Option<&str> {
+        let vendor = self.hypervisor_vendor.as_ref()?;
+        Some(
+            str::from_utf8(vendor)
+                .unwrap_or("(invalid)")
+                .trim_end_matches('\0'),
+        )
+    }
+
+    // 末尾のNULと前後の空白を除いたブランド名（拡張リーフがなければ空文字列）
+    pub fn brand_string(&self) -> &str {
+        let len = self
+            .brand
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(self.brand.len());
+        str::from_utf8(&self.brand[..len])
+            .unwrap_or("(invalid)")
+            .trim()
+    }
+
+    pub fn has_msr(&self) -> bool {
+        self.features_edx & (1 << 5) != 0
+    }
+    pub fn has_apic(&self) -> bool {
+        self.features_edx & (1 << 9) != 0
+    }
+    pub fn has_pat(&self) -> bool {
+        self.features_edx & (1 << 16) != 0
+    }
+    pub fn has_x2apic(&self) -> bool {
+        self.features_ecx & (1 << 21) != 0
+    }
+    pub fn has_rdrand(&self) -> bool {
+        self.features_ecx & (1 << 30) != 0
+    }
+    pub fn has_rdseed(&self) -> bool {
+        self.structured_features_ebx & (1 << 18) != 0
+    }
+    pub fn has_syscall(&self) -> bool {
+        self.ext_features_edx & (1 << 11) != 0
+    }
+    pub fn has_nx(&self) -> bool {
+        self.ext_features_edx & (1 << 20) != 0
+    }
+    pub fn has_1gb_pages(&self) -> bool {
+        self.ext_features_edx & (1 << 26) != 0
+    }
+    pub fn has_long_mode(&self) -> bool {
+        self.ext_features_edx & (1 << 29) != 0
+    }
+    pub fn max_phys_addr_bits(&self) -> u8 {
+        self.phys_addr_bits
+    }
+}
+
+// 起動時に出力する1行のまとめ
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "CPU: {} \"{}\" phys_bits={} apic={} x2apic={} nx={} 1gb_pages={} rdrand={}",
+            self.vendor(),
+            self.brand_string(),
+            self.max_phys_addr_bits(),
+            self.has_apic(),
+            self.has_x2apic(),
+            self.has_nx(),
+            self.has_1gb_pages(),
+            self.has_rdrand(),
+        )
+    }
+}
+
+// CPUの情報は変わらないので、最初に呼ばれた時に一度だけ読む
+static CPU_INFO: Once<CpuInfo> = Once::new();
+
+pub fn cpu_info() -> &'static CpuInfo {
+    CPU_INFO.call_once(CpuInfo::read)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // QEMUで動かした時のベンダー名は既知のどれかになる
+    #[test_case]
+    fn vendor_is_known() {
+        let vendor = cpu_info().vendor();
+        assert!(
+            ["GenuineIntel", "AuthenticAMD", "TCGTCGTCGTCG"].contains(&vendor),
+            "unknown vendor: {vendor}"
+        );
+    }
+
+    #[test_case]
+    fn brand_string_has_no_padding() {
+        let brand = cpu_info().brand_string();
+        assert!(!brand.contains('\0'));
+        assert_eq!(brand, brand.trim());
+    }
+
+    #[test_case]
+    fn long_mode_cpu_features() {
+        let info = cpu_info();
+        assert!(info.has_apic());
+        assert!(info.max_phys_addr_bits() >= 36);
+    }
+}
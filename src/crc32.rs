@@ -0,0 +1,55 @@
+// CRC-32（IEEE 802.3の多項式で、zlibやPNGと同じ値になるもの）
+
+const POLYNOMIAL: u32 = 0xedb8_8320;
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+static TABLE: [u32; 256] = make_table();
+
+// これまでのCRC（最初は0）にbytesを続けたときのCRCを返す
+// 分けて計算しても、つなげてから計算した場合と同じ値になる
+pub fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for b in bytes {
+        crc = TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0, bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn crc32_matches_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+        let split = crc32_update(crc32(b"12345"), b"6789");
+        assert_eq!(split, crc32(b"123456789"));
+    }
+}
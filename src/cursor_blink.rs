@@ -0,0 +1,169 @@
+use crate::print::apply_vram_cursor_blink;
+use crate::result::Result;
+use crate::timer::TICK_HZ;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+// VRAMコンソールのカーソルの点滅
+// 割り込みの中では描かずに、タイマー割り込みでは「切り替える時刻になった」という印を付けるだけにする
+// 実際の描画は、メインループやエグゼキュータが休む前にpoll()を呼んだときに行う
+
+// 1秒に何回点滅するか（表示と消去で1回）
+pub const DEFAULT_BLINK_HZ: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlinkAction {
+    // 表示と消去を入れ替える
+    Toggle,
+    // 点滅の周期を最初からやり直して、表示する（キー入力があったとき）
+    Show,
+    // 消して元の文字に戻す（点滅を止めたとき）
+    Hide,
+}
+
+const PENDING_TOGGLE: u8 = 1 << 0;
+const PENDING_SHOW: u8 = 1 << 1;
+const PENDING_HIDE: u8 = 1 << 2;
+
+// 点滅のタイミングを決める状態（ティックの数え方は呼び出し側が渡す）
+// 割り込みの中からも触るので、全てアトミック変数で持つ
+pub struct BlinkTimer {
+    enabled: AtomicBool,
+    // 表示と消去を切り替える間隔（ティック数）
+    interval: AtomicU64,
+    // 最後に切り替えた（または周期をやり直した）ティック
+    phase_start: AtomicU64,
+    pending: AtomicU8,
+}
+impl BlinkTimer {
+    pub const fn new(enabled: bool, interval: u64) -> Self {
+        Self {
+            enabled: AtomicBool::new(enabled),
+            interval: AtomicU64::new(interval),
+            phase_start: AtomicU64::new(0),
+            pending: AtomicU8::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    // 止めたときは、表示したままにならないように消す
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        let pending = if enabled { PENDING_SHOW } else { PENDING_HIDE };
+        self.pending.store(pending, Ordering::Relaxed);
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval.load(Ordering::Relaxed)
+    }
+
+    pub fn set_interval(&self, ticks: u64) {
+        self.interval.store(ticks.max(1), Ordering::Relaxed);
+    }
+
+    // ティックごとに割り込みハンドラから呼ぶ
+    pub fn on_tick(&self, now: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        if now.wrapping_sub(self.phase_start.load(Ordering::Relaxed)) >= self.interval() {
+            self.phase_start.store(now, Ordering::Relaxed);
+            self.pending.fetch_or(PENDING_TOGGLE, Ordering::Relaxed);
+        }
+    }
+
+    // 点滅の周期をやり直して、すぐに表示する
+    pub fn reset_phase(&self, now: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.phase_start.store(now, Ordering::Relaxed);
+        self.pending.fetch_or(PENDING_SHOW, Ordering::Relaxed);
+    }
+
+    // 溜まっている操作を1つにまとめて取り出す（消去、表示、切り替えの順に優先する）
+    pub fn take_action(&self) -> Option<BlinkAction> {
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        if pending & PENDING_HIDE != 0 {
+            Some(BlinkAction::Hide)
+        } else if pending & PENDING_SHOW != 0 {
+            Some(BlinkAction::Show)
+        } else if pending & PENDING_TOGGLE != 0 {
+            Some(BlinkAction::Toggle)
+        } else {
+            None
+        }
+    }
+}
+
+static BLINK: BlinkTimer = BlinkTimer::new(true, TICK_HZ / 2 / DEFAULT_BLINK_HZ);
+
+pub fn on_timer_tick(now: u64) {
+    BLINK.on_tick(now);
+}
+
+// キー入力があったときに呼ぶ（割り込みの中からでもよい）
+pub fn note_keystroke() {
+    BLINK.reset_phase(crate::timer::ticks());
+}
+
+pub fn is_enabled() -> bool {
+    BLINK.is_enabled()
+}
+
+pub fn set_enabled(enabled: bool) {
+    BLINK.set_enabled(enabled);
+}
+
+// 1秒にhz回点滅させる
+pub fn set_blink_hz(hz: u64) -> Result<()> {
+    if hz == 0 || hz * 2 > TICK_HZ {
+        return Err("cursor_blink: rate is out of range");
+    }
+    BLINK.set_interval(TICK_HZ / 2 / hz);
+    Ok(())
+}
+
+// 切り替える時刻になっていれば、VRAMコンソールのカーソルを描き直す
+// 割り込みの外から、休む前などに呼ぶ
+pub fn poll() {
+    if let Some(action) = BLINK.take_action() {
+        apply_vram_cursor_blink(action);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn blink_timer_toggles_every_interval() {
+        let blink = BlinkTimer::new(true, 250);
+        let toggles = (1..=1000)
+            .filter(|now| {
+                blink.on_tick(*now);
+                blink.take_action() == Some(BlinkAction::Toggle)
+            })
+            .count();
+        assert_eq!(toggles, 4);
+        // キー入力があると表示に戻り、そこから周期を数え直す
+        blink.reset_phase(1100);
+        assert_eq!(blink.take_action(), Some(BlinkAction::Show));
+        blink.on_tick(1300);
+        assert_eq!(blink.take_action(), None);
+        blink.on_tick(1350);
+        assert_eq!(blink.take_action(), Some(BlinkAction::Toggle));
+        // 止めると消去が優先され、その後は何も起きない
+        blink.on_tick(1600);
+        blink.set_enabled(false);
+        assert_eq!(blink.take_action(), Some(BlinkAction::Hide));
+        blink.on_tick(2000);
+        assert_eq!(blink.take_action(), None);
+        assert!(set_blink_hz(0).is_err());
+    }
+}
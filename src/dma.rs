@@ -0,0 +1,101 @@
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::PAGE_SIZE;
+use crate::result::Result;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+
+// デバイスが直接読み書きする（DMA）ためのバッファ
+// ページアロケータから物理的に連続したページを受け取る
+// ページアロケータが渡すページはアイデンティティマップされているので、仮想アドレスと物理アドレスは同じ
+
+// 32ビットのアドレスしか扱えないデバイスに渡すときのmax_phys_addr
+pub const DMA_32BIT_MAX_ADDR: u64 = 0xFFFF_FFFF;
+
+#[derive(Debug)]
+pub struct DmaBuffer {
+    ptr: NonNull<u8>,
+    phys_addr: u64,
+    layout: Layout,
+    pages: usize,
+}
+// バッファは持ち主からしか触らない
+unsafe impl Send for DmaBuffer {}
+impl DmaBuffer {
+    pub fn ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    // デバイスに渡すアドレス
+    pub fn phys_addr(&self) -> u64 {
+        self.phys_addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.ptr(), self.len()) }
+    }
+}
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // デバイスがまだ使っていないかは、持ち主が確かめてから手放すこと
+        unsafe { PAGE_ALLOCATOR.free_pages(self.ptr(), self.pages) };
+    }
+}
+
+// 0で埋めた、物理的に連続したlayout.size()バイトのバッファを確保する
+// 先頭はlayout.align()に揃い（ページより小さいアラインメントはページに揃う）、
+// バッファの最後のバイトの物理アドレスはmax_phys_addr以下になる
+pub fn alloc_dma(layout: Layout, max_phys_addr: u64) -> Result<DmaBuffer> {
+    if layout.size() == 0 {
+        return Err("alloc_dma: size must not be zero");
+    }
+    let pages = layout.size().div_ceil(PAGE_SIZE);
+    let align = layout.align().max(PAGE_SIZE);
+    let max_addr = usize::try_from(max_phys_addr).unwrap_or(usize::MAX);
+    let ptr = PAGE_ALLOCATOR.alloc_pages_below(pages, align, max_addr)?;
+    Ok(DmaBuffer {
+        ptr: NonNull::new(ptr).ok_or("alloc_dma: got a null page")?,
+        phys_addr: ptr as u64,
+        layout,
+        pages,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn dma_buffers_are_zeroed_aligned_and_below_the_limit() {
+        let before = PAGE_ALLOCATOR.free_page_count();
+        let layout = Layout::from_size_align(3 * PAGE_SIZE + 100, 64 * 1024).unwrap();
+        let mut buf = alloc_dma(layout, DMA_32BIT_MAX_ADDR).unwrap();
+        assert_eq!(buf.len(), layout.size());
+        assert!(buf.phys_addr().is_multiple_of(64 * 1024));
+        assert!(buf.phys_addr() + buf.len() as u64 - 1 <= DMA_32BIT_MAX_ADDR);
+        assert_eq!(buf.ptr() as u64, buf.phys_addr());
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        buf.as_mut_slice().fill(0xab);
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), before - 4);
+        drop(buf);
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), before);
+    }
+    #[test_case]
+    fn impossible_constraints_are_rejected() {
+        let layout = Layout::from_size_align(PAGE_SIZE, 8).unwrap();
+        // アドレス0のページは使わないので、最初のページだけには置けない
+        assert!(alloc_dma(layout, PAGE_SIZE as u64 - 1).is_err());
+        assert!(alloc_dma(Layout::from_size_align(0, 8).unwrap(), u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,651 @@
+extern crate alloc;
+
+use crate::allocator::LAYOUT_PAGE_4K;
+use crate::paging::is_mapped;
+use crate::paging::map_frame;
+use crate::paging::set_page_attr;
+use crate::paging::PageAttr;
+use crate::paging::PAGE_SIZE;
+use crate::result::Result;
+use alloc::alloc::alloc_zeroed;
+use alloc::alloc::dealloc;
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::write_unaligned;
+
+// ELFヘッダ（e_ident）の中身
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+// e_type
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const EHDR_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+
+// プログラムヘッダの種類と、セグメントの権限
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+// 動的セクションのエントリ（再配置の表の場所）
+const DYN_LEN: usize = 16;
+const DT_NULL: u64 = 0;
+const DT_PLTRELSZ: u64 = 2;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const RELA_LEN: usize = 24;
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const PAGE: u64 = PAGE_SIZE as u64;
+// 読み込めるイメージの大きさとアラインメントの上限
+const MAX_IMAGE_SIZE: u64 = 64 << 20;
+const MAX_SEGMENT_ALIGN: u64 = 2 << 20;
+// 下位半分の正規アドレス（この範囲にしか置かない）
+const LOWER_HALF_END: u64 = 1 << 47;
+
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| bytes.get(offset..end))
+        .and_then(|b| b.try_into().ok())
+        .ok_or("ELF: file is truncated")
+}
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16> {
+    read_le(bytes, offset).map(u16::from_le_bytes)
+}
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    read_le(bytes, offset).map(u32::from_le_bytes)
+}
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64> {
+    read_le(bytes, offset).map(u64::from_le_bytes)
+}
+
+fn page_floor(v: u64) -> u64 {
+    v & !(PAGE - 1)
+}
+fn page_ceil(v: u64) -> u64 {
+    (v + PAGE - 1) & !(PAGE - 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfType {
+    // 決められたアドレスに置く実行ファイル
+    Executable,
+    // 位置独立実行ファイル（PIE）。どこに置いてもよい
+    PositionIndependent,
+}
+
+// メモリに読み込むセグメント（PT_LOAD）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub flags: u32,
+    pub align: u64,
+}
+impl Segment {
+    fn page_start(&self) -> u64 {
+        page_floor(self.vaddr)
+    }
+    fn page_end(&self) -> u64 {
+        page_ceil(self.vaddr + self.mem_size)
+    }
+    fn contains(&self, vaddr: u64, len: u64) -> bool {
+        vaddr >= self.vaddr && vaddr + len <= self.vaddr + self.mem_size
+    }
+    // 読み込みはどのセグメントでもできるようにして、書き込みと実行だけ制限する
+    fn attr(&self) -> PageAttr {
+        match (self.flags & PF_W != 0, self.flags & PF_X != 0) {
+            (true, true) => PageAttr::ReadWriteKernel,
+            (true, false) => PageAttr::ReadWriteKernelNoExec,
+            (false, true) => PageAttr::ReadOnlyKernel,
+            (false, false) => PageAttr::ReadOnlyKernelNoExec,
+        }
+    }
+}
+
+// 読み込む前のELFファイル
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    pub elf_type: ElfType,
+    pub entry: u64,
+    // vaddrの順に並んでいて、ページ単位でも重ならない
+    pub segments: Vec<Segment>,
+    dynamic: Option<(u64, u64)>,
+}
+impl<'a> Elf<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        if bytes.len() < EHDR_LEN {
+            return Err("ELF: file is truncated");
+        }
+        if &bytes[0..4] != ELF_MAGIC {
+            return Err("ELF: bad magic");
+        }
+        if bytes[4] != ELFCLASS64 {
+            return Err("ELF: not a 64-bit ELF");
+        }
+        if bytes[5] != ELFDATA2LSB || bytes[6] != EV_CURRENT {
+            return Err("ELF: unsupported encoding or version");
+        }
+        let elf_type = match u16_at(bytes, 16)? {
+            ET_EXEC => ElfType::Executable,
+            ET_DYN => ElfType::PositionIndependent,
+            _ => return Err("ELF: not an executable"),
+        };
+        if u16_at(bytes, 18)? != EM_X86_64 {
+            return Err("ELF: not for x86-64");
+        }
+        let entry = u64_at(bytes, 24)?;
+        let phoff = u64_at(bytes, 32)? as usize;
+        let phentsize = u16_at(bytes, 54)? as usize;
+        let phnum = u16_at(bytes, 56)? as usize;
+        if phentsize < PHDR_LEN {
+            return Err("ELF: program header is too small");
+        }
+
+        let mut segments = Vec::new();
+        let mut dynamic = None;
+        for i in 0..phnum {
+            let ph = i
+                .checked_mul(phentsize)
+                .and_then(|off| off.checked_add(phoff))
+                .ok_or("ELF: file is truncated")?;
+            let p_type = u32_at(bytes, ph)?;
+            let offset = u64_at(bytes, ph + 8)?;
+            let vaddr = u64_at(bytes, ph + 16)?;
+            let file_size = u64_at(bytes, ph + 32)?;
+            let mem_size = u64_at(bytes, ph + 40)?;
+            let file_end = offset.checked_add(file_size);
+            if file_end.is_none_or(|end| end > bytes.len() as u64) {
+                return Err("ELF: segment data is truncated");
+            }
+            match p_type {
+                PT_LOAD if mem_size > 0 => {
+                    if file_size > mem_size {
+                        return Err("ELF: segment is larger in the file than in memory");
+                    }
+                    if vaddr
+                        .checked_add(mem_size)
+                        .is_none_or(|end| end > LOWER_HALF_END - PAGE)
+                    {
+                        return Err("ELF: segment is outside of the address space");
+                    }
+                    let align = u64_at(bytes, ph + 48)?;
+                    if align > 1 && !align.is_power_of_two() {
+                        return Err("ELF: segment alignment is not a power of two");
+                    }
+                    segments.push(Segment {
+                        offset,
+                        vaddr,
+                        file_size,
+                        mem_size,
+                        flags: u32_at(bytes, ph + 4)?,
+                        align,
+                    });
+                }
+                PT_DYNAMIC => dynamic = Some((offset, file_size)),
+                _ => {}
+            }
+        }
+        segments.sort_by_key(|s| s.vaddr);
+        let (Some(first), Some(last)) = (segments.first(), segments.last()) else {
+            return Err("ELF: no loadable segments");
+        };
+        // 権限はページ単位でしか設定できないので、同じページを共有するセグメントも重なりとみなす
+        if segments
+            .windows(2)
+            .any(|w| w[0].page_end() > w[1].page_start())
+        {
+            return Err("ELF: segments overlap");
+        }
+        if last.page_end() - first.page_start() > MAX_IMAGE_SIZE {
+            return Err("ELF: image is too large");
+        }
+        if !segments
+            .iter()
+            .any(|s| s.flags & PF_X != 0 && s.contains(entry, 1))
+        {
+            return Err("ELF: entry point is not in an executable segment");
+        }
+        Ok(Self {
+            bytes,
+            elf_type,
+            entry,
+            segments,
+            dynamic,
+        })
+    }
+
+    fn segment_data(&self, s: &Segment) -> &'a [u8] {
+        // 範囲はparseで確かめている
+        &self.bytes[s.offset as usize..(s.offset + s.file_size) as usize]
+    }
+
+    // 仮想アドレスの範囲に対応するファイルの中身
+    fn file_slice(&self, vaddr: u64, len: u64) -> Result<&'a [u8]> {
+        let s = self
+            .segments
+            .iter()
+            .find(|s| vaddr >= s.vaddr && vaddr - s.vaddr + len <= s.file_size)
+            .ok_or("ELF: address is not backed by the file")?;
+        let start = (vaddr - s.vaddr) as usize;
+        Ok(&self.segment_data(s)[start..start + len as usize])
+    }
+
+    // 各セグメント用のページを確保して中身を書き込み、セグメントの権限でマップする
+    pub fn load(&self) -> Result<LoadedImage> {
+        match self.elf_type {
+            ElfType::PositionIndependent => self.load_relocated(),
+            ElfType::Executable => self.load_at_requested_address(),
+        }
+    }
+
+    // PIEはヒープに確保した連続した領域（アイデンティティマップされている）に置く
+    fn load_relocated(&self) -> Result<LoadedImage> {
+        let start = self.segments[0].page_start();
+        let end = self.segments[self.segments.len() - 1].page_end();
+        let align = self.segments.iter().map(|s| s.align).fold(PAGE, u64::max);
+        if align > MAX_SEGMENT_ALIGN {
+            return Err("ELF: segment alignment is too large");
+        }
+        let layout = Layout::from_size_align((end - start) as usize, align as usize)
+            .map_err(|_| "ELF: invalid image layout")?;
+        // 0で埋めた領域を確保するので、BSS（ファイルにない部分）は0になっている
+        let block = unsafe { alloc_zeroed(layout) };
+        if block.is_null() {
+            return Err("ELF: out of memory");
+        }
+        let bias = (block as u64).wrapping_sub(start);
+        let image = LoadedImage {
+            entry: self.entry.wrapping_add(bias),
+            bias,
+            backing: Backing::Block { block, layout },
+        };
+        for s in &self.segments {
+            let data = self.segment_data(s);
+            let dst = s.vaddr.wrapping_add(bias) as *mut u8;
+            unsafe { copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        }
+        self.relocate(bias)?;
+        for s in &self.segments {
+            set_page_attr(
+                s.page_start().wrapping_add(bias),
+                s.page_end().wrapping_add(bias),
+                s.attr(),
+            )?;
+        }
+        Ok(image)
+    }
+
+    // 位置独立でない実行ファイルは、要求されたアドレスに新しいページをマップして置く
+    // 既に使われているアドレスを要求していたら、カーネルを壊さないように失敗する
+    fn load_at_requested_address(&self) -> Result<LoadedImage> {
+        let pages = || {
+            self.segments.iter().flat_map(|s| {
+                (s.page_start()..s.page_end())
+                    .step_by(PAGE_SIZE)
+                    .map(move |p| (s, p))
+            })
+        };
+        if pages().any(|(_, page)| is_mapped(page)) {
+            return Err("ELF: requested address is already in use");
+        }
+        let mut image = LoadedImage {
+            entry: self.entry,
+            bias: 0,
+            backing: Backing::Pages(Vec::new()),
+        };
+        for (s, page) in pages() {
+            let frame = unsafe { alloc_zeroed(LAYOUT_PAGE_4K) };
+            if frame.is_null() {
+                return Err("ELF: out of memory");
+            }
+            if let Backing::Pages(frames) = &mut image.backing {
+                frames.push((page, frame));
+            }
+            // このページに入るファイルの中身をフレームに書き込む（残りは0のまま）
+            let copy_start = s.vaddr.max(page);
+            let copy_end = (s.vaddr + s.file_size).min(page + PAGE);
+            if copy_start < copy_end {
+                let src = self.file_slice(copy_start, copy_end - copy_start)?;
+                let dst = unsafe { frame.add((copy_start - page) as usize) };
+                unsafe { copy_nonoverlapping(src.as_ptr(), dst, src.len()) };
+            }
+            map_frame(page, frame as u64, s.attr())?;
+        }
+        Ok(image)
+    }
+
+    // PIEの動的再配置を適用する（シンボルを使わない R_X86_64_RELATIVE だけに対応する）
+    fn relocate(&self, bias: u64) -> Result<()> {
+        let Some((offset, size)) = self.dynamic else {
+            return Ok(());
+        };
+        let dynamic = &self.bytes[offset as usize..(offset + size) as usize];
+        let mut rela = None;
+        let mut rela_size = 0;
+        for entry in dynamic.chunks_exact(DYN_LEN) {
+            let tag = u64_at(entry, 0)?;
+            let val = u64_at(entry, 8)?;
+            match tag {
+                DT_NULL => break,
+                DT_RELA => rela = Some(val),
+                DT_RELASZ => rela_size = val,
+                DT_RELAENT if val != RELA_LEN as u64 => {
+                    return Err("ELF: unexpected relocation entry size")
+                }
+                DT_REL => return Err("ELF: REL relocations are not supported"),
+                DT_PLTRELSZ if val != 0 => return Err("ELF: PLT relocations are not supported"),
+                _ => {}
+            }
+        }
+        let Some(rela) = rela else {
+            return Ok(());
+        };
+        for r in self.file_slice(rela, rela_size)?.chunks_exact(RELA_LEN) {
+            let target = u64_at(r, 0)?;
+            let addend = u64_at(r, 16)?;
+            match u64_at(r, 8)? as u32 {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    if !self.segments.iter().any(|s| s.contains(target, 8)) {
+                        return Err("ELF: relocation is outside of the image");
+                    }
+                    let dst = target.wrapping_add(bias) as *mut u64;
+                    unsafe { write_unaligned(dst, bias.wrapping_add(addend)) };
+                }
+                _ => return Err("ELF: unsupported relocation type"),
+            }
+        }
+        Ok(())
+    }
+}
+
+// 読み込んだプログラムのエントリポイント
+pub type EntryPoint = extern "sysv64" fn() -> u64;
+
+enum Backing {
+    // PIEを置いたヒープの領域
+    Block { block: *mut u8, layout: Layout },
+    // 要求されたアドレス（仮想）と、そこにマップしたフレーム
+    Pages(Vec<(u64, *mut u8)>),
+}
+
+// メモリに読み込んだプログラム
+// 破棄するとマップを元に戻してメモリを解放するので、実行中は破棄しないこと
+pub struct LoadedImage {
+    entry: u64,
+    bias: u64,
+    backing: Backing,
+}
+impl LoadedImage {
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    // ファイルに書かれたアドレスと、実際に置いたアドレスの差
+    pub fn load_bias(&self) -> u64 {
+        self.bias
+    }
+
+    /// # Safety
+    /// 返した関数は、このイメージをdropした後に呼ばないこと
+    /// また、読み込んだプログラムがsysv64のABIに従っていると信頼できること
+    pub unsafe fn entry_point(&self) -> EntryPoint {
+        core::mem::transmute::<usize, EntryPoint>(self.entry as usize)
+    }
+}
+impl Drop for LoadedImage {
+    fn drop(&mut self) {
+        match &self.backing {
+            Backing::Block { block, layout } => {
+                // ヒープとして使えるように、元の属性（読み書き可能、実行禁止）に戻す
+                let start = *block as u64;
+                let _ = set_page_attr(
+                    start,
+                    start + layout.size() as u64,
+                    PageAttr::ReadWriteKernelNoExec,
+                );
+                unsafe { dealloc(*block, *layout) };
+            }
+            Backing::Pages(frames) => {
+                for (page, frame) in frames {
+                    let _ = map_frame(*page, 0, PageAttr::NotPresent);
+                    unsafe { dealloc(*frame, LAYOUT_PAGE_4K) };
+                }
+            }
+        }
+    }
+}
+
+// ELFファイルを読み込む
+pub fn load(bytes: &[u8]) -> Result<LoadedImage> {
+    Elf::parse(bytes)?.load()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use core::ptr::read_volatile;
+
+    // apps/elf_test_payloadを実行すると返ってくる値（"WASABIEL"）
+    const PAYLOAD_MAGIC: u64 = 0x5741_5341_4249_454c;
+
+    const PF_R: u32 = 1 << 2;
+
+    struct Phdr<'a> {
+        p_type: u32,
+        flags: u32,
+        vaddr: u64,
+        data: &'a [u8],
+        mem_size: u64,
+    }
+    fn load_segment(flags: u32, vaddr: u64, data: &[u8], mem_size: u64) -> Phdr<'_> {
+        Phdr {
+            p_type: PT_LOAD,
+            flags,
+            vaddr,
+            data,
+            mem_size,
+        }
+    }
+    // ヘッダ、プログラムヘッダ、各セグメントの中身の順に並べたELFファイルを作る
+    fn build_elf(e_type: u16, entry: u64, phdrs: &[Phdr]) -> Vec<u8> {
+        let mut b = vec![0u8; EHDR_LEN + PHDR_LEN * phdrs.len()];
+        b[0..4].copy_from_slice(ELF_MAGIC);
+        b[4] = ELFCLASS64;
+        b[5] = ELFDATA2LSB;
+        b[6] = EV_CURRENT;
+        b[16..18].copy_from_slice(&e_type.to_le_bytes());
+        b[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        b[24..32].copy_from_slice(&entry.to_le_bytes());
+        b[32..40].copy_from_slice(&(EHDR_LEN as u64).to_le_bytes());
+        b[54..56].copy_from_slice(&(PHDR_LEN as u16).to_le_bytes());
+        b[56..58].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for (i, p) in phdrs.iter().enumerate() {
+            let offset = b.len() as u64;
+            let ph = EHDR_LEN + PHDR_LEN * i;
+            b[ph..ph + 4].copy_from_slice(&p.p_type.to_le_bytes());
+            b[ph + 4..ph + 8].copy_from_slice(&p.flags.to_le_bytes());
+            b[ph + 8..ph + 16].copy_from_slice(&offset.to_le_bytes());
+            b[ph + 16..ph + 24].copy_from_slice(&p.vaddr.to_le_bytes());
+            b[ph + 32..ph + 40].copy_from_slice(&(p.data.len() as u64).to_le_bytes());
+            b[ph + 40..ph + 48].copy_from_slice(&p.mem_size.to_le_bytes());
+            b[ph + 48..ph + 56].copy_from_slice(&PAGE.to_le_bytes());
+            b.extend_from_slice(p.data);
+        }
+        b
+    }
+    // mov rax, value; ret
+    fn return_value(value: u64) -> Vec<u8> {
+        let mut code = vec![0x48, 0xb8];
+        code.extend_from_slice(&value.to_le_bytes());
+        code.push(0xc3);
+        code
+    }
+    // 0x1000にコード、0x2000にBSSを含むデータを置くプログラム
+    fn simple_elf(e_type: u16, base: u64, value: u64) -> Vec<u8> {
+        let code = return_value(value);
+        build_elf(
+            e_type,
+            base + 0x1000,
+            &[
+                load_segment(PF_R | PF_X, base + 0x1000, &code, code.len() as u64),
+                load_segment(PF_R | PF_W, base + 0x2000, &[0xaa; 8], 0x1000),
+            ],
+        )
+    }
+
+    #[test_case]
+    fn headers_are_validated() {
+        let good = simple_elf(ET_DYN, 0, 42);
+        assert!(Elf::parse(&good).is_ok());
+        let broken = |i: usize, v: u8| {
+            let mut b = good.clone();
+            b[i] = v;
+            Elf::parse(&b).err()
+        };
+        assert_eq!(broken(0, 0), Some("ELF: bad magic"));
+        assert_eq!(broken(4, 1), Some("ELF: not a 64-bit ELF"));
+        assert_eq!(broken(5, 2), Some("ELF: unsupported encoding or version"));
+        assert_eq!(broken(16, 1), Some("ELF: not an executable"));
+        assert_eq!(broken(18, 3), Some("ELF: not for x86-64"));
+        assert_eq!(
+            Elf::parse(&good[..EHDR_LEN - 1]).err(),
+            Some("ELF: file is truncated")
+        );
+        // プログラムヘッダの途中で切れている
+        assert_eq!(
+            Elf::parse(&good[..EHDR_LEN + 10]).err(),
+            Some("ELF: file is truncated")
+        );
+        // セグメントの中身の途中で切れている
+        assert_eq!(
+            Elf::parse(&good[..good.len() - 1]).err(),
+            Some("ELF: segment data is truncated")
+        );
+    }
+    #[test_case]
+    fn bad_segments_are_rejected() {
+        let code = return_value(1);
+        let parse = |phdrs: &[Phdr]| Elf::parse(&build_elf(ET_DYN, 0x1000, phdrs)).err();
+        assert_eq!(
+            parse(&[
+                load_segment(PF_X, 0x1000, &code, 0x100),
+                load_segment(PF_W, 0x1800, &[], 0x100),
+            ]),
+            Some("ELF: segments overlap")
+        );
+        assert_eq!(
+            parse(&[load_segment(PF_X, 0x1000, &code, 4)]),
+            Some("ELF: segment is larger in the file than in memory")
+        );
+        assert_eq!(
+            parse(&[load_segment(PF_X, LOWER_HALF_END - 0x800, &code, 0x1000)]),
+            Some("ELF: segment is outside of the address space")
+        );
+        assert_eq!(
+            parse(&[load_segment(PF_X, u64::MAX - 4, &code, 0x1000)]),
+            Some("ELF: segment is outside of the address space")
+        );
+        assert_eq!(
+            parse(&[load_segment(PF_X, 0x1000, &code, MAX_IMAGE_SIZE + 1)]),
+            Some("ELF: image is too large")
+        );
+        assert_eq!(
+            parse(&[load_segment(PF_W, 0x1000, &code, 0x100)]),
+            Some("ELF: entry point is not in an executable segment")
+        );
+        assert_eq!(parse(&[]), Some("ELF: no loadable segments"));
+    }
+    #[test_case]
+    fn pie_is_loaded_and_runs() {
+        let image = load(&simple_elf(ET_DYN, 0, 0x1234_5678_9abc_def0)).unwrap();
+        let data = image.load_bias().wrapping_add(0x2000) as *const u64;
+        unsafe {
+            assert_eq!(read_volatile(data), 0xaaaa_aaaa_aaaa_aaaa);
+            // ファイルにない部分は0で埋められている
+            assert_eq!(read_volatile(data.add(1)), 0);
+            assert_eq!(read_volatile(data.add(511)), 0);
+            assert_eq!(image.entry_point()(), 0x1234_5678_9abc_def0);
+        }
+    }
+    #[test_case]
+    fn relative_relocations_are_applied() {
+        let code = return_value(7);
+        // 0x3000にある表で、0x2000のポインタを0x2010を指すように再配置する
+        let mut rela = Vec::new();
+        rela.extend_from_slice(&0x2000u64.to_le_bytes());
+        rela.extend_from_slice(&(R_X86_64_RELATIVE as u64).to_le_bytes());
+        rela.extend_from_slice(&0x2010u64.to_le_bytes());
+        let mut dynamic = Vec::new();
+        for (tag, val) in [
+            (DT_RELA, 0x3000),
+            (DT_RELASZ, RELA_LEN as u64),
+            (DT_RELAENT, RELA_LEN as u64),
+            (DT_NULL, 0),
+        ] {
+            dynamic.extend_from_slice(&tag.to_le_bytes());
+            dynamic.extend_from_slice(&val.to_le_bytes());
+        }
+        let elf = build_elf(
+            ET_DYN,
+            0x1000,
+            &[
+                load_segment(PF_R | PF_X, 0x1000, &code, code.len() as u64),
+                load_segment(PF_R | PF_W, 0x2000, &[0; 8], 0x100),
+                load_segment(PF_R, 0x3000, &rela, rela.len() as u64),
+                Phdr {
+                    p_type: PT_DYNAMIC,
+                    flags: PF_R,
+                    vaddr: 0x4000,
+                    data: &dynamic,
+                    mem_size: dynamic.len() as u64,
+                },
+            ],
+        );
+        let image = load(&elf).unwrap();
+        let bias = image.load_bias();
+        let ptr = bias.wrapping_add(0x2000) as *const u64;
+        assert_eq!(unsafe { read_volatile(ptr) }, bias.wrapping_add(0x2010));
+        assert_eq!(unsafe { image.entry_point()() }, 7);
+    }
+    #[test_case]
+    fn executable_is_loaded_at_requested_address() {
+        // カーネルがマップしていない高いアドレスに置く
+        let base = 0x4000_0000_0000;
+        let image = load(&simple_elf(ET_EXEC, base, 99)).unwrap();
+        assert_eq!(image.load_bias(), 0);
+        assert_eq!(image.entry(), base + 0x1000);
+        assert_eq!(unsafe { read_volatile((base + 0x2000) as *const u8) }, 0xaa);
+        assert_eq!(unsafe { image.entry_point()() }, 99);
+        // 既に使われているアドレスには置かない
+        assert_eq!(
+            load(&simple_elf(ET_EXEC, base, 99)).err(),
+            Some("ELF: requested address is already in use")
+        );
+        drop(image);
+        assert!(!is_mapped(base + 0x1000));
+    }
+    #[test_case]
+    fn embedded_payload_returns_magic() {
+        let payload =
+            crate::initrd::open("bin/elf_test_payload").expect("payload is not in initrd");
+        let elf = Elf::parse(payload).unwrap();
+        assert_eq!(elf.elf_type, ElfType::PositionIndependent);
+        let image = elf.load().unwrap();
+        assert_eq!(unsafe { image.entry_point()() }, PAYLOAD_MAGIC);
+    }
+}
@@ -0,0 +1,226 @@
+extern crate alloc;
+
+use crate::cursor_blink;
+use crate::page_alloc::IDLE_ZERO_PAGES;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::result::Result;
+use crate::ring_buffer::RingBuffer;
+use crate::watchdog;
+use crate::x86::disable_interrupts;
+use crate::x86::enable_interrupts;
+use crate::x86::hlt_with_interrupts;
+use crate::x86::InterruptGuard;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::task::Context;
+use core::task::Poll;
+use core::task::Waker;
+use spin::Mutex;
+
+// 同時に存在できるタスクの最大数
+// 各タスクは実行待ちキューに高々1つしか入らないので、キューの大きさもこれで足りる
+pub const MAX_TASKS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+// 割り込みハンドラからも起こされるので、ロックを取るときは割り込みを禁止する
+type ReadyQueue = Mutex<RingBuffer<TaskId, MAX_TASKS>>;
+
+// タスクIDで実行待ちキューにタスクを積むWaker
+// タスクが終了した後に起こされても、IDが見つからないだけで何も起きない
+struct TaskWaker {
+    id: TaskId,
+    // 既にキューに入っていればtrue（同じタスクを何度も積まないようにする）
+    queued: AtomicBool,
+    ready: Arc<ReadyQueue>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+    fn wake_by_ref(self: &Arc<Self>) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let _guard = InterruptGuard::new();
+        if self.ready.lock().push(self.id).is_err() {
+            panic!("Executor ready queue overflow");
+        }
+    }
+}
+
+pub struct Task {
+    future: Pin<Box<dyn Future<Output = ()>>>,
+    waker: Arc<TaskWaker>,
+}
+
+impl Task {
+    fn poll(&mut self) -> Poll<()> {
+        // poll中に起こされたらもう一度キューに積めるように、先にフラグを下ろす
+        self.waker.queued.store(false, Ordering::Release);
+        let waker = Waker::from(self.waker.clone());
+        let mut cx = Context::from_waker(&waker);
+        self.future.as_mut().poll(&mut cx)
+    }
+}
+
+// シングルスレッドの非同期エグゼキュータ
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    ready: Arc<ReadyQueue>,
+    next_id: u64,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self {
+            tasks: BTreeMap::new(),
+            ready: Arc::new(Mutex::new(RingBuffer::new())),
+            next_id: 0,
+        }
+    }
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + 'static) -> Result<TaskId> {
+        if self.tasks.len() >= MAX_TASKS {
+            return Err("Too many tasks");
+        }
+        let id = TaskId(self.next_id);
+        self.next_id += 1;
+        let waker = Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready: self.ready.clone(),
+        });
+        // 最初の1回は起こされなくてもpollする
+        waker.wake_by_ref();
+        self.tasks.insert(
+            id,
+            Task {
+                future: Box::pin(future),
+                waker,
+            },
+        );
+        Ok(id)
+    }
+    fn pop_ready(&self) -> Option<TaskId> {
+        let _guard = InterruptGuard::new();
+        self.ready.lock().pop()
+    }
+    // 実行待ちのタスクを全て実行する
+    pub fn run_ready_tasks(&mut self) {
+        while let Some(id) = self.pop_ready() {
+            // 終了したタスクのWakerが起こされた場合
+            let Some(task) = self.tasks.get_mut(&id) else {
+                continue;
+            };
+            if task.poll().is_ready() {
+                self.tasks.remove(&id);
+            }
+        }
+    }
+    // 全てのタスクが終了するまで実行する
+    // 実行できるタスクがなければ、割り込みが来るまでhltで休む
+    pub fn run(&mut self) {
+        loop {
+            watchdog::pet();
+            self.run_ready_tasks();
+            if self.tasks.is_empty() {
+                return;
+            }
+            cursor_blink::poll();
+            // 手が空いているので、空きページを少しずつ0で埋めておく
+            if self.ready.lock().is_empty() {
+                PAGE_ALLOCATOR.zero_free_pages(IDLE_ZERO_PAGES);
+            }
+            // キューが空なのを確かめてからhltするまでの間に割り込みが来ると起きられないので、
+            // 割り込みを禁止して確かめ、sti; hltで休む
+            disable_interrupts();
+            if self.ready.lock().is_empty() {
+                hlt_with_interrupts();
+            } else {
+                enable_interrupts();
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 1回だけPendingを返して、他のタスクに実行を譲るFuture
+pub struct Yield {
+    yielded: bool,
+}
+
+impl Future for Yield {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub fn yield_now() -> Yield {
+    Yield { yielded: false }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn yielding_tasks_ping_pong() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut executor = Executor::new();
+        for name in ["ping", "pong"] {
+            let log = log.clone();
+            executor
+                .spawn(async move {
+                    for i in 0..3 {
+                        log.lock().push((name, i));
+                        yield_now().await;
+                    }
+                })
+                .expect("spawn failed");
+        }
+        executor.run();
+        assert_eq!(
+            *log.lock(),
+            [
+                ("ping", 0),
+                ("pong", 0),
+                ("ping", 1),
+                ("pong", 1),
+                ("ping", 2),
+                ("pong", 2)
+            ]
+        );
+    }
+    #[test_case]
+    fn waking_finished_task_is_ignored() {
+        let mut executor = Executor::new();
+        let id = executor.spawn(async {}).expect("spawn failed");
+        let waker = Waker::from(executor.tasks[&id].waker.clone());
+        executor.run();
+        assert!(executor.tasks.is_empty());
+        waker.wake_by_ref();
+        executor.run_ready_tasks();
+        drop(waker);
+        assert!(executor.ready.lock().is_empty());
+    }
+}
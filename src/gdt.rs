@@ -0,0 +1,366 @@
+extern crate alloc;
+
+use crate::info;
+use crate::result::Result;
+use crate::stack::KernelStack;
+use crate::x86::write_cs;
+use crate::x86::write_ds;
+use crate::x86::write_es;
+use crate::x86::write_fs;
+use crate::x86::write_gs;
+use crate::x86::write_ss;
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::fmt;
+use core::mem::size_of;
+use core::pin::Pin;
+use spin::Mutex;
+
+// 割り込みを処理するときに使うスタックの大きさ
+const HANDLER_STACK_SIZE: usize = 64 * 1024;
+
+// 割り込みの状態管理
+// IST(Interrupt Stack Table)を持っていたりする
+#[repr(C, packed)]
+struct TaskStateSegment64Inner {
+    _reserved0: u32,
+    _rsp: [u64; 3], // for switch into ring0-2
+    _ist: [u64; 8], // ist[1]~ist[7] (ist[0] is reserved)
+    _reserved1: [u16; 5],
+    _io_map_base_addr: u16,
+}
+const _: () = assert!(size_of::<TaskStateSegment64Inner>() == 104);
+// innerを導入している理由は不明
+pub struct TaskStateSegment64 {
+    inner: Pin<Box<TaskStateSegment64Inner>>,
+}
+impl TaskStateSegment64 {
+    // 物理アドレスの取得
+    pub fn phys_addr(&self) -> u64 {
+        self.inner.as_ref().get_ref() as *const TaskStateSegment64Inner as u64
+    }
+    // istをメモリ上に配置
+    // ページングを切り替える前なので、ヒープに置く（後でuse_guarded_stacksで差し替える）
+    unsafe fn alloc_interrupt_stack() -> u64 {
+        let stack = Box::new([0u8; HANDLER_STACK_SIZE]);
+        let rsp = unsafe { stack.as_ptr().add(HANDLER_STACK_SIZE) as u64 };
+        core::mem::forget(stack);
+        // now, no one except us own the region since it is forgotten by the
+        // allocator ;)
+        rsp
+    }
+    // alloc_interrupt_stackで確保したスタックを解放する
+    unsafe fn free_interrupt_stack(rsp: u64) {
+        let stack = (rsp - HANDLER_STACK_SIZE as u64) as *mut [u8; HANDLER_STACK_SIZE];
+        drop(Box::from_raw(stack));
+    }
+    // rsp0とist[1..=7]を、下にガードページを置いたスタックに差し替えて、ヒープのスタックを解放する
+    // 割り込みのスタックが溢れても、ヒープを壊さずにページフォルト（ダブルフォルト）になる
+    // 割り込みを処理している途中には呼ばないこと
+    fn use_guarded_stacks(&mut self) -> Result<()> {
+        // 全てのスタックを確保できてから差し替える
+        // 途中で確保に失敗したら、それまでに確保したスタックを返して今のスタックを使い続ける
+        let mut guarded: [Option<KernelStack>; 8] = [const { None }; 8];
+        let mut error = None;
+        for slot in guarded.iter_mut() {
+            match KernelStack::alloc(HANDLER_STACK_SIZE) {
+                Ok(stack) => *slot = Some(stack),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if let Some(e) = error {
+            for stack in guarded.into_iter().flatten() {
+                // 確保したばかりで誰も使っていない
+                let _ = unsafe { stack.free() };
+            }
+            return Err(e);
+        }
+        let stacks = guarded.map(|stack| stack.map_or(0, |stack| stack.top()));
+        let inner = self.inner.as_mut().get_mut();
+        // packedな構造体のフィールドは参照を作れないので、丸ごとコピーして読み書きする
+        let (old_rsp, old_ist) = (inner._rsp, inner._ist);
+        inner._rsp = [stacks[0], old_rsp[1], old_rsp[2]];
+        inner._ist = [
+            0, stacks[1], stacks[2], stacks[3], stacks[4], stacks[5], stacks[6], stacks[7],
+        ];
+        for rsp in core::iter::once(old_rsp[0]).chain(old_ist[1..].iter().copied()) {
+            unsafe { Self::free_interrupt_stack(rsp) };
+        }
+        Ok(())
+    }
+    // TSSの作成
+    pub fn new() -> Self {
+        let rsp0 = unsafe { Self::alloc_interrupt_stack() };
+        let mut ist = [0u64; 8];
+        for ist in ist[1..=7].iter_mut() {
+            *ist = unsafe { Self::alloc_interrupt_stack() };
+        }
+        let tss64 = TaskStateSegment64Inner {
+            _reserved0: 0,
+            _rsp: [rsp0, 0, 0],
+            _ist: ist,
+            _reserved1: [0; 5],
+            _io_map_base_addr: 0,
+        };
+        let this = Self {
+            inner: Box::pin(tss64),
+        };
+        info!("TSS64 created @ {:#X}", this.phys_addr(),);
+        this
+    }
+}
+
+impl Default for TaskStateSegment64 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// そりゃdropしたら割り込みができなくなるからね
+// dropしたときにpanicを起こすようになっている
+impl Drop for TaskStateSegment64 {
+    fn drop(&mut self) {
+        panic!("TSS64 being dropped!");
+    }
+}
+
+// ここはよくわからない
+pub const BIT_TYPE_DATA: u64 = 0b10u64 << 43;
+pub const BIT_TYPE_CODE: u64 = 0b11u64 << 43;
+
+pub const BIT_PRESENT: u64 = 1u64 << 47;
+pub const BIT_CS_LONG_MODE: u64 = 1u64 << 53;
+pub const BIT_CS_READABLE: u64 = 1u64 << 41;
+pub const BIT_DS_WRITABLE: u64 = 1u64 << 41;
+pub const BIT_DPL0: u64 = 0u64 << 45;
+pub const BIT_DPL3: u64 = 3u64 << 45;
+
+// GDTの状態
+#[repr(u64)]
+enum GdtAttr {
+    KernelCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
+    KernelData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+}
+// 64bitカーネルのコード/データセグメントの定番のエンコーディングになっているかを確認する
+const _: () = assert!(GdtAttr::KernelCode as u64 == 0x0020_9A00_0000_0000);
+const _: () = assert!(GdtAttr::KernelData as u64 == 0x0000_9200_0000_0000);
+
+// GDTの容量とポインタ
+#[allow(dead_code)]
+#[repr(C, packed)]
+struct GdtrParameters {
+    limit: u16,
+    base: *const Gdt,
+}
+
+// ここはわからない
+pub const KERNEL_CS: u16 = 1 << 3;
+pub const KERNEL_DS: u16 = 2 << 3;
+pub const TSS64_SEL: u16 = 3 << 3;
+
+// GDT構造体
+// それぞれのセグメントを持っている
+#[allow(dead_code)]
+#[repr(C, packed)]
+pub struct Gdt {
+    null_segment: GdtSegmentDescriptor,
+    kernel_code_segment: GdtSegmentDescriptor,
+    kernel_data_segment: GdtSegmentDescriptor,
+    task_state_segment: TaskStateSegment64Descriptor,
+}
+const _: () = assert!(size_of::<Gdt>() == 40);
+
+// なぜかWrapしている
+// PinだからGDTもアドレス固定？
+// tssも持っている
+// おそらくだけどWrapper経由でGDTを操作するのかな
+#[allow(dead_code)]
+pub struct GdtWrapper {
+    inner: Pin<Box<Gdt>>,
+    tss64: TaskStateSegment64,
+}
+
+impl GdtWrapper {
+    // lgdt(load GDT)でgdtを取得してparamsに入れてる？？
+    // ltrってなんだ？
+    pub fn load(&self) {
+        let params = GdtrParameters {
+            limit: (size_of::<Gdt>() - 1) as u16,
+            base: self.inner.as_ref().get_ref() as *const Gdt,
+        };
+        info!("Loading GDT @ {:#018X}", params.base as u64);
+        // SAFETY: This is safe since it is loading a valid GDT just constructed
+        // in the above
+        unsafe {
+            asm!("lgdt [rcx]",
+                in("rcx") &params);
+        }
+        info!("Loading TSS ( selector = {:#X} )", TSS64_SEL);
+        unsafe {
+            asm!("ltr cx",
+                in("cx") TSS64_SEL);
+        }
+    }
+}
+
+// defaultトレイト実装でdefault()でGDTが作られるようになった
+impl Default for GdtWrapper {
+    fn default() -> Self {
+        let tss64 = TaskStateSegment64::new();
+        let gdt = Gdt {
+            null_segment: GdtSegmentDescriptor::null(),
+            kernel_code_segment: GdtSegmentDescriptor::new(GdtAttr::KernelCode),
+            kernel_data_segment: GdtSegmentDescriptor::new(GdtAttr::KernelData),
+            task_state_segment: TaskStateSegment64Descriptor::new(tss64.phys_addr()),
+        };
+        let gdt = Box::pin(gdt);
+        GdtWrapper { inner: gdt, tss64 }
+    }
+}
+
+// GDTの識別子
+pub struct GdtSegmentDescriptor {
+    value: u64,
+}
+impl GdtSegmentDescriptor {
+    // nullだと0番
+    const fn null() -> Self {
+        Self { value: 0 }
+    }
+    // それ以外だとGddAttrに依存
+    const fn new(attr: GdtAttr) -> Self {
+        Self { value: attr as u64 }
+    }
+}
+// 出力フォーマット
+impl fmt::Display for GdtSegmentDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#18X}", self.value)
+    }
+}
+
+// TSS構造体
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct TaskStateSegment64Descriptor {
+    limit_low: u16,
+    base_low: u16,
+    base_mid_low: u8,
+    attr: u16,
+    base_mid_high: u8,
+    base_high: u32,
+    reserved: u32,
+}
+
+impl TaskStateSegment64Descriptor {
+    // TSSの作成
+    const fn new(base_addr: u64) -> Self {
+        Self {
+            limit_low: size_of::<TaskStateSegment64Inner>() as u16,
+            base_low: (base_addr & 0xffff) as u16,
+            base_mid_low: ((base_addr >> 16) & 0xff) as u8,
+            attr: 0b1000_0000_1000_1001,
+            base_mid_high: ((base_addr >> 24) & 0xff) as u8,
+            base_high: ((base_addr >> 32) & 0xffffffff) as u32,
+            reserved: 0,
+        }
+    }
+}
+const _: () = assert!(size_of::<TaskStateSegment64Descriptor>() == 16);
+// 利用可能な64bit TSS（type = 0b1001）でPresentビットが立っていることを確認する
+const _: () = assert!(TaskStateSegment64Descriptor::new(0).attr & 0xff == 0x89);
+
+// 初期化したGDTの置き場所
+// ファームウェアのGDTはいつ再利用されるかわからないので、自前のものをstaticに保持し続ける
+static GDT: Mutex<Option<GdtWrapper>> = Mutex::new(None);
+
+// GDTの初期化
+// 自前のGDTとTSSを読み込み、cs,ss,es,ds,fs,gsを自前のセグメントに切り替える
+pub fn init_gdt() {
+    let gdt = GdtWrapper::default();
+    gdt.load();
+    load_kernel_segments();
+    *GDT.lock() = Some(gdt);
+}
+
+// cs,ss,es,ds,fs,gsを自前のGDTのセグメントに切り替える
+fn load_kernel_segments() {
+    unsafe {
+        write_cs(KERNEL_CS);
+        write_ss(KERNEL_DS);
+        write_es(KERNEL_DS);
+        write_ds(KERNEL_DS);
+        write_fs(KERNEL_DS);
+        write_gs(KERNEL_DS);
+    }
+}
+
+// APが使うGDTとTSSを作って読み込む
+// ISTのスタックは同時に使われないように、APごとにガードページ付きのものを持つ
+// APは止まらないので、作ったGDTとTSSは解放しない
+pub fn init_ap_gdt() -> Result<()> {
+    let mut gdt = GdtWrapper::default();
+    gdt.tss64.use_guarded_stacks()?;
+    gdt.load();
+    load_kernel_segments();
+    Box::leak(Box::new(gdt));
+    Ok(())
+}
+
+// 割り込みのスタックを、ガードページ付きのスタックに差し替える
+// ガードページのマップを外すので、自前のページテーブルに切り替えた後、割り込みを許可する前に呼ぶ
+pub fn init_guarded_interrupt_stacks() -> Result<()> {
+    GDT.lock()
+        .as_mut()
+        .ok_or("GDT is not initialized")?
+        .tss64
+        .use_guarded_stacks()
+}
+
+// 現在読み込まれているGDTの先頭アドレス
+pub fn read_gdtr_base() -> u64 {
+    let mut params = GdtrParameters {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    unsafe {
+        asm!("sgdt [rcx]",
+            in("rcx") &mut params);
+    }
+    params.base as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::read_cs;
+    use crate::x86::read_ss;
+
+    // init_gdt()の後はCS/SSが自前のGDTのセグメントを指していることを確認する
+    #[test_case]
+    fn segments_point_into_our_gdt() {
+        assert_eq!(read_cs(), KERNEL_CS);
+        assert_eq!(read_ss(), KERNEL_DS);
+        let base = GDT
+            .lock()
+            .as_ref()
+            .map(|gdt| gdt.inner.as_ref().get_ref() as *const Gdt as u64)
+            .expect("GDT is not initialized");
+        assert_eq!(read_gdtr_base(), base);
+    }
+    // 割り込みのスタックのすぐ下はガードページになっている
+    #[test_case]
+    fn interrupt_stacks_have_guard_pages() {
+        let gdt = GDT.lock();
+        let tss = &gdt.as_ref().expect("GDT is not initialized").tss64.inner;
+        let (rsp, ist) = (tss._rsp, tss._ist);
+        for top in core::iter::once(rsp[0]).chain(ist[1..].iter().copied()) {
+            let bottom = top - HANDLER_STACK_SIZE as u64;
+            assert!(crate::paging::is_mapped(bottom));
+            assert!(crate::stack::is_guard_page(bottom - 1));
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::result::Result;
 use core::cmp::min;
-use spin::Mutex;
+use core::slice;
 
 pub trait Bitmap {
     fn bytes_per_pixel(&self) -> i64;
@@ -8,6 +8,13 @@ pub trait Bitmap {
     fn width(&self) -> i64;
     fn height(&self) -> i64;
     fn buf_mut(&mut self) -> *mut u8;
+    fn buf(&self) -> *const u8;
+
+    // 0xRRGGBBの色を、このビットマップに書き込むピクセルの値に変換する
+    // 既定ではそのまま書く（メモリ上でB, G, Rの順に並ぶ）
+    fn pixel_value(&self, color: u32) -> u32 {
+        color
+    }
 
     // 指定した座標のピクセルへの可変ポインタを返す（範囲チェックなし）
     /// # Safety
@@ -17,6 +24,36 @@ pub trait Bitmap {
             as *mut u32
     }
 
+    // 指定した座標のピクセルの値を返す（範囲チェックなし）
+    // VRAMからの読み出しは書き込みよりずっと遅い（キャッシュされない）が、読むことはできる
+    /// # Safety
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> u32 {
+        *(self
+            .buf()
+            .add(((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize)
+            as *const u32)
+    }
+
+    fn pixel_at(&self, x: i64, y: i64) -> Option<u32> {
+        if self.is_in_x_range(x) && self.is_in_y_range(y) {
+            unsafe { Some(self.unchecked_pixel_at(x, y)) }
+        } else {
+            None
+        }
+    }
+
+    // y行目の見えている部分のピクセル（1ピクセル4バイトでなければNone）
+    fn row(&self, y: i64) -> Option<&[u32]> {
+        if !self.is_in_y_range(y) || self.bytes_per_pixel() != 4 {
+            return None;
+        }
+        let width = min(self.width(), self.pixels_per_line()).max(0) as usize;
+        unsafe {
+            let start = self.buf().add((y * self.pixels_per_line() * 4) as usize);
+            Some(slice::from_raw_parts(start as *const u32, width))
+        }
+    }
+
     fn pixel_at_mut(&mut self, x: i64, y: i64) -> Option<&mut u32> {
         // 範囲チェックを行う
         if self.is_in_x_range(x) && self.is_in_y_range(y) {
@@ -39,15 +76,17 @@ pub trait Bitmap {
     }
 }
 
-unsafe fn unchecked_draw_point<T: Bitmap>(buf: &mut T, color: u32, x: i64, y: i64) {
-    *buf.unchecked_pixel_at_mut(x, y) = color;
-}
-
 fn draw_point<T: Bitmap>(buf: &mut T, color: u32, x: i64, y: i64) -> Result<()> {
-    *(buf.pixel_at_mut(x, y).ok_or("Out of Range")?) = color;
+    let value = buf.pixel_value(color);
+    *(buf.pixel_at_mut(x, y).ok_or("Out of Range")?) = value;
     Ok(())
 }
 
+// (px, py)を左上とする幅w、高さhの矩形を塗りつぶす
+// - 幅か高さが0なら、位置に関係なく何も描かずにOk
+// - 幅か高さが負ならErr("negative size")（0より先に調べる）
+// - 右端か下端の座標がi64に収まらなければErr("size overflow")
+// - それ以外で描くピクセルが1つでも範囲外なら、何も描かずにErr("Out of Range")
 pub fn fill_rect<T: Bitmap>(
     buf: &mut T,
     color: u32,
@@ -56,17 +95,27 @@ pub fn fill_rect<T: Bitmap>(
     w: i64,
     h: i64,
 ) -> Result<()> {
+    if w < 0 || h < 0 {
+        return Err("negative size");
+    }
+    if w == 0 || h == 0 {
+        return Ok(());
+    }
+    let (Some(x_end), Some(y_end)) = (px.checked_add(w), py.checked_add(h)) else {
+        return Err("size overflow");
+    };
     if !buf.is_in_x_range(px)
         || !buf.is_in_y_range(py)
-        || !buf.is_in_x_range(px + w - 1)
-        || !buf.is_in_y_range(py + h - 1)
+        || !buf.is_in_x_range(x_end - 1)
+        || !buf.is_in_y_range(y_end - 1)
     {
         return Err("Out of Range");
     }
-    for y in py..py + h {
-        for x in px..px + w {
+    let value = buf.pixel_value(color);
+    for y in py..y_end {
+        for x in px..x_end {
             unsafe {
-                unchecked_draw_point(buf, color, x, y);
+                *buf.unchecked_pixel_at_mut(x, y) = value;
             }
         }
     }
@@ -85,7 +134,14 @@ fn calc_slope_point(da: i64, db: i64, ia: i64) -> Option<i64> {
     }
 }
 
-fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1: i64) -> Result<()> {
+pub fn draw_line<T: Bitmap>(
+    buf: &mut T,
+    color: u32,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+) -> Result<()> {
     if !buf.is_in_x_range(x0)
         || !buf.is_in_x_range(x1)
         || !buf.is_in_y_range(y0)
@@ -111,70 +167,162 @@ fn draw_line<T: Bitmap>(buf: &mut T, color: u32, x0: i64, y0: i64, x1: i64, y1:
     Ok(())
 }
 
-fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
-    // コンパイル時にフォントファイルをバイナリに埋め込む
-    const FONT_SOURCE: &str = include_str!("./font.txt");
-    // フォントキャッシュ
-    // 全部で256文字
-    // [[char; 8]; 16]: 一文字分のフォント
-    // 誰でもstatic mutを書き換えることができるのでスレッド安全なMutexを使用
-    // staticなのでバイナリデータの一部として格納されているので一度つくられたら再度作られない
-    static FONT_CACHE: Mutex<Option<[[[char; 8]; 16]; 256]>> = Mutex::new(None);
-
-    // 文字をu8に変換
-    if let Ok(c) = u8::try_from(c) {
-        // Mutexをロックして中身を取得
-        let mut cache = FONT_CACHE.lock();
-
-        // cacheからすでにフォントがあれば配列から取得できる
-        let font = cache.get_or_insert_with(|| {
-            let mut font = [[['*'; 8]; 16]; 256];
-            let mut fi = FONT_SOURCE.split('\n');
-            while let Some(line) = fi.next() {
-                // "0x"を切り取ってからスタート
-                if let Some(line) = line.strip_prefix("0x") {
-                    // 16進数を10進数変換
-                    if let Ok(idx) = u8::from_str_radix(line, 16) {
-                        // フォントの初期化
-                        let mut glyph = [['*'; 8]; 16];
-
-                        // １行ずつ取得
-                        for glypy_y in glyph.iter_mut() {
-                            if let Some(font_line) = fi.next() {
-                                // 1文字ずつ取得
-                                for (x, c) in font_line.chars().enumerate() {
-                                    if let Some(e) = glypy_y.get_mut(x) {
-                                        *e = c;
-                                    }
-                                }
-                            }
-                        }
-                        font[idx as usize] = glyph;
+// 1文字分のフォント（8x16ドット、各行の最上位ビットが左端）
+type Glyph = [u8; 16];
+pub const GLYPH_WIDTH: usize = 8;
+pub const GLYPH_HEIGHT: usize = 16;
+// フォントがない文字は、豆腐（中が空の四角）で表示する
+const FALLBACK_GLYPH: Glyph = [
+    0x00, 0x00, 0xfe, 0x82, 0x82, 0x82, 0x82, 0x82, 0x82, 0x82, 0x82, 0x82, 0x82, 0xfe, 0x00, 0x00,
+];
+// U+0100以降のグリフを入れられる数
+const MAX_EXTRA_GLYPHS: usize = 64;
+
+// U+00FFまでは配列で直接引き、それより後ろはコードポイントの順に並べて二分探索する
+// 罫線や矢印はU+2500などに散らばっているので、0x10000要素の配列にはしない
+struct FontTable {
+    latin1: [Glyph; 256],
+    extra: [(u32, Glyph); MAX_EXTRA_GLYPHS],
+    num_extra: usize,
+}
+impl FontTable {
+    fn lookup(&self, c: char) -> &Glyph {
+        let cp = c as u32;
+        if cp < 0x100 {
+            return &self.latin1[cp as usize];
+        }
+        let extra = &self.extra[..self.num_extra];
+        match extra.binary_search_by_key(&cp, |(cp, _)| *cp) {
+            Ok(i) => &extra[i].1,
+            Err(_) => &FALLBACK_GLYPH,
+        }
+    }
+
+    // 並び順を保ったまま追加する（同じコードポイントは後のもので上書きする）
+    const fn insert(&mut self, cp: u32, glyph: Glyph) {
+        if cp < 0x100 {
+            self.latin1[cp as usize] = glyph;
+            return;
+        }
+        let mut i = 0;
+        while i < self.num_extra && self.extra[i].0 < cp {
+            i += 1;
+        }
+        if i < self.num_extra && self.extra[i].0 == cp {
+            self.extra[i].1 = glyph;
+            return;
+        }
+        assert!(
+            self.num_extra < MAX_EXTRA_GLYPHS,
+            "font.txt has too many glyphs above U+00FF"
+        );
+        let mut j = self.num_extra;
+        while j > i {
+            self.extra[j] = self.extra[j - 1];
+            j -= 1;
+        }
+        self.extra[i] = (cp, glyph);
+        self.num_extra += 1;
+    }
+}
+
+// posから始まる行の終わり（'\n'の位置か、末尾）
+const fn line_end(s: &[u8], pos: usize) -> usize {
+    let mut i = pos;
+    while i < s.len() && s[i] != b'\n' {
+        i += 1;
+    }
+    i
+}
+
+// "0x"の後ろの16進数を読む（"0x41"や"0x2500"）
+const fn parse_code_point(s: &[u8], start: usize, end: usize) -> u32 {
+    assert!(start < end, "font.txt: missing code point after 0x");
+    let mut cp: u32 = 0;
+    let mut i = start;
+    while i < end {
+        let d = match s[i] {
+            b'0'..=b'9' => s[i] - b'0',
+            b'a'..=b'f' => s[i] - b'a' + 10,
+            b'A'..=b'F' => s[i] - b'A' + 10,
+            _ => panic!("font.txt: invalid code point"),
+        };
+        assert!(cp <= 0x10ffff, "font.txt: code point is too large");
+        cp = cp * 16 + d as u32;
+        i += 1;
+    }
+    assert!(
+        char::from_u32(cp).is_some(),
+        "font.txt: not a valid code point"
+    );
+    cp
+}
+
+// font.txtは"0x<コードポイント>"の行の後に、'*'が点を表す8文字x16行が続く形式
+// コンパイル時に読むので、形式がおかしければビルドが失敗する
+const fn parse_font(src: &str) -> FontTable {
+    let s = src.as_bytes();
+    let mut table = FontTable {
+        latin1: [FALLBACK_GLYPH; 256],
+        extra: [(0, FALLBACK_GLYPH); MAX_EXTRA_GLYPHS],
+        num_extra: 0,
+    };
+    let mut pos = 0;
+    while pos < s.len() {
+        let end = line_end(s, pos);
+        pos = if end - pos >= 2 && s[pos] == b'0' && s[pos + 1] == b'x' {
+            let cp = parse_code_point(s, pos + 2, end);
+            let mut glyph = [0u8; GLYPH_HEIGHT];
+            let mut row = end + 1;
+            let mut y = 0;
+            while y < GLYPH_HEIGHT && row < s.len() {
+                let row_end = line_end(s, row);
+                let mut x = 0;
+                while x < GLYPH_WIDTH && row + x < row_end {
+                    if s[row + x] == b'*' {
+                        glyph[y] |= 0x80 >> x;
                     }
+                    x += 1;
                 }
+                y += 1;
+                row = row_end + 1;
             }
-            font
-        });
-        Some(font[c as usize])
-    } else {
-        None
+            table.insert(cp, glyph);
+            row
+        } else {
+            end + 1
+        };
     }
+    table
+}
+
+// コンパイル時にフォントファイルをバイナリに埋め込んで、表にしておく
+static FONT: FontTable = parse_font(include_str!("./font.txt"));
+
+fn lookup_font(c: char) -> &'static Glyph {
+    FONT.lookup(c)
 }
 
 pub fn draw_font_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, c: char) {
-    if let Some(font) = lookup_font(c) {
-        for (dy, row) in font.iter().enumerate() {
-            for (dx, pixel) in row.iter().enumerate() {
-                let color = match pixel {
-                    '*' => color,
-                    _ => continue,
-                };
+    for (dy, row) in lookup_font(c).iter().enumerate() {
+        for dx in 0..GLYPH_WIDTH {
+            if row & (0x80 >> dx) != 0 {
                 let _ = draw_point(buf, color, x + dx as i64, y + dy as i64);
             }
         }
     }
 }
 
+// 背景も含めて1文字分を全て塗る（前に描いてあった文字を消さずに上書きできる）
+pub fn draw_font_fg_bg<T: Bitmap>(buf: &mut T, x: i64, y: i64, fg: u32, bg: u32, c: char) {
+    for (dy, row) in lookup_font(c).iter().enumerate() {
+        for dx in 0..GLYPH_WIDTH {
+            let color = if row & (0x80 >> dx) != 0 { fg } else { bg };
+            let _ = draw_point(buf, color, x + dx as i64, y + dy as i64);
+        }
+    }
+}
+
 // 文字列の入力を描く
 pub fn draw_str_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, s: &str) {
     for (i, c) in s.chars().enumerate() {
@@ -201,3 +349,210 @@ pub fn draw_test_pattern<T: Bitmap>(buf: &mut T) {
     draw_str_fg(buf, left, h * colors.len() as i64, 0x00ff00, "0123456789");
     draw_str_fg(buf, left, h * colors.len() as i64 + 16, 0x00ff00, "ABCDEF");
 }
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use crate::graphics_tests::OwnedBitmap;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::hint::black_box;
+
+    #[test_case]
+    fn pixels_can_be_read_back() {
+        let (w, h) = (7, 5);
+        let mut bitmap = OwnedBitmap::new(w, h);
+        let color = |x: i64, y: i64| (y * 0x100 + x) as u32 + 1;
+        for y in 0..h {
+            for x in 0..w {
+                draw_point(&mut bitmap, color(x, y), x, y).unwrap();
+            }
+        }
+        for y in 0..h {
+            for x in 0..w {
+                assert_eq!(bitmap.pixel_at(x, y), Some(color(x, y)), "({x}, {y})");
+            }
+            let row = bitmap.row(y).unwrap();
+            assert_eq!(row.len(), w as usize);
+            assert!(row
+                .iter()
+                .enumerate()
+                .all(|(x, p)| *p == color(x as i64, y)));
+        }
+        for (x, y) in [
+            (-1, 0),
+            (0, -1),
+            (w, 0),
+            (0, h),
+            (w, h),
+            (i64::MIN, i64::MAX),
+        ] {
+            assert_eq!(bitmap.pixel_at(x, y), None, "({x}, {y})");
+        }
+        assert!(bitmap.row(-1).is_none());
+        assert!(bitmap.row(h).is_none());
+    }
+
+    // 塗られたピクセル（0以外）の数
+    fn painted(bitmap: &OwnedBitmap) -> usize {
+        (0..bitmap.height())
+            .flat_map(|y| bitmap.row(y).unwrap())
+            .filter(|p| **p != 0)
+            .count()
+    }
+
+    #[test_case]
+    fn fill_rect_handles_sizes_consistently() {
+        const OUT: Result<()> = Err("Out of Range");
+        const NEG: Result<()> = Err("negative size");
+        const OVF: Result<()> = Err("size overflow");
+        // (x, y, w, h, 結果, 塗られるピクセル数)
+        let cases = [
+            // 四隅と内側
+            (0, 0, 1, 1, Ok(()), 1),
+            (7, 0, 1, 1, Ok(()), 1),
+            (0, 7, 1, 1, Ok(()), 1),
+            (7, 7, 1, 1, Ok(()), 1),
+            (3, 3, 2, 3, Ok(()), 6),
+            (0, 0, 8, 8, Ok(()), 64),
+            // 各辺をまたぐ
+            (-1, 3, 2, 1, OUT, 0),
+            (7, 3, 2, 1, OUT, 0),
+            (3, -1, 1, 2, OUT, 0),
+            (3, 7, 1, 2, OUT, 0),
+            (-1, -1, 10, 10, OUT, 0),
+            // 大きさが0なら、範囲外でも何もせずに成功する
+            (0, 0, 0, 0, Ok(()), 0),
+            (8, 8, 0, 0, Ok(()), 0),
+            (-1, -1, 0, 5, Ok(()), 0),
+            (7, 7, 5, 0, Ok(()), 0),
+            (100, 3, 0, 1, Ok(()), 0),
+            (3, -100, 1, 0, Ok(()), 0),
+            // 負の大きさは位置に関係なく失敗する
+            (3, 3, -1, 1, NEG, 0),
+            (3, 3, 1, -1, NEG, 0),
+            (0, 0, -1, 0, NEG, 0),
+            (8, 8, 0, -1, NEG, 0),
+            (-1, -1, -1, -1, NEG, 0),
+            // 右端や下端がi64に収まらない
+            (7, 0, i64::MAX, 1, OVF, 0),
+            (0, 7, 1, i64::MAX, OVF, 0),
+            (i64::MAX, i64::MAX, i64::MAX, i64::MAX, OVF, 0),
+            (-1, 0, i64::MAX, 1, OUT, 0),
+        ];
+        for (x, y, w, h, result, pixels) in cases {
+            let mut bitmap = OwnedBitmap::new(8, 8);
+            assert_eq!(
+                fill_rect(&mut bitmap, 0xffffff, x, y, w, h),
+                result,
+                "({x}, {y}, {w}, {h})"
+            );
+            assert_eq!(painted(&bitmap), pixels, "({x}, {y}, {w}, {h})");
+        }
+        // 四隅で幅と高さが-1, 0, 1の全ての組み合わせ
+        for (x, y) in [(0, 0), (7, 0), (0, 7), (7, 7)] {
+            for (w, h) in [-1, 0, 1]
+                .into_iter()
+                .flat_map(|w| [-1, 0, 1].map(|h| (w, h)))
+            {
+                let (result, pixels) = match (w, h) {
+                    _ if w < 0 || h < 0 => (NEG, 0),
+                    _ if w == 0 || h == 0 => (Ok(()), 0),
+                    _ => (Ok(()), 1),
+                };
+                let mut bitmap = OwnedBitmap::new(8, 8);
+                assert_eq!(
+                    fill_rect(&mut bitmap, 0xffffff, x, y, w, h),
+                    result,
+                    "({x}, {y}, {w}, {h})"
+                );
+                assert_eq!(painted(&bitmap), pixels, "({x}, {y}, {w}, {h})");
+            }
+        }
+    }
+
+    // 描いた文字列を1文字ずつ切り出す（点があればtrue）
+    fn rendered_cells(s: &str) -> Vec<Vec<bool>> {
+        let n = s.chars().count() as i64;
+        let mut bitmap = OwnedBitmap::new(n * 8, 16);
+        draw_str_fg(&mut bitmap, 0, 0, 0xffffff, s);
+        (0..n)
+            .map(|i| {
+                (0..16)
+                    .flat_map(|y| (0..8).map(move |x| (x, y)))
+                    .map(|(x, y)| bitmap.pixel_at(i * 8 + x, y) != Some(0))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn box_drawing_glyphs_are_rendered() {
+        let cells = rendered_cells("┌─┐");
+        for cell in &cells {
+            assert!(cell.iter().any(|p| *p));
+        }
+        assert_ne!(cells[0], cells[1]);
+        assert_ne!(cells[0], cells[2]);
+        assert_ne!(cells[1], cells[2]);
+    }
+    #[test_case]
+    fn glyphs_above_latin1_are_found() {
+        let chars = "─│┌┐└┘├┤┬┴┼█░▒▓←↑→↓";
+        for c in chars.chars() {
+            assert_ne!(lookup_font(c), &FALLBACK_GLYPH, "{c:?} has no glyph");
+        }
+        // 全て違う形をしている
+        for (i, a) in chars.chars().enumerate() {
+            for b in chars.chars().skip(i + 1) {
+                assert_ne!(lookup_font(a), lookup_font(b), "{a:?} and {b:?}");
+            }
+        }
+        assert_eq!(lookup_font('A'), &FONT.latin1[0x41]);
+    }
+    #[test_case]
+    fn unknown_code_points_use_the_fallback_glyph() {
+        for c in ['\u{100}', '\u{24ff}', '\u{2501}', 'あ', '\u{10ffff}'] {
+            assert_eq!(lookup_font(c), &FALLBACK_GLYPH, "{c:?}");
+        }
+        assert_eq!(rendered_cells("あ"), rendered_cells("\u{10ffff}"));
+    }
+    #[test_case]
+    fn font_table_keeps_extra_glyphs_sorted() {
+        // 1行目だけに点がある、16行のグリフ
+        fn entry(cp: u32, first_row: &str) -> String {
+            format!("0x{cp:x}\n{first_row}\n{}\n", ["........"; 15].join("\n"))
+        }
+        let src: String = [
+            entry(0x2593, "*......."),
+            entry(0x100, ".*......"),
+            entry(0x41, "..*....."),
+            entry(0x2500, "...*...."),
+            entry(0x100, "....*..."),
+        ]
+        .concat();
+        let mut table = parse_font(&src);
+        assert_eq!(table.num_extra, 3);
+        let cps: Vec<u32> = table.extra[..3].iter().map(|(cp, _)| *cp).collect();
+        assert_eq!(cps, [0x100, 0x2500, 0x2593]);
+        // 同じコードポイントは後のもので上書きされる
+        assert_eq!(table.lookup('\u{100}')[0], 0x08);
+        assert_eq!(table.lookup('A')[0], 0x20);
+        assert_eq!(table.lookup('\u{2593}')[..2], [0x80, 0x00]);
+        table.insert(0x2501, [0xff; 16]);
+        assert_eq!(table.lookup('\u{2501}'), &[0xff; 16]);
+        assert_eq!(table.lookup('\u{2502}'), &FALLBACK_GLYPH);
+    }
+
+    crate::kernel_bench!(fill_rect_1080p, {
+        let mut bitmap = OwnedBitmap::new(1920, 1080);
+        let mut color = 0;
+        move || {
+            color += 1;
+            fill_rect(black_box(&mut bitmap), color, 0, 0, 1920, 1080).expect("fill_rect failed");
+        }
+    });
+}
@@ -0,0 +1,240 @@
+extern crate alloc;
+
+use crate::crc32::crc32_update;
+use crate::graphics::draw_font_fg;
+use crate::graphics::draw_line;
+use crate::graphics::draw_test_pattern;
+use crate::graphics::fill_rect;
+use crate::graphics::Bitmap;
+use crate::graphics::GLYPH_HEIGHT;
+use crate::graphics::GLYPH_WIDTH;
+use crate::print::hexdump_slice;
+use crate::println;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// 決まった絵をメモリ上に描いて、ピクセルのCRC32を記録しておいた値と比べるテスト
+// 描画の仕方が少しでも変わると値が変わるので、意図した変更なら失敗時に表示される値で更新する
+// 時刻や乱数など、実行ごとに変わるものは描かない
+//
+// どこが変わったかを調べやすいように、画像全体のCRCに加えてGLYPH_HEIGHT行ごとのCRCも持つ
+// golden_debugフィーチャを有効にすると、最初に食い違った帯の行を16進ダンプする
+
+// VRAMの代わりにメモリ上に描くビットマップ（黒で初期化される）
+// 描画を確かめる他のモジュールのテストもこれを使う
+pub struct OwnedBitmap {
+    buf: Vec<u32>,
+    width: i64,
+    height: i64,
+}
+impl OwnedBitmap {
+    pub fn new(width: i64, height: i64) -> Self {
+        Self {
+            buf: vec![0; (width * height) as usize],
+            width,
+            height,
+        }
+    }
+    // 全てのピクセル（上の行から順に）
+    pub fn pixels(&self) -> &[u32] {
+        &self.buf
+    }
+    fn row_bytes(&self, y: i64) -> Vec<u8> {
+        self.row(y)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|p| p.to_le_bytes())
+            .collect()
+    }
+    // 各ピクセルをリトルエンディアンの4バイトとして、上の行から順に並べたもののCRC
+    fn crc_of_rows(&self, rows: core::ops::Range<i64>) -> u32 {
+        rows.fold(0, |crc, y| crc32_update(crc, &self.row_bytes(y)))
+    }
+    pub fn crc(&self) -> u32 {
+        self.crc_of_rows(0..self.height)
+    }
+    // GLYPH_HEIGHT行ずつに分けたときの、それぞれの帯のCRC
+    pub fn band_crcs(&self) -> Vec<u32> {
+        (0..self.height)
+            .step_by(GLYPH_HEIGHT)
+            .map(|y| self.crc_of_rows(y..(y + GLYPH_HEIGHT as i64).min(self.height)))
+            .collect()
+    }
+}
+impl Bitmap for OwnedBitmap {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr() as *mut u8
+    }
+    fn buf(&self) -> *const u8 {
+        self.buf.as_ptr() as *const u8
+    }
+}
+
+const COLORS: [u32; 6] = [0xffffff, 0xff0000, 0x00ff00, 0x0000ff, 0xffff00, 0x00ffff];
+
+// draw_test_patternが右端に描く絵（幅128の図形の下に2行の文字）がちょうど収まる大きさ
+pub fn scene_test_pattern() -> OwnedBitmap {
+    let mut bitmap = OwnedBitmap::new(320, 288);
+    draw_test_pattern(&mut bitmap);
+    bitmap
+}
+
+// 表示できるASCII文字（0x20から0x7eまで）を16文字ずつ並べる
+pub fn scene_ascii_text() -> OwnedBitmap {
+    const COLS: i64 = 16;
+    let mut bitmap = OwnedBitmap::new(COLS * GLYPH_WIDTH as i64, 6 * GLYPH_HEIGHT as i64);
+    for (i, c) in (' '..='~').enumerate() {
+        let i = i as i64;
+        let x = i % COLS * GLYPH_WIDTH as i64;
+        let y = i / COLS * GLYPH_HEIGHT as i64;
+        draw_font_fg(&mut bitmap, x, y, COLORS[(i / COLS) as usize], c);
+    }
+    bitmap
+}
+
+// 中心から外周の点へ、いろいろな傾きの線を引く
+pub fn scene_line_fan() -> OwnedBitmap {
+    const SIZE: i64 = 160;
+    let mut bitmap = OwnedBitmap::new(SIZE + 1, SIZE + 1);
+    let c = SIZE / 2;
+    for (i, t) in (0..=SIZE).step_by(8).enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        for (x, y) in [(t, 0), (SIZE, t), (SIZE - t, SIZE), (0, SIZE - t)] {
+            draw_line(&mut bitmap, color, c, c, x, y).expect("draw_line failed");
+        }
+    }
+    bitmap
+}
+
+// 塗りつぶした四角と枠だけの四角を交互に入れ子にする
+pub fn scene_nested_rects() -> OwnedBitmap {
+    const SIZE: i64 = 128;
+    let mut bitmap = OwnedBitmap::new(SIZE, SIZE);
+    for i in 0..8 {
+        let inset = i * 8;
+        let size = SIZE - inset * 2;
+        let color = COLORS[i as usize % COLORS.len()];
+        if i % 2 == 0 {
+            fill_rect(&mut bitmap, color, inset, inset, size, size).expect("fill_rect failed");
+        } else {
+            let far = inset + size - 1;
+            for (x, y, w, h) in [
+                (inset, inset, size, 1),
+                (inset, far, size, 1),
+                (inset, inset, 1, size),
+                (far, inset, 1, size),
+            ] {
+                fill_rect(&mut bitmap, color, x, y, w, h).expect("fill_rect failed");
+            }
+        }
+    }
+    bitmap
+}
+
+// 記録しておいた描画結果
+struct Golden {
+    crc: u32,
+    bands: &'static [u32],
+}
+
+// 食い違ったら、実際の値をそのまま貼り付けられる形で表示してからpanicする
+fn check_golden(name: &str, bitmap: &OwnedBitmap, golden: &Golden) {
+    let crc = bitmap.crc();
+    if crc == golden.crc {
+        return;
+    }
+    let bands = bitmap.band_crcs();
+    println!(
+        "golden mismatch in {name}: expected {:#010x}, actual {crc:#010x}",
+        golden.crc
+    );
+    println!("const {}: Golden = Golden {{", name.to_uppercase());
+    println!("    crc: {crc:#010x},");
+    println!("    bands: &{bands:#010x?},");
+    println!("}};");
+    let first_diff = bands
+        .iter()
+        .zip(golden.bands)
+        .position(|(actual, expected)| actual != expected)
+        .unwrap_or(bands.len().min(golden.bands.len()));
+    let first_row = (first_diff * GLYPH_HEIGHT) as i64;
+    println!("first differing band starts at row {first_row}");
+    if cfg!(feature = "golden_debug") {
+        let rows = first_row..(first_row + GLYPH_HEIGHT as i64).min(bitmap.height);
+        for y in rows {
+            println!("row {y}:");
+            let bytes = bitmap.row_bytes(y);
+            hexdump_slice(0, &bytes);
+        }
+    }
+    panic!("{name} does not match the golden image");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_PATTERN: Golden = Golden {
+        crc: 0x7cb789d4,
+        bands: &[
+            0x0f41d346, 0xffd346f1, 0x062cbe23, 0xff70eab7, 0x222749fb, 0x13aeda99, 0x863a3a84,
+            0x4b323a34, 0x5e01aad9, 0x6c4575fc, 0x6c4575fc, 0x6c4575fc, 0x3af02420, 0x3af02420,
+            0x3af02420, 0x3af02420, 0x5f8aad8b, 0xb7a81453,
+        ],
+    };
+    const ASCII_TEXT: Golden = Golden {
+        crc: 0x00e0db72,
+        bands: &[
+            0x921c8622, 0x465443fd, 0xbe115b2c, 0xdf74e4f7, 0x52f8352d, 0x100abc92,
+        ],
+    };
+    const LINE_FAN: Golden = Golden {
+        crc: 0x2a293b96,
+        bands: &[
+            0x591b5fca, 0x2d6381de, 0xd7c34c0d, 0x8bdb8a17, 0x31ad5507, 0xc83c4360, 0xb2ea1872,
+            0x8c9b25d3, 0x0bd0ccd5, 0x1cd5a324, 0xf1762e0a,
+        ],
+    };
+    const NESTED_RECTS: Golden = Golden {
+        crc: 0xee173601,
+        bands: &[
+            0x43cdb454, 0x7fd0073a, 0xf4b139c0, 0xbaa5995c, 0x1f7be60c, 0x652f3101, 0x453ba5e8,
+            0x7a258527,
+        ],
+    };
+
+    #[test_case]
+    fn test_pattern_matches_golden() {
+        check_golden("test_pattern", &scene_test_pattern(), &TEST_PATTERN);
+    }
+    #[test_case]
+    fn ascii_text_matches_golden() {
+        check_golden("ascii_text", &scene_ascii_text(), &ASCII_TEXT);
+    }
+    #[test_case]
+    fn line_fan_matches_golden() {
+        check_golden("line_fan", &scene_line_fan(), &LINE_FAN);
+    }
+    #[test_case]
+    fn nested_rects_match_golden() {
+        check_golden("nested_rects", &scene_nested_rects(), &NESTED_RECTS);
+    }
+    // 同じ絵は何度描いても同じになる
+    #[test_case]
+    fn scenes_are_deterministic() {
+        assert_eq!(scene_line_fan().crc(), scene_line_fan().crc());
+        assert_eq!(scene_ascii_text().band_crcs().len(), 6);
+    }
+}
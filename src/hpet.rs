@@ -0,0 +1,225 @@
+use crate::paging::map_mmio;
+use crate::result::Result;
+use crate::tsc::Tsc;
+use crate::x86::busy_loop_hint;
+use core::ptr::read_volatile;
+use core::ptr::write_volatile;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use spin::Once;
+
+// HPET（High Precision Event Timer）
+// 数MHz以上で一定の速さで増え続けるメインカウンタを持つ
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+// レジスタ領域の大きさ（タイマー32個分まで）
+const MMIO_SIZE: u64 = 0x400;
+
+// 立っていればメインカウンタが64bit、いなければ32bit
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+// メインカウンタを動かす
+const CONFIG_ENABLE: u64 = 1 << 0;
+// 仕様ではカウンタの周期は100ns以下
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FS_PER_NS: u128 = 1_000_000;
+
+// HPETのレジスタへのアクセス
+// テストでは実際のMMIOの代わりにモックを使えるようにする
+pub trait HpetRegisters {
+    fn read(&self, offset: usize) -> u64;
+    fn write(&self, offset: usize, value: u64);
+}
+
+pub struct Mmio {
+    base: u64,
+}
+impl HpetRegisters for Mmio {
+    fn read(&self, offset: usize) -> u64 {
+        unsafe { read_volatile((self.base as usize + offset) as *const u64) }
+    }
+    fn write(&self, offset: usize, value: u64) {
+        unsafe { write_volatile((self.base as usize + offset) as *mut u64, value) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    // カウンタが1増えるのにかかる時間（フェムト秒）
+    pub period_fs: u64,
+    pub is_64bit: bool,
+}
+impl HpetInfo {
+    fn from_capabilities(cap: u64) -> Result<Self> {
+        let period_fs = cap >> 32;
+        if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+            return Err("HPET: counter period is out of range");
+        }
+        Ok(Self {
+            period_fs,
+            is_64bit: cap & CAP_COUNTER_64BIT != 0,
+        })
+    }
+
+    pub fn frequency_hz(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        // 掛け算のオーバーフローを避けるためにu128で計算する
+        (ticks as u128 * self.period_fs as u128 / FS_PER_NS) as u64
+    }
+}
+
+// 32bitのカウンタの値を、前回読んだ値（64bitに拡張済み）を使って64bitに拡張する
+// 1周（10MHzで約7分）する間に一度は読まれている必要がある
+fn extend_counter(last: u64, raw: u32) -> u64 {
+    let v = (last & !0xffff_ffff) | raw as u64;
+    if v < last {
+        v + (1 << 32)
+    } else {
+        v
+    }
+}
+
+fn enable(regs: &impl HpetRegisters) -> Result<HpetInfo> {
+    let info = HpetInfo::from_capabilities(regs.read(REG_CAPABILITIES))?;
+    let config = regs.read(REG_CONFIG);
+    regs.write(REG_CONFIG, config | CONFIG_ENABLE);
+    Ok(info)
+}
+
+struct Hpet {
+    regs: Mmio,
+    info: HpetInfo,
+}
+static HPET: Once<Hpet> = Once::new();
+// 32bitのカウンタを拡張するために、最後に読んだ値を覚えておく
+static LAST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// ACPIのHPETテーブルに書かれていたアドレスのHPETを使えるようにする
+// ページングを切り替えた後に呼ぶ（レジスタをキャッシュなしでマップし直す）
+pub fn init(base: u64) -> Result<HpetInfo> {
+    if let Some(hpet) = HPET.get() {
+        return Ok(hpet.info);
+    }
+    map_mmio(base, MMIO_SIZE)?;
+    let regs = Mmio { base };
+    let info = enable(&regs)?;
+    Ok(HPET.call_once(|| Hpet { regs, info }).info)
+}
+
+pub fn info() -> Option<HpetInfo> {
+    HPET.get().map(|hpet| hpet.info)
+}
+
+pub fn is_available() -> bool {
+    HPET.get().is_some()
+}
+
+fn read_counter(hpet: &Hpet) -> u64 {
+    if hpet.info.is_64bit {
+        return hpet.regs.read(REG_MAIN_COUNTER);
+    }
+    // 割り込みで他の読み手が先に進めても戻らないように、カウンタより先に前回の値を読む
+    let last = LAST_COUNTER.load(Ordering::SeqCst);
+    let raw = hpet.regs.read(REG_MAIN_COUNTER) as u32;
+    let v = extend_counter(last, raw);
+    LAST_COUNTER.fetch_max(v, Ordering::SeqCst);
+    v
+}
+
+// HPETから求めた現在時刻（ナノ秒、HPETが使えなければ0）
+pub fn now_ns() -> u64 {
+    HPET.get()
+        .map_or(0, |hpet| hpet.info.ticks_to_ns(read_counter(hpet)))
+}
+
+// 割り込みを使わずに、HPETのカウンタを見ながら指定したミリ秒だけ待つ
+pub fn busy_wait_ms(ms: u64) {
+    let start = now_ns();
+    while now_ns() - start < ms * 1_000_000 {
+        busy_loop_hint();
+    }
+}
+
+// PITよりも精度の高いHPETを使ってTSCをキャリブレーションする
+pub fn calibrate_tsc_against_hpet() -> Option<Tsc> {
+    is_available().then(|| Tsc::calibrate(busy_wait_ms))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timer::sleep_ms;
+    use core::cell::Cell;
+
+    struct MockHpet {
+        cap: u64,
+        config: Cell<u64>,
+    }
+    impl HpetRegisters for MockHpet {
+        fn read(&self, offset: usize) -> u64 {
+            match offset {
+                REG_CAPABILITIES => self.cap,
+                REG_CONFIG => self.config.get(),
+                _ => 0,
+            }
+        }
+        fn write(&self, offset: usize, value: u64) {
+            assert_eq!(offset, REG_CONFIG);
+            self.config.set(value);
+        }
+    }
+
+    #[test_case]
+    fn capabilities_are_decoded() {
+        // QEMUのHPETは100MHz（10ns周期）で64bit
+        let regs = MockHpet {
+            cap: (10_000_000 << 32) | CAP_COUNTER_64BIT | 0x8086_a201,
+            config: Cell::new(0b10),
+        };
+        let info = enable(&regs).unwrap();
+        assert_eq!(info.period_fs, 10_000_000);
+        assert!(info.is_64bit);
+        assert_eq!(info.frequency_hz(), 100_000_000);
+        assert_eq!(info.ticks_to_ns(1_000), 10_000);
+        // 他の設定ビットは残したまま有効にする
+        assert_eq!(regs.config.get(), 0b11);
+        let zero = MockHpet {
+            cap: 0,
+            config: Cell::new(0),
+        };
+        assert!(enable(&zero).is_err());
+        assert_eq!(zero.config.get(), 0);
+    }
+    #[test_case]
+    fn narrow_counter_is_extended_across_wraps() {
+        assert_eq!(extend_counter(0, 5), 5);
+        assert_eq!(extend_counter(0xffff_fff0, 0xffff_ffff), 0xffff_ffff);
+        assert_eq!(extend_counter(0xffff_fff0, 0x10), 0x1_0000_0010);
+        assert_eq!(extend_counter(0x1_0000_0010, 0x20), 0x1_0000_0020);
+        assert_eq!(extend_counter(0x1_ffff_ffff, 0), 0x2_0000_0000);
+    }
+    #[test_case]
+    fn sleep_ms_agrees_with_hpet() {
+        if !is_available() {
+            return;
+        }
+        // ティックの境目に揃えてから測る
+        sleep_ms(1);
+        let start = now_ns();
+        sleep_ms(20);
+        let elapsed = now_ns() - start;
+        assert!(
+            (19_000_000..=21_000_000).contains(&elapsed),
+            "sleep_ms(20) took {elapsed} ns"
+        );
+    }
+    #[test_case]
+    fn tsc_calibration_against_hpet_is_plausible() {
+        if let Some(tsc) = calibrate_tsc_against_hpet() {
+            assert!(tsc.is_plausible());
+        }
+    }
+}
@@ -1,20 +1,474 @@
+extern crate alloc;
+
+use crate::acpi;
+use crate::allocator::heap_size;
+use crate::allocator::heap_start;
+use crate::allocator::FitPolicy;
+use crate::allocator::HeapBackend;
 use crate::allocator::ALLOCATOR;
+use crate::cpuid::cpu_info;
+use crate::gdt::init_gdt;
+use crate::gdt::init_guarded_interrupt_stacks;
+use crate::graphics::Bitmap;
+use crate::hpet;
+use crate::info;
+use crate::kaslr;
+use crate::kaslr::KaslrOffsets;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::init_paging;
+use crate::paging::protect_kernel_image;
+use crate::paging::PageAttr;
+use crate::paging::PAGE_SIZE;
+use crate::pci;
+use crate::pe::PeImage;
+use crate::pic::init_pic;
+use crate::print::init_vram_console;
+use crate::print::register_sink;
+use crate::print::set_log_level;
+use crate::print::vram_console_sink;
+use crate::print::LogLevel;
+use crate::ps2::init_keyboard;
+use crate::ps2::init_mouse;
+use crate::qemu::init_debugcon;
+use crate::reserve;
+use crate::result::Result;
+use crate::rtc;
+use crate::serial::detect_ports;
+use crate::serial::global_port;
+use crate::serial::set_global_port;
+use crate::serial::SerialPort;
+use crate::smp;
+use crate::stack::init_kernel_stack;
+use crate::stack::run_on_stack;
+use crate::timer::init_timer;
+use crate::tsc::init_tsc;
+use crate::tsc::tsc;
+use crate::uefi::decode_ucs2;
 use crate::uefi::exit_from_efi_boot_services;
+use crate::uefi::init_vram;
+use crate::uefi::keep_runtime_services;
+use crate::uefi::locate_loaded_image_protocol;
 use crate::uefi::EfiHandle;
+use crate::uefi::EfiMemoryType;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
+use crate::uefi::MemoryRegions;
+use crate::uefi::ModePreference;
+use crate::uefi::VramBufferInfo;
+use crate::warn;
+use crate::x86::enable_interrupts;
+use crate::x86::init_delay;
+use crate::x86::init_idt;
+use crate::x86::rdtsc;
+use alloc::string::String;
+use core::fmt;
+use core::str::from_utf8;
+
+// コマンドラインを保存しておくバッファの大きさ（ブートサービスを抜ける前はヒープが使えない）
+const COMMAND_LINE_BUFFER_SIZE: usize = 256;
+
+// 起動の各段階に到達したときのTSC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootTimestamps {
+    // init_basic_runtimeを呼んだとき
+    pub start: u64,
+    // ブートサービスを抜けたとき
+    pub exited_boot_services: u64,
+    // init_basic_runtimeが終わったとき
+    pub runtime_ready: u64,
+}
+
+// 起動時にUEFIから受け取った情報
+// UEFIのデータはブートサービスを抜けると使えなくなるものがあるので、init_basic_runtimeでまとめて集める
+pub struct BootInfo {
+    memory_regions: MemoryRegions,
+    vram: VramBufferInfo,
+    rsdp: Option<u64>,
+    command_line: String,
+    timestamps: BootTimestamps,
+    kaslr: KaslrOffsets,
+}
+// VRAMのポインタを持つが、どのCPU・タスクからも同じアドレスで使える
+unsafe impl Send for BootInfo {}
+impl BootInfo {
+    pub fn memory_regions(&self) -> &MemoryRegions {
+        &self.memory_regions
+    }
+    pub fn vram(&self) -> VramBufferInfo {
+        self.vram
+    }
+    // ACPIのRSDPの物理アドレス（ファームウェアが提供していなければNone）
+    pub fn rsdp(&self) -> Option<u64> {
+        self.rsdp
+    }
+    // 起動時に渡された引数（なければ空文字列）
+    pub fn command_line(&self) -> &str {
+        &self.command_line
+    }
+    pub fn timestamps(&self) -> BootTimestamps {
+        self.timestamps
+    }
+    // 起動時のカーネルのイメージとヒープのアドレス
+    // 乱数でずらしたのはヒープだけで、イメージはファームウェアが読み込んだ場所のまま
+    pub fn kaslr(&self) -> KaslrOffsets {
+        self.kaslr
+    }
+}
+// 起動時のログに出す要約（メモリマップは数だけ）
+impl fmt::Debug for BootInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |cycles: u64| match tsc().cycles_per_ms() {
+            0 => cycles,
+            c => cycles / c,
+        };
+        let t = self.timestamps;
+        f.debug_struct("BootInfo")
+            .field("memory_regions", &self.memory_regions.len())
+            .field(
+                "vram",
+                &format_args!(
+                    "{}x{} at {:#X}",
+                    self.vram.width(),
+                    self.vram.height(),
+                    self.vram.buf_addr()
+                ),
+            )
+            .field("rsdp", &format_args!("{:#X?}", self.rsdp))
+            .field("command_line", &self.command_line)
+            .field(
+                "boot_ms",
+                &format_args!(
+                    "exit_boot_services +{}, runtime_ready +{}",
+                    ms(t.exited_boot_services.wrapping_sub(t.start)),
+                    ms(t.runtime_ready.wrapping_sub(t.start))
+                ),
+            )
+            .finish()
+    }
+}
+
+// ページアロケータ（とヒープ）に渡してはいけない範囲を取っておく
+// メモリマップでCONVENTIONAL_MEMORYになっていなくても、ファームウェアの誤りに備えて明示しておく
+fn reserve_firmware_regions(
+    memory_map: &MemoryMapHolder,
+    vram: &VramBufferInfo,
+    rsdp: Option<u64>,
+) {
+    let reserve = |phys: u64, len: u64, tag: &'static str| {
+        if let Err(e) = reserve::reserve_region(phys, len, tag) {
+            warn!("{e}: {phys:#X} ({tag})");
+        }
+    };
+    if vram.buf_size() != 0 {
+        reserve(vram.buf_addr(), vram.buf_size(), "framebuffer");
+    }
+    if let Some(rsdp) = rsdp {
+        // RSDPは拡張された形式でも36バイト
+        reserve(rsdp, 36, "ACPI RSDP");
+    }
+    for e in memory_map.iter() {
+        let tag = match e.memory_type() {
+            EfiMemoryType::ACPI_RECLAIM_MEMORY | EfiMemoryType::ACPI_MEMORY_NVS => "ACPI",
+            EfiMemoryType::LOADER_CODE => "kernel image",
+            _ => continue,
+        };
+        reserve(
+            e.physical_start(),
+            e.number_of_pages() * PAGE_SIZE as u64,
+            tag,
+        );
+    }
+}
+
+// ACPIのHPETテーブルがあれば、HPETを動かして周期を表示する
+// MADTに載っているAPを起動する（起動したAPはまだ何もせずに止まっている）
+fn init_smp() {
+    let result = acpi::platform_info().and_then(smp::init);
+    match result {
+        Ok((online, total)) => info!("SMP: {online}/{total} CPUs online"),
+        Err(e) => warn!("{e}"),
+    }
+}
+
+// カーネルのイメージを、.textは読み込みと実行、.rdataは読み込み、.data/.bssは読み書きだけにする
+// イメージのヘッダーもLOADER_CODEのメモリにあるので、ブートサービスを抜けた後でも読める
+fn protect_kernel_sections(image_base: u64) {
+    if image_base == 0 {
+        warn!("W^X is not enforced: the kernel image was not found");
+        return;
+    }
+    let headers = unsafe { core::slice::from_raw_parts(image_base as *const u8, PAGE_SIZE) };
+    let result = PeImage::parse(headers).and_then(|pe| {
+        if !(pe.section_alignment as usize).is_multiple_of(PAGE_SIZE) {
+            return Err("Kernel sections are not page aligned");
+        }
+        // 先頭のページにあるヘッダーは読み込みだけにする
+        let header = image_base..image_base + PAGE_SIZE as u64;
+        protect_kernel_image(
+            core::iter::once((header, PageAttr::ReadOnlyKernelNoExec)).chain(
+                pe.sections()
+                    .map(|section| (section.range(image_base), section.attr())),
+            ),
+        )
+    });
+    match result {
+        Ok(sections) => info!("W^X: protected {sections} kernel sections"),
+        Err(e) => warn!("W^X is not enforced: {e}"),
+    }
+}
 
-// メモリマップの初期化
+fn init_hpet() {
+    let Some(base) = acpi::platform_info().ok().and_then(|p| p.hpet_addr) else {
+        info!("HPET is not available");
+        return;
+    };
+    match hpet::init(base) {
+        Ok(info) => info!(
+            "HPET: period {} fs ({} MHz), {}-bit counter",
+            info.period_fs,
+            info.frequency_hz() / 1_000_000,
+            if info.is_64bit { 64 } else { 32 }
+        ),
+        Err(e) => warn!("{e}"),
+    }
+}
+
+// 初期化の失敗を、テストでは警告だけにしてNoneを返す
+// ヒープや画面がなくても、使わないテストは実行できるようにする（使うテストはテストランナーがスキップする）
+#[cfg(test)]
+fn recoverable<T>(result: Result<T>, what: &str) -> Option<T> {
+    result.map_err(|e| warn!("{what} failed: {e}")).ok()
+}
+// テストでなければ、続けられないのでpanicする
+#[cfg(not(test))]
+fn recoverable<T>(result: Result<T>, what: &str) -> Option<T> {
+    Some(result.unwrap_or_else(|e| panic!("{what} failed: {e}")))
+}
+
+// UEFIから必要な情報を集めてブートサービスを抜け、カーネルの実行環境を初期化する
+// 順番に制約があるので、ここ以外でUEFIの情報を集めないこと
+// 1. UEFIに依存する情報（VRAM、RSDP、コマンドライン）をヒープを使わずに集める
+// 2. ブートサービスを抜ける（メモリマップはこのとき確定する）
+// 3. メモリマップを使ってアロケータを初期化する
+// 4. 集めた情報のうち、ヒープに置くもの（メモリマップ、コマンドライン）をコピーする
+// 5. 自前のカーネルスタックに切り替えて、main(BootInfo)を呼ぶ（戻ってこない）
 pub fn init_basic_runtime(
     image_handle: EfiHandle,
     efi_system_table: &EfiSystemTable,
-) -> MemoryMapHolder {
+    main: fn(BootInfo) -> !,
+) -> ! {
+    let mut timestamps = BootTimestamps {
+        start: rdtsc(),
+        ..Default::default()
+    };
+    // QEMUのdebugconは何も初期化せずに使えるので、一番最初に出力先に加える
+    init_debugcon();
+    // 解像度の指定（video=WxH）があるかもしれないので、画面より先にコマンドラインを読む
+    let mut command_line = [0u8; COMMAND_LINE_BUFFER_SIZE];
+    // KASLRの報告のために、イメージが読み込まれた場所とリンクしたアドレスもここで調べておく
+    let (command_line_len, image_base, image_link_base) =
+        match locate_loaded_image_protocol(image_handle, efi_system_table) {
+            Ok(image) => (
+                decode_ucs2(image.load_options(), &mut command_line),
+                image.image_base,
+                image
+                    .pe_headers()
+                    .and_then(|headers| PeImage::parse(headers).ok())
+                    .map(|pe| pe.image_base),
+            ),
+            Err(_) => (0, 0, None),
+        };
+    let command_line_str = from_utf8(&command_line[..command_line_len]).unwrap_or_default();
+    let mode_preference = ModePreference::from_command_line(command_line_str);
+    let heap_backend = HeapBackend::from_command_line(command_line_str);
+    let fit_policy = FitPolicy::from_command_line(command_line_str);
+    let kaslr_enabled = kaslr::enabled_from_command_line(command_line_str);
+    if let Some(level) = LogLevel::from_command_line(command_line_str) {
+        set_log_level(level);
+    }
+    // フレームバッファの場所はブートサービスを抜ける前に調べておく
+    let vram = recoverable(init_vram(efi_system_table, mode_preference), "init_vram")
+        .unwrap_or_else(VramBufferInfo::headless);
+    if vram.buf_size() != 0 {
+        init_vram_console(vram);
+    }
+    let rsdp = efi_system_table.acpi_rsdp();
+    // 電源を切るときに使うので、ランタイムサービスの場所も覚えておく
+    keep_runtime_services(efi_system_table);
     let mut memory_map = MemoryMapHolder::new();
     // UEFIブートサービスの終了
     exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
+    timestamps.exited_boot_services = rdtsc();
 
     // アロケータの初期コード
-    // OSが利用可能とマークされたメモリ（CONVENTIONAL_MEMORY)をアロケーターの空きリストに追加
-    ALLOCATOR.init_with_mmap(&memory_map);
-    memory_map
+    // OSが利用可能とマークされたメモリ（CONVENTIONAL_MEMORY)をページアロケータに渡し、
+    // ヒープはそこからページを受け取る
+    // ファームウェアやローダのデータと、1MiB未満に置く必要があるAPの起動コードの場所は、
+    // ページアロケータに渡す前に取っておく
+    reserve_firmware_regions(&memory_map, &vram, rsdp);
+    if smp::reserve_trampoline_area(&memory_map).is_none() {
+        warn!("No room for the AP trampoline below 1MiB");
+    }
+    if recoverable(
+        PAGE_ALLOCATOR.init_with_mmap(&memory_map),
+        "PAGE_ALLOCATOR.init_with_mmap",
+    )
+    .is_none()
+    {
+        // ヒープがないとGDTやIDT、自前のページテーブルも作れないので、UEFIのスタックのままmainを呼ぶ
+        // （heap_availableがfalseになり、テストランナーはヒープを使うテストをスキップする）
+        timestamps.runtime_ready = rdtsc();
+        main(BootInfo {
+            memory_regions: MemoryRegions::new(core::iter::empty()),
+            vram,
+            rsdp,
+            command_line: String::new(),
+            timestamps,
+            kaslr: KaslrOffsets::default(),
+        })
+    }
+    // ヒープの先頭は起動するたびに変わるように、乱数の分だけずらす
+    let heap_slide = kaslr::init_heap(heap_backend, fit_policy, kaslr_enabled);
+    let kaslr = KaslrOffsets {
+        enabled: kaslr_enabled,
+        image_base,
+        image_link_base,
+        heap_start: heap_start(),
+        heap_slide,
+    };
+    info!("{kaslr}");
+    for region in reserve::regions() {
+        info!("Reserved: {region}");
+    }
+    info!(
+        "Heap: {heap_backend:?} ({fit_policy:?}), {} KiB ({} KiB left in the page allocator)",
+        heap_size() / 1024,
+        PAGE_ALLOCATOR.free_page_count() * PAGE_SIZE / 1024
+    );
+    // 以降はヒープにコピーしたメモリマップを使う（元のバッファはこの関数を抜けると消える）
+    let memory_regions = MemoryRegions::from(&memory_map);
+    // decode_ucs2は文字の途中で切らないので、必ずUTF-8として読める
+    let command_line =
+        String::from(from_utf8(&command_line[..command_line_len]).unwrap_or_default());
+
+    // GDT/TSSと例外ハンドラの初期化（確保にアロケータを使うので、その後に行う）
+    // IDTはTSSのISTを参照するので、GDTを先に読み込む
+    init_gdt();
+    init_idt();
+    info!("{}", cpu_info());
+    // UEFIのページテーブルから自前のページテーブルに切り替える
+    init_paging(&memory_regions, &vram).expect("init_paging failed");
+    protect_kernel_sections(image_base);
+    // ガードページを空けてマップするので、自前のページテーブルに切り替えた後に確保する
+    let kernel_stack = init_kernel_stack().expect("init_kernel_stack failed");
+    init_guarded_interrupt_stacks().expect("init_guarded_interrupt_stacks failed");
+    info!(
+        "Kernel stack: {:#X}-{:#X} (guard page at {:#X})",
+        kernel_stack.range().start,
+        kernel_stack.range().end,
+        kernel_stack.guard().start
+    );
+    // ACPIのテーブルはメモリマップに載っているので、ページングを切り替えた後でも読める
+    match acpi::init(rsdp) {
+        Ok(platform) => acpi::log_platform_info(platform),
+        Err(e) => warn!("{e}"),
+    }
+    // PICやPS/2の初期化で使うdelay_usを、割り込みなしでキャリブレーションしておく
+    init_delay();
+    // PICを再配置して全てのIRQをマスクしておく（ドライバが必要なIRQだけを開ける）
+    init_pic();
+    // タイマーのキャリブレーションでPITの割り込みを使うので、先に割り込みを許可する
+    // （この時点では全てのIRQがマスクされている）
+    enable_interrupts();
+    init_timer();
+    init_tsc();
+    init_hpet();
+    init_smp();
+    info!("Current time: {} (RTC)", rtc::read());
+    if let Err(e) = init_keyboard() {
+        warn!("PS/2 keyboard is not available: {e}");
+    }
+    let ports = detect_ports();
+    info!("Serial ports: {ports}");
+    // COM1がなくて他のポートがあれば、出力先をそちらに切り替える
+    if !global_port().is_available() {
+        if let Some(port) = ports.iter().next() {
+            match set_global_port(port) {
+                Ok(()) => info!("Switched the console to {port}"),
+                Err(e) => warn!("Failed to switch the console to {port}: {e}"),
+            }
+        }
+    }
+    // 使えるシリアルポートがなければ、画面に出力する
+    if !global_port().is_available() {
+        register_sink(vram_console_sink()).expect("register_sink failed");
+    }
+    if let Err(e) = SerialPort::new_for_com1().init_with_rx_interrupt() {
+        warn!("Serial receive interrupt is not available: {e}");
+    }
+    match init_mouse() {
+        Ok(has_wheel) => info!("PS/2 mouse initialized (wheel: {has_wheel})"),
+        Err(e) => warn!("PS/2 mouse is not available: {e}"),
+    }
+    pci::log_devices(&pci::scan());
+    timestamps.runtime_ready = rdtsc();
+    let boot_info = BootInfo {
+        memory_regions,
+        vram,
+        rsdp,
+        command_line,
+        timestamps,
+        kaslr,
+    };
+    run_on_stack(kernel_stack, (boot_info, main), enter_main)
+}
+
+// 自前のスタックに移った後の最初の関数
+// UEFIのスタックはもう使わないので、ブートサービスのメモリを空きに加えてからmainを呼ぶ
+fn enter_main((boot_info, main): (BootInfo, fn(BootInfo) -> !)) -> ! {
+    let reclaimed = ALLOCATOR.reclaim_boot_memory(boot_info.memory_regions());
+    info!(
+        "Reclaimed {} KiB of boot services and loader memory ({} KiB free in the page allocator)",
+        reclaimed / 1024,
+        PAGE_ALLOCATOR.free_page_count() * PAGE_SIZE / 1024
+    );
+    main(boot_info)
+}
+
+// テストからBootInfoを参照できるように、テストの入口で保存しておく
+#[cfg(test)]
+static BOOT_INFO_FOR_TEST: spin::Mutex<Option<BootInfo>> = spin::Mutex::new(None);
+
+#[cfg(test)]
+pub fn keep_boot_info_for_test(boot_info: BootInfo) {
+    *BOOT_INFO_FOR_TEST.lock() = Some(boot_info);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn boot_info_is_populated_under_qemu() {
+        let boot_info = BOOT_INFO_FOR_TEST.lock();
+        let boot_info = boot_info.as_ref().expect("BootInfo was not kept");
+        assert!(!boot_info.memory_regions().is_empty());
+        let vram = boot_info.vram();
+        assert!(vram.width() > 0 && vram.height() > 0 && vram.buf_addr() != 0);
+        // OVMFはACPIテーブルを提供する
+        assert!(boot_info.rsdp().is_some_and(|addr| addr != 0));
+        // ブートマネージャから起動したときは引数がないこともあるので、NULを含まないことだけ確かめる
+        assert!(!boot_info.command_line().contains('\0'));
+        let t = boot_info.timestamps();
+        assert!(t.start < t.exited_boot_services);
+        assert!(t.exited_boot_services < t.runtime_ready);
+        // OVMFはブートサービスのメモリを使うので、抜けた後に空きにできる
+        assert!(crate::allocator::reclaimed_boot_memory() > 0);
+        assert_eq!(ALLOCATOR.reclaim_boot_memory(boot_info.memory_regions()), 0);
+        let kaslr = boot_info.kaslr();
+        assert!(kaslr.image_base != 0 && kaslr.image_link_base.is_some());
+        assert!(kaslr.heap_start != 0);
+        let summary = alloc::format!("{boot_info:?}");
+        assert!(summary.starts_with("BootInfo { memory_regions: "));
+    }
 }
@@ -0,0 +1,379 @@
+extern crate alloc;
+
+use crate::result::Result;
+use core::fmt;
+use core::ops::Range;
+
+// build.rsがinitrd/以下のファイルとELFのテスト用プログラムをまとめたUSTAR形式のアーカイブ
+static INITRD: &[u8] = include_bytes!(env!("INITRD"));
+
+const BLOCK_SIZE: usize = 512;
+
+// ヘッダの各フィールドの位置
+const NAME: Range<usize> = 0..100;
+const SIZE: Range<usize> = 124..136;
+const CHECKSUM: Range<usize> = 148..156;
+const TYPEFLAG: usize = 156;
+const MAGIC: Range<usize> = 257..262;
+const PREFIX: Range<usize> = 345..500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    // シンボリックリンクなど、今は扱わない種類
+    Other(u8),
+}
+impl EntryKind {
+    fn from_typeflag(flag: u8) -> Self {
+        match flag {
+            // 古いtarは通常のファイルを0x00で表す
+            b'0' | 0 => Self::File,
+            b'5' => Self::Directory,
+            _ => Self::Other(flag),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    // 長いパスは前半がprefixに入っていて、prefix + "/" + nameが本当のパスになる
+    prefix: &'a str,
+    name: &'a str,
+    kind: EntryKind,
+    data: &'a [u8],
+}
+impl<'a> Entry<'a> {
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    // パスがpathと同じならtrue（先頭の"/"や"./"は無視する）
+    pub fn path_eq(&self, path: &str) -> bool {
+        let path = normalize(path);
+        let name = self.name.trim_end_matches('/');
+        if self.prefix.is_empty() {
+            return normalize(name) == path;
+        }
+        path.strip_prefix(normalize(self.prefix))
+            .and_then(|rest| rest.strip_prefix('/'))
+            .is_some_and(|rest| rest == name)
+    }
+}
+impl fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix.is_empty() {
+            write!(f, "{}", normalize(self.name))
+        } else {
+            write!(f, "{}/{}", normalize(self.prefix), self.name)
+        }
+    }
+}
+
+fn normalize(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            return path;
+        }
+    }
+}
+
+// NULで終わる（または欄いっぱいの）文字列を取り出す
+fn c_str(field: &[u8]) -> Result<&str> {
+    let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| "initrd: file name is not UTF-8")
+}
+
+// 前後の空白とNULを除いた8進数を読む
+fn parse_octal(field: &[u8]) -> Result<u64> {
+    let digits = field
+        .iter()
+        .skip_while(|b| **b == b' ')
+        .take_while(|b| **b != 0 && **b != b' ');
+    let mut value: u64 = 0;
+    for b in digits {
+        if !(b'0'..=b'7').contains(b) {
+            return Err("initrd: invalid octal number");
+        }
+        value = value
+            .checked_mul(8)
+            .map(|v| v + (b - b'0') as u64)
+            .ok_or("initrd: octal number is too large")?;
+    }
+    Ok(value)
+}
+
+// チェックサムはチェックサム欄を空白とみなして、ヘッダの全バイトを符号なしで足したもの
+fn checksum(header: &[u8]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, b)| if CHECKSUM.contains(&i) { b' ' } else { *b } as u64)
+        .sum()
+}
+
+fn is_zero_block(block: &[u8]) -> bool {
+    block.iter().all(|b| *b == 0)
+}
+
+// アーカイブの先頭から順にエントリを返す
+// 壊れたヘッダを見つけたらErrを1つ返して、それ以降は何も返さない
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+impl<'a> Entries<'a> {
+    pub fn new(archive: &'a [u8]) -> Self {
+        Self {
+            archive,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn block(&self, offset: usize) -> Option<&'a [u8]> {
+        self.archive.get(offset..offset.checked_add(BLOCK_SIZE)?)
+    }
+
+    fn parse_next(&mut self) -> Result<Option<Entry<'a>>> {
+        if self.offset == self.archive.len() {
+            // 終わりのブロックが省かれていても、ちょうどブロックの境目で終わっていれば受け付ける
+            return Ok(None);
+        }
+        let header = self
+            .block(self.offset)
+            .ok_or("initrd: archive is truncated")?;
+        if is_zero_block(header) {
+            // 0のブロックが2つ続いたら終わり
+            return match self.block(self.offset + BLOCK_SIZE) {
+                Some(next) if !is_zero_block(next) => Err("initrd: unexpected zero block"),
+                _ => Ok(None),
+            };
+        }
+        if parse_octal(&header[CHECKSUM])? != checksum(header) {
+            return Err("initrd: header checksum mismatch");
+        }
+        // POSIXの"ustar\0"とGNU tarの"ustar "の両方を受け付ける
+        if &header[MAGIC] != b"ustar" {
+            return Err("initrd: not a USTAR archive");
+        }
+        let size = usize::try_from(parse_octal(&header[SIZE])?)
+            .map_err(|_| "initrd: file is too large")?;
+        let data_start = self.offset + BLOCK_SIZE;
+        let data = data_start
+            .checked_add(size)
+            .and_then(|end| self.archive.get(data_start..end))
+            .ok_or("initrd: file data is truncated")?;
+        let entry = Entry {
+            prefix: c_str(&header[PREFIX])?,
+            name: c_str(&header[NAME])?,
+            kind: EntryKind::from_typeflag(header[TYPEFLAG]),
+            data,
+        };
+        // データは512バイト単位で埋められている
+        self.offset = data_start + size.next_multiple_of(BLOCK_SIZE);
+        self.offset = self.offset.min(self.archive.len());
+        Ok(Some(entry))
+    }
+}
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.parse_next();
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+// archiveの中からpathという名前のファイルを探す（壊れている場合はNone）
+pub fn open_in<'a>(archive: &'a [u8], path: &str) -> Option<&'a [u8]> {
+    Entries::new(archive)
+        .map_while(|e| e.ok())
+        .find(|e| e.kind == EntryKind::File && e.path_eq(path))
+        .map(|e| e.data)
+}
+
+pub fn entries() -> Entries<'static> {
+    Entries::new(INITRD)
+}
+
+// カーネルに埋め込まれたinitrdからファイルを探す
+pub fn open(path: &str) -> Option<&'static [u8]> {
+    open_in(INITRD, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    fn write_octal(field: &mut [u8], value: u64) {
+        let s = format!("{value:0width$o}", width = field.len() - 1);
+        field[..s.len()].copy_from_slice(s.as_bytes());
+    }
+
+    fn header(prefix: &str, name: &str, typeflag: u8, size: usize) -> [u8; BLOCK_SIZE] {
+        let mut h = [0u8; BLOCK_SIZE];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut h[100..108], 0o644);
+        write_octal(&mut h[SIZE], size as u64);
+        h[TYPEFLAG] = typeflag;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        h[PREFIX.start..PREFIX.start + prefix.len()].copy_from_slice(prefix.as_bytes());
+        update_checksum(&mut h);
+        h
+    }
+
+    fn update_checksum(h: &mut [u8]) {
+        let sum = checksum(h);
+        write_octal(&mut h[CHECKSUM.start..CHECKSUM.end - 1], sum);
+        h[CHECKSUM.end - 1] = b' ';
+    }
+
+    fn append(archive: &mut Vec<u8>, prefix: &str, name: &str, typeflag: u8, data: &[u8]) {
+        archive.extend_from_slice(&header(prefix, name, typeflag, data.len()));
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+    }
+
+    fn finish(archive: &mut Vec<u8>) {
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    }
+
+    fn long_dir() -> alloc::string::String {
+        "assets/".repeat(15) + "fonts"
+    }
+
+    // 512の倍数でない大きさのファイル、ちょうど512バイトのファイル、
+    // ディレクトリ、prefixを使う長いパスのファイルを含むアーカイブ
+    fn sample_archive() -> Vec<u8> {
+        let mut a = Vec::new();
+        append(&mut a, "", "hello.txt", b'0', b"Hello, initrd!\n");
+        append(&mut a, "", "etc/", b'5', b"");
+        append(&mut a, "", "etc/block", b'0', &[0xab; BLOCK_SIZE]);
+        let big: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        append(&mut a, "", "./big.bin", 0, &big);
+        append(&mut a, &long_dir(), "font.bin", b'0', b"glyphs");
+        finish(&mut a);
+        a
+    }
+
+    #[test_case]
+    fn parses_entries() {
+        let archive = sample_archive();
+        let entries: Vec<Entry> = Entries::new(&archive).map(|e| e.unwrap()).collect();
+        let names: Vec<_> = entries.iter().map(|e| e.to_string()).collect();
+        let long_path = long_dir() + "/font.bin";
+        assert!(long_path.len() > 100);
+        assert_eq!(
+            names,
+            [
+                "hello.txt",
+                "etc/",
+                "etc/block",
+                "big.bin",
+                long_path.as_str()
+            ]
+        );
+        assert_eq!(entries[1].kind(), EntryKind::Directory);
+        assert_eq!(entries[3].kind(), EntryKind::File);
+        assert_eq!(entries[3].data().len(), 1300);
+        assert_eq!(entries[3].data()[1299], (1299 % 256) as u8);
+    }
+    #[test_case]
+    fn open_finds_files() {
+        let archive = sample_archive();
+        assert_eq!(
+            open_in(&archive, "hello.txt"),
+            Some(&b"Hello, initrd!\n"[..])
+        );
+        assert_eq!(
+            open_in(&archive, "/hello.txt"),
+            Some(&b"Hello, initrd!\n"[..])
+        );
+        assert_eq!(
+            open_in(&archive, "etc/block"),
+            Some(&[0xab; BLOCK_SIZE][..])
+        );
+        assert_eq!(open_in(&archive, "big.bin").map(|d| d.len()), Some(1300));
+        let long_path = long_dir() + "/font.bin";
+        assert_eq!(open_in(&archive, &long_path), Some(&b"glyphs"[..]));
+        // ディレクトリはファイルとしては開けない
+        assert_eq!(open_in(&archive, "etc"), None);
+        assert_eq!(open_in(&archive, "missing"), None);
+        assert_eq!(open_in(&archive, "font.bin"), None);
+    }
+    #[test_case]
+    fn stops_at_end_of_archive() {
+        let mut archive = sample_archive();
+        // 終わりのブロックより後ろにあるものは読まない
+        append(&mut archive, "", "after_end", b'0', b"ignored");
+        assert_eq!(Entries::new(&archive).count(), 5);
+        assert_eq!(open_in(&archive, "after_end"), None);
+        assert_eq!(Entries::new(&[]).count(), 0);
+    }
+    #[test_case]
+    fn rejects_corrupt_checksum() {
+        let mut archive = sample_archive();
+        // 2つ目のエントリ（etc/）の名前を書き換える
+        archive[2 * BLOCK_SIZE] = b'x';
+        let results: Vec<_> = Entries::new(&archive).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        assert_eq!(results[1], Err("initrd: header checksum mismatch"));
+        assert_eq!(open_in(&archive, "hello.txt").map(|d| d.len()), Some(15));
+        assert_eq!(open_in(&archive, "etc/block"), None);
+    }
+    #[test_case]
+    fn rejects_malformed_archives() {
+        let mut archive = Vec::new();
+        append(&mut archive, "", "file", b'0', &[1; 100]);
+        let truncated = &archive[..BLOCK_SIZE + 50];
+        assert_eq!(
+            Entries::new(truncated).next(),
+            Some(Err("initrd: file data is truncated"))
+        );
+        assert_eq!(
+            Entries::new(&archive[..100]).next(),
+            Some(Err("initrd: archive is truncated"))
+        );
+        let mut bad_magic = header("", "file", b'0', 0);
+        bad_magic[MAGIC.start] = b'x';
+        update_checksum(&mut bad_magic);
+        assert_eq!(
+            Entries::new(&bad_magic).next(),
+            Some(Err("initrd: not a USTAR archive"))
+        );
+        let mut bad_size = header("", "file", b'0', 0);
+        bad_size[SIZE.start] = b'9';
+        update_checksum(&mut bad_size);
+        assert_eq!(
+            Entries::new(&bad_size).next(),
+            Some(Err("initrd: invalid octal number"))
+        );
+    }
+    #[test_case]
+    fn embedded_initrd_has_files() {
+        assert!(entries().all(|e| e.is_ok()));
+        assert_eq!(open("etc/motd"), Some(&b"Welcome to WasabiOS!\n"[..]));
+        assert!(open("bin/elf_test_payload").is_some_and(|d| d.starts_with(b"\x7fELF")));
+    }
+}
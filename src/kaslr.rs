@@ -0,0 +1,130 @@
+use crate::allocator::heap_start;
+use crate::allocator::FitPolicy;
+use crate::allocator::HeapBackend;
+use crate::allocator::ALLOCATOR;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::PAGE_SIZE;
+use crate::rand::rand_u64;
+use core::fmt;
+
+// 起動するたびにカーネルの置かれるアドレスが変わるようにする（KASLR）
+// ただし、自分で乱数を使って動かしているのはヒープだけで、カーネルのイメージの再配置はしていない
+// イメージはUEFIのファームウェアが読み込む場所を決めるので、その場所と、
+// リンクしたアドレスからどれだけずれて読み込まれたか（スライド）を調べて報告するだけにしている
+// （ファームウェアが毎回同じ場所に読み込むなら、イメージのアドレスは起動ごとに変わらない）
+// ヒープは、ページアロケータの一番長い空きの先頭から乱数で決めたページ数だけずらして置く
+// 乱数はrdrandから取り、使えなければTSCなどから作った疑似乱数を使う（rand::rand_u64）
+
+// ヒープをずらす最大のページ数（256MiB）
+const HEAP_MAX_SLIDE_PAGES: usize = 65536;
+// ずらすのに使ってよいのは、空きページのこの割合まで（メモリが少ないときにヒープが小さくなりすぎないように）
+const HEAP_SLIDE_DIVISOR: usize = 8;
+
+// コマンドラインにnokaslrがあれば、アドレスをずらさない
+pub fn enabled_from_command_line(command_line: &str) -> bool {
+    !command_line.split_whitespace().any(|arg| arg == "nokaslr")
+}
+
+// 選んだアドレスのずれ（シリアルに報告する）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KaslrOffsets {
+    pub enabled: bool,
+    // ファームウェアがイメージを読み込んだアドレス（乱数では動かしていない）
+    pub image_base: u64,
+    // リンクしたときのイメージのアドレス（PEのヘッダーが読めなければNone）
+    pub image_link_base: Option<u64>,
+    // ヒープの先頭のアドレスと、ずらしたバイト数
+    pub heap_start: usize,
+    pub heap_slide: usize,
+}
+impl KaslrOffsets {
+    // 読み込んだアドレスとリンクしたアドレスの差
+    pub fn image_slide(&self) -> Option<i64> {
+        self.image_link_base
+            .map(|link| self.image_base.wrapping_sub(link) as i64)
+    }
+}
+impl fmt::Display for KaslrOffsets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KASLR: {}, image at {:#X}",
+            if self.enabled { "enabled" } else { "disabled" },
+            self.image_base
+        )?;
+        match (self.image_link_base, self.image_slide()) {
+            (Some(link), Some(slide)) => {
+                let sign = if slide < 0 { '-' } else { '+' };
+                write!(
+                    f,
+                    " (linked at {link:#X}, slide {sign}{:#X}, placed by firmware)",
+                    slide.unsigned_abs()
+                )?
+            }
+            _ => write!(f, " (link address unknown, placed by firmware)")?,
+        }
+        write!(
+            f,
+            ", heap at {:#X} (slide {:#X})",
+            self.heap_start, self.heap_slide
+        )
+    }
+}
+
+// 乱数で決めたページ数だけ先頭をずらしてヒープを初期化し、ずらしたバイト数を返す
+// ページアロケータを初期化した後、ALLOCATOR.init_with_pagesの代わりに一度だけ呼ぶこと
+pub fn init_heap(backend: HeapBackend, policy: FitPolicy, enabled: bool) -> usize {
+    let max_pages =
+        (PAGE_ALLOCATOR.free_page_count() / HEAP_SLIDE_DIVISOR).min(HEAP_MAX_SLIDE_PAGES);
+    let pages = if enabled && max_pages > 0 {
+        (rand_u64() % (max_pages as u64 + 1)) as usize
+    } else {
+        0
+    };
+    // ヒープは一番長い空きから受け取るので、その先頭を確保しておくとヒープはその後ろから始まる
+    let skipped = if pages > 0 {
+        PAGE_ALLOCATOR.alloc_largest_run(pages)
+    } else {
+        None
+    };
+    ALLOCATOR.init_with_pages(backend, policy);
+    let Some(run) = skipped else {
+        return 0;
+    };
+    // 確保しておいたページは、ヒープを置いた後はページアロケータに返して普通に使う
+    unsafe { PAGE_ALLOCATOR.free_pages(run.start as *mut u8, run.len() / PAGE_SIZE) };
+    // 残りが一番長い空きでなくなると、ヒープは別の空きに置かれるので、ずらしたことにならない
+    if heap_start() != run.end {
+        return 0;
+    }
+    run.len()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+
+    #[test_case]
+    fn kaslr_is_disabled_with_nokaslr() {
+        assert!(enabled_from_command_line(""));
+        assert!(enabled_from_command_line("heap=buddy nokaslrx"));
+        assert!(!enabled_from_command_line("heap=buddy nokaslr"));
+    }
+    #[test_case]
+    fn offsets_are_reported() {
+        let offsets = KaslrOffsets {
+            enabled: true,
+            image_base: 0x3e00_0000,
+            image_link_base: Some(0x1_4000_0000),
+            heap_start: 0x0123_4000,
+            heap_slide: 0x20_0000,
+        };
+        assert_eq!(offsets.image_slide(), Some(0x3e00_0000 - 0x1_4000_0000));
+        assert_eq!(
+            alloc::format!("{offsets}"),
+            "KASLR: enabled, image at 0x3E000000 (linked at 0x140000000, slide -0x102000000, placed by firmware), heap at 0x1234000 (slide 0x200000)"
+        );
+    }
+}
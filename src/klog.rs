@@ -0,0 +1,271 @@
+use crate::x86::InterruptGuard;
+use core::fmt;
+use core::str::from_utf8;
+use spin::Mutex;
+
+// カーネルのメッセージを保持しておくバッファの大きさ
+pub const KLOG_SIZE: usize = 64 * 1024;
+
+// print!で出力された内容を覚えておくリングバッファ
+// ヒープを使わないので、アロケータの初期化前から使える
+pub struct Klog<const N: usize> {
+    buf: [u8; N],
+    // これまでに書き込まれた総バイト数（N以上なら古い内容は上書きされている）
+    total: u64,
+    // clearした時点のtotal（これより前の内容は表示しない）
+    start: u64,
+    // 最後に上書きされたバイトが改行だったか（残っている内容が行の先頭から始まるか）
+    overwrote_newline: bool,
+}
+
+impl<const N: usize> Klog<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            total: 0,
+            start: 0,
+            overwrote_newline: false,
+        }
+    }
+    pub fn append(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            let slot = &mut self.buf[(self.total % N as u64) as usize];
+            if self.total >= N as u64 {
+                self.overwrote_newline = *slot == b'\n';
+            }
+            *slot = *b;
+            self.total += 1;
+        }
+    }
+    pub fn total_written(&self) -> u64 {
+        self.total
+    }
+    // 上書きされて失われた内容があればtrue
+    pub fn overwritten(&self) -> bool {
+        self.total - self.start > N as u64
+    }
+    pub fn clear(&mut self) {
+        self.start = self.total;
+    }
+    // 残っている内容を古い順に2つのスライスで返す（バッファの終端で折り返しているため）
+    fn retained(&self, max_len: usize) -> (&[u8], &[u8]) {
+        let len = (self.total - self.start).min(N as u64).min(max_len as u64) as usize;
+        let end = (self.total % N as u64) as usize;
+        if len <= end {
+            (&self.buf[end - len..end], &[])
+        } else {
+            (&self.buf[N - (len - end)..], &self.buf[..end])
+        }
+    }
+    // 書き出す内容の直前のバイトが改行か（書き出す内容がちょうど行の先頭から始まるか）
+    fn starts_at_line(&self, len: usize) -> bool {
+        let len = len as u64;
+        if len >= self.total - self.start {
+            true
+        } else if len < N as u64 {
+            self.buf[((self.total - len - 1) % N as u64) as usize] == b'\n'
+        } else {
+            // 直前のバイトは上書きされてバッファには残っていない
+            self.overwrote_newline
+        }
+    }
+    // 残っている内容のうち、最後のmax_lenバイト以内をwに書き出す
+    // 古い内容が欠けている場合は、途中で切れた最初の行を飛ばして、行の先頭から書き出す
+    pub fn replay(&self, w: &mut impl fmt::Write, max_len: usize) -> fmt::Result {
+        let (mut first, mut second) = self.retained(max_len);
+        if !self.starts_at_line(first.len() + second.len()) {
+            if let Some(pos) = first.iter().position(|b| *b == b'\n') {
+                first = &first[pos + 1..];
+            } else if let Some(pos) = second.iter().position(|b| *b == b'\n') {
+                first = &[];
+                second = &second[pos + 1..];
+            } else {
+                // 改行が1つもなければ、そのまま書き出す
+            }
+        }
+        write_utf8_chunks(w, [first, second])
+    }
+}
+
+impl<const N: usize> Default for Klog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Write for Klog<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.append(s.as_bytes());
+        Ok(())
+    }
+}
+
+// バイト列をUTF-8の文字列として書き出す
+// 折り返し位置で文字が2つのスライスに分かれていても正しくつなげる
+fn write_utf8_chunks(w: &mut impl fmt::Write, chunks: [&[u8]; 2]) -> fmt::Result {
+    let mut carry = [0u8; 4];
+    let mut carry_len = 0;
+    for chunk in chunks {
+        let mut rest = chunk;
+        // 前のスライスの末尾で切れた文字を完成させる
+        while carry_len > 0 && !rest.is_empty() {
+            carry[carry_len] = rest[0];
+            carry_len += 1;
+            rest = &rest[1..];
+            match from_utf8(&carry[..carry_len]) {
+                Ok(s) => {
+                    w.write_str(s)?;
+                    carry_len = 0;
+                }
+                Err(e) if e.error_len().is_some() => {
+                    w.write_char(char::REPLACEMENT_CHARACTER)?;
+                    carry_len = 0;
+                }
+                Err(_) => {}
+            }
+        }
+        loop {
+            match from_utf8(rest) {
+                Ok(s) => {
+                    w.write_str(s)?;
+                    break;
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    w.write_str(from_utf8(valid).unwrap_or_default())?;
+                    match e.error_len() {
+                        Some(n) => {
+                            w.write_char(char::REPLACEMENT_CHARACTER)?;
+                            rest = &after[n..];
+                        }
+                        None => {
+                            carry[..after.len()].copy_from_slice(after);
+                            carry_len = after.len();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if carry_len > 0 {
+        w.write_char(char::REPLACEMENT_CHARACTER)?;
+    }
+    Ok(())
+}
+
+static KLOG: Mutex<Klog<KLOG_SIZE>> = Mutex::new(Klog::new());
+
+// print!の出力先として登録するシンク
+pub const fn sink() -> &'static Mutex<Klog<KLOG_SIZE>> {
+    &KLOG
+}
+
+// 残っているメッセージを古い順にwに書き出す
+pub fn snapshot(w: &mut impl fmt::Write) -> fmt::Result {
+    snapshot_tail(w, KLOG_SIZE)
+}
+
+// 残っているメッセージのうち、最後のmax_lenバイト以内をwに書き出す
+pub fn snapshot_tail(w: &mut impl fmt::Write, max_len: usize) -> fmt::Result {
+    // 割り込みハンドラからも書き込まれるので、ロック中は割り込みを禁止する
+    let _guard = InterruptGuard::new();
+    KLOG.lock().replay(w, max_len)
+}
+
+// panic中など、klogへの書き込み中に止まった可能性があるときに使う
+pub fn is_busy() -> bool {
+    KLOG.is_locked()
+}
+
+pub fn clear() {
+    let _guard = InterruptGuard::new();
+    KLOG.lock().clear();
+}
+
+pub fn total_written() -> u64 {
+    let _guard = InterruptGuard::new();
+    KLOG.lock().total_written()
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::format;
+    use alloc::string::String;
+    use core::fmt::Write;
+
+    #[test_case]
+    fn replay_without_wrap() {
+        let mut klog = Klog::<64>::new();
+        writeln!(klog, "hello\nworld").unwrap();
+        let mut out = String::new();
+        klog.replay(&mut out, 64).unwrap();
+        assert_eq!(out, "hello\nworld\n");
+        assert!(!klog.overwritten());
+    }
+    #[test_case]
+    fn replay_after_wrapping_keeps_newest_whole_lines() {
+        let mut klog = Klog::<64>::new();
+        // 1行8バイトで、バッファを何周もさせる
+        for i in 0..50 {
+            writeln!(klog, "line {i:02}").unwrap();
+        }
+        assert!(klog.overwritten());
+        assert_eq!(klog.total_written(), 400);
+        let mut out = String::new();
+        klog.replay(&mut out, 64).unwrap();
+        // 64バイトにちょうど8行が収まっているので、最初の行も表示される
+        let expected: String = (42..50).map(|i| format!("line {i:02}\n")).collect();
+        assert_eq!(out, expected);
+    }
+    #[test_case]
+    fn replay_after_wrapping_skips_only_a_cut_line() {
+        let mut klog = Klog::<64>::new();
+        // 1行9バイトなので、64バイトの先頭は行の途中になる
+        for i in 0..50 {
+            writeln!(klog, "line {i:03}").unwrap();
+        }
+        let mut out = String::new();
+        klog.replay(&mut out, 64).unwrap();
+        let expected: String = (43..50).map(|i| format!("line {i:03}\n")).collect();
+        assert_eq!(out, expected);
+        // 上書きされていなくても、max_lenで切った位置が行の先頭なら行を飛ばさない
+        let mut klog = Klog::<64>::new();
+        writeln!(klog, "first\nsecond").unwrap();
+        let mut out = String::new();
+        klog.replay(&mut out, 7).unwrap();
+        assert_eq!(out, "second\n");
+    }
+    #[test_case]
+    fn replay_joins_chars_split_at_wrap_point() {
+        let mut klog = Klog::<8>::new();
+        // "日"(3バイト)がバッファの終端をまたぐようにする
+        writeln!(klog, "abcdef").unwrap();
+        writeln!(klog, "日").unwrap();
+        let mut out = String::new();
+        klog.replay(&mut out, 8).unwrap();
+        assert_eq!(out, "日\n");
+    }
+    #[test_case]
+    fn clear_hides_old_messages() {
+        let mut klog = Klog::<64>::new();
+        writeln!(klog, "old").unwrap();
+        klog.clear();
+        writeln!(klog, "new").unwrap();
+        let mut out = String::new();
+        klog.replay(&mut out, 64).unwrap();
+        assert_eq!(out, "new\n");
+    }
+    #[test_case]
+    fn print_is_recorded_in_klog() {
+        let before = total_written();
+        crate::println!("\nklog test");
+        assert_eq!(total_written(), before + "\nklog test\n".len() as u64);
+        let mut out = String::new();
+        snapshot_tail(&mut out, 10).unwrap();
+        assert_eq!(out, "klog test\n");
+    }
+}
@@ -1,24 +1,81 @@
 #![no_std]
+#![feature(alloc_error_handler)]
 #![feature(custom_test_frameworks)]
 #![test_runner(crate::test_runner::test_runner)]
 #![reexport_test_harness_main = "run_unit_tests"]
 #![no_main]
+pub mod acpi;
+#[cfg(feature = "alloc_tracking")]
+pub mod alloc_track;
 pub mod allocator;
+pub mod apic;
+pub mod buddy;
+pub mod cpu_cache;
+pub mod cpuid;
+pub mod crc32;
+pub mod cursor_blink;
+pub mod dma;
+pub mod elf;
+pub mod executor;
+pub mod gdt;
 pub mod graphics;
+pub mod hpet;
 pub mod init;
+pub mod initrd;
+pub mod kaslr;
+pub mod klog;
+pub mod line_editor;
+pub mod msr;
+pub mod page_alloc;
+pub mod paging;
+pub mod panic;
+pub mod pci;
+pub mod pe;
+pub mod pic;
+pub mod power;
 pub mod print;
+pub mod profiler;
+pub mod ps2;
 pub mod qemu;
+pub mod rand;
+pub mod reserve;
 pub mod result;
+pub mod ring_buffer;
+pub mod rtc;
+pub mod screenshot;
 pub mod serial;
+pub mod shell;
+pub mod slab;
+pub mod smp;
+pub mod stack;
+pub mod sync;
+pub mod task;
+pub mod text_console;
+pub mod timer;
+pub mod tsc;
 pub mod uefi;
+pub mod vmm;
+pub mod watchdog;
 pub mod x86;
 
+#[cfg(test)]
+pub mod graphics_tests;
 #[cfg(test)]
 pub mod test_runner;
 
 #[cfg(test)]
 #[no_mangle]
 fn efi_main(image_handle: uefi::EfiHandle, efi_system_table: &uefi::EfiSystemTable) {
-    init::init_basic_runtime(image_handle, efi_system_table);
-    run_unit_tests()
+    uefi::init_con_out(efi_system_table);
+    init::init_basic_runtime(image_handle, efi_system_table, run_tests_on_kernel_stack)
+}
+
+#[cfg(test)]
+fn run_tests_on_kernel_stack(boot_info: init::BootInfo) -> ! {
+    init::keep_boot_info_for_test(boot_info);
+    run_unit_tests();
+    // テストランナーは最後にQEMUを終了するので、ここには来ない
+    loop {
+        x86::hlt()
+    }
 }
@@ -0,0 +1,573 @@
+extern crate alloc;
+
+use crate::ps2::KeyCode;
+use crate::ps2::KeyEvent;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::fmt::Write;
+
+// シェル用の1行エディタ
+// シリアルからのバイト列（ESC [ C などのエスケープシーケンスを含む）と、PS/2キーボードのキーイベントの
+// どちらからでも入力でき、エコーバックは任意のfmt::Writeに出す
+// 扱うのは表示できるASCII文字だけなので、1文字が端末の1桁になる
+
+// 端末の幅がわからないときに仮定する桁数
+pub const DEFAULT_TERMINAL_WIDTH: usize = 80;
+
+// 入力をまとめた編集操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditKey {
+    Insert(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    // 履歴をさかのぼる / 戻る
+    Up,
+    Down,
+    // カーソルから行末まで切り取る（Ctrl-K）
+    KillToEnd,
+    // 行頭からカーソルまで切り取る（Ctrl-U）
+    KillToStart,
+    // カーソルの前の単語を切り取る（Ctrl-W）
+    KillWord,
+    // 最後に切り取った文字列を貼り付ける（Ctrl-Y）
+    Yank,
+}
+
+// 制御文字と表示できる文字を編集操作にする（キーボードとシリアルで共通）
+fn edit_key_from_char(c: char) -> Option<EditKey> {
+    Some(match c {
+        '\r' | '\n' => EditKey::Enter,
+        '\x08' | '\x7f' => EditKey::Backspace,
+        '\x01' => EditKey::Home,
+        '\x02' => EditKey::Left,
+        '\x04' => EditKey::Delete,
+        '\x05' => EditKey::End,
+        '\x06' => EditKey::Right,
+        '\x0b' => EditKey::KillToEnd,
+        '\x0e' => EditKey::Down,
+        '\x10' => EditKey::Up,
+        '\x15' => EditKey::KillToStart,
+        '\x17' => EditKey::KillWord,
+        '\x19' => EditKey::Yank,
+        ' '..='~' => EditKey::Insert(c),
+        _ => return None,
+    })
+}
+
+// キーボードのキーイベントを編集操作にする（離したときのイベントは無視する）
+pub fn edit_key_from_event(e: &KeyEvent) -> Option<EditKey> {
+    if !e.pressed {
+        return None;
+    }
+    match e.code {
+        KeyCode::Left => Some(EditKey::Left),
+        KeyCode::Right => Some(EditKey::Right),
+        KeyCode::Up => Some(EditKey::Up),
+        KeyCode::Down => Some(EditKey::Down),
+        KeyCode::Home => Some(EditKey::Home),
+        KeyCode::End => Some(EditKey::End),
+        KeyCode::Delete => Some(EditKey::Delete),
+        _ => e.ch.and_then(edit_key_from_char),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,
+    // ESCを受け取った
+    Escape,
+    // ESC [ または ESC O の後で、数字の引数を読んでいる
+    Csi(u8),
+}
+
+// シリアルから来るバイト列を編集操作にする状態機械
+// 矢印キーなどはESC [ A のようなエスケープシーケンスで届く
+#[derive(Debug)]
+pub struct EscapeDecoder {
+    state: EscapeState,
+    // 直前が\rだったか（\r\nを1回のEnterとして扱うため）
+    after_cr: bool,
+}
+impl EscapeDecoder {
+    pub const fn new() -> Self {
+        Self {
+            state: EscapeState::Normal,
+            after_cr: false,
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<EditKey> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match self.state {
+            EscapeState::Normal => match byte {
+                0x1b => {
+                    self.state = EscapeState::Escape;
+                    None
+                }
+                b'\n' if after_cr => None,
+                _ => edit_key_from_char(byte as char),
+            },
+            EscapeState::Escape => {
+                self.state = match byte {
+                    b'[' | b'O' => EscapeState::Csi(0),
+                    _ => EscapeState::Normal,
+                };
+                None
+            }
+            EscapeState::Csi(param) => {
+                if byte.is_ascii_digit() {
+                    self.state =
+                        EscapeState::Csi(param.saturating_mul(10).saturating_add(byte - b'0'));
+                    return None;
+                }
+                self.state = EscapeState::Normal;
+                match (byte, param) {
+                    (b'A', _) => Some(EditKey::Up),
+                    (b'B', _) => Some(EditKey::Down),
+                    (b'C', _) => Some(EditKey::Right),
+                    (b'D', _) => Some(EditKey::Left),
+                    (b'H', _) | (b'~', 1 | 7) => Some(EditKey::Home),
+                    (b'F', _) | (b'~', 4 | 8) => Some(EditKey::End),
+                    (b'~', 3) => Some(EditKey::Delete),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+impl Default for EscapeDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// 履歴とキルバッファを持つ1行エディタ
+// begin()でプロンプトを出してから入力を渡していき、Enterで入力された行が返る
+pub struct LineEditor {
+    prompt: &'static str,
+    buf: Vec<u8>,
+    cursor: usize,
+    max_len: usize,
+    width: usize,
+    // 表示している範囲の先頭（行が端末の幅に収まらないときは横にスクロールする）
+    scroll: usize,
+    history: VecDeque<String>,
+    history_len: usize,
+    // 履歴を見ているときの位置（0が一番新しい）
+    history_pos: Option<usize>,
+    // 履歴を見始める前に入力していた行
+    saved: Vec<u8>,
+    kill: Vec<u8>,
+    decoder: EscapeDecoder,
+}
+impl LineEditor {
+    // history_len個までの履歴を覚え、max_len文字より長い入力は捨てる
+    pub fn new(history_len: usize, max_len: usize) -> Self {
+        Self {
+            prompt: "",
+            buf: Vec::new(),
+            cursor: 0,
+            max_len,
+            width: DEFAULT_TERMINAL_WIDTH,
+            scroll: 0,
+            history: VecDeque::new(),
+            history_len,
+            history_pos: None,
+            saved: Vec::new(),
+            kill: Vec::new(),
+            decoder: EscapeDecoder::new(),
+        }
+    }
+
+    pub fn set_width(&mut self, width: usize) {
+        self.width = width;
+    }
+
+    // 入力中の行（ASCIIしか入れないので必ずUTF-8として正しい）
+    pub fn line(&self) -> &str {
+        core::str::from_utf8(&self.buf).unwrap_or_default()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    // 古い順
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(|s| s.as_str())
+    }
+
+    // 新しい行の入力を始める
+    pub fn begin(&mut self, prompt: &'static str, out: &mut impl Write) {
+        self.prompt = prompt;
+        self.buf.clear();
+        self.cursor = 0;
+        self.scroll = 0;
+        self.history_pos = None;
+        let _ = out.write_str(prompt);
+    }
+
+    // シリアルから1バイト受け取る（行が確定したらそれを返す）
+    pub fn feed_byte(&mut self, byte: u8, out: &mut impl Write) -> Option<String> {
+        let key = self.decoder.feed(byte)?;
+        self.apply(key, out)
+    }
+
+    // キーボードからキーイベントを1つ受け取る（行が確定したらそれを返す）
+    pub fn feed_key(&mut self, e: &KeyEvent, out: &mut impl Write) -> Option<String> {
+        let key = edit_key_from_event(e)?;
+        self.apply(key, out)
+    }
+
+    pub fn apply(&mut self, key: EditKey, out: &mut impl Write) -> Option<String> {
+        match key {
+            EditKey::Insert(c) => self.insert(&[c as u8], out),
+            EditKey::Enter => return Some(self.finish(out)),
+            EditKey::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.buf.remove(self.cursor);
+                    self.redraw_tail(1, out);
+                }
+            }
+            EditKey::Delete => {
+                if self.cursor < self.buf.len() {
+                    self.buf.remove(self.cursor);
+                    self.redraw_tail(0, out);
+                }
+            }
+            EditKey::Left => self.move_to(self.cursor.saturating_sub(1), out),
+            EditKey::Right => self.move_to((self.cursor + 1).min(self.buf.len()), out),
+            EditKey::Home => self.move_to(0, out),
+            EditKey::End => self.move_to(self.buf.len(), out),
+            EditKey::Up => self.browse_history(true, out),
+            EditKey::Down => self.browse_history(false, out),
+            EditKey::KillToEnd => self.kill_range(self.cursor..self.buf.len(), out),
+            EditKey::KillToStart => self.kill_range(0..self.cursor, out),
+            EditKey::KillWord => {
+                let mut start = self.cursor;
+                while start > 0 && self.buf[start - 1] == b' ' {
+                    start -= 1;
+                }
+                while start > 0 && self.buf[start - 1] != b' ' {
+                    start -= 1;
+                }
+                self.kill_range(start..self.cursor, out);
+            }
+            EditKey::Yank => {
+                let kill = core::mem::take(&mut self.kill);
+                self.insert(&kill, out);
+                self.kill = kill;
+            }
+        }
+        None
+    }
+
+    // 行を確定して履歴に入れる（空行と直前と同じ行は入れない）
+    fn finish(&mut self, out: &mut impl Write) -> String {
+        let _ = out.write_str("\r\n");
+        let line = String::from(self.line());
+        self.buf.clear();
+        self.cursor = 0;
+        self.history_pos = None;
+        if !line.is_empty() && self.history_len > 0 && self.history.back() != Some(&line) {
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    // カーソルの位置に挿入する（max_lenを超える分は捨てる）
+    fn insert(&mut self, bytes: &[u8], out: &mut impl Write) {
+        let n = bytes.len().min(self.max_len.saturating_sub(self.buf.len()));
+        if n == 0 {
+            return;
+        }
+        let at = self.cursor;
+        self.buf.splice(at..at, bytes[..n].iter().copied());
+        self.cursor += n;
+        if !self.fits() {
+            self.refresh(out);
+            return;
+        }
+        // 挿入した文字とその後ろを書き直して、カーソルを挿入した文字の直後に戻す
+        let _ = out.write_str(as_str(&self.buf[at..]));
+        move_left(self.buf.len() - self.cursor, out);
+    }
+
+    fn kill_range(&mut self, range: core::ops::Range<usize>, out: &mut impl Write) {
+        if range.is_empty() {
+            return;
+        }
+        self.kill = self.buf.drain(range.clone()).collect();
+        let moved = self.cursor - range.start;
+        self.cursor = range.start;
+        self.redraw_tail(moved, out);
+    }
+
+    fn move_to(&mut self, cursor: usize, out: &mut impl Write) {
+        let from = self.cursor;
+        self.cursor = cursor;
+        if !self.fits() {
+            self.refresh(out);
+        } else if cursor < from {
+            move_left(from - cursor, out);
+        } else if cursor > from {
+            let _ = write!(out, "\x1b[{}C", cursor - from);
+        }
+    }
+
+    // 文字を消した後に、画面上のカーソルをmoved桁戻してから後ろを書き直す
+    fn redraw_tail(&mut self, moved: usize, out: &mut impl Write) {
+        if !self.fits() {
+            self.refresh(out);
+            return;
+        }
+        move_left(moved, out);
+        let _ = out.write_str(as_str(&self.buf[self.cursor..]));
+        let _ = out.write_str("\x1b[K");
+        move_left(self.buf.len() - self.cursor, out);
+    }
+
+    fn browse_history(&mut self, older: bool, out: &mut impl Write) {
+        let pos = match (self.history_pos, older) {
+            (None, false) => return,
+            (None, true) if self.history.is_empty() => return,
+            (None, true) => {
+                self.saved = self.buf.clone();
+                Some(0)
+            }
+            (Some(pos), true) => Some((pos + 1).min(self.history.len() - 1)),
+            (Some(0), false) => None,
+            (Some(pos), false) => Some(pos - 1),
+        };
+        self.history_pos = pos;
+        self.buf = match pos {
+            Some(pos) => self.history[self.history.len() - 1 - pos]
+                .as_bytes()
+                .to_vec(),
+            None => core::mem::take(&mut self.saved),
+        };
+        self.cursor = self.buf.len();
+        self.refresh(out);
+    }
+
+    // プロンプトの後ろに表示できる桁数（最後の1桁はカーソルのために空けておく）
+    fn visible_cols(&self) -> usize {
+        self.width.saturating_sub(self.prompt.len() + 1).max(1)
+    }
+
+    // スクロールせずに1行に収まっていて、少しずつ書き直してよいか
+    fn fits(&self) -> bool {
+        self.scroll == 0 && self.buf.len() <= self.visible_cols()
+    }
+
+    // 行頭に戻って、プロンプトと見えている範囲を全部書き直す
+    // 端末の幅を超える行は折り返さずに、カーソルが見える範囲だけを表示する
+    fn refresh(&mut self, out: &mut impl Write) {
+        let cols = self.visible_cols();
+        if self.buf.len() <= cols {
+            self.scroll = 0;
+        } else if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor > self.scroll + cols {
+            self.scroll = self.cursor - cols;
+        }
+        let end = self.buf.len().min(self.scroll + cols);
+        let _ = out.write_str("\r");
+        let _ = out.write_str(self.prompt);
+        let _ = out.write_str(as_str(&self.buf[self.scroll..end]));
+        let _ = out.write_str("\x1b[K");
+        move_left(end - self.cursor, out);
+    }
+}
+
+fn as_str(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or_default()
+}
+
+fn move_left(n: usize, out: &mut impl Write) {
+    if n > 0 {
+        let _ = write!(out, "\x1b[{n}D");
+    }
+}
+
+impl fmt::Debug for LineEditor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LineEditor")
+            .field("line", &self.line())
+            .field("cursor", &self.cursor)
+            .field("history", &self.history.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // バイト列を順に入力して、確定した行とエコーバックを返す
+    fn feed(editor: &mut LineEditor, input: &[u8]) -> (Option<String>, String) {
+        let mut echo = String::new();
+        let mut line = None;
+        for b in input {
+            if let Some(l) = editor.feed_byte(*b, &mut echo) {
+                line = Some(l);
+            }
+        }
+        (line, echo)
+    }
+
+    fn editor() -> LineEditor {
+        let mut editor = LineEditor::new(4, 64);
+        editor.begin("> ", &mut String::new());
+        editor
+    }
+
+    #[test_case]
+    fn typing_and_enter_returns_the_line() {
+        let mut e = editor();
+        let (line, echo) = feed(&mut e, b"ls\r\n");
+        assert_eq!(line.as_deref(), Some("ls"));
+        assert_eq!(echo, "ls\r\n");
+        // \r\nの\nで空行が確定しない
+        assert_eq!(feed(&mut e, b"").0, None);
+        assert_eq!(e.line(), "");
+    }
+
+    #[test_case]
+    fn insert_in_the_middle_redraws_the_tail() {
+        let mut e = editor();
+        let (_, echo) = feed(&mut e, b"hllo\x1b[D\x1b[D\x1b[De");
+        assert_eq!(e.line(), "hello");
+        assert_eq!(e.cursor(), 2);
+        assert_eq!(echo, "hllo\x1b[1D\x1b[1D\x1b[1Dello\x1b[3D");
+    }
+
+    #[test_case]
+    fn backspace_and_delete_in_the_middle() {
+        let mut e = editor();
+        let (_, echo) = feed(&mut e, b"abcd\x1b[D\x1b[D\x7f");
+        assert_eq!(e.line(), "acd");
+        assert_eq!(echo, "abcd\x1b[1D\x1b[1D\x1b[1Dcd\x1b[K\x1b[2D");
+        let (_, echo) = feed(&mut e, b"\x1b[3~");
+        assert_eq!(e.line(), "ad");
+        assert_eq!(echo, "d\x1b[K\x1b[1D");
+        assert_eq!(e.cursor(), 1);
+    }
+
+    #[test_case]
+    fn home_end_and_ctrl_keys_move_the_cursor() {
+        let mut e = editor();
+        feed(&mut e, b"abc\x01");
+        assert_eq!(e.cursor(), 0);
+        let (_, echo) = feed(&mut e, b"\x05");
+        assert_eq!(e.cursor(), 3);
+        assert_eq!(echo, "\x1b[3C");
+        feed(&mut e, b"\x1b[H");
+        assert_eq!(e.cursor(), 0);
+        feed(&mut e, b"\x1b[4~\x02");
+        assert_eq!(e.cursor(), 2);
+    }
+
+    #[test_case]
+    fn kill_and_yank() {
+        let mut e = editor();
+        feed(&mut e, b"echo foo bar\x17");
+        assert_eq!(e.line(), "echo foo ");
+        feed(&mut e, b"\x01\x19");
+        assert_eq!(e.line(), "barecho foo ");
+        feed(&mut e, b"\x0b");
+        assert_eq!(e.line(), "bar");
+        feed(&mut e, b"\x15");
+        assert_eq!(e.line(), "");
+        feed(&mut e, b"\x19");
+        assert_eq!(e.line(), "bar");
+    }
+
+    #[test_case]
+    fn history_is_navigated_with_arrows() {
+        let mut e = editor();
+        feed(&mut e, b"one\rtwo\rtwo\r\r");
+        assert!(e.history().eq(["one", "two"]));
+        feed(&mut e, b"th");
+        let (_, echo) = feed(&mut e, b"\x1b[A");
+        assert_eq!(e.line(), "two");
+        assert_eq!(echo, "\r> two\x1b[K");
+        feed(&mut e, b"\x1b[A\x1b[A");
+        assert_eq!(e.line(), "one");
+        feed(&mut e, b"\x1b[B\x1b[B");
+        assert_eq!(e.line(), "th");
+        let (line, _) = feed(&mut e, b"\x1b[A\r");
+        assert_eq!(line.as_deref(), Some("two"));
+        // 古いものから捨てる
+        feed(&mut e, b"a\rb\rc\rd\r");
+        assert!(e.history().eq(["a", "b", "c", "d"]));
+    }
+
+    #[test_case]
+    fn long_lines_scroll_instead_of_wrapping() {
+        let mut e = LineEditor::new(4, 64);
+        e.set_width(10);
+        e.begin("> ", &mut String::new());
+        // プロンプトの後ろに7桁まで表示できる
+        let (_, echo) = feed(&mut e, b"abcdefg");
+        assert_eq!(echo, "abcdefg");
+        let (_, echo) = feed(&mut e, b"h");
+        assert_eq!(echo, "\r> bcdefgh\x1b[K");
+        let (_, echo) = feed(&mut e, b"\x01");
+        assert_eq!(echo, "\r> abcdefg\x1b[K\x1b[7D");
+        // 表示は端末の幅を超えない
+        for line in echo.split('\r') {
+            let visible = line.split('\x1b').next().unwrap_or_default();
+            assert!(visible.len() < 10);
+        }
+        let (line, _) = feed(&mut e, b"\x05\r");
+        assert_eq!(line.as_deref(), Some("abcdefgh"));
+    }
+
+    #[test_case]
+    fn input_beyond_max_len_is_dropped() {
+        let mut e = LineEditor::new(4, 3);
+        e.begin("> ", &mut String::new());
+        let (line, echo) = feed(&mut e, b"abcdef\r");
+        assert_eq!(line.as_deref(), Some("abc"));
+        assert_eq!(echo, "abc\r\n");
+    }
+
+    #[test_case]
+    fn keyboard_events_are_edit_keys() {
+        let mut e = editor();
+        let mut echo = String::new();
+        let key = |code, ch| KeyEvent {
+            code,
+            ch,
+            pressed: true,
+        };
+        e.feed_key(&key(KeyCode::Char('a'), Some('a')), &mut echo);
+        e.feed_key(&key(KeyCode::Char('b'), Some('b')), &mut echo);
+        e.feed_key(&key(KeyCode::Left, None), &mut echo);
+        e.feed_key(&key(KeyCode::Char('x'), Some('x')), &mut echo);
+        // 離したときのイベントは無視する
+        let released = KeyEvent {
+            pressed: false,
+            ..key(KeyCode::Char('y'), Some('y'))
+        };
+        e.feed_key(&released, &mut echo);
+        assert_eq!(e.line(), "axb");
+        // Ctrl-Aはキーボードでも行頭に戻る
+        e.feed_key(&key(KeyCode::Char('a'), Some('\x01')), &mut echo);
+        assert_eq!(e.cursor(), 0);
+        let line = e.feed_key(&key(KeyCode::Enter, Some('\n')), &mut echo);
+        assert_eq!(line.as_deref(), Some("axb"));
+    }
+}
@@ -1,32 +1,53 @@
 #![no_std]
 #![no_main]
 
-use core::fmt::Write;
 use core::panic::PanicInfo;
-use core::writeln;
+use wasabi::allocator::ALLOCATOR;
+use wasabi::cursor_blink;
 use wasabi::error;
+#[cfg(feature = "keypress_demo")]
+use wasabi::executor::Executor;
 use wasabi::graphics::draw_test_pattern;
 use wasabi::graphics::fill_rect;
 use wasabi::graphics::Bitmap;
 use wasabi::info;
 use wasabi::init::init_basic_runtime;
+use wasabi::init::BootInfo;
+use wasabi::page_alloc::IDLE_ZERO_PAGES;
+use wasabi::page_alloc::PAGE_ALLOCATOR;
+use wasabi::panic::halt_or_reboot;
+use wasabi::panic::report_panic;
 use wasabi::print::hexdump;
+use wasabi::print::register_sink;
+use wasabi::print::vram_console_sink;
 use wasabi::println;
-use wasabi::qemu::exit_qemu;
+#[cfg(feature = "keypress_demo")]
+use wasabi::ps2::read_key_async;
+use wasabi::ps2::try_read_mouse;
 use wasabi::qemu::QemuExitCode;
-use wasabi::uefi::init_vram;
+use wasabi::serial::switch_to_sync_tx;
+use wasabi::serial::SerialPort;
+use wasabi::serial::TxOverflowPolicy;
+use wasabi::shell;
+use wasabi::task::num_tasks;
+use wasabi::task::spawn;
+use wasabi::task::yield_now;
+use wasabi::uefi::init_con_out;
 use wasabi::uefi::locate_loaded_image_protocol;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiMemoryType;
 use wasabi::uefi::EfiSystemTable;
-use wasabi::uefi::VramTextWriter;
 use wasabi::warn;
-use wasabi::x86::hlt;
-use wasabi::x86::init_exceptions;
+use wasabi::watchdog;
+use wasabi::x86::hlt_with_interrupts;
 use wasabi::x86::trigger_debug_interrupt;
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
+    // 画面やシリアルの初期化に失敗しても見えるように、まずファームウェアのコンソールに出力する
+    init_con_out(efi_system_table);
+    // COM1がなければ、以降の出力はVRAMに切り替わる（init_basic_runtimeで設定）
+    let _ = SerialPort::new_for_com1().init();
     println!("Booting WasabiOS...");
     println!("image_handle: {:#018X}", image_handle);
     println!("efi_system_table: {:#p}", efi_system_table);
@@ -39,35 +60,39 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     error!("error");
     hexdump(efi_system_table);
 
-    let mut vram = init_vram(efi_system_table).expect("init_vram failed");
+    init_basic_runtime(image_handle, efi_system_table, kernel_main)
+}
+
+// init_basic_runtimeが自前のカーネルスタックに切り替えてから呼ぶ
+fn kernel_main(boot_info: BootInfo) -> ! {
+    info!("{boot_info:?}");
 
+    let mut vram = boot_info.vram();
     let vw = vram.width();
     let vh = vram.height();
     fill_rect(&mut vram, 0x000000, 0, 0, vw, vh).expect("fill_rect failed");
     draw_test_pattern(&mut vram);
 
-    let mut w = VramTextWriter::new(&mut vram);
-
-    let memory_map = init_basic_runtime(image_handle, efi_system_table);
+    // 割り込みが使えるようになったので、ログの送信を待たずに処理を進める
+    if let Err(e) = SerialPort::new_for_com1().enable_buffered_tx(TxOverflowPolicy::Block) {
+        warn!("Buffered serial transmit is not available: {e}");
+    }
+    // 画面が使えるようになったので、以降の出力はシリアルと画面の両方に出す
+    register_sink(vram_console_sink()).expect("register_sink failed");
 
-    let mut total_memory_pages = 0;
-    for e in memory_map.iter() {
-        if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
-            continue;
+    let memory_regions = boot_info.memory_regions();
+    for r in memory_regions.iter() {
+        if r.kind == EfiMemoryType::CONVENTIONAL_MEMORY {
+            println!("{:?}", r);
         }
-        total_memory_pages += e.number_of_pages();
-        writeln!(w, "{:?}", e).unwrap();
     }
+    let total_memory_pages = memory_regions.total_pages(EfiMemoryType::CONVENTIONAL_MEMORY);
     // 4096は1ページのサイズ
     // 1024で割ると1KiBでさらに1024で割ると1MiB
     let total_memory_size_mib = total_memory_pages * 4096 / 1024 / 1024;
-    writeln!(
-        w,
-        "Total: {total_memory_pages} pages = {total_memory_size_mib} MiB"
-    )
-    .unwrap();
+    println!("Total: {total_memory_pages} pages = {total_memory_size_mib} MiB");
 
-    writeln!(w, "Hello, Non-UEFI world!").unwrap();
+    println!("Hello, Non-UEFI world!");
 
     println!();
     let cr3 = wasabi::x86::read_cr3();
@@ -86,18 +111,95 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     // let t = t.and_then(|t| t.next_level(0));
     // println!("{t:?}");
 
-    let (_gdt, _idt) = init_exceptions();
-    info!("Exception initialized!");
     trigger_debug_interrupt();
     info!("Execution continued.");
 
+    // 2つのタスクが交互に実行されるデモ
+    spawn(counter_task_a).expect("spawn failed");
+    spawn(counter_task_b).expect("spawn failed");
+    while num_tasks() > 1 {
+        yield_now();
+    }
+    info!("Heap: {}", ALLOCATOR.stats());
+
+    // シリアルからコマンドを入力して中の様子を調べるシェル（exitで次のデモに進む）
+    shell::run(&boot_info);
+    let serial = SerialPort::new_for_com1();
+
+    // キー入力をasyncで待つデモ（待っている間はhltで休む）
+    // キーが押されるまで先に進まないので、画面のないQEMUで止まらないようにkeypress_demoの時だけ動かす
+    #[cfg(feature = "keypress_demo")]
+    {
+        let mut executor = Executor::new();
+        executor
+            .spawn(async {
+                info!("Press any key to continue...");
+                loop {
+                    let key = read_key_async().await;
+                    if key.pressed {
+                        info!("Key pressed: {key:?}");
+                        break;
+                    }
+                }
+            })
+            .expect("spawn failed");
+        executor.run();
+    }
+
+    // マウスを動かすと十字のカーソルが動くデモ
+    // このループはずっと回り続けるので、止まっていないかをウォッチドッグで見張る
+    watchdog::arm();
+    let mut x = vw / 2;
+    let mut y = vh / 2;
+    draw_crosshair(&mut vram, x, y, CROSSHAIR_COLOR);
     loop {
-        hlt();
+        watchdog::pet();
+        while let Some(e) = try_read_mouse() {
+            draw_crosshair(&mut vram, x, y, 0x000000);
+            x = (x + e.dx as i64).clamp(CROSSHAIR_RADIUS, vw - 1 - CROSSHAIR_RADIUS);
+            y = (y + e.dy as i64).clamp(CROSSHAIR_RADIUS, vh - 1 - CROSSHAIR_RADIUS);
+            let color = if e.buttons != 0 {
+                0xff0000
+            } else {
+                CROSSHAIR_COLOR
+            };
+            draw_crosshair(&mut vram, x, y, color);
+        }
+        // 送信割り込みが使えない場合に備えて、休む前に送信バッファを空にしておく
+        serial.flush();
+        cursor_blink::poll();
+        PAGE_ALLOCATOR.zero_free_pages(IDLE_ZERO_PAGES);
+        hlt_with_interrupts();
     }
 }
 
+fn counter_task_a() {
+    for i in 0..3 {
+        info!("task A: {i}");
+        yield_now();
+    }
+}
+
+fn counter_task_b() {
+    for i in 0..3 {
+        info!("task B: {i}");
+        yield_now();
+    }
+}
+
+const CROSSHAIR_RADIUS: i64 = 5;
+const CROSSHAIR_COLOR: u32 = 0xffff00;
+
+// (x, y)を中心に十字を描く
+fn draw_crosshair<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32) {
+    let r = CROSSHAIR_RADIUS;
+    let _ = fill_rect(buf, color, x - r, y, r * 2 + 1, 1);
+    let _ = fill_rect(buf, color, x, y - r, 1, r * 2 + 1);
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    error!("PANIC: {info:?}");
-    exit_qemu(QemuExitCode::Fail);
+    switch_to_sync_tx();
+    report_panic(info);
+    halt_or_reboot(QemuExitCode::Panicked)
 }
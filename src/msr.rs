@@ -0,0 +1,185 @@
+use crate::cpuid::cpu_info;
+use crate::result::Result;
+use crate::x86::read_msr;
+use crate::x86::write_msr;
+
+// よく使うMSRの番号
+const IA32_APIC_BASE: u32 = 0x1b;
+const IA32_PAT: u32 = 0x277;
+const IA32_EFER: u32 = 0xc000_0080;
+const IA32_FS_BASE: u32 = 0xc000_0100;
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+// MSRに触る前にCPUIDで対応しているかを確認する
+fn check(supported: bool, msg: &'static str) -> Result<()> {
+    if !cpu_info().has_msr() {
+        return Err("MSR is not supported");
+    }
+    if supported {
+        Ok(())
+    } else {
+        Err(msg)
+    }
+}
+
+// IA32_EFER（Extended Feature Enable Register）
+pub struct Efer;
+impl Efer {
+    const SCE: u64 = 1 << 0; // syscall/sysretを有効にする
+    const NXE: u64 = 1 << 11; // ページテーブルのNXビットを有効にする
+
+    pub fn read() -> Result<u64> {
+        check(cpu_info().has_long_mode(), "EFER is not supported")?;
+        Ok(unsafe { read_msr(IA32_EFER) })
+    }
+
+    fn update(bit: u64, enable: bool) -> Result<()> {
+        let value = Self::read()?;
+        let value = if enable { value | bit } else { value & !bit };
+        unsafe { write_msr(IA32_EFER, value) };
+        Ok(())
+    }
+
+    pub fn nxe() -> Result<bool> {
+        Ok(Self::read()? & Self::NXE != 0)
+    }
+    pub fn set_nxe(enable: bool) -> Result<()> {
+        check(cpu_info().has_nx(), "NX is not supported")?;
+        Self::update(Self::NXE, enable)
+    }
+
+    pub fn sce() -> Result<bool> {
+        Ok(Self::read()? & Self::SCE != 0)
+    }
+    pub fn set_sce(enable: bool) -> Result<()> {
+        check(cpu_info().has_syscall(), "syscall is not supported")?;
+        Self::update(Self::SCE, enable)
+    }
+}
+
+// IA32_APIC_BASE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApicBase {
+    value: u64,
+}
+impl ApicBase {
+    const BSP: u64 = 1 << 8; // このCPUがBSP（最初に起動したCPU）かどうか
+    const ENABLE: u64 = 1 << 11;
+    const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    pub fn read() -> Result<Self> {
+        check(cpu_info().has_apic(), "APIC is not supported")?;
+        Ok(Self {
+            value: unsafe { read_msr(IA32_APIC_BASE) },
+        })
+    }
+
+    // Local APICのMMIOレジスタの物理アドレス
+    pub fn base(&self) -> u64 {
+        self.value & Self::ADDR_MASK
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.value & Self::ENABLE != 0
+    }
+    pub fn is_bsp(&self) -> bool {
+        self.value & Self::BSP != 0
+    }
+
+    // Local APICを有効にする
+    pub fn enable(&self) -> Result<()> {
+        check(cpu_info().has_apic(), "APIC is not supported")?;
+        unsafe { write_msr(IA32_APIC_BASE, self.value | Self::ENABLE) };
+        Ok(())
+    }
+}
+
+// IA32_PAT（Page Attribute Table）
+// ページテーブルのエントリのPAT・PCD・PWTビットを3ビットの番号（PWTが最下位）として、
+// 番号ごとのメモリタイプを1バイトずつ並べたもの
+pub struct Pat;
+impl Pat {
+    pub const UNCACHEABLE: u8 = 0x00;
+    pub const WRITE_COMBINING: u8 = 0x01;
+    pub const WRITE_THROUGH: u8 = 0x04;
+    pub const WRITE_PROTECTED: u8 = 0x05;
+    pub const WRITE_BACK: u8 = 0x06;
+    pub const UNCACHED_MINUS: u8 = 0x07;
+
+    pub fn read() -> Result<u64> {
+        check(cpu_info().has_pat(), "PAT is not supported")?;
+        Ok(unsafe { read_msr(IA32_PAT) })
+    }
+    pub fn write(value: u64) -> Result<()> {
+        check(cpu_info().has_pat(), "PAT is not supported")?;
+        unsafe { write_msr(IA32_PAT, value) };
+        Ok(())
+    }
+
+    // valueのindex番のメモリタイプ
+    pub fn entry(value: u64, index: usize) -> u8 {
+        (value >> (index * 8)) as u8
+    }
+    // valueのindex番のメモリタイプをmemory_typeに変えた値
+    pub fn with_entry(value: u64, index: usize, memory_type: u8) -> u64 {
+        (value & !(0xff << (index * 8))) | ((memory_type as u64) << (index * 8))
+    }
+}
+
+// IA32_FS_BASE（FSセグメントのベースアドレス）
+pub struct FsBase;
+impl FsBase {
+    pub fn read() -> Result<u64> {
+        check(cpu_info().has_long_mode(), "FS_BASE is not supported")?;
+        Ok(unsafe { read_msr(IA32_FS_BASE) })
+    }
+    pub fn write(value: u64) -> Result<()> {
+        check(cpu_info().has_long_mode(), "FS_BASE is not supported")?;
+        unsafe { write_msr(IA32_FS_BASE, value) };
+        Ok(())
+    }
+}
+
+// IA32_GS_BASE（GSセグメントのベースアドレス）
+pub struct GsBase;
+impl GsBase {
+    pub fn read() -> Result<u64> {
+        check(cpu_info().has_long_mode(), "GS_BASE is not supported")?;
+        Ok(unsafe { read_msr(IA32_GS_BASE) })
+    }
+    pub fn write(value: u64) -> Result<()> {
+        check(cpu_info().has_long_mode(), "GS_BASE is not supported")?;
+        unsafe { write_msr(IA32_GS_BASE, value) };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // テストはBSPで動いているので、BSPビットが立っているはず
+    #[test_case]
+    fn apic_base_is_bsp() {
+        let apic_base = ApicBase::read().expect("Failed to read IA32_APIC_BASE");
+        assert!(apic_base.is_bsp());
+        assert_eq!(apic_base.base() & 0xfff, 0);
+    }
+
+    #[test_case]
+    fn pat_entries_can_be_replaced() {
+        let value = 0x0007_0406_0007_0406;
+        assert_eq!(Pat::entry(value, 0), Pat::WRITE_BACK);
+        assert_eq!(Pat::entry(value, 2), Pat::UNCACHED_MINUS);
+        let value = Pat::with_entry(value, 1, Pat::WRITE_COMBINING);
+        assert_eq!(value, 0x0007_0406_0007_0106);
+    }
+
+    // FS_BASEは今は使っていないので、書いた値が読めることを確認して元に戻す
+    #[test_case]
+    fn fs_base_roundtrip() {
+        let saved = FsBase::read().expect("Failed to read FS_BASE");
+        FsBase::write(0x0000_1234_5678_9000).expect("Failed to write FS_BASE");
+        assert_eq!(FsBase::read(), Ok(0x0000_1234_5678_9000));
+        FsBase::write(saved).expect("Failed to restore FS_BASE");
+    }
+}
@@ -0,0 +1,595 @@
+use crate::paging::PAGE_SIZE;
+use crate::reserve;
+use crate::result::Result;
+use crate::sync::SpinLock;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::x86::fill_zero;
+use core::ops::Range;
+use core::ptr::null_mut;
+use core::ptr::write_bytes;
+
+// 4KiBの物理ページ（フレーム）を単位にメモリを管理するアロケータ
+// 1フレームを1ビットで表すビットマップを使い、ビットが1なら使用中とする
+// ヒープ（ALLOCATOR）もページテーブルも、ここからページを受け取って使う
+// 空いているページのうち0で埋めたことが分かっているものは、もう1つのビットマップで覚えておき、
+// 0で埋めたページを求められたときに埋め直さずに渡す（手が空いたときにzero_free_pagesで埋めておく）
+
+struct FrameBitmap {
+    words: *mut u64,
+    // 1なら中身が全て0のフレーム（空いている間だけ意味を持ち、解放したときに0に戻す）
+    zero: *mut u64,
+    // ビットマップの最初のビットが表すフレームの番号
+    base_frame: usize,
+    frames: usize,
+    free: usize,
+    // 次に空きを探し始める位置（ビットの番号）
+    hint: usize,
+    // 次に0で埋める空きフレームを探し始める位置
+    zero_hint: usize,
+}
+// ビットマップのページはこのアロケータからしか触らない
+unsafe impl Send for FrameBitmap {}
+impl FrameBitmap {
+    const fn empty() -> Self {
+        Self {
+            words: null_mut(),
+            zero: null_mut(),
+            base_frame: 0,
+            frames: 0,
+            free: 0,
+            hint: 0,
+            zero_hint: 0,
+        }
+    }
+
+    fn is_used(&self, i: usize) -> bool {
+        unsafe { *self.words.add(i / 64) & (1 << (i % 64)) != 0 }
+    }
+
+    fn is_zero(&self, i: usize) -> bool {
+        unsafe { *self.zero.add(i / 64) & (1 << (i % 64)) != 0 }
+    }
+
+    fn set_zero(&mut self, i: usize, zero: bool) {
+        let word = unsafe { &mut *self.zero.add(i / 64) };
+        if zero {
+            *word |= 1 << (i % 64);
+        } else {
+            *word &= !(1 << (i % 64));
+        }
+    }
+
+    // [start, start + n)のビットをusedにして、状態が変わったフレームの数を返す
+    fn set_range(&mut self, start: usize, n: usize, used: bool) -> usize {
+        let mut changed = 0;
+        for i in start..start + n {
+            if self.is_used(i) == used {
+                continue;
+            }
+            unsafe { *self.words.add(i / 64) ^= 1 << (i % 64) };
+            changed += 1;
+        }
+        if used {
+            self.free -= changed;
+        } else {
+            self.free += changed;
+        }
+        changed
+    }
+
+    // [start, end)に含まれるフレーム全体を空きにする（端の欠けたフレームは使わない）
+    fn add_free_range(&mut self, start: usize, end: usize) {
+        let first = start.div_ceil(PAGE_SIZE).max(self.base_frame);
+        let last = (end / PAGE_SIZE).min(self.base_frame + self.frames);
+        if first < last {
+            self.set_range(first - self.base_frame, last - first, false);
+        }
+    }
+
+    fn reserve_range(&mut self, start: usize, end: usize) {
+        let first = (start / PAGE_SIZE).max(self.base_frame);
+        let last = end.div_ceil(PAGE_SIZE).min(self.base_frame + self.frames);
+        if first < last {
+            self.set_range(first - self.base_frame, last - first, true);
+        }
+    }
+
+    // fromから順に、n個続いた空きフレームを探す
+    fn find_run(&self, from: usize, to: usize, n: usize) -> Option<usize> {
+        let mut run_start = from;
+        let mut i = from;
+        while i < to {
+            // 全て使用中のワードは飛ばす
+            if i.is_multiple_of(64)
+                && i + 64 <= to
+                && unsafe { *self.words.add(i / 64) } == u64::MAX
+            {
+                i += 64;
+                run_start = i;
+                continue;
+            }
+            if self.is_used(i) {
+                run_start = i + 1;
+            } else if i + 1 - run_start == n {
+                return Some(run_start);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    fn alloc(&mut self, n: usize) -> Option<usize> {
+        if n == 0 || n > self.free {
+            return None;
+        }
+        let start = self
+            .find_run(self.hint, self.frames, n)
+            .or_else(|| self.find_run(0, self.frames, n))?;
+        self.set_range(start, n, true);
+        self.hint = start + n;
+        Some(self.frame_addr(start))
+    }
+
+    // n個続いた空きフレームを、先頭のフレーム番号がalignの倍数で、
+    // 最後のフレームがlimit_frameより前に収まるように探して確保する
+    fn alloc_constrained(&mut self, n: usize, align: usize, limit_frame: usize) -> Option<usize> {
+        if n == 0 || n > self.free {
+            return None;
+        }
+        let to = limit_frame.saturating_sub(self.base_frame).min(self.frames);
+        let align_up = |i: usize| (self.base_frame + i).next_multiple_of(align) - self.base_frame;
+        let mut start = align_up(0);
+        while start + n <= to {
+            // 途中に使用中のフレームがあれば、その次の揃った位置から探し直す
+            match (start..start + n).rev().find(|i| self.is_used(*i)) {
+                Some(used) => start = align_up(used + 1),
+                None => {
+                    self.set_range(start, n, true);
+                    return Some(self.frame_addr(start));
+                }
+            }
+        }
+        None
+    }
+
+    // 一番長く続いている空きフレームを、max個までまとめて確保する
+    fn alloc_largest_run(&mut self, max: usize) -> Option<Range<usize>> {
+        let mut best = 0..0;
+        let mut run_start = 0;
+        for i in 0..=self.frames {
+            if i < self.frames && !self.is_used(i) {
+                continue;
+            }
+            if i - run_start > best.len() {
+                best = run_start..i;
+            }
+            run_start = i + 1;
+        }
+        if best.is_empty() || max == 0 {
+            return None;
+        }
+        let n = best.len().min(max);
+        self.set_range(best.start, n, true);
+        let start = self.frame_addr(best.start);
+        Some(start..start + n * PAGE_SIZE)
+    }
+
+    fn free(&mut self, addr: usize, n: usize) {
+        let frame = addr / PAGE_SIZE;
+        assert!(
+            addr.is_multiple_of(PAGE_SIZE)
+                && frame >= self.base_frame
+                && frame + n <= self.base_frame + self.frames,
+            "free_pages: {addr:#X} is not managed by the page allocator"
+        );
+        let start = frame - self.base_frame;
+        assert!(
+            self.set_range(start, n, false) == n,
+            "free_pages: {addr:#X} is already free"
+        );
+        // 使っている間に書き込まれたかもしれない
+        for i in start..start + n {
+            self.set_zero(i, false);
+        }
+        self.hint = self.hint.min(start);
+    }
+
+    // まだ0で埋めていない空きフレームを1つ探し、埋めている間に使われないように使用中にする
+    fn take_dirty_frame(&mut self) -> Option<usize> {
+        let num_words = self.frames.div_ceil(64);
+        // 範囲外のビットは使用中にしてあるので、ワードごとに調べればよい
+        let i = (0..num_words)
+            .map(|k| (self.zero_hint / 64 + k) % num_words)
+            .find_map(|w| {
+                let dirty = unsafe { !*self.words.add(w) & !*self.zero.add(w) };
+                (dirty != 0).then(|| w * 64 + dirty.trailing_zeros() as usize)
+            })?;
+        self.set_range(i, 1, true);
+        self.zero_hint = i + 1;
+        Some(i)
+    }
+
+    // take_dirty_frameで取り出して0で埋めたフレームを空きに戻す
+    fn put_back_zeroed(&mut self, i: usize) {
+        self.set_range(i, 1, false);
+        self.set_zero(i, true);
+    }
+
+    fn frame_addr(&self, i: usize) -> usize {
+        (self.base_frame + i) * PAGE_SIZE
+    }
+}
+
+pub struct PageAllocator {
+    bitmap: SpinLock<FrameBitmap>,
+}
+impl PageAllocator {
+    const fn new() -> Self {
+        Self {
+            bitmap: SpinLock::new(FrameBitmap::empty()),
+        }
+    }
+
+    // 空きメモリの範囲（free）を管理対象にする
+    // ビットマップ自身はfreeのうち十分な大きさのある範囲の先頭に置く
+    // reservedの範囲（APの起動コードやACPIのテーブルなど）は空きにしない
+    fn init_with_ranges(
+        &self,
+        free: impl Iterator<Item = Range<usize>> + Clone,
+        reserved: impl Iterator<Item = Range<usize>> + Clone,
+    ) -> Result<()> {
+        // アドレス0のページは、ヌルポインタと区別が付かないので使わない
+        let usable = free.map(|r| r.start.max(PAGE_SIZE)..r.end);
+        let base_frame = usable
+            .clone()
+            .filter(|r| !r.is_empty())
+            .map(|r| r.start / PAGE_SIZE)
+            .min()
+            .ok_or("PageAllocator: no free memory")?;
+        let end_frame = usable.clone().map(|r| r.end / PAGE_SIZE).max().unwrap_or(0);
+        let frames = end_frame - base_frame;
+        let bitmap_bytes = frames.div_ceil(64) * 8;
+        // 使用中のビットマップの後ろに、0で埋めたフレームのビットマップを置く
+        let both_bytes = bitmap_bytes * 2;
+        let bitmap_start = usable
+            .clone()
+            .map(|r| r.start.next_multiple_of(PAGE_SIZE)..r.end)
+            .flat_map(|r| {
+                // 取っておく範囲と重なるときは、その後ろに置けるか試す
+                let mut start = r.start;
+                while let Some(overlap) = reserved
+                    .clone()
+                    .find(|v| v.start < start + both_bytes && start < v.end)
+                {
+                    start = overlap.end.next_multiple_of(PAGE_SIZE);
+                }
+                (start + both_bytes <= r.end).then_some(start)
+            })
+            .next()
+            .ok_or("PageAllocator: no room for the bitmap")?;
+        let words = bitmap_start as *mut u64;
+        let zero = (bitmap_start + bitmap_bytes) as *mut u64;
+        // 最初は全て使用中にしておき、空きの範囲だけを空ける
+        // ファームウェアから受け取ったメモリは0とは限らない
+        unsafe {
+            write_bytes(words, 0xff, bitmap_bytes / 8);
+            write_bytes(zero, 0, bitmap_bytes / 8);
+        }
+        let mut bitmap = self.bitmap.lock();
+        *bitmap = FrameBitmap {
+            words,
+            zero,
+            base_frame,
+            frames,
+            free: 0,
+            hint: 0,
+            zero_hint: 0,
+        };
+        for r in usable {
+            bitmap.add_free_range(r.start, r.end);
+        }
+        for r in reserved {
+            bitmap.reserve_range(r.start, r.end);
+        }
+        bitmap.reserve_range(bitmap_start, bitmap_start + both_bytes);
+        Ok(())
+    }
+
+    // ブートローダから渡されたメモリマップのうち、CONVENTIONAL_MEMORYを管理対象にする
+    // reserve::reserve_regionで取っておいた範囲は空きにしない
+    // ヒープを初期化する前に、一度だけ呼ぶこと
+    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) -> Result<()> {
+        let free = memory_map
+            .iter()
+            .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+            .map(|e| {
+                let start = e.physical_start() as usize;
+                start..start + e.number_of_pages() as usize * PAGE_SIZE
+            });
+        let reserved = reserve::regions().map(|r| {
+            let range = r.range();
+            range.start as usize..range.end as usize
+        });
+        self.init_with_ranges(free, reserved)
+    }
+
+    // 連続したn枚のページを確保して、先頭のアドレスを返す（中身は0で埋めない）
+    pub fn alloc_pages(&self, n: usize) -> Result<*mut u8> {
+        self.bitmap
+            .lock()
+            .alloc(n)
+            .map(|addr| addr as *mut u8)
+            .ok_or("PageAllocator: out of memory")
+    }
+
+    // 連続したn枚の0で埋めたページを確保する
+    // 空いている間に0で埋めておいたページは、埋め直さずにそのまま渡す
+    pub fn alloc_zeroed_pages(&self, n: usize) -> Result<*mut u8> {
+        let start = self.alloc_pages(n)?;
+        self.zero_allocated(start, n);
+        Ok(start)
+    }
+
+    // DMAのバッファのように物理アドレスに制約があるときに使う
+    // 連続したn枚の0で埋めたページを、先頭がalignバイト（PAGE_SIZEの倍数）に揃い、
+    // 最後のバイトがmax_addr以下になるように確保する
+    pub fn alloc_pages_below(&self, n: usize, align: usize, max_addr: usize) -> Result<*mut u8> {
+        if !align.is_power_of_two() {
+            return Err("PageAllocator: alignment must be a power of two");
+        }
+        let align_frames = align.div_ceil(PAGE_SIZE);
+        let limit_frame = max_addr.saturating_add(1) / PAGE_SIZE;
+        let start =
+            self.bitmap
+                .lock()
+                .alloc_constrained(n, align_frames, limit_frame)
+                .ok_or("PageAllocator: no pages in the requested range")? as *mut u8;
+        self.zero_allocated(start, n);
+        Ok(start)
+    }
+
+    // 確保したn枚のページのうち、0で埋めたと分かっていないページを埋める
+    fn zero_allocated(&self, start: *mut u8, n: usize) {
+        for i in 0..n {
+            let page = unsafe { start.add(i * PAGE_SIZE) };
+            // 確保したページのビットは、解放するまで他からは書き換えられない
+            let is_zero = {
+                let bitmap = self.bitmap.lock();
+                bitmap.is_zero(page as usize / PAGE_SIZE - bitmap.base_frame)
+            };
+            if !is_zero {
+                unsafe { fill_zero(page, PAGE_SIZE) };
+            }
+        }
+    }
+
+    // まだ0で埋めていない空きページを、max_pages枚まで0で埋めておき、埋めた枚数を返す
+    // 休む前など、手が空いているときに呼ぶ
+    pub fn zero_free_pages(&self, max_pages: usize) -> usize {
+        let mut zeroed = 0;
+        while zeroed < max_pages {
+            let taken = {
+                let mut bitmap = self.bitmap.lock();
+                bitmap.take_dirty_frame().map(|i| (i, bitmap.frame_addr(i)))
+            };
+            let Some((i, addr)) = taken else {
+                break;
+            };
+            // 埋めている間はロックを外しておく
+            unsafe { fill_zero(addr as *mut u8, PAGE_SIZE) };
+            self.bitmap.lock().put_back_zeroed(i);
+            zeroed += 1;
+        }
+        zeroed
+    }
+
+    // このアロケータが管理できる物理アドレスの範囲（ビットマップが表す範囲）
+    pub fn managed_range(&self) -> Range<usize> {
+        let bitmap = self.bitmap.lock();
+        bitmap.base_frame * PAGE_SIZE..(bitmap.base_frame + bitmap.frames) * PAGE_SIZE
+    }
+
+    // 初期化した後で使えるようになったメモリ（ブートサービスのメモリなど）を空きに加え、空きになったページ数を返す
+    // rangeのうち管理できる範囲の外と、端の欠けたページは加えない
+    // 加えるページは、誰も使っていないこと
+    pub fn add_free_memory(&self, range: Range<usize>) -> usize {
+        let mut bitmap = self.bitmap.lock();
+        let before = bitmap.free;
+        bitmap.add_free_range(range.start, range.end);
+        bitmap.free - before
+    }
+
+    // 一番長く続いている空きページを、max_pages枚までまとめて確保する
+    pub fn alloc_largest_run(&self, max_pages: usize) -> Option<Range<usize>> {
+        self.bitmap.lock().alloc_largest_run(max_pages)
+    }
+
+    /// # Safety
+    /// ptrとnはalloc_pagesで確保したページの範囲であること
+    /// 解放した後はそのページを使わないこと
+    pub unsafe fn free_pages(&self, ptr: *mut u8, n: usize) {
+        self.bitmap.lock().free(ptr as usize, n);
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        self.bitmap.lock().free
+    }
+}
+
+pub static PAGE_ALLOCATOR: PageAllocator = PageAllocator::new();
+
+// 休む前に一度に0で埋める空きページの数（割り込みへの応答が遅れすぎないように少なめにする）
+pub const IDLE_ZERO_PAGES: usize = 16;
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::alloc::alloc;
+    use alloc::alloc::dealloc;
+    use alloc::alloc::Layout;
+
+    const ARENA_PAGES: usize = 64;
+    const ARENA_LAYOUT: Layout =
+        unsafe { Layout::from_size_align_unchecked(ARENA_PAGES * PAGE_SIZE, PAGE_SIZE) };
+
+    // ヒープから切り出したページの範囲を、空きメモリとして渡す
+    struct TestArena {
+        start: usize,
+    }
+    impl TestArena {
+        fn new() -> Self {
+            let start = unsafe { alloc(ARENA_LAYOUT) } as usize;
+            assert!(start != 0);
+            Self { start }
+        }
+        fn page(&self, i: usize) -> usize {
+            self.start + i * PAGE_SIZE
+        }
+    }
+    impl Drop for TestArena {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.start as *mut u8, ARENA_LAYOUT) };
+        }
+    }
+
+    #[test_case]
+    fn pages_are_allocated_and_freed() {
+        let arena = TestArena::new();
+        let pages = PageAllocator::new();
+        pages
+            .init_with_ranges(
+                core::iter::once(arena.page(0)..arena.page(ARENA_PAGES)),
+                core::iter::empty(),
+            )
+            .unwrap();
+        // 先頭のページはビットマップに使われている
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 1);
+        let a = pages.alloc_pages(1).unwrap() as usize;
+        let b = pages.alloc_pages(4).unwrap() as usize;
+        assert_eq!(a, arena.page(1));
+        assert_eq!(b, arena.page(2));
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 6);
+        unsafe { pages.free_pages(b as *mut u8, 4) };
+        // 空いた場所は、次に連続したページを探すときに再び使われる
+        let c = pages.alloc_pages(3).unwrap() as usize;
+        assert_eq!(c, b);
+        assert!(pages.alloc_pages(ARENA_PAGES).is_err());
+        assert!(pages.alloc_pages(0).is_err());
+        unsafe {
+            pages.free_pages(a as *mut u8, 1);
+            pages.free_pages(c as *mut u8, 3);
+        }
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 1);
+    }
+    #[test_case]
+    fn reserved_pages_and_holes_are_never_handed_out() {
+        let arena = TestArena::new();
+        let pages = PageAllocator::new();
+        pages
+            .init_with_ranges(
+                [
+                    arena.page(0)..arena.page(8),
+                    arena.page(16)..arena.page(ARENA_PAGES),
+                ]
+                .into_iter(),
+                [arena.page(0)..arena.page(2), arena.page(5)..arena.page(6)].into_iter(),
+            )
+            .unwrap();
+        // ビットマップは取っておく範囲の後ろに置かれる
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 8 - 4);
+        let run = pages.alloc_largest_run(usize::MAX).unwrap();
+        assert_eq!(run, arena.page(16)..arena.page(ARENA_PAGES));
+        // 取っておいたページをまたいで続けて確保することはない
+        assert!(pages.alloc_pages(3).is_err());
+        let first = pages.alloc_pages(2).unwrap() as usize;
+        let second = pages.alloc_pages(2).unwrap() as usize;
+        assert_eq!((first, second), (arena.page(3), arena.page(6)));
+        assert!(pages.alloc_pages(1).is_err());
+        unsafe {
+            pages.free_pages(first as *mut u8, 2);
+            pages.free_pages(second as *mut u8, 2);
+            pages.free_pages(run.start as *mut u8, run.len() / PAGE_SIZE);
+        }
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 8 - 4);
+    }
+    #[test_case]
+    fn alloc_zeroed_pages_skips_pages_zeroed_while_idle() {
+        let arena = TestArena::new();
+        unsafe { write_bytes(arena.page(0) as *mut u8, 0xaa, ARENA_PAGES * PAGE_SIZE) };
+        let pages = PageAllocator::new();
+        pages
+            .init_with_ranges(
+                core::iter::once(arena.page(0)..arena.page(ARENA_PAGES)),
+                core::iter::empty(),
+            )
+            .unwrap();
+        // 埋めていないページは確保するときに0で埋める
+        let p = pages.alloc_zeroed_pages(2).unwrap();
+        let bytes = unsafe { core::slice::from_raw_parts(p, 2 * PAGE_SIZE) };
+        assert!(bytes.iter().all(|b| *b == 0));
+        unsafe { pages.free_pages(p, 2) };
+        assert_eq!(pages.zero_free_pages(4), 4);
+        // 0で埋めたと覚えているページは埋め直さない（わざと書き換えて確かめる）
+        unsafe { write_bytes(arena.page(1) as *mut u8, 0x55, PAGE_SIZE) };
+        let q = pages.alloc_zeroed_pages(1).unwrap();
+        assert_eq!(q as usize, arena.page(1));
+        assert_eq!(unsafe { *q }, 0x55);
+        // 一度確保して解放したページは、また埋め直す
+        unsafe { pages.free_pages(q, 1) };
+        let q = pages.alloc_zeroed_pages(1).unwrap();
+        assert_eq!(unsafe { *q }, 0);
+        unsafe { pages.free_pages(q, 1) };
+        assert_eq!(pages.zero_free_pages(usize::MAX), ARENA_PAGES - 1 - 3);
+        assert_eq!(pages.zero_free_pages(usize::MAX), 0);
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 1);
+    }
+    #[test_case]
+    fn constrained_allocations_respect_alignment_and_limit() {
+        let arena = TestArena::new();
+        let pages = PageAllocator::new();
+        pages
+            .init_with_ranges(
+                core::iter::once(arena.page(0)..arena.page(ARENA_PAGES)),
+                core::iter::empty(),
+            )
+            .unwrap();
+        let align = 8 * PAGE_SIZE;
+        let p = pages.alloc_pages_below(3, align, usize::MAX).unwrap() as usize;
+        assert!(p.is_multiple_of(align));
+        // 揃った位置が使われていれば、次の揃った位置を使う
+        let q = pages.alloc_pages_below(1, align, usize::MAX).unwrap() as usize;
+        assert_eq!(q, p + align);
+        // 最後のバイトがmax_addrを超えるページは使わない
+        let limit = arena.page(16) - 1;
+        let r = pages.alloc_pages_below(4, PAGE_SIZE, limit).unwrap() as usize;
+        assert!(r + 4 * PAGE_SIZE - 1 <= limit);
+        assert!(pages.alloc_pages_below(16, PAGE_SIZE, limit).is_err());
+        assert!(pages
+            .alloc_pages_below(1, 3 * PAGE_SIZE, usize::MAX)
+            .is_err());
+        let bytes = unsafe { core::slice::from_raw_parts(r as *const u8, 4 * PAGE_SIZE) };
+        assert!(bytes.iter().all(|b| *b == 0));
+        unsafe {
+            pages.free_pages(p as *mut u8, 3);
+            pages.free_pages(q as *mut u8, 1);
+            pages.free_pages(r as *mut u8, 4);
+        }
+        assert_eq!(pages.free_page_count(), ARENA_PAGES - 1);
+    }
+    #[test_case]
+    fn page_allocator_manages_the_boot_memory() {
+        assert!(PAGE_ALLOCATOR.free_page_count() > 0);
+        let before = PAGE_ALLOCATOR.free_page_count();
+        let p = PAGE_ALLOCATOR.alloc_pages(2).unwrap();
+        assert!((p as usize).is_multiple_of(PAGE_SIZE));
+        // 確保したページには書き込める
+        unsafe {
+            write_bytes(p, 0xaa, 2 * PAGE_SIZE);
+            PAGE_ALLOCATOR.free_pages(p, 2);
+        }
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), before);
+    }
+}
@@ -0,0 +1,1374 @@
+extern crate alloc;
+
+use crate::cpuid::cpu_info;
+use crate::info;
+use crate::msr::ApicBase;
+use crate::msr::Efer;
+use crate::msr::Pat;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::result::Result;
+use crate::smp::current_cpu_id;
+use crate::smp::flush_requested_tlb;
+use crate::smp::shootdown_tlb;
+use crate::sync::SpinLock;
+use crate::sync::SpinLockGuard;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryRegions;
+use crate::uefi::VramBufferInfo;
+use crate::x86::busy_loop_hint;
+use crate::x86::invlpg;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::wbinvd;
+use crate::x86::write_cr0;
+use crate::x86::write_cr3;
+use crate::x86::InterruptGuard;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::ops::Range;
+use core::ptr::copy_nonoverlapping;
+use core::ptr::write_bytes;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+pub const PAGE_SIZE: usize = 4096; // ページサイズは4KB
+const ATTR_MASK: u64 = 0xFFF; // 右24bit全て1のマスク　（23 20 C0 BF 00 00 00 00 00 00 00 00 00 00 00 00）の時（23 20 C0 BF）を取りたい
+const ATTR_PRESENT: u64 = 1 << 0; // 内容が有効なエントリのbit
+const ATTR_WRITABLE: u64 = 1 << 1; // 書き込み可能かのbit
+const ATTR_WRITE_THROUGH: u64 = 1 << 3; // 書き込みキャッシュの挙動bit
+const ATTR_CACHE_DISABLE: u64 = 1 << 4; // キャッシュが有効かのbit
+const ATTR_PAGE_SIZE: u64 = 1 << 7; // L3/L2で立っていると1GB/2MBの大きなページを指すbit
+const ATTR_COW: u64 = 1 << 9; // ソフトウェアが自由に使えるbitで、コピーオンライトのページの印にする
+const ATTR_NO_EXECUTE: u64 = 1 << 63; // 実行禁止のbit（EFER.NXEが有効な時だけ使える）
+const ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000; // エントリの中の物理アドレスの部分
+
+const CR0_WP: u64 = 1 << 16; // カーネルでも読み込み専用ページへの書き込みを禁止する
+
+const LARGE_PAGE_SIZE_2M: u64 = 1 << 21;
+const LARGE_PAGE_SIZE_1G: u64 = 1 << 30;
+
+// 対応付けに使うページの大きさ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MapSize {
+    // 揃っている範囲には、使える一番大きなページ（1GB、2MB、4KBの順）を使う
+    // 既に細かいページテーブルがある場所は、その中を書き換える
+    #[default]
+    Auto,
+    // 全てこの大きさのページで対応付ける（アドレスと大きさがページの大きさに揃っていること）
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u64)]
+// ページ属性
+pub enum PageAttr {
+    NotPresent = 0,
+    ReadOnlyKernel = ATTR_PRESENT,
+    ReadOnlyKernelNoExec = ATTR_PRESENT | ATTR_NO_EXECUTE,
+    ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
+    ReadWriteKernelNoExec = ATTR_PRESENT | ATTR_WRITABLE | ATTR_NO_EXECUTE,
+    // PCDとPWTを立てるとPATの3番（UC）を使うので、デバイスのレジスタはキャッシュされない
+    ReadWriteIo =
+        ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE | ATTR_NO_EXECUTE,
+    // PWTだけを立てるとPATの1番を使う（init_patでWCにしておく）
+    ReadWriteWriteCombining = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_NO_EXECUTE,
+}
+
+// NXが有効になっているか
+// 有効でない時にNXビットを立てると予約ビット違反のページフォルトになるので、その場合は落とす
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// PATの1番をWCにできたか
+static WRITE_COMBINING_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// 1GBのページが使えるか（CPUIDで確かめる）
+static PAGE_1G_ENABLED: AtomicBool = AtomicBool::new(false);
+
+impl PageAttr {
+    fn entry_bits(self) -> u64 {
+        // WCが使えなければ、初期値のWTではなくUCにしておく
+        let bits = if self == Self::ReadWriteWriteCombining
+            && !WRITE_COMBINING_ENABLED.load(Ordering::Relaxed)
+        {
+            Self::ReadWriteIo as u64
+        } else {
+            self as u64
+        };
+        if NX_ENABLED.load(Ordering::Relaxed) {
+            bits
+        } else {
+            bits & !ATTR_NO_EXECUTE
+        }
+    }
+}
+
+// マップされているページに許されているアクセス
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageAccess {
+    pub writable: bool,
+    pub executable: bool,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+// 変換先の状態
+pub enum TranslationResult {
+    PageMapped4K { phys: u64 },
+    PageMapped2M { phys: u64 },
+    PageMapped1G { phys: u64 },
+}
+impl TranslationResult {
+    pub fn phys(&self) -> u64 {
+        match *self {
+            Self::PageMapped4K { phys }
+            | Self::PageMapped2M { phys }
+            | Self::PageMapped1G { phys } => phys,
+        }
+    }
+}
+
+// ページテーブル用の物理ページ（フレーム）の取得元
+pub trait FrameSource {
+    // 0で埋められた4KBの物理ページの先頭アドレスを返す
+    fn alloc_frame(&mut self) -> Result<u64>;
+}
+
+// ページアロケータから4KBのページをもらってフレームとして使う
+// UEFIがアイデンティティマップしているので、ページのアドレスがそのまま物理アドレスになる
+pub struct PageFrameSource;
+impl FrameSource for PageFrameSource {
+    fn alloc_frame(&mut self) -> Result<u64> {
+        let frame = PAGE_ALLOCATOR.alloc_pages(1)?;
+        unsafe { write_bytes(frame, 0, PAGE_SIZE) };
+        Ok(frame as u64)
+    }
+}
+
+#[repr(transparent)]
+pub struct Entry<const LEVEL: usize, const SHIFT: usize, NEXT> {
+    value: u64,
+    next_type: PhantomData<NEXT>,
+}
+impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
+    fn read_value(&self) -> u64 {
+        self.value
+    }
+    // 一番右のビットの判定
+    // 1ならこのエントリの内容が有効
+    fn is_present(&self) -> bool {
+        (self.read_value() & (1 << 0)) != 0
+    }
+
+    // 右から2番目のビットの判定
+    // 1なら書き込みアクセス可能
+    fn is_writable(&self) -> bool {
+        (self.read_value() & (1 << 1)) != 0
+    }
+
+    // 右から3番目のビットの判定
+    // 1ならユーザーランドからのアクセス可能
+    fn is_user(&self) -> bool {
+        (self.read_value() & (1 << 2)) != 0
+    }
+
+    // 次のテーブルではなく大きなページを直接指しているか
+    fn is_large_page(&self) -> bool {
+        (self.read_value() & ATTR_PAGE_SIZE) != 0
+    }
+
+    // コピーオンライトで共有している（書き込まれたらコピーする）ページか
+    fn is_cow(&self) -> bool {
+        (self.read_value() & ATTR_COW) != 0
+    }
+
+    // 最上位bitの判定
+    // 1ならこのページのコードは実行できない
+    fn is_no_execute(&self) -> bool {
+        (self.read_value() & ATTR_NO_EXECUTE) != 0
+    }
+
+    // エントリが指している物理アドレス
+    fn addr(&self) -> u64 {
+        self.read_value() & ADDR_MASK
+    }
+
+    // 最後のエントリの権限（途中のテーブルは全て許可している）
+    fn access(&self) -> PageAccess {
+        PageAccess {
+            writable: self.is_writable(),
+            executable: !self.is_no_execute() || !NX_ENABLED.load(Ordering::Relaxed),
+        }
+    }
+
+    // 出力フォーマットの設定
+    fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "L{}Entry @ {:#p} {{ {:#018X} {}{}{}{} ",
+            LEVEL,
+            self,
+            self.read_value(),
+            if self.is_present() { "P" } else { "N" },
+            if self.is_writable() { "W" } else { "R" },
+            if self.is_user() { "U" } else { "S" },
+            if self.is_no_execute() { "-" } else { "X" }
+        )?;
+        write!(f, " }}")
+    }
+
+    // 次のページテーブルを取得
+    fn table(&self) -> Result<&NEXT> {
+        if self.is_present() && !self.is_large_page() {
+            // マスクで属性のビットを無視したアドレスをとってきてそれをポインタにしてOkでラップ
+            Ok(unsafe { &*(self.addr() as *const NEXT) })
+        } else {
+            Err("Page Not Found")
+        }
+    }
+
+    // 書き換えるために次のページテーブルを取得
+    fn table_mut(&mut self) -> Result<&mut NEXT> {
+        if self.is_present() && !self.is_large_page() {
+            Ok(unsafe { &mut *(self.addr() as *mut NEXT) })
+        } else {
+            Err("Page Not Found")
+        }
+    }
+
+    // 次のページテーブルを取得（なければフレームを確保して作る）
+    fn populate(&mut self, frames: &mut impl FrameSource) -> Result<&mut NEXT> {
+        if !self.is_present() {
+            // 途中のテーブルは全て許可しておき、最後のエントリで権限を決める
+            self.value = frames.alloc_frame()? | ATTR_PRESENT | ATTR_WRITABLE;
+        } else if self.is_large_page() {
+            // 大きなページの一部だけを書き換えるときは、同じ対応の小さなページに分ける
+            self.split(frames)?;
+        }
+        Ok(unsafe { &mut *(self.addr() as *mut NEXT) })
+    }
+
+    // 大きなページを、同じ物理アドレスと属性を持つ次のレベルの512個のエントリに分ける
+    fn split(&mut self, frames: &mut impl FrameSource) -> Result<()> {
+        let table = frames.alloc_frame()?;
+        let mut bits = self.read_value() & !ADDR_MASK;
+        // 4KBのページのエントリではbit7はPATの選択に使うので落とす
+        if LEVEL == 2 {
+            bits &= !ATTR_PAGE_SIZE;
+        }
+        let step = 1u64 << (SHIFT - 9);
+        let entries = unsafe { &mut *(table as *mut [u64; 512]) };
+        for (i, e) in entries.iter_mut().enumerate() {
+            *e = (self.addr() + i as u64 * step) | bits;
+        }
+        self.value = table | ATTR_PRESENT | ATTR_WRITABLE;
+        Ok(())
+    }
+
+    // 大きなページで置き換えられるか（下に細かいページテーブルがあるときは置き換えない）
+    fn can_hold_large_page(&self) -> bool {
+        !self.is_present() || self.is_large_page()
+    }
+
+    // このエントリがphysからの大きなページ（L3なら1GB、L2なら2MB）を指すようにする
+    fn set_large_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
+        if phys & ((1 << SHIFT) - 1) != 0 {
+            return Err("phys is not aligned to the large page");
+        }
+        self.value = match attr {
+            PageAttr::NotPresent => 0,
+            _ => phys | attr.entry_bits() | ATTR_PAGE_SIZE,
+        };
+        Ok(())
+    }
+
+    // このエントリがphysのページを指すようにする
+    fn set_page(&mut self, phys: u64, attr: PageAttr) -> Result<()> {
+        if phys & ATTR_MASK != 0 {
+            return Err("phys is not aligned");
+        }
+        self.value = phys | attr.entry_bits();
+        Ok(())
+    }
+}
+
+// Displayトレイトの実装
+// 構造体や列挙型のto_stringが可能になる？
+impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.format(f)
+    }
+}
+
+// Debugトレイトの実装
+// println!("{:#?}")が使えるようになる？
+impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Debug for Entry<LEVEL, SHIFT, NEXT> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.format(f)
+    }
+}
+
+#[repr(align(4096))]
+// ページテーブル
+pub struct Table<const LEVEL: usize, const SHIFT: usize, NEXT> {
+    entry: [Entry<LEVEL, SHIFT, NEXT>; 512],
+}
+impl<const LEVEL: usize, const SHIFT: usize, NEXT> Table<LEVEL, SHIFT, NEXT> {
+    // 仮想アドレスのうち、このレベルのテーブルのインデックスになる9bitを取り出す
+    fn calc_index(addr: u64) -> usize {
+        ((addr >> SHIFT) & 0b1_1111_1111) as usize
+    }
+
+    fn entry_for(&self, addr: u64) -> &Entry<LEVEL, SHIFT, NEXT> {
+        &self.entry[Self::calc_index(addr)]
+    }
+
+    fn entry_for_mut(&mut self, addr: u64) -> &mut Entry<LEVEL, SHIFT, NEXT> {
+        &mut self.entry[Self::calc_index(addr)]
+    }
+}
+impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> Table<LEVEL, SHIFT, NEXT> {
+    // 出力フォーマット
+    fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "L{}Table @ {:#p} {{", LEVEL, self)?;
+        for (i, e) in self.entry.iter().enumerate() {
+            if !e.is_present() {
+                continue;
+            }
+            writeln!(f, " entry[{:3}] = {:?}", i, e)?;
+        }
+        writeln!(f, "}}")
+    }
+
+    // 次のテーブルを取得
+    pub fn next_level(&self, index: usize) -> Option<&NEXT> {
+        // entryから指定したindexのエントリーを取得してその中のページテーブルを取得
+        self.entry.get(index).and_then(|e| e.table().ok())
+    }
+}
+
+// Debugトレイトの実装
+impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> fmt::Debug
+    for Table<LEVEL, SHIFT, NEXT>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.format(f)
+    }
+}
+
+pub type PT = Table<1, 12, [u8; PAGE_SIZE]>; // Level1
+pub type PD = Table<2, 21, PT>; // Level2
+pub type PDPT = Table<3, 30, PD>; // Level3
+pub type PML4 = Table<4, 39, PDPT>; // Level4
+
+impl PML4 {
+    // 空のPML4を作る
+    // ページテーブルはカーネルが動いている間ずっと使うので、解放はしない
+    pub fn new(frames: &mut impl FrameSource) -> Result<&'static mut PML4> {
+        Ok(unsafe { &mut *(frames.alloc_frame()? as *mut PML4) })
+    }
+
+    // 仮想アドレスvirtの4KBページを物理アドレスphysに対応付ける
+    pub fn map_page(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        attr: PageAttr,
+        frames: &mut impl FrameSource,
+    ) -> Result<()> {
+        self.populate_page_entry(virt, frames)?.set_page(phys, attr)
+    }
+
+    // 仮想アドレスvirtの4KBページのエントリ（途中のテーブルがなければ作る）
+    fn populate_page_entry(
+        &mut self,
+        virt: u64,
+        frames: &mut impl FrameSource,
+    ) -> Result<&mut Entry<1, 12, [u8; PAGE_SIZE]>> {
+        let pdpt = self.entry_for_mut(virt).populate(frames)?;
+        let pd = pdpt.entry_for_mut(virt).populate(frames)?;
+        let pt = pd.entry_for_mut(virt).populate(frames)?;
+        Ok(pt.entry_for_mut(virt))
+    }
+
+    // 仮想アドレスvirtの4KBページのエントリ（途中のテーブルが既にあること）
+    fn page_entry_mut_or_empty(&mut self, virt: u64) -> Result<&mut Entry<1, 12, [u8; PAGE_SIZE]>> {
+        let pdpt = self.entry_for_mut(virt).table_mut()?;
+        let pd = pdpt.entry_for_mut(virt).table_mut()?;
+        Ok(pd.entry_for_mut(virt).table_mut()?.entry_for_mut(virt))
+    }
+
+    // 仮想アドレスvirtをマップしている4KBページのエントリ
+    // マップされていないときや、大きなページでマップされているときはErr
+    fn page_entry_mut(&mut self, virt: u64) -> Result<&mut Entry<1, 12, [u8; PAGE_SIZE]>> {
+        let e = self.page_entry_mut_or_empty(virt)?;
+        if !e.is_present() {
+            return Err("Page Not Found");
+        }
+        Ok(e)
+    }
+
+    // 仮想アドレスvirtの2MBページを物理アドレスphysに対応付ける
+    // 既に4KBのページテーブルがあれば、何もせずにfalseを返す
+    fn map_page_2m(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        attr: PageAttr,
+        frames: &mut impl FrameSource,
+    ) -> Result<bool> {
+        let pdpt = self.entry_for_mut(virt).populate(frames)?;
+        let e = pdpt
+            .entry_for_mut(virt)
+            .populate(frames)?
+            .entry_for_mut(virt);
+        if !e.can_hold_large_page() {
+            return Ok(false);
+        }
+        e.set_large_page(phys, attr)?;
+        Ok(true)
+    }
+
+    // 仮想アドレスvirtの1GBページを物理アドレスphysに対応付ける
+    // 既に2MBや4KBのページテーブルがあれば、何もせずにfalseを返す
+    fn map_page_1g(
+        &mut self,
+        virt: u64,
+        phys: u64,
+        attr: PageAttr,
+        frames: &mut impl FrameSource,
+    ) -> Result<bool> {
+        let e = self
+            .entry_for_mut(virt)
+            .populate(frames)?
+            .entry_for_mut(virt);
+        if !e.can_hold_large_page() {
+            return Ok(false);
+        }
+        e.set_large_page(phys, attr)?;
+        Ok(true)
+    }
+
+    // [virt_start, virt_end)をphys_startから始まる物理アドレスに対応付ける
+    // sizeがMapSize::Autoなら、virtとphysが揃っている所は大きなページで対応付ける
+    pub fn create_mapping(
+        &mut self,
+        virt_start: u64,
+        virt_end: u64,
+        phys_start: u64,
+        attr: PageAttr,
+        size: MapSize,
+        frames: &mut impl FrameSource,
+    ) -> Result<()> {
+        if virt_start & ATTR_MASK != 0 || phys_start & ATTR_MASK != 0 {
+            return Err("Mapping is not page aligned");
+        }
+        let page_1g = PAGE_1G_ENABLED.load(Ordering::Relaxed);
+        match size {
+            MapSize::Size1G if !page_1g => return Err("1GB pages are not supported"),
+            MapSize::Size2M | MapSize::Size1G => {
+                let page = if size == MapSize::Size2M {
+                    LARGE_PAGE_SIZE_2M
+                } else {
+                    LARGE_PAGE_SIZE_1G
+                };
+                if (virt_start | virt_end | phys_start) & (page - 1) != 0 {
+                    return Err("Mapping is not aligned to the page size");
+                }
+            }
+            _ => {}
+        }
+        let mut virt = virt_start;
+        let mut phys = phys_start;
+        while virt < virt_end {
+            // virtとphysの両方が揃っていて、残りが収まる一番大きなページを選ぶ
+            let fits = |page: u64| (virt | phys) & (page - 1) == 0 && virt_end - virt >= page;
+            let mapped = match size {
+                MapSize::Size1G => {
+                    if !self.map_page_1g(virt, phys, attr, frames)? {
+                        return Err("Already mapped with smaller pages");
+                    }
+                    LARGE_PAGE_SIZE_1G
+                }
+                MapSize::Size2M => {
+                    if !self.map_page_2m(virt, phys, attr, frames)? {
+                        return Err("Already mapped with smaller pages");
+                    }
+                    LARGE_PAGE_SIZE_2M
+                }
+                MapSize::Auto
+                    if page_1g
+                        && fits(LARGE_PAGE_SIZE_1G)
+                        && self.map_page_1g(virt, phys, attr, frames)? =>
+                {
+                    LARGE_PAGE_SIZE_1G
+                }
+                MapSize::Auto
+                    if fits(LARGE_PAGE_SIZE_2M)
+                        && self.map_page_2m(virt, phys, attr, frames)? =>
+                {
+                    LARGE_PAGE_SIZE_2M
+                }
+                _ => {
+                    self.map_page(virt, phys, attr, frames)?;
+                    PAGE_SIZE as u64
+                }
+            };
+            virt += mapped;
+            phys += mapped;
+        }
+        Ok(())
+    }
+
+    // ページテーブルを辿って仮想アドレスを物理アドレスに変換する
+    pub fn translate(&self, virt: u64) -> Result<TranslationResult> {
+        let pdpt = self.entry_for(virt).table()?;
+        let e = pdpt.entry_for(virt);
+        if e.is_present() && e.is_large_page() {
+            return Ok(TranslationResult::PageMapped1G {
+                phys: e.addr() + (virt & ((1 << 30) - 1)),
+            });
+        }
+        let pd = e.table()?;
+        let e = pd.entry_for(virt);
+        if e.is_present() && e.is_large_page() {
+            return Ok(TranslationResult::PageMapped2M {
+                phys: e.addr() + (virt & ((1 << 21) - 1)),
+            });
+        }
+        let pt = e.table()?;
+        let e = pt.entry_for(virt);
+        if !e.is_present() {
+            return Err("Page Not Found");
+        }
+        Ok(TranslationResult::PageMapped4K {
+            phys: e.addr() + (virt & ATTR_MASK),
+        })
+    }
+
+    // 仮想アドレスvirtのページに許されているアクセスを調べる
+    pub fn access(&self, virt: u64) -> Result<PageAccess> {
+        let pdpt = self.entry_for(virt).table()?;
+        let e = pdpt.entry_for(virt);
+        if e.is_present() && e.is_large_page() {
+            return Ok(e.access());
+        }
+        let pd = e.table()?;
+        let e = pd.entry_for(virt);
+        if e.is_present() && e.is_large_page() {
+            return Ok(e.access());
+        }
+        let e = e.table()?.entry_for(virt);
+        if !e.is_present() {
+            return Err("Page Not Found");
+        }
+        Ok(e.access())
+    }
+}
+
+// 自前で作ったページテーブル（0なら未初期化）
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+pub fn kernel_pml4() -> Option<&'static PML4> {
+    match KERNEL_PML4.load(Ordering::SeqCst) {
+        0 => None,
+        addr => Some(unsafe { &*(addr as *const PML4) }),
+    }
+}
+
+// 自前のページテーブルを作って切り替える
+// UEFIのページテーブルは使い終わったメモリに置かれている可能性があるので、アロケータの初期化後に呼ぶ
+// メモリマップに載っている領域は全てアイデンティティマップするので、
+// 今実行しているコード（LOADER_CODE）やスタック（BOOT_SERVICES_DATA）が消えることはない
+pub fn init_paging(memory_regions: &MemoryRegions, vram: &VramBufferInfo) -> Result<()> {
+    NX_ENABLED.store(Efer::set_nxe(true).is_ok(), Ordering::Relaxed);
+    PAGE_1G_ENABLED.store(cpu_info().has_1gb_pages(), Ordering::Relaxed);
+    WRITE_COMBINING_ENABLED.store(init_pat(), Ordering::Relaxed);
+    let mut frames = PageFrameSource;
+    let table = PML4::new(&mut frames)?;
+    for r in memory_regions.iter() {
+        let attr = match r.kind {
+            // カーネルのイメージとランタイムサービスのコードだけ実行可能にする
+            // （カーネルのイメージは、後でprotect_kernel_imageがセクションごとに権限を絞る）
+            EfiMemoryType::LOADER_CODE | EfiMemoryType::RUNTIME_SERVICES_CODE => {
+                PageAttr::ReadWriteKernel
+            }
+            EfiMemoryType::MEMORY_MAPPED_IO | EfiMemoryType::MEMORY_MAPPED_IO_PORT_SPACE => {
+                PageAttr::ReadWriteIo
+            }
+            _ => PageAttr::ReadWriteKernelNoExec,
+        };
+        // 大きな領域（ヒープになる空きメモリなど）は大きなページでマップして、TLBの消費を減らす
+        table.create_mapping(r.start, r.end(), r.start, attr, MapSize::Auto, &mut frames)?;
+    }
+    // フレームバッファはWCにして、書き込みをまとめてから送る（fill_rectなどが速くなる）
+    if vram.buf_size() != 0 {
+        let vram_start = vram.buf_addr() & !ATTR_MASK;
+        let vram_end = (vram.buf_addr() + vram.buf_size() + ATTR_MASK) & !ATTR_MASK;
+        table.create_mapping(
+            vram_start,
+            vram_end,
+            vram_start,
+            PageAttr::ReadWriteWriteCombining,
+            MapSize::Auto,
+            &mut frames,
+        )?;
+    }
+    // Local APICのレジスタもメモリマップには載っていないので追加する
+    if let Ok(apic_base) = ApicBase::read() {
+        let base = apic_base.base();
+        table.map_page(base, base, PageAttr::ReadWriteIo, &mut frames)?;
+    }
+    KERNEL_PML4.store(table as *mut PML4 as u64, Ordering::SeqCst);
+    unsafe {
+        write_cr3(table);
+        // 書き込み禁止のページはカーネルからも書き込めないようにする
+        write_cr0(read_cr0() | CR0_WP);
+    }
+    info!(
+        "Paging: switched to our own page table @ {:#018X} (NX: {}, WC: {}, 1GB pages: {})",
+        table as *const PML4 as u64,
+        NX_ENABLED.load(Ordering::Relaxed),
+        WRITE_COMBINING_ENABLED.load(Ordering::Relaxed),
+        PAGE_1G_ENABLED.load(Ordering::Relaxed)
+    );
+    Ok(())
+}
+
+// このCPUのPATの1番（PWTだけを立てたエントリ）を、初期値のWTからWCに変え、変えられたかを返す
+// 他の番号は初期値のまま（0番はWB、3番はUC）なので、既存のマッピングのメモリタイプは変わらない
+// MTRRはファームウェアの設定のまま使う（フレームバッファがMTRRでUCになっていても、PATのWCが優先される）
+// 全てのCPUで同じ値にしないといけないので、APも起動したときに呼ぶ
+pub fn init_pat() -> bool {
+    let Ok(pat) = Pat::read() else {
+        return false;
+    };
+    let _guard = InterruptGuard::new();
+    // 古いメモリタイプでキャッシュされた内容とTLBを捨ててから使う
+    wbinvd();
+    if Pat::write(Pat::with_entry(pat, 1, Pat::WRITE_COMBINING)).is_err() {
+        return false;
+    }
+    wbinvd();
+    unsafe { write_cr3(read_cr3()) };
+    true
+}
+
+pub fn write_combining_enabled() -> bool {
+    WRITE_COMBINING_ENABLED.load(Ordering::Relaxed)
+}
+
+// 書き換えるための自前のページテーブル
+// 書き換えている間は、呼び出し側でPAGE_TABLE_LOCKを持っておく（lock_kernel_pml4を使う）
+fn kernel_pml4_mut() -> Result<&'static mut PML4> {
+    let addr = KERNEL_PML4.load(Ordering::SeqCst);
+    if addr == 0 {
+        return Err("Paging is not initialized");
+    }
+    Ok(unsafe { &mut *(addr as *mut PML4) })
+}
+
+// 自前のページテーブルを書き換えるCPUを1つにするロック
+// 持っている間は割り込みが禁止され、書き換えたページのTLBシュートダウンもロックを持ったまま済ませる
+static PAGE_TABLE_LOCK: SpinLock<()> = SpinLock::new(());
+// PAGE_TABLE_LOCKを持っているCPUのLocal APIC ID
+// 持ったまま同じCPUでページフォルトが起きたときは、待っても取れないのでエラーにする
+static PAGE_TABLE_OWNER: AtomicU32 = AtomicU32::new(NO_PAGE_TABLE_OWNER);
+const NO_PAGE_TABLE_OWNER: u32 = u32::MAX;
+
+// PAGE_TABLE_LOCKを持った自前のページテーブル（手放す前に持ち主を消す）
+struct PageTableGuard {
+    table: &'static mut PML4,
+    _lock: SpinLockGuard<'static, ()>,
+}
+impl Drop for PageTableGuard {
+    fn drop(&mut self) {
+        PAGE_TABLE_OWNER.store(NO_PAGE_TABLE_OWNER, Ordering::SeqCst);
+    }
+}
+impl Deref for PageTableGuard {
+    type Target = PML4;
+    fn deref(&self) -> &PML4 {
+        self.table
+    }
+}
+impl DerefMut for PageTableGuard {
+    fn deref_mut(&mut self) -> &mut PML4 {
+        self.table
+    }
+}
+
+fn page_table_locked_by_this_cpu() -> bool {
+    PAGE_TABLE_OWNER.load(Ordering::SeqCst) == current_cpu_id()
+}
+
+// PAGE_TABLE_LOCKを取って、自前のページテーブルを書き換えられるようにする
+// ロックを持っているCPUは他のCPUがTLBを捨てるのを待っているかもしれないので、
+// 待っている間も自分に頼まれたTLBシュートダウンには応える
+fn lock_kernel_pml4() -> Result<PageTableGuard> {
+    let table = kernel_pml4_mut()?;
+    if page_table_locked_by_this_cpu() {
+        return Err("Page table is already locked by this CPU");
+    }
+    let lock = loop {
+        if let Some(lock) = PAGE_TABLE_LOCK.try_lock() {
+            break lock;
+        }
+        flush_requested_tlb();
+        busy_loop_hint();
+    };
+    PAGE_TABLE_OWNER.store(current_cpu_id(), Ordering::SeqCst);
+    Ok(PageTableGuard { table, _lock: lock })
+}
+
+// 自前のページテーブルで[virt_start, virt_end)をphys_startからの物理アドレスに対応付け直す
+fn remap(
+    virt_start: u64,
+    virt_end: u64,
+    phys_start: u64,
+    attr: PageAttr,
+    size: MapSize,
+) -> Result<()> {
+    let mut table = lock_kernel_pml4()?;
+    table.create_mapping(
+        virt_start,
+        virt_end,
+        phys_start,
+        attr,
+        size,
+        &mut PageFrameSource,
+    )?;
+    // 古い対応がTLBに残らないように、CR3を書き直して全て捨てる（他のCPUにも捨てさせる）
+    unsafe { write_cr3(&mut *table) };
+    let pages = ((virt_end - virt_start) as usize).div_ceil(PAGE_SIZE);
+    shootdown_tlb(virt_start, pages);
+    Ok(())
+}
+
+// 起動後に見つかったデバイスのレジスタ（HPETなど）を、キャッシュせずにアイデンティティマップする
+// 自前のページテーブルに切り替える前は、UEFIのページテーブルで全てマップされているので何もしない
+pub fn map_mmio(phys: u64, size: u64) -> Result<()> {
+    if kernel_pml4().is_none() {
+        return Ok(());
+    }
+    let start = phys & !ATTR_MASK;
+    let end = (phys + size + ATTR_MASK) & !ATTR_MASK;
+    remap(start, end, start, PageAttr::ReadWriteIo, MapSize::Auto)
+}
+
+// アイデンティティマップされている[start, end)の属性を変える（ロードしたプログラムの権限の設定など）
+// 自前のページテーブルに切り替える前は、UEFIのページテーブルで全て読み書き・実行できるので何もしない
+pub fn set_page_attr(start: u64, end: u64, attr: PageAttr) -> Result<()> {
+    if kernel_pml4().is_none() {
+        return Ok(());
+    }
+    remap(start, end, start, attr, MapSize::Auto)
+}
+
+// UEFIが読み込んだカーネルのイメージを、セクションごとの権限でマップし直す（W^X）
+// init_pagingの時点ではイメージ全体が読み書き・実行できるので、切り替えた後に一度だけ呼ぶ
+// 各セクションの範囲と属性を受け取り、マップし直したセクションの数を返す
+// セクションの後ろの端数は次のセクションの手前までなので、ページの境界まで広げる
+pub fn protect_kernel_image(
+    sections: impl Iterator<Item = (Range<u64>, PageAttr)>,
+) -> Result<usize> {
+    let mut count = 0;
+    for (range, attr) in sections.filter(|(range, _)| !range.is_empty()) {
+        if range.start & ATTR_MASK != 0 {
+            return Err("protect_kernel_image: sections are not page aligned");
+        }
+        let end = (range.end + ATTR_MASK) & !ATTR_MASK;
+        remap(range.start, end, range.start, attr, MapSize::Auto)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// 物理アドレスphysからのsizeバイトを、仮想アドレスvirtから見えるように対応付ける
+// physとvirtはページの境界に揃っていること（端数のページは丸ごと対応付ける）
+// 揃っている所は2MBや1GBの大きなページで対応付ける
+// attrにPageAttr::NotPresentを渡すと、対応を外す
+pub fn map_range(phys: u64, virt: u64, size: u64, attr: PageAttr) -> Result<()> {
+    map_range_with_size(phys, virt, size, attr, MapSize::Auto)
+}
+
+// map_rangeと同じだが、使うページの大きさをmap_sizeで決める
+// MapSize::Size2MとSize1Gでは、physとvirtとsizeがそのページの大きさに揃っていること
+pub fn map_range_with_size(
+    phys: u64,
+    virt: u64,
+    size: u64,
+    attr: PageAttr,
+    map_size: MapSize,
+) -> Result<()> {
+    if (phys | virt) & ATTR_MASK != 0 {
+        return Err("map_range: addresses must be page aligned");
+    }
+    let virt_end = virt
+        .checked_add(size)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE as u64))
+        .ok_or("map_range: range overflows")?;
+    remap(virt, virt_end, phys, attr, map_size)
+}
+
+// virtからのpages枚のページを、physからの物理ページに対応付け直す
+// CR3を書き直すremapと違い、書き換えたページのTLBだけをinvlpgで捨てるので、他のページのTLBは残る
+// 他のCPUにも同じページのTLBを捨てさせてから戻るので、戻った後は外した物理ページを解放してよい
+pub fn map_pages(virt: u64, phys: u64, pages: usize, attr: PageAttr) -> Result<()> {
+    let mut table = lock_kernel_pml4()?;
+    let mut result = Ok(());
+    let mut mapped = 0;
+    while mapped < pages {
+        let offset = (mapped * PAGE_SIZE) as u64;
+        result = table.map_page(virt + offset, phys + offset, attr, &mut PageFrameSource);
+        if result.is_err() {
+            break;
+        }
+        invlpg(virt + offset);
+        mapped += 1;
+    }
+    shootdown_tlb(virt, mapped);
+    result
+}
+
+// 仮想アドレスvirtの4KBページを物理アドレスphysのページに対応付ける
+pub fn map_frame(virt: u64, phys: u64, attr: PageAttr) -> Result<()> {
+    remap(virt, virt + PAGE_SIZE as u64, phys, attr, MapSize::Size4K)
+}
+
+// コピーオンライトで共有している物理ページと、コピーに使うために取っておいた物理ページ
+struct CowFrames {
+    // share_cowで共有した物理ページと、それをマップしている仮想アドレスの数
+    // 数が1になったページは、次に書き込まれたときにコピーせずにそのまま書き込めるようにする
+    refs: BTreeMap<u64, usize>,
+    // ページフォルトのハンドラの中ではアロケータを使わないように、コピー先は共有するときに確保しておく
+    // COWの印を付けたページごとに、マップしている数-1枚あれば、どの順に書き込まれても足りる
+    reserve: Vec<u64>,
+}
+// PAGE_TABLE_LOCKを持ってから取る
+static COW_FRAMES: SpinLock<CowFrames> = SpinLock::new(CowFrames {
+    refs: BTreeMap::new(),
+    reserve: Vec::new(),
+});
+
+// srcからのpages枚のページを、dstからも同じ物理ページで見えるようにする（コピーオンライト）
+// 書き込めるページは両方とも読み込み専用にしてCOWの印を付け、どちらかに書き込まれたときに
+// ページフォルトのハンドラ（handle_cow_fault）がそのページだけをコピーして付け替える
+// 読み込み専用のページは書き換えられないので、印を付けずにそのまま共有する
+// srcが大きなページでマップされていれば、同じ対応の4KBのページに分けてから共有する
+// dstはマップされていないこと
+// 共有した物理ページはこの仕組みが持つので、使い終わったら両方ともunshare_cowで対応を外すこと
+pub fn share_cow(src: u64, dst: u64, pages: usize) -> Result<()> {
+    if (src | dst) & ATTR_MASK != 0 {
+        return Err("share_cow: addresses must be page aligned");
+    }
+    let size = (pages * PAGE_SIZE) as u64;
+    if src < dst + size && dst < src + size {
+        return Err("share_cow: src and dst overlap");
+    }
+    let mut table = lock_kernel_pml4()?;
+    let mut cow = COW_FRAMES.lock();
+    // 途中で失敗しても一部だけ共有した状態が残らないように、対応を変える前に失敗しうることを全て済ませる
+    // （大きなページを分けたり途中のテーブルを作ったりしても、見えるものは変わらない）
+    let mut copies = 0;
+    for i in 0..pages as u64 {
+        let offset = i * PAGE_SIZE as u64;
+        let e = table.populate_page_entry(src + offset, &mut PageFrameSource)?;
+        if !e.is_present() {
+            return Err("share_cow: src is not mapped");
+        }
+        if e.is_writable() || e.is_cow() {
+            copies += 1;
+        }
+        if table
+            .populate_page_entry(dst + offset, &mut PageFrameSource)?
+            .is_present()
+        {
+            return Err("share_cow: dst is already mapped");
+        }
+    }
+    let reserved = cow.reserve.len();
+    for _ in 0..copies {
+        match PAGE_ALLOCATOR.alloc_pages(1) {
+            Ok(frame) => cow.reserve.push(frame as u64),
+            Err(e) => {
+                for frame in cow.reserve.drain(reserved..) {
+                    unsafe { PAGE_ALLOCATOR.free_pages(frame as *mut u8, 1) };
+                }
+                return Err(e);
+            }
+        }
+    }
+    for i in 0..pages as u64 {
+        let offset = i * PAGE_SIZE as u64;
+        let e = table.page_entry_mut(src + offset)?;
+        if e.is_writable() || e.is_cow() {
+            e.value = (e.read_value() & !ATTR_WRITABLE) | ATTR_COW;
+            invlpg(src + offset);
+        }
+        // 初めて共有するページは、src自身の分も数える
+        *cow.refs.entry(e.addr()).or_insert(1) += 1;
+        let value = e.read_value();
+        table.page_entry_mut_or_empty(dst + offset)?.value = value;
+        invlpg(dst + offset);
+    }
+    // 読み込み専用にしたsrcのページに、他のCPUが書き込めるままにならないようにする
+    shootdown_tlb(src, pages);
+    Ok(())
+}
+
+// share_cowで共有したvirtからのpages枚のページ（書き込まれてできたコピーも含む）の対応を外す
+// 物理ページは、それをマップしている最後のページの対応を外したときに解放する
+// 要らなくなったコピー用のページも解放する
+pub fn unshare_cow(virt: u64, pages: usize) -> Result<()> {
+    if virt & ATTR_MASK != 0 {
+        return Err("unshare_cow: virt must be page aligned");
+    }
+    let mut table = lock_kernel_pml4()?;
+    let mut cow = COW_FRAMES.lock();
+    // 一部だけ対応を外した状態で失敗しないように、先に全てマップされていることを確かめる
+    for i in 0..pages as u64 {
+        table.page_entry_mut(virt + i * PAGE_SIZE as u64)?;
+    }
+    let mut unmapped = Vec::with_capacity(pages);
+    for i in 0..pages as u64 {
+        let page = virt + i * PAGE_SIZE as u64;
+        let e = table.page_entry_mut(page)?;
+        unmapped.push((e.addr(), e.is_cow()));
+        e.value = 0;
+        invlpg(page);
+    }
+    // 他のCPUのTLBに残っている間は、物理ページを解放しない
+    shootdown_tlb(virt, pages);
+    let frames = &mut *cow;
+    for (phys, was_cow) in unmapped {
+        let last = match frames.refs.get_mut(&phys) {
+            Some(refs) if *refs > 1 => {
+                *refs -= 1;
+                // 残りのページがコピーする分が1枚減る
+                if let Some(spare) = was_cow.then(|| frames.reserve.pop()).flatten() {
+                    unsafe { PAGE_ALLOCATOR.free_pages(spare as *mut u8, 1) };
+                }
+                false
+            }
+            Some(_) => {
+                frames.refs.remove(&phys);
+                true
+            }
+            // 書き込まれてできたコピーは、このページだけが使っている
+            None => true,
+        };
+        if last {
+            unsafe { PAGE_ALLOCATOR.free_pages(phys as *mut u8, 1) };
+        }
+    }
+    Ok(())
+}
+
+// addrへの書き込みのページフォルトがコピーオンライトのページで起きたなら、書き込めるようにしてtrueを返す
+// まだ他のページと共有していれば、share_cowが取っておいた物理ページにコピーしてそちらに付け替える
+// 最後の1つなら、コピーせずにそのまま書き込めるようにする
+// フォルトしたコードがアロケータのロックを持っているかもしれないので、ここではアロケータを使わない
+pub fn handle_cow_fault(addr: u64) -> bool {
+    if KERNEL_PML4.load(Ordering::SeqCst) == 0 {
+        return false;
+    }
+    if page_table_locked_by_this_cpu() {
+        panic!("copy-on-write fault at CR2={addr:#018X} while updating the page table");
+    }
+    let Ok(mut table) = lock_kernel_pml4() else {
+        return false;
+    };
+    let mut cow = COW_FRAMES.lock();
+    let page = addr & !ATTR_MASK;
+    let Ok(e) = table.page_entry_mut(page) else {
+        return false;
+    };
+    if !e.is_cow() {
+        // 他のCPUが先に書き込めるようにしていたら、このCPUのTLBに古い対応が残っていただけ
+        if e.is_writable() {
+            invlpg(page);
+            return true;
+        }
+        return false;
+    }
+    let phys = e.addr();
+    let bits = (e.read_value() & !ADDR_MASK & !ATTR_COW) | ATTR_WRITABLE;
+    let frames = &mut *cow;
+    match frames.refs.get_mut(&phys) {
+        Some(refs) if *refs > 1 => {
+            let Some(copy) = frames.reserve.pop() else {
+                panic!("out of memory: no frame reserved to copy the page at CR2={addr:#018X}");
+            };
+            unsafe { copy_nonoverlapping(phys as *const u8, copy as *mut u8, PAGE_SIZE) };
+            e.value = copy | bits;
+            *refs -= 1;
+            invlpg(page);
+            // 他のCPUが古い物理ページを読み続けないように、付け替えたことを知らせる
+            shootdown_tlb(page, 1);
+        }
+        // 最後の1つは、数を1のまま残しておき、unshare_cowで対応を外すときに解放する
+        // 権限を広げるだけなので、他のCPUは古い対応でフォルトしたときに捨てればよい
+        _ => {
+            e.value = phys | bits;
+            invlpg(page);
+        }
+    }
+    true
+}
+
+// 今使っているページテーブルでvirtがマップされているか
+pub fn is_mapped(virt: u64) -> bool {
+    let table = match kernel_pml4() {
+        Some(table) => table,
+        None => unsafe { &*read_cr3() },
+    };
+    table.translate(virt).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::read_cr3;
+    use alloc::boxed::Box;
+
+    #[test_case]
+    fn cr3_points_to_kernel_pml4() {
+        let table = kernel_pml4().expect("paging is not initialized");
+        assert_eq!(read_cr3() as u64, table as *const PML4 as u64);
+    }
+
+    // スタック、コード、ヒープはアイデンティティマップされている（大きなページかもしれない）
+    #[test_case]
+    fn translate_identity_mapped() {
+        let table = kernel_pml4().expect("paging is not initialized");
+        let local = 0u64;
+        let stack_addr = &local as *const u64 as u64;
+        assert_eq!(
+            table.translate(stack_addr).map(|t| t.phys()),
+            Ok(stack_addr)
+        );
+        let code_addr = init_paging as *const () as u64;
+        assert_eq!(table.translate(code_addr).map(|t| t.phys()), Ok(code_addr));
+        let heap = Box::new(0u64);
+        let heap_addr = &*heap as *const u64 as u64;
+        assert_eq!(table.translate(heap_addr).map(|t| t.phys()), Ok(heap_addr));
+    }
+
+    // 同じ物理ページを別の仮想アドレスからも読み書きできる
+    #[test_case]
+    fn map_range_creates_an_alias() {
+        const ALIAS: u64 = 0x0000_4000_0000_0000;
+        let pages = PAGE_ALLOCATOR.alloc_zeroed_pages(2).unwrap();
+        let phys = pages as u64;
+        map_range(
+            phys,
+            ALIAS,
+            PAGE_SIZE as u64 + 1,
+            PageAttr::ReadWriteKernelNoExec,
+        )
+        .unwrap();
+        let table = kernel_pml4().expect("paging is not initialized");
+        assert_eq!(
+            table.translate(ALIAS + PAGE_SIZE as u64 + 8),
+            Ok(TranslationResult::PageMapped4K {
+                phys: phys + PAGE_SIZE as u64 + 8
+            })
+        );
+        unsafe {
+            pages.add(PAGE_SIZE).write_volatile(0x5a);
+            assert_eq!(
+                ((ALIAS + PAGE_SIZE as u64) as *const u8).read_volatile(),
+                0x5a
+            );
+            (ALIAS as *mut u8).write_volatile(0xa5);
+            assert_eq!(pages.read_volatile(), 0xa5);
+        }
+        map_range(phys, ALIAS, 2 * PAGE_SIZE as u64, PageAttr::NotPresent).unwrap();
+        assert!(!is_mapped(ALIAS));
+        assert!(map_range(phys + 1, ALIAS, 1, PageAttr::ReadOnlyKernel).is_err());
+        unsafe { PAGE_ALLOCATOR.free_pages(pages, 2) };
+    }
+
+    // 揃った範囲は2MBのページで対応付け、一部を書き換えると4KBのページに分ける
+    #[test_case]
+    fn aligned_ranges_are_mapped_with_large_pages() {
+        const ALIAS: u64 = 0x0000_4000_4000_0000;
+        const SIZE: u64 = LARGE_PAGE_SIZE_2M;
+        let pages = SIZE as usize / PAGE_SIZE;
+        let ptr = PAGE_ALLOCATOR
+            .alloc_pages_below(pages, SIZE as usize, usize::MAX)
+            .unwrap();
+        let phys = ptr as u64;
+        let table = kernel_pml4().expect("paging is not initialized");
+        map_range(phys, ALIAS, SIZE, PageAttr::ReadWriteKernelNoExec).unwrap();
+        assert_eq!(
+            table.translate(ALIAS + 0x1234),
+            Ok(TranslationResult::PageMapped2M {
+                phys: phys + 0x1234
+            })
+        );
+        // 1ページだけ別の物理ページに向けても、残りは元の対応のまま
+        map_frame(ALIAS + 0x3000, phys, PageAttr::ReadOnlyKernelNoExec).unwrap();
+        assert_eq!(
+            table.translate(ALIAS + 0x3008),
+            Ok(TranslationResult::PageMapped4K { phys: phys + 8 })
+        );
+        assert_eq!(
+            table.translate(ALIAS + 0x5008),
+            Ok(TranslationResult::PageMapped4K {
+                phys: phys + 0x5008
+            })
+        );
+        assert!(!table.access(ALIAS + 0x3000).unwrap().writable);
+        assert!(table.access(ALIAS + 0x4000).unwrap().writable);
+        unsafe {
+            ptr.add(0x5008).write_volatile(0x5a);
+            assert_eq!(((ALIAS + 0x5008) as *const u8).read_volatile(), 0x5a);
+        }
+        // 4KBのページテーブルができた所には、明示的に2MBのページを使えない
+        assert!(map_range_with_size(
+            phys,
+            ALIAS,
+            SIZE,
+            PageAttr::ReadWriteKernel,
+            MapSize::Size2M
+        )
+        .is_err());
+        assert!(map_range_with_size(
+            phys,
+            ALIAS + SIZE,
+            PAGE_SIZE as u64,
+            PageAttr::ReadWriteKernel,
+            MapSize::Size2M
+        )
+        .is_err());
+        map_range(phys, ALIAS, SIZE, PageAttr::NotPresent).unwrap();
+        assert!(!is_mapped(ALIAS + 0x5000));
+        unsafe { PAGE_ALLOCATOR.free_pages(ptr, pages) };
+    }
+
+    #[test_case]
+    fn pat_makes_the_framebuffer_write_combining() {
+        let bits = PageAttr::ReadWriteWriteCombining.entry_bits();
+        if !write_combining_enabled() {
+            assert_eq!(bits, PageAttr::ReadWriteIo.entry_bits());
+            return;
+        }
+        let pat = Pat::read().unwrap();
+        assert_eq!(Pat::entry(pat, 1), Pat::WRITE_COMBINING);
+        // MMIOに使う3番はUCのまま
+        assert_eq!(Pat::entry(pat, 3), Pat::UNCACHEABLE);
+        assert_eq!(
+            bits & (ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE),
+            ATTR_WRITE_THROUGH
+        );
+    }
+
+    #[test_case]
+    fn translate_unmapped() {
+        let table = kernel_pml4().expect("paging is not initialized");
+        assert!(table.translate(0x0000_4000_0000_0000).is_err());
+    }
+
+    // 物理ページをコピーオンライトで共有している仮想アドレスの数
+    fn cow_refs(phys: u64) -> usize {
+        COW_FRAMES.lock().refs.get(&phys).copied().unwrap_or(0)
+    }
+
+    // virtの4KBページの物理アドレスと、書き込めるか
+    fn cow_page(virt: u64) -> (u64, bool) {
+        let mut table = lock_kernel_pml4().unwrap();
+        let e = table.page_entry_mut(virt).unwrap();
+        (e.addr(), e.is_writable())
+    }
+
+    // 共有したページに書き込むと、書き込んだ側だけが新しい物理ページに付け替わり、もう一方は変わらない
+    #[test_case]
+    fn writing_to_a_cow_page_copies_it() {
+        const ORIGINAL: u64 = 0x0000_4000_8000_0000;
+        const SHARED: u64 = ORIGINAL + PAGE_SIZE as u64;
+        let frame = PAGE_ALLOCATOR.alloc_zeroed_pages(1).unwrap();
+        let phys = frame as u64;
+        map_frame(ORIGINAL, phys, PageAttr::ReadWriteKernelNoExec).unwrap();
+        unsafe { (ORIGINAL as *mut u64).write_volatile(0x1111) };
+        share_cow(ORIGINAL, SHARED, 1).unwrap();
+        let free = PAGE_ALLOCATOR.free_page_count();
+        assert_eq!(cow_page(ORIGINAL), (phys, false));
+        assert_eq!(cow_page(SHARED), (phys, false));
+        assert_eq!(cow_refs(phys), 2);
+        unsafe {
+            assert_eq!((SHARED as *const u64).read_volatile(), 0x1111);
+            (SHARED as *mut u64).write_volatile(0x2222);
+            assert_eq!((SHARED as *const u64).read_volatile(), 0x2222);
+            assert_eq!((ORIGINAL as *const u64).read_volatile(), 0x1111);
+        }
+        let (copy, writable) = cow_page(SHARED);
+        assert!(copy != phys && writable);
+        assert_eq!(cow_refs(phys), 1);
+        // 残った1つはコピーせずに、元の物理ページのまま書き込めるようになる
+        unsafe {
+            (ORIGINAL as *mut u64).write_volatile(0x3333);
+            assert_eq!((SHARED as *const u64).read_volatile(), 0x2222);
+            assert_eq!(frame.cast::<u64>().read_volatile(), 0x3333);
+        }
+        assert_eq!(cow_page(ORIGINAL), (phys, true));
+        // コピーは共有するときに取っておいたページなので、フォルトの中では確保していない
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free);
+        unshare_cow(ORIGINAL, 2).unwrap();
+        assert!(!is_mapped(ORIGINAL) && !is_mapped(SHARED));
+        assert_eq!(cow_refs(phys), 0);
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free + 2);
+    }
+
+    // 書き込まずに共有をやめると、取っておいたコピー用のページと、最後に外した物理ページが解放される
+    #[test_case]
+    fn unsharing_frees_the_frame() {
+        const ORIGINAL: u64 = 0x0000_4000_8000_4000;
+        const SHARED: u64 = ORIGINAL + PAGE_SIZE as u64;
+        let frame = PAGE_ALLOCATOR.alloc_zeroed_pages(1).unwrap();
+        let phys = frame as u64;
+        map_frame(ORIGINAL, phys, PageAttr::ReadWriteKernelNoExec).unwrap();
+        share_cow(ORIGINAL, SHARED, 1).unwrap();
+        let free = PAGE_ALLOCATOR.free_page_count();
+        unshare_cow(SHARED, 1).unwrap();
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free + 1);
+        assert_eq!(cow_refs(phys), 1);
+        // 残った1つは、書き込んでもコピーしない
+        unsafe { (ORIGINAL as *mut u64).write_volatile(0x4444) };
+        assert_eq!(cow_page(ORIGINAL), (phys, true));
+        unshare_cow(ORIGINAL, 1).unwrap();
+        assert_eq!(cow_refs(phys), 0);
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free + 2);
+        assert!(unshare_cow(ORIGINAL, 1).is_err());
+    }
+
+    // 読み込み専用のページは、COWの印を付けずにそのまま共有する
+    #[test_case]
+    fn read_only_pages_are_shared_without_cow() {
+        const ORIGINAL: u64 = 0x0000_4000_8000_2000;
+        const SHARED: u64 = ORIGINAL + PAGE_SIZE as u64;
+        let frame = PAGE_ALLOCATOR.alloc_zeroed_pages(1).unwrap();
+        let phys = frame as u64;
+        map_frame(ORIGINAL, phys, PageAttr::ReadOnlyKernelNoExec).unwrap();
+        let free = PAGE_ALLOCATOR.free_page_count();
+        share_cow(ORIGINAL, SHARED, 1).unwrap();
+        assert_eq!(cow_page(SHARED), (phys, false));
+        assert_eq!(cow_refs(phys), 2);
+        // コピーすることはないので、コピー用のページも取っておかない
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free);
+        assert!(!handle_cow_fault(SHARED));
+        assert!(share_cow(ORIGINAL + 8, SHARED, 1).is_err());
+        unshare_cow(ORIGINAL, 2).unwrap();
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), free + 1);
+    }
+
+    // 途中のページで失敗したときは、どのページも共有していない元の状態のまま
+    #[test_case]
+    fn failed_share_leaves_pages_unchanged() {
+        const ORIGINAL: u64 = 0x0000_4000_8001_0000;
+        const SHARED: u64 = ORIGINAL + 2 * PAGE_SIZE as u64;
+        let frames = PAGE_ALLOCATOR.alloc_zeroed_pages(3).unwrap();
+        let phys = frames as u64;
+        map_pages(ORIGINAL, phys, 2, PageAttr::ReadWriteKernelNoExec).unwrap();
+        // dstの2枚目が既にマップされている
+        map_frame(
+            SHARED + PAGE_SIZE as u64,
+            phys + 2 * PAGE_SIZE as u64,
+            PageAttr::ReadWriteKernelNoExec,
+        )
+        .unwrap();
+        assert!(share_cow(ORIGINAL, SHARED, 2).is_err());
+        assert_eq!(cow_page(ORIGINAL), (phys, true));
+        assert_eq!(cow_refs(phys), 0);
+        assert!(!is_mapped(SHARED));
+        // 重なる範囲は共有できない
+        assert!(share_cow(ORIGINAL, ORIGINAL + PAGE_SIZE as u64, 2).is_err());
+        map_range(phys, ORIGINAL, 4 * PAGE_SIZE as u64, PageAttr::NotPresent).unwrap();
+        unsafe { PAGE_ALLOCATOR.free_pages(frames, 3) };
+    }
+
+    // 大きなページでマップされたsrcは、4KBのページに分けてから共有する
+    #[test_case]
+    fn large_source_pages_are_split_before_sharing() {
+        const ALIAS: u64 = 0x0000_4000_c000_0000;
+        const SHARED: u64 = 0x0000_4000_8002_0000;
+        const SIZE: u64 = LARGE_PAGE_SIZE_2M;
+        let pages = SIZE as usize / PAGE_SIZE;
+        let ptr = PAGE_ALLOCATOR
+            .alloc_pages_below(pages, SIZE as usize, usize::MAX)
+            .unwrap();
+        let phys = ptr as u64;
+        let table = kernel_pml4().expect("paging is not initialized");
+        map_range(phys, ALIAS, SIZE, PageAttr::ReadWriteKernelNoExec).unwrap();
+        assert!(matches!(
+            table.translate(ALIAS),
+            Ok(TranslationResult::PageMapped2M { .. })
+        ));
+        share_cow(ALIAS + 0x3000, SHARED, 1).unwrap();
+        assert_eq!(cow_page(ALIAS + 0x3000), (phys + 0x3000, false));
+        assert_eq!(cow_page(SHARED), (phys + 0x3000, false));
+        // 残りのページは元の対応と権限のまま
+        assert_eq!(cow_page(ALIAS + 0x4000), (phys + 0x4000, true));
+        unshare_cow(SHARED, 1).unwrap();
+        // 最後の対応を外すと、srcの物理ページも解放される
+        unshare_cow(ALIAS + 0x3000, 1).unwrap();
+        map_range(phys, ALIAS, SIZE, PageAttr::NotPresent).unwrap();
+        unsafe {
+            PAGE_ALLOCATOR.free_pages(ptr, 3);
+            PAGE_ALLOCATOR.free_pages(ptr.add(0x4000), pages - 4);
+        }
+    }
+
+    // マップされていないアドレスを読むと、そのアドレスでページフォルトが起きることを確認する
+    // ハンドラのpanicのメッセージに、記録した例外の番号（14）とハンドラが読んだCR2が入っている
+    // このテストは必ずpanicで終了するので、exception_tests featureを有効にした時だけ実行する
+    #[cfg(feature = "exception_tests")]
+    crate::kernel_test! {
+        #[should_panic(expected = "exception 0x0E: page fault at CR2=0x0000400000000000")]
+        fn page_fault_on_unmapped_address() {
+            const UNMAPPED: u64 = 0x0000_4000_0000_0000;
+            let table = kernel_pml4().expect("paging is not initialized");
+            assert!(table.translate(UNMAPPED).is_err());
+            let _ = unsafe { core::ptr::read_volatile(UNMAPPED as *const u64) };
+            unreachable!("#PF handler returned");
+        }
+    }
+
+    // 読み込み専用のデータと書き換えるデータ（.rdataと.data/.bssに置かれる）
+    static READ_ONLY: [u64; 4] = [1, 2, 3, 4];
+    static WRITABLE: AtomicU64 = AtomicU64::new(0);
+
+    #[test_case]
+    fn kernel_image_is_mapped_w_xor_x() {
+        let table = kernel_pml4().expect("paging is not initialized");
+        let access = |addr: u64| table.access(addr).unwrap();
+        let code = access(kernel_pml4 as *const () as u64);
+        assert!(code.executable && !code.writable);
+        let rodata = access(READ_ONLY.as_ptr() as u64);
+        assert!(!rodata.writable);
+        WRITABLE.fetch_add(1, Ordering::SeqCst);
+        assert!(access(&WRITABLE as *const AtomicU64 as u64).writable);
+        if NX_ENABLED.load(Ordering::Relaxed) {
+            assert!(!rodata.executable);
+            assert!(!access(&WRITABLE as *const AtomicU64 as u64).executable);
+        }
+    }
+
+    // #PFのハンドラの中でpanicするので、exception_tests featureを有効にした時だけ実行する
+    // （continue_after_exceptionでカーネルのスタックに戻ってから次のテストを続ける）
+    #[cfg(feature = "exception_tests")]
+    crate::kernel_test! {
+        #[should_panic(expected = "W^X violation")]
+        fn writing_to_kernel_code_faults() {
+            let code = kernel_pml4 as *const () as *mut u8;
+            unsafe { core::ptr::write_volatile(code, 0xcc) };
+        }
+    }
+}
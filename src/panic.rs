@@ -0,0 +1,234 @@
+use crate::graphics::draw_font_fg;
+use crate::graphics::fill_rect;
+use crate::graphics::Bitmap;
+use crate::klog;
+use crate::power::poweroff;
+use crate::print::is_printing;
+use crate::print::with_vram_console;
+use crate::qemu::exit_qemu_with;
+use crate::qemu::is_qemu;
+use crate::qemu::QemuExitCode;
+use crate::serial::global_port;
+use crate::uefi::con_out_sink;
+use crate::x86::disable_interrupts;
+use crate::x86::hlt;
+use crate::x86::io_delay;
+use crate::x86::write_io_port_u8;
+use core::fmt;
+use core::fmt::Write;
+use core::panic::Location;
+use core::panic::PanicInfo;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+// 画面上部に描くバナーの行数
+pub const PANIC_BANNER_LINES: i64 = 8;
+const BANNER_BG_COLOR: u32 = 0xc00000;
+const BANNER_FG_COLOR: u32 = 0xffffff;
+const CHAR_WIDTH: i64 = 8;
+const CHAR_HEIGHT: i64 = 16;
+// リリースビルドで再起動するまでの時間（io_delayは約1マイクロ秒）
+const REBOOT_DELAY_US: usize = 5_000_000;
+
+// panicの内容を"PANIC at file:line:column: message"の形で表示する
+// アロケータの中でpanicした可能性もあるので、ヒープを使わずに直接書き出す
+pub struct PanicReport<'a> {
+    message: &'a dyn fmt::Display,
+    location: Option<&'a Location<'a>>,
+}
+impl<'a> PanicReport<'a> {
+    pub fn new(message: &'a dyn fmt::Display, location: Option<&'a Location<'a>>) -> Self {
+        Self { message, location }
+    }
+}
+impl fmt::Display for PanicReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.location {
+            Some(loc) => write!(
+                f,
+                "PANIC at {}:{}:{}: {}",
+                loc.file(),
+                loc.line(),
+                loc.column(),
+                self.message
+            ),
+            None => write!(f, "PANIC at unknown location: {}", self.message),
+        }
+    }
+}
+
+// 矩形の中に文字を描いていくWriter
+// 右端で折り返し、下端からはみ出す部分は描かない
+struct BannerWriter<'a, T: Bitmap> {
+    buf: &'a mut T,
+    x: i64,
+    y: i64,
+    width: i64,
+    bottom: i64,
+}
+impl<T: Bitmap> fmt::Write for BannerWriter<'_, T> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if c == '\n' || self.x + CHAR_WIDTH > self.width {
+                self.x = 0;
+                self.y += CHAR_HEIGHT;
+            }
+            if c == '\n' {
+                continue;
+            }
+            if self.y + CHAR_HEIGHT > self.bottom {
+                break;
+            }
+            draw_font_fg(self.buf, self.x, self.y, BANNER_FG_COLOR, c);
+            self.x += CHAR_WIDTH;
+        }
+        Ok(())
+    }
+}
+
+// 画面上部を赤く塗りつぶして、その上に白い文字で描く
+// 背景を先に塗るので、元の画面の内容に関係なく読める
+pub fn draw_panic_banner<T: Bitmap>(buf: &mut T, args: fmt::Arguments) {
+    let width = buf.width();
+    let bottom = (PANIC_BANNER_LINES * CHAR_HEIGHT).min(buf.height());
+    let _ = fill_rect(buf, BANNER_BG_COLOR, 0, 0, width, bottom);
+    let mut w = BannerWriter {
+        buf,
+        x: 0,
+        y: 0,
+        width,
+        bottom,
+    };
+    let _ = fmt::write(&mut w, args);
+}
+
+// panicハンドラ用の出力先
+// シンクの一覧もロックも使わずに、print!の出力先のシリアルポートに直接書く
+pub struct EmergencyWriter;
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        global_port().emergency_send_str(s);
+        Ok(())
+    }
+}
+
+// report_panicの途中ならtrue（報告の途中でのpanicを見つける）
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+// 報告を始められるならREPORTINGを立てる
+// 始められなければ、代わりにシリアルに書く文字列を返す（REPORTINGは立てたままにしない）
+fn begin_report(printing: bool) -> Result<(), &'static str> {
+    const WHILE_REPORTING: &str = "\nPANIC while reporting a panic\n";
+    if REPORTING.load(Ordering::Acquire) {
+        return Err(WHILE_REPORTING);
+    }
+    if printing {
+        return Err("\nPANIC while printing\n");
+    }
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        return Err(WHILE_REPORTING);
+    }
+    Ok(())
+}
+
+// panicの内容をシリアルとklogに出力し、VRAMコンソールを使っていれば画面にも表示する
+// 出力や報告の途中でpanicした場合は、決まった文字列だけをシリアルに書いて戻る
+// （もう一度フォーマットしようとすると、同じところでpanicし続けるかもしれない）
+pub fn report_panic(info: &PanicInfo) {
+    let mut w = EmergencyWriter;
+    if let Err(notice) = begin_report(is_printing()) {
+        let _ = w.write_str(notice);
+        return;
+    }
+    let message = info.message();
+    let report = PanicReport::new(&message, info.location());
+    let _ = writeln!(w, "{report}");
+    if let Some(mut klog) = klog::sink().try_lock() {
+        let _ = writeln!(klog, "{report}");
+    }
+    // ブートサービスを抜ける前（画面の初期化に失敗したときなど）は、ファームウェアのコンソールにも出す
+    if let Some(mut con_out) = con_out_sink().try_lock() {
+        let _ = writeln!(con_out, "{report}");
+    }
+    with_vram_console(|vram| draw_panic_banner(vram, format_args!("{report}")));
+    REPORTING.store(false, Ordering::Release);
+}
+
+// panicやメモリ不足の後に呼ぶ
+// デバッグビルドではexit_codeでQEMUを終了し（実機では電源を切る）、
+// リリースビルドでは少し待ってから再起動する
+pub fn halt_or_reboot(exit_code: QemuExitCode) -> ! {
+    if cfg!(debug_assertions) {
+        if is_qemu() {
+            exit_qemu_with(exit_code);
+        }
+        poweroff();
+    }
+    disable_interrupts();
+    for _ in 0..REBOOT_DELAY_US {
+        io_delay();
+    }
+    reboot()
+}
+
+// キーボードコントローラのリセット線でCPUをリセットする
+pub fn reboot() -> ! {
+    disable_interrupts();
+    write_io_port_u8(0x64, 0xfe);
+    loop {
+        hlt()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use crate::graphics_tests::OwnedBitmap;
+    use alloc::format;
+
+    #[test_case]
+    fn report_contains_file_line_and_column() {
+        let location = Location::caller();
+        let report = format!("{}", PanicReport::new(&"boom", Some(location)));
+        assert_eq!(
+            report,
+            format!(
+                "PANIC at {}:{}:{}: boom",
+                file!(),
+                location.line(),
+                location.column()
+            )
+        );
+        assert!(report.starts_with("PANIC at src/panic.rs:"));
+        assert_eq!(
+            format!("{}", PanicReport::new(&"boom", None)),
+            "PANIC at unknown location: boom"
+        );
+    }
+    // 出力の途中のpanicで報告をやめても、次のpanicは報告できる
+    #[test_case]
+    fn report_can_begin_after_a_panic_while_printing() {
+        assert_eq!(begin_report(true), Err("\nPANIC while printing\n"));
+        assert!(!REPORTING.load(Ordering::Acquire));
+        assert_eq!(begin_report(false), Ok(()));
+        assert_eq!(
+            begin_report(false),
+            Err("\nPANIC while reporting a panic\n")
+        );
+        REPORTING.store(false, Ordering::Release);
+    }
+    #[test_case]
+    fn banner_fills_only_the_top_of_the_screen() {
+        let (width, height) = (64, 256);
+        let mut bitmap = OwnedBitmap::new(width, height);
+        // 長い文字列を描いても、バナーの外にははみ出さない
+        draw_panic_banner(&mut bitmap, format_args!("{}", "#".repeat(200)));
+        let banner_bottom = PANIC_BANNER_LINES * CHAR_HEIGHT;
+        let (banner, rest) = bitmap.pixels().split_at((banner_bottom * width) as usize);
+        assert!(banner.contains(&BANNER_BG_COLOR));
+        assert!(banner.contains(&BANNER_FG_COLOR));
+        assert!(rest.iter().all(|p| *p == 0));
+    }
+}
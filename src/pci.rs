@@ -0,0 +1,570 @@
+extern crate alloc;
+
+use crate::info;
+use crate::x86::read_io_port_u32;
+use crate::x86::write_io_port_u32;
+use crate::x86::InterruptGuard;
+use alloc::vec::Vec;
+use core::fmt;
+
+// コンフィギュレーションメカニズム#1のポート
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+// コンフィギュレーション空間のレジスタのオフセット
+const REG_ID: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER: u8 = 0x0c;
+const REG_BAR0: u8 = 0x10;
+// PCI-to-PCIブリッジ（ヘッダタイプ1）のバス番号
+const REG_BRIDGE_BUSES: u8 = 0x18;
+
+// コマンドレジスタのI/O空間とメモリ空間のデコードを有効にするビット
+const COMMAND_DECODE_MASK: u32 = 0b11;
+// ヘッダタイプの最上位ビットが立っていれば、ファンクション1以降もある
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+const NUM_BARS: usize = 6;
+// ブリッジにはBARが2つしかない
+const NUM_BRIDGE_BARS: usize = 2;
+
+// find_by_classに渡すクラスコード
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+// バス・デバイス・ファンクション番号
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+}
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.dev, self.func)
+    }
+}
+
+// コンフィギュレーション空間へのアクセス
+// テストでは実際のI/Oポートの代わりに、記録した内容を返すモックを使えるようにする
+pub trait ConfigSpace {
+    fn read_u32(&self, addr: PciAddress, offset: u8) -> u32;
+    fn write_u32(&self, addr: PciAddress, offset: u8, value: u32);
+}
+
+// 0xCF8にアドレスを書いて、0xCFCでデータを読み書きする
+pub struct PortConfigSpace;
+impl PortConfigSpace {
+    fn select(addr: PciAddress, offset: u8) {
+        let address = 1 << 31
+            | (addr.bus as u32) << 16
+            | (addr.dev as u32) << 11
+            | (addr.func as u32) << 8
+            | (offset & 0xfc) as u32;
+        write_io_port_u32(CONFIG_ADDRESS, address);
+    }
+}
+impl ConfigSpace for PortConfigSpace {
+    fn read_u32(&self, addr: PciAddress, offset: u8) -> u32 {
+        // アドレスとデータの書き込みの間に、割り込みハンドラが別のアドレスを選ばないようにする
+        let _guard = InterruptGuard::new();
+        Self::select(addr, offset);
+        read_io_port_u32(CONFIG_DATA)
+    }
+    fn write_u32(&self, addr: PciAddress, offset: u8, value: u32) {
+        let _guard = InterruptGuard::new();
+        Self::select(addr, offset);
+        write_io_port_u32(CONFIG_DATA, value);
+    }
+}
+
+// Base Address Registerの内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciBar {
+    // 使われていない（64ビットのBARの上位32ビットもこれにする）
+    None,
+    Memory {
+        addr: u64,
+        size: u64,
+        is_64bit: bool,
+        prefetchable: bool,
+    },
+    Io {
+        port: u32,
+        size: u32,
+    },
+}
+impl fmt::Display for PciBar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PciBar::None => write!(f, "none"),
+            PciBar::Memory {
+                addr,
+                size,
+                is_64bit,
+                prefetchable,
+            } => write!(
+                f,
+                "memory at {addr:#x} ({}-bit, {}prefetchable) [size={size:#x}]",
+                if *is_64bit { 64 } else { 32 },
+                if *prefetchable { "" } else { "non-" }
+            ),
+            PciBar::Io { port, size } => write!(f, "I/O ports at {port:#x} [size={size:#x}]"),
+        }
+    }
+}
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEM_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_MEM_TYPE_64: u32 = 0b10 << 1;
+const BAR_MEM_PREFETCHABLE: u32 = 1 << 3;
+
+// BARに全て1を書いて読み戻し、元の値に戻す
+// 読み戻した値は、デバイスが固定している下位ビットが0になっている
+fn probe_bar(cs: &impl ConfigSpace, addr: PciAddress, offset: u8) -> (u32, u32) {
+    let original = cs.read_u32(addr, offset);
+    cs.write_u32(addr, offset, 0xffff_ffff);
+    let mask = cs.read_u32(addr, offset);
+    cs.write_u32(addr, offset, original);
+    (original, mask)
+}
+
+// index番目のBARを読んで大きさを調べる
+// 64ビットのBARなら次のBARも使うので、使ったBARの数も返す
+fn read_bar(cs: &impl ConfigSpace, addr: PciAddress, index: usize) -> (PciBar, usize) {
+    let offset = REG_BAR0 + index as u8 * 4;
+    let (original, mask) = probe_bar(cs, addr, offset);
+    if original & BAR_IO != 0 {
+        let mask = mask & !0b11;
+        if mask == 0 {
+            return (PciBar::None, 1);
+        }
+        let bar = PciBar::Io {
+            port: original & !0b11,
+            size: 1 << mask.trailing_zeros(),
+        };
+        return (bar, 1);
+    }
+    let is_64bit = original & BAR_MEM_TYPE_MASK == BAR_MEM_TYPE_64;
+    let (original, mask, used) = if is_64bit {
+        let (high, high_mask) = probe_bar(cs, addr, offset + 4);
+        (
+            (high as u64) << 32 | original as u64,
+            (high_mask as u64) << 32 | mask as u64,
+            2,
+        )
+    } else {
+        (original as u64, mask as u64, 1)
+    };
+    let mask = mask & !0xf;
+    if mask == 0 {
+        return (PciBar::None, used);
+    }
+    let bar = PciBar::Memory {
+        addr: original & !0xf,
+        size: 1 << mask.trailing_zeros(),
+        is_64bit,
+        prefetchable: original as u32 & BAR_MEM_PREFETCHABLE != 0,
+    };
+    (bar, used)
+}
+
+// 全てのBARを読む
+// 大きさを調べている間にデバイスがアドレスをデコードしないように、I/Oとメモリのデコードを止めておく
+fn read_bars(cs: &impl ConfigSpace, addr: PciAddress, num_bars: usize) -> [PciBar; NUM_BARS] {
+    let mut bars = [PciBar::None; NUM_BARS];
+    let _guard = InterruptGuard::new();
+    let command = cs.read_u32(addr, REG_COMMAND);
+    cs.write_u32(addr, REG_COMMAND, command & !COMMAND_DECODE_MASK);
+    let mut index = 0;
+    while index < num_bars {
+        let (bar, used) = read_bar(cs, addr, index);
+        bars[index] = bar;
+        index += used;
+    }
+    cs.write_u32(addr, REG_COMMAND, command);
+    bars
+}
+
+// 見つかったPCIデバイスのファンクション
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub dev: u8,
+    pub func: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub header_type: u8,
+    pub bars: [PciBar; NUM_BARS],
+}
+impl PciDevice {
+    pub fn address(&self) -> PciAddress {
+        PciAddress {
+            bus: self.bus,
+            dev: self.dev,
+            func: self.func,
+        }
+    }
+    pub fn is_pci_bridge(&self) -> bool {
+        self.class == CLASS_BRIDGE && self.subclass == SUBCLASS_PCI_BRIDGE
+    }
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+// lspciのような1行の表示
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} [{:02x}{:02x}]: {:04x}:{:04x}",
+            self.address(),
+            self.class_name(),
+            self.class,
+            self.subclass,
+            self.vendor_id,
+            self.device_id
+        )
+    }
+}
+
+// よく使うクラスの名前
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, _) => "Communication controller",
+        (0x08, _) => "System peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        _ => "Unknown device",
+    }
+}
+
+// デバイスがなければNone
+fn read_function(cs: &impl ConfigSpace, addr: PciAddress) -> Option<PciDevice> {
+    let id = cs.read_u32(addr, REG_ID);
+    let vendor_id = id as u16;
+    if vendor_id == 0xffff {
+        return None;
+    }
+    let [_revision, prog_if, subclass, class] = cs.read_u32(addr, REG_CLASS).to_le_bytes();
+    let header_type = cs.read_u32(addr, REG_HEADER).to_le_bytes()[2];
+    let num_bars = match header_type & !HEADER_MULTIFUNCTION {
+        0x00 => NUM_BARS,
+        HEADER_TYPE_BRIDGE => NUM_BRIDGE_BARS,
+        // CardBusブリッジなど、BARの位置が違うヘッダは読まない
+        _ => 0,
+    };
+    Some(PciDevice {
+        bus: addr.bus,
+        dev: addr.dev,
+        func: addr.func,
+        vendor_id,
+        device_id: (id >> 16) as u16,
+        class,
+        subclass,
+        prog_if,
+        header_type,
+        bars: read_bars(cs, addr, num_bars),
+    })
+}
+
+struct Scanner<'a, C: ConfigSpace> {
+    cs: &'a C,
+    devices: Vec<PciDevice>,
+    // ブリッジの設定がおかしくても、同じバスを何度も調べないようにする
+    visited: [bool; 256],
+}
+impl<C: ConfigSpace> Scanner<'_, C> {
+    fn scan_bus(&mut self, bus: u8) {
+        if self.visited[bus as usize] {
+            return;
+        }
+        self.visited[bus as usize] = true;
+        for dev in 0..32 {
+            self.scan_device(bus, dev);
+        }
+    }
+    fn scan_device(&mut self, bus: u8, dev: u8) {
+        let Some(first) = read_function(self.cs, PciAddress { bus, dev, func: 0 }) else {
+            return;
+        };
+        self.add(first);
+        if first.header_type & HEADER_MULTIFUNCTION == 0 {
+            return;
+        }
+        for func in 1..8 {
+            if let Some(device) = read_function(self.cs, PciAddress { bus, dev, func }) {
+                self.add(device);
+            }
+        }
+    }
+    fn add(&mut self, device: PciDevice) {
+        self.devices.push(device);
+        if device.is_pci_bridge()
+            && device.header_type & !HEADER_MULTIFUNCTION == HEADER_TYPE_BRIDGE
+        {
+            let secondary = self
+                .cs
+                .read_u32(device.address(), REG_BRIDGE_BUSES)
+                .to_le_bytes()[1];
+            self.scan_bus(secondary);
+        }
+    }
+}
+
+// バス0から、PCI-to-PCIブリッジの先のバスもたどって全てのファンクションを探す
+fn scan_with(cs: &impl ConfigSpace) -> Vec<PciDevice> {
+    let mut scanner = Scanner {
+        cs,
+        devices: Vec::new(),
+        visited: [false; 256],
+    };
+    // ホストブリッジが複数のファンクションを持つときは、ファンクションごとに別のバスを受け持っている
+    let host = PciAddress {
+        bus: 0,
+        dev: 0,
+        func: 0,
+    };
+    let header_type = cs.read_u32(host, REG_HEADER).to_le_bytes()[2];
+    if header_type & HEADER_MULTIFUNCTION == 0 {
+        scanner.scan_bus(0);
+    } else {
+        for func in 0..8 {
+            let host = PciAddress { func, ..host };
+            if cs.read_u32(host, REG_ID) as u16 != 0xffff {
+                scanner.scan_bus(func);
+            }
+        }
+    }
+    scanner.devices
+}
+
+pub fn scan() -> Vec<PciDevice> {
+    scan_with(&PortConfigSpace)
+}
+
+// クラスとサブクラスが一致するデバイス
+pub fn find_by_class(
+    devices: &[PciDevice],
+    class: u8,
+    subclass: u8,
+) -> impl Iterator<Item = &PciDevice> {
+    devices
+        .iter()
+        .filter(move |d| d.class == class && d.subclass == subclass)
+}
+
+// lspciのように、見つかったデバイスと使われているBARを表示する
+pub fn log_devices(devices: &[PciDevice]) {
+    info!("PCI: {} functions found", devices.len());
+    for d in devices {
+        info!("  {d}");
+        for (i, bar) in d.bars.iter().enumerate() {
+            if *bar != PciBar::None {
+                info!("    BAR{i}: {bar}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::cell::RefCell;
+
+    // QEMUで記録したコンフィギュレーション空間の先頭64バイトと、BARの書き込めるビット
+    #[derive(Clone)]
+    struct MockFunction {
+        addr: PciAddress,
+        regs: [u32; 16],
+        bar_masks: [u32; NUM_BARS],
+    }
+    struct MockConfigSpace {
+        functions: RefCell<Vec<MockFunction>>,
+    }
+    impl MockConfigSpace {
+        fn new(functions: &[MockFunction]) -> Self {
+            Self {
+                functions: RefCell::new(functions.to_vec()),
+            }
+        }
+        fn regs(&self, addr: PciAddress) -> Option<[u32; 16]> {
+            self.functions
+                .borrow()
+                .iter()
+                .find(|f| f.addr == addr)
+                .map(|f| f.regs)
+        }
+    }
+    impl ConfigSpace for MockConfigSpace {
+        fn read_u32(&self, addr: PciAddress, offset: u8) -> u32 {
+            self.regs(addr)
+                .map_or(0xffff_ffff, |regs| regs[offset as usize / 4])
+        }
+        fn write_u32(&self, addr: PciAddress, offset: u8, value: u32) {
+            let mut functions = self.functions.borrow_mut();
+            let Some(f) = functions.iter_mut().find(|f| f.addr == addr) else {
+                return;
+            };
+            let i = offset as usize / 4;
+            // BARは書き込めるビットだけが変わり、残りはデバイスが決めた値のまま
+            let mask = match offset {
+                REG_BAR0..0x28 => f.bar_masks[(offset - REG_BAR0) as usize / 4],
+                _ => 0xffff_ffff,
+            };
+            f.regs[i] = (value & mask) | (f.regs[i] & !mask);
+        }
+    }
+
+    fn function(bus: u8, dev: u8, func: u8, regs: [u32; 16], bar_masks: [u32; 6]) -> MockFunction {
+        MockFunction {
+            addr: PciAddress { bus, dev, func },
+            regs,
+            bar_masks,
+        }
+    }
+    // q35のホストブリッジ（8086:29c0）
+    fn host_bridge() -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = 0x29c0_8086;
+        regs[2] = 0x0600_0000;
+        function(0, 0, 0, regs, [0; 6])
+    }
+    // QEMUの標準VGA（1234:1111）
+    // BAR0: 16MiBのプリフェッチ可能なフレームバッファ、BAR2: 4KiBのMMIO
+    fn std_vga() -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = 0x1111_1234;
+        regs[1] = 0x0000_0003;
+        regs[2] = 0x0300_0002;
+        regs[4] = 0x8000_0008;
+        regs[6] = 0x8100_0000;
+        function(0, 1, 0, regs, [!0xff_ffff, 0, !0xfff, 0, 0, 0])
+    }
+    // e1000（8086:100e）
+    // BAR0: 128KiBのMMIO、BAR1: 64バイトのI/Oポート
+    fn e1000() -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = 0x100e_8086;
+        regs[2] = 0x0200_0003;
+        regs[4] = 0x8102_0000;
+        regs[5] = 0x0000_c001;
+        function(0, 2, 0, regs, [!0x1_ffff, !0x3f, 0, 0, 0, 0])
+    }
+    // virtio-net（1af4:1041）
+    // BAR4: 64ビットでプリフェッチ可能な16KiBのMMIO（4GiBより上に置かれている）
+    fn virtio_net(bus: u8) -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = 0x1041_1af4;
+        regs[2] = 0x0200_0001;
+        regs[8] = 0x0000_000c;
+        regs[9] = 0x0000_0008;
+        function(bus, 0, 0, regs, [0, 0, 0, 0, !0x3fff, 0xffff_ffff])
+    }
+    // PCI-to-PCIブリッジ（1b36:0001）、その先はsecondaryのバス
+    fn pci_bridge(dev: u8, secondary: u8) -> MockFunction {
+        let mut regs = [0; 16];
+        regs[0] = 0x0001_1b36;
+        regs[2] = 0x0604_0000;
+        regs[3] = 0x0001_0000;
+        regs[6] = (secondary as u32) << 16 | (secondary as u32) << 8;
+        function(0, dev, 0, regs, [0; 6])
+    }
+
+    #[test_case]
+    fn bar_sizing_decodes_memory_and_io_bars() {
+        let cs = MockConfigSpace::new(&[std_vga(), e1000()]);
+        let vga = read_function(&cs, std_vga().addr).unwrap();
+        assert_eq!(
+            vga.bars[0],
+            PciBar::Memory {
+                addr: 0x8000_0000,
+                size: 16 << 20,
+                is_64bit: false,
+                prefetchable: true
+            }
+        );
+        assert_eq!(vga.bars[1], PciBar::None);
+        assert_eq!(
+            vga.bars[2],
+            PciBar::Memory {
+                addr: 0x8100_0000,
+                size: 0x1000,
+                is_64bit: false,
+                prefetchable: false
+            }
+        );
+        let nic = read_function(&cs, e1000().addr).unwrap();
+        assert_eq!(
+            nic.bars[1],
+            PciBar::Io {
+                port: 0xc000,
+                size: 0x40
+            }
+        );
+        // 大きさを調べた後は元の値に戻っている
+        assert_eq!(cs.regs(std_vga().addr), Some(std_vga().regs));
+        assert_eq!(cs.regs(e1000().addr), Some(e1000().regs));
+    }
+    #[test_case]
+    fn bar_sizing_handles_64bit_bars() {
+        let cs = MockConfigSpace::new(&[virtio_net(0)]);
+        let dev = read_function(&cs, virtio_net(0).addr).unwrap();
+        assert_eq!(
+            dev.bars[4],
+            PciBar::Memory {
+                addr: 0x8_0000_0000,
+                size: 0x4000,
+                is_64bit: true,
+                prefetchable: true
+            }
+        );
+        // 上位32ビットのBARは別のBARとして扱わない
+        assert_eq!(dev.bars[5], PciBar::None);
+        assert_eq!(cs.regs(virtio_net(0).addr), Some(virtio_net(0).regs));
+    }
+    #[test_case]
+    fn scan_follows_bridges_and_finds_by_class() {
+        let cs = MockConfigSpace::new(&[host_bridge(), std_vga(), pci_bridge(3, 1), virtio_net(1)]);
+        let devices = scan_with(&cs);
+        let addrs: Vec<(u8, u8)> = devices.iter().map(|d| (d.bus, d.dev)).collect();
+        assert_eq!(addrs, [(0, 0), (0, 1), (0, 3), (1, 0)]);
+        let vga: Vec<&PciDevice> = find_by_class(&devices, CLASS_DISPLAY, 0x00).collect();
+        assert_eq!(vga.len(), 1);
+        assert_eq!(
+            alloc::format!("{}", vga[0]),
+            "00:01.0 VGA compatible controller [0300]: 1234:1111"
+        );
+    }
+    #[test_case]
+    fn scan_finds_host_bridge_and_display_under_qemu() {
+        let devices = scan();
+        assert!(find_by_class(&devices, CLASS_BRIDGE, SUBCLASS_HOST_BRIDGE)
+            .next()
+            .is_some());
+        assert!(devices.iter().any(|d| d.class == CLASS_DISPLAY));
+    }
+}
@@ -0,0 +1,186 @@
+use crate::paging::PageAttr;
+use crate::result::Result;
+use core::ops::Range;
+
+// UEFIが読み込んだカーネルのイメージ（PE32+）のヘッダーを読む
+// ブートサービスを抜ける前にも使うので、ヒープを使わない
+
+const DOS_MAGIC: &[u8; 2] = b"MZ";
+const PE_MAGIC: &[u8; 4] = b"PE\0\0";
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+const COFF_HEADER_LEN: usize = 20;
+const SECTION_HEADER_LEN: usize = 40;
+
+// セクションの権限（Characteristics）
+const IMAGE_SCN_MEM_EXECUTE: u32 = 0x2000_0000;
+const IMAGE_SCN_MEM_WRITE: u32 = 0x8000_0000;
+
+fn read_le<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| bytes.get(offset..end))
+        .and_then(|b| b.try_into().ok())
+        .ok_or("PE: headers are truncated")
+}
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16> {
+    read_le(bytes, offset).map(u16::from_le_bytes)
+}
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32> {
+    read_le(bytes, offset).map(u32::from_le_bytes)
+}
+fn u64_at(bytes: &[u8], offset: usize) -> Result<u64> {
+    read_le(bytes, offset).map(u64::from_le_bytes)
+}
+
+// セクションヘッダーの中身（アドレスはイメージの先頭からの相対アドレス）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeSection {
+    pub name: [u8; 8],
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub characteristics: u32,
+}
+impl PeSection {
+    // 名前（8バイトに満たない分はNULで埋められている）
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(8);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+    pub fn is_executable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_EXECUTE != 0
+    }
+    pub fn is_writable(&self) -> bool {
+        self.characteristics & IMAGE_SCN_MEM_WRITE != 0
+    }
+    // 読み込んだイメージの中での範囲（.bssのようにファイルより大きい分も含む）
+    pub fn range(&self, image_base: u64) -> Range<u64> {
+        let start = image_base + self.virtual_address as u64;
+        start..start + self.virtual_size as u64
+    }
+    // 書き込みと実行は同時に許さない（W^X）
+    // 両方が必要なセクションは、書き込みだけを許して実行はさせない
+    pub fn attr(&self) -> PageAttr {
+        match (self.is_writable(), self.is_executable()) {
+            (true, _) => PageAttr::ReadWriteKernelNoExec,
+            (false, true) => PageAttr::ReadOnlyKernel,
+            (false, false) => PageAttr::ReadOnlyKernelNoExec,
+        }
+    }
+}
+
+pub struct PeImage<'a> {
+    headers: &'a [u8],
+    // リンクしたときのイメージの先頭アドレス（ImageBase）
+    pub image_base: u64,
+    // セクションを置く境界
+    pub section_alignment: u32,
+    section_table: usize,
+    section_count: usize,
+}
+impl<'a> PeImage<'a> {
+    // headersはイメージの先頭から、少なくともセクションヘッダーの表の終わりまで
+    pub fn parse(headers: &'a [u8]) -> Result<Self> {
+        if read_le::<2>(headers, 0)? != *DOS_MAGIC {
+            return Err("PE: bad DOS magic");
+        }
+        let pe = u32_at(headers, 0x3c)? as usize;
+        if read_le::<4>(headers, pe)? != *PE_MAGIC {
+            return Err("PE: bad PE magic");
+        }
+        let section_count = u16_at(headers, pe + 6)? as usize;
+        let optional_len = u16_at(headers, pe + 20)? as usize;
+        // COFFヘッダーの後ろがオプションヘッダーで、その後ろにセクションヘッダーの表が続く
+        let optional = pe + 4 + COFF_HEADER_LEN;
+        if u16_at(headers, optional)? != PE32_PLUS_MAGIC {
+            return Err("PE: not a PE32+ image");
+        }
+        let image_base = u64_at(headers, optional + 24)?;
+        let section_alignment = u32_at(headers, optional + 32)?;
+        let section_table = optional + optional_len;
+        if section_table + section_count * SECTION_HEADER_LEN > headers.len() {
+            return Err("PE: headers are truncated");
+        }
+        Ok(Self {
+            headers,
+            image_base,
+            section_alignment,
+            section_table,
+            section_count,
+        })
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = PeSection> + '_ {
+        (0..self.section_count).map(|i| {
+            let h = &self.headers[self.section_table + i * SECTION_HEADER_LEN..];
+            // 表の大きさはparseで確かめてあるので、読み損ねることはない
+            PeSection {
+                name: read_le(h, 0).unwrap_or_default(),
+                virtual_size: u32_at(h, 8).unwrap_or_default(),
+                virtual_address: u32_at(h, 12).unwrap_or_default(),
+                characteristics: u32_at(h, 36).unwrap_or_default(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // MZヘッダー、PE32+のヘッダー、セクションヘッダーの表だけを持つイメージ
+    fn fake_pe(image_base: u64, sections: &[(&[u8], u32, u32, u32)]) -> [u8; 0x200] {
+        let mut image = [0u8; 0x200];
+        image[0..2].copy_from_slice(DOS_MAGIC);
+        image[0x3c..0x40].copy_from_slice(&0x80u32.to_le_bytes());
+        image[0x80..0x84].copy_from_slice(PE_MAGIC);
+        image[0x86..0x88].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        image[0x94..0x96].copy_from_slice(&0xf0u16.to_le_bytes());
+        image[0x98..0x9a].copy_from_slice(&PE32_PLUS_MAGIC.to_le_bytes());
+        image[0xb0..0xb8].copy_from_slice(&image_base.to_le_bytes());
+        image[0xb8..0xbc].copy_from_slice(&0x1000u32.to_le_bytes());
+        for (i, (name, rva, size, characteristics)) in sections.iter().enumerate() {
+            let h = 0x188 + i * SECTION_HEADER_LEN;
+            image[h..h + name.len()].copy_from_slice(name);
+            image[h + 8..h + 12].copy_from_slice(&size.to_le_bytes());
+            image[h + 12..h + 16].copy_from_slice(&rva.to_le_bytes());
+            image[h + 36..h + 40].copy_from_slice(&characteristics.to_le_bytes());
+        }
+        image
+    }
+
+    #[test_case]
+    fn sections_are_read_from_pe_headers() {
+        const CODE: u32 = IMAGE_SCN_MEM_EXECUTE | 0x4000_0000;
+        const DATA: u32 = IMAGE_SCN_MEM_WRITE | 0x4000_0000;
+        let image = fake_pe(
+            0x1_4000_0000,
+            &[
+                (b".text", 0x1000, 0x2345, CODE),
+                (b".rdata", 0x4000, 0x800, 0x4000_0000),
+                (b".data", 0x5000, 0x3000, DATA),
+            ],
+        );
+        let pe = PeImage::parse(&image).unwrap();
+        assert_eq!(pe.image_base, 0x1_4000_0000);
+        assert_eq!(pe.section_alignment, 0x1000);
+        let sections: [PeSection; 3] = core::array::from_fn(|i| pe.sections().nth(i).unwrap());
+        assert_eq!(pe.sections().count(), 3);
+        assert_eq!(sections[0].name(), ".text");
+        assert_eq!(sections[0].range(0x10_0000), 0x10_1000..0x10_3345);
+        assert_eq!(sections[0].attr(), PageAttr::ReadOnlyKernel);
+        assert_eq!(sections[1].name(), ".rdata");
+        assert_eq!(sections[1].attr(), PageAttr::ReadOnlyKernelNoExec);
+        assert_eq!(sections[2].attr(), PageAttr::ReadWriteKernelNoExec);
+    }
+    #[test_case]
+    fn broken_headers_are_rejected() {
+        let image = fake_pe(0x1_4000_0000, &[(b".text", 0x1000, 0x10, 0)]);
+        // PE32（32ビット）のヘッダーや、切れたヘッダーは読まない
+        let mut pe32 = image;
+        pe32[0x98..0x9a].copy_from_slice(&0x10bu16.to_le_bytes());
+        assert!(PeImage::parse(&pe32).is_err());
+        assert!(PeImage::parse(&image[..0x1a0]).is_err());
+        assert!(PeImage::parse(&image[..0x1b0]).is_ok());
+        assert!(PeImage::parse(b"\x7fELF").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use crate::x86::delay_us;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use crate::x86::InterruptGuard;
+use spin::Mutex;
+
+// 8259 PIC（Programmable Interrupt Controller）
+// マスター（IRQ0~7）とスレーブ（IRQ8~15）の2つのチップがIRQ2でカスケード接続されている
+const PIC1_CMD: u16 = 0x20;
+const PIC1_DATA: u16 = 0x21;
+const PIC2_CMD: u16 = 0xa0;
+const PIC2_DATA: u16 = 0xa1;
+
+const ICW1_INIT: u8 = 0x10; // 初期化開始
+const ICW1_ICW4: u8 = 0x01; // ICW4を送る
+const ICW4_8086: u8 = 0x01; // 8086モード
+const OCW3_READ_ISR: u8 = 0x0b; // 次の読み込みでISRを返す
+const CMD_EOI: u8 = 0x20; // End Of Interrupt
+
+// IRQ0がマップされる割り込みベクタ
+// 0x00~0x1fはCPU例外で使われているので、その直後に配置する
+pub const IRQ_VECTOR_BASE: usize = 0x20;
+pub const NUM_IRQS: usize = 16;
+// スレーブが繋がっているマスターのIRQ番号
+const CASCADE_IRQ: u8 = 2;
+
+// ベクタ番号からIRQ番号へ変換する（IRQでなければNone）
+pub fn irq_from_vector(vector: usize) -> Option<u8> {
+    if (IRQ_VECTOR_BASE..IRQ_VECTOR_BASE + NUM_IRQS).contains(&vector) {
+        Some((vector - IRQ_VECTOR_BASE) as u8)
+    } else {
+        None
+    }
+}
+
+// 古いPICはICWを続けて書くと取りこぼすことがあるので、1つ書くごとに少し待つ
+const ICW_DELAY_US: u64 = 1;
+
+// PICの初期化
+// ICW1~ICW4を送ってIRQ0~15をベクタ0x20~0x2fに再配置し、全てのIRQをマスクする
+pub fn init_pic() {
+    // ICW1: 初期化開始
+    write_io_port_u8(PIC1_CMD, ICW1_INIT | ICW1_ICW4);
+    delay_us(ICW_DELAY_US);
+    write_io_port_u8(PIC2_CMD, ICW1_INIT | ICW1_ICW4);
+    delay_us(ICW_DELAY_US);
+    // ICW2: ベクタのオフセット
+    write_io_port_u8(PIC1_DATA, IRQ_VECTOR_BASE as u8);
+    delay_us(ICW_DELAY_US);
+    write_io_port_u8(PIC2_DATA, (IRQ_VECTOR_BASE + 8) as u8);
+    delay_us(ICW_DELAY_US);
+    // ICW3: マスターにはスレーブが繋がっているIRQのビット、スレーブには自分のカスケードID
+    write_io_port_u8(PIC1_DATA, 1 << CASCADE_IRQ);
+    delay_us(ICW_DELAY_US);
+    write_io_port_u8(PIC2_DATA, CASCADE_IRQ);
+    delay_us(ICW_DELAY_US);
+    // ICW4: 8086モード
+    write_io_port_u8(PIC1_DATA, ICW4_8086);
+    delay_us(ICW_DELAY_US);
+    write_io_port_u8(PIC2_DATA, ICW4_8086);
+    delay_us(ICW_DELAY_US);
+    // OCW1: 最初は全てのIRQをマスクしておく
+    write_io_port_u8(PIC1_DATA, 0xff);
+    write_io_port_u8(PIC2_DATA, 0xff);
+}
+
+// IRQ番号に対応するデータポートとビット位置
+fn mask_port_and_bit(irq: u8) -> (u16, u8) {
+    assert!((irq as usize) < NUM_IRQS, "invalid IRQ number");
+    if irq < 8 {
+        (PIC1_DATA, irq)
+    } else {
+        (PIC2_DATA, irq - 8)
+    }
+}
+
+// 指定したIRQのマスクを外す
+pub fn enable_irq(irq: u8) {
+    let (port, bit) = mask_port_and_bit(irq);
+    write_io_port_u8(port, read_io_port_u8(port) & !(1 << bit));
+    // スレーブのIRQはマスター側のカスケードも開けておかないと届かない
+    if irq >= 8 {
+        enable_irq(CASCADE_IRQ);
+    }
+}
+
+// 指定したIRQをマスクする
+pub fn disable_irq(irq: u8) {
+    let (port, bit) = mask_port_and_bit(irq);
+    write_io_port_u8(port, read_io_port_u8(port) | (1 << bit));
+}
+
+// 割り込み処理の完了をPICに通知する
+pub fn send_eoi(irq: u8) {
+    if irq >= 8 {
+        write_io_port_u8(PIC2_CMD, CMD_EOI);
+    }
+    write_io_port_u8(PIC1_CMD, CMD_EOI);
+}
+
+// ISR（In-Service Register）を読む
+// 上位8bitがスレーブ、下位8bitがマスター
+fn read_isr() -> u16 {
+    write_io_port_u8(PIC1_CMD, OCW3_READ_ISR);
+    write_io_port_u8(PIC2_CMD, OCW3_READ_ISR);
+    ((read_io_port_u8(PIC2_CMD) as u16) << 8) | read_io_port_u8(PIC1_CMD) as u16
+}
+
+// IRQ7/IRQ15はノイズなどで偽の割り込み（spurious IRQ）が届くことがある
+// その場合はISRのビットが立っていない
+fn is_spurious(irq: u8) -> bool {
+    (irq == 7 || irq == 15) && read_isr() & (1 << irq) == 0
+}
+
+// IRQごとに登録されたハンドラ
+type IrqHandler = fn();
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; NUM_IRQS]> = Mutex::new([None; NUM_IRQS]);
+
+// IRQのハンドラを登録して、前に登録されていたハンドラを返す
+// 割り込みハンドラからも同じロックを取るので、ロック中は割り込みを禁止しておく
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Option<IrqHandler> {
+    set_irq_handler(irq, Some(handler))
+}
+
+// 登録されたハンドラを外す
+pub fn unregister_irq_handler(irq: u8) -> Option<IrqHandler> {
+    set_irq_handler(irq, None)
+}
+
+fn set_irq_handler(irq: u8, handler: Option<IrqHandler>) -> Option<IrqHandler> {
+    assert!((irq as usize) < NUM_IRQS, "invalid IRQ number");
+    let _guard = InterruptGuard::new();
+    core::mem::replace(&mut IRQ_HANDLERS.lock()[irq as usize], handler)
+}
+
+// IDTから呼ばれるIRQの処理
+// 登録されたハンドラを呼んでからEOIを送る
+pub fn dispatch_irq(irq: u8) {
+    if is_spurious(irq) {
+        // IRQ15の偽の割り込みはマスター側にとっては本物のIRQ2なので、マスターにだけEOIを送る
+        if irq == 15 {
+            write_io_port_u8(PIC1_CMD, CMD_EOI);
+        }
+        return;
+    }
+    // ハンドラの呼び出し中はロックを持たないように、先に取り出しておく
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+    send_eoi(irq);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::hlt;
+    use core::sync::atomic::AtomicUsize;
+    use core::sync::atomic::Ordering;
+
+    static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_tick() {
+        TICKS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn irq_from_vector_maps_pic_range() {
+        assert_eq!(irq_from_vector(0x1f), None);
+        assert_eq!(irq_from_vector(0x20), Some(0));
+        assert_eq!(irq_from_vector(0x2f), Some(15));
+        assert_eq!(irq_from_vector(0x30), None);
+    }
+
+    // タイマーのIRQ0のハンドラを差し替えて、実際に割り込みが何回か届くことを確認する
+    // （PITの設定と割り込みの許可はinit_basic_runtimeで済んでいる）
+    #[test_case]
+    fn timer_irq_is_dispatched() {
+        TICKS.store(0, Ordering::SeqCst);
+        let prev = register_irq_handler(0, count_tick);
+        enable_irq(0);
+        while TICKS.load(Ordering::SeqCst) < 3 {
+            hlt();
+        }
+        match prev {
+            Some(handler) => {
+                register_irq_handler(0, handler);
+            }
+            None => {
+                unregister_irq_handler(0);
+                disable_irq(0);
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::acpi;
+use crate::acpi::IoPorts;
+use crate::qemu::exit_qemu;
+use crate::qemu::is_qemu;
+use crate::qemu::QemuExitCode;
+use crate::result::Result;
+use crate::serial::switch_to_sync_tx;
+use crate::uefi::reset_system_shutdown;
+use crate::warn;
+use crate::x86::disable_interrupts;
+use crate::x86::hlt;
+use crate::x86::io_delay;
+
+// ACPIでS5に入ってから、電源が切れるのを待つ時間（io_delayは約1マイクロ秒）
+const POWEROFF_WAIT_US: usize = 500_000;
+
+// ACPIのS5で電源を切る（失敗したときだけ戻ってくる）
+fn acpi_poweroff() -> Result<()> {
+    let s5 = acpi::platform_info()?.s5.as_ref().map_err(|e| *e)?;
+    s5.enter_s5(&mut IoPorts)?;
+    for _ in 0..POWEROFF_WAIT_US {
+        io_delay();
+    }
+    Err("ACPI: the machine did not power off")
+}
+
+// 電源を切る
+// 自前のページテーブルに切り替えた後のEFIランタイムサービスは確実には動かないので、
+// ACPI、EFIのResetSystem、QEMUのisa-debug-exitの順に試す
+pub fn poweroff() -> ! {
+    disable_interrupts();
+    // 電源が切れる前に、送信バッファに残っている出力を送りきる
+    switch_to_sync_tx();
+    if let Err(e) = acpi_poweroff() {
+        warn!("{e}");
+    }
+    if let Err(e) = reset_system_shutdown() {
+        warn!("{e}");
+    }
+    if is_qemu() {
+        exit_qemu(QemuExitCode::Success);
+    }
+    warn!("Failed to power off. Halting...");
+    loop {
+        hlt()
+    }
+}
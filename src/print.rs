@@ -1,73 +1,495 @@
+#[cfg(test)]
+extern crate alloc;
+
+use crate::apic::LocalApic;
+use crate::cursor_blink::BlinkAction;
+use crate::graphics::draw_font_fg;
+use crate::graphics::fill_rect;
+use crate::graphics::Bitmap;
+use crate::klog;
 use crate::print;
-use crate::println;
-use crate::serial::SerialPort;
+use crate::result::Result;
+use crate::serial::global_port;
+use crate::sync::SpinLock;
+use crate::sync::SpinLockGuard;
+use crate::timer::ticks;
+use crate::timer::uptime_ms;
+use crate::tsc::is_calibrated;
+use crate::tsc::now_ns;
+use crate::uefi::VramBufferInfo;
+use crate::x86::InterruptGuard;
 use core::fmt;
+use core::fmt::Write;
 use core::mem::size_of;
 use core::slice;
+use core::str::FromStr;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
 
-// ターミナル上への出力（シリアルポートを介して）
-pub fn global_print(args: fmt::Arguments) {
-    let mut writer = SerialPort::default();
-    fmt::write(&mut writer, args).unwrap();
+// VRAMに文字を出力するコンソール
+// 画面の一番下まで書いたら画面を消して上から書き直す
+// VRAMが設定されるまでは何も出力しない
+// テストではVRAMの代わりにメモリ上のビットマップに描ける
+pub struct VramConsole<B = VramBufferInfo> {
+    vram: Option<B>,
+    cursor_x: i64,
+    cursor_y: i64,
+    // ログの色付けに使うエスケープシーケンスは画面には描かない
+    escape: AnsiStripper,
+    // カーソルを描いたマスの元のピクセル（描いていなければNone）
+    saved_cell: Option<[u32; VRAM_CURSOR_PIXELS]>,
+    // 前回カーソルを切り替えてから文字を書いたか
+    written: bool,
 }
+const VRAM_CURSOR_PIXELS: usize = (VramConsole::<VramBufferInfo>::CHAR_WIDTH
+    * VramConsole::<VramBufferInfo>::CHAR_HEIGHT) as usize;
+// VRAMはこのコンソールからしか書き換えない
+unsafe impl Send for VramConsole {}
+impl<B> VramConsole<B> {
+    const CHAR_WIDTH: i64 = 8;
+    const CHAR_HEIGHT: i64 = 16;
 
-// u8のバイト列を16進数表示
-fn hexdump_bytes(bytes: &[u8]) {
-    let mut i = 0;
-    let mut ascii = [0u8; 16];
-    let mut offset = 0;
-    for v in bytes.iter() {
-        // データの開始位置からの距離
-        // ８桁の16進数表示
-        if i == 0 {
-            print!("{offset:08X}: ");
-        }
-        // 1Byteずつ出力
-        print!("{:02X} ", v);
-        ascii[i] = *v;
-        i += 1;
-        if i == 16 {
-            print!("|");
-            // 1Byteずつ取得して、それを文字に変換
-            for c in ascii.iter() {
-                print!(
-                    "{}",
-                    match c {
-                        // スペース(0x20)から'~'(0x7e)まで
-                        // それ以外は'.'
-                        0x20..=0x7e => {
-                            *c as char
-                        }
-                        _ => {
-                            '.'
-                        }
-                    }
-                );
+    const fn new() -> Self {
+        Self {
+            vram: None,
+            cursor_x: 0,
+            cursor_y: 0,
+            escape: AnsiStripper::new(),
+            saved_cell: None,
+            written: false,
+        }
+    }
+}
+impl<B: Bitmap> VramConsole<B> {
+    pub fn for_bitmap(vram: B) -> Self {
+        Self {
+            vram: Some(vram),
+            ..Self::new()
+        }
+    }
+
+    pub fn bitmap(&self) -> Option<&B> {
+        self.vram.as_ref()
+    }
+
+    fn new_line(vram: &mut B, cursor_x: &mut i64, cursor_y: &mut i64) {
+        *cursor_x = 0;
+        *cursor_y += Self::CHAR_HEIGHT;
+        if *cursor_y + Self::CHAR_HEIGHT > vram.height() {
+            let (w, h) = (vram.width(), vram.height());
+            let _ = fill_rect(vram, 0x000000, 0, 0, w, h);
+            *cursor_y = 0;
+        }
+    }
+
+    // カーソルのマスのi番目のピクセルの座標（左上から行の順）
+    fn cursor_pixel(&self, i: usize) -> (i64, i64) {
+        let i = i as i64;
+        (
+            self.cursor_x + i % Self::CHAR_WIDTH,
+            self.cursor_y + i / Self::CHAR_WIDTH,
+        )
+    }
+
+    pub fn is_cursor_shown(&self) -> bool {
+        self.saved_cell.is_some()
+    }
+
+    // 次に文字を書くマスを、元のピクセルを覚えてから色を反転して描く
+    pub fn show_cursor(&mut self) {
+        if self.is_cursor_shown() || self.vram.is_none() {
+            return;
+        }
+        let mut saved = [0; VRAM_CURSOR_PIXELS];
+        for (i, saved) in saved.iter_mut().enumerate() {
+            let (x, y) = self.cursor_pixel(i);
+            // 画面からはみ出した部分は描かない
+            if let Some(p) = self.vram.as_mut().and_then(|vram| vram.pixel_at_mut(x, y)) {
+                *saved = *p;
+                *p ^= 0xffffff;
             }
-            println!("|");
-            offset += 16;
-            i = 0;
-        }
-    }
-    // バイト列が16個ない時
-    if i != 0 {
-        for _ in 0..(16 - i) {
-            print!("   ");
-        }
-        print!("|");
-        for c in ascii.iter().take(i) {
-            print!(
-                "{}",
-                if (0x20u8..=0x7fu8).contains(c) {
-                    *c as char
-                } else {
-                    '.'
-                }
-            )
         }
-        println!("|");
+        self.saved_cell = Some(saved);
+    }
+
+    // カーソルを消して、覚えておいたピクセルに戻す
+    pub fn hide_cursor(&mut self) {
+        let Some(saved) = self.saved_cell.take() else {
+            return;
+        };
+        for (i, p) in saved.iter().enumerate() {
+            let (x, y) = self.cursor_pixel(i);
+            if let Some(dst) = self.vram.as_mut().and_then(|vram| vram.pixel_at_mut(x, y)) {
+                *dst = *p;
+            }
+        }
+    }
+
+    // カーソルの表示と消去を入れ替える
+    // 前回から文字が書かれていたら、書いている最中とみなして消したままにする
+    pub fn toggle_cursor(&mut self) {
+        if core::mem::take(&mut self.written) || self.is_cursor_shown() {
+            self.hide_cursor();
+        } else {
+            self.show_cursor();
+        }
+    }
+
+    pub fn apply_blink(&mut self, action: BlinkAction) {
+        match action {
+            BlinkAction::Toggle => self.toggle_cursor(),
+            BlinkAction::Show => {
+                self.written = false;
+                self.show_cursor();
+            }
+            BlinkAction::Hide => self.hide_cursor(),
+        }
     }
 }
+impl<B: Bitmap> fmt::Write for VramConsole<B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // カーソルを描いたまま文字を書くと、後で消すときに文字まで消してしまう
+        self.hide_cursor();
+        self.written = true;
+        let Self {
+            vram: Some(vram),
+            cursor_x,
+            cursor_y,
+            escape,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+        for c in s.chars().filter(|c| escape.feed(*c)) {
+            if c == '\n' {
+                Self::new_line(vram, cursor_x, cursor_y);
+                continue;
+            }
+            if *cursor_x + Self::CHAR_WIDTH > vram.width() {
+                Self::new_line(vram, cursor_x, cursor_y);
+            }
+            draw_font_fg(vram, *cursor_x, *cursor_y, 0xffffff, c);
+            *cursor_x += Self::CHAR_WIDTH;
+        }
+        Ok(())
+    }
+}
+
+// ANSIエスケープシーケンスを取り除くフィルタ
+// シーケンスが複数回のwrite_strに分かれて届いても、状態を持ち越して取り除ける
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiStripper {
+    Text,
+    // ESCを受け取った直後
+    Escape,
+    // "ESC ["の後、終端文字(0x40..=0x7e)を待っている
+    Csi,
+}
+impl AnsiStripper {
+    pub const fn new() -> Self {
+        Self::Text
+    }
+    // cを表示するならtrueを返す
+    pub fn feed(&mut self, c: char) -> bool {
+        match (*self, c) {
+            (Self::Text, '\x1b') => *self = Self::Escape,
+            (Self::Text, _) => return true,
+            (Self::Escape, '[') => *self = Self::Csi,
+            // "ESC c"のような2文字のシーケンス
+            (Self::Escape, _) => *self = Self::Text,
+            // パラメータと中間文字
+            (Self::Csi, '\x20'..='\x3f') => {}
+            (Self::Csi, _) => *self = Self::Text,
+        }
+        false
+    }
+}
+impl Default for AnsiStripper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static VRAM_CONSOLE: Mutex<VramConsole> = Mutex::new(VramConsole::new());
+
+// VRAMコンソールの出力先を設定する（出力するにはregister_sinkで登録する）
+pub fn init_vram_console(vram: VramBufferInfo) {
+    let _guard = InterruptGuard::new();
+    *VRAM_CONSOLE.lock() = VramConsole {
+        vram: Some(vram),
+        ..VramConsole::new()
+    };
+}
+
+// 画面を黒く塗りつぶして、カーソルを左上に戻す
+pub fn clear_vram_console() {
+    let _guard = InterruptGuard::new();
+    let mut console = VRAM_CONSOLE.lock();
+    if let Some(vram) = console.vram.as_mut() {
+        let (w, h) = (vram.width(), vram.height());
+        let _ = fill_rect(vram, 0x000000, 0, 0, w, h);
+    }
+    console.cursor_x = 0;
+    console.cursor_y = 0;
+    // カーソルも塗りつぶしたので、元のピクセルに戻す必要はない
+    console.saved_cell = None;
+}
+
+// カーソルの点滅の操作をVRAMコンソールに反映する
+// 文字を書いている最中でロックが取れなければ、今回は何もしない
+pub fn apply_vram_cursor_blink(action: BlinkAction) {
+    let _guard = InterruptGuard::new();
+    if let Some(mut console) = VRAM_CONSOLE.try_lock() {
+        console.apply_blink(action);
+    }
+}
+
+pub fn vram_console_sink() -> Sink {
+    &VRAM_CONSOLE
+}
+
+// VRAMコンソールが出力先に登録されていれば、その画面に直接描画する
+// panic時に使うので、描画中でロックが取れないときは待たずに諦める
+pub fn with_vram_console<F: FnOnce(&mut VramBufferInfo)>(f: F) -> bool {
+    let _guard = InterruptGuard::new();
+    let registered = SINKS.try_lock().is_some_and(|sinks| {
+        sinks
+            .iter()
+            .flatten()
+            .any(|s| core::ptr::addr_eq(*s, vram_console_sink()))
+    });
+    if !registered {
+        return false;
+    }
+    let Some(mut console) = VRAM_CONSOLE.try_lock() else {
+        return false;
+    };
+    let Some(vram) = console.vram.as_mut() else {
+        return false;
+    };
+    f(vram);
+    true
+}
+
+// print!などの出力先（通常はCOM1）に送るシンク
+pub struct SerialSink;
+// 初期化される前に出力しようとしたら、先に初期化する
+impl fmt::Write for SerialSink {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut port = global_port();
+        port.init_once();
+        port.send_str(s);
+        Ok(())
+    }
+}
+
+static SERIAL_SINK: Mutex<SerialSink> = Mutex::new(SerialSink);
+
+pub fn serial_sink() -> Sink {
+    &SERIAL_SINK
+}
+
+// print!の出力先
+pub type Sink = &'static Mutex<dyn fmt::Write + Send>;
+
+// 同時に登録できるシンクの数
+// アロケータが使えるようになる前から出力するので、固定長の配列で持つ
+// シリアル、klog、debugcon、VRAMのコンソールで4つ使うので、テストで追加する分も空けておく
+pub const MAX_SINKS: usize = 8;
+// シリアルとklogには最初から出力する
+static SINKS: SpinLock<[Option<Sink>; MAX_SINKS]> = SpinLock::new({
+    let mut sinks: [Option<Sink>; MAX_SINKS] = [None; MAX_SINKS];
+    sinks[0] = Some(&SERIAL_SINK);
+    sinks[1] = Some(klog::sink());
+    sinks
+});
+
+// print!の出力先を追加する（既に登録されていれば何もしない）
+pub fn register_sink(sink: Sink) -> Result<()> {
+    let mut sinks = SINKS.lock();
+    if sinks.iter().flatten().any(|s| core::ptr::addr_eq(*s, sink)) {
+        return Ok(());
+    }
+    let slot = sinks
+        .iter_mut()
+        .find(|s| s.is_none())
+        .ok_or("Too many print sinks")?;
+    *slot = Some(sink);
+    Ok(())
+}
+
+// print!の出力先から外す（登録されていたらtrue）
+pub fn unregister_sink(sink: Sink) -> bool {
+    let mut sinks = SINKS.lock();
+    for slot in sinks.iter_mut() {
+        if slot.is_some_and(|s| core::ptr::addr_eq(s, sink)) {
+            *slot = None;
+            return true;
+        }
+    }
+    false
+}
+
+// sinkがprint!の出力先に登録されていればtrue
+pub fn is_sink_registered(sink: Sink) -> bool {
+    SINKS
+        .lock()
+        .iter()
+        .flatten()
+        .any(|s| core::ptr::addr_eq(*s, sink))
+}
+
+// global_printの1回分の出力が、割り込みハンドラなどからの出力と混ざらないようにするロック
+// 今はCPUごとではなく全体で1つだけ持つ
+static PRINT_LOCK: SpinLock<()> = SpinLock::new(());
+// PRINT_LOCKを持っているCPUのLocal APIC ID（NO_OWNERなら誰も持っていない）
+// 同じCPUがシンクの中から出力したときだけ、ロックを取らずに続けるために覚えておく
+static PRINT_OWNER: AtomicU32 = AtomicU32::new(NO_OWNER);
+const NO_OWNER: u32 = u32::MAX;
+// シンクがエラーを返した回数
+static SINK_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+// 出力の途中でpanicした場合、panicの報告はprint!を使わずに直接シリアルに書く
+pub fn is_printing() -> bool {
+    PRINT_LOCK.is_locked()
+}
+
+// panicでglobal_printを抜けたときに残ったロックを外す（panicの後も動き続けるテストランナー用）
+// panicしたglobal_printには戻らないので、ロックを持っていた側とぶつかることはない
+pub fn reset_printing() {
+    PRINT_OWNER.store(NO_OWNER, Ordering::SeqCst);
+    unsafe { PRINT_LOCK.force_unlock() };
+}
+
+pub fn sink_errors() -> u64 {
+    SINK_ERRORS.load(Ordering::Relaxed)
+}
+
+// 今動いているCPUのLocal APIC ID（Local APICを初期化する前はBSPしか動いていないので0）
+fn current_cpu_id() -> u32 {
+    LocalApic::current().map_or(0, |apic| apic.id())
+}
+
+// PRINT_LOCKのガード（手放す前に持ち主を消す）
+struct PrintGuard {
+    _lock: SpinLockGuard<'static, ()>,
+}
+impl PrintGuard {
+    fn lock(cpu: u32) -> Self {
+        let lock = PRINT_LOCK.lock();
+        PRINT_OWNER.store(cpu, Ordering::SeqCst);
+        Self { _lock: lock }
+    }
+}
+impl Drop for PrintGuard {
+    fn drop(&mut self) {
+        PRINT_OWNER.store(NO_OWNER, Ordering::SeqCst);
+    }
+}
+
+// 登録されている全てのシンクに出力する
+// あるシンクがエラーを返しても、数えるだけで他のシンクには出力する
+// 1回の呼び出しの出力は、割り込みハンドラからの出力と行の途中で混ざらない
+pub fn global_print(args: fmt::Arguments) {
+    // ロック中は割り込みが禁止されるので、同じCPUが出力中にここに来るのはシンクの中からの出力かpanicだけ
+    // シンクの中からの出力は、外側が既にロックを持っているので取らずに続ける
+    // 他のCPUが持っているときは、その出力が終わるまで待つ
+    let cpu = current_cpu_id();
+    let _lock = (PRINT_OWNER.load(Ordering::SeqCst) != cpu).then(|| PrintGuard::lock(cpu));
+    let sinks = *SINKS.lock();
+    for sink in sinks.iter().flatten() {
+        // シンクへの出力中にそのシンクへ出力しようとした場合は、デッドロックしないように飛ばす
+        if let Some(mut w) = sink.try_lock() {
+            if fmt::write(&mut *w, args).is_err() {
+                SINK_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+// 16進ダンプの右側に表示する文字
+// スペース(0x20)から'~'(0x7e)まで、それ以外は'.'
+fn printable_char(c: u8) -> char {
+    match c {
+        0x20..=0x7e => c as char,
+        _ => '.',
+    }
+}
+
+// 16進ダンプの1行分（アドレス、16バイト分の16進数、文字、改行で、アドレスが16桁なら85バイト）を組み立てるバッファ
+// 1バイトごとにprint!すると、その度にシンクを順に呼んでシリアルの送信を待つので遅い
+struct HexdumpLine {
+    buf: [u8; Self::CAPACITY],
+    len: usize,
+}
+impl HexdumpLine {
+    const CAPACITY: usize = 88;
+
+    // "{addr:08X}: XX XX ... |........|\n"
+    fn new(addr: usize, row: &[u8]) -> Self {
+        let mut line = Self {
+            buf: [0; Self::CAPACITY],
+            len: 0,
+        };
+        // 16バイト以下の行なら必ず収まる
+        let _ = write!(line, "{addr:08X}: ");
+        for v in row {
+            let _ = write!(line, "{v:02X} ");
+        }
+        // バイト列が16個ない時は、右側の文字の位置をそろえる
+        for _ in row.len()..16 {
+            let _ = line.write_str("   ");
+        }
+        let _ = line.write_char('|');
+        for c in row {
+            let _ = line.write_char(printable_char(*c));
+        }
+        let _ = line.write_str("|\n");
+        line
+    }
+    fn as_str(&self) -> &str {
+        // ASCIIしか書かないので必ずUTF-8として正しい
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+impl fmt::Write for HexdumpLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// バイト列を16バイトずつ16進数表示する
+// 左端にはaddr_labelから始まるアドレスを表示するので、実際のアドレスと対応付けられる
+// 1行ずつまとめてからprint!する
+pub fn hexdump_slice(addr_label: usize, bytes: &[u8]) {
+    for (i, row) in bytes.chunks(16).enumerate() {
+        print!("{}", HexdumpLine::new(addr_label + i * 16, row).as_str());
+    }
+}
+
+// u8のバイト列を16進数表示（オフセットは0から）
+fn hexdump_bytes(bytes: &[u8]) {
+    hexdump_slice(0, bytes);
+}
+
+// 任意のアドレスのメモリを16進数表示
+/// # Safety
+/// addrからlenバイトが読み取り可能なメモリでなければならない
+pub unsafe fn hexdump_range(addr: usize, len: usize) {
+    hexdump_slice(addr, slice::from_raw_parts(addr as *const u8, len));
+}
+
 // どのような型(T)でも16進数のスライスに変換
 // ポインタからデータを取得
 // バイト単位でデータを読み取った方が操作しやすい
@@ -88,23 +510,768 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// ログの重要度（小さいほど重要）
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+impl LogLevel {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => Self::Error,
+            1 => Self::Warn,
+            2 => Self::Info,
+            3 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+    // コマンドラインのloglevel=error|warn|info|debug|traceを読む（なければNone）
+    pub fn from_command_line(command_line: &str) -> Option<Self> {
+        command_line
+            .split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("loglevel="))
+            .find_map(|v| v.parse().ok())
+    }
+}
+// ブート時のコマンドラインやシェルから"warn"のように指定できるようにする
+impl FromStr for LogLevel {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err("Unknown log level"),
+        }
+    }
+}
+
+// このレベルより重要度の低いログは出力しない
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    LogLevel::from_u8(LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+// levelのログを出力するか
+// ログマクロはこれを確かめてからフォーマットするので、出力しないログはほとんどコストがかからない
+#[inline]
+pub fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+static LOG_COLOR: AtomicBool = AtomicBool::new(true);
+
+// エスケープシーケンスを解釈しない端末やCIのログ向けに、色付けをやめる
+pub fn set_log_color(enabled: bool) {
+    LOG_COLOR.store(enabled, Ordering::Relaxed);
+}
+
+// ログのレベル表示（"[ERROR]"など）
+// 色付けが有効なら、タグの部分だけをANSIエスケープシーケンスで色付けする
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelTag(pub LogLevel);
+impl LevelTag {
+    const RESET: &'static str = "\x1b[0m";
+    fn text(&self) -> &'static str {
+        match self.0 {
+            LogLevel::Error => "[ERROR]",
+            LogLevel::Warn => "[WARN]",
+            LogLevel::Info => "[INFO]",
+            LogLevel::Debug => "[DEBUG]",
+            LogLevel::Trace => "[TRACE]",
+        }
+    }
+    // SGRの色指定（Noneなら端末のデフォルトの色のまま）
+    fn sgr(&self) -> Option<&'static str> {
+        match self.0 {
+            LogLevel::Error => Some("\x1b[31m"),
+            LogLevel::Warn => Some("\x1b[33m"),
+            LogLevel::Info => None,
+            LogLevel::Debug | LogLevel::Trace => Some("\x1b[2m"),
+        }
+    }
+}
+// 色付けの有無に関わらず、後ろの空白も含めて8文字分の幅にそろえる
+impl fmt::Display for LevelTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = self.text();
+        match self.sgr() {
+            Some(sgr) if LOG_COLOR.load(Ordering::Relaxed) => {
+                write!(f, "{sgr}{text}{}", Self::RESET)?
+            }
+            _ => f.write_str(text)?,
+        }
+        write!(f, "{:1$}", "", 8 - text.len())
+    }
+}
+
+// ログの先頭に付けるタイムスタンプ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimestamp {
+    Disabled,
+    // 起動してからのマイクロ秒
+    Micros(u64),
+    // タイマーが動く前は時刻が分からないので、ログの順番だけを表す通し番号を使う
+    Sequence(u64),
+}
+
+static LOG_TIMESTAMPS: AtomicBool = AtomicBool::new(true);
+static LOG_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+// 時刻の取り方が切り替わっても逆戻りしないように、最後に出した時刻を覚えておく
+static LAST_LOG_MICROS: AtomicU64 = AtomicU64::new(0);
+
+// テストで出力を比較するときなどに、タイムスタンプを消す
+pub fn set_log_timestamps(enabled: bool) {
+    LOG_TIMESTAMPS.store(enabled, Ordering::Relaxed);
+}
+
+impl LogTimestamp {
+    // TSCがキャリブレーション済みならTSC、タイマーが動いていればティック数から時刻を求める
+    pub fn now() -> Self {
+        if !LOG_TIMESTAMPS.load(Ordering::Relaxed) {
+            return Self::Disabled;
+        }
+        let micros = if is_calibrated() {
+            now_ns() / 1000
+        } else if ticks() != 0 {
+            uptime_ms() * 1000
+        } else {
+            return Self::Sequence(LOG_SEQUENCE.fetch_add(1, Ordering::Relaxed));
+        };
+        let prev = LAST_LOG_MICROS.fetch_max(micros, Ordering::Relaxed);
+        Self::Micros(prev.max(micros))
+    }
+}
+// 浮動小数点を使わずに、桁をそろえて出力する
+impl fmt::Display for LogTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Disabled => Ok(()),
+            Self::Micros(us) => write!(f, "[{:>5}.{:06}] ", us / 1_000_000, us % 1_000_000),
+            Self::Sequence(n) => write!(f, "[#{n:>11}] "),
+        }
+    }
+}
+
 // file!(): 呼び出されたファイル名
 // line!(): 呼び出された行数
 
 // ログメッセージ
 #[macro_export]
 macro_rules! info {
-    ($($arg:tt)*) => ($crate::print!("[INFO]  {}:{:<1}: {}\n", file!(), line!(), format_args!($($arg)*)))
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Info) {
+            $crate::print!("{}{}{}:{:<1}: {}\n", $crate::print::LogTimestamp::now(), $crate::print::LevelTag($crate::print::LogLevel::Info), file!(), line!(), format_args!($($arg)*))
+        }
+    };
 }
 
 // 警告メッセージ
 #[macro_export]
 macro_rules! warn {
-    ($($arg:tt)*) => ($crate::print!("[WARN]  {}:{:<1}: {}\n", file!(), line!(), format_args!($($arg)*)));
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Warn) {
+            $crate::print!("{}{}{}:{:<1}: {}\n", $crate::print::LogTimestamp::now(), $crate::print::LevelTag($crate::print::LogLevel::Warn), file!(), line!(), format_args!($($arg)*))
+        }
+    };
 }
 
 // エラーメッセージ
 #[macro_export]
 macro_rules! error {
-    ($($arg:tt)*) => ($crate::print!("[ERROR] {}:{:<1}: {}\n", file!(), line!(), format_args!($($arg)*)));
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Error) {
+            $crate::print!("{}{}{}:{:<1}: {}\n", $crate::print::LogTimestamp::now(), $crate::print::LevelTag($crate::print::LogLevel::Error), file!(), line!(), format_args!($($arg)*))
+        }
+    };
+}
+
+// デバッグ用の詳しいメッセージ
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Debug) {
+            $crate::print!("{}{}{}:{:<1}: {}\n", $crate::print::LogTimestamp::now(), $crate::print::LevelTag($crate::print::LogLevel::Debug), file!(), line!(), format_args!($($arg)*))
+        }
+    };
+}
+
+// debug!よりさらに細かいトレース
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if $crate::print::log_enabled($crate::print::LogLevel::Trace) {
+            $crate::print!("{}{}{}:{:<1}: {}\n", $crate::print::LogTimestamp::now(), $crate::print::LevelTag($crate::print::LogLevel::Trace), file!(), line!(), format_args!($($arg)*))
+        }
+    };
+}
+
+// kassert!などが失敗したときの内容
+// アロケータが使えないところでも使えるように、メッセージは固定長のバッファに入れる（入りきらない分は捨てる）
+#[derive(Clone)]
+pub struct AssertionFailure {
+    file: &'static str,
+    line: u32,
+    message: [u8; Self::MAX_MESSAGE_LEN],
+    len: usize,
+}
+impl AssertionFailure {
+    // kassert_eq!の式と両方の値が1行ずつ入る長さ（Resultのエラーとして返すので、大きくしすぎない）
+    const MAX_MESSAGE_LEN: usize = 256;
+
+    pub fn new(file: &'static str, line: u32, args: fmt::Arguments) -> Self {
+        let mut failure = Self {
+            file,
+            line,
+            message: [0; Self::MAX_MESSAGE_LEN],
+            len: 0,
+        };
+        let _ = fmt::write(&mut failure, args);
+        failure
+    }
+    pub fn file(&self) -> &'static str {
+        self.file
+    }
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+    pub fn message(&self) -> &str {
+        match core::str::from_utf8(&self.message[..self.len]) {
+            Ok(s) => s,
+            // 文字の途中で切れていたら、その手前まで
+            Err(e) => core::str::from_utf8(&self.message[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+impl fmt::Write for AssertionFailure {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(Self::MAX_MESSAGE_LEN - self.len);
+        self.message[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+impl fmt::Display for AssertionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message())
+    }
+}
+impl fmt::Debug for AssertionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AssertionFailure({self})")
+    }
+}
+
+// kassert!などを使うテストの戻り値
+pub type TestResult = core::result::Result<(), AssertionFailure>;
+
+// kassert!などが失敗したときの振る舞いを、使われた関数の戻り値の型で切り替える
+// TestResultを返す関数（テスト）では失敗を返して、テストランナーに残りのテストを続けさせる
+// それ以外のカーネルのコードでは、error!で出力してからpanicする
+pub trait KassertReturn {
+    fn assertion_failed(failure: AssertionFailure) -> Self;
+}
+impl<T> KassertReturn for core::result::Result<T, AssertionFailure> {
+    fn assertion_failed(failure: AssertionFailure) -> Self {
+        Err(failure)
+    }
+}
+impl KassertReturn for () {
+    fn assertion_failed(failure: AssertionFailure) -> Self {
+        crate::error!("{failure}");
+        panic!("{failure}");
+    }
+}
+
+// テスト関数の戻り値をTestResultにそろえる（()を返すテストは常に成功）
+pub trait IntoTestResult {
+    fn into_test_result(self) -> TestResult;
+}
+impl IntoTestResult for () {
+    fn into_test_result(self) -> TestResult {
+        Ok(())
+    }
+}
+impl IntoTestResult for TestResult {
+    fn into_test_result(self) -> TestResult {
+        self
+    }
+}
+
+// 条件が成り立たなければ、式と（あれば）メッセージを記録して失敗する
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            return $crate::print::KassertReturn::assertion_failed(
+                $crate::print::AssertionFailure::new(
+                    file!(),
+                    line!(),
+                    format_args!("assertion failed: {}", stringify!($cond)),
+                ),
+            );
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            return $crate::print::KassertReturn::assertion_failed(
+                $crate::print::AssertionFailure::new(
+                    file!(),
+                    line!(),
+                    format_args!(
+                        "assertion failed: {}: {}",
+                        stringify!($cond),
+                        format_args!($($arg)+)
+                    ),
+                ),
+            );
+        }
+    };
+}
+
+// 2つの値が等しくなければ、両方の式とDebug表示を記録して失敗する
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    return $crate::print::KassertReturn::assertion_failed(
+                        $crate::print::AssertionFailure::new(
+                            file!(),
+                            line!(),
+                            format_args!(
+                                "assertion failed: {} == {}\n  left: {:?}\n right: {:?}",
+                                stringify!($left),
+                                stringify!($right),
+                                left,
+                                right
+                            ),
+                        ),
+                    );
+                }
+            }
+        }
+    };
+}
+
+// 2つの値が等しければ、両方の式とDebug表示を記録して失敗する
+#[macro_export]
+macro_rules! kassert_ne {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if *left == *right {
+                    return $crate::print::KassertReturn::assertion_failed(
+                        $crate::print::AssertionFailure::new(
+                            file!(),
+                            line!(),
+                            format_args!(
+                                "assertion failed: {} != {}\n  left: {:?}\n right: {:?}",
+                                stringify!($left),
+                                stringify!($right),
+                                left,
+                                right
+                            ),
+                        ),
+                    );
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serial::SerialPort;
+    use crate::timer::set_tick_hook;
+    use crate::timer::sleep_ms;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn log_level_is_read_from_command_line() {
+        assert_eq!(LogLevel::from_command_line(""), None);
+        assert_eq!(
+            LogLevel::from_command_line("nokaslr loglevel=debug"),
+            Some(LogLevel::Debug)
+        );
+        // 読めない値は飛ばして、次の指定を使う
+        assert_eq!(
+            LogLevel::from_command_line("loglevel=loud loglevel=warn"),
+            Some(LogLevel::Warn)
+        );
+        for level in ["error", "warn", "info", "debug", "trace"] {
+            assert_eq!(level.parse::<LogLevel>().unwrap().name(), level);
+        }
+    }
+    #[test_case]
+    fn log_level_filters_messages() {
+        let prev = log_level();
+        set_log_level(LogLevel::Warn);
+        set_log_timestamps(false);
+        set_log_color(false);
+        start_capture();
+        error!("e");
+        warn!("w");
+        info!("i");
+        debug!("d");
+        trace!("t");
+        let captured = stop_capture();
+        set_log_color(true);
+        set_log_timestamps(true);
+        set_log_level(prev);
+        let tags: alloc::vec::Vec<&str> = captured.lines().map(|l| &l[..7]).collect();
+        assert_eq!(tags, ["[ERROR]", "[WARN] "]);
+    }
+    // 全てのレベルのログを1行ずつ出力して、出力された内容を返す
+    fn capture_all_levels(color: bool) -> String {
+        let prev = log_level();
+        set_log_level(LogLevel::Trace);
+        set_log_timestamps(false);
+        set_log_color(color);
+        start_capture();
+        error!("e");
+        warn!("w");
+        info!("i");
+        debug!("d");
+        trace!("t");
+        let captured = stop_capture();
+        set_log_color(true);
+        set_log_timestamps(true);
+        set_log_level(prev);
+        captured
+    }
+    #[test_case]
+    fn log_tags_are_colored_only_when_enabled() {
+        let colored = capture_all_levels(true);
+        let tags: alloc::vec::Vec<&str> = colored
+            .lines()
+            .map(|l| l.split_once(' ').expect("no tag").0)
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                "\x1b[31m[ERROR]\x1b[0m",
+                "\x1b[33m[WARN]\x1b[0m",
+                "[INFO]",
+                "\x1b[2m[DEBUG]\x1b[0m",
+                "\x1b[2m[TRACE]\x1b[0m",
+            ]
+        );
+        let plain = capture_all_levels(false);
+        assert!(!plain.contains('\x1b'));
+        assert!(plain.lines().all(|l| l.as_bytes()[8] == b's'));
+    }
+    #[test_case]
+    fn stripper_removes_sequences_split_across_writes() {
+        let colored = capture_all_levels(true);
+        let plain = capture_all_levels(false);
+        // どこで分割して渡しても、色付けなしの出力と同じになる
+        for (i, _) in colored.char_indices() {
+            let mut stripper = AnsiStripper::new();
+            let (a, b) = colored.split_at(i);
+            let mut stripped: String = a.chars().filter(|c| stripper.feed(*c)).collect();
+            stripped.extend(b.chars().filter(|c| stripper.feed(*c)));
+            assert_eq!(stripped, plain);
+        }
+    }
+    #[test_case]
+    fn log_level_parses_names() {
+        assert_eq!("debug".parse(), Ok(LogLevel::Debug));
+        assert!("verbose".parse::<LogLevel>().is_err());
+        assert!(LogLevel::Error < LogLevel::Trace);
+    }
+    #[test_case]
+    fn timestamps_are_monotonic() {
+        start_capture();
+        info!("first");
+        info!("second");
+        let captured = stop_capture();
+        let micros: alloc::vec::Vec<u64> = captured
+            .lines()
+            .map(|l| {
+                let (secs, frac) = l[1..13].trim().split_once('.').expect("no timestamp");
+                secs.parse::<u64>().unwrap() * 1_000_000 + frac.parse::<u64>().unwrap()
+            })
+            .collect();
+        assert_eq!(micros.len(), 2);
+        assert!(micros[0] <= micros[1]);
+    }
+    #[test_case]
+    fn timestamp_has_six_fractional_digits() {
+        use alloc::format;
+        assert_eq!(
+            format!("{}", LogTimestamp::Micros(12_345_678)),
+            "[   12.345678] "
+        );
+        assert_eq!(format!("{}", LogTimestamp::Micros(5)), "[    0.000005] ");
+        assert_eq!(format!("{}", LogTimestamp::Sequence(42)), "[#         42] ");
+        assert_eq!(format!("{}", LogTimestamp::Disabled), "");
+    }
+    #[test_case]
+    fn hexdump_slice_prints_addresses_and_partial_row() {
+        let bytes: alloc::vec::Vec<u8> = (0x5c..0x5c + 40).collect();
+        start_capture();
+        hexdump_slice(0x1000, &bytes);
+        let captured = stop_capture();
+        let expected = [
+            "00001000: 5C 5D 5E 5F 60 61 62 63 64 65 66 67 68 69 6A 6B |\\]^_`abcdefghijk|",
+            "00001010: 6C 6D 6E 6F 70 71 72 73 74 75 76 77 78 79 7A 7B |lmnopqrstuvwxyz{|",
+            // 0x7fは表示できない文字なので'.'になる
+            "00001020: 7C 7D 7E 7F 80 81 82 83                         ||}~.....|",
+        ];
+        assert!(captured.lines().eq(expected));
+    }
+
+    #[test_case]
+    fn hexdump_lines_are_formatted_byte_for_byte() {
+        let row: Vec<u8> = (0..16).map(|i| i * 7 + 3).collect();
+        assert_eq!(
+            HexdumpLine::new(0xffff_f000, &row).as_str(),
+            "FFFFF000: 03 0A 11 18 1F 26 2D 34 3B 42 49 50 57 5E 65 6C |.....&-4;BIPW^el|\n"
+        );
+        // 16バイトない行は、右側の文字の位置をそろえる
+        assert_eq!(
+            HexdumpLine::new(0x10, &[0x7e, 0x7f, 0x20]).as_str(),
+            "00000010: 7E 7F 20                                        |~. |\n"
+        );
+        // 一番長い行もバッファに収まる
+        let longest = HexdumpLine::new(usize::MAX, &[0; 16]);
+        assert_eq!(
+            longest.as_str(),
+            "FFFFFFFFFFFFFFFF: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 |................|\n"
+        );
+        assert_eq!(longest.len, 85);
+    }
+
+    // 何もしないシンクだけを出力先にしてfを実行する（シリアルの送信待ちを測らないため）
+    struct NullSink;
+    impl fmt::Write for NullSink {
+        fn write_str(&mut self, _s: &str) -> fmt::Result {
+            Ok(())
+        }
+    }
+    static NULL_SINK: Mutex<NullSink> = Mutex::new(NullSink);
+    fn with_null_sink(f: impl FnOnce()) {
+        let mut sinks: [Option<Sink>; MAX_SINKS] = [None; MAX_SINKS];
+        sinks[0] = Some(&NULL_SINK);
+        let saved = core::mem::replace(&mut *SINKS.lock(), sinks);
+        f();
+        *SINKS.lock() = saved;
+    }
+
+    // 1行ずつまとめる前の書き方と比べる
+    crate::kernel_bench!(hexdump_4k_per_byte, {
+        let bytes = [0x5au8; 4096];
+        move || {
+            with_null_sink(|| {
+                for (i, row) in core::hint::black_box(&bytes).chunks(16).enumerate() {
+                    print!("{:08X}: ", i * 16);
+                    for v in row {
+                        print!("{:02X} ", v);
+                    }
+                    for _ in row.len()..16 {
+                        print!("   ");
+                    }
+                    print!("|");
+                    for c in row {
+                        print!("{}", printable_char(*c));
+                    }
+                    println!("|");
+                }
+            })
+        }
+    });
+    crate::kernel_bench!(hexdump_4k_buffered, {
+        let bytes = [0x5au8; 4096];
+        move || with_null_sink(|| hexdump_slice(0, core::hint::black_box(&bytes)))
+    });
+
+    // 出力された内容をためておくシンク
+    struct CaptureSink(String);
+    impl fmt::Write for CaptureSink {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.push_str(s);
+            Ok(())
+        }
+    }
+    static CAPTURE: Mutex<CaptureSink> = Mutex::new(CaptureSink(String::new()));
+
+    fn start_capture() {
+        {
+            let _guard = InterruptGuard::new();
+            CAPTURE.lock().0.clear();
+        }
+        register_sink(&CAPTURE).expect("register_sink failed");
+    }
+    fn stop_capture() -> String {
+        unregister_sink(&CAPTURE);
+        let _guard = InterruptGuard::new();
+        core::mem::take(&mut CAPTURE.lock().0)
+    }
+
+    // エラーを返すシンク
+    struct FailingSink;
+    impl fmt::Write for FailingSink {
+        fn write_str(&mut self, _s: &str) -> fmt::Result {
+            Err(fmt::Error)
+        }
+    }
+    static FAILING: Mutex<FailingSink> = Mutex::new(FailingSink);
+
+    #[test_case]
+    fn capture_sink_receives_what_serial_does() {
+        let com1 = SerialPort::new_for_com1();
+        while com1.try_read_char().is_some() {}
+        // COM1をループバックモードにして、シリアルに出力されたものを受信バッファで受け取る
+        com1.set_loopback(true);
+        // エラーを返すシンクがあっても、他のシンクには出力される
+        register_sink(&FAILING).expect("register_sink failed");
+        start_capture();
+        print!("sink {}", 42);
+        let captured = stop_capture();
+        unregister_sink(&FAILING);
+        sleep_ms(5);
+        com1.set_loopback(false);
+        let mut received = alloc::vec::Vec::new();
+        while let Some(c) = com1.try_read_char() {
+            received.push(c);
+        }
+        assert_eq!(captured, "sink 42");
+        assert_eq!(received, captured.as_bytes());
+    }
+    #[test_case]
+    fn sink_errors_are_counted_and_printing_continues() {
+        let errors = sink_errors();
+        register_sink(&FAILING).expect("register_sink failed");
+        start_capture();
+        print!("first");
+        print!(" second");
+        unregister_sink(&FAILING);
+        print!(" third");
+        let captured = stop_capture();
+        assert_eq!(captured, "first second third");
+        assert_eq!(sink_errors(), errors + 2);
+        assert!(!is_printing());
+        assert_eq!(PRINT_OWNER.load(Ordering::SeqCst), NO_OWNER);
+    }
+
+    static TIMER_LINES: AtomicU64 = AtomicU64::new(0);
+    fn print_from_timer() {
+        let n = TIMER_LINES.fetch_add(1, Ordering::Relaxed);
+        println!("timer {n} tttttttt");
+    }
+    // 割り込みハンドラとメインループが同時に出力しても、行が混ざらないことを確認する
+    #[test_case]
+    fn lines_from_interrupts_are_not_interleaved() {
+        TIMER_LINES.store(0, Ordering::Relaxed);
+        start_capture();
+        set_tick_hook(Some(print_from_timer));
+        let mut main_lines = 0;
+        while TIMER_LINES.load(Ordering::Relaxed) < 20 && main_lines < 100_000 {
+            // 1行を何回かに分けてシンクに書く
+            println!("main {main_lines} mmmmmmmm {}", main_lines * 7);
+            main_lines += 1;
+        }
+        set_tick_hook(None);
+        let captured = stop_capture();
+        let timer_lines = TIMER_LINES.load(Ordering::Relaxed);
+        assert!(timer_lines >= 20, "timer_lines = {timer_lines}");
+        assert!(captured.ends_with('\n'));
+        let (mut main_seen, mut timer_seen) = (0, 0);
+        for line in captured.lines() {
+            let words: Vec<&str> = line.split(' ').collect();
+            match words[..] {
+                ["main", n, "mmmmmmmm", m] => {
+                    assert_eq!(n.parse::<u64>().unwrap() * 7, m.parse().unwrap(), "{line}");
+                    main_seen += 1;
+                }
+                ["timer", n, "tttttttt"] if n.parse::<u64>().is_ok() => timer_seen += 1,
+                _ => panic!("broken line: {line:?}"),
+            }
+        }
+        assert_eq!(main_seen, main_lines);
+        assert_eq!(timer_seen, timer_lines);
+    }
+    #[test_case]
+    fn register_sink_is_idempotent_and_bounded() {
+        register_sink(&CAPTURE).expect("register_sink failed");
+        register_sink(&CAPTURE).expect("register_sink failed");
+        assert!(unregister_sink(&CAPTURE));
+        assert!(!unregister_sink(&CAPTURE));
+    }
+
+    // メモリ上のコンソールと、自分で進めるティックでカーソルの点滅を確かめる
+    #[test_case]
+    fn vram_cursor_blinks_and_is_restored() {
+        use crate::cursor_blink::BlinkTimer;
+        use crate::graphics_tests::OwnedBitmap;
+        use core::fmt::Write;
+
+        let mut console = VramConsole::for_bitmap(OwnedBitmap::new(64, 32));
+        let blink = BlinkTimer::new(true, 5);
+        let cell = |console: &VramConsole<OwnedBitmap>, col: i64| -> Vec<u32> {
+            let bitmap = console.bitmap().expect("no bitmap");
+            (0..16)
+                .flat_map(|y| (0..8).map(move |x| (col * 8 + x, y)))
+                .map(|(x, y)| bitmap.pixel_at(x, y).expect("out of range"))
+                .collect()
+        };
+        write!(console, "ab").unwrap();
+        let blank = cell(&console, 2);
+        let inverted: Vec<u32> = blank.iter().map(|p| p ^ 0xffffff).collect();
+        let mut states = Vec::new();
+        for now in 1..=30 {
+            blink.on_tick(now);
+            if let Some(action) = blink.take_action() {
+                console.apply_blink(action);
+                states.push(console.is_cursor_shown());
+                let expected = if console.is_cursor_shown() {
+                    &inverted
+                } else {
+                    &blank
+                };
+                assert_eq!(&cell(&console, 2), expected);
+            }
+        }
+        // 書いた直後の1回は消したままで、その後は表示と消去を繰り返す
+        assert_eq!(states, [false, true, false, true, false, true]);
+        // 表示中に書いても、カーソルの跡は残らない
+        blink.reset_phase(31);
+        console.apply_blink(blink.take_action().unwrap());
+        assert!(console.is_cursor_shown());
+        write!(console, "c").unwrap();
+        let mut expected = VramConsole::for_bitmap(OwnedBitmap::new(64, 32));
+        write!(expected, "abc").unwrap();
+        assert_eq!(cell(&console, 2), cell(&expected, 2));
+        // 止めると、表示していても元に戻る
+        console.apply_blink(BlinkAction::Show);
+        blink.set_enabled(false);
+        console.apply_blink(blink.take_action().unwrap());
+        assert!(!console.is_cursor_shown());
+        assert_eq!(cell(&console, 3), blank);
+    }
 }
@@ -0,0 +1,257 @@
+use crate::println;
+use crate::result::Result;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+// タイマー割り込みで割り込まれた場所(RIP)を数える、統計的なプロファイラ
+// アドレスはBUCKET_SIZEバイトごとの区間にまとめて、固定長のハッシュ表で数える
+// 割り込みの中で数えるので、ヒープもロックも使わず、表を探す回数にも上限を付ける
+
+// アドレスをまとめる単位
+pub const BUCKET_SIZE: u64 = 64;
+// ハッシュ表の大きさ（2の累乗）
+const TABLE_BITS: u32 = 10;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+// 空きを探す回数の上限（超えたら数えずに捨てる）
+const MAX_PROBES: usize = 8;
+// reportで表示できる数の上限
+pub const MAX_TOP: usize = 32;
+
+struct Slot {
+    // 区間の先頭アドレス（0なら空き）
+    bucket: AtomicU64,
+    count: AtomicU64,
+}
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            bucket: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+static TABLE: [Slot; TABLE_SIZE] = [const { Slot::new() }; TABLE_SIZE];
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// 何ティックに1回記録するか
+static INTERVAL: AtomicU64 = AtomicU64::new(1);
+static TICKS_SEEN: AtomicU64 = AtomicU64::new(0);
+static SAMPLES: AtomicU64 = AtomicU64::new(0);
+// 表がいっぱいで数えられなかったサンプルの数
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+fn bucket_of(rip: u64) -> u64 {
+    rip & !(BUCKET_SIZE - 1)
+}
+
+// フィボナッチハッシュで表の位置を決める
+fn slot_index(bucket: u64) -> usize {
+    ((bucket / BUCKET_SIZE).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - TABLE_BITS)) as usize
+}
+
+// 表を空にして記録を始める（intervalティックに1回記録する）
+pub fn start(interval: u64) -> Result<()> {
+    if interval == 0 {
+        return Err("profiler: interval must be at least 1");
+    }
+    // 止めてから消すので、消している途中に割り込みで書き込まれることはない
+    RUNNING.store(false, Ordering::SeqCst);
+    for slot in &TABLE {
+        slot.bucket.store(0, Ordering::Relaxed);
+        slot.count.store(0, Ordering::Relaxed);
+    }
+    INTERVAL.store(interval, Ordering::Relaxed);
+    TICKS_SEEN.store(0, Ordering::Relaxed);
+    SAMPLES.store(0, Ordering::Relaxed);
+    DROPPED.store(0, Ordering::Relaxed);
+    RUNNING.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+pub fn samples() -> u64 {
+    SAMPLES.load(Ordering::Relaxed)
+}
+
+pub fn dropped() -> u64 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// タイマー割り込みのたびに割り込みハンドラから呼ぶ
+pub fn on_timer_tick(rip: u64) {
+    if !is_running() {
+        return;
+    }
+    let tick = TICKS_SEEN.fetch_add(1, Ordering::Relaxed);
+    if !tick.is_multiple_of(INTERVAL.load(Ordering::Relaxed)) {
+        return;
+    }
+    SAMPLES.fetch_add(1, Ordering::Relaxed);
+    let bucket = bucket_of(rip);
+    if bucket == 0 {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let start = slot_index(bucket);
+    for i in 0..MAX_PROBES {
+        let slot = &TABLE[(start + i) % TABLE_SIZE];
+        let owner =
+            match slot
+                .bucket
+                .compare_exchange(0, bucket, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => bucket,
+                Err(current) => current,
+            };
+        if owner == bucket {
+            slot.count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// 回数の多い順に(区間の先頭アドレス, 回数)をoutに入れて、入れた数を返す
+// 表を一度なめながら、out.len()個までの上位だけを挿入で並べておくので、ヒープを使わない
+pub fn top(out: &mut [(u64, u64)]) -> usize {
+    let mut len = 0;
+    for slot in &TABLE {
+        let count = slot.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        let entry = (slot.bucket.load(Ordering::Relaxed), count);
+        // entryより回数の少ない最初の位置に入れる（同じ回数なら先に見つかった方が上）
+        let pos = out[..len]
+            .iter()
+            .position(|(_, c)| *c < count)
+            .unwrap_or(len);
+        if pos >= out.len() {
+            continue;
+        }
+        len = (len + 1).min(out.len());
+        out[pos..len].rotate_right(1);
+        out[pos] = entry;
+    }
+    len
+}
+
+// 上位top_n個の区間を、全体に対する割合と一緒に表示する
+pub fn report(top_n: usize) {
+    let mut entries = [(0, 0); MAX_TOP];
+    let n = top(&mut entries[..top_n.min(MAX_TOP)]);
+    let samples = samples();
+    println!(
+        "profile: {samples} samples, {} dropped ({})",
+        dropped(),
+        if is_running() { "running" } else { "stopped" }
+    );
+    for (i, (bucket, count)) in entries[..n].iter().enumerate() {
+        // 小数点以下1桁まで（浮動小数点数は使わない）
+        let permille = count * 1000 / samples.max(1);
+        println!(
+            "  #{:<2} {bucket:#018X}-{:#018X} {count:>8} {:>3}.{}%",
+            i + 1,
+            bucket + BUCKET_SIZE - 1,
+            permille / 10,
+            permille % 10
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::arch::asm;
+    use core::ops::Range;
+
+    // サンプルがtarget個になるまで回り、回っていたコードの範囲を返す
+    // 記録されなくても終わるように、回る回数には上限を付ける
+    #[inline(never)]
+    fn spin_until_sampled(target: u64) -> Range<u64> {
+        let start: u64;
+        let end: u64;
+        unsafe {
+            asm!(
+                "lea {start}, [rip + 2f]",
+                "lea {end}, [rip + 3f]",
+                "2:",
+                "pause",
+                "cmp [{samples}], {target}",
+                "jae 3f",
+                "dec {limit}",
+                "jnz 2b",
+                "3:",
+                start = out(reg) start,
+                end = out(reg) end,
+                samples = in(reg) SAMPLES.as_ptr(),
+                target = in(reg) target,
+                limit = inout(reg) 1u64 << 32 => _,
+                options(nostack, readonly)
+            );
+        }
+        start..end
+    }
+
+    #[test_case]
+    fn hot_loop_dominates_the_profile() {
+        start(1).unwrap();
+        let spin = spin_until_sampled(50);
+        stop();
+        let samples = samples();
+        assert!(samples >= 50, "samples = {samples}");
+        let mut entries = [(0, 0); MAX_TOP];
+        let n = top(&mut entries);
+        // 回っていた区間と重なるバケット
+        let hot = |bucket: u64| bucket < spin.end && spin.start < bucket + BUCKET_SIZE;
+        assert!(n > 0 && hot(entries[0].0), "top = {:#X?}", &entries[..n]);
+        let in_spin: u64 = entries[..n]
+            .iter()
+            .filter(|(b, _)| hot(*b))
+            .map(|(_, c)| c)
+            .sum();
+        assert!(
+            in_spin * 10 >= samples * 8,
+            "{in_spin} of {samples} samples in {spin:#X?}"
+        );
+    }
+    #[test_case]
+    fn top_keeps_the_largest_counts_in_order() {
+        stop();
+        for slot in &TABLE {
+            slot.bucket.store(0, Ordering::Relaxed);
+            slot.count.store(0, Ordering::Relaxed);
+        }
+        for (i, count) in [5, 1, 9, 3, 9, 7].into_iter().enumerate() {
+            TABLE[i * 3]
+                .bucket
+                .store(0x1000 * (i as u64 + 1), Ordering::Relaxed);
+            TABLE[i * 3].count.store(count, Ordering::Relaxed);
+        }
+        let mut entries = [(0, 0); 4];
+        assert_eq!(top(&mut entries), 4);
+        assert_eq!(
+            entries,
+            [(0x3000, 9), (0x5000, 9), (0x6000, 7), (0x1000, 5)]
+        );
+        let mut all = [(0, 0); 8];
+        assert_eq!(top(&mut all), 6);
+        assert_eq!(all[5], (0x2000, 1));
+        assert!(start(0).is_err());
+    }
+    #[test_case]
+    fn nearby_addresses_share_a_bucket() {
+        assert_eq!(bucket_of(0x1234), 0x1200);
+        assert_eq!(bucket_of(0x123f), 0x1200);
+        assert_eq!(bucket_of(0x1240), 0x1240);
+        assert!(slot_index(!(BUCKET_SIZE - 1)) < TABLE_SIZE);
+    }
+}
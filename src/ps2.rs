@@ -0,0 +1,747 @@
+use crate::cursor_blink;
+use crate::pic::enable_irq;
+use crate::pic::register_irq_handler;
+use crate::result::Result;
+use crate::ring_buffer::RingBuffer;
+use crate::x86::delay_us;
+use crate::x86::disable_interrupts;
+use crate::x86::hlt_with_interrupts;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use crate::x86::InterruptGuard;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Context;
+use core::task::Poll;
+use core::task::Waker;
+use spin::Mutex;
+
+// 8042 PS/2コントローラ
+const PS2_DATA: u16 = 0x60;
+const PS2_STATUS: u16 = 0x64; // 読むとステータス
+const PS2_CMD: u16 = 0x64; // 書くとコマンド
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0; // データポートに読めるデータがある
+const STATUS_INPUT_FULL: u8 = 1 << 1; // コントローラがまだ前の書き込みを処理している
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_ENABLE_PORT2: u8 = 0xa8;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+const CMD_WRITE_PORT2: u8 = 0xd4; // 次にデータポートに書いた値を2番目のポート（マウス）に送る
+
+const CONFIG_PORT1_IRQ: u8 = 1 << 0;
+const CONFIG_PORT2_IRQ: u8 = 1 << 1;
+const CONFIG_PORT2_CLOCK_DISABLE: u8 = 1 << 5;
+
+const DEV_ENABLE_SCANNING: u8 = 0xf4; // マウスではデータ送信の開始
+const DEV_SET_DEFAULTS: u8 = 0xf6;
+const DEV_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEV_GET_ID: u8 = 0xf2;
+const DEV_ACK: u8 = 0xfa;
+
+// スクロールホイール付きのマウス（IntelliMouse）のID
+const MOUSE_ID_WHEEL: u8 = 3;
+
+const KEYBOARD_IRQ: u8 = 1;
+const MOUSE_IRQ: u8 = 12;
+
+// コントローラがない環境で永遠に待たないように、待つ時間に上限をつける
+// （1マイクロ秒ずつ待つので、ポートを読む時間の分だけ実際にはもう少し長くなる）
+const WAIT_TIMEOUT_US: u64 = 100_000;
+
+fn wait_input_empty() -> Result<()> {
+    for _ in 0..WAIT_TIMEOUT_US {
+        if read_io_port_u8(PS2_STATUS) & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        delay_us(1);
+    }
+    Err("PS/2 controller timed out (input)")
+}
+
+fn wait_output_full() -> Result<()> {
+    for _ in 0..WAIT_TIMEOUT_US {
+        if read_io_port_u8(PS2_STATUS) & STATUS_OUTPUT_FULL != 0 {
+            return Ok(());
+        }
+        delay_us(1);
+    }
+    Err("PS/2 controller timed out (output)")
+}
+
+fn write_command(cmd: u8) -> Result<()> {
+    wait_input_empty()?;
+    write_io_port_u8(PS2_CMD, cmd);
+    Ok(())
+}
+
+fn write_data(data: u8) -> Result<()> {
+    wait_input_empty()?;
+    write_io_port_u8(PS2_DATA, data);
+    Ok(())
+}
+
+fn read_data() -> Result<u8> {
+    wait_output_full()?;
+    Ok(read_io_port_u8(PS2_DATA))
+}
+
+// 読まれずに残っているデータを捨てる
+fn flush_output() {
+    while read_io_port_u8(PS2_STATUS) & STATUS_OUTPUT_FULL != 0 {
+        read_io_port_u8(PS2_DATA);
+    }
+}
+
+// デバイスにコマンドを送ってACKを待つ
+fn send_to_device(data: u8) -> Result<()> {
+    write_data(data)?;
+    match read_data()? {
+        DEV_ACK => Ok(()),
+        _ => Err("PS/2 device did not acknowledge"),
+    }
+}
+
+// マウスにコマンドを送ってACKを待つ
+fn send_to_mouse(data: u8) -> Result<()> {
+    write_command(CMD_WRITE_PORT2)?;
+    send_to_device(data)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    // 文字が割り当てられたキー（シフトなしの文字）
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    F(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    Unknown(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    // 修飾キーを反映した入力文字（文字を入力しないキーはNone）
+    pub ch: Option<char>,
+    pub pressed: bool,
+}
+
+// スキャンコードセット1の0x00~0x3fまでの（シフトなし, シフトあり）の文字
+// 文字でないキーは'\0'
+const SCANCODE_CHARS: [(char, char); 0x40] = {
+    let mut t = [('\0', '\0'); 0x40];
+    let rows: [(usize, &str, &str); 4] = [
+        (0x02, "1234567890-=", "!@#$%^&*()_+"),
+        (0x10, "qwertyuiop[]", "QWERTYUIOP{}"),
+        (0x1e, "asdfghjkl;'`", "ASDFGHJKL:\"~"),
+        (0x2b, "\\zxcvbnm,./", "|ZXCVBNM<>?"),
+    ];
+    let mut r = 0;
+    while r < rows.len() {
+        let (start, normal, shifted) = rows[r];
+        let (normal, shifted) = (normal.as_bytes(), shifted.as_bytes());
+        let mut i = 0;
+        while i < normal.len() {
+            t[start + i] = (normal[i] as char, shifted[i] as char);
+            i += 1;
+        }
+        r += 1;
+    }
+    t[0x37] = ('*', '*');
+    t[0x39] = (' ', ' ');
+    t
+};
+
+// スキャンコードセット1をキーイベントに変換する状態機械
+#[derive(Debug, Default)]
+pub struct ScancodeDecoder {
+    // 直前に拡張キーのプレフィックス0xE0を受け取ったか
+    extended: bool,
+    left_shift: bool,
+    right_shift: bool,
+    ctrl: bool,
+    alt: bool,
+    caps_lock: bool,
+}
+impl ScancodeDecoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            left_shift: false,
+            right_shift: false,
+            ctrl: false,
+            alt: false,
+            caps_lock: false,
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+    pub fn ctrl(&self) -> bool {
+        self.ctrl
+    }
+    pub fn alt(&self) -> bool {
+        self.alt
+    }
+    pub fn caps_lock(&self) -> bool {
+        self.caps_lock
+    }
+
+    fn key_code(extended: bool, code: u8) -> KeyCode {
+        if extended {
+            return match code {
+                0x1c => KeyCode::Enter,
+                0x1d => KeyCode::RightCtrl,
+                0x35 => KeyCode::Char('/'),
+                0x38 => KeyCode::RightAlt,
+                0x47 => KeyCode::Home,
+                0x48 => KeyCode::Up,
+                0x49 => KeyCode::PageUp,
+                0x4b => KeyCode::Left,
+                0x4d => KeyCode::Right,
+                0x4f => KeyCode::End,
+                0x50 => KeyCode::Down,
+                0x51 => KeyCode::PageDown,
+                0x52 => KeyCode::Insert,
+                0x53 => KeyCode::Delete,
+                _ => KeyCode::Unknown(code),
+            };
+        }
+        match code {
+            0x01 => KeyCode::Escape,
+            0x0e => KeyCode::Backspace,
+            0x0f => KeyCode::Tab,
+            0x1c => KeyCode::Enter,
+            0x1d => KeyCode::LeftCtrl,
+            0x2a => KeyCode::LeftShift,
+            0x36 => KeyCode::RightShift,
+            0x38 => KeyCode::LeftAlt,
+            0x3a => KeyCode::CapsLock,
+            0x3b..=0x44 => KeyCode::F(code - 0x3b + 1),
+            0x57 => KeyCode::F(11),
+            0x58 => KeyCode::F(12),
+            _ => match SCANCODE_CHARS.get(code as usize) {
+                Some((c, _)) if *c != '\0' => KeyCode::Char(*c),
+                _ => KeyCode::Unknown(code),
+            },
+        }
+    }
+
+    // 修飾キーの状態から入力文字を決める
+    fn translate(&self, code: KeyCode) -> Option<char> {
+        match code {
+            KeyCode::Char(c) => {
+                let shifted = SCANCODE_CHARS
+                    .iter()
+                    .find(|(normal, _)| *normal == c)
+                    .map(|(_, shifted)| *shifted)
+                    .unwrap_or(c);
+                let c = if c.is_ascii_alphabetic() {
+                    // アルファベットはCapsLockでシフトが反転する
+                    if self.shift() != self.caps_lock {
+                        shifted
+                    } else {
+                        c
+                    }
+                } else if self.shift() {
+                    shifted
+                } else {
+                    c
+                };
+                // Ctrl+アルファベットは制御文字（Ctrl+A = 0x01）
+                if self.ctrl && c.is_ascii_alphabetic() {
+                    Some(((c as u8) & 0x1f) as char)
+                } else {
+                    Some(c)
+                }
+            }
+            KeyCode::Enter => Some('\n'),
+            KeyCode::Tab => Some('\t'),
+            KeyCode::Backspace => Some('\x08'),
+            KeyCode::Escape => Some('\x1b'),
+            _ => None,
+        }
+    }
+
+    // 1バイト受け取って、キーイベントが完成したら返す
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if byte == 0xe0 {
+            self.extended = true;
+            return None;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        // 最上位bitが立っていればキーを離した
+        let pressed = byte & 0x80 == 0;
+        let code = Self::key_code(extended, byte & 0x7f);
+        match code {
+            KeyCode::LeftShift => self.left_shift = pressed,
+            KeyCode::RightShift => self.right_shift = pressed,
+            KeyCode::LeftCtrl | KeyCode::RightCtrl => self.ctrl = pressed,
+            KeyCode::LeftAlt | KeyCode::RightAlt => self.alt = pressed,
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            _ => {}
+        }
+        let ch = if pressed { self.translate(code) } else { None };
+        Some(KeyEvent { code, ch, pressed })
+    }
+}
+
+static DECODER: Mutex<ScancodeDecoder> = Mutex::new(ScancodeDecoder::new());
+static KEY_EVENTS: Mutex<RingBuffer<KeyEvent, 64>> = Mutex::new(RingBuffer::new());
+// キー入力を待っている非同期タスクのWaker
+// 割り込みハンドラでWakerをdropするとアロケータが呼ばれてしまうので、ハンドラでは参照して起こすだけにする
+static KEY_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+fn on_keyboard_irq() {
+    let byte = read_io_port_u8(PS2_DATA);
+    if let Some(e) = DECODER.lock().feed(byte) {
+        // 入力している間はカーソルを表示したままにする
+        if e.pressed {
+            cursor_blink::note_keystroke();
+        }
+        // 溢れたら古いイベントを捨てる
+        KEY_EVENTS.lock().push_overwrite(e);
+        if let Some(waker) = KEY_WAKER.lock().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+// PS/2キーボードの初期化
+// ファームウェアがスキャンコードセット1への変換を有効にしているので、それをそのまま使う
+pub fn init_keyboard() -> Result<()> {
+    write_command(CMD_DISABLE_PORT1)?;
+    write_command(CMD_DISABLE_PORT2)?;
+    flush_output();
+
+    write_command(CMD_READ_CONFIG)?;
+    let config = read_data()?;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config | CONFIG_PORT1_IRQ)?;
+
+    write_command(CMD_ENABLE_PORT1)?;
+    send_to_device(DEV_ENABLE_SCANNING)?;
+    flush_output();
+
+    register_irq_handler(KEYBOARD_IRQ, on_keyboard_irq);
+    enable_irq(KEYBOARD_IRQ);
+    Ok(())
+}
+
+// キーイベントがあれば取り出す（なければすぐにNone）
+pub fn try_read_key() -> Option<KeyEvent> {
+    // 割り込みハンドラと同じロックを取るので、その間は割り込みを禁止する
+    let _guard = InterruptGuard::new();
+    KEY_EVENTS.lock().pop()
+}
+
+// キーイベントが来るまで待つ
+// 確かめてから休むまでの間に来た割り込みで起こし損ねないように、割り込みを禁止して確かめ、
+// stiの直後にhltする（割り込みの許可状態は戻ってから元に戻す）
+pub fn read_key() -> KeyEvent {
+    let _guard = InterruptGuard::new();
+    loop {
+        if let Some(e) = KEY_EVENTS.lock().pop() {
+            return e;
+        }
+        hlt_with_interrupts();
+        disable_interrupts();
+    }
+}
+
+// キーイベントが来るまで待つFuture
+pub struct ReadKey;
+
+impl Future for ReadKey {
+    type Output = KeyEvent;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<KeyEvent> {
+        let _guard = InterruptGuard::new();
+        if let Some(e) = KEY_EVENTS.lock().pop() {
+            return Poll::Ready(e);
+        }
+        // イベントを確かめてからWakerを登録するまで割り込みを禁止しているので、起こし損ねることはない
+        let mut waker = KEY_WAKER.lock();
+        if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+            *waker = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+// キーイベントが来るまで待つ（async版）
+// 待っている間はエグゼキュータが他のタスクを実行するか、hltで休む
+pub fn read_key_async() -> ReadKey {
+    ReadKey
+}
+
+// バッファが溢れて捨てたキーイベントの数
+pub fn dropped_key_events() -> usize {
+    let _guard = InterruptGuard::new();
+    KEY_EVENTS.lock().dropped()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    // 右と下が正の方向（画面の座標と同じ向き）
+    pub dx: i16,
+    pub dy: i16,
+    // ホイールの回転量（ホイールがなければ常に0）
+    pub dz: i8,
+    // bit0: 左, bit1: 右, bit2: 中ボタン
+    pub buttons: u8,
+}
+
+// マウスのパケット（3バイト、ホイール付きなら4バイト）を組み立てる
+#[derive(Debug)]
+pub struct MousePacketDecoder {
+    bytes: [u8; 4],
+    index: usize,
+    packet_len: usize,
+    // パケットの区切りが分かっているか（起動直後と、取りこぼしに気付いた後はfalse）
+    synced: bool,
+}
+impl MousePacketDecoder {
+    const FLAG_ALWAYS_ONE: u8 = 1 << 3;
+    const FLAG_X_SIGN: u8 = 1 << 4;
+    const FLAG_Y_SIGN: u8 = 1 << 5;
+    const FLAG_X_OVERFLOW: u8 = 1 << 6;
+    const FLAG_Y_OVERFLOW: u8 = 1 << 7;
+
+    pub const fn new(has_wheel: bool) -> Self {
+        Self {
+            bytes: [0; 4],
+            index: 0,
+            packet_len: if has_wheel { 4 } else { 3 },
+            synced: false,
+        }
+    }
+
+    pub fn set_has_wheel(&mut self, has_wheel: bool) {
+        *self = Self::new(has_wheel);
+    }
+
+    // 9bitの符号付き整数に直す
+    fn delta(value: u8, sign: bool) -> i16 {
+        if sign {
+            value as i16 - 0x100
+        } else {
+            value as i16
+        }
+    }
+
+    // パケットの先頭のバイトになりうるか
+    // 先頭のバイトはbit3が必ず立っている
+    // 区切りを探している間は、データのバイトを先頭と取り違えないように、オーバーフローしたものも除く
+    fn can_start_packet(&self, byte: u8) -> bool {
+        byte & Self::FLAG_ALWAYS_ONE != 0
+            && (self.synced || byte & (Self::FLAG_X_OVERFLOW | Self::FLAG_Y_OVERFLOW) == 0)
+    }
+
+    // 揃ったパケットの符号のビットが、移動量のバイトの最上位ビットと一致するか
+    // 区切りを探している間は、1パケットで128以上動くことはないとみなして、データのバイトを先頭と取り違えていないかを確かめる
+    fn signs_match(&self) -> bool {
+        let flags = self.bytes[0];
+        (flags & Self::FLAG_X_SIGN != 0) == (self.bytes[1] & 0x80 != 0)
+            && (flags & Self::FLAG_Y_SIGN != 0) == (self.bytes[2] & 0x80 != 0)
+    }
+
+    // 1バイト受け取って、パケットが揃ったらイベントを返す
+    pub fn feed(&mut self, byte: u8) -> Option<MouseEvent> {
+        // 先頭になりえないバイトが来たらバイトを取りこぼしてずれているので、次の先頭が来るまで捨てる
+        if self.index == 0 && !self.can_start_packet(byte) {
+            self.synced = false;
+            return None;
+        }
+        self.bytes[self.index] = byte;
+        self.index += 1;
+        if self.index < self.packet_len {
+            return None;
+        }
+        self.index = 0;
+        if !self.synced && !self.signs_match() {
+            // 先頭だと思ったバイトはデータだったので、その次のバイトから先頭を探し直す
+            // （渡し直すのはパケットより短いので、ここでイベントが揃うことはない）
+            let bytes = self.bytes;
+            for b in &bytes[1..self.packet_len] {
+                self.feed(*b);
+            }
+            return None;
+        }
+        self.synced = true;
+        let flags = self.bytes[0];
+        // オーバーフローした時の移動量は信用できないので0にする
+        let dx = if flags & Self::FLAG_X_OVERFLOW != 0 {
+            0
+        } else {
+            Self::delta(self.bytes[1], flags & Self::FLAG_X_SIGN != 0)
+        };
+        let dy = if flags & Self::FLAG_Y_OVERFLOW != 0 {
+            0
+        } else {
+            Self::delta(self.bytes[2], flags & Self::FLAG_Y_SIGN != 0)
+        };
+        // ホイールの回転量は4バイト目の下位4bitの符号付き整数
+        let dz = if self.packet_len == 4 {
+            ((self.bytes[3] << 4) as i8) >> 4
+        } else {
+            0
+        };
+        Some(MouseEvent {
+            dx,
+            // PS/2マウスは上が正なので、画面の向きに合わせて反転する
+            dy: -dy,
+            dz,
+            buttons: flags & 0b111,
+        })
+    }
+}
+
+static MOUSE_DECODER: Mutex<MousePacketDecoder> = Mutex::new(MousePacketDecoder::new(false));
+static MOUSE_EVENTS: Mutex<RingBuffer<MouseEvent, 64>> = Mutex::new(RingBuffer::new());
+
+fn on_mouse_irq() {
+    let byte = read_io_port_u8(PS2_DATA);
+    if let Some(e) = MOUSE_DECODER.lock().feed(byte) {
+        MOUSE_EVENTS.lock().push_overwrite(e);
+    }
+}
+
+// サンプリングレートを200, 100, 80の順に設定するとホイールが有効になり、IDが3に変わる
+fn probe_mouse_wheel() -> Result<bool> {
+    for rate in [200, 100, 80] {
+        send_to_mouse(DEV_SET_SAMPLE_RATE)?;
+        send_to_mouse(rate)?;
+    }
+    send_to_mouse(DEV_GET_ID)?;
+    Ok(read_data()? == MOUSE_ID_WHEEL)
+}
+
+// PS/2マウスの初期化
+pub fn init_mouse() -> Result<bool> {
+    write_command(CMD_ENABLE_PORT2)?;
+    send_to_mouse(DEV_SET_DEFAULTS)?;
+    let has_wheel = probe_mouse_wheel().unwrap_or(false);
+    MOUSE_DECODER.lock().set_has_wheel(has_wheel);
+    send_to_mouse(DEV_ENABLE_SCANNING)?;
+    flush_output();
+
+    write_command(CMD_READ_CONFIG)?;
+    let config = read_data()?;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data((config | CONFIG_PORT2_IRQ) & !CONFIG_PORT2_CLOCK_DISABLE)?;
+
+    register_irq_handler(MOUSE_IRQ, on_mouse_irq);
+    enable_irq(MOUSE_IRQ);
+    Ok(has_wheel)
+}
+
+// マウスイベントがあれば取り出す（なければすぐにNone）
+pub fn try_read_mouse() -> Option<MouseEvent> {
+    let _guard = InterruptGuard::new();
+    MOUSE_EVENTS.lock().pop()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_all(decoder: &mut ScancodeDecoder, bytes: &[u8]) -> Option<KeyEvent> {
+        bytes.iter().fold(None, |_, b| decoder.feed(*b))
+    }
+
+    #[test_case]
+    fn decode_plain_key_press_and_release() {
+        let mut d = ScancodeDecoder::new();
+        assert_eq!(
+            d.feed(0x1e),
+            Some(KeyEvent {
+                code: KeyCode::Char('a'),
+                ch: Some('a'),
+                pressed: true
+            })
+        );
+        assert_eq!(
+            d.feed(0x9e),
+            Some(KeyEvent {
+                code: KeyCode::Char('a'),
+                ch: None,
+                pressed: false
+            })
+        );
+    }
+
+    // Shift/CapsLock/Ctrlの組み合わせ
+    #[test_case]
+    fn decode_modifier_combinations() {
+        let mut d = ScancodeDecoder::new();
+        // Shift + 1 = '!'
+        assert_eq!(feed_all(&mut d, &[0x2a, 0x02]).unwrap().ch, Some('!'));
+        // Shift + a = 'A'
+        assert_eq!(d.feed(0x1e).unwrap().ch, Some('A'));
+        // Shiftを離す
+        assert!(!feed_all(&mut d, &[0xaa]).unwrap().pressed);
+        assert!(!d.shift());
+        // CapsLock + a = 'A'、CapsLock + 1 = '1'
+        assert_eq!(feed_all(&mut d, &[0x3a, 0xba, 0x1e]).unwrap().ch, Some('A'));
+        assert_eq!(d.feed(0x02).unwrap().ch, Some('1'));
+        // CapsLock + Shift + a = 'a'
+        assert_eq!(feed_all(&mut d, &[0x36, 0x1e]).unwrap().ch, Some('a'));
+        assert_eq!(
+            feed_all(&mut d, &[0xb6, 0x3a, 0xba]).unwrap().code,
+            KeyCode::CapsLock
+        );
+        assert!(!d.caps_lock());
+        // Ctrl + c = 0x03
+        assert_eq!(feed_all(&mut d, &[0x1d, 0x2e]).unwrap().ch, Some('\x03'));
+        d.feed(0x9d);
+        assert!(!d.ctrl());
+    }
+
+    // 0xE0で始まる拡張キー
+    #[test_case]
+    fn decode_extended_keys() {
+        let mut d = ScancodeDecoder::new();
+        assert_eq!(d.feed(0xe0), None);
+        assert_eq!(
+            d.feed(0x48),
+            Some(KeyEvent {
+                code: KeyCode::Up,
+                ch: None,
+                pressed: true
+            })
+        );
+        // 拡張キーの離す方
+        assert_eq!(
+            feed_all(&mut d, &[0xe0, 0xc8]),
+            Some(KeyEvent {
+                code: KeyCode::Up,
+                ch: None,
+                pressed: false
+            })
+        );
+        // 右Ctrlも左Ctrlと同じように効く
+        assert_eq!(
+            feed_all(&mut d, &[0xe0, 0x1d, 0x26]).unwrap().ch,
+            Some('\x0c')
+        );
+        assert_eq!(
+            feed_all(&mut d, &[0xe0, 0x9d]).unwrap().code,
+            KeyCode::RightCtrl
+        );
+        // プレフィックスの効果は次の1バイトだけ
+        assert_eq!(d.feed(0x48).unwrap().code, KeyCode::Unknown(0x48));
+    }
+
+    fn feed_mouse(decoder: &mut MousePacketDecoder, bytes: &[u8]) -> Option<MouseEvent> {
+        bytes.iter().fold(None, |_, b| decoder.feed(*b))
+    }
+
+    #[test_case]
+    fn decode_movement_and_buttons() {
+        let mut d = MousePacketDecoder::new(false);
+        // 右に5、上に3、左ボタン
+        assert_eq!(
+            feed_mouse(&mut d, &[0x09, 5, 3]),
+            Some(MouseEvent {
+                dx: 5,
+                dy: -3,
+                dz: 0,
+                buttons: 1
+            })
+        );
+        // 左に2（X符号）、下に4（Y符号）、右ボタン
+        assert_eq!(
+            feed_mouse(&mut d, &[0x3a, 0xfe, 0xfc]),
+            Some(MouseEvent {
+                dx: -2,
+                dy: 4,
+                dz: 0,
+                buttons: 2
+            })
+        );
+        // オーバーフローした方向は0になる
+        assert_eq!(
+            feed_mouse(&mut d, &[0x48, 0xff, 7]).map(|e| (e.dx, e.dy)),
+            Some((0, -7))
+        );
+    }
+
+    // 1バイト取りこぼしても、先頭バイトのbit3で同期し直す
+    #[test_case]
+    fn resync_after_dropped_byte() {
+        let mut d = MousePacketDecoder::new(false);
+        // 1つ目のパケットの先頭が欠けた: 5, 3 は先頭として扱われず捨てられる
+        assert_eq!(feed_mouse(&mut d, &[5, 3]), None);
+        assert_eq!(
+            feed_mouse(&mut d, &[0x08, 1, 1]),
+            Some(MouseEvent {
+                dx: 1,
+                dy: -1,
+                dz: 0,
+                buttons: 0
+            })
+        );
+        // bit3が立っていても、同期し直している間はオーバーフローのビットが立ったバイトを先頭にしない
+        assert_eq!(feed_mouse(&mut d, &[5, 0x48]), None);
+        assert_eq!(
+            feed_mouse(&mut d, &[0x08, 2, 2]).map(|e| (e.dx, e.dy)),
+            Some((2, -2))
+        );
+    }
+    // 先頭が欠けたパケットのデータのバイトがbit3を立てていても、符号のビットが合わないので先頭にしない
+    #[test_case]
+    fn resync_checks_sign_bits() {
+        let mut d = MousePacketDecoder::new(false);
+        // 0x09, 0x28, 0x10 の先頭が欠けた後に、0x08, 3, 4 が来る
+        // 0x28を先頭にすると、Yの符号が立っているのに移動量の0x08は正になる
+        assert_eq!(feed_mouse(&mut d, &[0x28, 0x10, 0x08]), None);
+        assert_eq!(
+            feed_mouse(&mut d, &[3, 4]),
+            Some(MouseEvent {
+                dx: 3,
+                dy: -4,
+                dz: 0,
+                buttons: 0
+            })
+        );
+        // 同期した後は、128以上の移動やオーバーフローもそのまま受け取る
+        assert_eq!(
+            feed_mouse(&mut d, &[0x18, 0x10, 0]).map(|e| e.dx),
+            Some(-240)
+        );
+    }
+
+    #[test_case]
+    fn decode_wheel_packet() {
+        let mut d = MousePacketDecoder::new(true);
+        assert_eq!(feed_mouse(&mut d, &[0x08, 0, 0]), None);
+        assert_eq!(d.feed(0x0f).map(|e| e.dz), Some(-1));
+        assert_eq!(
+            feed_mouse(&mut d, &[0x08, 0, 0, 0x01]).map(|e| e.dz),
+            Some(1)
+        );
+    }
+}
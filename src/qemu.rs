@@ -1,11 +1,50 @@
+use crate::cpuid::cpu_info;
+use crate::print::register_sink;
+use crate::print::Sink;
+use crate::serial::switch_to_sync_tx;
 use crate::x86::hlt;
+use crate::x86::read_io_port_u8;
 use crate::x86::write_io_port_u8;
+use core::fmt;
+use spin::Mutex;
 
+// isa-debug-exitに書き込む値
+// QEMUは書き込まれた値をcとして(c << 1) | 1で終了するので、終了ステータスは必ず奇数になる
+// （0はゲストが普通に電源を切ったとき、1はQEMU自身のエラーなので、どの値とも区別できる）
+// scripts/launch_qemu.shはstatus()の値で結果を判断するので、値を変えたらスクリプトも直すこと
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
+    // 終了ステータス3: 全てのテストが成功した
     Success = 0x1,
+    // 終了ステータス5: 原因を特定しない失敗
     Fail = 0x2,
+    // 終了ステータス7: テストのフィルタに一致するテストが1つもなかった
+    NoTestsMatched = 0x3,
+    // 終了ステータス9: テストの外でpanicした
+    Panicked = 0x4,
+    // 終了ステータス11: 失敗したテストがあった
+    TestFailed = 0x5,
+    // 終了ステータス13: 対応していないハードウェアなどの理由で、意図して実行をやめた
+    Skipped = 0x6,
+    // 終了ステータス15: メモリを確保できなかった
+    OutOfMemory = 0x7,
+}
+
+impl QemuExitCode {
+    pub const ALL: [QemuExitCode; 7] = [
+        QemuExitCode::Success,
+        QemuExitCode::Fail,
+        QemuExitCode::NoTestsMatched,
+        QemuExitCode::Panicked,
+        QemuExitCode::TestFailed,
+        QemuExitCode::Skipped,
+        QemuExitCode::OutOfMemory,
+    ];
+    // QEMUの終了ステータス
+    pub const fn status(self) -> i32 {
+        ((self as i32) << 1) | 1
+    }
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
@@ -14,3 +53,95 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
         hlt()
     }
 }
+
+// QEMU（TCGかKVM）の上で動いていればtrue
+// 実機でisa-debug-exitのポートに書き込まないように、終了の代わりに電源を切るときに確かめる
+pub fn is_qemu() -> bool {
+    matches!(
+        cpu_info().hypervisor_vendor(),
+        Some("TCGTCGTCGTCG" | "KVMKVMKVM")
+    )
+}
+
+// 送信バッファに残っている出力を送りきってから、QEMUを終了する
+// panicやメモリ不足のときも、最後のメッセージがログに残るようにする
+pub fn exit_qemu_with(exit_code: QemuExitCode) -> ! {
+    switch_to_sync_tx();
+    exit_qemu(exit_code)
+}
+
+// QEMUのisa-debugconデバイスのポート（-debugcon file:log.txtなどで出力先を指定する）
+pub const DEBUGCON_PORT: u16 = 0xe9;
+
+// debugconに書き込むだけの出力先
+// 初期化が不要でヒープも使わないので、アロケータやシリアルの初期化前から使える
+pub struct DebugCon;
+impl DebugCon {
+    // QEMUのdebugconはポートを読むと0xe9を返す
+    // 実機で関係のないデバイスに書き込まないように、書き込む前にこれで確かめる
+    pub fn is_present() -> bool {
+        is_debugcon_signature(read_io_port_u8(DEBUGCON_PORT))
+    }
+    // シリアルと違って何も変換しないので、バイナリもそのまま書ける
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            write_io_port_u8(DEBUGCON_PORT, *b);
+        }
+    }
+}
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+fn is_debugcon_signature(value: u8) -> bool {
+    value == DEBUGCON_PORT as u8
+}
+
+static DEBUGCON_SINK: Mutex<DebugCon> = Mutex::new(DebugCon);
+
+pub fn debugcon_sink() -> Sink {
+    &DEBUGCON_SINK
+}
+
+// debugconがあれば、print!の出力先に登録する（登録したらtrue）
+pub fn init_debugcon() -> bool {
+    DebugCon::is_present() && register_sink(debugcon_sink()).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print::is_sink_registered;
+
+    #[test_case]
+    fn debugcon_probe_matches_only_qemu_signature() {
+        assert!(is_debugcon_signature(0xe9));
+        // デバイスがないポートは普通0xffを返す
+        assert!(!is_debugcon_signature(0xff));
+        assert!(!is_debugcon_signature(0x00));
+    }
+    #[test_case]
+    fn debugcon_is_registered_under_qemu() {
+        // scripts/launch_qemu.shは-debugconを付けて起動する
+        assert!(DebugCon::is_present());
+        assert!(is_sink_registered(debugcon_sink()));
+        // launch_qemu.shがこの行がdebugconの出力にもあることを確かめる
+        crate::println!("debugcon capture marker");
+    }
+
+    #[test_case]
+    fn qemu_is_detected() {
+        assert!(is_qemu());
+    }
+
+    #[test_case]
+    fn exit_statuses_match_launch_script() {
+        let statuses = QemuExitCode::ALL.map(QemuExitCode::status);
+        assert_eq!(statuses, [3, 5, 7, 9, 11, 13, 15]);
+        // 終了ステータスは8ビットで、シグナルによる終了(128以上)とも区別できる
+        assert!(statuses.iter().all(|s| (2..128).contains(s)));
+    }
+}
@@ -0,0 +1,215 @@
+use crate::cpuid::cpu_info;
+use crate::hpet;
+use crate::timer::ticks;
+use crate::x86::busy_loop_hint;
+use crate::x86::rdtsc;
+use crate::x86::InterruptGuard;
+use core::arch::asm;
+use spin::Mutex;
+
+// rdrand/rdseedが失敗（CF=0）したときに試し直す回数の上限
+// rdrandはIntelの推奨に従って10回、rdseedはエントロピーが溜まるのを待つので少し多めにする
+const RDRAND_RETRIES: usize = 10;
+const RDSEED_RETRIES: usize = 100;
+
+fn rdrand_step() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    // 成功したかどうかはキャリーフラグにしか残らないので、asmの中でsetcで取り出す
+    unsafe {
+        asm!(
+            "rdrand {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok,
+            options(nomem, nostack)
+        )
+    }
+    (ok != 0).then_some(value)
+}
+
+fn rdseed_step() -> Option<u64> {
+    let value: u64;
+    let ok: u8;
+    unsafe {
+        asm!(
+            "rdseed {value}",
+            "setc {ok}",
+            value = out(reg) value,
+            ok = out(reg_byte) ok,
+            options(nomem, nostack)
+        )
+    }
+    (ok != 0).then_some(value)
+}
+
+// ハードウェアの乱数生成器（rdrand）から読む
+// 命令がない場合や、何度試しても失敗する場合はNone
+pub fn rdrand() -> Option<u64> {
+    if !cpu_info().has_rdrand() {
+        return None;
+    }
+    (0..RDRAND_RETRIES).find_map(|_| rdrand_step())
+}
+
+// エントロピー源から直接読む（rdrandより遅いが、シードに向いている）
+pub fn rdseed() -> Option<u64> {
+    if !cpu_info().has_rdseed() {
+        return None;
+    }
+    (0..RDSEED_RETRIES).find_map(|_| {
+        let v = rdseed_step();
+        if v.is_none() {
+            busy_loop_hint();
+        }
+        v
+    })
+}
+
+// シードを混ぜて、0になりにくい値にする（SplitMix64）
+fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// シードから決まった列を作る疑似乱数生成器（xorshift64*）
+// 同じシードからは必ず同じ列が出てくるので、テストの再現に使える
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeededRng {
+    state: u64,
+}
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // 状態が0だとずっと0しか出てこないので避ける
+        let state = match splitmix64(seed) {
+            0 => 1,
+            s => s,
+        };
+        Self { state }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        fill_bytes_with(buf, || self.next_u64());
+    }
+}
+
+fn fill_bytes_with(buf: &mut [u8], mut next: impl FnMut() -> u64) {
+    for chunk in buf.chunks_mut(8) {
+        let bytes = next().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+// rdrandが使えないときに使う疑似乱数生成器（最初に使うときにシードを決める）
+static FALLBACK: Mutex<Option<SeededRng>> = Mutex::new(None);
+
+// 手元にあるエントロピーを混ぜてシードを作る
+fn gather_seed() -> u64 {
+    let local = 0u8;
+    let stack_addr = &local as *const u8 as u64;
+    let mut seed = rdtsc();
+    for v in [rdseed().unwrap_or(0), ticks(), hpet::now_ns(), stack_addr] {
+        seed = splitmix64(seed ^ v);
+    }
+    seed
+}
+
+fn fallback_u64() -> u64 {
+    // 割り込みハンドラからも呼べるように、ロック中は割り込みを禁止する
+    let _guard = InterruptGuard::new();
+    FALLBACK
+        .lock()
+        .get_or_insert_with(|| SeededRng::new(gather_seed()))
+        .next_u64()
+}
+
+// 乱数を返す（rdrandが使えなければ疑似乱数）
+pub fn rand_u64() -> u64 {
+    rdrand().unwrap_or_else(fallback_u64)
+}
+
+// bufを乱数で埋める
+pub fn fill_bytes(buf: &mut [u8]) {
+    fill_bytes_with(buf, rand_u64);
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::collections::BTreeSet;
+    use alloc::vec::Vec;
+
+    // 連続した値が全て異なり、どのビットも0と1の両方をとる
+    fn assert_looks_random(values: &[u64]) {
+        let distinct: BTreeSet<u64> = values.iter().copied().collect();
+        assert_eq!(distinct.len(), values.len());
+        assert_eq!(values.iter().fold(0, |acc, v| acc | v), u64::MAX);
+        assert_eq!(values.iter().fold(u64::MAX, |acc, v| acc & v), 0);
+    }
+
+    #[test_case]
+    fn fallback_prng_is_not_constant() {
+        let values: Vec<u64> = (0..1000).map(|_| fallback_u64()).collect();
+        assert_looks_random(&values);
+    }
+    #[test_case]
+    fn rand_u64_looks_random() {
+        let values: Vec<u64> = (0..1000).map(|_| rand_u64()).collect();
+        assert_looks_random(&values);
+        // QEMUのCPUモデルによってはrdrandがないので、ある場合だけ確かめる
+        if cpu_info().has_rdrand() {
+            assert!(rdrand().is_some());
+        }
+    }
+    #[test_case]
+    fn fill_bytes_fills_every_byte() {
+        // 末尾の8バイトに満たない部分も埋まることを確かめるために、半端な長さにする
+        let mut touched = [false; 37];
+        for _ in 0..8 {
+            let mut buf = [0u8; 37];
+            fill_bytes(&mut buf);
+            for (t, b) in touched.iter_mut().zip(buf) {
+                *t |= b != 0;
+            }
+        }
+        assert!(touched.iter().all(|t| *t));
+    }
+    #[test_case]
+    fn seeded_rng_is_reproducible() {
+        let mut a = SeededRng::new(42);
+        let mut b = SeededRng::new(42);
+        let first: Vec<u64> = (0..3).map(|_| a.next_u64()).collect();
+        assert_eq!(
+            first,
+            [
+                0x31b0_ece7_c4f6_97a2,
+                0x9008_a3b1_cb68_6f03,
+                0x7c71_73ab_d97b_e16f
+            ]
+        );
+        assert_eq!(b.next_u64(), first[0]);
+        let mut x = [0u8; 13];
+        let mut y = [0u8; 13];
+        a.fill_bytes(&mut x);
+        b.next_u64();
+        b.next_u64();
+        b.fill_bytes(&mut y);
+        assert_eq!(x, y);
+        assert_ne!(SeededRng::new(43).next_u64(), first[0]);
+        let values: Vec<u64> = (0..1000).map(|_| a.next_u64()).collect();
+        assert_looks_random(&values);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::result::Result;
+use crate::sync::SpinLock;
+use core::fmt;
+use core::ops::Range;
+
+// ページアロケータに渡してはいけない物理メモリの範囲（ACPIのテーブル、フレームバッファ、ローダのデータなど）
+// ページアロケータのinit_with_mmapがここを見て、空きメモリから除く
+// ヒープができる前に使うので、決まった数の表に覚えておく
+
+// 覚えておける範囲の数
+const MAX_RESERVED_REGIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReservedRegion {
+    pub phys: u64,
+    pub len: u64,
+    // 何のために取っておいたか（ログに出す）
+    pub tag: &'static str,
+}
+impl ReservedRegion {
+    pub fn range(&self) -> Range<u64> {
+        self.phys..self.phys + self.len
+    }
+}
+impl fmt::Display for ReservedRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018X}-{:#018X} {}",
+            self.phys,
+            self.phys + self.len,
+            self.tag
+        )
+    }
+}
+
+#[derive(Clone)]
+struct ReservationTable {
+    regions: [Option<ReservedRegion>; MAX_RESERVED_REGIONS],
+}
+impl ReservationTable {
+    const fn new() -> Self {
+        Self {
+            regions: [None; MAX_RESERVED_REGIONS],
+        }
+    }
+
+    fn reserve(&mut self, phys: u64, len: u64, tag: &'static str) -> Result<()> {
+        if len == 0 {
+            return Err("reserve_region: len must not be zero");
+        }
+        let end = phys
+            .checked_add(len)
+            .ok_or("reserve_region: range overflows")?;
+        // 同じタグで重なるか隣り合う範囲があれば、広げてまとめる（メモリマップの細切れの範囲で表が溢れないように）
+        for r in self.regions.iter_mut().flatten() {
+            if r.tag == tag && phys <= r.phys + r.len && r.phys <= end {
+                let start = r.phys.min(phys);
+                r.len = (r.phys + r.len).max(end) - start;
+                r.phys = start;
+                return Ok(());
+            }
+        }
+        let slot = self
+            .regions
+            .iter_mut()
+            .find(|r| r.is_none())
+            .ok_or("reserve_region: too many regions")?;
+        *slot = Some(ReservedRegion { phys, len, tag });
+        Ok(())
+    }
+
+    // rangeのうち、取っておいた範囲と重ならない部分を、アドレスの小さい順にfに渡す
+    fn for_each_unreserved(&self, range: Range<u64>, mut f: impl FnMut(Range<u64>)) {
+        let mut sorted = self.regions;
+        sorted.sort_unstable_by_key(|r| r.map_or(u64::MAX, |r| r.phys));
+        let mut start = range.start;
+        for r in sorted.iter().flatten() {
+            if r.phys >= range.end {
+                break;
+            }
+            if r.phys > start {
+                f(start..r.phys);
+            }
+            start = start.max(r.phys + r.len);
+        }
+        if start < range.end {
+            f(start..range.end);
+        }
+    }
+}
+
+static RESERVATIONS: SpinLock<ReservationTable> = SpinLock::new(ReservationTable::new());
+
+// 物理アドレスphysからlenバイトを、tagという名前で取っておく
+// ページアロケータを初期化する前（init_with_mmapより前）に呼ぶこと
+pub fn reserve_region(phys: u64, len: u64, tag: &'static str) -> Result<()> {
+    RESERVATIONS.lock().reserve(phys, len, tag)
+}
+
+// rangeのうち、取っておいた範囲と重ならない部分を、アドレスの小さい順にfに渡す
+// 後から空きメモリを加えるとき（reclaim_boot_memory）に使う
+pub fn for_each_unreserved(range: Range<u64>, f: impl FnMut(Range<u64>)) {
+    let table = RESERVATIONS.lock().clone();
+    table.for_each_unreserved(range, f);
+}
+
+// 取っておいた範囲を、取っておいた順に返す（呼んだ時点の写し）
+pub fn regions() -> Regions {
+    Regions {
+        table: RESERVATIONS.lock().clone(),
+        next: 0,
+    }
+}
+
+#[derive(Clone)]
+pub struct Regions {
+    table: ReservationTable,
+    next: usize,
+}
+impl Iterator for Regions {
+    type Item = ReservedRegion;
+    fn next(&mut self) -> Option<Self::Item> {
+        let region = *self.table.regions.get(self.next)?;
+        self.next += 1;
+        region
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+
+    #[test_case]
+    fn regions_with_the_same_tag_are_merged() {
+        let mut table = ReservationTable::new();
+        table.reserve(0x1000, 0x1000, "ACPI").unwrap();
+        table.reserve(0x2000, 0x3000, "ACPI").unwrap();
+        table.reserve(0x4000, 0x1000, "framebuffer").unwrap();
+        table.reserve(0x800, 0x1000, "ACPI").unwrap();
+        assert!(table.reserve(0x9000, 0, "empty").is_err());
+        assert!(table.reserve(u64::MAX, 2, "overflow").is_err());
+        let regions = Regions { table, next: 0 };
+        let mut iter = regions.clone();
+        assert_eq!(iter.next().map(|r| r.range()), Some(0x800..0x5000));
+        let fb = iter.next().unwrap();
+        assert_eq!((fb.range(), fb.tag), (0x4000..0x5000, "framebuffer"));
+        assert_eq!(iter.next(), None);
+        assert_eq!(
+            alloc::format!("{fb}"),
+            "0x0000000000004000-0x0000000000005000 framebuffer"
+        );
+        assert_eq!(regions.count(), 2);
+    }
+    #[test_case]
+    fn unreserved_parts_are_reported_in_order() {
+        let mut table = ReservationTable::new();
+        table.reserve(0x6000, 0x1000, "b").unwrap();
+        table.reserve(0x2000, 0x1000, "a").unwrap();
+        table.reserve(0x2800, 0x1000, "c").unwrap();
+        let mut parts = [0..0, 0..0, 0..0, 0..0];
+        let mut n = 0;
+        table.for_each_unreserved(0x1000..0x8000, |r| {
+            parts[n] = r;
+            n += 1;
+        });
+        assert_eq!(n, 3);
+        assert_eq!(parts[..3], [0x1000..0x2000, 0x3800..0x6000, 0x7000..0x8000]);
+        n = 0;
+        table.for_each_unreserved(0x2000..0x3000, |_| n += 1);
+        assert_eq!(n, 0);
+    }
+    #[test_case]
+    fn the_table_has_a_fixed_size() {
+        let mut table = ReservationTable::new();
+        for i in 0..MAX_RESERVED_REGIONS as u64 {
+            // 隣り合わないように1ページずつ空ける
+            table.reserve(i * 0x2000, 0x1000, "loader data").unwrap();
+        }
+        assert!(table.reserve(0x100_0000, 0x1000, "loader data").is_err());
+        // まとめられる範囲なら、表が一杯でも取っておける
+        table.reserve(0x1000, 0x1000, "loader data").unwrap();
+    }
+    #[test_case]
+    fn boot_reservations_are_reported() {
+        // 起動時にフレームバッファを取っておいている
+        assert!(regions().any(|r| r.tag == "framebuffer" && r.len > 0));
+    }
+}
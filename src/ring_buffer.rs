@@ -0,0 +1,118 @@
+// 固定長のリングバッファ
+// ヒープを使わないので、割り込みハンドラやアロケータの初期化前でも使える
+pub struct RingBuffer<T, const N: usize> {
+    buf: [Option<T>; N],
+    // 一番古い要素の位置
+    head: usize,
+    len: usize,
+    // 満杯で捨てた要素の数
+    dropped: usize,
+}
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [None; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+    pub fn capacity(&self) -> usize {
+        N
+    }
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    // 末尾に追加する（満杯なら追加せずに返す）
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.buf[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    // 末尾に追加する（満杯なら一番古い要素を捨てる）
+    pub fn push_overwrite(&mut self, value: T) {
+        if self.is_full() {
+            self.pop();
+            self.dropped += 1;
+        }
+        // 満杯でなくなったので必ず成功する
+        let _ = self.push(value);
+    }
+
+    // 一番古い要素を取り出す
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = self.buf[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        value
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+
+    // 古い順に要素を辿る
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len).filter_map(move |i| self.buf[(self.head + i) % N])
+    }
+}
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn push_and_pop_in_order() {
+        let mut rb = RingBuffer::<u8, 4>::new();
+        assert!(rb.is_empty());
+        for i in 0..4 {
+            assert_eq!(rb.push(i), Ok(()));
+        }
+        assert!(rb.is_full());
+        assert_eq!(rb.push(4), Err(4));
+        assert_eq!(rb.pop(), Some(0));
+        assert_eq!(rb.push(4), Ok(()));
+        assert_eq!(rb.iter().sum::<u8>(), 1 + 2 + 3 + 4);
+        for i in 1..5 {
+            assert_eq!(rb.pop(), Some(i));
+        }
+        assert_eq!(rb.pop(), None);
+    }
+
+    // 満杯の時は一番古い要素を捨てて、その数を数える
+    #[test_case]
+    fn push_overwrite_drops_oldest() {
+        let mut rb = RingBuffer::<u8, 3>::new();
+        for i in 0..5 {
+            rb.push_overwrite(i);
+        }
+        assert_eq!(rb.dropped(), 2);
+        assert_eq!(rb.pop(), Some(2));
+        assert_eq!(rb.pop(), Some(3));
+        assert_eq!(rb.pop(), Some(4));
+        assert!(rb.is_empty());
+    }
+}
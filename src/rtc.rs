@@ -0,0 +1,267 @@
+use crate::x86::busy_loop_hint;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use crate::x86::InterruptGuard;
+use core::fmt;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+// CMOSのレジスタ番号を書くポートと、値を読むポート
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+
+// 時刻の更新中（この間に読むと値が壊れていることがある）
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+// 立っていれば24時間表記、いなければ12時間表記（時の最上位ビットが午後）
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+// 立っていればバイナリ、いなければBCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOUR_PM: u8 = 1 << 7;
+
+// 更新中フラグが消えるのを待つ回数の上限（更新は1秒に1回、2ms以内に終わる）
+const MAX_UPDATE_POLLS: usize = 1_000_000;
+// 2回続けて同じ値が読めるまで読み直す回数の上限
+const MAX_READ_ATTEMPTS: usize = 8;
+
+// CMOSのレジスタへのアクセス
+// テストでは実際のI/Oポートの代わりにモックを使えるようにする
+pub trait CmosRegisters {
+    fn read_reg(&self, index: u8) -> u8;
+}
+
+pub struct Cmos;
+impl CmosRegisters for Cmos {
+    fn read_reg(&self, index: u8) -> u8 {
+        // 番号の書き込みと値の読み込みの間に、割り込みハンドラが別の番号を書かないようにする
+        let _guard = InterruptGuard::new();
+        write_io_port_u8(CMOS_INDEX, index);
+        read_io_port_u8(CMOS_DATA)
+    }
+}
+
+// ACPIのFADTに書かれている、世紀を保持するCMOSのレジスタ番号（0ならない）
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+// ACPIのテーブルを読んだら、世紀のレジスタを設定する
+// 設定されていなければ、20xx年とみなす
+pub fn set_century_register(index: u8) {
+    CENTURY_REGISTER.store(index, Ordering::Relaxed);
+}
+
+// RTCのレジスタから読んだままの値
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+impl RawTime {
+    fn read(cmos: &impl CmosRegisters, century_reg: Option<u8>) -> Self {
+        Self {
+            second: cmos.read_reg(REG_SECOND),
+            minute: cmos.read_reg(REG_MINUTE),
+            hour: cmos.read_reg(REG_HOUR),
+            day: cmos.read_reg(REG_DAY),
+            month: cmos.read_reg(REG_MONTH),
+            year: cmos.read_reg(REG_YEAR),
+            century: century_reg.map(|r| cmos.read_reg(r)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+// ISO 8601の形式（2024-01-02T03:04:05）
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn bcd_to_binary(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+// レジスタBの設定に従って、BCDや12時間表記を変換する
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let conv = |v: u8| if binary { v } else { bcd_to_binary(v) };
+    let mut hour = conv(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12時間表記では、午前0時は12AM、正午は12PM
+        let pm = raw.hour & HOUR_PM != 0;
+        hour = match (pm, hour) {
+            (false, 12) => 0,
+            (true, 12) => 12,
+            (true, h) => h + 12,
+            (false, h) => h,
+        };
+    }
+    let century = raw.century.map_or(20, conv) as u16;
+    DateTime {
+        year: century * 100 + conv(raw.year) as u16,
+        month: conv(raw.month),
+        day: conv(raw.day),
+        hour,
+        minute: conv(raw.minute),
+        second: conv(raw.second),
+    }
+}
+
+fn wait_for_update(cmos: &impl CmosRegisters) {
+    for _ in 0..MAX_UPDATE_POLLS {
+        if cmos.read_reg(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS == 0 {
+            return;
+        }
+        busy_loop_hint();
+    }
+}
+
+// 更新の途中で読んだ値が混ざらないように、2回続けて同じ値が読めるまで読み直す
+fn read_from(cmos: &impl CmosRegisters, century_reg: Option<u8>) -> DateTime {
+    wait_for_update(cmos);
+    let mut last = RawTime::read(cmos, century_reg);
+    for _ in 0..MAX_READ_ATTEMPTS {
+        wait_for_update(cmos);
+        let raw = RawTime::read(cmos, century_reg);
+        if raw == last {
+            break;
+        }
+        last = raw;
+    }
+    decode(last, cmos.read_reg(REG_STATUS_B))
+}
+
+// 現在の日時を読む
+pub fn read() -> DateTime {
+    let century_reg = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => None,
+        r => Some(r),
+    };
+    read_from(&Cmos, century_reg)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use core::cell::Cell;
+
+    fn raw(hour: u8) -> RawTime {
+        RawTime {
+            second: 0x59,
+            minute: 0x30,
+            hour,
+            day: 0x31,
+            month: 0x12,
+            year: 0x99,
+            century: None,
+        }
+    }
+
+    #[test_case]
+    fn bcd_values_are_converted() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x10), 10);
+        assert_eq!(bcd_to_binary(0x59), 59);
+        let t = decode(raw(0x23), STATUS_B_24_HOUR);
+        assert_eq!(alloc::format!("{t}"), "2099-12-31T23:30:59");
+        // バイナリモードでは変換しない
+        let binary = RawTime {
+            century: Some(20),
+            ..raw(23)
+        };
+        let t = decode(binary, STATUS_B_24_HOUR | STATUS_B_BINARY);
+        assert_eq!((t.year, t.month, t.day), (2000 + 0x99, 0x12, 0x31));
+        assert_eq!((t.hour, t.minute, t.second), (23, 0x30, 0x59));
+    }
+    #[test_case]
+    fn twelve_hour_edge_cases() {
+        let hour = |raw_hour, status_b| decode(raw(raw_hour), status_b).hour;
+        // BCD（12AMは0時、12PMは12時）
+        assert_eq!(hour(0x12, 0), 0);
+        assert_eq!(hour(0x01, 0), 1);
+        assert_eq!(hour(0x11, 0), 11);
+        assert_eq!(hour(HOUR_PM | 0x12, 0), 12);
+        assert_eq!(hour(HOUR_PM | 0x01, 0), 13);
+        assert_eq!(hour(HOUR_PM | 0x11, 0), 23);
+        // バイナリ
+        assert_eq!(hour(12, STATUS_B_BINARY), 0);
+        assert_eq!(hour(HOUR_PM | 12, STATUS_B_BINARY), 12);
+        assert_eq!(hour(HOUR_PM | 11, STATUS_B_BINARY), 23);
+    }
+    #[test_case]
+    fn century_register_is_used_when_given() {
+        let t = decode(
+            RawTime {
+                century: Some(0x21),
+                year: 0x05,
+                ..raw(0x00)
+            },
+            STATUS_B_24_HOUR,
+        );
+        assert_eq!(t.year, 2105);
+    }
+
+    // 秒のレジスタを読むたびに、最初の何回かは違う値を返すモック
+    struct TornCmos {
+        second_reads: Cell<usize>,
+    }
+    impl CmosRegisters for TornCmos {
+        fn read_reg(&self, index: u8) -> u8 {
+            match index {
+                REG_SECOND => {
+                    let n = self.second_reads.get();
+                    self.second_reads.set(n + 1);
+                    [0x58, 0x59, 0x00, 0x00][n.min(3)]
+                }
+                REG_STATUS_B => STATUS_B_24_HOUR,
+                REG_HOUR => 0x10,
+                REG_DAY | REG_MONTH => 0x01,
+                _ => 0,
+            }
+        }
+    }
+    #[test_case]
+    fn read_retries_until_two_reads_match() {
+        let cmos = TornCmos {
+            second_reads: Cell::new(0),
+        };
+        let t = read_from(&cmos, None);
+        assert_eq!(alloc::format!("{t}"), "2000-01-01T10:00:00");
+        assert_eq!(cmos.second_reads.get(), 4);
+    }
+    #[test_case]
+    fn rtc_reads_a_plausible_date() {
+        let t = read();
+        assert!(t.year >= 2024);
+        assert!((1..=12).contains(&t.month) && (1..=31).contains(&t.day));
+        assert!(t.hour < 24 && t.minute < 60 && t.second < 60);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::crc32::crc32_update;
+use crate::graphics::Bitmap;
+use crate::uefi::PixelFormat;
+use crate::uefi::VramBufferInfo;
+use core::fmt;
+use core::fmt::Write;
+
+// 画面をPPM（P6形式）の画像として書き出す
+// シリアルのようなテキストの出力先には、Base64にして前後に目印の行を付けて書く
+//   -----BEGIN WASABI SCREENSHOT <幅>x<高さ>-----
+//   （76文字ごとに改行したBase64）
+//   -----END WASABI SCREENSHOT crc32=<PPM全体のCRC32>-----
+// debugconのようにバイナリを通せる出力先には、目印の行の間にPPMをそのまま書く
+//   -----BEGIN WASABI SCREENSHOT RAW <バイト数>-----
+// scripts/decode_screenshot.pyがログから取り出してPPMファイルに戻す
+// 1080pだと6MB近くになり、起動直後はヒープに収まらないことがあるので、少しずつ読みながら書く
+
+const BASE64_LINE_LEN: usize = 76;
+// 一度に変換するピクセル数
+const PIXELS_PER_CHUNK: usize = 64;
+
+// ピクセルの値からR, G, Bを取り出す
+// ピクセルはフレームバッファの形式の順に並んでいる（BGRなら0xRRGGBB、RGBなら0xBBGGRR）
+fn to_rgb(pixel: u32, format: PixelFormat) -> [u8; 3] {
+    let [b0, b1, b2, _] = pixel.to_le_bytes();
+    match format {
+        PixelFormat::Rgb => [b0, b1, b2],
+        _ => [b2, b1, b0],
+    }
+}
+
+// ヘッダや数字のような短い文字列を、ヒープを使わずに組み立てるバッファ
+struct SmallBuf {
+    buf: [u8; 48],
+    len: usize,
+}
+impl SmallBuf {
+    fn new() -> Self {
+        Self {
+            buf: [0; 48],
+            len: 0,
+        }
+    }
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+impl fmt::Write for SmallBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// PPMのヘッダ（"P6\n<幅> <高さ>\n255\n"）
+fn ppm_header<T: Bitmap>(bitmap: &T) -> SmallBuf {
+    let mut header = SmallBuf::new();
+    // i64の幅と高さでもバッファに収まる
+    let _ = write!(header, "P6\n{} {}\n255\n", bitmap.width(), bitmap.height());
+    header
+}
+
+// PPM全体のバイト数
+fn ppm_len<T: Bitmap>(bitmap: &T) -> usize {
+    ppm_header(bitmap).len + (bitmap.width() * bitmap.height()) as usize * 3
+}
+
+// PPMのバイト列を少しずつemitに渡す
+// 各行はrow()で読むので、pixels_per_lineが幅より大きくても余った部分は含まない
+fn for_each_ppm_chunk<T: Bitmap>(
+    bitmap: &T,
+    format: PixelFormat,
+    mut emit: impl FnMut(&[u8]) -> fmt::Result,
+) -> fmt::Result {
+    emit(ppm_header(bitmap).as_bytes())?;
+    let mut rgb = [0u8; PIXELS_PER_CHUNK * 3];
+    for y in 0..bitmap.height() {
+        let row = bitmap.row(y).ok_or(fmt::Error)?;
+        for pixels in row.chunks(PIXELS_PER_CHUNK) {
+            for (dst, pixel) in rgb.chunks_exact_mut(3).zip(pixels) {
+                dst.copy_from_slice(&to_rgb(*pixel, format));
+            }
+            emit(&rgb[..pixels.len() * 3])?;
+        }
+    }
+    Ok(())
+}
+
+const BASE64_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// 渡されたバイト列をBase64にして、BASE64_LINE_LEN文字ごとに改行しながら書く
+// 3バイトに満たない端数は次の呼び出しまで持っておき、finish()で'='を補って書く
+struct Base64Writer<'a, W: fmt::Write> {
+    out: &'a mut W,
+    pending: [u8; 3],
+    pending_len: usize,
+    column: usize,
+}
+impl<'a, W: fmt::Write> Base64Writer<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Self {
+            out,
+            pending: [0; 3],
+            pending_len: 0,
+            column: 0,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> fmt::Result {
+        for b in bytes {
+            self.pending[self.pending_len] = *b;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.flush_group()?;
+            }
+        }
+        Ok(())
+    }
+
+    // pendingの1から3バイトを4文字にして書く
+    fn flush_group(&mut self) -> fmt::Result {
+        let [a, b, c] = self.pending;
+        let n = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+        let mut quad = [b'='; 4];
+        for (i, q) in quad.iter_mut().enumerate().take(self.pending_len + 1) {
+            *q = BASE64_TABLE[(n >> (18 - 6 * i) & 0x3f) as usize];
+        }
+        // 表の文字と'='だけなのでUTF-8として読める
+        self.out
+            .write_str(core::str::from_utf8(&quad).map_err(|_| fmt::Error)?)?;
+        self.pending = [0; 3];
+        self.pending_len = 0;
+        self.column += 4;
+        if self.column >= BASE64_LINE_LEN {
+            self.out.write_char('\n')?;
+            self.column = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> fmt::Result {
+        if self.pending_len > 0 {
+            self.flush_group()?;
+        }
+        if self.column > 0 {
+            self.out.write_char('\n')?;
+        }
+        Ok(())
+    }
+}
+
+// bitmapをPPMにして、Base64で目印の行と一緒に書く
+pub fn write_ppm_base64<T: Bitmap>(
+    bitmap: &T,
+    format: PixelFormat,
+    out: &mut impl fmt::Write,
+) -> fmt::Result {
+    writeln!(
+        out,
+        "-----BEGIN WASABI SCREENSHOT {}x{}-----",
+        bitmap.width(),
+        bitmap.height()
+    )?;
+    let mut crc = 0;
+    let mut base64 = Base64Writer::new(out);
+    for_each_ppm_chunk(bitmap, format, |bytes| {
+        crc = crc32_update(crc, bytes);
+        base64.write(bytes)
+    })?;
+    base64.finish()?;
+    writeln!(out, "-----END WASABI SCREENSHOT crc32={crc:08x}-----")
+}
+
+// bitmapをPPMにして、目印の行の間にそのまま書く（バイナリを通せる出力先用）
+pub fn write_ppm_raw<T: Bitmap>(
+    bitmap: &T,
+    format: PixelFormat,
+    mut out: impl FnMut(&[u8]),
+) -> fmt::Result {
+    let mut begin = SmallBuf::new();
+    writeln!(
+        begin,
+        "-----BEGIN WASABI SCREENSHOT RAW {}-----",
+        ppm_len(bitmap)
+    )?;
+    out(begin.as_bytes());
+    let mut crc = 0;
+    for_each_ppm_chunk(bitmap, format, |bytes| {
+        crc = crc32_update(crc, bytes);
+        out(bytes);
+        Ok(())
+    })?;
+    let mut end = SmallBuf::new();
+    writeln!(end, "\n-----END WASABI SCREENSHOT crc32={crc:08x}-----")?;
+    out(end.as_bytes());
+    Ok(())
+}
+
+// 今の画面をBase64のPPMとして書き出す
+// フレームバッファを読むのは遅いので、1080pだと数秒かかる
+pub fn dump_ppm(vram: &VramBufferInfo, out: &mut impl fmt::Write) -> fmt::Result {
+    write_ppm_base64(vram, vram.pixel_format(), out)
+}
+
+// 今の画面をPPMのままoutに書き出す
+pub fn dump_ppm_raw(vram: &VramBufferInfo, out: impl FnMut(&[u8])) -> fmt::Result {
+    write_ppm_raw(vram, vram.pixel_format(), out)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use crate::crc32::crc32;
+    use crate::graphics_tests::OwnedBitmap;
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    fn decode_base64(s: &str) -> Vec<u8> {
+        let values: Vec<u32> = s
+            .bytes()
+            .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+            .map(|c| BASE64_TABLE.iter().position(|t| *t == c).unwrap() as u32)
+            .collect();
+        let mut bytes = Vec::new();
+        for group in values.chunks(4) {
+            let n = group
+                .iter()
+                .enumerate()
+                .fold(0, |n, (i, v)| n | v << (18 - 6 * i));
+            let len = group.len() - 1;
+            bytes.extend_from_slice(&n.to_be_bytes()[1..1 + len]);
+        }
+        bytes
+    }
+
+    // 5x3の画像（幅が3の倍数でないので、Base64の端数も確かめられる）
+    fn test_bitmap() -> OwnedBitmap {
+        let mut bitmap = OwnedBitmap::new(5, 3);
+        for y in 0..3 {
+            for x in 0..5 {
+                *bitmap.pixel_at_mut(x, y).unwrap() = (x as u32 * 0x40) << 16 | (y as u32) << 8 | 7;
+            }
+        }
+        bitmap
+    }
+    fn expected_ppm(bitmap: &OwnedBitmap) -> Vec<u8> {
+        let mut ppm = Vec::from(&b"P6\n5 3\n255\n"[..]);
+        for y in 0..3 {
+            for x in 0..5 {
+                let p = bitmap.pixel_at(x, y).unwrap();
+                ppm.extend_from_slice(&[(p >> 16) as u8, (p >> 8) as u8, p as u8]);
+            }
+        }
+        ppm
+    }
+
+    #[test_case]
+    fn base64_dump_decodes_to_the_source_pixels() {
+        let bitmap = test_bitmap();
+        let mut out = String::new();
+        write_ppm_base64(&bitmap, PixelFormat::Bgr, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "-----BEGIN WASABI SCREENSHOT 5x3-----");
+        let ppm = decode_base64(&lines[1..lines.len() - 1].concat());
+        assert_eq!(ppm, expected_ppm(&bitmap));
+        assert_eq!(ppm.len(), ppm_len(&bitmap));
+        assert_eq!(
+            lines[lines.len() - 1],
+            alloc::format!("-----END WASABI SCREENSHOT crc32={:08x}-----", crc32(&ppm))
+        );
+        assert!(lines.iter().all(|l| l.len() <= BASE64_LINE_LEN));
+    }
+    #[test_case]
+    fn long_dumps_are_wrapped_into_lines() {
+        let bitmap = OwnedBitmap::new(40, 2);
+        let mut out = String::new();
+        write_ppm_base64(&bitmap, PixelFormat::Bgr, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        let body = &lines[1..lines.len() - 1];
+        assert!(body.len() > 1);
+        assert!(body[..body.len() - 1]
+            .iter()
+            .all(|l| l.len() == BASE64_LINE_LEN));
+        assert_eq!(decode_base64(&body.concat()), expected_ppm_zero(40, 2));
+    }
+    fn expected_ppm_zero(w: usize, h: usize) -> Vec<u8> {
+        let mut ppm = Vec::from(alloc::format!("P6\n{w} {h}\n255\n").as_bytes());
+        ppm.resize(ppm.len() + w * h * 3, 0);
+        ppm
+    }
+    #[test_case]
+    fn raw_dump_is_framed_and_rgb_is_converted() {
+        let bitmap = test_bitmap();
+        let mut out = Vec::new();
+        write_ppm_raw(&bitmap, PixelFormat::Bgr, |b| out.extend_from_slice(b)).unwrap();
+        let ppm = expected_ppm(&bitmap);
+        let begin = alloc::format!("-----BEGIN WASABI SCREENSHOT RAW {}-----\n", ppm.len());
+        let end = alloc::format!(
+            "\n-----END WASABI SCREENSHOT crc32={:08x}-----\n",
+            crc32(&ppm)
+        );
+        assert_eq!(out, [begin.as_bytes(), &ppm, end.as_bytes()].concat());
+
+        // RGBのフレームバッファでは、下位バイトが赤になる
+        assert_eq!(to_rgb(0x00332211, PixelFormat::Rgb), [0x11, 0x22, 0x33]);
+        assert_eq!(to_rgb(0x00332211, PixelFormat::Bgr), [0x33, 0x22, 0x11]);
+    }
+}
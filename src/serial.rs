@@ -1,70 +1,1716 @@
+use crate::pic::enable_irq;
+use crate::pic::register_irq_handler;
+use crate::result::Result;
+use crate::ring_buffer::RingBuffer;
+use crate::watchdog;
 use crate::x86::busy_loop_hint;
+use crate::x86::disable_interrupts;
 use crate::x86::read_io_port_u8;
 use crate::x86::write_io_port_u8;
+use crate::x86::InterruptGuard;
 use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
 
+// 標準的なシリアルポートのI/Oアドレス
+const COM1_BASE: u16 = 0x3f8;
+const COM2_BASE: u16 = 0x2f8;
+const COM3_BASE: u16 = 0x3e8;
+const COM4_BASE: u16 = 0x2e8;
+// COM1/COM3はIRQ4、COM2/COM4はIRQ3を共有する
+const STANDARD_PORTS: [(u16, u8); 4] = [
+    (COM1_BASE, 4),
+    (COM2_BASE, 3),
+    (COM3_BASE, 4),
+    (COM4_BASE, 3),
+];
+
+fn standard_port_index(base: u16) -> Option<usize> {
+    STANDARD_PORTS.iter().position(|(b, _)| *b == base)
+}
+
+// レジスタのオフセット
+const REG_DATA: u16 = 0;
+const REG_IER: u16 = 1; // 割り込み許可レジスタ
+const REG_FCR: u16 = 2; // FIFO制御レジスタ
+const REG_IIR: u16 = 2; // 割り込み識別レジスタ（読み出すとFCRの代わりにこれが読める）
+const REG_LCR: u16 = 3; // ライン制御レジスタ
+const REG_MCR: u16 = 4; // モデム制御レジスタ
+const REG_DLL: u16 = 0; // DLAB=1のときの除数の下位バイト
+const REG_DLM: u16 = 1; // DLAB=1のときの除数の上位バイト
+
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+const LCR_DLAB: u8 = 1 << 7;
+const REG_LSR: u16 = 5; // ラインステータスレジスタ
+const REG_SCRATCH: u16 = 7; // 何に使ってもよいスクラッチレジスタ
+
+const IER_RX_AVAILABLE: u8 = 1 << 0; // 受信データがあるときに割り込む
+const IER_TX_EMPTY: u8 = 1 << 1; // 送信バッファが空になったときに割り込む
+const MCR_LOOPBACK: u8 = 1 << 4; // 送信したデータをそのまま受信する
+
+// FIFO制御レジスタの値
+const FCR_ENABLE: u8 = 0xC7; // FIFO有効、送受信FIFOをクリア、14バイトで受信割り込み
+const FCR_64_BYTE_FIFO: u8 = 1 << 5; // 64バイトFIFOを使う（16750のみ、DLAB=1のときだけ書ける）
+const FCR_ENABLE_64: u8 = FCR_ENABLE | FCR_64_BYTE_FIFO;
+
+// FCRを書いた後のIIRの上位2ビット
+const IIR_FIFO_MASK: u8 = 0b1100_0000;
+const IIR_FIFO_BROKEN: u8 = 0b1000_0000; // 16550: FIFOはあるが壊れている
+const IIR_FIFO_WORKING: u8 = 0b1100_0000; // 16550A以降
+const IIR_FIFO_64: u8 = 1 << 5; // 16750の64バイトFIFOが有効
+
+// ラインステータスレジスタのビット
+const LSR_DATA_READY: u8 = 1 << 0; // 受信データがある
+const LSR_OVERRUN_ERROR: u8 = 1 << 1; // 受信データを読む前に次のデータが来て、前のデータが失われた
+const LSR_PARITY_ERROR: u8 = 1 << 2;
+const LSR_FRAMING_ERROR: u8 = 1 << 3;
+const LSR_TX_EMPTY: u8 = 1 << 5; // 送信バッファが空
+
+// シリアルポートのレジスタへのアクセス
+// テストでは実際のI/Oポートの代わりにモックを使えるようにする
+pub trait UartRegisters {
+    fn read_reg(&self, offset: u16) -> u8;
+    fn write_reg(&self, offset: u16, value: u8);
+}
+
+// 受信時に検出したラインステータスエラーの回数
+pub struct LineErrors {
+    overrun: AtomicUsize,
+    parity: AtomicUsize,
+    framing: AtomicUsize,
+}
+impl LineErrors {
+    pub const fn new() -> Self {
+        Self {
+            overrun: AtomicUsize::new(0),
+            parity: AtomicUsize::new(0),
+            framing: AtomicUsize::new(0),
+        }
+    }
+    fn record(&self, lsr: u8) {
+        if lsr & LSR_OVERRUN_ERROR != 0 {
+            self.overrun.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & LSR_PARITY_ERROR != 0 {
+            self.parity.fetch_add(1, Ordering::Relaxed);
+        }
+        if lsr & LSR_FRAMING_ERROR != 0 {
+            self.framing.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub fn overrun(&self) -> usize {
+        self.overrun.load(Ordering::Relaxed)
+    }
+    pub fn parity(&self) -> usize {
+        self.parity.load(Ordering::Relaxed)
+    }
+    pub fn framing(&self) -> usize {
+        self.framing.load(Ordering::Relaxed)
+    }
+}
+impl Default for LineErrors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// SerialPortは使うたびに作り直されるので、エラーの回数はここで数える
+static LINE_ERRORS: LineErrors = LineErrors::new();
+
+pub fn line_errors() -> &'static LineErrors {
+    &LINE_ERRORS
+}
+
+// 除数を1にしたときの通信速度
+const UART_CLOCK_BAUD: u32 = 115200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,  // パリティビットが常に1
+    Space, // パリティビットが常に0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8, // 5〜8
+    pub stop_bits: u8, // 1または2
+    pub parity: Parity,
+}
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud: UART_CLOCK_BAUD,
+            data_bits: 8,
+            stop_bits: 1,
+            parity: Parity::None,
+        }
+    }
+}
+impl SerialConfig {
+    // ボーレートの除数
+    // 実際の通信速度と指定した速度のずれが2%を超える場合はエラー
+    fn divisor(&self) -> Result<u16> {
+        if self.baud == 0 || self.baud > UART_CLOCK_BAUD {
+            return Err("Unsupported baud rate");
+        }
+        let divisor = (UART_CLOCK_BAUD + self.baud / 2) / self.baud;
+        let actual = UART_CLOCK_BAUD / divisor;
+        if actual.abs_diff(self.baud) * 50 > self.baud {
+            return Err("Baud rate cannot be generated within 2%");
+        }
+        u16::try_from(divisor).or(Err("Baud rate is too low"))
+    }
+    // ライン制御レジスタの値（DLABは0）
+    fn lcr(&self) -> Result<u8> {
+        let word_length = match self.data_bits {
+            5..=8 => self.data_bits - 5,
+            _ => return Err("Unsupported number of data bits"),
+        };
+        let stop_bits = match self.stop_bits {
+            1 => 0,
+            2 => LCR_TWO_STOP_BITS,
+            _ => return Err("Unsupported number of stop bits"),
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000,
+            Parity::Odd => 0b001,
+            Parity::Even => 0b011,
+            Parity::Mark => 0b101,
+            Parity::Space => 0b111,
+        } << 3;
+        Ok(word_length | stop_bits | parity)
+    }
+}
+
+// UARTのチップの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum UartType {
+    // まだ調べていない、またはUARTが見つからない
+    Unknown,
+    // スクラッチレジスタもFIFOもない
+    Uart8250,
+    // FIFOがない
+    Uart16450,
+    // FIFOはあるが、バグがあって使えない
+    Uart16550,
+    // 16バイトのFIFO
+    Uart16550A,
+    // 64バイトのFIFO
+    Uart16750,
+}
+impl UartType {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Uart8250,
+            2 => Self::Uart16450,
+            3 => Self::Uart16550,
+            4 => Self::Uart16550A,
+            5 => Self::Uart16750,
+            _ => Self::Unknown,
+        }
+    }
+    // 一度に送受信できるバイト数（FIFOが使えないチップでは1）
+    pub fn fifo_depth(&self) -> usize {
+        match self {
+            Self::Uart16550A => 16,
+            Self::Uart16750 => 64,
+            _ => 1,
+        }
+    }
+}
+impl fmt::Display for UartType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Unknown => "unknown",
+            Self::Uart8250 => "8250",
+            Self::Uart16450 => "16450",
+            Self::Uart16550 => "16550",
+            Self::Uart16550A => "16550A",
+            Self::Uart16750 => "16750",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// 対応しているチップの中で最も大きいFIFOの大きさ
+const MAX_FIFO_DEPTH: usize = 64;
+// チップの種類を調べるときにスクラッチレジスタに書き込む値
+const DETECT_SCRATCH: u8 = 0xA5;
+
+// FIFOを有効にしてみて、IIRに返ってくる値からチップの種類を調べる
+// FIFOが使えないチップではFIFOを無効にしておく
+fn detect_uart_type<R: UartRegisters>(regs: &R) -> UartType {
+    // UARTがないアドレスは0xffが読める（本物のIIRはビット4が常に0）
+    if regs.read_reg(REG_IIR) == 0xff {
+        return UartType::Unknown;
+    }
+    // 8250だけはスクラッチレジスタを持たない
+    regs.write_reg(REG_SCRATCH, DETECT_SCRATCH);
+    if regs.read_reg(REG_SCRATCH) != DETECT_SCRATCH {
+        regs.write_reg(REG_FCR, 0);
+        return UartType::Uart8250;
+    }
+    let lcr = regs.read_reg(REG_LCR);
+    regs.write_reg(REG_LCR, lcr | LCR_DLAB);
+    regs.write_reg(REG_FCR, FCR_ENABLE_64);
+    regs.write_reg(REG_LCR, lcr);
+    let iir = regs.read_reg(REG_IIR);
+    let uart_type = match iir & IIR_FIFO_MASK {
+        IIR_FIFO_WORKING if iir & IIR_FIFO_64 != 0 => UartType::Uart16750,
+        IIR_FIFO_WORKING => UartType::Uart16550A,
+        IIR_FIFO_BROKEN => UartType::Uart16550,
+        _ => UartType::Uart16450,
+    };
+    match uart_type {
+        // 64バイトモードのまま使う
+        UartType::Uart16750 => {}
+        UartType::Uart16550A => regs.write_reg(REG_FCR, FCR_ENABLE),
+        _ => regs.write_reg(REG_FCR, 0),
+    }
+    uart_type
+}
+
+fn init_regs<R: UartRegisters>(regs: &R, cfg: &SerialConfig) -> Result<()> {
+    // レジスタに書き込む前に設定を検証しておく
+    let divisor = cfg.divisor()?;
+    let lcr = cfg.lcr()?;
+
+    regs.write_reg(REG_IER, 0x00); // 割り込み無効化
+
+    // ボーレート設定: DLABを立てている間はデータレジスタとIERが除数の下位/上位バイトになる
+    regs.write_reg(REG_LCR, LCR_DLAB);
+    regs.write_reg(REG_DLL, (divisor & 0xff) as u8);
+    regs.write_reg(REG_DLM, (divisor >> 8) as u8);
+
+    // データビット長、ストップビット、パリティを設定（DLABも同時に下ろす）
+    regs.write_reg(REG_LCR, lcr);
+    // FIFO制御レジスタを有効（FIFOがないチップでは、この後のdetect_uart_typeで無効にする）
+    regs.write_reg(REG_FCR, FCR_ENABLE);
+    regs.write_reg(REG_MCR, 0x0B);
+    Ok(())
+}
+
+// 受信データがあれば1バイト読む
+// パリティエラーやフレーミングエラーのデータは壊れているので捨てる
+// オーバーランは前のデータが失われただけで、今読んだデータは正しい
+fn try_read_from<R: UartRegisters>(regs: &R, errors: &LineErrors) -> Option<u8> {
+    let lsr = regs.read_reg(REG_LSR);
+    read_data_with_lsr(regs, errors, lsr)
+}
+
+// LSRはエラービットを読むとクリアされるので、一度読んだ値を使って受信データを読む
+fn read_data_with_lsr<R: UartRegisters>(regs: &R, errors: &LineErrors, lsr: u8) -> Option<u8> {
+    if lsr & LSR_DATA_READY == 0 {
+        return None;
+    }
+    let data = regs.read_reg(REG_DATA);
+    errors.record(lsr);
+    if lsr & (LSR_PARITY_ERROR | LSR_FRAMING_ERROR) != 0 {
+        return None;
+    }
+    Some(data)
+}
+
+// 1回の割り込みで受信FIFOから読む最大バイト数
+// FIFOの大きさは検出を誤ることがあるので使わず、DATA_READYが下りない壊れたポートで止まらないためだけに制限する
+const RX_DRAIN_MAX_READS: usize = 1024;
+
+// 受信FIFOが空になる（DATA_READYが下りる）まで読み出してrxに積む
+// rxが溢れたら古いデータを捨てる（捨てた数はRingBufferが数えている）
+fn drain_rx_fifo<R: UartRegisters, const N: usize>(
+    regs: &R,
+    errors: &LineErrors,
+    rx: &mut RingBuffer<u8, N>,
+) {
+    for _ in 0..RX_DRAIN_MAX_READS {
+        let lsr = regs.read_reg(REG_LSR);
+        if lsr & LSR_DATA_READY == 0 {
+            return;
+        }
+        if let Some(c) = read_data_with_lsr(regs, errors, lsr) {
+            rx.push_overwrite(c);
+        }
+    }
+}
+
+// 自己診断でループバックさせるバイト、スクラッチレジスタに書き込む値
+const SELF_TEST_BYTE: u8 = 0xAE;
+const SELF_TEST_SCRATCH: u8 = 0x5A;
+// 自己診断でLSRのビットを待つ最大回数
+const SELF_TEST_MAX_POLLS: usize = 10000;
+
+// 送信バッファが空くのを待つ最大回数
+// 300bpsでも1バイト送るのに約33ミリ秒なので、それより十分長く待つ
+const TX_MAX_POLLS: usize = 100_000;
+
+// LSRのビットが立つのを一定回数だけ待つ
+fn wait_for_lsr<R: UartRegisters>(regs: &R, bit: u8, max_polls: usize) -> bool {
+    for _ in 0..max_polls {
+        if regs.read_reg(REG_LSR) & bit != 0 {
+            return true;
+        }
+        busy_loop_hint();
+    }
+    false
+}
+
+// UARTが存在して正しく動くかを確かめる
+// UARTがないアドレスを読むと0xffが返ってくるので、スクラッチレジスタやループバックの値が一致しない
+fn self_test_regs<R: UartRegisters>(regs: &R) -> bool {
+    regs.write_reg(REG_SCRATCH, SELF_TEST_SCRATCH);
+    if regs.read_reg(REG_SCRATCH) != SELF_TEST_SCRATCH {
+        return false;
+    }
+    // 受信割り込みが有効だと折り返したバイトをハンドラに取られてしまうので、一時的に止める
+    let ier = regs.read_reg(REG_IER);
+    regs.write_reg(REG_IER, 0);
+    let mcr = regs.read_reg(REG_MCR);
+    regs.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+    let ok = loopback_roundtrip(regs);
+    // 失敗してもループバックモードのままにしない
+    regs.write_reg(REG_MCR, mcr);
+    regs.write_reg(REG_IER, ier);
+    ok
+}
+
+fn loopback_roundtrip<R: UartRegisters>(regs: &R) -> bool {
+    // 受信FIFOに残っているデータを捨てておく（チップの種類を調べる前にも呼ばれるので、最大の大きさで考える）
+    for _ in 0..MAX_FIFO_DEPTH {
+        if regs.read_reg(REG_LSR) & LSR_DATA_READY == 0 {
+            break;
+        }
+        regs.read_reg(REG_DATA);
+    }
+    if !wait_for_lsr(regs, LSR_TX_EMPTY, SELF_TEST_MAX_POLLS) {
+        return false;
+    }
+    regs.write_reg(REG_DATA, SELF_TEST_BYTE);
+    if !wait_for_lsr(regs, LSR_DATA_READY, SELF_TEST_MAX_POLLS) {
+        return false;
+    }
+    regs.read_reg(REG_DATA) == SELF_TEST_BYTE
+}
+
+// 自己診断に失敗したポートは、送信が終わるのを待っても永遠に終わらないので使わない
+// STANDARD_PORTSと同じ順番
+static PORT_DEAD: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+// read_lineが\rで行を終えたポート（続く\nを次の行の先頭で捨てる）
+static RX_AFTER_CR: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+// 一度でも初期化したポート（init_onceで使う）
+static PORT_INITIALIZED: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+// 初期化のときに調べたチップの種類（UartTypeの値）
+static PORT_UART_TYPE: [AtomicU8; 4] = [const { AtomicU8::new(UartType::Unknown as u8) }; 4];
+
+// 自己診断で見つかったポートの一覧
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SerialPortList {
+    ports: [Option<SerialPort>; 4],
+    len: usize,
+}
+impl SerialPortList {
+    fn push(&mut self, port: SerialPort) {
+        self.ports[self.len] = Some(port);
+        self.len += 1;
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn iter(&self) -> impl Iterator<Item = SerialPort> + '_ {
+        self.ports[..self.len].iter().flatten().copied()
+    }
+    pub fn contains(&self, port: &SerialPort) -> bool {
+        self.iter().any(|p| p == *port)
+    }
+}
+impl fmt::Display for SerialPortList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "(none)");
+        }
+        for (i, port) in self.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{port}")?;
+        }
+        Ok(())
+    }
+}
+
+// COM1〜COM4に自己診断を行い、動くポートを返す
+pub fn detect_ports() -> SerialPortList {
+    let mut list = SerialPortList::default();
+    for (base, _) in STANDARD_PORTS {
+        let mut port = SerialPort::new(base);
+        if port.self_test() {
+            list.push(port);
+        }
+    }
+    list
+}
+
+// panicしたときに呼ぶ
+// 送信バッファに残っているものを送り、以降の出力は同期的に行う
+// panicがバッファのロック中に起きた場合は、残りを諦めて最後のメッセージを優先する
+pub fn switch_to_sync_tx() {
+    let com1 = SerialPort::new_for_com1();
+    if !com1.tx_buffered() {
+        return;
+    }
+    disable_interrupts();
+    if let Some(mut tx) = TX_BUFFER.try_lock() {
+        if com1.is_available() {
+            while let Some(b) = tx.pop() {
+                if !send_byte_to(&com1, b) {
+                    break;
+                }
+            }
+        }
+    }
+    TX_BUFFERED.store(false, Ordering::Release);
+    com1.write_reg(REG_IER, com1.read_reg(REG_IER) & !IER_TX_EMPTY);
+}
+
+// print!などの出力先のポート
+static GLOBAL_PORT_BASE: AtomicU16 = AtomicU16::new(COM1_BASE);
+
+// print!などの出力先を切り替える
+// ファームウェアの設定のまま送らないように、まだ初期化されていなければ初期化してから切り替える
+// 自己診断に失敗したポートには切り替えない
+pub fn set_global_port(mut port: SerialPort) -> Result<()> {
+    match standard_port_index(port.base) {
+        Some(_) => port.init_once(),
+        None => port.init()?,
+    }
+    if !port.is_available() {
+        return Err("Serial port is not available");
+    }
+    GLOBAL_PORT_BASE.store(port.base, Ordering::Release);
+    Ok(())
+}
+
+pub fn global_port() -> SerialPort {
+    SerialPort::new(GLOBAL_PORT_BASE.load(Ordering::Acquire))
+}
+
+// 割り込みで受信したCOM1のデータ
+static RX_BUFFER: Mutex<RingBuffer<u8, 256>> = Mutex::new(RingBuffer::new());
+static RX_IRQ_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn on_com1_irq() {
+    let com1 = SerialPort::new_for_com1();
+    drain_rx_fifo(&com1, &LINE_ERRORS, &mut RX_BUFFER.lock());
+    if TX_BUFFERED.load(Ordering::Acquire) {
+        let mut tx = TX_BUFFER.lock();
+        if com1.read_reg(REG_LSR) & LSR_TX_EMPTY != 0 {
+            drain_tx_fifo(&com1, &mut tx, com1.fifo_depth());
+        }
+        // 送るものがなくなったら送信割り込みを止める（止めないと割り込みが来続ける）
+        if tx.is_empty() {
+            com1.write_reg(REG_IER, com1.read_reg(REG_IER) & !IER_TX_EMPTY);
+        }
+    }
+}
+
+// 受信バッファが溢れて捨てたバイト数
+pub fn rx_dropped() -> usize {
+    let _guard = InterruptGuard::new();
+    RX_BUFFER.lock().dropped()
+}
+
+// 送信バッファが一杯のときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxOverflowPolicy {
+    // 入りきらない分を捨てる（呼び出し元を待たせない）
+    Drop,
+    // 空きができるまで同期的に送信する（何も失わない）
+    Block,
+}
+
+const TX_BUFFER_SIZE: usize = 16 * 1024;
+
+// COM1の送信バッファ
+// バッファに積んだデータは、送信割り込み（割り込みが使えるようになるまではflush）で送る
+static TX_BUFFER: Mutex<RingBuffer<u8, TX_BUFFER_SIZE>> = Mutex::new(RingBuffer::new());
+static TX_BUFFERED: AtomicBool = AtomicBool::new(false);
+static TX_POLICY_BLOCK: AtomicBool = AtomicBool::new(true);
+static TX_DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// 送信バッファが溢れて捨てたバイト数
+pub fn tx_dropped() -> usize {
+    TX_DROPPED.load(Ordering::Relaxed)
+}
+
+// 送信FIFOが空になったところで、FIFOに入るだけ（depthバイトまで）送る
+fn drain_tx_fifo<R: UartRegisters, const N: usize>(
+    regs: &R,
+    tx: &mut RingBuffer<u8, N>,
+    depth: usize,
+) {
+    for _ in 0..depth {
+        let Some(byte) = tx.pop() else {
+            return;
+        };
+        regs.write_reg(REG_DATA, byte);
+    }
+}
+
+// 送信バッファに1バイト積む
+// 溢れたときは、Dropなら捨ててfalseを返し、Blockなら古いデータを同期的に送って空きを作る
+fn push_tx<R: UartRegisters, const N: usize>(
+    regs: &R,
+    tx: &mut RingBuffer<u8, N>,
+    byte: u8,
+    policy: TxOverflowPolicy,
+    depth: usize,
+) -> bool {
+    loop {
+        if tx.push(byte).is_ok() {
+            return true;
+        }
+        match policy {
+            TxOverflowPolicy::Drop => return false,
+            TxOverflowPolicy::Block => {
+                // 送信できなくなったポートを待ち続けないように、諦めて捨てる
+                if !wait_for_lsr(regs, LSR_TX_EMPTY, TX_MAX_POLLS) {
+                    return false;
+                }
+                drain_tx_fifo(regs, tx, depth);
+            }
+        }
+    }
+}
+
+// 送信バッファが空くのを待ってから1バイト送る
+// いつまでも空かない（UARTが応答しない）ときは送らずにfalseを返す
+fn send_byte_to<R: UartRegisters>(regs: &R, byte: u8) -> bool {
+    if !wait_for_lsr(regs, LSR_TX_EMPTY, TX_MAX_POLLS) {
+        return false;
+    }
+    regs.write_reg(REG_DATA, byte);
+    true
+}
+
+// 文字列を送信する
+// Crlfモードでは\nの前に\rを補う（既に\r\nになっているところには補わない）
+// 前回の呼び出しの最後が\rだったかは覚えていないので、\rと\nが別々に渡されると\rが重なる
+// 送信がタイムアウトしたら残りは送らずにfalseを返す
+fn send_str_to<R: UartRegisters>(regs: &R, s: &str, newline_mode: NewlineMode) -> bool {
+    let mut ok = true;
+    for_each_tx_byte(s, newline_mode, |b| {
+        if ok {
+            ok = send_byte_to(regs, b);
+        }
+    });
+    ok
+}
+
+// 使えなくなったポートには送らず、送信がタイムアウトしたらdeadに記録して以降は送らない
+// （deadがNoneなら記録しない）
+// この呼び出しで使えなくなったならtrueを返す
+fn send_str_unless_dead<R: UartRegisters>(
+    regs: &R,
+    s: &str,
+    newline_mode: NewlineMode,
+    dead: Option<&AtomicBool>,
+) -> bool {
+    if dead.is_some_and(|dead| dead.load(Ordering::Acquire)) {
+        return false;
+    }
+    if send_str_to(regs, s, newline_mode) {
+        return false;
+    }
+    dead.is_some_and(|dead| !dead.swap(true, Ordering::AcqRel))
+}
+
+// 改行を変換しながら、送信するバイトを順に渡す
+// ASCII以外の文字はUTF-8のバイト列になる
+fn for_each_tx_byte(s: &str, newline_mode: NewlineMode, mut f: impl FnMut(u8)) {
+    let mut prev = None;
+    for b in s.bytes() {
+        if b == b'\n' && newline_mode == NewlineMode::Crlf && prev != Some(b'\r') {
+            f(b'\r');
+        }
+        f(b);
+        prev = Some(b);
+    }
+}
+
+// 1行読んでbufに入れ、読んだバイト数を返す（改行は含まない）
+// 入力された文字はエコーバックし、バックスペース/DELで1文字消す
+// bufに入りきらない文字は捨てる
+// 受信はtry_readで行う（割り込みを使うときはバッファから読むため）
+// \r、\n、\r\nのどれも1つの改行として扱う
+// \r\nの\nは\rで戻った後に届くことがあるので、\rで終わったことをafter_crに覚えておき、次の行の先頭で捨てる
+fn read_line_with<R: UartRegisters>(
+    regs: &R,
+    buf: &mut [u8],
+    after_cr: &AtomicBool,
+    mut try_read: impl FnMut() -> Option<u8>,
+) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(c) = try_read() else {
+            busy_loop_hint();
+            continue;
+        };
+        if after_cr.swap(false, Ordering::Relaxed) && c == b'\n' {
+            continue;
+        }
+        match c {
+            b'\r' | b'\n' => {
+                after_cr.store(c == b'\r', Ordering::Relaxed);
+                let _ = send_byte_to(regs, b'\r') && send_byte_to(regs, b'\n');
+                return len;
+            }
+            0x08 | 0x7f => {
+                if len > 0 {
+                    len -= 1;
+                    for b in b"\x08 \x08" {
+                        let _ = send_byte_to(regs, *b);
+                    }
+                }
+            }
+            0x20..=0x7e if len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                let _ = send_byte_to(regs, c);
+            }
+            _ => {}
+        }
+    }
+}
+
+// 送信時の改行の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NewlineMode {
+    // \nを\r\nに変換する（端末で行頭に戻るように）
+    #[default]
+    Crlf,
+    // 何も変換しない（バイナリを送るとき用）
+    Raw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SerialPort {
     base: u16,
+    // 標準的なアドレス以外のポートはIRQが分からないのでNone
+    irq: Option<u8>,
+    newline_mode: NewlineMode,
 }
 impl SerialPort {
     pub fn new(base: u16) -> Self {
-        Self { base }
+        let irq = standard_port_index(base).map(|i| STANDARD_PORTS[i].1);
+        Self {
+            base,
+            irq,
+            newline_mode: NewlineMode::default(),
+        }
+    }
+
+    // 改行の扱いを変えたポートを返す
+    pub fn with_newline_mode(self, newline_mode: NewlineMode) -> Self {
+        Self {
+            newline_mode,
+            ..self
+        }
+    }
+    pub fn newline_mode(&self) -> NewlineMode {
+        self.newline_mode
     }
 
     pub fn new_for_com1() -> Self {
         // ほとんどのPCで標準とされているシリアルポート1番(COM1)のI/Oアドレス: 0x3f8
-        Self::new(0x3f8)
+        Self::new(COM1_BASE)
+    }
+    pub fn new_for_com2() -> Self {
+        Self::new(COM2_BASE)
+    }
+    pub fn new_for_com3() -> Self {
+        Self::new(COM3_BASE)
+    }
+    pub fn new_for_com4() -> Self {
+        Self::new(COM4_BASE)
     }
 
-    // シリアルポートの初期化
-    pub fn init(&mut self) {
-        write_io_port_u8(self.base + 1, 0x00); // 割り込み無効化
-        write_io_port_u8(self.base + 3, 0x80);
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
 
-        // ボーレート設定: 通信速度を決めるための設定
-        const BAUD_DIVISOR: u16 = 0x0001;
-        write_io_port_u8(self.base, (BAUD_DIVISOR & 0xff) as u8);
-        write_io_port_u8(self.base + 1, (BAUD_DIVISOR >> 8) as u8);
+    // シリアルポートの初期化（115200bps, 8N1）
+    pub fn init(&mut self) -> Result<()> {
+        self.init_with_config(SerialConfig::default())
+    }
 
-        // データビット長、ストップビットなどのデータ形式決定
-        // FIFO制御レジスタを有効
-        write_io_port_u8(self.base + 3, 0x03);
-        write_io_port_u8(self.base + 2, 0xC7);
-        write_io_port_u8(self.base + 4, 0x0B);
+    // 通信速度やデータ形式を指定して初期化する
+    // 初期化後に自己診断を行い、失敗したらこのポートへの送信をやめる
+    pub fn init_with_config(&mut self, cfg: SerialConfig) -> Result<()> {
+        if let Some(i) = standard_port_index(self.base) {
+            PORT_INITIALIZED[i].store(true, Ordering::Release);
+        }
+        init_regs(self, &cfg)?;
+        let uart_type = detect_uart_type(self);
+        if let Some(i) = standard_port_index(self.base) {
+            PORT_UART_TYPE[i].store(uart_type as u8, Ordering::Release);
+        }
+        crate::info!(
+            "{self}: {uart_type} UART, {}-byte FIFO",
+            uart_type.fifo_depth()
+        );
+        if !self.self_test() {
+            return Err("UART self-test failed");
+        }
+        Ok(())
     }
 
-    // 送信バッファが空になるまで待機し、一文字送信
-    pub fn send_char(&self, c: char) {
-        // base + 5: ラインステータスレジスタ
-        // 0x20: 送信バッファ空きフラグ
-        while (read_io_port_u8(self.base + 5) & 0x20) == 0 {
-            // 送信可能になるまでCPUを休止
-            busy_loop_hint();
+    // ループバックモードとスクラッチレジスタでUARTが動くかを確かめる
+    // 結果はこのポートが使えるかどうかとして記録される
+    pub fn self_test(&mut self) -> bool {
+        let ok = self_test_regs(self);
+        if let Some(i) = standard_port_index(self.base) {
+            PORT_DEAD[i].store(!ok, Ordering::Release);
         }
-        // データレジスタ（base)に文字を書き込み送信
-        write_io_port_u8(self.base, c as u8);
+        ok
+    }
+
+    // 初期化のときに調べたチップの種類（初期化していなければUnknown）
+    pub fn uart_type(&self) -> UartType {
+        standard_port_index(self.base).map_or(UartType::Unknown, |i| {
+            UartType::from_u8(PORT_UART_TYPE[i].load(Ordering::Acquire))
+        })
+    }
+    // 一度に送受信できるバイト数（FIFOが使えない、または分からないときは1）
+    pub fn fifo_depth(&self) -> usize {
+        self.uart_type().fifo_depth()
+    }
+
+    // 自己診断に失敗していなければtrue
+    pub fn is_available(&self) -> bool {
+        standard_port_index(self.base).is_none_or(|i| !PORT_DEAD[i].load(Ordering::Acquire))
     }
 
-    // 文字列をcharに分解して1文字ずつ送信
+    // 受信割り込みを有効にして初期化する
+    // 以降、受信したデータは割り込みハンドラがバッファにためるので、try_read_charはバッファから読む
+    pub fn init_with_rx_interrupt(&mut self) -> Result<()> {
+        if self.base != COM1_BASE {
+            return Err("Interrupt-driven receive is only supported on COM1");
+        }
+        let irq = self.irq.ok_or("Unknown IRQ for this port")?;
+        self.init()?;
+        register_irq_handler(irq, on_com1_irq);
+        RX_IRQ_ENABLED.store(true, Ordering::Release);
+        self.write_reg(REG_IER, IER_RX_AVAILABLE);
+        enable_irq(irq);
+        Ok(())
+    }
+
+    fn rx_irq_enabled(&self) -> bool {
+        self.base == COM1_BASE && RX_IRQ_ENABLED.load(Ordering::Acquire)
+    }
+
+    // 一文字送信（ASCII以外の文字はUTF-8で送る）
+    // ポートが使えない場合は何もしない
+    pub fn send_char(&self, c: char) {
+        let mut buf = [0u8; 4];
+        self.send_str(c.encode_utf8(&mut buf));
+    }
+
+    // 文字列をUTF-8のバイト列として送信
     pub fn send_str(&self, s: &str) {
-        for c in s.chars() {
-            self.send_char(c);
+        if !self.is_available() {
+            return;
+        }
+        if self.tx_buffered() {
+            self.send_str_buffered(s);
+            return;
+        }
+        if send_str_unless_dead(self, s, self.newline_mode, self.dead_flag()) {
+            self.report_dead();
         }
     }
+
+    // ロックも送信バッファも使わずに、ポートに直接書き込む（panicの報告用）
+    // 送信がタイムアウトしても、警告を出力しようとしてprint!に戻らないように何もしない
+    pub fn emergency_send_str(&self, s: &str) {
+        if self.is_available() {
+            send_str_to(self, s, self.newline_mode);
+        }
+    }
+
+    // 送信がタイムアウトしたポートを使えないものとして記録する
+    // 以降このポートへの出力は捨てられ、print!の出力はklogなど他のシンクにだけ残る
+    fn mark_dead(&self) {
+        if self
+            .dead_flag()
+            .is_some_and(|dead| !dead.swap(true, Ordering::AcqRel))
+        {
+            self.report_dead();
+        }
+    }
+
+    // 使えないかどうかの記録（標準のポートでなければNone）
+    fn dead_flag(&self) -> Option<&'static AtomicBool> {
+        standard_port_index(self.base).map(|i| &PORT_DEAD[i])
+    }
+
+    fn report_dead(&self) {
+        crate::warn!("{self}: transmit timed out, disabling the port");
+    }
+
+    // まだ初期化されていなければ初期化する（print!が最初に呼ばれたときに使う）
+    // ファームウェアが残した設定のままだと送信が終わらないことがあるので、一度だけ初期化する
+    pub fn init_once(&mut self) {
+        let Some(i) = standard_port_index(self.base) else {
+            return;
+        };
+        if !PORT_INITIALIZED[i].swap(true, Ordering::AcqRel) {
+            let _ = self.init();
+        }
+    }
+
+    fn tx_buffered(&self) -> bool {
+        self.base == COM1_BASE && TX_BUFFERED.load(Ordering::Acquire)
+    }
+
+    fn send_str_buffered(&self, s: &str) {
+        let policy = if TX_POLICY_BLOCK.load(Ordering::Relaxed) {
+            TxOverflowPolicy::Block
+        } else {
+            TxOverflowPolicy::Drop
+        };
+        {
+            // 割り込みハンドラと同じロックを取るので、その間は割り込みを禁止する
+            let _guard = InterruptGuard::new();
+            let mut tx = TX_BUFFER.lock();
+            let depth = self.fifo_depth();
+            let mut dropped = 0;
+            for_each_tx_byte(s, self.newline_mode, |b| {
+                if !push_tx(self, &mut tx, b, policy, depth) {
+                    dropped += 1;
+                }
+            });
+            if dropped > 0 {
+                TX_DROPPED.fetch_add(dropped, Ordering::Relaxed);
+            }
+            // 割り込みが使えるなら、送信割り込みで送ってもらう
+            if self.rx_irq_enabled() && !tx.is_empty() {
+                self.write_reg(REG_IER, self.read_reg(REG_IER) | IER_TX_EMPTY);
+            }
+        }
+    }
+
+    // 送信をバッファ経由にする（COM1のみ）
+    // 割り込みが使えるようになるまでは、flushを呼ぶまで送信されない
+    pub fn enable_buffered_tx(&self, policy: TxOverflowPolicy) -> Result<()> {
+        if self.base != COM1_BASE {
+            return Err("Buffered transmit is only supported on COM1");
+        }
+        TX_POLICY_BLOCK.store(policy == TxOverflowPolicy::Block, Ordering::Relaxed);
+        TX_BUFFERED.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    // バッファに残っているデータを送ってから、同期的な送信に戻す
+    pub fn disable_buffered_tx(&self) {
+        if !self.tx_buffered() {
+            return;
+        }
+        let _guard = InterruptGuard::new();
+        self.flush();
+        TX_BUFFERED.store(false, Ordering::Release);
+        self.write_reg(REG_IER, self.read_reg(REG_IER) & !IER_TX_EMPTY);
+    }
+
+    // 送信バッファが空になるまで同期的に送信する
+    pub fn flush(&self) {
+        if !self.tx_buffered() || !self.is_available() {
+            return;
+        }
+        let _guard = InterruptGuard::new();
+        let mut tx = TX_BUFFER.lock();
+        let depth = self.fifo_depth();
+        while !tx.is_empty() {
+            if !wait_for_lsr(self, LSR_TX_EMPTY, TX_MAX_POLLS) {
+                drop(tx);
+                self.mark_dead();
+                return;
+            }
+            drain_tx_fifo(self, &mut tx, depth);
+        }
+    }
+
+    // ループバックモード（送信したデータをそのまま受信する）を切り替える
+    pub fn set_loopback(&self, enabled: bool) {
+        let mcr = self.read_reg(REG_MCR);
+        if enabled {
+            self.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+        } else {
+            self.write_reg(REG_MCR, mcr & !MCR_LOOPBACK);
+        }
+    }
+
+    // 受信データがあれば1バイト読む（なければすぐにNone）
+    pub fn try_read_char(&self) -> Option<u8> {
+        if !self.is_available() {
+            return None;
+        }
+        if self.rx_irq_enabled() {
+            // 割り込みハンドラと同じロックを取るので、その間は割り込みを禁止する
+            let _guard = InterruptGuard::new();
+            return RX_BUFFER.lock().pop();
+        }
+        try_read_from(self, &LINE_ERRORS)
+    }
+
+    // 1バイト受信するまで待つ
+    pub fn read_char(&self) -> u8 {
+        loop {
+            if let Some(c) = self.try_read_char() {
+                return c;
+            }
+            busy_loop_hint();
+        }
+    }
+
+    // 1行読んでbufに入れ、読んだバイト数を返す
+    pub fn read_line(&self, buf: &mut [u8]) -> usize {
+        // 標準のポートでなければ、呼び出しをまたいだ\r\nは覚えておけない
+        let local = AtomicBool::new(false);
+        let after_cr = standard_port_index(self.base).map_or(&local, |i| &RX_AFTER_CR[i]);
+        // 入力を待っている間は止まっているわけではないので、ウォッチドッグに知らせる
+        read_line_with(self, buf, after_cr, || {
+            watchdog::pet();
+            self.try_read_char()
+        })
+    }
+}
+
+impl UartRegisters for SerialPort {
+    fn read_reg(&self, offset: u16) -> u8 {
+        read_io_port_u8(self.base + offset)
+    }
+    fn write_reg(&self, offset: u16, value: u8) {
+        write_io_port_u8(self.base + offset, value)
+    }
 }
 
 // Writeトレイト実装: write!/writeln!マクロを使えるようにする
 impl fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let serial = Self::default();
-        serial.send_str(s);
+        self.send_str(s);
         Ok(())
     }
 }
 
-// Defaultトレイト実装: SerialPort::default()でCOM1インスタンスを作成可能にする
+impl fmt::Display for SerialPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (standard_port_index(self.base), self.irq) {
+            (Some(i), Some(irq)) => write!(f, "COM{} ({:#x}, IRQ{irq})", i + 1, self.base),
+            _ => write!(f, "{:#x}", self.base),
+        }
+    }
+}
+
+// Defaultトレイト実装: SerialPort::default()でprint!などの出力先（通常はCOM1）のインスタンスを作成可能にする
 impl Default for SerialPort {
     fn default() -> Self {
-        Self::new_for_com1()
+        global_port()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use crate::timer::sleep_ms;
+    use crate::tsc::busy_wait_us;
+    use alloc::collections::VecDeque;
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+    use core::cell::RefCell;
+
+    // 受信データを(エラービット, データ)の列として持ち、レジスタへの書き込みを記録するモック
+    #[derive(Default)]
+    struct MockUart {
+        rx: RefCell<VecDeque<(u8, u8)>>,
+        writes: RefCell<Vec<(u16, u8)>>,
+    }
+    impl MockUart {
+        fn with_input(input: &[u8]) -> Self {
+            let mock = Self::default();
+            mock.rx.borrow_mut().extend(input.iter().map(|c| (0, *c)));
+            mock
+        }
+    }
+    impl UartRegisters for MockUart {
+        fn read_reg(&self, offset: u16) -> u8 {
+            match offset {
+                REG_DATA => self.rx.borrow_mut().pop_front().map_or(0, |(_, c)| c),
+                REG_LSR => {
+                    LSR_TX_EMPTY
+                        | self
+                            .rx
+                            .borrow()
+                            .front()
+                            .map_or(0, |(e, _)| LSR_DATA_READY | e)
+                }
+                _ => 0,
+            }
+        }
+        fn write_reg(&self, offset: u16, value: u8) {
+            self.writes.borrow_mut().push((offset, value));
+        }
+    }
+    impl MockUart {
+        // データレジスタに書き込まれた（送信された）バイト列
+        fn tx(&self) -> Vec<u8> {
+            self.writes
+                .borrow()
+                .iter()
+                .filter(|(offset, _)| *offset == REG_DATA)
+                .map(|(_, v)| *v)
+                .collect()
+        }
+    }
+
+    #[test_case]
+    fn read_returns_none_without_data_ready() {
+        let mock = MockUart::default();
+        let errors = LineErrors::new();
+        assert_eq!(try_read_from(&mock, &errors), None);
+    }
+    #[test_case]
+    fn line_errors_are_counted() {
+        let mock = MockUart::default();
+        mock.rx.borrow_mut().extend([
+            (LSR_PARITY_ERROR, b'x'),
+            (LSR_FRAMING_ERROR, b'y'),
+            (LSR_OVERRUN_ERROR, b'a'),
+            (0, b'b'),
+        ]);
+        let errors = LineErrors::new();
+        // 壊れたデータは捨てられる
+        assert_eq!(try_read_from(&mock, &errors), None);
+        assert_eq!(try_read_from(&mock, &errors), None);
+        // オーバーランでも今のデータは正しい
+        assert_eq!(try_read_from(&mock, &errors), Some(b'a'));
+        assert_eq!(try_read_from(&mock, &errors), Some(b'b'));
+        assert_eq!(errors.parity(), 1);
+        assert_eq!(errors.framing(), 1);
+        assert_eq!(errors.overrun(), 1);
+    }
+    #[test_case]
+    fn read_line_echoes_and_handles_backspace() {
+        let mock = MockUart::with_input(b"abx\x7fc\r");
+        let errors = LineErrors::new();
+        let mut buf = [0u8; 16];
+        let len = read_line_with(&mock, &mut buf, &AtomicBool::new(false), || {
+            try_read_from(&mock, &errors)
+        });
+        assert_eq!(&buf[..len], b"abc");
+        assert_eq!(mock.tx(), b"abx\x08 \x08c\r\n");
+    }
+    #[test_case]
+    fn init_default_config_writes_8n1_115200() {
+        let mock = MockUart::default();
+        init_regs(&mock, &SerialConfig::default()).expect("init failed");
+        assert_eq!(
+            *mock.writes.borrow(),
+            [
+                (REG_IER, 0x00),
+                (REG_LCR, 0x80),
+                (REG_DLL, 0x01),
+                (REG_DLM, 0x00),
+                (REG_LCR, 0x03),
+                (REG_FCR, 0xC7),
+                (REG_MCR, 0x0B),
+            ]
+        );
+    }
+    #[test_case]
+    fn init_9600_7e1() {
+        let mock = MockUart::default();
+        let cfg = SerialConfig {
+            baud: 9600,
+            data_bits: 7,
+            stop_bits: 1,
+            parity: Parity::Even,
+        };
+        init_regs(&mock, &cfg).expect("init failed");
+        let writes = mock.writes.borrow();
+        // 115200 / 9600 = 12
+        assert_eq!(writes[2..5], [(REG_DLL, 12), (REG_DLM, 0), (REG_LCR, 0x1a)]);
+    }
+    #[test_case]
+    fn init_38400_8o2() {
+        let mock = MockUart::default();
+        let cfg = SerialConfig {
+            baud: 38400,
+            data_bits: 8,
+            stop_bits: 2,
+            parity: Parity::Odd,
+        };
+        init_regs(&mock, &cfg).expect("init failed");
+        let writes = mock.writes.borrow();
+        assert_eq!(writes[2..5], [(REG_DLL, 3), (REG_DLM, 0), (REG_LCR, 0x0f)]);
+    }
+    #[test_case]
+    fn init_rejects_invalid_configs() {
+        let mock = MockUart::default();
+        for cfg in [
+            // 115200 / 7000 = 16.46 なので、除数が16でも17でも2%以上ずれる
+            SerialConfig {
+                baud: 7000,
+                ..Default::default()
+            },
+            SerialConfig {
+                baud: 230400,
+                ..Default::default()
+            },
+            SerialConfig {
+                data_bits: 9,
+                ..Default::default()
+            },
+            SerialConfig {
+                stop_bits: 3,
+                ..Default::default()
+            },
+        ] {
+            assert!(init_regs(&mock, &cfg).is_err());
+        }
+        // 不正な設定ではレジスタに何も書き込まない
+        assert!(mock.writes.borrow().is_empty());
+    }
+    // 自己診断用のモック
+    struct SelfTestMock {
+        scratch_works: bool,
+        loopback_works: bool,
+        scratch: Cell<u8>,
+        mcr: Cell<u8>,
+        rx: Cell<Option<u8>>,
+    }
+    impl SelfTestMock {
+        fn new(scratch_works: bool, loopback_works: bool) -> Self {
+            Self {
+                scratch_works,
+                loopback_works,
+                scratch: Cell::new(0),
+                mcr: Cell::new(0x0B),
+                rx: Cell::new(None),
+            }
+        }
+    }
+    impl UartRegisters for SelfTestMock {
+        fn read_reg(&self, offset: u16) -> u8 {
+            match offset {
+                REG_DATA => self.rx.take().unwrap_or(0),
+                REG_MCR => self.mcr.get(),
+                REG_LSR => {
+                    LSR_TX_EMPTY
+                        | if self.rx.get().is_some() {
+                            LSR_DATA_READY
+                        } else {
+                            0
+                        }
+                }
+                REG_SCRATCH => self.scratch.get(),
+                _ => 0,
+            }
+        }
+        fn write_reg(&self, offset: u16, value: u8) {
+            match offset {
+                REG_DATA if self.loopback_works && self.mcr.get() & MCR_LOOPBACK != 0 => {
+                    self.rx.set(Some(value))
+                }
+                REG_MCR => self.mcr.set(value),
+                REG_SCRATCH if self.scratch_works => self.scratch.set(value),
+                _ => {}
+            }
+        }
+    }
+    // FCRへの書き込みに対して、チップの種類ごとのIIRを返すモック
+    struct FifoProbeMock {
+        scratch_works: bool,
+        // FIFOを有効にしたときにIIRの上位ビットに現れる値
+        iir_fifo_bits: u8,
+        // 64バイトFIFOを持つか（16750）
+        has_64_byte_fifo: bool,
+        scratch: Cell<u8>,
+        lcr: Cell<u8>,
+        fcr: Cell<u8>,
+        fcr_writes: RefCell<Vec<u8>>,
+    }
+    impl FifoProbeMock {
+        fn new(scratch_works: bool, iir_fifo_bits: u8, has_64_byte_fifo: bool) -> Self {
+            Self {
+                scratch_works,
+                iir_fifo_bits,
+                has_64_byte_fifo,
+                scratch: Cell::new(0),
+                lcr: Cell::new(0x03),
+                fcr: Cell::new(0),
+                fcr_writes: RefCell::new(Vec::new()),
+            }
+        }
+    }
+    impl UartRegisters for FifoProbeMock {
+        fn read_reg(&self, offset: u16) -> u8 {
+            match offset {
+                REG_IIR => {
+                    // 割り込みが来ていないことを示すビット0は常に立っている
+                    let fcr = self.fcr.get();
+                    let mut iir = 0x01;
+                    if fcr & 1 != 0 {
+                        iir |= self.iir_fifo_bits;
+                        if fcr & FCR_64_BYTE_FIFO != 0 {
+                            iir |= IIR_FIFO_64;
+                        }
+                    }
+                    iir
+                }
+                REG_LCR => self.lcr.get(),
+                REG_SCRATCH => self.scratch.get(),
+                _ => 0,
+            }
+        }
+        fn write_reg(&self, offset: u16, value: u8) {
+            match offset {
+                REG_FCR => {
+                    self.fcr_writes.borrow_mut().push(value);
+                    // 64バイトモードのビットはDLAB=1のときだけ書ける
+                    let mut value = value;
+                    if !self.has_64_byte_fifo || self.lcr.get() & LCR_DLAB == 0 {
+                        value &= !FCR_64_BYTE_FIFO;
+                    }
+                    self.fcr.set(value);
+                }
+                REG_LCR => self.lcr.set(value),
+                REG_SCRATCH if self.scratch_works => self.scratch.set(value),
+                _ => {}
+            }
+        }
+    }
+
+    #[test_case]
+    fn detects_8250_without_scratch_register() {
+        let mock = FifoProbeMock::new(false, 0, false);
+        assert_eq!(detect_uart_type(&mock), UartType::Uart8250);
+        assert_eq!(mock.fcr_writes.borrow().last(), Some(&0));
+    }
+    #[test_case]
+    fn detects_16450_without_fifo() {
+        let mock = FifoProbeMock::new(true, 0, false);
+        assert_eq!(detect_uart_type(&mock), UartType::Uart16450);
+        assert_eq!(mock.fcr_writes.borrow().last(), Some(&0));
+        assert_eq!(UartType::Uart16450.fifo_depth(), 1);
+    }
+    #[test_case]
+    fn detects_16550_with_broken_fifo() {
+        let mock = FifoProbeMock::new(true, IIR_FIFO_BROKEN, false);
+        assert_eq!(detect_uart_type(&mock), UartType::Uart16550);
+        // 壊れたFIFOは使わない
+        assert_eq!(mock.fcr_writes.borrow().last(), Some(&0));
+        assert_eq!(UartType::Uart16550.fifo_depth(), 1);
+    }
+    #[test_case]
+    fn detects_16550a_with_working_fifo() {
+        let mock = FifoProbeMock::new(true, IIR_FIFO_WORKING, false);
+        assert_eq!(detect_uart_type(&mock), UartType::Uart16550A);
+        assert_eq!(mock.fcr_writes.borrow().last(), Some(&FCR_ENABLE));
+        // 調べ終わったらLCRは元に戻っている
+        assert_eq!(mock.lcr.get(), 0x03);
+        assert_eq!(UartType::Uart16550A.fifo_depth(), 16);
+    }
+    #[test_case]
+    fn detects_16750_with_64_byte_fifo() {
+        let mock = FifoProbeMock::new(true, IIR_FIFO_WORKING, true);
+        assert_eq!(detect_uart_type(&mock), UartType::Uart16750);
+        assert_eq!(mock.fcr_writes.borrow().last(), Some(&FCR_ENABLE_64));
+        assert_eq!(mock.lcr.get(), 0x03);
+        assert_eq!(UartType::Uart16750.fifo_depth(), 64);
+    }
+    #[test_case]
+    fn detect_reports_unknown_on_absent_uart() {
+        assert_eq!(detect_uart_type(&AbsentUart), UartType::Unknown);
+        assert_eq!(UartType::Unknown.fifo_depth(), 1);
+    }
+    #[test_case]
+    fn com1_has_a_known_uart_type() {
+        // QEMUのシリアルポートは16550A
+        let com1 = SerialPort::new_for_com1();
+        assert_eq!(com1.uart_type(), UartType::Uart16550A);
+        assert_eq!(com1.fifo_depth(), 16);
+    }
+
+    // UARTがないアドレスは全て0xffが読める
+    struct AbsentUart;
+    impl UartRegisters for AbsentUart {
+        fn read_reg(&self, _offset: u16) -> u8 {
+            0xff
+        }
+        fn write_reg(&self, _offset: u16, _value: u8) {}
+    }
+
+    // 送信バッファがいつまでも空かないUART
+    struct StuckUart {
+        data_writes: Cell<usize>,
+    }
+    impl UartRegisters for StuckUart {
+        fn read_reg(&self, _offset: u16) -> u8 {
+            0
+        }
+        fn write_reg(&self, offset: u16, _value: u8) {
+            if offset == REG_DATA {
+                self.data_writes.set(self.data_writes.get() + 1);
+            }
+        }
+    }
+
+    #[test_case]
+    fn send_gives_up_when_tx_never_empties() {
+        let stuck = StuckUart {
+            data_writes: Cell::new(0),
+        };
+        assert!(!send_str_to(&stuck, "hello\n", NewlineMode::Crlf));
+        assert_eq!(stuck.data_writes.get(), 0);
+        let mut tx = RingBuffer::<u8, 1>::new();
+        tx.push(b'a').unwrap();
+        assert!(!push_tx(&stuck, &mut tx, b'b', TxOverflowPolicy::Block, 16));
+    }
+    // 送信バッファがいつまでも空かない偽のポートを、print!の出力先として使う
+    // （COM1を使えなくすると、その後のテストの出力が失われる）
+    struct DeadPortSink {
+        dead: AtomicBool,
+        lsr_polls: AtomicUsize,
+    }
+    impl UartRegisters for DeadPortSink {
+        fn read_reg(&self, offset: u16) -> u8 {
+            if offset == REG_LSR {
+                self.lsr_polls.fetch_add(1, Ordering::Relaxed);
+            }
+            0
+        }
+        fn write_reg(&self, _offset: u16, _value: u8) {}
+    }
+    impl fmt::Write for DeadPortSink {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            send_str_unless_dead(&*self, s, NewlineMode::Crlf, Some(&self.dead));
+            Ok(())
+        }
+    }
+    static DEAD_PORT_SINK: Mutex<DeadPortSink> = Mutex::new(DeadPortSink {
+        dead: AtomicBool::new(false),
+        lsr_polls: AtomicUsize::new(0),
+    });
+    #[test_case]
+    fn output_reaches_klog_while_port_is_dead() {
+        crate::print::register_sink(&DEAD_PORT_SINK).unwrap();
+        // 最初の出力でタイムアウトして、ポートは使えないものとして記録される
+        crate::println!("\nbefore dead");
+        let polls = {
+            let sink = DEAD_PORT_SINK.lock();
+            assert!(sink.dead.load(Ordering::Acquire));
+            sink.lsr_polls.load(Ordering::Relaxed)
+        };
+        // 使えないポートへの出力は待たずに捨てられ、klogには残る
+        crate::println!("\nwhile dead");
+        crate::print::unregister_sink(&DEAD_PORT_SINK);
+        assert_eq!(
+            DEAD_PORT_SINK.lock().lsr_polls.load(Ordering::Relaxed),
+            polls
+        );
+        let mut out = alloc::string::String::new();
+        crate::klog::snapshot_tail(&mut out, 12).unwrap();
+        assert_eq!(out, "while dead\n");
+    }
+    #[test_case]
+    fn self_test_passes_on_working_uart() {
+        let mock = SelfTestMock::new(true, true);
+        assert!(self_test_regs(&mock));
+        assert_eq!(mock.mcr.get(), 0x0B);
+    }
+    #[test_case]
+    fn self_test_times_out_without_loopback() {
+        let mock = SelfTestMock::new(true, false);
+        assert!(!self_test_regs(&mock));
+        // 失敗してもループバックモードは解除される
+        assert_eq!(mock.mcr.get(), 0x0B);
+    }
+    #[test_case]
+    fn self_test_fails_on_broken_scratch() {
+        let mock = SelfTestMock::new(false, true);
+        assert!(!self_test_regs(&mock));
+    }
+    #[test_case]
+    fn self_test_fails_on_absent_uart() {
+        assert!(!self_test_regs(&AbsentUart));
+    }
+    #[test_case]
+    fn com1_passes_self_test() {
+        assert!(SerialPort::new_for_com1().is_available());
+    }
+    #[test_case]
+    fn crlf_mode_translates_bare_lf() {
+        let mock = MockUart::default();
+        send_str_to(&mock, "a\nb\r\nc\rd\n\n", NewlineMode::Crlf);
+        assert_eq!(mock.tx(), b"a\r\nb\r\nc\rd\r\n\r\n");
+    }
+    #[test_case]
+    fn raw_mode_sends_bytes_as_is() {
+        let mock = MockUart::default();
+        send_str_to(&mock, "a\nb\r\nc\rd\n\n", NewlineMode::Raw);
+        assert_eq!(mock.tx(), b"a\nb\r\nc\rd\n\n");
+    }
+    #[test_case]
+    fn ports_default_to_crlf() {
+        assert_eq!(SerialPort::new_for_com1().newline_mode(), NewlineMode::Crlf);
+        let raw = SerialPort::new_for_com1().with_newline_mode(NewlineMode::Raw);
+        assert_eq!(raw.newline_mode(), NewlineMode::Raw);
+    }
+    #[test_case]
+    fn tx_overflow_drop_keeps_oldest_in_order() {
+        // 送信FIFOが空にならないモック
+        struct BusyUart;
+        impl UartRegisters for BusyUart {
+            fn read_reg(&self, _offset: u16) -> u8 {
+                0
+            }
+            fn write_reg(&self, _offset: u16, _value: u8) {
+                panic!("Nothing should be sent");
+            }
+        }
+        let mut tx = RingBuffer::<u8, 8>::new();
+        let pushed: Vec<bool> = (0..12)
+            .map(|i| push_tx(&BusyUart, &mut tx, i, TxOverflowPolicy::Drop, 16))
+            .collect();
+        assert_eq!(pushed.iter().filter(|ok| !**ok).count(), 4);
+        assert!(tx.iter().eq(0..8));
+    }
+    #[test_case]
+    fn tx_overflow_block_sends_everything_in_order() {
+        let mock = MockUart::default();
+        let mut tx = RingBuffer::<u8, 8>::new();
+        for i in 0..40 {
+            assert!(push_tx(&mock, &mut tx, i, TxOverflowPolicy::Block, 16));
+        }
+        while !tx.is_empty() {
+            drain_tx_fifo(&mock, &mut tx, 16);
+        }
+        assert!(mock.tx().into_iter().eq(0..40));
+    }
+    #[test_case]
+    fn drain_tx_fifo_sends_one_byte_without_fifo() {
+        let mock = MockUart::default();
+        let mut tx = RingBuffer::<u8, 8>::new();
+        for i in 0..4 {
+            tx.push(i).unwrap();
+        }
+        drain_tx_fifo(&mock, &mut tx, 1);
+        assert_eq!(mock.tx(), [0]);
+        drain_tx_fifo(&mock, &mut tx, 16);
+        assert_eq!(mock.tx(), [0, 1, 2, 3]);
+    }
+    // バッファに積んだだけで戻り、残りは送信割り込みが送るにつれて減っていく
+    // （かかった時間はQEMUを動かしているマシンの負荷で変わるので、順序だけを確かめる）
+    #[test_case]
+    fn buffered_tx_burst_returns_before_it_is_sent() {
+        let com1 = SerialPort::new_for_com1();
+        assert!(com1.rx_irq_enabled());
+        // ループバックモードにして、試験用のデータを画面（ログ）に出さないようにする
+        let mcr = com1.read_reg(REG_MCR);
+        com1.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+        com1.enable_buffered_tx(TxOverflowPolicy::Block)
+            .expect("enable_buffered_tx failed");
+        let pending = || {
+            let _guard = InterruptGuard::new();
+            TX_BUFFER.lock().len()
+        };
+        let line = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcde\n";
+        for _ in 0..(10 * 1024 / line.len()) {
+            com1.send_str(line);
+        }
+        let mut prev = pending();
+        assert!(prev > 0);
+        for _ in 0..10_000 {
+            if prev == 0 {
+                break;
+            }
+            sleep_ms(1);
+            let now = pending();
+            assert!(now <= prev, "pending grew from {prev} to {now}");
+            prev = now;
+        }
+        com1.disable_buffered_tx();
+        com1.write_reg(REG_MCR, mcr);
+        while com1.try_read_char().is_some() {}
+        assert_eq!(prev, 0);
+        assert_eq!(tx_dropped(), 0);
+    }
+    #[test_case]
+    fn non_ascii_chars_are_sent_as_utf8() {
+        let mock = MockUart::default();
+        send_str_to(&mock, "a日\n", NewlineMode::Crlf);
+        assert_eq!(mock.tx(), b"a\xe6\x97\xa5\r\n");
+    }
+    #[test_case]
+    fn write_goes_to_the_receivers_port() {
+        use core::fmt::Write;
+        let mut com1 = SerialPort::new_for_com1();
+        // QEMUの既定ではCOM4はないので、何も送られない
+        let mut com4 = SerialPort::new_for_com4();
+        while com1.try_read_char().is_some() {}
+        let mcr = com1.read_reg(REG_MCR);
+        com1.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+        write!(com4, "com4").unwrap();
+        write!(com1, "日").unwrap();
+        sleep_ms(5);
+        com1.write_reg(REG_MCR, mcr);
+        let mut received = Vec::new();
+        while let Some(c) = com1.try_read_char() {
+            received.push(c);
+        }
+        assert_eq!(received, "日".as_bytes());
+    }
+    #[test_case]
+    fn detect_ports_finds_com1_only() {
+        let ports = detect_ports();
+        assert!(ports.contains(&SerialPort::new_for_com1()));
+        assert!(!ports.contains(&SerialPort::new_for_com4()));
+        assert!(!SerialPort::new_for_com4().is_available());
+    }
+    #[test_case]
+    fn standard_ports_have_irqs() {
+        assert_eq!(SerialPort::new_for_com1().irq(), Some(4));
+        assert_eq!(SerialPort::new_for_com2().irq(), Some(3));
+        assert_eq!(SerialPort::new_for_com3().irq(), Some(4));
+        assert_eq!(SerialPort::new_for_com4().irq(), Some(3));
+        assert_eq!(SerialPort::new(0x1234).irq(), None);
+    }
+    #[test_case]
+    fn set_global_port_rejects_missing_ports() {
+        let com1 = SerialPort::new_for_com1();
+        while com1.try_read_char().is_some() {}
+        // COM1をループバックモードにして、COM1に出力されたものを受信バッファで受け取る
+        let mcr = com1.read_reg(REG_MCR);
+        com1.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+        // QEMUにはCOM4がないので、切り替えは失敗して出力先はCOM1のままになる
+        assert!(set_global_port(SerialPort::new_for_com4()).is_err());
+        crate::print!("to ");
+        set_global_port(com1).expect("set_global_port failed");
+        crate::print!("com1");
+        sleep_ms(5);
+        com1.write_reg(REG_MCR, mcr);
+        let mut received = Vec::new();
+        while let Some(c) = com1.try_read_char() {
+            received.push(c);
+        }
+        assert_eq!(received, b"to com1");
+    }
+    #[test_case]
+    fn drain_rx_fifo_reads_until_empty() {
+        let mock = MockUart::with_input(b"0123456789abcdef");
+        mock.rx.borrow_mut().insert(3, (LSR_PARITY_ERROR, b'x'));
+        let errors = LineErrors::new();
+        let mut rx = RingBuffer::<u8, 8>::new();
+        drain_rx_fifo(&mock, &errors, &mut rx);
+        assert!(mock.rx.borrow().is_empty());
+        // 溢れた分は古い方から捨てられる
+        assert!(rx.iter().eq(b"89abcdef".iter().copied()));
+        assert_eq!(rx.dropped(), 8);
+        assert_eq!(errors.parity(), 1);
+        // FIFOの大きさより多くたまっていても全部読む
+        let mock = MockUart::with_input(&[b'a'; 100]);
+        let mut rx = RingBuffer::<u8, 128>::new();
+        drain_rx_fifo(&mock, &errors, &mut rx);
+        assert!(mock.rx.borrow().is_empty());
+        assert_eq!(rx.len(), 100);
+    }
+    // DATA_READYがいつまでも下りないポートでも、読むのをやめて戻ることを確認する
+    #[test_case]
+    fn drain_rx_fifo_stops_on_stuck_data_ready() {
+        struct AlwaysReady {
+            reads: Cell<usize>,
+        }
+        impl UartRegisters for AlwaysReady {
+            fn read_reg(&self, offset: u16) -> u8 {
+                if offset == REG_DATA {
+                    self.reads.set(self.reads.get() + 1);
+                }
+                LSR_DATA_READY
+            }
+            fn write_reg(&self, _offset: u16, _value: u8) {}
+        }
+        let uart = AlwaysReady {
+            reads: Cell::new(0),
+        };
+        let mut rx = RingBuffer::<u8, 8>::new();
+        drain_rx_fifo(&uart, &LineErrors::new(), &mut rx);
+        assert_eq!(uart.reads.get(), RX_DRAIN_MAX_READS);
+    }
+    #[test_case]
+    fn rx_interrupt_buffers_bytes_while_busy() {
+        let port = SerialPort::new_for_com1();
+        assert!(port.rx_irq_enabled());
+        while port.try_read_char().is_some() {}
+        // ループバックモードにして、送ったデータをそのまま受信させる
+        let mcr = port.read_reg(REG_MCR);
+        port.write_reg(REG_MCR, mcr | MCR_LOOPBACK);
+        for c in b"0123456789" {
+            send_byte_to(&port, *c);
+        }
+        // 受信している間、ポーリングせずに忙しく待つ（届いたバイトは割り込みハンドラがバッファに入れる）
+        busy_wait_us(20_000);
+        sleep_ms(5);
+        port.write_reg(REG_MCR, mcr);
+        let mut received = Vec::new();
+        while let Some(c) = port.try_read_char() {
+            received.push(c);
+        }
+        assert_eq!(received, b"0123456789");
+    }
+    // \r\nは1つの改行で、空の行にはならない（\nが次の呼び出しで届いても同じ）
+    #[test_case]
+    fn read_line_treats_crlf_as_one_line_ending() {
+        let mock = MockUart::with_input(b"ab\r\ncd\r\n\nef\n");
+        let errors = LineErrors::new();
+        let after_cr = AtomicBool::new(false);
+        let read = || {
+            let mut buf = [0u8; 16];
+            let len = read_line_with(&mock, &mut buf, &after_cr, || try_read_from(&mock, &errors));
+            Vec::from(&buf[..len])
+        };
+        assert_eq!(read(), b"ab");
+        assert_eq!(read(), b"cd");
+        // \r\nの後の\nは空の行
+        assert_eq!(read(), b"");
+        assert_eq!(read(), b"ef");
+        assert!(!after_cr.load(Ordering::Relaxed));
+    }
+    #[test_case]
+    fn read_line_drops_overflowing_chars() {
+        let mock = MockUart::with_input(b"abcdef\n");
+        let errors = LineErrors::new();
+        let mut buf = [0u8; 3];
+        let len = read_line_with(&mock, &mut buf, &AtomicBool::new(false), || {
+            try_read_from(&mock, &errors)
+        });
+        assert_eq!(&buf[..len], b"abc");
+        assert_eq!(mock.tx(), b"abc\r\n");
     }
 }
@@ -0,0 +1,558 @@
+extern crate alloc;
+
+use crate::allocator::alloc_stats;
+use crate::allocator::ALLOCATOR;
+use crate::cursor_blink;
+use crate::graphics::Bitmap;
+use crate::init::BootInfo;
+use crate::initrd;
+use crate::initrd::EntryKind;
+use crate::klog;
+use crate::line_editor::LineEditor;
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::kernel_pml4;
+use crate::panic::reboot;
+use crate::pci;
+use crate::power::poweroff;
+use crate::print;
+use crate::print::clear_vram_console;
+use crate::print::hexdump_range;
+use crate::print::log_level;
+use crate::print::set_log_level;
+use crate::println;
+use crate::profiler;
+use crate::ps2::try_read_key;
+use crate::qemu::DebugCon;
+use crate::result::Result;
+use crate::screenshot;
+use crate::serial::global_port;
+use crate::serial::SerialPort;
+use crate::timer::uptime_ms;
+use crate::uefi::UEFI_PAGE_SIZE;
+use crate::watchdog;
+use crate::x86::busy_loop_hint;
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const PROMPT: &str = "wasabi> ";
+const MAX_LINE_LEN: usize = 128;
+// 上下の矢印で呼び出せる、過去に入力した行の数
+const HISTORY_LEN: usize = 32;
+// 1回のhexdumpで表示する長さの上限
+const MAX_HEXDUMP_LEN: u64 = 4096;
+const PAGE_SIZE: u64 = 4096;
+// 画面を消してカーソルを左上に戻すエスケープシーケンス
+const ANSI_CLEAR: &str = "\x1b[2J\x1b[H";
+
+// コマンドが正常に終わったら、シェルを続けるかどうかを返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Exit,
+}
+
+// シェルのコマンド
+// argsにはコマンド名を含まない引数が入る
+// 引数がおかしいときはErrを返すと、エラーと使い方が表示される
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub help: &'static str,
+    pub run: fn(&BootInfo, &[&str]) -> Result<Flow>,
+}
+impl Command {
+    fn print_usage(&self) {
+        println!("usage: {} {}", self.name, self.args);
+    }
+}
+
+const BUILTIN_COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        args: "",
+        help: "show this list",
+        run: cmd_help,
+    },
+    Command {
+        name: "mem",
+        args: "",
+        help: "show heap and page allocator stats",
+        run: cmd_mem,
+    },
+    Command {
+        name: "mmap",
+        args: "",
+        help: "summarize the memory map",
+        run: cmd_mmap,
+    },
+    Command {
+        name: "hexdump",
+        args: "<addr> <len>",
+        help: "dump memory (hex arguments)",
+        run: cmd_hexdump,
+    },
+    Command {
+        name: "ls",
+        args: "",
+        help: "list files in the initrd",
+        run: cmd_ls,
+    },
+    Command {
+        name: "lspci",
+        args: "",
+        help: "list PCI devices",
+        run: cmd_lspci,
+    },
+    Command {
+        name: "dmesg",
+        args: "",
+        help: "show the kernel log",
+        run: cmd_dmesg,
+    },
+    Command {
+        name: "loglevel",
+        args: "[error | warn | info | debug | trace]",
+        help: "show or set the log level",
+        run: cmd_loglevel,
+    },
+    Command {
+        name: "uptime",
+        args: "",
+        help: "show time since the timer started",
+        run: cmd_uptime,
+    },
+    Command {
+        name: "watchdog",
+        args: "[on | off | timeout <ms> | action <record|warn|panic|reboot>]",
+        help: "show or configure the watchdog",
+        run: cmd_watchdog,
+    },
+    Command {
+        name: "profile",
+        args: "start [<interval>] | stop | report [<n>]",
+        help: "sample where the timer interrupt lands",
+        run: cmd_profile,
+    },
+    Command {
+        name: "screenshot",
+        args: "[--raw]",
+        help: "dump the screen as PPM (base64 on serial, raw on debugcon)",
+        run: cmd_screenshot,
+    },
+    Command {
+        name: "clear",
+        args: "",
+        help: "clear the console",
+        run: cmd_clear,
+    },
+    Command {
+        name: "reboot",
+        args: "",
+        help: "reset the machine",
+        run: cmd_reboot,
+    },
+    Command {
+        name: "poweroff",
+        args: "",
+        help: "power off the machine",
+        run: cmd_poweroff,
+    },
+    Command {
+        name: "exit",
+        args: "",
+        help: "leave the shell",
+        run: cmd_exit,
+    },
+];
+
+// 他のモジュールが追加したコマンド
+static EXTRA_COMMANDS: Mutex<Vec<Command>> = Mutex::new(Vec::new());
+
+// コマンドを追加する（同じ名前のコマンドがあれば失敗する）
+pub fn register_command(cmd: Command) -> Result<()> {
+    let mut extra = EXTRA_COMMANDS.lock();
+    if find_in(BUILTIN_COMMANDS, cmd.name).is_some() || find_in(&extra, cmd.name).is_some() {
+        return Err("shell: command is already registered");
+    }
+    extra.push(cmd);
+    Ok(())
+}
+
+fn find_in(commands: &[Command], name: &str) -> Option<Command> {
+    commands.iter().find(|c| c.name == name).copied()
+}
+
+fn find_command(name: &str) -> Option<Command> {
+    find_in(BUILTIN_COMMANDS, name).or_else(|| find_in(&EXTRA_COMMANDS.lock(), name))
+}
+
+// 空白で区切って単語に分ける
+fn tokenize(line: &str) -> Vec<&str> {
+    line.split_whitespace().collect()
+}
+
+// 16進数の引数を読む（0xは付いていてもいなくてもよい）
+fn parse_hex(s: &str) -> Result<u64> {
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    // from_str_radixは先頭の+を受け付けてしまうので、数字だけかを先に確かめる
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err("not a hex number");
+    }
+    u64::from_str_radix(digits, 16).map_err(|_| "hex number is too large")
+}
+
+// hexdumpの引数を読んで、範囲がおかしくないか確かめる
+fn parse_hexdump_args(args: &[&str]) -> Result<(u64, u64)> {
+    let [addr, len] = args else {
+        return Err("expected 2 arguments");
+    };
+    let addr = parse_hex(addr)?;
+    let len = parse_hex(len)?;
+    if len == 0 || len > MAX_HEXDUMP_LEN {
+        return Err("len must be between 1 and 0x1000");
+    }
+    if addr.checked_add(len).is_none() {
+        return Err("range wraps around");
+    }
+    Ok((addr, len))
+}
+
+// 読もうとしている範囲が全てマップされているか確かめる
+// 自前のページテーブルに切り替える前は、UEFIが全てマップしている
+fn check_mapped(addr: u64, len: u64) -> Result<()> {
+    let Some(table) = kernel_pml4() else {
+        return Ok(());
+    };
+    let mut page = addr & !(PAGE_SIZE - 1);
+    while page < addr + len {
+        table.translate(page).map_err(|_| "range is not mapped")?;
+        page += PAGE_SIZE;
+    }
+    Ok(())
+}
+
+// 1行を実行する（空行なら何もしない）
+fn execute(boot_info: &BootInfo, line: &str) -> Flow {
+    let tokens = tokenize(line);
+    let Some((name, args)) = tokens.split_first() else {
+        return Flow::Continue;
+    };
+    let Some(cmd) = find_command(name) else {
+        println!("unknown command: {name} (try 'help')");
+        return Flow::Continue;
+    };
+    match (cmd.run)(boot_info, args) {
+        Ok(flow) => flow,
+        Err(e) => {
+            println!("{}: {e}", cmd.name);
+            cmd.print_usage();
+            Flow::Continue
+        }
+    }
+}
+
+// シリアルかキーボードから1行ずつ読んでコマンドを実行する（exitで抜ける）
+// 行の編集とエコーバックはLineEditorで行い、エコーバックはシリアルに出す
+pub fn run(boot_info: &BootInfo) {
+    let mut serial = global_port();
+    let mut editor = LineEditor::new(HISTORY_LEN, MAX_LINE_LEN);
+    println!("Type 'help' for a list of commands");
+    loop {
+        // エコーバックはprint!を通さずに送るので、先にそれまでの出力を送りきる
+        // （行を書き直すときにプロンプトも出し直すので、プロンプトもエディタから出す）
+        serial.flush();
+        editor.begin(PROMPT, &mut serial);
+        let line = read_line(&mut editor, &mut serial);
+        if execute(boot_info, &line) == Flow::Exit {
+            return;
+        }
+    }
+}
+
+// 行が確定するまで、シリアルとキーボードの両方から入力を受け取る
+fn read_line(editor: &mut LineEditor, serial: &mut SerialPort) -> String {
+    loop {
+        // 入力を待っている間は止まっているわけではないので、ウォッチドッグに知らせる
+        watchdog::pet();
+        let line = if let Some(b) = serial.try_read_char() {
+            editor.feed_byte(b, serial)
+        } else if let Some(e) = try_read_key() {
+            editor.feed_key(&e, serial)
+        } else {
+            cursor_blink::poll();
+            busy_loop_hint();
+            continue;
+        };
+        serial.flush();
+        if let Some(line) = line {
+            return line;
+        }
+    }
+}
+
+fn no_args(args: &[&str]) -> Result<()> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err("takes no arguments")
+    }
+}
+
+fn cmd_help(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    let extra = EXTRA_COMMANDS.lock().clone();
+    for c in BUILTIN_COMMANDS.iter().chain(extra.iter()) {
+        let usage = alloc::format!("{} {}", c.name, c.args);
+        println!("  {usage:<24} {}", c.help);
+    }
+    Ok(Flow::Continue)
+}
+
+fn cmd_mem(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    let stats = alloc_stats();
+    println!(
+        "heap: {} bytes in {} live allocations",
+        stats.allocated_bytes, stats.live_allocation_count
+    );
+    println!("heap: {}", ALLOCATOR.stats());
+    println!("heap cache: {}", ALLOCATOR.cache_stats());
+    println!(
+        "pages: {} KiB free",
+        PAGE_ALLOCATOR.free_page_count() as u64 * PAGE_SIZE / 1024
+    );
+    Ok(Flow::Continue)
+}
+
+fn cmd_mmap(boot_info: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    let regions = boot_info.memory_regions();
+    // 最初に現れた順に種類ごとのページ数を表示する
+    let mut kinds = Vec::new();
+    for r in regions.iter() {
+        if !kinds.contains(&r.kind) {
+            kinds.push(r.kind);
+        }
+    }
+    for kind in kinds {
+        let pages = regions.total_pages(kind);
+        println!(
+            "  {:<28} {pages:>8} pages ({} KiB)",
+            alloc::format!("{kind}"),
+            pages * UEFI_PAGE_SIZE / 1024
+        );
+    }
+    println!("{} regions", regions.len());
+    Ok(Flow::Continue)
+}
+
+fn cmd_hexdump(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    let (addr, len) = parse_hexdump_args(args)?;
+    check_mapped(addr, len)?;
+    unsafe { hexdump_range(addr as usize, len as usize) };
+    Ok(Flow::Continue)
+}
+
+fn cmd_ls(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    for entry in initrd::entries() {
+        let entry = entry?;
+        match entry.kind() {
+            EntryKind::Directory => println!("  {:>8} {entry}", "<dir>"),
+            _ => println!("  {:>8} {entry}", entry.data().len()),
+        }
+    }
+    Ok(Flow::Continue)
+}
+
+fn cmd_lspci(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    for d in pci::scan() {
+        println!("{d}");
+    }
+    Ok(Flow::Continue)
+}
+
+fn cmd_dmesg(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    // 出力はklogにも書き込まれるので、ロックを持ったまま表示しないように一度コピーする
+    let mut log = String::new();
+    klog::snapshot(&mut log).map_err(|_| "failed to read the kernel log")?;
+    print!("{log}");
+    Ok(Flow::Continue)
+}
+
+fn cmd_loglevel(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    match args {
+        [] => {}
+        [level] => set_log_level(level.parse()?),
+        _ => return Err("invalid arguments"),
+    }
+    println!("loglevel: {}", log_level().name());
+    Ok(Flow::Continue)
+}
+
+fn cmd_uptime(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    let ms = uptime_ms();
+    println!("up {}.{:03} s", ms / 1000, ms % 1000);
+    Ok(Flow::Continue)
+}
+
+fn cmd_watchdog(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    match args {
+        [] => {}
+        ["on"] => watchdog::arm(),
+        ["off"] => watchdog::disarm(),
+        ["timeout", ms] => {
+            let ms = ms.parse().map_err(|_| "not a decimal number")?;
+            watchdog::set_timeout_ms(ms)?;
+        }
+        ["action", name] => watchdog::set_action(watchdog::Action::from_name(name)?),
+        _ => return Err("invalid arguments"),
+    }
+    println!(
+        "watchdog: {}, timeout {} ms, action {}, fired {} times",
+        if watchdog::is_armed() { "on" } else { "off" },
+        watchdog::timeout_ms(),
+        watchdog::action(),
+        watchdog::bite_count()
+    );
+    Ok(Flow::Continue)
+}
+
+fn cmd_profile(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    let parse = |s: &str| s.parse().map_err(|_| "not a decimal number");
+    match args {
+        ["start"] => profiler::start(1)?,
+        ["start", interval] => profiler::start(parse(interval)?)?,
+        ["stop"] => profiler::stop(),
+        ["report"] => profiler::report(10),
+        ["report", n] => profiler::report(parse(n)? as usize),
+        _ => return Err("invalid arguments"),
+    }
+    Ok(Flow::Continue)
+}
+
+// 画面の内容はシリアル（--rawならdebugcon）にだけ書く
+// print!を使うとklogがあふれ、VRAMコンソールが読んでいる途中の画面を書き換えてしまう
+fn cmd_screenshot(boot_info: &BootInfo, args: &[&str]) -> Result<Flow> {
+    let vram = boot_info.vram();
+    match args {
+        [] => {
+            screenshot::dump_ppm(&vram, &mut global_port())
+                .map_err(|_| "screenshot: failed to read the screen")?;
+        }
+        ["--raw"] => {
+            if !DebugCon::is_present() {
+                return Err("screenshot: debugcon is not available");
+            }
+            let mut debugcon = DebugCon;
+            screenshot::dump_ppm_raw(&vram, |bytes| debugcon.write_bytes(bytes))
+                .map_err(|_| "screenshot: failed to read the screen")?;
+            println!(
+                "screenshot: wrote {}x{} PPM to debugcon",
+                vram.width(),
+                vram.height()
+            );
+        }
+        _ => return Err("invalid arguments"),
+    }
+    Ok(Flow::Continue)
+}
+
+fn cmd_clear(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    print!("{ANSI_CLEAR}");
+    clear_vram_console();
+    Ok(Flow::Continue)
+}
+
+fn cmd_reboot(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    println!("Rebooting...");
+    global_port().flush();
+    reboot()
+}
+
+fn cmd_poweroff(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    println!("Powering off...");
+    poweroff()
+}
+
+fn cmd_exit(_: &BootInfo, args: &[&str]) -> Result<Flow> {
+    no_args(args)?;
+    Ok(Flow::Exit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn tokenizer_splits_on_any_whitespace() {
+        assert_eq!(tokenize(""), Vec::<&str>::new());
+        assert_eq!(tokenize("   \t "), Vec::<&str>::new());
+        assert_eq!(tokenize("help"), ["help"]);
+        assert_eq!(
+            tokenize("  hexdump\t0x1000   10 "),
+            ["hexdump", "0x1000", "10"]
+        );
+    }
+    #[test_case]
+    fn hex_arguments_are_parsed() {
+        assert_eq!(parse_hex("0x1000"), Ok(0x1000));
+        assert_eq!(parse_hex("0XfF"), Ok(0xff));
+        assert_eq!(parse_hex("10"), Ok(0x10));
+        assert_eq!(parse_hex("0xffffffffffffffff"), Ok(u64::MAX));
+        assert!(parse_hex("").is_err());
+        assert!(parse_hex("0x").is_err());
+        assert!(parse_hex("+10").is_err());
+        assert!(parse_hex("0x-1").is_err());
+        assert!(parse_hex("12g").is_err());
+        assert!(parse_hex("0x10000000000000000").is_err());
+    }
+    #[test_case]
+    fn hexdump_arguments_are_checked() {
+        assert_eq!(parse_hexdump_args(&["0x1000", "0x10"]), Ok((0x1000, 0x10)));
+        assert_eq!(parse_hexdump_args(&["1000", "1000"]), Ok((0x1000, 0x1000)));
+        assert!(parse_hexdump_args(&[]).is_err());
+        assert!(parse_hexdump_args(&["0x1000"]).is_err());
+        assert!(parse_hexdump_args(&["0x1000", "0x10", "0x10"]).is_err());
+        assert!(parse_hexdump_args(&["0x1000", "0"]).is_err());
+        assert!(parse_hexdump_args(&["0x1000", "0x1001"]).is_err());
+        assert!(parse_hexdump_args(&["0xfffffffffffffff0", "0x20"]).is_err());
+        assert!(parse_hexdump_args(&["zzz", "0x10"]).is_err());
+    }
+    // 追加したコマンドはグローバルなテーブルに残る
+    crate::kernel_test! {
+        #[allow_leaks]
+        fn commands_are_found_and_registered() {
+            assert!(find_command("help").is_some());
+            assert!(find_command("nosuchcommand").is_none());
+            fn cmd_test_echo(_: &BootInfo, _: &[&str]) -> Result<Flow> {
+                Ok(Flow::Continue)
+            }
+            let cmd = Command {
+                name: "test-echo",
+                args: "",
+                help: "test command",
+                run: cmd_test_echo,
+            };
+            register_command(cmd).unwrap();
+            assert!(find_command("test-echo").is_some());
+            assert!(register_command(cmd).is_err());
+            assert!(register_command(Command {
+                name: "help",
+                ..cmd
+            })
+            .is_err());
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use crate::allocator::ALLOCATOR;
+use crate::allocator::LAYOUT_PAGE_4K;
+use crate::result::Result;
+use crate::sync::SpinLock;
+use core::alloc::GlobalAlloc;
+use core::marker::PhantomData;
+use core::mem::align_of;
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::ptr::NonNull;
+
+// 同じ大きさのオブジェクトをたくさん確保するためのスラブアロケータ
+// 4KiBのページ（スラブ）を同じ大きさのスロットに分けて使う
+// スラブの先頭にSlabHeaderを置くので、オブジェクトのアドレスの下位12ビットを落とせばスラブが分かる
+
+const SLAB_SIZE: usize = 4096;
+
+// 空いているスロットの先頭に置く
+struct FreeSlot {
+    next: *mut FreeSlot,
+}
+
+#[repr(C)]
+struct SlabHeader {
+    // 空きスロットがあるスラブのリスト
+    next: *mut SlabHeader,
+    prev: *mut SlabHeader,
+    // このスラブの空きスロットのリスト
+    free: *mut FreeSlot,
+    // 使われているスロットの数
+    in_use: usize,
+}
+
+// キャッシュ全体の状態
+struct SlabList {
+    // 空きスロットがあるスラブ
+    partial: *mut SlabHeader,
+    // 一杯になったスラブ（空きができたらpartialに戻す）
+    full: *mut SlabHeader,
+    slabs: usize,
+    in_use: usize,
+}
+// スラブのページはこのキャッシュからしか触らない
+unsafe impl Send for SlabList {}
+
+// headから始まるスラブのリストの先頭にslabを加える
+fn push(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    unsafe {
+        (*slab).prev = null_mut();
+        (*slab).next = *head;
+        if let Some(head) = head.as_mut() {
+            head.prev = slab;
+        }
+    }
+    *head = slab;
+}
+
+// headから始まるスラブのリストからslabを外す
+fn remove(head: &mut *mut SlabHeader, slab: *mut SlabHeader) {
+    unsafe {
+        let SlabHeader { next, prev, .. } = *slab;
+        match prev.as_mut() {
+            Some(prev) => prev.next = next,
+            None => *head = next,
+        }
+        if let Some(next) = next.as_mut() {
+            next.prev = prev;
+        }
+    }
+}
+
+// 使用中のスロットとスラブの数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SlabStats {
+    pub objects: usize,
+    pub slabs: usize,
+}
+
+pub struct SlabCache<T> {
+    list: SpinLock<SlabList>,
+    _marker: PhantomData<T>,
+}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+impl<T> SlabCache<T> {
+    // 1つのスロットの大きさ（空きのときはFreeSlotが入る）
+    const SLOT_SIZE: usize = {
+        let size = if size_of::<T>() > size_of::<FreeSlot>() {
+            size_of::<T>()
+        } else {
+            size_of::<FreeSlot>()
+        };
+        size.next_multiple_of(Self::SLOT_ALIGN)
+    };
+    const SLOT_ALIGN: usize = if align_of::<T>() > align_of::<FreeSlot>() {
+        align_of::<T>()
+    } else {
+        align_of::<FreeSlot>()
+    };
+    // ヘッダーの後ろの最初のスロットの位置
+    const FIRST_SLOT: usize = size_of::<SlabHeader>().next_multiple_of(Self::SLOT_ALIGN);
+    pub const OBJECTS_PER_SLAB: usize = (SLAB_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE;
+
+    pub const fn new() -> Self {
+        const { assert!(Self::OBJECTS_PER_SLAB > 0, "object is too large for a slab") };
+        Self {
+            list: SpinLock::new(SlabList {
+                partial: null_mut(),
+                full: null_mut(),
+                slabs: 0,
+                in_use: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    // ページを1つ確保して、全てのスロットを空きにしたスラブを作る
+    fn new_slab() -> Result<*mut SlabHeader> {
+        let page = unsafe { ALLOCATOR.alloc(LAYOUT_PAGE_4K) };
+        if page.is_null() {
+            return Err("SlabCache: out of memory");
+        }
+        let slab = page as *mut SlabHeader;
+        let mut free = null_mut();
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let slot = unsafe { page.add(Self::FIRST_SLOT + i * Self::SLOT_SIZE) } as *mut FreeSlot;
+            unsafe { slot.write(FreeSlot { next: free }) };
+            free = slot;
+        }
+        unsafe {
+            slab.write(SlabHeader {
+                next: null_mut(),
+                prev: null_mut(),
+                free,
+                in_use: 0,
+            })
+        };
+        Ok(slab)
+    }
+
+    // valueをスロットに置いて、そのポインタを返す
+    pub fn alloc(&self, value: T) -> Result<NonNull<T>> {
+        let mut list = self.list.lock();
+        if list.partial.is_null() {
+            let slab = Self::new_slab()?;
+            push(&mut list.partial, slab);
+            list.slabs += 1;
+        }
+        let slab = unsafe { &mut *list.partial };
+        let slot = slab.free;
+        slab.free = unsafe { (*slot).next };
+        slab.in_use += 1;
+        // 一杯になったスラブは、空きができるまでfullに移す
+        if slab.free.is_null() {
+            remove(&mut list.partial, slab);
+            push(&mut list.full, slab);
+        }
+        list.in_use += 1;
+        let obj = slot as *mut T;
+        unsafe { obj.write(value) };
+        Ok(unsafe { NonNull::new_unchecked(obj) })
+    }
+
+    /// # Safety
+    /// ptrはこのキャッシュのallocが返したもので、まだfreeしていないこと
+    /// オブジェクトはdropされ、以降ptrは使えない
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        ptr.drop_in_place();
+        let slot = ptr.as_ptr() as *mut FreeSlot;
+        let slab = (ptr.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader;
+        let mut list = self.list.lock();
+        let was_full = (*slab).free.is_null();
+        slot.write(FreeSlot { next: (*slab).free });
+        (*slab).free = slot;
+        (*slab).in_use -= 1;
+        list.in_use -= 1;
+        if was_full {
+            remove(&mut list.full, slab);
+            push(&mut list.partial, slab);
+        }
+        // 空になったスラブはページを返す（最後の1つは次のallocのために残し、キャッシュをdropしたときに返す）
+        if (*slab).in_use == 0 && list.slabs > 1 {
+            remove(&mut list.partial, slab);
+            list.slabs -= 1;
+            ALLOCATOR.dealloc(slab as *mut u8, LAYOUT_PAGE_4K);
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let list = self.list.lock();
+        SlabStats {
+            objects: list.in_use,
+            slabs: list.slabs,
+        }
+    }
+}
+// 残っている全てのスラブのページを返す
+// 使用中のオブジェクトが残っていても、それらのdropは呼ばない
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        let mut list = self.list.lock();
+        for mut slab in [list.partial, list.full] {
+            while !slab.is_null() {
+                let next = unsafe { (*slab).next };
+                unsafe { ALLOCATOR.dealloc(slab as *mut u8, LAYOUT_PAGE_4K) };
+                slab = next;
+            }
+        }
+        list.partial = null_mut();
+        list.full = null_mut();
+        list.slabs = 0;
+    }
+}
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use core::hint::black_box;
+
+    #[derive(Debug, PartialEq)]
+    struct Packet {
+        id: u64,
+        payload: [u8; 200],
+    }
+
+    #[test_case]
+    fn objects_are_aligned_and_keep_their_values() {
+        #[repr(align(64))]
+        struct Aligned(u32);
+        let cache = SlabCache::<Aligned>::new();
+        let objects: Vec<NonNull<Aligned>> =
+            (0..100).map(|i| cache.alloc(Aligned(i)).unwrap()).collect();
+        for (i, p) in objects.iter().enumerate() {
+            assert!((p.as_ptr() as usize).is_multiple_of(64));
+            assert_eq!(unsafe { p.as_ref().0 }, i as u32);
+        }
+        for p in objects {
+            unsafe { cache.free(p) };
+        }
+    }
+    #[test_case]
+    fn slabs_grow_and_shrink_with_use() {
+        let cache = SlabCache::<Packet>::new();
+        let per_slab = SlabCache::<Packet>::OBJECTS_PER_SLAB;
+        assert!(per_slab >= 16);
+        let objects: Vec<NonNull<Packet>> = (0..per_slab * 3 + 1)
+            .map(|id| {
+                cache
+                    .alloc(Packet {
+                        id: id as u64,
+                        payload: [id as u8; 200],
+                    })
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            cache.stats(),
+            SlabStats {
+                objects: per_slab * 3 + 1,
+                slabs: 4
+            }
+        );
+        for (id, p) in objects.iter().enumerate() {
+            let packet = unsafe { p.as_ref() };
+            assert_eq!(packet.id, id as u64);
+            assert_eq!(packet.payload, [id as u8; 200]);
+        }
+        for p in objects {
+            unsafe { cache.free(p) };
+        }
+        // 空になったスラブは1つを残して返される
+        assert_eq!(
+            cache.stats(),
+            SlabStats {
+                objects: 0,
+                slabs: 1
+            }
+        );
+    }
+    #[test_case]
+    fn freed_slots_are_reused() {
+        let cache = SlabCache::<u64>::new();
+        let a = cache.alloc(1).unwrap();
+        let b = cache.alloc(2).unwrap();
+        unsafe { cache.free(a) };
+        let c = cache.alloc(3).unwrap();
+        assert_eq!(c, a);
+        unsafe {
+            cache.free(b);
+            cache.free(c);
+        }
+    }
+    #[test_case]
+    fn free_drops_the_object() {
+        let cache = SlabCache::<Box<u32>>::new();
+        let before = crate::allocator::alloc_stats();
+        let p = cache.alloc(Box::new(7)).unwrap();
+        unsafe { cache.free(p) };
+        // 残ったスラブのページと、Boxの中身だけが使われている
+        assert_eq!(
+            crate::allocator::alloc_stats().live_allocation_count,
+            before.live_allocation_count + 1
+        );
+        // キャッシュをdropすると、最後のスラブのページも返される
+        drop(cache);
+        assert_eq!(
+            crate::allocator::alloc_stats().live_allocation_count,
+            before.live_allocation_count
+        );
+    }
+    #[test_case]
+    fn dropping_the_cache_frees_full_slabs() {
+        let before = crate::allocator::alloc_stats();
+        let cache = SlabCache::<u64>::new();
+        let objects: Vec<NonNull<u64>> = (0..SlabCache::<u64>::OBJECTS_PER_SLAB * 2)
+            .map(|i| cache.alloc(i as u64).unwrap())
+            .collect();
+        assert_eq!(cache.stats().slabs, 2);
+        drop(cache);
+        drop(objects);
+        assert_eq!(
+            crate::allocator::alloc_stats().live_allocation_count,
+            before.live_allocation_count
+        );
+    }
+
+    crate::kernel_bench!(slab_alloc_free_packet, {
+        let cache: &'static SlabCache<Packet> = Box::leak(Box::new(SlabCache::new()));
+        move || unsafe {
+            let p = cache
+                .alloc(Packet {
+                    id: 0,
+                    payload: [0; 200],
+                })
+                .unwrap();
+            cache.free(black_box(p));
+        }
+    });
+    crate::kernel_bench!(box_alloc_free_packet, {
+        || {
+            let p = Box::new(Packet {
+                id: 0,
+                payload: [0; 200],
+            });
+            drop(black_box(p));
+        }
+    });
+}
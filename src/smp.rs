@@ -0,0 +1,512 @@
+extern crate alloc;
+
+use crate::acpi::PlatformInfo;
+use crate::apic::LocalApic;
+use crate::apic::TLB_SHOOTDOWN_VECTOR;
+use crate::gdt::init_ap_gdt;
+use crate::gdt::BIT_CS_LONG_MODE;
+use crate::gdt::BIT_CS_READABLE;
+use crate::gdt::BIT_DS_WRITABLE;
+use crate::gdt::BIT_PRESENT;
+use crate::gdt::BIT_TYPE_CODE;
+use crate::gdt::BIT_TYPE_DATA;
+use crate::gdt::KERNEL_CS;
+use crate::gdt::KERNEL_DS;
+use crate::info;
+use crate::msr::Efer;
+use crate::paging::init_pat;
+use crate::paging::set_page_attr;
+use crate::paging::write_combining_enabled;
+use crate::paging::PageAttr;
+use crate::reserve::reserve_region;
+use crate::result::Result;
+use crate::stack::KernelStack;
+use crate::tsc::busy_wait_us;
+use crate::uefi::EfiMemoryType;
+use crate::uefi::MemoryMapHolder;
+use crate::warn;
+use crate::x86::disable_interrupts;
+use crate::x86::hlt;
+use crate::x86::hlt_with_interrupts;
+use crate::x86::invlpg;
+use crate::x86::load_idt;
+use crate::x86::read_cr0;
+use crate::x86::read_cr3;
+use crate::x86::read_cr4;
+use crate::x86::read_idtr_base;
+use crate::x86::write_cr3;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::mem::size_of;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::fence;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use spin::Once;
+
+const PAGE_SIZE: u64 = 4096;
+// APの起動に使う低いアドレスの領域
+// 先頭のページに起動コード（トランポリン）を置き、続く3ページを一時的なページテーブルにする
+const TRAMPOLINE_PAGES: u64 = 4;
+const TRAMPOLINE_SIZE: u64 = TRAMPOLINE_PAGES * PAGE_SIZE;
+// SIPIで指定できるのは1MiB未満のページだけ（0番のページは避ける）
+const LOW_MEMORY: Range<u64> = PAGE_SIZE..0x10_0000;
+// トランポリンのページの中で、BSPが値を書き込んでおく場所
+const PARAMS_OFFSET: usize = 0xf00;
+// APごとのスタックの大きさ
+const AP_STACK_SIZE: usize = 16 * 1024;
+// INITを送ってからSIPIを送るまで、SIPIを送ってからもう一度送るまでの時間
+const INIT_DELAY_US: u64 = 10_000;
+const SIPI_DELAY_US: u64 = 200;
+// SIPIを送ってからAPが起動したと諦めるまでの時間
+const AP_START_TIMEOUT_US: u64 = 100_000;
+
+const CR0_PE: u32 = 1 << 0;
+const CR0_PG: u32 = 1 << 31;
+const CR4_PAE: u32 = 1 << 5;
+const IA32_EFER: u32 = 0xc000_0080;
+// LMAはCPUが立てるビットなので、BSPのEFERから写さない
+const EFER_LMA: u64 = 1 << 10;
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_LARGE_PAGE: u64 = 1 << 7;
+
+// BSPがトランポリンに渡す値（トランポリンのアセンブリはこの並びを前提にしている）
+#[repr(C)]
+struct TrampolineParams {
+    // APが最初に使うGDT（null, コード, データ）
+    gdt: [u64; 3],
+    // lgdtに渡す（上限, ベースの下位16bit, 上位16bit）
+    gdtr: [u16; 4],
+    // 64bitのコードへのfar jumpの飛び先
+    long_mode_entry: u32,
+    long_mode_selector: u16,
+    _reserved: u16,
+    // 起動コードと同じ低いアドレスだけをマップした一時的なページテーブル
+    temp_cr3: u32,
+    efer: u32,
+    // ロングモードに入った後に、BSPと同じ値に切り替える
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    stack_top: u64,
+    entry: u64,
+}
+const _: () = assert!(PARAMS_OFFSET + size_of::<TrampolineParams>() <= PAGE_SIZE as usize);
+
+// リアルモードで起動したAPを、一時的なページテーブルで直接ロングモードに切り替えてから、
+// カーネルのページテーブルとスタックに切り替えてap_mainを呼ぶ
+// このコードはトランポリンのページにコピーして実行するので、ラベルの差とRIP相対のアドレスしか使わない
+global_asm!(
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    // リアルモードのlgdtはベースを24bitしか読まないが、トランポリンは1MiB未満にあるので足りる
+    "lgdt [{params} + {gdtr}]",
+    "mov eax, cr4",
+    "or eax, {cr4_pae}",
+    "mov cr4, eax",
+    "mov eax, dword ptr [{params} + {temp_cr3}]",
+    "mov cr3, eax",
+    "mov ecx, {ia32_efer}",
+    "rdmsr",
+    "or eax, dword ptr [{params} + {efer}]",
+    "wrmsr",
+    // プロテクトモードとページングを同時に有効にして、ロングモードに入る
+    "mov eax, cr0",
+    "or eax, {cr0_pe_pg}",
+    "mov cr0, eax",
+    // jmp far dword ptr [params + long_mode_entry]（16bitのコードでは32bitのオフセットを書けないので直接並べる）
+    ".byte 0x66, 0xff, 0x2e",
+    ".word {params} + {long_mode_entry}",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, {data_sel}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rax, qword ptr [rip + ap_trampoline_start + {params} + {cr4}]",
+    "mov cr4, rax",
+    "mov rax, qword ptr [rip + ap_trampoline_start + {params} + {cr3}]",
+    "mov cr3, rax",
+    "mov rax, qword ptr [rip + ap_trampoline_start + {params} + {cr0}]",
+    "mov cr0, rax",
+    "mov rsp, qword ptr [rip + ap_trampoline_start + {params} + {stack_top}]",
+    "mov rax, qword ptr [rip + ap_trampoline_start + {params} + {entry}]",
+    "xor ebp, ebp",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    params = const PARAMS_OFFSET,
+    gdtr = const offset_of!(TrampolineParams, gdtr),
+    long_mode_entry = const offset_of!(TrampolineParams, long_mode_entry),
+    temp_cr3 = const offset_of!(TrampolineParams, temp_cr3),
+    efer = const offset_of!(TrampolineParams, efer),
+    cr0 = const offset_of!(TrampolineParams, cr0),
+    cr3 = const offset_of!(TrampolineParams, cr3),
+    cr4 = const offset_of!(TrampolineParams, cr4),
+    stack_top = const offset_of!(TrampolineParams, stack_top),
+    entry = const offset_of!(TrampolineParams, entry),
+    cr4_pae = const CR4_PAE,
+    cr0_pe_pg = const CR0_PE | CR0_PG,
+    ia32_efer = const IA32_EFER,
+    data_sel = const KERNEL_DS,
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+// 起動コードの(バイト列, 先頭からロングモードのコードまでの距離)
+fn trampoline_code() -> (&'static [u8], usize) {
+    unsafe {
+        let start = &raw const ap_trampoline_start;
+        let long_mode = &raw const ap_trampoline_long_mode;
+        let end = &raw const ap_trampoline_end;
+        let code = core::slice::from_raw_parts(start, end as usize - start as usize);
+        (code, long_mode as usize - start as usize)
+    }
+}
+
+// 空いている範囲の中から、1MiB未満でできるだけ高いアドレスにトランポリンの領域を探す
+fn find_trampoline_area(free: impl Iterator<Item = Range<u64>>) -> Option<Range<u64>> {
+    free.filter_map(|r| {
+        let start = r.start.max(LOW_MEMORY.start).next_multiple_of(PAGE_SIZE);
+        let end = r.end.min(LOW_MEMORY.end) & !(PAGE_SIZE - 1);
+        (end >= start + TRAMPOLINE_SIZE).then(|| end - TRAMPOLINE_SIZE..end)
+    })
+    .max_by_key(|r| r.start)
+}
+
+static TRAMPOLINE_AREA: Once<Range<u64>> = Once::new();
+
+// アロケータに渡す前のメモリマップから、APの起動に使う領域を探して取っておく
+// 取っておいた範囲は、reserve::reserve_regionでページアロケータから除かれる
+pub fn reserve_trampoline_area(memory_map: &MemoryMapHolder) -> Option<Range<u64>> {
+    let free = memory_map
+        .iter()
+        .filter(|e| e.memory_type() == EfiMemoryType::CONVENTIONAL_MEMORY)
+        .map(|e| {
+            let start = e.physical_start();
+            start..start + e.number_of_pages() * PAGE_SIZE
+        });
+    let area = find_trampoline_area(free)?;
+    reserve_region(area.start, area.end - area.start, "AP trampoline").ok()?;
+    Some(TRAMPOLINE_AREA.call_once(|| area).clone())
+}
+
+// 起動しているCPUの数（BSPを含む）
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+// 今動いているCPUのLocal APIC ID（Local APICを初期化する前はBSPしか動いていないので0）
+pub fn current_cpu_id() -> u32 {
+    LocalApic::current().map_or(0, |apic| apic.id())
+}
+
+// TLBの無効化（TLBシュートダウン）
+// ページテーブルの対応を外したり権限を絞ったりしたCPUは、自分のTLBをinvlpgで捨てた後に、
+// 他のCPUにもIPIを送って同じ範囲を捨てさせ、全員が捨て終わるまで待つ
+// 要求はページテーブルのロックを持ったまま送るので、同時に送られる要求は1つだけ
+// IPIを受け取れるようになったCPUのLocal APIC IDのビット（64以上のIDのCPUには送らない）
+static TLB_LISTENERS: AtomicU64 = AtomicU64::new(0);
+// まだ捨て終わっていないCPUのビット
+static TLB_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static TLB_REQUEST_START: AtomicU64 = AtomicU64::new(0);
+static TLB_REQUEST_PAGES: AtomicUsize = AtomicUsize::new(0);
+// これより多くのページを捨てるときは、1ページずつではなくCR3を書き直して全て捨てる
+const TLB_FLUSH_ALL_PAGES: usize = 32;
+// 他のCPUが捨て終わるのを待つ時間の上限
+const TLB_SHOOTDOWN_TIMEOUT_US: u64 = 100_000;
+
+fn cpu_bit(apic_id: u32) -> u64 {
+    1u64.checked_shl(apic_id).unwrap_or(0)
+}
+
+// このCPUでTLBシュートダウンのIPIを受け取れるようになったことを知らせる
+// IDTとLocal APICを初期化して、割り込みを許可するCPUが呼ぶ
+fn listen_for_tlb_shootdown() {
+    TLB_LISTENERS.fetch_or(cpu_bit(current_cpu_id()), Ordering::SeqCst);
+}
+
+fn flush_tlb(start: u64, pages: usize) {
+    if pages > TLB_FLUSH_ALL_PAGES {
+        unsafe { write_cr3(read_cr3()) };
+        return;
+    }
+    for i in 0..pages as u64 {
+        invlpg(start + i * PAGE_SIZE);
+    }
+}
+
+// このCPUに捨てるように頼まれている範囲があれば、TLBから捨てて済んだことを知らせる
+// ページテーブルのロックを待っている間は割り込みが入らないことがあるので、待ちながらも呼ぶ
+pub fn flush_requested_tlb() {
+    let bit = cpu_bit(current_cpu_id());
+    if TLB_REQUESTS.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+    flush_tlb(
+        TLB_REQUEST_START.load(Ordering::SeqCst),
+        TLB_REQUEST_PAGES.load(Ordering::SeqCst),
+    );
+    TLB_REQUESTS.fetch_and(!bit, Ordering::SeqCst);
+}
+
+// IDTから呼ばれるTLBシュートダウンのIPIの処理
+pub fn handle_tlb_shootdown() {
+    flush_requested_tlb();
+    if let Some(apic) = LocalApic::current() {
+        apic.send_eoi();
+    }
+}
+
+// 他の全てのCPUに、startからのpages枚のページのTLBを捨てさせ、捨て終わったCPUの数を返す
+// 自分のTLBは呼び出し側で捨てておくこと
+// ページテーブルのロックを持ったまま呼ぶ（捨て終わるまでは、外した物理ページを解放しない）
+pub fn shootdown_tlb(start: u64, pages: usize) -> usize {
+    let targets = TLB_LISTENERS.load(Ordering::SeqCst) & !cpu_bit(current_cpu_id());
+    let Some(apic) = LocalApic::current().filter(|_| targets != 0) else {
+        return 0;
+    };
+    TLB_REQUEST_START.store(start, Ordering::SeqCst);
+    TLB_REQUEST_PAGES.store(pages, Ordering::SeqCst);
+    TLB_REQUESTS.store(targets, Ordering::SeqCst);
+    for apic_id in 0..u64::BITS {
+        if targets & cpu_bit(apic_id) == 0 {
+            continue;
+        }
+        if let Err(e) = apic.send_fixed(apic_id as u8, TLB_SHOOTDOWN_VECTOR as u8) {
+            warn!("{e} (TLB shootdown to APIC ID {apic_id})");
+            TLB_REQUESTS.fetch_and(!cpu_bit(apic_id), Ordering::SeqCst);
+        }
+    }
+    let mut waited = 0;
+    while TLB_REQUESTS.load(Ordering::SeqCst) != 0 && waited < TLB_SHOOTDOWN_TIMEOUT_US {
+        busy_wait_us(10);
+        waited += 10;
+    }
+    let pending = TLB_REQUESTS.swap(0, Ordering::SeqCst);
+    if pending != 0 {
+        warn!("SMP: TLB shootdown timed out (pending CPUs: {pending:#b})");
+    }
+    (targets & !pending).count_ones() as usize
+}
+
+// 最後に起動したAPが読み込んだIDTの先頭アドレス（読み込めなかったら0）
+static AP_IDT_BASE: AtomicU64 = AtomicU64::new(0);
+
+// トランポリンから呼ばれるAPの入口
+// まだスケジューラはAPを使わないので、起動したことを知らせたら、TLBシュートダウンのIPIだけを受けて止まっている
+extern "sysv64" fn ap_main() -> ! {
+    let id = current_cpu_id();
+    // IDTがないと、APで起きた例外はトリプルフォルトになってマシン全体がリセットされる
+    // IDTのゲートはISTを使うので、このAP用のTSSを先に読み込んでから、BSPと同じIDTを読み込む
+    let idt_loaded = match init_ap_gdt().and_then(|()| load_idt()) {
+        Ok(()) => {
+            AP_IDT_BASE.store(read_idtr_base(), Ordering::SeqCst);
+            true
+        }
+        Err(e) => {
+            warn!("SMP: CPU (APIC ID {id}) runs without an IDT: {e}");
+            false
+        }
+    };
+    // BSPと同じPATにしておかないと、フレームバッファのメモリタイプがCPUごとに変わってしまう
+    if write_combining_enabled() {
+        init_pat();
+    }
+    // IPIを受け取れるように、このAPのLocal APICも有効にする（タイマーは止めたまま）
+    let listening = idt_loaded && LocalApic::init().is_some();
+    if listening {
+        listen_for_tlb_shootdown();
+    }
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    info!("SMP: CPU (APIC ID {id}) is online");
+    loop {
+        if listening {
+            hlt_with_interrupts();
+        } else {
+            disable_interrupts();
+            hlt();
+        }
+    }
+}
+
+// トランポリンの領域に起動コードと一時的なページテーブルを書き込み、ページを実行可能にする
+fn install_trampoline(area: &Range<u64>) -> Result<()> {
+    let (code, long_mode_offset) = trampoline_code();
+    if code.len() > PARAMS_OFFSET {
+        return Err("SMP: trampoline code is too large");
+    }
+    let base = area.start;
+    let [pml4, pdpt, pd] = [1, 2, 3].map(|i| (base + i * PAGE_SIZE) as *mut u64);
+    unsafe {
+        ptr::write_bytes(base as *mut u8, 0, TRAMPOLINE_SIZE as usize);
+        ptr::copy_nonoverlapping(code.as_ptr(), base as *mut u8, code.len());
+        // 0から2MiBまでを1つの大きなページでアイデンティティマップする
+        pml4.write(pdpt as u64 | PTE_PRESENT | PTE_WRITABLE);
+        pdpt.write(pd as u64 | PTE_PRESENT | PTE_WRITABLE);
+        pd.write(PTE_PRESENT | PTE_WRITABLE | PTE_LARGE_PAGE);
+    }
+    let gdt_base = base + (PARAMS_OFFSET + offset_of!(TrampolineParams, gdt)) as u64;
+    let params = TrampolineParams {
+        gdt: [
+            0,
+            BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
+            BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+        ],
+        gdtr: [
+            (size_of::<[u64; 3]>() - 1) as u16,
+            gdt_base as u16,
+            (gdt_base >> 16) as u16,
+            0,
+        ],
+        long_mode_entry: (base + long_mode_offset as u64) as u32,
+        long_mode_selector: KERNEL_CS,
+        _reserved: 0,
+        temp_cr3: pml4 as u32,
+        efer: (Efer::read()? & !EFER_LMA) as u32,
+        cr0: read_cr0(),
+        cr3: read_cr3() as u64,
+        cr4: read_cr4(),
+        stack_top: 0,
+        entry: ap_main as *const () as u64,
+    };
+    unsafe { params_ptr(area).write_volatile(params) };
+    // カーネルのページテーブルに切り替えた直後も起動コードを実行できるようにする
+    set_page_attr(base, base + PAGE_SIZE, PageAttr::ReadWriteKernel)
+}
+
+fn params_ptr(area: &Range<u64>) -> *mut TrampolineParams {
+    (area.start + PARAMS_OFFSET as u64) as *mut TrampolineParams
+}
+
+// INIT-SIPI-SIPIでAPを1つ起動して、ONLINE_CPUSが増えるのを待つ
+fn start_ap(bsp: &LocalApic, area: &Range<u64>, apic_id: u8) -> Result<()> {
+    // APは止めたままにするので、スタックは解放しない
+    let stack_top = KernelStack::alloc(AP_STACK_SIZE)?.top();
+    unsafe {
+        ptr::addr_of_mut!((*params_ptr(area)).stack_top).write_volatile(stack_top);
+    }
+    // APが読む前に書き込みを終わらせる
+    fence(Ordering::SeqCst);
+    let vector = (area.start / PAGE_SIZE) as u8;
+    let online = online_cpus();
+    bsp.send_init(apic_id)?;
+    busy_wait_us(INIT_DELAY_US);
+    for _ in 0..2 {
+        bsp.send_startup(apic_id, vector)?;
+        busy_wait_us(SIPI_DELAY_US);
+        if online_cpus() > online {
+            return Ok(());
+        }
+    }
+    let mut waited = 0;
+    while waited < AP_START_TIMEOUT_US {
+        if online_cpus() > online {
+            return Ok(());
+        }
+        busy_wait_us(100);
+        waited += 100;
+    }
+    // 後から動き出して次のAPと同じスタックを使わないように、INITで止めておく
+    let _ = bsp.send_init(apic_id);
+    Err("SMP: AP did not respond")
+}
+
+// MADTに載っている有効なAPを全て起動して、(起動したCPU数, 有効なCPU数)を返す
+pub fn init(platform: &PlatformInfo) -> Result<(usize, usize)> {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return Err("SMP: already initialized");
+    }
+    let bsp = LocalApic::current().ok_or("SMP: Local APIC is not initialized")?;
+    // BSPはIDTとLocal APICの初期化が済んで、割り込みも許可されている
+    listen_for_tlb_shootdown();
+    let area = TRAMPOLINE_AREA
+        .get()
+        .ok_or("SMP: no memory below 1 MiB for the AP trampoline")?;
+    install_trampoline(area)?;
+    let bsp_id = bsp.id();
+    let cpus = platform.cpus.iter().filter(|c| c.enabled);
+    let total = cpus.clone().count();
+    for cpu in cpus.filter(|c| c.apic_id as u32 != bsp_id) {
+        if let Err(e) = start_ap(&bsp, area, cpu.apic_id) {
+            warn!("{e} (APIC ID {})", cpu.apic_id);
+        }
+    }
+    Ok((online_cpus(), total))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn trampoline_area_is_below_1mib_and_page_aligned() {
+        let free = [0..0x9_f000, 0x10_0000..0x800_0000];
+        assert_eq!(
+            find_trampoline_area(free.into_iter()),
+            Some(0x9_b000..0x9_f000)
+        );
+        // ページの途中から始まる領域や、0番のページだけの領域は使わない
+        let free = [0..0x2000, 0x1_0800..0x1_5000];
+        assert_eq!(
+            find_trampoline_area(free.into_iter()),
+            Some(0x1_1000..0x1_5000)
+        );
+        let free = [0..0x4000, 0x10_0000..0x800_0000];
+        assert_eq!(find_trampoline_area(free.into_iter()), None);
+    }
+    #[test_case]
+    fn trampoline_fits_in_one_page() {
+        let (code, long_mode_offset) = trampoline_code();
+        assert!(code.len() <= PARAMS_OFFSET);
+        assert!(long_mode_offset < code.len());
+        // cliから始まる
+        assert_eq!(code[0], 0xfa);
+    }
+    // scripts/launch_qemu.shは-smp 4で起動する
+    #[test_case]
+    fn all_cpus_are_online_under_qemu() {
+        assert_eq!(online_cpus(), 4);
+    }
+    // 起動した全てのCPUがTLBシュートダウンのIPIに応える
+    #[test_case]
+    fn tlb_shootdown_reaches_every_ap() {
+        assert_eq!(
+            TLB_LISTENERS.load(Ordering::SeqCst).count_ones() as usize,
+            online_cpus()
+        );
+        let page = ap_main as *const () as u64 & !(PAGE_SIZE - 1);
+        assert_eq!(shootdown_tlb(page, 1), online_cpus() - 1);
+        assert_eq!(
+            shootdown_tlb(page, TLB_FLUSH_ALL_PAGES + 1),
+            online_cpus() - 1
+        );
+        assert_eq!(TLB_REQUESTS.load(Ordering::SeqCst), 0);
+    }
+    // APもBSPと同じIDTで例外を受け取る
+    #[test_case]
+    fn aps_load_the_bsp_idt() {
+        assert!(online_cpus() > 1);
+        assert_eq!(AP_IDT_BASE.load(Ordering::SeqCst), read_idtr_base());
+    }
+}
@@ -0,0 +1,199 @@
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::is_mapped;
+use crate::paging::PageAttr;
+use crate::paging::PAGE_SIZE;
+use crate::result::Result;
+use crate::vmm::Vmm;
+use crate::vmm::KERNEL_STACK_AREA;
+use core::arch::asm;
+use core::mem::ManuallyDrop;
+use core::ops::Range;
+use core::ptr;
+use spin::Once;
+
+// カーネルのスタックの大きさ（この下に1ページのガードページを置く）
+pub const KERNEL_STACK_SIZE: usize = 128 * 1024;
+const GUARD_SIZE: usize = PAGE_SIZE;
+
+// スタックはヒープではなく、この範囲の仮想アドレスに置く
+// 範囲の一番下のページはマップしないでおくので、溢れたときにそのページへのページフォルトになる
+// （Vmmは範囲の後ろにもガードページを空けるので、上にはみ出したときもページフォルトになる）
+static STACK_VMM: Vmm = Vmm::new(KERNEL_STACK_AREA);
+
+// UEFIから渡されたスタックは大きさも場所も分からないので、自前で確保したスタックに移る
+// 割り込みのスタック（TSS）やAPのスタックも同じように確保する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KernelStack {
+    guard_start: u64,
+    size: usize,
+    // スタックにマップした物理ページの先頭
+    frames: u64,
+}
+impl KernelStack {
+    // sizeバイト（ページ単位に切り上げる）のスタックを、下にガードページを付けて確保する
+    // 自前のページテーブルに切り替えた後に呼ぶ
+    pub fn alloc(size: usize) -> Result<Self> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        let guard_start = STACK_VMM.alloc_range(GUARD_SIZE / PAGE_SIZE + pages)?;
+        let frames = match PAGE_ALLOCATOR.alloc_pages(pages) {
+            Ok(frames) => frames,
+            Err(e) => {
+                STACK_VMM.free_range(guard_start)?;
+                return Err(e);
+            }
+        };
+        let stack_start = guard_start + GUARD_SIZE as u64;
+        if let Err(e) = STACK_VMM.map(
+            stack_start,
+            frames as u64,
+            pages,
+            PageAttr::ReadWriteKernelNoExec,
+        ) {
+            unsafe { PAGE_ALLOCATOR.free_pages(frames, pages) };
+            STACK_VMM.free_range(guard_start)?;
+            return Err(e);
+        }
+        Ok(Self {
+            guard_start,
+            size: pages * PAGE_SIZE,
+            frames: frames as u64,
+        })
+    }
+
+    /// # Safety
+    /// このスタックの上で動いているコードや、このスタックを指しているTSSなどがないこと
+    pub unsafe fn free(self) -> Result<()> {
+        STACK_VMM.free_range(self.guard_start)?;
+        PAGE_ALLOCATOR.free_pages(self.frames as *mut u8, self.size / PAGE_SIZE);
+        Ok(())
+    }
+
+    pub fn guard(&self) -> Range<u64> {
+        self.guard_start..self.guard_start + GUARD_SIZE as u64
+    }
+
+    // スタックとして使える範囲（ガードページを含まない）
+    pub fn range(&self) -> Range<u64> {
+        self.guard().end..self.top()
+    }
+
+    pub fn top(&self) -> u64 {
+        self.guard().end + self.size as u64
+    }
+}
+
+static KERNEL_STACK: Once<KernelStack> = Once::new();
+
+// カーネルのスタックを確保する
+// 自前のページテーブルに切り替えた後に呼ぶ
+pub fn init_kernel_stack() -> Result<&'static KernelStack> {
+    if let Some(stack) = KERNEL_STACK.get() {
+        return Ok(stack);
+    }
+    let stack = KernelStack::alloc(KERNEL_STACK_SIZE)?;
+    Ok(KERNEL_STACK.call_once(|| stack))
+}
+
+pub fn kernel_stack() -> Option<&'static KernelStack> {
+    KERNEL_STACK.get()
+}
+
+// addrがどれかのスタックのガードページの中にあればtrue（スタックが溢れた）
+// スタックは確保したときに全てマップするので、スタックの範囲でマップされていないページはガードページ
+pub fn is_guard_page(addr: u64) -> bool {
+    KERNEL_STACK_AREA.contains(&addr) && !is_mapped(addr)
+}
+
+struct Continuation<T> {
+    arg: T,
+    f: fn(T) -> !,
+}
+
+extern "sysv64" fn enter_continuation<T>(c: *mut Continuation<T>) -> ! {
+    // 元のスタックにある値を、新しいスタックに移してから呼ぶ
+    let Continuation { arg, f } = unsafe { ptr::read(c) };
+    f(arg)
+}
+
+// スタックの一番上からf(arg)を実行する（戻ってこない）
+// 呼び出し元のフレームは二度と使われないので、その上に積まれていた値は破棄されずに残る
+pub fn run_on_stack<T>(stack: &KernelStack, arg: T, f: fn(T) -> !) -> ! {
+    let mut c = ManuallyDrop::new(Continuation { arg, f });
+    let c: *mut Continuation<T> = &mut *c;
+    let entry: extern "sysv64" fn(*mut Continuation<T>) -> ! = enter_continuation::<T>;
+    // 生きているRustのフレームの下でRSPを動かすことはできないので、
+    // RSPを切り替えてから関数を呼ぶところまでをアセンブリで行う
+    // topは16バイト境界なので、callで戻りアドレスを積むと関数の入口で期待される配置になる
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {entry}",
+            "ud2",
+            top = in(reg) stack.top(),
+            entry = in(reg) entry,
+            in("rdi") c,
+            options(noreturn)
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn tests_run_on_the_kernel_stack() {
+        let stack = kernel_stack().expect("kernel stack is not initialized");
+        let local = 0u64;
+        let addr = &local as *const u64 as u64;
+        assert!(stack.range().contains(&addr));
+        assert_eq!(stack.top() % 16, 0);
+        assert!(!crate::paging::is_mapped(stack.guard().start));
+        assert!(is_guard_page(stack.guard().end - 1));
+        assert!(!is_guard_page(stack.guard().end));
+        assert!(is_guard_page(stack.top()));
+    }
+    #[test_case]
+    fn stacks_get_their_own_guard_pages() {
+        let a = KernelStack::alloc(3 * PAGE_SIZE - 100).unwrap();
+        let b = KernelStack::alloc(PAGE_SIZE).unwrap();
+        assert_eq!(a.range().end - a.range().start, 3 * PAGE_SIZE as u64);
+        assert!(a.range().end <= b.guard().start);
+        for stack in [a, b] {
+            assert!(is_guard_page(stack.guard().start));
+            assert!(is_mapped(stack.range().start));
+            assert!(is_mapped(stack.top() - 8));
+            unsafe { ((stack.top() - 8) as *mut u64).write_volatile(0x1234) };
+        }
+        // コードのような、スタックの範囲の外はガードページではない
+        assert!(!is_guard_page(kernel_stack as *const () as u64));
+        unsafe {
+            a.free().unwrap();
+            b.free().unwrap();
+        }
+        assert!(!is_mapped(a.range().start));
+    }
+}
+
+// 再帰し続けてガードページに触れると、ページフォルトのハンドラがスタックの溢れと判断することを確かめる
+// このテストは必ず例外で終了するので、exception_tests featureを有効にした時だけ実行する
+#[cfg(all(test, feature = "exception_tests"))]
+mod overflow_test {
+    use core::hint::black_box;
+
+    // 1回の呼び出しで512バイトずつスタックを使う
+    #[allow(unconditional_recursion)]
+    #[inline(never)]
+    fn recurse(depth: u64) -> u64 {
+        let frame = black_box([depth; 64]);
+        recurse(depth + 1) + frame[63]
+    }
+
+    crate::kernel_test! {
+        #[should_panic(expected = "kernel stack overflow")]
+        fn stack_overflow_hits_guard_page() {
+            black_box(recurse(0));
+        }
+    }
+}
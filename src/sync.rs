@@ -0,0 +1,131 @@
+use crate::x86::busy_loop_hint;
+use crate::x86::InterruptGuard;
+use core::cell::UnsafeCell;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+// 割り込みハンドラと共有できるスピンロック
+// ロックを持っている間は割り込みを禁止するので、同じCPUの割り込みハンドラが
+// ロックを待ち続けてデッドロックすることはない（割り込みの許可状態はガードを外すときに戻す）
+// 同じCPUで二重にロックするとデッドロックするので、入れ子になりうる場所ではtry_lockを使う
+pub struct SpinLock<T: ?Sized> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for SpinLock<T> {}
+unsafe impl<T: ?Sized + Send> Sync for SpinLock<T> {}
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+impl<T: ?Sized> SpinLock<T> {
+    // 割り込みを禁止してから、ロックが取れるまで待つ
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+        while !self.acquire() {
+            busy_loop_hint();
+        }
+        SpinLockGuard {
+            lock: self,
+            _interrupts: interrupts,
+        }
+    }
+
+    // ロックが取れなければ待たずにNoneを返す（割り込みの許可状態も元のまま）
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let interrupts = InterruptGuard::new();
+        self.acquire().then_some(SpinLockGuard {
+            lock: self,
+            _interrupts: interrupts,
+        })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // ガードを外さずにロックを解放する
+    // ロックを持ったままpanicした後に、出力などを続けるために使う
+    // ガードが持っていた割り込みの許可状態は戻らない
+    /// # Safety
+    /// ロックを持っていたガードが二度と使われない（持ち主がpanicして戻らないなど）ことを保証すること
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+pub struct SpinLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinLock<T>,
+    // ロックを解放してから割り込みの許可状態を戻すので、Dropの後に外れるフィールドにしておく
+    _interrupts: InterruptGuard,
+}
+impl<T: ?Sized> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+impl<T: ?Sized> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+impl<T: ?Sized> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::x86::interrupts_enabled;
+    use core::mem::forget;
+
+    #[test_case]
+    fn lock_disables_interrupts_and_restores_them() {
+        let lock = SpinLock::new(1);
+        let before = interrupts_enabled();
+        {
+            let mut guard = lock.lock();
+            assert!(!interrupts_enabled());
+            *guard += 1;
+            assert!(lock.is_locked());
+            assert!(lock.try_lock().is_none());
+            // 取れなかったtry_lockは割り込みを許可しない
+            assert!(!interrupts_enabled());
+        }
+        assert_eq!(interrupts_enabled(), before);
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+        assert_eq!(interrupts_enabled(), before);
+    }
+    // ロックを持ったままガードが失われても（panicした場合など）、force_unlockで取り直せる
+    #[test_case]
+    fn force_unlock_releases_a_lost_guard() {
+        let lock = SpinLock::new(0u32);
+        let before = interrupts_enabled();
+        forget(lock.lock());
+        assert!(lock.try_lock().is_none());
+        unsafe { lock.force_unlock() };
+        *lock.lock() = 7;
+        assert_eq!(*lock.lock(), 7);
+        // 失われたガードの代わりに割り込みの許可状態を戻す
+        if before {
+            crate::x86::enable_interrupts();
+        }
+        assert_eq!(interrupts_enabled(), before);
+    }
+}
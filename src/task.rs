@@ -0,0 +1,292 @@
+extern crate alloc;
+
+use crate::allocator::ALLOCATOR;
+use crate::result::Result;
+use alloc::alloc::GlobalAlloc;
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::mem::offset_of;
+use core::mem::replace;
+use spin::Mutex;
+
+// 1タスクあたりのカーネルスタックのサイズ
+pub const TASK_STACK_SIZE: usize = 64 * 1024;
+const TASK_STACK_LAYOUT: Layout =
+    unsafe { Layout::from_size_align_unchecked(TASK_STACK_SIZE, 4096) };
+
+// コンテキストスイッチで保存するレジスタ
+// SysV ABIのcallee-savedレジスタ（rbx, rbp, r12-r15）とrsp, ripだけを保存すればよい
+// （caller-savedレジスタはswitch_contextを呼び出す側が保存している）
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Context {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rip: u64,
+}
+// switch_contextのアセンブリで使うオフセットと一致していることを確認する
+const _: () = assert!(offset_of!(Context, rsp) == 0x00);
+const _: () = assert!(offset_of!(Context, rbx) == 0x08);
+const _: () = assert!(offset_of!(Context, rbp) == 0x10);
+const _: () = assert!(offset_of!(Context, r12) == 0x18);
+const _: () = assert!(offset_of!(Context, r13) == 0x20);
+const _: () = assert!(offset_of!(Context, r14) == 0x28);
+const _: () = assert!(offset_of!(Context, r15) == 0x30);
+const _: () = assert!(offset_of!(Context, rip) == 0x38);
+
+// 現在のレジスタをprevに保存し、nextのレジスタを復元してnext.ripへジャンプする
+// prevのripには戻り先（ラベル1）を保存するので、後でprevに切り替えられると
+// switch_contextの呼び出し元へ普通にリターンしたように見える
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    mov [rdi + 0x00], rsp
+    mov [rdi + 0x08], rbx
+    mov [rdi + 0x10], rbp
+    mov [rdi + 0x18], r12
+    mov [rdi + 0x20], r13
+    mov [rdi + 0x28], r14
+    mov [rdi + 0x30], r15
+    lea rax, [rip + 1f]
+    mov [rdi + 0x38], rax
+
+    mov rsp, [rsi + 0x00]
+    mov rbx, [rsi + 0x08]
+    mov rbp, [rsi + 0x10]
+    mov r12, [rsi + 0x18]
+    mov r13, [rsi + 0x20]
+    mov r14, [rsi + 0x28]
+    mov r15, [rsi + 0x30]
+    jmp [rsi + 0x38]
+1:
+    ret
+"#
+);
+
+// 新しいタスクが最初に実行する場所
+// r12にはエントリポイントが入っている（Task::newで設定する）
+global_asm!(
+    r#"
+.global task_entry_trampoline
+task_entry_trampoline:
+    mov rdi, r12
+    and rsp, -16
+    call task_main
+    ud2
+"#
+);
+
+extern "sysv64" {
+    fn switch_context(prev: *mut Context, next: *const Context);
+    fn task_entry_trampoline();
+}
+
+// タスクのエントリポイントを呼び出し、戻ってきたらスケジューラに制御を返す
+#[no_mangle]
+extern "sysv64" fn task_main(entry: usize) -> ! {
+    // Task::newでfn()をusizeにして渡しているので元に戻す
+    let entry: fn() = unsafe { core::mem::transmute(entry) };
+    // 直前に終了したタスクのスタックはこのタスクに切り替わった後でないと解放できない
+    reap_finished_tasks();
+    entry();
+    exit_current()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Ready,
+    Finished,
+}
+
+pub struct Task {
+    context: Context,
+    // ブート時のタスク（efi_main）はUEFIが用意したスタックを使うのでNone
+    stack: Option<*mut u8>,
+    state: TaskState,
+}
+
+impl Task {
+    pub fn new(entry: fn()) -> Result<Self> {
+        let stack = ALLOCATOR.alloc_with_options(TASK_STACK_LAYOUT);
+        if stack.is_null() {
+            return Err("Failed to allocate a task stack");
+        }
+        let stack_top = stack as u64 + TASK_STACK_SIZE as u64;
+        Ok(Self {
+            context: Context {
+                rsp: stack_top,
+                r12: entry as usize as u64,
+                rip: task_entry_trampoline as *const () as u64,
+                ..Default::default()
+            },
+            stack: Some(stack),
+            state: TaskState::Ready,
+        })
+    }
+    // 現在実行中のコンテキストを表すタスク（切り替え時にレジスタが保存される）
+    fn current() -> Self {
+        Self {
+            context: Context::default(),
+            stack: None,
+            state: TaskState::Ready,
+        }
+    }
+    pub fn state(&self) -> TaskState {
+        self.state
+    }
+}
+
+// スタックは各タスクが所有していて、他から参照されることはない
+unsafe impl Send for Task {}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        if let Some(stack) = self.stack {
+            unsafe { ALLOCATOR.dealloc(stack, TASK_STACK_LAYOUT) }
+        }
+    }
+}
+
+// 実行可能なタスクを順番に切り替えるラウンドロビンスケジューラ
+// タスクはBoxに入れておき、Contextのアドレスが移動しないようにする
+pub struct Scheduler {
+    current: Box<Task>,
+    ready: VecDeque<Box<Task>>,
+    // 切り替え中は終了したタスクのContextにもレジスタが書き込まれるので、こちらもBoxに入れておく
+    #[allow(clippy::vec_box)]
+    finished: Vec<Box<Task>>,
+}
+
+impl Scheduler {
+    fn new() -> Self {
+        Self {
+            current: Box::new(Task::current()),
+            ready: VecDeque::new(),
+            finished: Vec::new(),
+        }
+    }
+    pub fn spawn(&mut self, task: Task) {
+        self.ready.push_back(Box::new(task));
+    }
+    // 次に実行するタスクをcurrentにして、(前のタスク, 次のタスク)のContextを返す
+    // 実行可能なタスクが他になければNone
+    fn rotate(&mut self) -> Option<(*mut Context, *const Context)> {
+        let next = self.ready.pop_front()?;
+        let prev = replace(&mut self.current, next);
+        let prev = if prev.state == TaskState::Finished {
+            self.finished.push(prev);
+            self.finished.last_mut()
+        } else {
+            self.ready.push_back(prev);
+            self.ready.back_mut()
+        }?;
+        Some((
+            &mut prev.context as *mut Context,
+            &self.current.context as *const Context,
+        ))
+    }
+    pub fn num_tasks(&self) -> usize {
+        1 + self.ready.len()
+    }
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+// エントリポイントを新しいタスクとして登録する
+// タスクは呼び出し元がyield_nowしたときに初めて実行される
+pub fn spawn(entry: fn()) -> Result<()> {
+    let task = Task::new(entry)?;
+    SCHEDULER
+        .lock()
+        .get_or_insert_with(Scheduler::new)
+        .spawn(task);
+    Ok(())
+}
+
+// 他のタスクに実行を譲る
+// 実行可能なタスクが他になければ何もせずに戻る
+pub fn yield_now() {
+    let contexts = SCHEDULER.lock().as_mut().and_then(Scheduler::rotate);
+    let Some((prev, next)) = contexts else {
+        return;
+    };
+    // ロックを持ったまま切り替えると次のタスクがスケジューラを使えないので、
+    // ロックを外してから切り替える
+    unsafe { switch_context(prev, next) };
+    reap_finished_tasks();
+}
+
+// 現在のタスクを終了し、次のタスクに切り替える
+pub fn exit_current() -> ! {
+    let contexts = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("Scheduler is not initialized");
+        scheduler.current.state = TaskState::Finished;
+        scheduler.rotate()
+    };
+    let Some((prev, next)) = contexts else {
+        panic!("The last task exited");
+    };
+    unsafe { switch_context(prev, next) };
+    unreachable!("A finished task was resumed");
+}
+
+// 終了したタスクのスタックを解放する
+// 実行中のタスクのスタックは解放できないので、切り替えが終わった後に呼ぶ
+fn reap_finished_tasks() {
+    let finished = SCHEDULER
+        .lock()
+        .as_mut()
+        .map(|s| core::mem::take(&mut s.finished));
+    // dropでアロケータを呼ぶので、スケジューラのロックを外してから解放する
+    drop(finished);
+}
+
+// 現在のタスクも含めた、終了していないタスクの数
+pub fn num_tasks() -> usize {
+    SCHEDULER.lock().as_ref().map_or(1, Scheduler::num_tasks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    static LOG: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+    fn task_a() {
+        LOG.lock().push(1);
+        yield_now();
+        LOG.lock().push(3);
+    }
+    fn task_b() {
+        LOG.lock().push(2);
+        yield_now();
+        LOG.lock().push(4);
+    }
+
+    #[test_case]
+    fn yielding_tasks_interleave() {
+        LOG.lock().clear();
+        spawn(task_a).expect("spawn failed");
+        spawn(task_b).expect("spawn failed");
+        assert_eq!(num_tasks(), 3);
+        while num_tasks() > 1 {
+            yield_now();
+        }
+        assert_eq!(*LOG.lock(), [1, 2, 3, 4]);
+    }
+    #[test_case]
+    fn yield_without_other_tasks_returns() {
+        yield_now();
+        assert_eq!(num_tasks(), 1);
+    }
+}
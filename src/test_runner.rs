@@ -1,40 +1,1036 @@
-use crate::qemu::exit_qemu;
+extern crate alloc;
+
+use crate::allocator::alloc_stats;
+use crate::allocator::heap_available;
+use crate::allocator::heap_size;
+use crate::allocator::reset_after_panic;
+use crate::allocator::AllocStats;
+use crate::klog;
+use crate::panic::halt_or_reboot;
+use crate::panic::report_panic;
+use crate::panic::EmergencyWriter;
+use crate::print::reset_printing;
+use crate::print::TestResult;
+use crate::qemu::exit_qemu_with;
 use crate::qemu::QemuExitCode;
+use crate::serial::switch_to_sync_tx;
 use crate::serial::SerialPort;
+use crate::tsc::tsc;
+use crate::x86::enable_interrupts;
+use crate::x86::interrupts_enabled;
+use crate::x86::rdtsc;
+use alloc::vec::Vec;
 use core::any::type_name;
+use core::fmt;
 use core::fmt::Write;
+use core::hint::black_box;
 use core::panic::PanicInfo;
+use core::slice;
+use core::str::from_utf8;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
 
 pub trait TestTable {
-    fn run(&self, writer: &mut SerialPort);
+    // 実行結果の表示に使うテストの名前
+    fn name(&self) -> &'static str;
+    fn run_test(&self) -> TestResult;
+    // panicすることを期待するテストなら、panicのメッセージに含まれるべき文字列
+    // （Some("")ならどんなメッセージでもよい）
+    fn expected_panic(&self) -> Option<&'static str> {
+        None
+    }
+    // ベンチマークならtrue（KERNEL_BENCHを指定したときだけ実行する）
+    fn is_bench(&self) -> bool {
+        false
+    }
+    // グローバルなキャッシュなど、確保したメモリを意図して残すテストならtrue
+    fn allows_leaks(&self) -> bool {
+        false
+    }
+    // ヒープを使うテストならtrue（ヒープが使えないときは実行せずにスキップする）
+    // 使わないことが分かっているテストだけがfalseを返す
+    fn requires_heap(&self) -> bool {
+        true
+    }
 }
+// #[test_case]を付けた関数
+// 関数の型名はそのままパス付きの関数名になるが、クロージャでは読めない名前になる
 impl<T> TestTable for T
 where
     T: Fn(),
 {
-    // テストの実行前と実行後にログ出力
-    fn run(&self, writer: &mut SerialPort) {
-        writeln!(writer, "[RUNNING] >>> {}", type_name::<T>()).unwrap();
+    fn name(&self) -> &'static str {
+        type_name::<T>()
+    }
+    fn run_test(&self) -> TestResult {
         self();
-        writeln!(writer, "[PASS   ] <<< {}", type_name::<T>()).unwrap();
+        Ok(())
+    }
+}
+
+// 名前を付けて登録するテスト（kernel_test!で作る）
+// kassert!などを使うテストはTestResultを返すので、()を返すテストもそれに合わせて登録する
+pub struct TestDescriptor {
+    pub name: &'static str,
+    pub func: fn() -> TestResult,
+    pub expected_panic: Option<&'static str>,
+    pub allow_leaks: bool,
+    pub requires_heap: bool,
+}
+impl TestTable for TestDescriptor {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn run_test(&self) -> TestResult {
+        (self.func)()
+    }
+    fn expected_panic(&self) -> Option<&'static str> {
+        self.expected_panic
+    }
+    fn allows_leaks(&self) -> bool {
+        self.allow_leaks
+    }
+    fn requires_heap(&self) -> bool {
+        self.requires_heap
+    }
+}
+
+// 定義した場所で関数名を文字列にして、テストとして登録する
+// kernel_test! { fn name() { ... } }
+// kassert!などを使うテストは kernel_test! { fn name() -> TestResult { ...; Ok(()) } }
+// panicすることを確かめるテストには#[should_panic]か#[should_panic(expected = "...")]を付ける
+// 確保したメモリを意図して残すテストには#[allow_leaks]を付ける
+// ヒープを使わないテストには#[no_heap]を付ける（ヒープの初期化に失敗しても実行される）
+// （#[test_case]を付けた要素は名前で参照できないので、登録せずにTestDescriptorだけを作る
+// kernel_test!(@descriptor fn name() { ... })も用意している）
+#[macro_export]
+macro_rules! kernel_test {
+    (@build $expected:expr, $allow_leaks:expr, $requires_heap:expr, fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::test_runner::TestDescriptor {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: {
+                fn $name() $(-> $ret)? $body
+                fn run() -> $crate::print::TestResult {
+                    $crate::print::IntoTestResult::into_test_result($name())
+                }
+                run
+            },
+            expected_panic: $expected,
+            allow_leaks: $allow_leaks,
+            requires_heap: $requires_heap,
+        }
+    };
+    (@descriptor #[allow_leaks] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@build None, true, true, fn $name() $(-> $ret)? $body)
+    };
+    (@descriptor #[no_heap] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@build None, false, false, fn $name() $(-> $ret)? $body)
+    };
+    (@descriptor $expected:expr, fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@build $expected, false, true, fn $name() $(-> $ret)? $body)
+    };
+    (@descriptor fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@descriptor None, fn $name() $(-> $ret)? $body)
+    };
+    (@register $expected:expr, $allow_leaks:expr, $requires_heap:expr, fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::test_runner::TestDescriptor =
+            $crate::kernel_test!(@build $expected, $allow_leaks, $requires_heap, fn $name() $(-> $ret)? $body);
+    };
+    (#[should_panic(expected = $msg:literal)] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@register Some($msg), false, true, fn $name() $(-> $ret)? $body);
+    };
+    (#[should_panic] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@register Some(""), false, true, fn $name() $(-> $ret)? $body);
+    };
+    (#[allow_leaks] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@register None, true, true, fn $name() $(-> $ret)? $body);
+    };
+    (#[no_heap] fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@register None, false, false, fn $name() $(-> $ret)? $body);
+    };
+    (fn $name:ident() $(-> $ret:ty)? $body:block) => {
+        $crate::kernel_test!(@register None, false, true, fn $name() $(-> $ret)? $body);
+    };
+}
+
+// 名前を付けて登録するベンチマーク（kernel_bench!で作る）
+pub struct BenchDescriptor {
+    pub name: &'static str,
+    pub func: fn(),
+}
+impl TestTable for BenchDescriptor {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn run_test(&self) -> TestResult {
+        (self.func)();
+        Ok(())
+    }
+    fn is_bench(&self) -> bool {
+        true
+    }
+}
+
+// 繰り返し実行して1回あたりのサイクル数を測るベンチマークを登録する
+// 準備が必要なら、準備をしてからクロージャを返すブロックを渡す
+// kernel_bench!(name, { let mut buf = ...; move || { ... } });
+// 結果を使わない計算は最適化で消されることがあるので、core::hint::black_boxに通すこと
+#[macro_export]
+macro_rules! kernel_bench {
+    ($name:ident, $f:expr $(,)?) => {
+        #[test_case]
+        #[allow(non_upper_case_globals)]
+        static $name: $crate::test_runner::BenchDescriptor = $crate::test_runner::BenchDescriptor {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: {
+                fn $name() {
+                    $crate::test_runner::bench(stringify!($name), $f);
+                }
+                $name
+            },
+        };
+    };
+}
+
+// ベンチマークの結果（1回あたりのサイクル数）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchStats {
+    pub iterations: usize,
+    pub min: u64,
+    pub median: u64,
+    pub mean: u64,
+}
+// 集計スクリプトで読めるように、形式を変えないこと
+impl fmt::Display for BenchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} cycles/iter (min {}, mean {}, {} iters)",
+            self.median, self.min, self.mean, self.iterations
+        )
+    }
+}
+
+// KERNEL_BENCHに空でない値を指定してビルドすると、ベンチマークも実行する
+// KERNEL_BENCH=1 cargo test
+const BENCH_ENABLED: bool = matches!(option_env!("KERNEL_BENCH"), Some(v) if !v.is_empty());
+// 1つのベンチマークにかける時間の目安
+const BENCH_TARGET_MS: u64 = 100;
+// TSCがキャリブレーションされていないときは、1GHzとみなして回数を決める
+const BENCH_FALLBACK_CYCLES_PER_MS: u64 = 1_000_000;
+const BENCH_WARMUP_ITERATIONS: usize = 3;
+const BENCH_MIN_ITERATIONS: usize = 5;
+const BENCH_MAX_ITERATIONS: usize = 10_000;
+
+// fを合計でtarget_cycles程度になる回数だけ実行して、1回ごとのサイクル数を集計する
+fn measure(mut f: impl FnMut(), target_cycles: u64) -> BenchStats {
+    // 最初の何回かはキャッシュなどが温まっていないので測らず、最後の1回で回数を決める
+    let mut estimate = 1;
+    for _ in 0..BENCH_WARMUP_ITERATIONS {
+        let start = rdtsc();
+        black_box(&mut f)();
+        estimate = rdtsc().wrapping_sub(start).max(1);
+    }
+    let iterations =
+        ((target_cycles / estimate) as usize).clamp(BENCH_MIN_ITERATIONS, BENCH_MAX_ITERATIONS);
+    // 測定中に確保しないように、先に確保しておく
+    let mut samples = Vec::with_capacity(iterations);
+    for _ in 0..iterations {
+        let start = rdtsc();
+        black_box(&mut f)();
+        samples.push(rdtsc().wrapping_sub(start));
+    }
+    samples.sort_unstable();
+    BenchStats {
+        iterations,
+        min: samples[0],
+        median: samples[iterations / 2],
+        mean: samples.iter().sum::<u64>() / iterations as u64,
+    }
+}
+
+// ベンチマークを実行して、結果をシリアルに出力する
+pub fn bench(name: &str, f: impl FnMut()) -> BenchStats {
+    let cycles_per_ms = match tsc().cycles_per_ms() {
+        0 => BENCH_FALLBACK_CYCLES_PER_MS,
+        c => c,
+    };
+    let stats = measure(f, cycles_per_ms * BENCH_TARGET_MS);
+    writeln!(SerialPort::default(), "[BENCH  ] {name}: {stats}").unwrap();
+    stats
+}
+
+// テストの実行状態
+// panicしたテストからは戻れないので、panicハンドラが次のテストから実行を再開できるようにstaticに置く
+// テストの一覧は呼び出し元（run_unit_tests）のスタックにあり、例外から再開するときは
+// スタックを一番上から使い直すので、ヒープにコピーしてそのアドレスと長さを覚えておく
+static TESTS: Mutex<(usize, usize)> = Mutex::new((0, 0));
+// 実行中のテストの番号（usize::MAXなら実行中のテストはない）
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(usize::MAX);
+// テストを始めたときに割り込みが有効だったか（panicしたテストが無効にしたままのことがある）
+static INTERRUPTS_AT_START: AtomicBool = AtomicBool::new(false);
+// 成功・失敗したテストの数
+static PASSED: AtomicUsize = AtomicUsize::new(0);
+static FAILED: AtomicUsize = AtomicUsize::new(0);
+// 最後にまとめて表示する、失敗したテストの番号（入りきらない分は数だけ数える）
+const MAX_FAILURES_LISTED: usize = 32;
+static FAILED_TESTS: Mutex<[usize; MAX_FAILURES_LISTED]> = Mutex::new([0; MAX_FAILURES_LISTED]);
+
+fn record_failure(index: usize) {
+    let n = FAILED.fetch_add(1, Ordering::SeqCst);
+    // panicしたテストがロックを持っていることはないはずだが、念のため待たない
+    if let (true, Some(mut failed)) = (n < MAX_FAILURES_LISTED, FAILED_TESTS.try_lock()) {
+        failed[n] = index;
+    }
+}
+
+// テストを失敗させる
+// assert!では書きにくい条件で、メッセージを付けてテストを失敗させるときに使う
+#[macro_export]
+macro_rules! fail {
+    ($($arg:tt)*) => {
+        panic!("test failed: {}", format_args!($($arg)*))
+    };
+}
+
+// テストの結果が期待通りならtrue
+// panic_messageはテストがpanicしたときのメッセージ（panicしなかったらNone）
+fn outcome_matches(expected_panic: Option<&str>, panic_message: Option<&str>) -> bool {
+    match (expected_panic, panic_message) {
+        (None, None) => true,
+        (Some(expected), Some(message)) => message.contains(expected),
+        _ => false,
+    }
+}
+
+// テストにかかった時間
+// TSCがキャリブレーションされていなければ（cycles_per_msが0なら）サイクル数のまま表示する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TestDuration {
+    cycles: u64,
+    cycles_per_ms: u64,
+}
+impl TestDuration {
+    fn since(start: u64) -> Self {
+        Self {
+            cycles: rdtsc().wrapping_sub(start),
+            cycles_per_ms: tsc().cycles_per_ms(),
+        }
+    }
+}
+// 浮動小数点を使わずに、1ms未満はマイクロ秒、それ以上はミリ秒を小数点以下1桁で表示する
+impl fmt::Display for TestDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.cycles_per_ms == 0 {
+            return write!(f, "{} cycles", self.cycles);
+        }
+        let us = (self.cycles as u128 * 1000 / self.cycles_per_ms as u128) as u64;
+        if us < 1000 {
+            write!(f, "{us} us")
+        } else {
+            write!(f, "{}.{} ms", us / 1000, us % 1000 / 100)
+        }
+    }
+}
+
+// 実行中のテストを始めたときのTSC（panicしたテストの時間も測れるようにstaticに置く）
+static TEST_START: AtomicU64 = AtomicU64::new(0);
+// 全テストの合計時間と、一番遅かったテスト（番号と時間）
+static TOTAL_CYCLES: AtomicU64 = AtomicU64::new(0);
+static SLOWEST_TEST: AtomicUsize = AtomicUsize::new(usize::MAX);
+static SLOWEST_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+fn record_duration(index: usize, duration: TestDuration) {
+    TOTAL_CYCLES.fetch_add(duration.cycles, Ordering::Relaxed);
+    if duration.cycles >= SLOWEST_CYCLES.load(Ordering::Relaxed) {
+        SLOWEST_CYCLES.store(duration.cycles, Ordering::Relaxed);
+        SLOWEST_TEST.store(index, Ordering::Relaxed);
+    }
+}
+
+// KERNEL_TEST_STRICT_LEAKSに空でない値を指定してビルドすると、メモリを残したテストを失敗にする
+// （指定しなければ警告だけを表示する）
+const LEAK_CHECK_STRICT: bool =
+    matches!(option_env!("KERNEL_TEST_STRICT_LEAKS"), Some(v) if !v.is_empty());
+
+// テストの前後で、確保されたままのメモリがどれだけ増えたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LeakDelta {
+    bytes: isize,
+    allocations: isize,
+}
+impl LeakDelta {
+    // 前後で変わっていなければNone
+    fn between(before: AllocStats, after: AllocStats) -> Option<Self> {
+        let delta = Self {
+            bytes: after.allocated_bytes.wrapping_sub(before.allocated_bytes) as isize,
+            allocations: after
+                .live_allocation_count
+                .wrapping_sub(before.live_allocation_count) as isize,
+        };
+        (delta.bytes != 0 || delta.allocations != 0).then_some(delta)
+    }
+}
+impl fmt::Display for LeakDelta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:+} bytes, {:+} allocations",
+            self.bytes, self.allocations
+        )
+    }
+}
+
+// テストの実行前と実行後にログ出力
+// panicしなかったテストが成功したらtrueと、かかった時間を返す
+fn run_one(test: &dyn TestTable, writer: &mut impl Write) -> (bool, TestDuration) {
+    writeln!(writer, "[RUNNING] >>> {}", test.name()).unwrap();
+    let start = rdtsc();
+    TEST_START.store(start, Ordering::SeqCst);
+    // ランナー自身の確保を含めないように、テストを呼ぶ直前に記録する
+    let before = alloc_stats();
+    #[cfg(feature = "alloc_tracking")]
+    let checkpoint = crate::alloc_track::checkpoint();
+    let result = test.run_test();
+    let after = alloc_stats();
+    let duration = TestDuration::since(start);
+    // kassert!などが失敗した
+    if let Err(failure) = result {
+        writeln!(writer, "[FAIL   ] <<< {}: {failure}", test.name()).unwrap();
+        return (false, duration);
+    }
+    if outcome_matches(test.expected_panic(), None) {
+        match LeakDelta::between(before, after) {
+            Some(leak) if !test.allows_leaks() => {
+                writeln!(writer, "[LEAK   ] <<< {}: {leak}", test.name()).unwrap();
+                // どこで確保したものが残っているかを表示する
+                #[cfg(feature = "alloc_tracking")]
+                crate::alloc_track::dump_leaks(checkpoint);
+                if LEAK_CHECK_STRICT {
+                    writeln!(writer, "[FAIL   ] <<< {} (leaked memory)", test.name()).unwrap();
+                    return (false, duration);
+                }
+            }
+            _ => {}
+        }
+        writeln!(writer, "[PASS   ] <<< {} ({duration})", test.name()).unwrap();
+        (true, duration)
+    } else {
+        writeln!(writer, "[FAIL   ] <<< {} (did not panic)", test.name()).unwrap();
+        (false, duration)
+    }
+}
+
+// 名前にこの文字列を含むテストだけを実行する（ビルド時に環境変数で指定する）
+// KERNEL_TEST_FILTER=graphics cargo test
+const TEST_FILTER: Option<&str> = option_env!("KERNEL_TEST_FILTER");
+
+// フィルタが指定されていないか空なら、全てのテストを実行する
+fn matches_filter(test: &dyn TestTable, filter: Option<&str>) -> bool {
+    filter.is_none_or(|f| test.name().contains(f))
+}
+
+// このビルドで実行するテストならtrue
+fn is_selected(test: &dyn TestTable) -> bool {
+    matches_filter(test, TEST_FILTER) && (BENCH_ENABLED || !test.is_bench())
+}
+
+// 終了ステータスの確認用に、テストを実行せずに指定した経路で終了する（ビルド時に環境変数で指定する）
+// KERNEL_TEST_FORCE_EXIT=panic cargo test
+// 失敗したテストやフィルタに一致しない場合は、failing_testsやKERNEL_TEST_FILTERで確かめる
+const KERNEL_TEST_FORCE_EXIT: Option<&str> = option_env!("KERNEL_TEST_FORCE_EXIT");
+
+fn force_exit(path: Option<&str>) {
+    match path {
+        None | Some("") => {}
+        Some("panic") => panic!("forced by KERNEL_TEST_FORCE_EXIT"),
+        // 実際に確保に失敗させて、アロケータのエラーハンドラを通す
+        Some("oom") => {
+            let v: Vec<u8> = Vec::with_capacity(1 << 40);
+            black_box(v);
+        }
+        Some("skip") => exit_qemu_with(QemuExitCode::Skipped),
+        // isa-debug-exitを使わずに電源を切る（QEMUは終了ステータス0で終わる）
+        Some("poweroff") => crate::power::poweroff(),
+        Some(other) => panic!("unknown KERNEL_TEST_FORCE_EXIT: {other:?}"),
+    }
+}
+
+// ヒープが使えないときに、テストの一覧をコピーしておく場所
+const MAX_TESTS_WITHOUT_HEAP: usize = 1024;
+struct StaticTests([&'static dyn TestTable; MAX_TESTS_WITHOUT_HEAP]);
+// テストランナーはBSPでしか動かない
+unsafe impl Send for StaticTests {}
+fn unused_slot() {}
+static TESTS_WITHOUT_HEAP: Mutex<StaticTests> =
+    Mutex::new(StaticTests([&unused_slot; MAX_TESTS_WITHOUT_HEAP]));
+
+// ヒープが使えずにテストをスキップするなら、その理由
+fn heap_skip_reason(test: &dyn TestTable, heap_available: bool) -> Option<&'static str> {
+    (test.requires_heap() && !heap_available).then_some("requires the heap, which is not available")
+}
+
+// テストの一覧を、最初のテストの前にコピーしてその場所と長さを返す
+// （テストの間に解放されない（リークとして数えられない）ように、ヒープにコピーしたものは解放しない）
+// ヒープが使えなければ、固定長の配列にコピーする（入りきらない分は実行しない）
+fn copy_tests(tests: &[&dyn TestTable]) -> (usize, usize) {
+    if heap_available() {
+        let tests: &[&dyn TestTable] = Vec::from(tests).leak();
+        return (tests.as_ptr() as usize, tests.len());
     }
+    let mut copied = TESTS_WITHOUT_HEAP.lock();
+    let len = tests.len().min(MAX_TESTS_WITHOUT_HEAP);
+    for (dst, src) in copied.0.iter_mut().zip(tests) {
+        // 登録されたテストは関数かstaticなので、ずっと有効
+        *dst = unsafe { core::mem::transmute::<&dyn TestTable, &'static dyn TestTable>(*src) };
+    }
+    (copied.0.as_ptr() as usize, len)
 }
 
 // テストの実行
+// efi_mainがinit_basic_runtimeでヒープやタイマーを初期化してから呼ぶ
 pub fn test_runner(tests: &[&dyn TestTable]) -> ! {
-    let mut sw = SerialPort::new_for_com1();
-    writeln!(sw, "Running {} tests...", tests.len()).unwrap();
-    for test in tests {
-        test.run(&mut sw);
+    *TESTS.lock() = copy_tests(tests);
+    let tests = registered_tests(*TESTS.lock());
+    INTERRUPTS_AT_START.store(interrupts_enabled(), Ordering::Relaxed);
+    force_exit(KERNEL_TEST_FORCE_EXIT);
+    let mut sw = SerialPort::default();
+    let selected = tests.iter().filter(|t| is_selected(**t)).count();
+    if !heap_available() {
+        writeln!(
+            sw,
+            "The heap is not available; tests that need it will be skipped"
+        )
+        .unwrap();
+    }
+    match TEST_FILTER {
+        Some(filter) if !filter.is_empty() => {
+            writeln!(
+                sw,
+                "Running {selected} of {} tests matching {filter:?} (heap: {} KiB)...",
+                tests.len(),
+                heap_size() / 1024
+            )
+            .unwrap();
+            // 一致するテストがないまま成功扱いにすると、フィルタの打ち間違いに気付けない
+            if selected == 0 {
+                writeln!(sw, "No tests matched the filter").unwrap();
+                exit_qemu_with(QemuExitCode::NoTestsMatched);
+            }
+        }
+        _ => writeln!(
+            sw,
+            "Running {} tests (heap: {} KiB)...",
+            tests.len(),
+            heap_size() / 1024
+        )
+        .unwrap(),
+    }
+    run_tests_from(0)
+}
+
+// test_runnerに渡されたテストの一覧
+fn registered_tests((addr, len): (usize, usize)) -> &'static [&'static dyn TestTable] {
+    if len == 0 {
+        return &[];
+    }
+    // test_runnerがヒープにコピーしたまま解放しないので、ずっと有効
+    unsafe { slice::from_raw_parts(addr as *const &dyn TestTable, len) }
+}
+
+// first番目以降のテストを実行する
+// panicから再開したときは、panicしたテストのスタックの上に積み重なっていく
+// （panicするテストは少ないので、それで足りる）
+fn run_tests_from(first: usize) -> ! {
+    let tests = registered_tests(*TESTS.lock());
+    let mut sw = SerialPort::default();
+    for (i, test) in tests.iter().enumerate().skip(first) {
+        if !is_selected(*test) {
+            writeln!(sw, "[SKIP   ] --- {}", test.name()).unwrap();
+            continue;
+        }
+        if let Some(reason) = heap_skip_reason(*test, heap_available()) {
+            writeln!(sw, "[SKIP   ] --- {} ({reason})", test.name()).unwrap();
+            continue;
+        }
+        CURRENT_TEST.store(i, Ordering::SeqCst);
+        let (passed, duration) = run_one(*test, &mut sw);
+        if passed {
+            PASSED.fetch_add(1, Ordering::SeqCst);
+            record_duration(i, duration);
+        } else {
+            record_failure(i);
+        }
+    }
+    CURRENT_TEST.store(usize::MAX, Ordering::SeqCst);
+    let cycles_per_ms = tsc().cycles_per_ms();
+    let total = TestDuration {
+        cycles: TOTAL_CYCLES.load(Ordering::Relaxed),
+        cycles_per_ms,
+    };
+    let run = tests
+        .iter()
+        .filter(|t| is_selected(**t) && heap_skip_reason(**t, heap_available()).is_none())
+        .count();
+    let passed = PASSED.load(Ordering::SeqCst);
+    let failed = FAILED.load(Ordering::SeqCst);
+    writeln!(sw, "Completed {run} tests! ({total})").unwrap();
+    writeln!(sw, "---- summary ----").unwrap();
+    writeln!(sw, "passed:  {passed}").unwrap();
+    writeln!(sw, "failed:  {failed}").unwrap();
+    writeln!(sw, "skipped: {}", tests.len() - run).unwrap();
+    if let Some(slowest) = tests.get(SLOWEST_TEST.load(Ordering::Relaxed)) {
+        let duration = TestDuration {
+            cycles: SLOWEST_CYCLES.load(Ordering::Relaxed),
+            cycles_per_ms,
+        };
+        writeln!(sw, "slowest: {} ({duration})", slowest.name()).unwrap();
+    }
+    for index in FAILED_TESTS.lock().iter().take(failed) {
+        writeln!(sw, "[FAIL   ] {}", tests[*index].name()).unwrap();
+    }
+    if failed > MAX_FAILURES_LISTED {
+        writeln!(sw, "... and {} more", failed - MAX_FAILURES_LISTED).unwrap();
+    }
+    if failed > 0 {
+        exit_qemu_with(QemuExitCode::TestFailed);
+    }
+    exit_qemu_with(QemuExitCode::Success);
+}
+
+// panicのメッセージを入れておくバッファ
+// ヒープが壊れてpanicした場合もあるので、スタック上に置く（入りきらない分は捨てる）
+struct MessageBuf {
+    buf: [u8; 256],
+    len: usize,
+}
+impl MessageBuf {
+    fn new() -> Self {
+        Self {
+            buf: [0; 256],
+            len: 0,
+        }
+    }
+    fn as_str(&self) -> &str {
+        match from_utf8(&self.buf[..self.len]) {
+            Ok(s) => s,
+            // 文字の途中で切れていたら、その手前まで
+            Err(e) => from_utf8(&self.buf[..e.valid_up_to()]).unwrap_or_default(),
+        }
+    }
+}
+impl Write for MessageBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
     }
-    writeln!(sw, "Completed {} tests!", tests.len()).unwrap();
-    exit_qemu(QemuExitCode::Success);
 }
 
 // パニックハンドラ
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    let mut sw = SerialPort::new_for_com1();
-    writeln!(sw, "PANIC during test: {info:?}").unwrap();
-    exit_qemu(QemuExitCode::Fail);
+    switch_to_sync_tx();
+    // 出力の途中でpanicしたかもしれないので、print!やロックを使わずに直接シリアルに書く
+    let mut sw = EmergencyWriter;
+    let current = CURRENT_TEST.load(Ordering::SeqCst);
+    // 例外テストでは、例外ハンドラが呼ばれた結果のpanicを成功として扱う
+    #[cfg(feature = "exception_tests")]
+    if let Some(index) = crate::x86::take_last_exception() {
+        continue_after_exception(&mut sw, current, index, info);
+    }
+    let test = TESTS
+        .try_lock()
+        .and_then(|tests| registered_tests(*tests).get(current).copied());
+    let Some(test) = test else {
+        // テストの外（テストの一覧を作る前や、結果を表示している途中）でpanicした
+        // テストでないときと同じ経路で報告して終了する（scripts/check_exit_codes.shで確かめる）
+        writeln!(sw, "PANIC outside of tests").unwrap();
+        dump_klog(&mut sw);
+        report_panic(info);
+        halt_or_reboot(QemuExitCode::Panicked);
+    };
+    let mut message = MessageBuf::new();
+    let _ = write!(message, "{}", info.message());
+    if outcome_matches(test.expected_panic(), Some(message.as_str())) {
+        let duration = TestDuration::since(TEST_START.load(Ordering::SeqCst));
+        record_duration(current, duration);
+        PASSED.fetch_add(1, Ordering::SeqCst);
+        writeln!(
+            sw,
+            "[PASS   ] <<< {} ({duration}, panicked as expected)",
+            test.name()
+        )
+        .unwrap();
+    } else {
+        record_failure(current);
+        writeln!(sw, "[FAIL   ] <<< {}: {}", test.name(), message.as_str()).unwrap();
+        if let Some(location) = info.location() {
+            writeln!(sw, "          at {location}").unwrap();
+        }
+        dump_klog(&mut sw);
+    }
+    // 次のテストから続ける
+    reset_printing();
+    reset_after_panic();
+    if INTERRUPTS_AT_START.load(Ordering::Relaxed) {
+        enable_interrupts();
+    }
+    run_tests_from(current + 1)
+}
+
+// 例外ハンドラの中（ISTのスタック）でpanicしたテストの結果を記録して、次のテストに進む
+// should_panicでメッセージが指定されていれば、例外ハンドラのpanicがそれに合うかも確かめる
+// ISTのスタックは次の割り込みで上書きされるので、カーネルのスタックに戻ってから続ける
+#[cfg(feature = "exception_tests")]
+fn continue_after_exception(
+    sw: &mut impl Write,
+    current: usize,
+    index: usize,
+    info: &PanicInfo,
+) -> ! {
+    let mut message = MessageBuf::new();
+    let _ = write!(message, "{}", info.message());
+    let expected = TESTS
+        .try_lock()
+        .and_then(|tests| registered_tests(*tests).get(current).copied())
+        .and_then(|test| test.expected_panic());
+    if expected.is_none_or(|e| message.as_str().contains(e)) {
+        PASSED.fetch_add(1, Ordering::SeqCst);
+        writeln!(sw, "[PASS   ] exception {index:#04X} was handled").unwrap();
+    } else {
+        record_failure(current);
+        writeln!(
+            sw,
+            "[FAIL   ] exception {index:#04X} was handled, but: {}",
+            message.as_str()
+        )
+        .unwrap();
+    }
+    // 割り込みはカーネルのスタックに移ってから許可する
+    match crate::stack::kernel_stack() {
+        Some(stack) => crate::stack::run_on_stack(stack, current + 1, resume_tests),
+        None => resume_tests(current + 1),
+    }
+}
+
+#[cfg(feature = "exception_tests")]
+fn resume_tests(next: usize) -> ! {
+    if INTERRUPTS_AT_START.load(Ordering::Relaxed) {
+        enable_interrupts();
+    }
+    run_tests_from(next)
+}
+
+// 失敗したテストの直前に出力されたメッセージを表示する
+fn dump_klog(sw: &mut impl Write) {
+    // klogへの書き込み中にpanicした場合はロックが取れないので諦める
+    if klog::is_busy() {
+        writeln!(sw, "(klog is busy)").unwrap();
+        return;
+    }
+    writeln!(sw, "---- klog ----").unwrap();
+    let _ = klog::snapshot_tail(sw, 4096);
+    writeln!(sw, "---- end of klog ----").unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    extern crate alloc;
+
+    use super::*;
+    use alloc::string::String;
+
+    crate::kernel_test! {
+        fn registered_by_kernel_test() {
+            // テストランナーが今実行しているテストとして、このテストの記述子が登録されている
+            let current = CURRENT_TEST.load(Ordering::SeqCst);
+            let test = registered_tests(*TESTS.lock())[current];
+            assert_eq!(
+                test.name(),
+                concat!(module_path!(), "::registered_by_kernel_test")
+            );
+            assert_eq!(test.expected_panic(), None);
+            assert!(!test.allows_leaks());
+        }
+    }
+    crate::kernel_test! {
+        #[should_panic(expected = "expected failure")]
+        fn panics_with_expected_message() {
+            panic!("this is the expected failure");
+        }
+    }
+
+    // ヒープはテストの前に初期化されているので、大きな確保もできる
+    crate::kernel_test! {
+        fn allocates_a_few_mib() {
+            const SIZE: usize = 4 << 20;
+            let mut v: Vec<u8> = alloc::vec![0; SIZE];
+            v[SIZE - 1] = 0xaa;
+            assert_eq!(v.iter().map(|b| *b as usize).sum::<usize>(), 0xaa);
+        }
+    }
+    crate::kernel_test! {
+        #[no_heap]
+        fn runs_without_the_heap() {
+            assert_eq!([1u8, 2, 3].iter().sum::<u8>(), 6);
+        }
+    }
+    #[test_case]
+    fn heap_dependent_tests_are_skipped_without_heap() {
+        assert!(heap_available());
+        assert!(heap_size() >= 4 << 20);
+        let no_heap = crate::kernel_test!(@descriptor #[no_heap] fn no_heap() {});
+        let uses_heap = crate::kernel_test!(@descriptor fn uses_heap() {});
+        fn plain() {}
+        assert_eq!(heap_skip_reason(&no_heap, false), None);
+        assert!(heap_skip_reason(&uses_heap, false).is_some());
+        assert!(heap_skip_reason(&plain, false).is_some());
+        assert_eq!(heap_skip_reason(&uses_heap, true), None);
+    }
+
+    #[test_case]
+    fn kernel_test_prints_source_identifier() {
+        let named_test = crate::kernel_test!(@descriptor fn named_test() {});
+        assert_eq!(named_test.name(), "wasabi::test_runner::test::named_test");
+        let mut out = String::new();
+        let (passed, duration) = run_one(&named_test, &mut out);
+        assert!(passed);
+        assert_eq!(
+            out,
+            alloc::format!(
+                "[RUNNING] >>> wasabi::test_runner::test::named_test\n\
+                 [PASS   ] <<< wasabi::test_runner::test::named_test ({duration})\n"
+            )
+        );
+    }
+    #[test_case]
+    fn plain_test_fn_is_named_by_its_path() {
+        fn plain() {}
+        assert_eq!(
+            TestTable::name(&plain),
+            "wasabi::test_runner::test::plain_test_fn_is_named_by_its_path::plain"
+        );
+    }
+    #[test_case]
+    fn should_panic_outcomes() {
+        assert!(outcome_matches(Some("boom"), Some("big boom!")));
+        assert!(outcome_matches(Some(""), Some("anything")));
+        // 違うメッセージでpanicした
+        assert!(!outcome_matches(Some("boom"), Some("index out of bounds")));
+        // panicしなかった
+        assert!(!outcome_matches(Some("boom"), None));
+        assert!(!outcome_matches(None, Some("boom")));
+        assert!(outcome_matches(None, None));
+    }
+    #[test_case]
+    fn should_panic_test_that_returns_fails() {
+        let test = crate::kernel_test!(@descriptor Some(""), fn returns_normally() {});
+        let mut out = String::new();
+        assert!(!run_one(&test, &mut out).0);
+        assert!(out.ends_with(
+            "[FAIL   ] <<< wasabi::test_runner::test::returns_normally (did not panic)\n"
+        ));
+    }
+    // 中で動かしたテストが残したメモリは、外側のこのテストのリークとしても数えられる
+    crate::kernel_test! {
+        #[allow_leaks]
+        fn leaky_test_is_reported() {
+            let test = crate::kernel_test!(@descriptor fn leaks_a_box() {
+                alloc::boxed::Box::leak(alloc::boxed::Box::new([0u8; 64]));
+            });
+            let mut out = String::new();
+            let (passed, _) = run_one(&test, &mut out);
+            // 通常は警告だけで、KERNEL_TEST_STRICT_LEAKSを指定したときは失敗になる
+            assert_eq!(passed, !LEAK_CHECK_STRICT);
+            assert!(out.contains(
+                "[LEAK   ] <<< wasabi::test_runner::test::leaks_a_box: +64 bytes, +1 allocations\n"
+            ));
+        }
+    }
+    static GLOBAL_CACHE: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    crate::kernel_test! {
+        #[allow_leaks]
+        fn global_cache_with_allow_leaks_is_not_reported() {
+            let test = crate::kernel_test!(@descriptor #[allow_leaks] fn fills_global_cache() {
+                GLOBAL_CACHE.lock().push(42);
+            });
+            assert!(test.allows_leaks());
+            let mut out = String::new();
+            assert!(run_one(&test, &mut out).0);
+            assert!(!out.contains("[LEAK"));
+            assert_eq!(*GLOBAL_CACHE.lock(), [42]);
+        }
+    }
+    #[test_case]
+    fn leak_delta_is_signed() {
+        let stats = |allocated_bytes, live_allocation_count| AllocStats {
+            allocated_bytes,
+            live_allocation_count,
+        };
+        assert_eq!(LeakDelta::between(stats(100, 2), stats(100, 2)), None);
+        let freed = LeakDelta::between(stats(100, 2), stats(36, 1)).unwrap();
+        assert_eq!(alloc::format!("{freed}"), "-64 bytes, -1 allocations");
+    }
+    #[test_case]
+    fn duration_is_formatted_with_integers() {
+        let d = |cycles| {
+            alloc::format!(
+                "{}",
+                TestDuration {
+                    cycles,
+                    cycles_per_ms: 1_000_000
+                }
+            )
+        };
+        assert_eq!(d(12_400_000), "12.4 ms");
+        assert_eq!(d(1_000_000), "1.0 ms");
+        assert_eq!(d(500_000), "500 us");
+        let uncalibrated = TestDuration {
+            cycles: 1234,
+            cycles_per_ms: 0,
+        };
+        assert_eq!(alloc::format!("{uncalibrated}"), "1234 cycles");
+    }
+    #[test_case]
+    fn sleeping_test_reports_about_its_length() {
+        let test = crate::kernel_test!(@descriptor fn sleeps_10ms() {
+            crate::timer::sleep_ms(10);
+        });
+        let (passed, duration) = run_one(&test, &mut String::new());
+        assert!(passed);
+        assert!(duration.cycles_per_ms != 0);
+        let ms = duration.cycles / duration.cycles_per_ms;
+        assert!((8..50).contains(&ms), "{ms} ms");
+    }
+    static ALPHA_RUNS: AtomicUsize = AtomicUsize::new(0);
+    static BETA_RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn filter_runs_only_matching_tests() {
+        let alpha = crate::kernel_test!(@descriptor fn filter_alpha() {
+            ALPHA_RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+        let beta = crate::kernel_test!(@descriptor fn filter_beta() {
+            BETA_RUNS.fetch_add(1, Ordering::Relaxed);
+        });
+        let tests: [&dyn TestTable; 2] = [&alpha, &beta];
+        for filter in [Some("alpha"), Some("nothing"), Some(""), None] {
+            for test in tests.iter().filter(|t| matches_filter(**t, filter)) {
+                run_one(*test, &mut String::new());
+            }
+        }
+        // "alpha"と、フィルタなし（空文字列とNone）で3回
+        assert_eq!(ALPHA_RUNS.load(Ordering::Relaxed), 3);
+        assert_eq!(BETA_RUNS.load(Ordering::Relaxed), 2);
+    }
+    crate::kernel_test! {
+        fn kassert_passes_through_on_success() -> TestResult {
+            // 定数の式だと検査が意味を持たないので、実行時に決まる値で確かめる
+            let two = core::hint::black_box(1) + 1;
+            crate::kassert!(two == 2);
+            crate::kassert_eq!(two, 2);
+            crate::kassert_ne!(two, 3, );
+            Ok(())
+        }
+    }
+    #[test_case]
+    fn failing_kassert_eq_reports_both_values() {
+        let test = crate::kernel_test!(@descriptor fn kassert_eq_fails() -> TestResult {
+            let value = 1 + 1;
+            crate::kassert_eq!(value, 3);
+            crate::fail!("kassert_eq! did not return early");
+        });
+        let mut out = String::new();
+        // 失敗してもpanicせずに戻ってくるので、残りのテストも実行される
+        let (passed, _) = run_one(&test, &mut out);
+        assert!(!passed);
+        let line = line!() - 7;
+        assert!(out.contains(&alloc::format!(
+            "[FAIL   ] <<< wasabi::test_runner::test::kassert_eq_fails: src/test_runner.rs:{line}: \
+             assertion failed: value == 3\n  left: 2\n right: 3"
+        )));
+    }
+    // 長めの値でも、右側の値まで切れずに残る
+    #[test_case]
+    fn failing_kassert_eq_keeps_long_values() {
+        fn check(left: &str, right: &str) -> TestResult {
+            crate::kassert_eq!(left, right);
+            Ok(())
+        }
+        let left = "0123456789abcdef0123456789abcdef0123456789abcdef";
+        let right = "0123456789abcdef0123456789abcdef0123456789abcdeX";
+        let failure = check(left, right).unwrap_err();
+        assert_eq!(
+            failure.message(),
+            alloc::format!("assertion failed: left == right\n  left: {left:?}\n right: {right:?}")
+        );
+    }
+    #[test_case]
+    fn kassert_records_condition_and_message() {
+        fn check(x: u32) -> TestResult {
+            crate::kassert!(x < 10, "x is {}", x);
+            Ok(())
+        }
+        assert!(check(3).is_ok());
+        let failure = check(42).unwrap_err();
+        assert_eq!(failure.message(), "assertion failed: x < 10: x is 42");
+        assert_eq!(failure.file(), file!());
+    }
+    #[test_case]
+    fn bench_scales_iterations_to_target() {
+        let stats = measure(
+            || {
+                let mut x = 0u64;
+                for i in 0..black_box(1000) {
+                    x = x.wrapping_add(black_box(i));
+                }
+                black_box(x);
+            },
+            10_000_000,
+        );
+        assert!((BENCH_MIN_ITERATIONS..=BENCH_MAX_ITERATIONS).contains(&stats.iterations));
+        assert!(stats.min <= stats.median);
+        assert!(stats.min <= stats.mean);
+        assert!(stats.min > 0);
+        let stats = BenchStats {
+            iterations: 10,
+            min: 1790003,
+            median: 1843210,
+            mean: 1850000,
+        };
+        assert_eq!(
+            alloc::format!("{stats}"),
+            "1843210 cycles/iter (min 1790003, mean 1850000, 10 iters)"
+        );
+    }
+    #[test_case]
+    fn message_buf_truncates_on_char_boundary() {
+        let mut message = MessageBuf::new();
+        write!(message, "{}", "あ".repeat(100)).unwrap();
+        assert_eq!(message.as_str().chars().count(), 85);
+    }
+}
+
+// 失敗したテストの後も実行が続き、最後に失敗として終了することを確かめるテスト
+// cargo test --features failing_tests で実行すると、3つの結果が全て表示されてから失敗で終了する
+#[cfg(all(test, feature = "failing_tests"))]
+mod failing_tests {
+    crate::kernel_test! {
+        fn failing_tests_1_passes() {}
+    }
+    crate::kernel_test! {
+        fn failing_tests_2_fails() {
+            crate::fail!("deliberate failure");
+        }
+    }
+    crate::kernel_test! {
+        fn failing_tests_3_passes() {}
+    }
 }
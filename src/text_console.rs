@@ -0,0 +1,313 @@
+extern crate alloc;
+
+use crate::graphics::draw_font_fg_bg;
+use crate::graphics::Bitmap;
+use crate::graphics::GLYPH_HEIGHT;
+use crate::graphics::GLYPH_WIDTH;
+use crate::result::Result;
+use alloc::vec;
+use alloc::vec::Vec;
+
+// 画面を文字のマス目として扱うコンソール
+// 書き換えたマスの行だけを覚えておき、render()でその行だけを描き直す
+// ステータスバーのように一部だけを何度も書き換えても、画面全体を描き直さずに済む
+
+// 1マス分の文字と色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    pub fg: u32,
+    pub bg: u32,
+}
+impl Cell {
+    pub const fn new(ch: char, fg: u32, bg: u32) -> Self {
+        Self { ch, fg, bg }
+    }
+}
+// 黒地に白の空白
+impl Default for Cell {
+    fn default() -> Self {
+        Self::new(' ', 0xffffff, 0x000000)
+    }
+}
+
+pub struct TextConsole {
+    cols: usize,
+    rows: usize,
+    // 左上から行の順に並べたマス（cols * rows個）
+    cells: Vec<Cell>,
+    // 前回のrender()から書き換えた行
+    dirty: Vec<bool>,
+}
+impl TextConsole {
+    // 最初のrender()で全ての行が描かれるように、全ての行を書き換えたことにしておく
+    pub fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cols,
+            rows,
+            cells: vec![Cell::default(); cols * rows],
+            dirty: vec![true; rows],
+        }
+    }
+
+    // 画面に収まるだけのマス目を作る（端に余った半端なドットは使わない）
+    pub fn for_bitmap<T: Bitmap>(buf: &T) -> Self {
+        let cols = buf.width().max(0) as usize / GLYPH_WIDTH;
+        let rows = buf.height().max(0) as usize / GLYPH_HEIGHT;
+        Self::new(cols, rows)
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cell(&self, col: usize, row: usize) -> Option<Cell> {
+        self.index(col, row).map(|i| self.cells[i])
+    }
+
+    pub fn is_dirty(&self, row: usize) -> bool {
+        self.dirty.get(row).copied().unwrap_or(false)
+    }
+
+    fn index(&self, col: usize, row: usize) -> Option<usize> {
+        (col < self.cols && row < self.rows).then(|| row * self.cols + col)
+    }
+
+    // 同じ内容を書いた場合は、描き直す必要がないので行を汚さない
+    pub fn put_char(&mut self, col: usize, row: usize, cell: Cell) -> Result<()> {
+        let i = self
+            .index(col, row)
+            .ok_or("TextConsole: position is out of range")?;
+        if self.cells[i] != cell {
+            self.cells[i] = cell;
+            self.dirty[row] = true;
+        }
+        Ok(())
+    }
+
+    // (col, row)から右に向かって書く（行の右端からはみ出す部分は書かない）
+    // 書いた文字数を返す
+    pub fn write_str_at(
+        &mut self,
+        col: usize,
+        row: usize,
+        s: &str,
+        fg: u32,
+        bg: u32,
+    ) -> Result<usize> {
+        if self.index(col, row).is_none() {
+            return Err("TextConsole: position is out of range");
+        }
+        let mut written = 0;
+        for (c, ch) in (col..self.cols).zip(s.chars()) {
+            self.put_char(c, row, Cell::new(ch, fg, bg))?;
+            written += 1;
+        }
+        Ok(written)
+    }
+
+    // 1行をcellで埋める
+    pub fn fill_row(&mut self, row: usize, cell: Cell) -> Result<()> {
+        for col in 0..self.cols {
+            self.put_char(col, row, cell)?;
+        }
+        Ok(())
+    }
+
+    // 全てのマスを空白にする
+    pub fn clear(&mut self) {
+        for row in 0..self.rows {
+            // 行は範囲内なので失敗しない
+            let _ = self.fill_row(row, Cell::default());
+        }
+    }
+
+    // linesだけ上にずらして、下に空いた行を空白にする
+    // ずれた行は全て内容が変わりうるので、全ての行を描き直す
+    pub fn scroll_up(&mut self, lines: usize) {
+        let lines = lines.min(self.rows);
+        if lines == 0 {
+            return;
+        }
+        self.cells.copy_within(lines * self.cols.., 0);
+        let blank_from = (self.rows - lines) * self.cols;
+        self.cells[blank_from..].fill(Cell::default());
+        self.dirty.fill(true);
+    }
+
+    // 前回から書き換えた行だけを描いて、描いた行数を返す
+    // 背景も塗る描き方なので、前に描いてあった文字を消す必要はない
+    pub fn render<T: Bitmap>(&mut self, buf: &mut T) -> usize {
+        let mut rendered = 0;
+        for row in 0..self.rows {
+            if !self.dirty[row] {
+                continue;
+            }
+            let y = (row * GLYPH_HEIGHT) as i64;
+            let cells = &self.cells[row * self.cols..(row + 1) * self.cols];
+            for (col, cell) in cells.iter().enumerate() {
+                let x = (col * GLYPH_WIDTH) as i64;
+                draw_font_fg_bg(buf, x, y, cell.fg, cell.bg, cell.ch);
+            }
+            self.dirty[row] = false;
+            rendered += 1;
+        }
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::BTreeSet;
+
+    // 書き込まれたピクセルの行を記録するビットマップ
+    // 描画関数はpixel_at_mut / unchecked_pixel_at_mutでピクセルを得るので、そこで数える
+    struct CountingBitmap {
+        buf: Vec<u32>,
+        width: i64,
+        height: i64,
+        touched_rows: BTreeSet<i64>,
+        touched: usize,
+    }
+    impl CountingBitmap {
+        fn new(width: i64, height: i64) -> Self {
+            Self {
+                buf: vec![0; (width * height) as usize],
+                width,
+                height,
+                touched_rows: BTreeSet::new(),
+                touched: 0,
+            }
+        }
+        fn reset_counts(&mut self) {
+            self.touched_rows.clear();
+            self.touched = 0;
+        }
+    }
+    impl Bitmap for CountingBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            self.width
+        }
+        fn width(&self) -> i64 {
+            self.width
+        }
+        fn height(&self) -> i64 {
+            self.height
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            self.buf.as_mut_ptr() as *mut u8
+        }
+        fn buf(&self) -> *const u8 {
+            self.buf.as_ptr() as *const u8
+        }
+        unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+            self.touched_rows.insert(y);
+            self.touched += 1;
+            self.buf.as_mut_ptr().add((y * self.width + x) as usize)
+        }
+        fn pixel_at_mut(&mut self, x: i64, y: i64) -> Option<&mut u32> {
+            if self.is_in_x_range(x) && self.is_in_y_range(y) {
+                unsafe { Some(&mut *self.unchecked_pixel_at_mut(x, y)) }
+            } else {
+                None
+            }
+        }
+    }
+
+    // 文字の行rowが占めるピクセルの行
+    fn pixel_rows(row: usize) -> impl Iterator<Item = i64> {
+        let top = (row * GLYPH_HEIGHT) as i64;
+        top..top + GLYPH_HEIGHT as i64
+    }
+
+    #[test_case]
+    fn console_is_sized_from_the_bitmap() {
+        let console = TextConsole::for_bitmap(&CountingBitmap::new(100, 40));
+        assert_eq!((console.cols(), console.rows()), (12, 2));
+        assert_eq!(console.cell(11, 1), Some(Cell::default()));
+        assert_eq!(console.cell(12, 0), None);
+        assert_eq!(console.cell(0, 2), None);
+    }
+    #[test_case]
+    fn only_dirty_rows_are_rendered() {
+        let (cols, rows) = (10, 5);
+        let mut bitmap =
+            CountingBitmap::new((cols * GLYPH_WIDTH) as i64, (rows * GLYPH_HEIGHT) as i64);
+        let mut console = TextConsole::new(cols, rows);
+        // 最初は全ての行を描く
+        assert_eq!(console.render(&mut bitmap), rows);
+        assert_eq!(bitmap.touched, cols * rows * GLYPH_WIDTH * GLYPH_HEIGHT);
+
+        console
+            .put_char(1, 1, Cell::new('A', 0xff0000, 0x0000ff))
+            .unwrap();
+        console
+            .put_char(7, 3, Cell::new('┼', 0x00ff00, 0x000000))
+            .unwrap();
+        console
+            .put_char(0, 3, Cell::new('z', 0xffffff, 0x000000))
+            .unwrap();
+        // 同じ内容を書いても行は汚れない
+        console.put_char(4, 4, Cell::default()).unwrap();
+        assert!(console.is_dirty(1) && console.is_dirty(3));
+        assert!(!console.is_dirty(0) && !console.is_dirty(2) && !console.is_dirty(4));
+
+        bitmap.reset_counts();
+        assert_eq!(console.render(&mut bitmap), 2);
+        let expected: BTreeSet<i64> = pixel_rows(1).chain(pixel_rows(3)).collect();
+        assert_eq!(bitmap.touched_rows, expected);
+        assert_eq!(bitmap.touched, 2 * cols * GLYPH_WIDTH * GLYPH_HEIGHT);
+        // 背景色も塗られている
+        assert_eq!(
+            bitmap.pixel_at(GLYPH_WIDTH as i64, pixel_rows(1).next().unwrap()),
+            Some(0x0000ff)
+        );
+
+        // 何も変えなければ何も描かない
+        bitmap.reset_counts();
+        assert_eq!(console.render(&mut bitmap), 0);
+        assert_eq!(bitmap.touched, 0);
+    }
+    #[test_case]
+    fn strings_are_clipped_at_the_right_edge() {
+        let mut console = TextConsole::new(4, 2);
+        console.render(&mut CountingBitmap::new(32, 32));
+        assert_eq!(console.write_str_at(2, 1, "abc", 1, 2), Ok(2));
+        assert_eq!(console.cell(2, 1), Some(Cell::new('a', 1, 2)));
+        assert_eq!(console.cell(3, 1), Some(Cell::new('b', 1, 2)));
+        assert_eq!(console.cell(0, 1), Some(Cell::default()));
+        assert!(!console.is_dirty(0));
+        assert!(console.is_dirty(1));
+        assert!(console.write_str_at(4, 0, "x", 1, 2).is_err());
+        assert!(console.put_char(0, 2, Cell::default()).is_err());
+    }
+    #[test_case]
+    fn scroll_and_clear_update_every_row() {
+        let mut console = TextConsole::new(3, 3);
+        for (row, s) in ["abc", "def", "ghi"].iter().enumerate() {
+            console.write_str_at(0, row, s, 0xffffff, 0).unwrap();
+        }
+        console.render(&mut CountingBitmap::new(24, 48));
+        console.scroll_up(1);
+        assert!((0..3).all(|row| console.is_dirty(row)));
+        assert_eq!(console.cell(0, 0).map(|c| c.ch), Some('d'));
+        assert_eq!(console.cell(2, 1).map(|c| c.ch), Some('i'));
+        assert_eq!(console.cell(1, 2), Some(Cell::default()));
+        console.scroll_up(10);
+        assert_eq!(console.cell(0, 0), Some(Cell::default()));
+
+        console.write_str_at(0, 1, "xyz", 0xffffff, 0).unwrap();
+        console.render(&mut CountingBitmap::new(24, 48));
+        console.clear();
+        assert!(!console.is_dirty(0) && console.is_dirty(1) && !console.is_dirty(2));
+        assert_eq!(console.cell(2, 1), Some(Cell::default()));
+    }
+}
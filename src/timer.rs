@@ -0,0 +1,189 @@
+use crate::apic::LocalApic;
+use crate::apic::TIMER_DIVISOR;
+use crate::info;
+use crate::pic::disable_irq;
+use crate::pic::enable_irq;
+use crate::pic::register_irq_handler;
+use crate::sync::SpinLock;
+use crate::x86::hlt;
+use crate::x86::rdtsc;
+use crate::x86::read_io_port_u8;
+use crate::x86::write_io_port_u8;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+// PIT（Programmable Interval Timer, 8253/8254）
+// 入力クロック1.193182MHzを分周した周期でIRQ0を発生させる
+const PIT_CH0_DATA: u16 = 0x40;
+const PIT_CMD: u16 = 0x43;
+// チャンネル0, 下位→上位バイトの順で書き込み, モード2（レートジェネレータ）, バイナリ
+const PIT_CMD_CH0_RATE_GENERATOR: u8 = 0b0011_0100;
+const PIT_INPUT_HZ: u64 = 1_193_182;
+// チャンネル2はゲートと出力をポート0x61から操作・確認できるので、割り込みなしで使える
+const PIT_CH2_DATA: u16 = 0x42;
+// チャンネル2、下位/上位バイトの順に書く、モード0（カウントが0になったら出力がHになる）
+const PIT_CMD_CH2_ONESHOT: u8 = 0b1011_0000;
+const PORT_SYSTEM_CONTROL: u16 = 0x61;
+const SYSTEM_CONTROL_CH2_GATE: u8 = 1 << 0;
+const SYSTEM_CONTROL_SPEAKER: u8 = 1 << 1;
+const SYSTEM_CONTROL_CH2_OUT: u8 = 1 << 5;
+pub const PIT_IRQ: u8 = 0;
+
+// 1秒あたりのティック数（1ティック = 1ms）
+pub const TICK_HZ: u64 = 1000;
+
+// 目的の周波数に一番近い分周比を求める（四捨五入）
+const fn pit_divisor(hz: u64) -> u16 {
+    ((PIT_INPUT_HZ + hz / 2) / hz) as u16
+}
+const PIT_DIVISOR: u16 = pit_divisor(TICK_HZ);
+// 1kHzだと分周比は1193で、実際の周期は約1000.15Hzになる
+const _: () = assert!(PIT_DIVISOR == 1193);
+
+// 実際にティックを数えているタイマー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerBackend {
+    Pit,
+    LocalApic,
+}
+static BACKEND: Mutex<TimerBackend> = Mutex::new(TimerBackend::Pit);
+
+pub fn timer_backend() -> TimerBackend {
+    *BACKEND.lock()
+}
+
+// 起動してからのティック数
+// 64bitなので1msごとに増えても約5億年はラップしない
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// ティックごとに割り込みの中から呼ぶ関数
+static TICK_HOOK: SpinLock<Option<fn()>> = SpinLock::new(None);
+
+pub fn set_tick_hook(hook: Option<fn()>) {
+    *TICK_HOOK.lock() = hook;
+}
+
+fn on_timer_irq() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    // 設定し直している途中なら、このティックでは呼ばない
+    let hook = TICK_HOOK.try_lock().and_then(|hook| *hook);
+    if let Some(hook) = hook {
+        hook();
+    }
+}
+
+// PITのチャンネル0を1kHzに設定して、IRQ0でティックを数え始める
+fn init_pit() {
+    write_io_port_u8(PIT_CMD, PIT_CMD_CH0_RATE_GENERATOR);
+    write_io_port_u8(PIT_CH0_DATA, (PIT_DIVISOR & 0xff) as u8);
+    write_io_port_u8(PIT_CH0_DATA, (PIT_DIVISOR >> 8) as u8);
+    register_irq_handler(PIT_IRQ, on_timer_irq);
+    enable_irq(PIT_IRQ);
+}
+
+// Local APICタイマーに切り替える（使えなければNone）
+// キャリブレーションにPITのティックを使うので、PITが動いている状態で呼ぶ
+fn switch_to_local_apic() -> Option<u64> {
+    let apic = LocalApic::init()?;
+    let counts_per_ms = apic.calibrate_timer(sleep_ms);
+    if counts_per_ms == 0 {
+        return None;
+    }
+    // 1msごとに割り込みが来るようにする
+    let counts_per_tick = counts_per_ms as u64 * 1000 / TICK_HZ;
+    apic.start_periodic_timer(counts_per_tick as u32, on_timer_irq);
+    disable_irq(PIT_IRQ);
+    Some(counts_per_ms as u64 * 1000 * TIMER_DIVISOR)
+}
+
+// タイマーの初期化
+// Local APICが使えればそちらを、使えなければPITを使う
+// PITでのキャリブレーションのために割り込みが許可されている必要がある
+pub fn init_timer() {
+    init_pit();
+    match switch_to_local_apic() {
+        Some(hz) => {
+            *BACKEND.lock() = TimerBackend::LocalApic;
+            info!("Timer: Local APIC timer (bus clock {} kHz)", hz / 1000);
+        }
+        None => {
+            info!(
+                "Timer: PIT (divisor {}, {} Hz)",
+                PIT_DIVISOR,
+                PIT_INPUT_HZ / PIT_DIVISOR as u64
+            );
+        }
+    }
+}
+
+// PITのチャンネル2をワンショットで動かし、出力がHになるまでポーリングしてTSCの周波数を測る
+// 割り込みを使わないので、PICやIDTの準備ができる前でも呼べる
+// 1マイクロ秒あたりのTSCのサイクル数を返す（PITが動いていないように見えればNone）
+pub fn measure_tsc_per_us_polled() -> Option<u64> {
+    const WAIT_US: u64 = 10_000;
+    // ポートを1回読むのに約1マイクロ秒かかるので、待つ時間の100倍読んでも終わらなければ諦める
+    const POLL_LIMIT: u64 = WAIT_US * 100;
+    let count = PIT_INPUT_HZ * WAIT_US / 1_000_000;
+    let control = read_io_port_u8(PORT_SYSTEM_CONTROL);
+    // スピーカーは鳴らさずに、ゲートだけを開ける
+    write_io_port_u8(
+        PORT_SYSTEM_CONTROL,
+        (control & !SYSTEM_CONTROL_SPEAKER) | SYSTEM_CONTROL_CH2_GATE,
+    );
+    write_io_port_u8(PIT_CMD, PIT_CMD_CH2_ONESHOT);
+    write_io_port_u8(PIT_CH2_DATA, (count & 0xff) as u8);
+    write_io_port_u8(PIT_CH2_DATA, (count >> 8) as u8);
+    let start = rdtsc();
+    let mut polls = 0;
+    while read_io_port_u8(PORT_SYSTEM_CONTROL) & SYSTEM_CONTROL_CH2_OUT == 0 && polls < POLL_LIMIT {
+        polls += 1;
+    }
+    let cycles = rdtsc().wrapping_sub(start);
+    write_io_port_u8(PORT_SYSTEM_CONTROL, control);
+    // すぐにHになった場合も、PITが動いていないとみなす
+    if polls == 0 || polls >= POLL_LIMIT {
+        return None;
+    }
+    Some(cycles / WAIT_US).filter(|c| *c != 0)
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// 起動してからの経過時間（ミリ秒）
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ
+}
+
+// 指定したミリ秒だけ待つ
+// 割り込みが有効になっている必要がある（次のティックまでhltで休む）
+pub fn sleep_ms(ms: u64) {
+    let start = ticks();
+    let duration = ms * TICK_HZ / 1000;
+    // ラップしても経過ティック数が正しく求まるようにwrapping_subで比較する
+    while ticks().wrapping_sub(start) < duration {
+        hlt();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn pit_divisor_rounds_to_nearest() {
+        assert_eq!(pit_divisor(1000), 1193);
+        assert_eq!(pit_divisor(100), 11932);
+    }
+
+    // 50ms眠って、実際に進んだティック数が許容範囲内であることを確認する
+    #[test_case]
+    fn sleep_ms_waits_for_ticks() {
+        let start = ticks();
+        sleep_ms(50);
+        let elapsed = ticks() - start;
+        assert!((50..=60).contains(&elapsed), "elapsed = {elapsed}");
+    }
+}
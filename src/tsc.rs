@@ -0,0 +1,157 @@
+use crate::timer::sleep_ms;
+use crate::warn;
+use crate::x86::delay_us;
+use crate::x86::rdtsc;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+// TSC（Time Stamp Counter）
+// CPUのクロックごとに増えるカウンタで、読むのが安くて分解能が高い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tsc {
+    cycles_per_ms: u64,
+}
+impl Tsc {
+    // 既に動いている別のタイマーで指定したミリ秒待つ関数を使って、1msあたりのサイクル数を測る
+    // ばらつきを減らすために何回か測って平均をとる
+    pub fn calibrate(wait_ms: fn(u64)) -> Self {
+        const ROUNDS: u64 = 4;
+        const WAIT_MS: u64 = 10;
+        let mut total = 0;
+        for _ in 0..ROUNDS {
+            // 1ms待ってティックの境目に揃えてから測り始める
+            wait_ms(1);
+            let start = rdtsc();
+            wait_ms(WAIT_MS);
+            total += rdtsc() - start;
+        }
+        Self {
+            cycles_per_ms: total / ROUNDS / WAIT_MS,
+        }
+    }
+
+    pub fn cycles_per_ms(&self) -> u64 {
+        self.cycles_per_ms
+    }
+
+    // 100MHz未満や10GHz超えはキャリブレーションがおかしい
+    pub fn is_plausible(&self) -> bool {
+        (100_000..=10_000_000).contains(&self.cycles_per_ms)
+    }
+
+    pub fn cycles_to_ns(&self, cycles: u64) -> u64 {
+        if self.cycles_per_ms == 0 {
+            return 0;
+        }
+        // 掛け算のオーバーフローを避けるためにu128で計算する
+        (cycles as u128 * 1_000_000 / self.cycles_per_ms as u128) as u64
+    }
+}
+
+// 起動時にキャリブレーションした結果（0なら未初期化）
+static CYCLES_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+// PITなどのタイマーを使ってTSCをキャリブレーションする
+// タイマーが動いていて、割り込みが許可されている状態で呼ぶ
+pub fn init_tsc() {
+    let tsc = Tsc::calibrate(sleep_ms);
+    if !tsc.is_plausible() {
+        warn!(
+            "TSC calibration looks bogus: {} cycles/ms",
+            tsc.cycles_per_ms()
+        );
+    }
+    CYCLES_PER_MS.store(tsc.cycles_per_ms(), Ordering::Relaxed);
+}
+
+// キャリブレーションが終わっていればtrue
+pub fn is_calibrated() -> bool {
+    CYCLES_PER_MS.load(Ordering::Relaxed) != 0
+}
+
+pub fn tsc() -> Tsc {
+    Tsc {
+        cycles_per_ms: CYCLES_PER_MS.load(Ordering::Relaxed),
+    }
+}
+
+pub fn cycles_to_ns(cycles: u64) -> u64 {
+    tsc().cycles_to_ns(cycles)
+}
+
+// TSCから求めた現在時刻（ナノ秒）
+pub fn now_ns() -> u64 {
+    cycles_to_ns(rdtsc())
+}
+
+// startはrdtsc()で取得したサイクル数
+pub fn elapsed(start: u64) -> Duration {
+    Duration::from_nanos(cycles_to_ns(rdtsc().wrapping_sub(start)))
+}
+
+// 割り込みを使わずに指定したマイクロ秒だけ待つ
+pub fn busy_wait_us(us: u64) {
+    delay_us(us);
+}
+
+// 式の実行にかかったサイクル数を計測してinfo!で出力する
+#[macro_export]
+macro_rules! time_it {
+    ($e:expr) => {
+        $crate::time_it!(stringify!($e), $e)
+    };
+    ($label:expr, $e:expr) => {{
+        let start = $crate::x86::rdtsc();
+        let result = $e;
+        let cycles = $crate::x86::rdtsc().wrapping_sub(start);
+        $crate::info!(
+            "{}: {} cycles ({} ns)",
+            $label,
+            cycles,
+            $crate::tsc::cycles_to_ns(cycles)
+        );
+        result
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 何千回読んでもTSCが減らないことを確認する
+    #[test_case]
+    fn rdtsc_is_monotonic() {
+        let mut prev = rdtsc();
+        for _ in 0..10000 {
+            let now = rdtsc();
+            assert!(now >= prev);
+            prev = now;
+        }
+    }
+
+    // sleep_ms(10)がTSCでもだいたい10msとして測れることを確認する
+    #[test_case]
+    fn sleep_10ms_measures_about_10ms() {
+        let start = rdtsc();
+        sleep_ms(10);
+        let ms = elapsed(start).as_millis();
+        assert!((8..=13).contains(&ms), "measured {ms} ms");
+    }
+
+    #[test_case]
+    fn cycles_to_ns_conversion() {
+        let tsc = Tsc {
+            cycles_per_ms: 2_000_000,
+        };
+        assert!(tsc.is_plausible());
+        assert_eq!(tsc.cycles_to_ns(2_000_000), 1_000_000);
+        assert_eq!(tsc.cycles_to_ns(2), 1);
+        assert!(!Tsc { cycles_per_ms: 10 }.is_plausible());
+    }
+
+    #[test_case]
+    fn time_it_returns_value() {
+        assert_eq!(crate::time_it!(1 + 2), 3);
+    }
+}
@@ -1,10 +1,21 @@
+extern crate alloc;
+
 use crate::graphics::draw_font_fg;
 use crate::graphics::Bitmap;
+use crate::info;
+use crate::print::register_sink;
+use crate::print::unregister_sink;
+use crate::print::Sink;
 use crate::result::Result;
+use crate::warn;
+use alloc::vec::Vec;
 use core::fmt;
 use core::mem::offset_of;
 use core::mem::size_of;
+use core::ptr::null;
 use core::ptr::null_mut;
+use spin::Mutex;
+use spin::Once;
 
 type EfiVoid = u8;
 pub type EfiHandle = u64;
@@ -32,6 +43,21 @@ const EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID: EfiGuid = EfiGuid {
     data3: [0x96, 0xfb, 0x7a, 0xde, 0xd0, 0x80, 0x51, 0x6a],
 };
 
+// ACPIのRSDPを指す構成テーブルのGUID（2.0以降と1.0）
+const EFI_ACPI_20_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0x8868e871,
+    data1: 0xe4f1,
+    data2: 0x11d3,
+    data3: [0xbc, 0x22, 0x00, 0x80, 0xc7, 0x3c, 0x88, 0x81],
+};
+
+const EFI_ACPI_10_TABLE_GUID: EfiGuid = EfiGuid {
+    data0: 0xeb9d2d30,
+    data1: 0x2d88,
+    data2: 0x11d3,
+    data3: [0x9a, 0x16, 0x00, 0x90, 0x27, 0x3f, 0xc1, 0x4d],
+};
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[must_use]
 #[repr(u64)]
@@ -39,12 +65,22 @@ pub enum EfiStatus {
     Success = 0,
 }
 
-#[repr(i64)]
+// LocateHandleBufferの検索方法
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+enum EfiLocateSearchType {
+    AllHandles = 0,
+    ByRegisterNotify,
+    // 指定したプロトコルをサポートするハンドルを全て返す
+    ByProtocol,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 // そのディスクリプタが示すメモリ領域の種類
 pub enum EfiMemoryType {
-    RESERVED = 0,
+    RESERVED,
     LOADER_CODE,
     LOADER_DATA,
     BOOT_SERVICES_CODE,
@@ -60,20 +96,63 @@ pub enum EfiMemoryType {
     MEMORY_MAPPED_IO_PORT_SPACE,
     PAL_CODE,
     PRESISTENT_MEMORY,
+    // 使う前にゲストが受け入れる必要がある領域（UEFI 2.9以降）
+    UNACCEPTED_MEMORY,
+    // 知らない種類（OEMが定義する0x70000000以降の値など）
+    Unknown(u32),
+}
+impl EfiMemoryType {
+    // 仕様の値の順に並べたもの
+    const KNOWN: [EfiMemoryType; 16] = [
+        Self::RESERVED,
+        Self::LOADER_CODE,
+        Self::LOADER_DATA,
+        Self::BOOT_SERVICES_CODE,
+        Self::BOOT_SERVICES_DATA,
+        Self::RUNTIME_SERVICES_CODE,
+        Self::RUNTIME_SERVICES_DATA,
+        Self::CONVENTIONAL_MEMORY,
+        Self::UNUSABLE_MEMORY,
+        Self::ACPI_RECLAIM_MEMORY,
+        Self::ACPI_MEMORY_NVS,
+        Self::MEMORY_MAPPED_IO,
+        Self::MEMORY_MAPPED_IO_PORT_SPACE,
+        Self::PAL_CODE,
+        Self::PRESISTENT_MEMORY,
+        Self::UNACCEPTED_MEMORY,
+    ];
+
+    pub fn from_raw(value: u32) -> Self {
+        Self::KNOWN
+            .get(value as usize)
+            .copied()
+            .unwrap_or(Self::Unknown(value))
+    }
+}
+impl fmt::Display for EfiMemoryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unknown(value) => write!(f, "UNKNOWN({value:#x})"),
+            known => write!(f, "{known:?}"),
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct EfiMemoryDescriptor {
-    memory_type: EfiMemoryType,
+    // ファームウェアは仕様にない値も返すので、enumとしては読まずに数値のまま持つ
+    memory_type: u32,
+    _padding: u32,
     physical_start: u64,
     virtual_start: u64,
     number_of_pages: u64,
     attribute: u64,
 }
+const _: () = assert!(size_of::<EfiMemoryDescriptor>() == 40);
 impl EfiMemoryDescriptor {
     pub fn memory_type(&self) -> EfiMemoryType {
-        self.memory_type
+        EfiMemoryType::from_raw(self.memory_type)
     }
     pub fn number_of_pages(&self) -> u64 {
         self.number_of_pages
@@ -81,6 +160,9 @@ impl EfiMemoryDescriptor {
     pub fn physical_start(&self) -> u64 {
         self.physical_start
     }
+    pub fn attribute(&self) -> u64 {
+        self.attribute
+    }
 }
 
 const MEMORY_MAP_BUFFER_SIZE: usize = 0x8000;
@@ -113,6 +195,7 @@ impl Default for MemoryMapHolder {
     }
 }
 
+#[derive(Clone)]
 pub struct MemoryMapIterator<'a> {
     map: &'a MemoryMapHolder,
     ofs: usize,
@@ -132,6 +215,82 @@ impl<'a> Iterator for MemoryMapIterator<'a> {
     }
 }
 
+// メモリマップの1つの領域（ヒープにコピーして使う形）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: u64,
+    pub pages: u64,
+    pub kind: EfiMemoryType,
+    pub attributes: u64,
+}
+impl MemoryRegion {
+    // 領域の終わり（この番地は含まない）
+    pub fn end(&self) -> u64 {
+        self.start + self.pages * UEFI_PAGE_SIZE
+    }
+}
+impl From<&EfiMemoryDescriptor> for MemoryRegion {
+    fn from(e: &EfiMemoryDescriptor) -> Self {
+        Self {
+            start: e.physical_start(),
+            pages: e.number_of_pages(),
+            kind: e.memory_type(),
+            attributes: e.attribute(),
+        }
+    }
+}
+
+// UEFIのメモリマップのページの大きさ（CPUのページの大きさとは関係なく常に4KiB）
+pub const UEFI_PAGE_SIZE: u64 = 4096;
+
+// アロケータの初期化後にメモリマップをヒープにコピーしたもの
+// MemoryMapHolderのバッファを残しておかなくても使えるように、起動後はこちらを使う
+// アドレス順に並べ、隣り合っていて種類と属性が同じ領域は1つにまとめてある
+pub struct MemoryRegions {
+    regions: Vec<MemoryRegion>,
+}
+impl MemoryRegions {
+    pub fn new(regions: impl Iterator<Item = MemoryRegion>) -> Self {
+        let mut sorted: Vec<MemoryRegion> = regions.filter(|r| r.pages > 0).collect();
+        sorted.sort_unstable_by_key(|r| r.start);
+        let mut merged: Vec<MemoryRegion> = Vec::with_capacity(sorted.len());
+        for r in sorted {
+            match merged.last_mut() {
+                Some(last)
+                    if last.end() == r.start
+                        && last.kind == r.kind
+                        && last.attributes == r.attributes =>
+                {
+                    last.pages += r.pages
+                }
+                _ => merged.push(r),
+            }
+        }
+        Self { regions: merged }
+    }
+    pub fn iter(&self) -> core::slice::Iter<'_, MemoryRegion> {
+        self.regions.iter()
+    }
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+    // 指定した種類の領域のページ数の合計
+    pub fn total_pages(&self, kind: EfiMemoryType) -> u64 {
+        self.iter()
+            .filter(|r| r.kind == kind)
+            .map(|r| r.pages)
+            .sum()
+    }
+}
+impl From<&MemoryMapHolder> for MemoryRegions {
+    fn from(map: &MemoryMapHolder) -> Self {
+        Self::new(map.iter().map(MemoryRegion::from))
+    }
+}
+
 #[repr(C)]
 // EFIブートサービステーブル
 pub struct EfiBootServicesTable {
@@ -155,7 +314,10 @@ pub struct EfiBootServicesTable {
         descripter_size: *mut usize,
         descripter_version: *mut u32,
     ) -> EfiStatus,
-    _reserved2: [u64; 11],
+    _reserved2: [u64; 1],
+    // AllocatePool/LocateHandleBufferなどで確保されたバッファを解放するAPI
+    free_pool: extern "C" fn(buffer: *mut EfiVoid) -> EfiStatus,
+    _reserved3: [u64; 9],
     handle_protocol: extern "win64" fn(
         handle: EfiHandle,
         protocol: *const EfiGuid,
@@ -163,7 +325,16 @@ pub struct EfiBootServicesTable {
     ) -> EfiStatus,
     _reserved1: [u64; 9],
     exit_boot_services: extern "C" fn(image_handle: EfiHandle, map_key: usize) -> EfiStatus,
-    _reserved4: [u64; 10],
+    _reserved4: [u64; 9],
+    // 指定したプロトコルをサポートするハンドルの一覧を取得するAPI
+    // バッファはファームウェアが確保するので、使い終わったらfree_poolで解放する
+    locate_handle_buffer: extern "C" fn(
+        search_type: EfiLocateSearchType,
+        protocol: *const EfiGuid,
+        search_key: *const EfiVoid,
+        number_of_handles: *mut usize,
+        buffer: *mut *mut EfiHandle,
+    ) -> EfiStatus,
     // x86_64環境では関数呼び出し規約がWindows ABIに従うため、extern "win64"を指定したいがRustではサポートされていないため、extern "C"を使用する
     locate_protocol: extern "C" fn(
         protocol: *const EfiGuid,
@@ -185,24 +356,240 @@ impl EfiBootServicesTable {
 // offset_of!マクロを使用することによって、get_memory_mapのオフセットが56であることを確認する
 const _: () = assert!(offset_of!(EfiBootServicesTable, get_memory_map) == 56);
 
+// offset_of!マクロを使用することによって、free_poolのオフセットが72であることを確認する
+const _: () = assert!(offset_of!(EfiBootServicesTable, free_pool) == 72);
+
+// offset_of!マクロを使用することによって、handle_protocolのオフセットが152であることを確認する
+const _: () = assert!(offset_of!(EfiBootServicesTable, handle_protocol) == 152);
+
 // offset_of!マクロを使用することによって、exit_boot_serviceのオフセットが56であることを確認する
 const _: () = assert!(offset_of!(EfiBootServicesTable, exit_boot_services) == 232);
 
+// offset_of!マクロを使用することによって、locate_handle_bufferのオフセットが312であることを確認する
+const _: () = assert!(offset_of!(EfiBootServicesTable, locate_handle_buffer) == 312);
+
 // offset_of!マクロを使用することによって、locate_protocolのオフセットが320であることを確認する
 const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
 
 #[repr(C)]
 // EFIシステムテーブル
 pub struct EfiSystemTable {
-    _reserved0: [u64; 12],
+    _reserved0: [u64; 8],
+    // 標準出力に使うテキストコンソール（ブートサービスを抜けると使えない）
+    con_out: *const EfiSimpleTextOutputProtocol,
+    _reserved1: [u64; 2],
+    runtime_services: *const EfiRuntimeServicesTable,
     pub boot_services: &'static EfiBootServicesTable,
+    number_of_table_entries: usize,
+    configuration_table: *const EfiConfigurationTable,
 }
+// con_outのオフセットが64であることを確認する
+const _: () = assert!(offset_of!(EfiSystemTable, con_out) == 64);
+// runtime_servicesのオフセットが88であることを確認する
+const _: () = assert!(offset_of!(EfiSystemTable, runtime_services) == 88);
 // boot_servicesのオフセットが96であることを確認する
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
+// 構成テーブルの数と先頭アドレスのオフセットが104と112であることを確認する
+const _: () = assert!(offset_of!(EfiSystemTable, number_of_table_entries) == 104);
+const _: () = assert!(offset_of!(EfiSystemTable, configuration_table) == 112);
 impl EfiSystemTable {
     pub fn boot_services(&self) -> &EfiBootServicesTable {
         self.boot_services
     }
+    // ファームウェアが用意したACPIやSMBIOSなどのテーブルの一覧
+    fn configuration_tables(&self) -> &[EfiConfigurationTable] {
+        if self.configuration_table.is_null() {
+            return &[];
+        }
+        unsafe {
+            core::slice::from_raw_parts(self.configuration_table, self.number_of_table_entries)
+        }
+    }
+    // ACPIのRSDPの物理アドレス
+    pub fn acpi_rsdp(&self) -> Option<u64> {
+        find_acpi_rsdp(self.configuration_tables())
+    }
+    // ファームウェアのテキストコンソール（ヘッドレスの環境などではないこともある）
+    pub fn con_out(&self) -> Option<&EfiSimpleTextOutputProtocol> {
+        unsafe { self.con_out.as_ref() }
+    }
+    // ランタイムサービスはブートサービスを抜けた後も使える
+    pub fn runtime_services(&self) -> Option<&'static EfiRuntimeServicesTable> {
+        unsafe { self.runtime_services.as_ref() }
+    }
+}
+
+#[repr(C)]
+// EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL
+pub struct EfiSimpleTextOutputProtocol {
+    _reset: u64,
+    // NUL終端のUCS-2の文字列を出力する
+    output_string:
+        extern "win64" fn(this: *const EfiSimpleTextOutputProtocol, string: *const u16) -> usize,
+}
+const _: () = assert!(offset_of!(EfiSimpleTextOutputProtocol, output_string) == 8);
+impl EfiSimpleTextOutputProtocol {
+    // sはNUL文字で終わっていること
+    pub fn output_string(&self, s: &[u16]) -> Result<()> {
+        if s.last() != Some(&0) {
+            return Err("output_string: the string is not NUL-terminated");
+        }
+        if (self.output_string)(self, s.as_ptr()) != EFI_SUCCESS {
+            return Err("output_string failed");
+        }
+        Ok(())
+    }
+}
+
+// 一度にoutput_stringに渡す文字数（NUL文字を除く）
+const UCS2_CHUNK_LEN: usize = 64;
+
+// 文字列をUCS-2に変換し、スタック上のバッファに入る分ずつNUL文字を付けてfに渡す
+// UCS-2で表せない文字（U+10000以降）はU+FFFDにする
+pub fn for_each_ucs2_chunk(s: &str, mut f: impl FnMut(&[u16])) {
+    let mut buf = [0u16; UCS2_CHUNK_LEN + 1];
+    let mut len = 0;
+    for c in s.chars() {
+        let mut units = [0u16; 2];
+        buf[len] = match c.encode_utf16(&mut units) {
+            [unit] => *unit,
+            _ => char::REPLACEMENT_CHARACTER as u16,
+        };
+        len += 1;
+        if len == UCS2_CHUNK_LEN {
+            buf[len] = 0;
+            f(&buf[..=len]);
+            len = 0;
+        }
+    }
+    if len > 0 {
+        buf[len] = 0;
+        f(&buf[..=len]);
+    }
+}
+
+// ファームウェアのテキストコンソールに出力するWriter
+// 画面やシリアルを初期化する前から使えるので、起動直後のエラーを見えるところに出せる
+pub struct ConOutWriter {
+    con_out: *const EfiSimpleTextOutputProtocol,
+}
+// ブートサービスを抜けるまで、BSPからしか使わない
+unsafe impl Send for ConOutWriter {}
+impl ConOutWriter {
+    const fn new() -> Self {
+        Self { con_out: null() }
+    }
+}
+impl fmt::Write for ConOutWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let Some(con_out) = (unsafe { self.con_out.as_ref() }) else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        // テキストコンソールは\nでは行頭に戻らないので、\r\nにする
+        for (i, line) in s.split('\n').enumerate() {
+            if i != 0 {
+                let crlf = [b'\r' as u16, b'\n' as u16, 0];
+                result = result.and(con_out.output_string(&crlf));
+            }
+            for_each_ucs2_chunk(line, |chunk| {
+                result = result.and(con_out.output_string(chunk));
+            });
+        }
+        result.or(Err(fmt::Error))
+    }
+}
+
+static CON_OUT: Mutex<ConOutWriter> = Mutex::new(ConOutWriter::new());
+
+pub fn con_out_sink() -> Sink {
+    &CON_OUT
+}
+
+// ファームウェアのテキストコンソールをprint!の出力先に加える（コンソールがあればtrue）
+// efi_mainの最初に呼び、ブートサービスを抜ける前にshutdown_con_outで外す
+pub fn init_con_out(efi_system_table: &EfiSystemTable) -> bool {
+    let Some(con_out) = efi_system_table.con_out() else {
+        return false;
+    };
+    CON_OUT.lock().con_out = con_out;
+    register_sink(con_out_sink()).is_ok()
+}
+
+// ブートサービスを抜けるとテキストコンソールは使えなくなるので、出力先から外す
+pub fn shutdown_con_out() {
+    unregister_sink(con_out_sink());
+    CON_OUT.lock().con_out = null();
+}
+
+// ResetSystemの種類
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+enum EfiResetType {
+    Cold = 0,
+    Warm,
+    Shutdown,
+}
+
+#[repr(C)]
+// EFIランタイムサービステーブル
+pub struct EfiRuntimeServicesTable {
+    _reserved0: [u64; 13],
+    // 成功すれば戻ってこない
+    reset_system: extern "win64" fn(
+        reset_type: EfiResetType,
+        reset_status: EfiStatus,
+        data_size: usize,
+        reset_data: *const EfiVoid,
+    ),
+}
+// reset_systemのオフセットが104であることを確認する
+const _: () = assert!(offset_of!(EfiRuntimeServicesTable, reset_system) == 104);
+
+// ブートサービスを抜けた後に使うランタイムサービス
+static RUNTIME_SERVICES: Once<&'static EfiRuntimeServicesTable> = Once::new();
+
+// ブートサービスを抜ける前に、システムテーブルからランタイムサービスの場所を覚えておく
+pub fn keep_runtime_services(efi_system_table: &EfiSystemTable) {
+    if let Some(rt) = efi_system_table.runtime_services() {
+        RUNTIME_SERVICES.call_once(|| rt);
+    }
+}
+
+// ResetSystemで電源を切る（失敗したときだけ戻ってくる）
+// SetVirtualAddressMapを呼んでいないので、ファームウェアの実装によっては動かないことがある
+pub fn reset_system_shutdown() -> Result<()> {
+    let rt = RUNTIME_SERVICES
+        .get()
+        .ok_or("EFI runtime services are not available")?;
+    (rt.reset_system)(
+        EfiResetType::Shutdown,
+        EfiStatus::Success,
+        0,
+        core::ptr::null(),
+    );
+    Err("EFI ResetSystem returned")
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+// EFI構成テーブルの要素（GUIDとテーブルのアドレスの組）
+struct EfiConfigurationTable {
+    vendor_guid: EfiGuid,
+    vendor_table: u64,
+}
+const _: () = assert!(size_of::<EfiConfigurationTable>() == 24);
+
+// ACPI 2.0以降のRSDPがあればそれを、なければ1.0のRSDPを探す
+fn find_acpi_rsdp(tables: &[EfiConfigurationTable]) -> Option<u64> {
+    let find = |guid| {
+        tables
+            .iter()
+            .find(|t| t.vendor_guid == guid)
+            .map(|t| t.vendor_table)
+    };
+    find(EFI_ACPI_20_TABLE_GUID).or_else(|| find(EFI_ACPI_10_TABLE_GUID))
 }
 
 #[repr(C)]
@@ -213,7 +600,9 @@ struct EfiGraphicsOutputProtocolPixelInfo {
     pub horizontal_resolution: u32,
     // 垂直方向の画素数
     pub vertical_resolution: u32,
-    _padding0: [u32; 5],
+    pixel_format: u32,
+    // PixelBitMaskのときの赤、緑、青、予約のビットマスク
+    _pixel_information: [u32; 4],
     pub pixels_per_scan_line: u32,
 }
 // EfiGraphicsOutputProtocolPixelInfoのサイズが36バイトであることを確認する
@@ -231,38 +620,328 @@ struct EfiGraphicsOutputProtocolMode<'a> {
     pub frame_buffer_size: u64,
 }
 
+// 1ピクセルの並び方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 1ピクセル4バイトで、メモリ上の順にR, G, B, 予約
+    Rgb,
+    // 1ピクセル4バイトで、メモリ上の順にB, G, R, 予約
+    Bgr,
+    // 各色の位置がビットマスクで決まる
+    BitMask,
+    // フレームバッファがなく、Bltでしか描けない
+    BltOnly,
+    Unknown(u32),
+}
+impl PixelFormat {
+    fn from_raw(value: u32) -> Self {
+        match value {
+            0 => Self::Rgb,
+            1 => Self::Bgr,
+            2 => Self::BitMask,
+            3 => Self::BltOnly,
+            v => Self::Unknown(v),
+        }
+    }
+    // フレームバッファに1ピクセル4バイトで直接描けるか
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Self::Rgb | Self::Bgr)
+    }
+}
+
+// GOPの1つのモードの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeInfo {
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: PixelFormat,
+    pub pixels_per_scan_line: u32,
+}
+impl ModeInfo {
+    fn pixel_count(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+}
+impl From<&EfiGraphicsOutputProtocolPixelInfo> for ModeInfo {
+    fn from(info: &EfiGraphicsOutputProtocolPixelInfo) -> Self {
+        Self {
+            width: info.horizontal_resolution,
+            height: info.vertical_resolution,
+            pixel_format: PixelFormat::from_raw(info.pixel_format),
+            pixels_per_scan_line: info.pixels_per_scan_line,
+        }
+    }
+}
+impl fmt::Display for ModeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{} {:?}", self.width, self.height, self.pixel_format)
+    }
+}
+
+// GOPの関数が返すEFI_STATUS
+// 失敗したときは最上位ビットが立った値が返ってくるので、EfiStatusとしては受け取らずに数値で比べる
+const EFI_SUCCESS: usize = 0;
+
 #[repr(C)]
 #[derive(Debug)]
-struct EfiGraphicsOutputProtocol<'a> {
-    reserved: [u64; 3],
-    pub mode: &'a EfiGraphicsOutputProtocolMode<'a>,
+pub struct EfiGraphicsOutputProtocol<'a> {
+    // モード番号の情報を返す（情報のバッファはファームウェアが確保する）
+    query_mode: extern "win64" fn(
+        this: *const EfiGraphicsOutputProtocol,
+        mode_number: u32,
+        size_of_info: *mut usize,
+        info: *mut *const EfiGraphicsOutputProtocolPixelInfo,
+    ) -> usize,
+    // モードを切り替えて、画面を黒く塗りつぶす
+    set_mode: extern "win64" fn(this: *const EfiGraphicsOutputProtocol, mode_number: u32) -> usize,
+    _blt: u64,
+    mode: &'a EfiGraphicsOutputProtocolMode<'a>,
 }
-fn locate_graphic_protocol<'a>(
-    efi_system_table: &EfiSystemTable,
-) -> Result<&'a EfiGraphicsOutputProtocol<'a>> {
-    // null_mut()で空のポインタを作る
-    let mut graphic_output_protocol = null_mut::<EfiGraphicsOutputProtocol>();
-    // locate_protocol関数を呼び出して、グラフィックス出力プロトコルのアドレスを取得する
-    let status = (efi_system_table.boot_services.locate_protocol)(
-        // 検索したいプロトコルのGUIDへのポインタ
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, query_mode) == 0);
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, set_mode) == 8);
+const _: () = assert!(offset_of!(EfiGraphicsOutputProtocol, mode) == 24);
+impl EfiGraphicsOutputProtocol<'_> {
+    pub fn horizontal_resolution(&self) -> u32 {
+        self.mode.info.horizontal_resolution
+    }
+    pub fn vertical_resolution(&self) -> u32 {
+        self.mode.info.vertical_resolution
+    }
+    pub fn frame_buffer_base(&self) -> u64 {
+        self.mode.frame_buffer_base
+    }
+    // 画面全体のピクセル数（解像度の比較に使う）
+    fn pixel_count(&self) -> u64 {
+        self.horizontal_resolution() as u64 * self.vertical_resolution() as u64
+    }
+}
+
+// GOPをサポートするハンドルを1つずつ辿るイテレータ
+// ハンドルのバッファはファームウェアが確保したものなので、Dropでfree_poolを呼んで返却する
+pub struct GopIterator<'a> {
+    efi_system_table: &'a EfiSystemTable,
+    handles: *mut EfiHandle,
+    number_of_handles: usize,
+    index: usize,
+}
+impl<'a> Iterator for GopIterator<'a> {
+    type Item = &'a EfiGraphicsOutputProtocol<'a>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.number_of_handles {
+            let handle = unsafe { *self.handles.add(self.index) };
+            self.index += 1;
+            let mut gop = null_mut::<EfiGraphicsOutputProtocol>();
+            let status = (self.efi_system_table.boot_services.handle_protocol)(
+                handle,
+                &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
+                &mut gop as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,
+            );
+            // プロトコルを取得できなかったハンドルは飛ばす
+            if status == EfiStatus::Success && !gop.is_null() {
+                return Some(unsafe { &*gop });
+            }
+        }
+        None
+    }
+}
+impl Drop for GopIterator<'_> {
+    fn drop(&mut self) {
+        if !self.handles.is_null() {
+            let _ = (self.efi_system_table.boot_services.free_pool)(self.handles as *mut EfiVoid);
+        }
+    }
+}
+
+// グラフィックス出力プロトコルを持つ全てのインスタンスを列挙する
+// locate_protocolは任意の1つしか返さないため、GPUが複数ある場合はこちらを使う
+pub fn enumerate_gop<'a>(
+    efi_system_table: &'a EfiSystemTable,
+) -> Result<impl Iterator<Item = &'a EfiGraphicsOutputProtocol<'a>> + 'a> {
+    let mut number_of_handles = 0;
+    let mut handles = null_mut::<EfiHandle>();
+    let status = (efi_system_table.boot_services.locate_handle_buffer)(
+        EfiLocateSearchType::ByProtocol,
         &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
-        // C言語のNULLポインタに相当
         null_mut::<EfiVoid>(),
-        &mut graphic_output_protocol as *mut *mut EfiGraphicsOutputProtocol as *mut *mut EfiVoid,
+        &mut number_of_handles,
+        &mut handles,
     );
-
     if status != EfiStatus::Success {
-        return Err("Failed to locate graphics output protocol");
+        return Err("Failed to locate graphics output handles");
     }
+    Ok(GopIterator {
+        efi_system_table,
+        handles,
+        number_of_handles,
+        index: 0,
+    })
+}
 
-    Ok(unsafe { &*graphic_output_protocol })
+// 候補の中から使うGOPを選ぶ
+// 今の解像度がコマンドラインで指定されたものと同じGOPがあればそれを、
+// なければ最も解像度が大きいGOPを選ぶ（同じ場合は先に見つかった方）
+fn select_gop<'a, I>(
+    candidates: I,
+    preference: ModePreference,
+) -> Option<&'a EfiGraphicsOutputProtocol<'a>>
+where
+    I: Iterator<Item = &'a EfiGraphicsOutputProtocol<'a>>,
+{
+    let matches = |gop: &EfiGraphicsOutputProtocol| {
+        preference
+            == ModePreference::Resolution(gop.horizontal_resolution(), gop.vertical_resolution())
+    };
+    candidates.fold(None, |best: Option<&EfiGraphicsOutputProtocol>, gop| {
+        info!(
+            "GOP candidate: {}x{} @ {:#018X}",
+            gop.horizontal_resolution(),
+            gop.vertical_resolution(),
+            gop.frame_buffer_base()
+        );
+        match best {
+            Some(best) if matches(best) => Some(best),
+            _ if matches(gop) => Some(gop),
+            Some(best) if best.pixel_count() >= gop.pixel_count() => Some(best),
+            _ => Some(gop),
+        }
+    })
+}
+
+// GOPが対応している全てのモードを(モード番号, 情報)の組で列挙する
+// 情報を取得できなかったモードは飛ばす
+pub fn list_modes<'a>(
+    efi_system_table: &'a EfiSystemTable,
+    gop: &'a EfiGraphicsOutputProtocol<'a>,
+) -> impl Iterator<Item = (u32, ModeInfo)> + 'a {
+    (0..gop.mode.max_mode).filter_map(move |mode_number| {
+        let mut size_of_info = 0;
+        let mut info = null::<EfiGraphicsOutputProtocolPixelInfo>();
+        let status = (gop.query_mode)(gop, mode_number, &mut size_of_info, &mut info);
+        if status != EFI_SUCCESS || info.is_null() {
+            return None;
+        }
+        let mode_info = ModeInfo::from(unsafe { &*info });
+        let _ = (efi_system_table.boot_services.free_pool)(info as *mut EfiVoid);
+        Some((mode_number, mode_info))
+    })
 }
 
+// 画面の解像度の選び方（コマンドラインの"video=1920x1080"で指定できる）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModePreference {
+    // 対応している中で一番解像度が大きいもの
+    #[default]
+    Highest,
+    // 指定した解像度（なければHighestと同じ）
+    Resolution(u32, u32),
+}
+impl ModePreference {
+    pub fn from_command_line(command_line: &str) -> Self {
+        command_line
+            .split_whitespace()
+            .filter_map(|arg| arg.strip_prefix("video="))
+            .find_map(|v| {
+                let (w, h) = v.split_once('x')?;
+                Some(Self::Resolution(w.parse().ok()?, h.parse().ok()?))
+            })
+            .unwrap_or_default()
+    }
+}
+
+// 候補の中から使うモードを選ぶ（フレームバッファに直接描けないモードは使わない）
+// 指定された解像度があればそれを、なければ一番解像度が大きいもの（同じ場合は先に見つかった方）
+fn choose_mode(
+    modes: impl Iterator<Item = (u32, ModeInfo)>,
+    preference: ModePreference,
+) -> Option<(u32, ModeInfo)> {
+    let mut best: Option<(u32, ModeInfo)> = None;
+    for (mode_number, info) in modes.filter(|(_, info)| info.pixel_format.is_supported()) {
+        if preference == ModePreference::Resolution(info.width, info.height) {
+            return Some((mode_number, info));
+        }
+        if best.is_none_or(|(_, b)| b.pixel_count() < info.pixel_count()) {
+            best = Some((mode_number, info));
+        }
+    }
+    best
+}
+
+// GOPのモードを選んで切り替える
+// 切り替えに失敗したら、画面が真っ暗なままにならないように元のモードに戻す
+pub fn select_best_mode(
+    efi_system_table: &EfiSystemTable,
+    gop: &EfiGraphicsOutputProtocol,
+    preference: ModePreference,
+) {
+    let current = gop.mode.mode;
+    let Some((mode_number, info)) = choose_mode(list_modes(efi_system_table, gop), preference)
+    else {
+        warn!("GOP: no usable mode found, keeping mode {current}");
+        return;
+    };
+    if let ModePreference::Resolution(w, h) = preference {
+        if (info.width, info.height) != (w, h) {
+            warn!("GOP: {w}x{h} is not available");
+        }
+    }
+    if mode_number == current {
+        info!("GOP: using mode {mode_number} ({info})");
+        return;
+    }
+    if (gop.set_mode)(gop, mode_number) == EFI_SUCCESS {
+        info!("GOP: switched to mode {mode_number} ({info})");
+    } else {
+        warn!("GOP: failed to switch to mode {mode_number} ({info}), keeping mode {current}");
+        let _ = (gop.set_mode)(gop, current);
+    }
+}
+
+#[repr(C)]
 pub struct EfiLoadedImageProtocol {
-    _reserved: [u64; 8],
+    _reserved: [u64; 6],
+    // 起動時に渡された引数（UCS-2の文字列）のバイト数とアドレス
+    load_options_size: u32,
+    load_options: *const u16,
     pub image_base: u64,
     pub image_size: u64,
 }
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, load_options) == 56);
+const _: () = assert!(offset_of!(EfiLoadedImageProtocol, image_base) == 64);
+impl EfiLoadedImageProtocol {
+    // 起動時に渡された引数（UEFIシェルのコマンドラインなど）
+    pub fn load_options(&self) -> &[u16] {
+        if self.load_options.is_null() {
+            return &[];
+        }
+        let len = self.load_options_size as usize / size_of::<u16>();
+        unsafe { core::slice::from_raw_parts(self.load_options, len) }
+    }
+
+    // 読み込まれたイメージの先頭にあるヘッダー（PE/COFF）の部分
+    // ファームウェアはヘッダーもイメージと一緒にメモリに読み込む
+    pub fn pe_headers(&self) -> Option<&[u8]> {
+        if self.image_base == 0 {
+            return None;
+        }
+        let len = self.image_size.min(UEFI_PAGE_SIZE) as usize;
+        Some(unsafe { core::slice::from_raw_parts(self.image_base as *const u8, len) })
+    }
+}
+
+// UCS-2の文字列をUTF-8にしてbufに書き込み、書き込んだバイト数を返す
+// NUL文字で終わり、入りきらない文字は書き込まない（不正な文字はU+FFFDにする）
+pub fn decode_ucs2(src: &[u16], buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    let chars = char::decode_utf16(src.iter().copied().take_while(|c| *c != 0))
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER));
+    for c in chars {
+        if len + c.len_utf8() > buf.len() {
+            break;
+        }
+        len += c.encode_utf8(&mut buf[len..]).len();
+    }
+    len
+}
 
 pub fn locate_loaded_image_protocol(
     image_handle: EfiHandle,
@@ -287,6 +966,29 @@ pub struct VramBufferInfo {
     width: i64,
     height: i64,
     pixels_per_line: i64,
+    pixel_format: PixelFormat,
+}
+impl VramBufferInfo {
+    // フレームバッファがないとき（GOPが見つからずに続けるテストなど）の、大きさ0の画面
+    pub fn headless() -> Self {
+        Self {
+            buf: null_mut(),
+            width: 0,
+            height: 0,
+            pixels_per_line: 0,
+            pixel_format: PixelFormat::BltOnly,
+        }
+    }
+    // フレームバッファの先頭アドレスとバイト数
+    pub fn buf_addr(&self) -> u64 {
+        self.buf as u64
+    }
+    pub fn buf_size(&self) -> u64 {
+        (self.pixels_per_line * self.height * self.bytes_per_pixel()) as u64
+    }
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
 }
 // BitmapトレイトをVramBufferInfo構造体に実装する
 impl Bitmap for VramBufferInfo {
@@ -305,14 +1007,31 @@ impl Bitmap for VramBufferInfo {
     fn buf_mut(&mut self) -> *mut u8 {
         self.buf
     }
+    fn buf(&self) -> *const u8 {
+        self.buf
+    }
+    // RGBの並びではメモリ上で赤が先に来るので、赤と青を入れ替えて書く
+    fn pixel_value(&self, color: u32) -> u32 {
+        match self.pixel_format {
+            PixelFormat::Rgb => color & 0xff00ff00 | (color & 0xff) << 16 | (color >> 16) & 0xff,
+            _ => color,
+        }
+    }
 }
-pub fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
-    let gp = locate_graphic_protocol(efi_system_table)?;
+// 使うGOPとモードを選んでから、フレームバッファの情報を読む
+pub fn init_vram(
+    efi_system_table: &EfiSystemTable,
+    preference: ModePreference,
+) -> Result<VramBufferInfo> {
+    let gp = select_gop(enumerate_gop(efi_system_table)?, preference)
+        .ok_or("No graphics output protocol found")?;
+    select_best_mode(efi_system_table, gp, preference);
     Ok(VramBufferInfo {
         buf: gp.mode.frame_buffer_base as *mut u8,
         width: gp.mode.info.horizontal_resolution as i64,
         height: gp.mode.info.vertical_resolution as i64,
         pixels_per_line: gp.mode.info.pixels_per_scan_line as i64,
+        pixel_format: PixelFormat::from_raw(gp.mode.info.pixel_format),
     })
 }
 
@@ -357,6 +1076,8 @@ pub fn exit_from_efi_boot_services(
     loop {
         let status = efi_system_table.boot_services.get_memory_map(memory_map);
         assert_eq!(status, EfiStatus::Success);
+        // ここから先はコンソールに出力するとメモリマップが変わってしまうし、抜けた後は使えない
+        shutdown_con_out();
         let status =
             (efi_system_table.boot_services.exit_boot_services)(image_handle, memory_map.map_key);
         if status == EfiStatus::Success {
@@ -364,3 +1085,330 @@ pub fn exit_from_efi_boot_services(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphics::fill_rect;
+
+    // 赤と青が違う色を描いて、フレームバッファの形式どおりの順にバイトが並ぶことを確認する
+    #[test_case]
+    fn drawing_follows_the_pixel_format() {
+        for (pixel_format, bytes) in [
+            (PixelFormat::Rgb, [0x11, 0x22, 0x33]),
+            (PixelFormat::Bgr, [0x33, 0x22, 0x11]),
+        ] {
+            let mut pixels = [0u32; 4];
+            let mut vram = VramBufferInfo {
+                buf: pixels.as_mut_ptr() as *mut u8,
+                width: 2,
+                height: 2,
+                pixels_per_line: 2,
+                pixel_format,
+            };
+            fill_rect(&mut vram, 0x112233, 0, 0, 2, 2).expect("fill_rect failed");
+            for p in pixels {
+                assert_eq!(p.to_le_bytes()[..3], bytes, "{pixel_format:?}");
+            }
+        }
+    }
+
+    // テスト用のモードを作成する
+    fn mock_mode<'a>(
+        info: &'a EfiGraphicsOutputProtocolPixelInfo,
+        frame_buffer_base: u64,
+    ) -> EfiGraphicsOutputProtocolMode<'a> {
+        EfiGraphicsOutputProtocolMode {
+            max_mode: 1,
+            mode: 0,
+            info,
+            size_of_info: size_of::<EfiGraphicsOutputProtocolPixelInfo>() as u64,
+            frame_buffer_base,
+            frame_buffer_size: 0,
+        }
+    }
+
+    fn mock_info(w: u32, h: u32) -> EfiGraphicsOutputProtocolPixelInfo {
+        EfiGraphicsOutputProtocolPixelInfo {
+            version: 0,
+            horizontal_resolution: w,
+            vertical_resolution: h,
+            // PixelBlueGreenRedReserved8BitPerColor
+            pixel_format: 1,
+            _pixel_information: [0; 4],
+            pixels_per_scan_line: w,
+        }
+    }
+
+    // テストではモードを問い合わせたり切り替えたりしない
+    extern "win64" fn unsupported_query_mode(
+        _this: *const EfiGraphicsOutputProtocol,
+        _mode_number: u32,
+        _size_of_info: *mut usize,
+        _info: *mut *const EfiGraphicsOutputProtocolPixelInfo,
+    ) -> usize {
+        usize::MAX
+    }
+    extern "win64" fn unsupported_set_mode(
+        _this: *const EfiGraphicsOutputProtocol,
+        _mode_number: u32,
+    ) -> usize {
+        usize::MAX
+    }
+    fn mock_gop<'a>(mode: &'a EfiGraphicsOutputProtocolMode<'a>) -> EfiGraphicsOutputProtocol<'a> {
+        EfiGraphicsOutputProtocol {
+            query_mode: unsupported_query_mode,
+            set_mode: unsupported_set_mode,
+            _blt: 0,
+            mode,
+        }
+    }
+
+    // 解像度の異なるGOPの中から一番大きいものが選ばれることを確認する
+    #[test_case]
+    fn select_gop_picks_largest_resolution() {
+        let info0 = mock_info(800, 600);
+        let info1 = mock_info(1920, 1080);
+        let info2 = mock_info(1024, 768);
+        let mode0 = mock_mode(&info0, 0x1000);
+        let mode1 = mock_mode(&info1, 0x2000);
+        let mode2 = mock_mode(&info2, 0x3000);
+        let gops = [mock_gop(&mode0), mock_gop(&mode1), mock_gop(&mode2)];
+        let selected = select_gop(gops.iter(), ModePreference::Highest).expect("no GOP selected");
+        assert_eq!(selected.frame_buffer_base(), 0x2000);
+        assert!(select_gop(gops[..0].iter(), ModePreference::Highest).is_none());
+    }
+
+    // 同じ解像度の場合は先に見つかったものが選ばれることを確認する
+    #[test_case]
+    fn select_gop_keeps_first_on_tie() {
+        let info = mock_info(640, 480);
+        let mode0 = mock_mode(&info, 0x1000);
+        let mode1 = mock_mode(&info, 0x2000);
+        let gops = [mock_gop(&mode0), mock_gop(&mode1)];
+        let selected = select_gop(gops.iter(), ModePreference::Highest).expect("no GOP selected");
+        assert_eq!(selected.frame_buffer_base(), 0x1000);
+    }
+
+    // video=WxHが指定されていれば、小さくても今の解像度がそれと同じGOPが選ばれることを確認する
+    #[test_case]
+    fn select_gop_prefers_boot_option_resolution() {
+        let info0 = mock_info(1920, 1080);
+        let info1 = mock_info(1024, 768);
+        let info2 = mock_info(1024, 768);
+        let mode0 = mock_mode(&info0, 0x1000);
+        let mode1 = mock_mode(&info1, 0x2000);
+        let mode2 = mock_mode(&info2, 0x3000);
+        let gops = [mock_gop(&mode0), mock_gop(&mode1), mock_gop(&mode2)];
+        let select =
+            |preference| select_gop(gops.iter(), preference).map(|gop| gop.frame_buffer_base());
+        assert_eq!(
+            select(ModePreference::from_command_line("video=1024x768")),
+            Some(0x2000)
+        );
+        // 一致するものがなければ最も大きいものになる
+        assert_eq!(select(ModePreference::Resolution(640, 480)), Some(0x1000));
+        assert_eq!(select(ModePreference::Highest), Some(0x1000));
+    }
+
+    fn mode_info(width: u32, height: u32, pixel_format: PixelFormat) -> ModeInfo {
+        ModeInfo {
+            width,
+            height,
+            pixel_format,
+            pixels_per_scan_line: width,
+        }
+    }
+
+    // フレームバッファに描けないモードは、解像度が大きくても選ばれないことを確認する
+    #[test_case]
+    fn choose_mode_skips_unsupported_formats() {
+        let modes = [
+            (0, mode_info(800, 600, PixelFormat::Bgr)),
+            (1, mode_info(3840, 2160, PixelFormat::BltOnly)),
+            (2, mode_info(1280, 1024, PixelFormat::Rgb)),
+            (3, mode_info(2560, 1440, PixelFormat::BitMask)),
+            (4, mode_info(1024, 1280, PixelFormat::Bgr)),
+        ];
+        let (mode_number, _) = choose_mode(modes.into_iter(), ModePreference::Highest).unwrap();
+        // 同じ画素数の場合は先に見つかった方
+        assert_eq!(mode_number, 2);
+        let only_blt = [(0, mode_info(1920, 1080, PixelFormat::BltOnly))];
+        assert!(choose_mode(only_blt.into_iter(), ModePreference::Highest).is_none());
+    }
+
+    // 指定した解像度があればそれが、なければ一番大きいものが選ばれることを確認する
+    #[test_case]
+    fn choose_mode_honors_requested_resolution() {
+        let modes = [
+            (0, mode_info(640, 480, PixelFormat::Bgr)),
+            (1, mode_info(1024, 768, PixelFormat::BltOnly)),
+            (2, mode_info(1024, 768, PixelFormat::Bgr)),
+            (3, mode_info(1920, 1080, PixelFormat::Bgr)),
+        ];
+        let choose = |preference| choose_mode(modes.into_iter(), preference).map(|(n, _)| n);
+        assert_eq!(choose(ModePreference::Resolution(1024, 768)), Some(2));
+        assert_eq!(choose(ModePreference::Resolution(640, 480)), Some(0));
+        assert_eq!(choose(ModePreference::Resolution(1280, 720)), Some(3));
+    }
+
+    #[test_case]
+    fn mode_preference_is_read_from_command_line() {
+        let parse = ModePreference::from_command_line;
+        assert_eq!(parse(""), ModePreference::Highest);
+        assert_eq!(
+            parse("quiet video=1280x720 debug"),
+            ModePreference::Resolution(1280, 720)
+        );
+        assert_eq!(parse("video=1280"), ModePreference::Highest);
+        assert_eq!(parse("video=axb"), ModePreference::Highest);
+        assert_eq!(parse("xvideo=800x600"), ModePreference::Highest);
+    }
+
+    // ACPI 2.0のRSDPがあれば、1.0より優先されることを確認する
+    #[test_case]
+    fn find_acpi_rsdp_prefers_acpi_20() {
+        let other = EfiConfigurationTable {
+            vendor_guid: EFI_LOADED_IMAGE_PROTOCOL_GUID,
+            vendor_table: 0x1000,
+        };
+        let acpi10 = EfiConfigurationTable {
+            vendor_guid: EFI_ACPI_10_TABLE_GUID,
+            vendor_table: 0x2000,
+        };
+        let acpi20 = EfiConfigurationTable {
+            vendor_guid: EFI_ACPI_20_TABLE_GUID,
+            vendor_table: 0x3000,
+        };
+        assert_eq!(find_acpi_rsdp(&[other, acpi10, acpi20]), Some(0x3000));
+        assert_eq!(find_acpi_rsdp(&[other, acpi10]), Some(0x2000));
+        assert_eq!(find_acpi_rsdp(&[other]), None);
+    }
+
+    #[test_case]
+    fn ucs2_chunks_are_bounded_and_nul_terminated() {
+        let s = "a\u{65e5}\u{1f600}".repeat(50);
+        let mut units = Vec::new();
+        for_each_ucs2_chunk(&s, |chunk| {
+            assert!(chunk.len() <= UCS2_CHUNK_LEN + 1);
+            assert_eq!(chunk.last(), Some(&0));
+            units.extend_from_slice(&chunk[..chunk.len() - 1]);
+        });
+        // BMPの外の文字はU+FFFDになる
+        let expected: Vec<u16> = [b'a' as u16, 0x65e5, 0xfffd].repeat(50);
+        assert_eq!(units, expected);
+        let mut called = false;
+        for_each_ucs2_chunk("", |_| called = true);
+        assert!(!called);
+    }
+    #[test_case]
+    fn decode_ucs2_stops_at_nul_and_buffer_end() {
+        let src: [u16; 8] = [
+            'a' as u16, ' ' as u16, 0x65e5, 'b' as u16, 0xd800, 0, 'x' as u16, 0,
+        ];
+        let mut buf = [0u8; 16];
+        let len = decode_ucs2(&src, &mut buf);
+        assert_eq!(core::str::from_utf8(&buf[..len]), Ok("a 日b\u{fffd}"));
+        // 入りきらない文字は途中で切らずに捨てる
+        let mut small = [0u8; 4];
+        let len = decode_ucs2(&src, &mut small);
+        assert_eq!(core::str::from_utf8(&small[..len]), Ok("a "));
+    }
+
+    #[test_case]
+    fn unknown_memory_types_are_reported_as_unknown() {
+        let descriptor = |memory_type, physical_start, number_of_pages| EfiMemoryDescriptor {
+            memory_type,
+            _padding: 0,
+            physical_start,
+            virtual_start: 0,
+            number_of_pages,
+            attribute: 0xf,
+        };
+        let input = [
+            descriptor(7, 0x1000, 4),
+            descriptor(0x7000_0001, 0x5000, 2),
+            descriptor(15, 0x7000, 1),
+            descriptor(0x8000_0000, 0x8000, 1),
+        ];
+        // 実際のファームウェアと同じように、ディスクリプタの間隔は構造体より大きくする
+        let mut map = MemoryMapHolder::new();
+        map.descripter_size = 48;
+        map.memory_map_size = map.descripter_size * input.len();
+        for (i, d) in input.iter().enumerate() {
+            unsafe {
+                let p = map
+                    .memory_map_buffer
+                    .as_mut_ptr()
+                    .add(i * map.descripter_size);
+                (p as *mut EfiMemoryDescriptor).write_unaligned(*d);
+            }
+        }
+        let kinds: Vec<EfiMemoryType> = map.iter().map(|e| e.memory_type()).collect();
+        assert_eq!(
+            kinds,
+            [
+                EfiMemoryType::CONVENTIONAL_MEMORY,
+                EfiMemoryType::Unknown(0x7000_0001),
+                EfiMemoryType::UNACCEPTED_MEMORY,
+                EfiMemoryType::Unknown(0x8000_0000),
+            ]
+        );
+        let regions = MemoryRegions::from(&map);
+        assert_eq!(regions.len(), 4);
+        assert_eq!(regions.total_pages(EfiMemoryType::CONVENTIONAL_MEMORY), 4);
+        assert_eq!(regions.total_pages(EfiMemoryType::Unknown(0x7000_0001)), 2);
+        assert_eq!(
+            alloc::format!("{}", EfiMemoryType::Unknown(0x7000_0001)),
+            "UNKNOWN(0x70000001)"
+        );
+        assert_eq!(
+            alloc::format!("{}", EfiMemoryType::CONVENTIONAL_MEMORY),
+            "CONVENTIONAL_MEMORY"
+        );
+    }
+
+    #[test_case]
+    fn memory_regions_merge_only_same_kind_and_attributes() {
+        use EfiMemoryType::*;
+        let region = |start, pages, kind, attributes| MemoryRegion {
+            start,
+            pages,
+            kind,
+            attributes,
+        };
+        // アドレス順に並んでいないものも混ぜる
+        let input = [
+            region(0x3000, 1, CONVENTIONAL_MEMORY, 0xf),
+            region(0x0000, 2, CONVENTIONAL_MEMORY, 0xf),
+            region(0x2000, 1, CONVENTIONAL_MEMORY, 0xf),
+            // 種類が違うので隣り合っていてもまとめない
+            region(0x4000, 1, BOOT_SERVICES_DATA, 0xf),
+            region(0x5000, 2, CONVENTIONAL_MEMORY, 0xf),
+            // 属性が違うのでまとめない
+            region(0x7000, 1, CONVENTIONAL_MEMORY, 0x8),
+            // 間が空いているのでまとめない
+            region(0x9000, 1, CONVENTIONAL_MEMORY, 0x8),
+            region(0xa000, 0, CONVENTIONAL_MEMORY, 0x8),
+        ];
+        let regions = MemoryRegions::new(input.iter().copied());
+        let merged: Vec<MemoryRegion> = regions.iter().copied().collect();
+        assert_eq!(
+            merged,
+            [
+                region(0x0000, 4, CONVENTIONAL_MEMORY, 0xf),
+                region(0x4000, 1, BOOT_SERVICES_DATA, 0xf),
+                region(0x5000, 2, CONVENTIONAL_MEMORY, 0xf),
+                region(0x7000, 1, CONVENTIONAL_MEMORY, 0x8),
+                region(0x9000, 1, CONVENTIONAL_MEMORY, 0x8),
+            ]
+        );
+        let before: u64 = input
+            .iter()
+            .filter(|r| r.kind == CONVENTIONAL_MEMORY)
+            .map(|r| r.pages)
+            .sum();
+        assert_eq!(regions.total_pages(CONVENTIONAL_MEMORY), before);
+        assert_eq!(regions.total_pages(BOOT_SERVICES_DATA), 1);
+    }
+}
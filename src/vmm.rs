@@ -0,0 +1,297 @@
+extern crate alloc;
+
+use crate::page_alloc::PAGE_ALLOCATOR;
+use crate::paging::kernel_pml4;
+use crate::paging::map_pages;
+use crate::paging::PageAttr;
+use crate::paging::TranslationResult;
+use crate::paging::PAGE_SIZE;
+use crate::result::Result;
+use crate::sync::SpinLock;
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+
+// 仮想アドレス空間のうち、物理メモリのアイデンティティマップとは別に使う範囲を管理する
+// 範囲を割り当ててから、その中のページをmap/unmap/protectする
+// ページテーブルを書き換えたページは、そのページのTLBだけをinvlpgで捨てる
+// 他のCPUのTLBはmap_pagesがシュートダウンで捨てさせるので、unmapした物理ページはその後に解放する
+// map_lazyで割り当てた範囲は最初は何もマップせず、触ったページだけを
+// ページフォルトのハンドラ（handle_page_fault）が0で埋めたページにマップする
+
+// カーネルが使う範囲（上位の半分の先頭512GiB、PML4のエントリ1つ分）
+pub const KERNEL_VMM_AREA: Range<u64> = 0xFFFF_8000_0000_0000..0xFFFF_8080_0000_0000;
+// カーネルのスタックに使う範囲（stack.rsが別のVmmで管理する、PML4の次のエントリの先頭4GiB）
+pub const KERNEL_STACK_AREA: Range<u64> = 0xFFFF_8080_0000_0000..0xFFFF_8081_0000_0000;
+// 割り当てた範囲の後ろに空けておく、マップしないページの数
+// 範囲からはみ出したアクセスは、次の範囲を壊さずにページフォルトになる
+const GUARD_PAGES: u64 = 1;
+
+// 割り当てた範囲
+#[derive(Debug, Copy, Clone)]
+struct Region {
+    pages: usize,
+    // map_lazyで割り当てた範囲なら、ページフォルトでマップするときの属性
+    lazy: Option<PageAttr>,
+}
+impl Region {
+    fn end(&self, start: u64) -> u64 {
+        start + (self.pages * PAGE_SIZE) as u64
+    }
+}
+
+pub struct Vmm {
+    area: Range<u64>,
+    // 割り当てた範囲（先頭のアドレス → 範囲）
+    regions: SpinLock<BTreeMap<u64, Region>>,
+}
+impl Vmm {
+    pub const fn new(area: Range<u64>) -> Self {
+        Self {
+            area,
+            regions: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    // pages枚のページが入る仮想アドレスの範囲を割り当てて、先頭のアドレスを返す
+    // 割り当てただけではマップしない
+    pub fn alloc_range(&self, pages: usize) -> Result<u64> {
+        self.alloc_region(pages, None)
+    }
+
+    // pages枚のページが入る範囲を割り当てて、先頭のアドレスを返す
+    // ページは触ったときに初めて物理ページを受け取り、attrでマップされる
+    // 大きなヒープやユーザーのスタックのように、全部は使わないかもしれない範囲に使う
+    pub fn map_lazy(&self, pages: usize, attr: PageAttr) -> Result<u64> {
+        if attr == PageAttr::NotPresent {
+            return Err("Vmm: lazy pages must be present");
+        }
+        self.alloc_region(pages, Some(attr))
+    }
+
+    fn alloc_region(&self, pages: usize, lazy: Option<PageAttr>) -> Result<u64> {
+        if pages == 0 {
+            return Err("Vmm: pages must not be zero");
+        }
+        let size = (pages as u64 + GUARD_PAGES) * PAGE_SIZE as u64;
+        let mut regions = self.regions.lock();
+        // 割り当てた範囲の間の隙間を、先頭から順に探す
+        let mut start = self.area.start;
+        for (addr, region) in regions.iter() {
+            if start + size <= *addr {
+                break;
+            }
+            start = region.end(*addr) + GUARD_PAGES * PAGE_SIZE as u64;
+        }
+        if start
+            .checked_add(size)
+            .is_none_or(|end| end > self.area.end)
+        {
+            return Err("Vmm: out of virtual address space");
+        }
+        regions.insert(start, Region { pages, lazy });
+        Ok(start)
+    }
+
+    // alloc_rangeで割り当てた範囲を、マップしているページも外してから返す
+    // map_lazyで割り当てた範囲なら、ページフォルトで受け取った物理ページも返す
+    pub fn free_range(&self, start: u64) -> Result<()> {
+        let region = *self
+            .regions
+            .lock()
+            .get(&start)
+            .ok_or("Vmm: range is not allocated")?;
+        self.unmap(start, region.pages)?;
+        self.regions.lock().remove(&start);
+        Ok(())
+    }
+
+    // [virt, virt + pages * PAGE_SIZE)が、割り当てた1つの範囲に収まっているか確かめて、その範囲を返す
+    fn check(&self, virt: u64, pages: usize) -> Result<Region> {
+        if !virt.is_multiple_of(PAGE_SIZE as u64) {
+            return Err("Vmm: address is not page aligned");
+        }
+        let regions = self.regions.lock();
+        let (start, region) = regions
+            .range(..=virt)
+            .next_back()
+            .ok_or("Vmm: range is not allocated")?;
+        if virt + (pages * PAGE_SIZE) as u64 > region.end(*start) {
+            return Err("Vmm: range is not allocated");
+        }
+        Ok(*region)
+    }
+
+    // virtからのpages枚のページを、physからの物理ページに対応付ける
+    // map_lazyで割り当てた範囲の物理ページはVmmが持つので、ここではマップできない
+    pub fn map(&self, virt: u64, phys: u64, pages: usize, attr: PageAttr) -> Result<()> {
+        if self.check(virt, pages)?.lazy.is_some() {
+            return Err("Vmm: range is mapped lazily");
+        }
+        map_pages(virt, phys, pages, attr)
+    }
+
+    // virtからのpages枚のページの対応を外す（範囲は割り当てたまま）
+    // map_lazyで割り当てた範囲では、マップしていた物理ページを返す（次に触ると0のページになる）
+    pub fn unmap(&self, virt: u64, pages: usize) -> Result<()> {
+        if self.check(virt, pages)?.lazy.is_none() {
+            return map_pages(virt, 0, pages, PageAttr::NotPresent);
+        }
+        let table = kernel_pml4().ok_or("Paging is not initialized")?;
+        for i in 0..pages as u64 {
+            let page = virt + i * PAGE_SIZE as u64;
+            if let Ok(TranslationResult::PageMapped4K { phys }) = table.translate(page) {
+                map_pages(page, 0, 1, PageAttr::NotPresent)?;
+                unsafe { PAGE_ALLOCATOR.free_pages(phys as *mut u8, 1) };
+            }
+        }
+        Ok(())
+    }
+
+    // addrへのアクセスで起きたページフォルトを、map_lazyの範囲ならページをマップして解決する
+    // 解決したらtrueを返す（フォルトした命令をもう一度実行すればよい）
+    pub fn handle_page_fault(&self, addr: u64) -> bool {
+        let page = addr & !(PAGE_SIZE as u64 - 1);
+        let Some(attr) = self.check(page, 1).ok().and_then(|region| region.lazy) else {
+            return false;
+        };
+        let Ok(frame) = PAGE_ALLOCATOR.alloc_zeroed_pages(1) else {
+            return false;
+        };
+        if map_pages(page, frame as u64, 1, attr).is_err() {
+            unsafe { PAGE_ALLOCATOR.free_pages(frame, 1) };
+            return false;
+        }
+        true
+    }
+
+    // マップしているvirtからのpages枚のページの属性を、同じ物理ページのままattrに変える
+    pub fn protect(&self, virt: u64, pages: usize, attr: PageAttr) -> Result<()> {
+        self.check(virt, pages)?;
+        let table = kernel_pml4().ok_or("Paging is not initialized")?;
+        for i in 0..pages as u64 {
+            let page = virt + i * PAGE_SIZE as u64;
+            let TranslationResult::PageMapped4K { phys } = table.translate(page)? else {
+                return Err("Vmm: large pages can not be protected");
+            };
+            map_pages(page, phys, 1, attr)?;
+        }
+        Ok(())
+    }
+
+    // 割り当てた範囲の数
+    pub fn region_count(&self) -> usize {
+        self.regions.lock().len()
+    }
+}
+
+pub static KERNEL_VMM: Vmm = Vmm::new(KERNEL_VMM_AREA);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::is_mapped;
+    use crate::x86::take_last_exception;
+
+    const PAGE: u64 = PAGE_SIZE as u64;
+
+    #[test_case]
+    fn ranges_are_separated_by_guard_pages_and_reused() {
+        // マップしないので、どこを管理してもよい
+        let base = 0xFFFF_9000_0000_0000;
+        let vmm = Vmm::new(base..base + 8 * PAGE);
+        let a = vmm.alloc_range(2).unwrap();
+        let b = vmm.alloc_range(3).unwrap();
+        assert_eq!(a, base);
+        assert_eq!(b, base + 3 * PAGE);
+        // 残りは1ページ分しか空いていない（ガードページが入らない）
+        assert!(vmm.alloc_range(1).is_err());
+        assert!(vmm.alloc_range(0).is_err());
+        vmm.regions.lock().remove(&a);
+        assert_eq!(vmm.alloc_range(1).unwrap(), base);
+        assert_eq!(vmm.region_count(), 2);
+        assert!(vmm.check(b + PAGE, 2).is_ok());
+        assert!(vmm.check(b + PAGE, 3).is_err());
+        assert!(vmm.check(base + 7 * PAGE, 1).is_err());
+    }
+    #[test_case]
+    fn map_protect_and_unmap_kernel_pages() {
+        let frames = PAGE_ALLOCATOR.alloc_zeroed_pages(2).unwrap();
+        let phys = frames as u64;
+        let virt = KERNEL_VMM.alloc_range(2).unwrap();
+        KERNEL_VMM
+            .map(virt, phys, 2, PageAttr::ReadWriteKernelNoExec)
+            .unwrap();
+        unsafe {
+            ((virt + PAGE) as *mut u64).write_volatile(0x1234);
+            assert_eq!(
+                (frames.add(PAGE_SIZE) as *const u64).read_volatile(),
+                0x1234
+            );
+        }
+        KERNEL_VMM
+            .protect(virt, 2, PageAttr::ReadOnlyKernelNoExec)
+            .unwrap();
+        let table = kernel_pml4().unwrap();
+        assert_eq!(
+            table.translate(virt + PAGE),
+            Ok(TranslationResult::PageMapped4K { phys: phys + PAGE })
+        );
+        assert_eq!(
+            unsafe { ((virt + PAGE) as *const u64).read_volatile() },
+            0x1234
+        );
+        // 割り当てた範囲の外（ガードページ）には触れない
+        assert!(KERNEL_VMM
+            .map(virt + 2 * PAGE, phys, 1, PageAttr::ReadWriteKernelNoExec)
+            .is_err());
+        KERNEL_VMM.unmap(virt + PAGE, 1).unwrap();
+        assert!(is_mapped(virt));
+        assert!(!is_mapped(virt + PAGE));
+        assert!(KERNEL_VMM
+            .protect(virt + PAGE, 1, PageAttr::ReadOnlyKernel)
+            .is_err());
+        KERNEL_VMM.free_range(virt).unwrap();
+        assert!(!is_mapped(virt));
+        assert!(KERNEL_VMM.free_range(virt).is_err());
+        unsafe { PAGE_ALLOCATOR.free_pages(frames, 2) };
+    }
+    #[test_case]
+    fn lazy_pages_are_mapped_on_first_touch() {
+        let before = PAGE_ALLOCATOR.free_page_count();
+        let virt = KERNEL_VMM
+            .map_lazy(1024, PageAttr::ReadWriteKernelNoExec)
+            .unwrap();
+        // 4MiBの範囲を割り当てても、物理ページはまだ使わない
+        assert_eq!(PAGE_ALLOCATOR.free_page_count(), before);
+        assert!(!is_mapped(virt + 100 * PAGE));
+        take_last_exception();
+        unsafe {
+            let p = (virt + 100 * PAGE + 8) as *mut u64;
+            assert_eq!(p.read_volatile(), 0);
+            p.write_volatile(0x5678);
+            assert_eq!(p.read_volatile(), 0x5678);
+        }
+        // 解決したフォルトは致命的な例外として記録しない
+        assert_eq!(take_last_exception(), None);
+        assert!(is_mapped(virt + 100 * PAGE));
+        assert!(!is_mapped(virt + 101 * PAGE));
+        assert!(KERNEL_VMM
+            .map(virt, 0x1000, 1, PageAttr::ReadWriteKernelNoExec)
+            .is_err());
+        // 外したページは、次に触ると0のページになる
+        KERNEL_VMM.unmap(virt + 100 * PAGE, 1).unwrap();
+        assert_eq!(
+            unsafe { ((virt + 100 * PAGE + 8) as *const u64).read_volatile() },
+            0
+        );
+        assert!(KERNEL_VMM.handle_page_fault(virt + 5 * PAGE + 123));
+        assert!(is_mapped(virt + 5 * PAGE));
+        // ガードページや割り当てていない場所は解決しない
+        assert!(!KERNEL_VMM.handle_page_fault(virt + 1024 * PAGE));
+        KERNEL_VMM.free_range(virt).unwrap();
+        assert!(!is_mapped(virt + 5 * PAGE));
+        // ページテーブルに使ったページは残るので、物理ページの数はそれ以外で比べる
+        let table_pages = before - PAGE_ALLOCATOR.free_page_count();
+        assert!(table_pages <= 3, "leaked {table_pages} pages");
+    }
+}
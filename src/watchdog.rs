@@ -0,0 +1,305 @@
+use crate::panic::reboot;
+use crate::result::Result;
+use crate::stack::KERNEL_STACK_SIZE;
+use crate::timer::ticks;
+use crate::timer::TICK_HZ;
+use crate::warn;
+use crate::x86::InterruptGuard;
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+// ソフトウェアのウォッチドッグ
+// メインループ（シェルやExecutorの待ち時間も含む）がpet()を呼ばないまま閾値を超えたら、
+// タイマー割り込みの中で割り込まれた場所を報告する
+// 割り込みを禁止したまま止まっている場合は、割り込みが許可された時点で見つかる
+
+pub const DEFAULT_TIMEOUT_MS: u64 = 5000;
+// 数ティックより短いと、pet()した直後でも反応してしまう
+const MIN_TIMEOUT_MS: u64 = 10;
+// バックトレースで辿るフレームの数の上限
+const MAX_BACKTRACE_DEPTH: usize = 16;
+
+// 閾値を超えたときにすること
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Action {
+    // 記録するだけで何も表示しない（テスト用）
+    Record,
+    // 割り込まれた場所とバックトレースを表示する
+    Warn,
+    // 表示してからpanicする
+    Panic,
+    // 表示してから再起動する
+    Reboot,
+}
+impl Action {
+    const ALL: [Action; 4] = [Action::Record, Action::Warn, Action::Panic, Action::Reboot];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Record => "record",
+            Action::Warn => "warn",
+            Action::Panic => "panic",
+            Action::Reboot => "reboot",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|a| a.name() == name)
+            .ok_or("watchdog: unknown action")
+    }
+
+    fn from_u8(v: u8) -> Self {
+        Self::ALL[v as usize]
+    }
+}
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// タイマー割り込みで割り込まれたときのレジスタ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptedContext {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rbp: u64,
+}
+
+// ウォッチドッグが反応したときの記録
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bite {
+    pub rip: u64,
+    pub rsp: u64,
+    // 最後にpet()されてからの時間
+    pub stalled_ms: u64,
+}
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+static TIMEOUT_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIMEOUT_MS);
+static ACTION: AtomicU8 = AtomicU8::new(Action::Warn as u8);
+// 最後にpet()されたときのティック数
+static LAST_PET: AtomicU64 = AtomicU64::new(0);
+// 同じ停止を何度も報告しないように、pet()されるまでは一度だけ反応する
+static BITTEN: AtomicBool = AtomicBool::new(false);
+static BITE_COUNT: AtomicU64 = AtomicU64::new(0);
+static LAST_BITE: Mutex<Option<Bite>> = Mutex::new(None);
+
+// メインループが動いていることを知らせる
+pub fn pet() {
+    LAST_PET.store(ticks(), Ordering::Relaxed);
+    BITTEN.store(false, Ordering::Relaxed);
+}
+
+// 監視を始める（始めた時点でpetしたことにする）
+pub fn arm() {
+    pet();
+    ARMED.store(true, Ordering::Release);
+}
+
+pub fn disarm() {
+    ARMED.store(false, Ordering::Release);
+}
+
+pub fn is_armed() -> bool {
+    ARMED.load(Ordering::Acquire)
+}
+
+pub fn timeout_ms() -> u64 {
+    TIMEOUT_MS.load(Ordering::Relaxed)
+}
+
+pub fn set_timeout_ms(ms: u64) -> Result<()> {
+    if ms < MIN_TIMEOUT_MS {
+        return Err("watchdog: timeout must be at least 10 ms");
+    }
+    TIMEOUT_MS.store(ms, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn action() -> Action {
+    Action::from_u8(ACTION.load(Ordering::Relaxed))
+}
+
+pub fn set_action(action: Action) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+// 起動してから反応した回数
+pub fn bite_count() -> u64 {
+    BITE_COUNT.load(Ordering::Acquire)
+}
+
+pub fn last_bite() -> Option<Bite> {
+    let _guard = InterruptGuard::new();
+    *LAST_BITE.lock()
+}
+
+// rbpから始めてフレームポインタを辿り、戻りアドレスをframesに書き込んで個数を返す
+// 壊れたフレームでページフォルトを起こさないように、
+// rspから上に向かって単調に増え、スタック1つ分に収まる間だけ辿る
+pub(crate) fn walk_frames(rbp: u64, rsp: u64, frames: &mut [u64]) -> usize {
+    let limit = rsp.saturating_add(KERNEL_STACK_SIZE as u64);
+    let mut rbp = rbp;
+    let mut prev = rsp;
+    let mut depth = 0;
+    while depth < frames.len() {
+        if rbp < prev || !rbp.is_multiple_of(8) || rbp.saturating_add(16) > limit {
+            break;
+        }
+        // [rbp]に呼び出し元のrbp、[rbp+8]に戻りアドレスが入っている
+        let (next, ret) = unsafe {
+            let p = rbp as *const u64;
+            (p.read(), p.add(1).read())
+        };
+        if ret == 0 {
+            break;
+        }
+        frames[depth] = ret;
+        depth += 1;
+        prev = rbp + 16;
+        rbp = next;
+    }
+    depth
+}
+
+fn report(ctx: &InterruptedContext, stalled_ms: u64) {
+    warn!("!!!!!!!! WATCHDOG: the main loop has not run for {stalled_ms} ms !!!!!!!!");
+    warn!(
+        "WATCHDOG: interrupted at RIP={:#018X}, RSP={:#018X}, RBP={:#018X}",
+        ctx.rip, ctx.rsp, ctx.rbp
+    );
+    let mut frames = [0u64; MAX_BACKTRACE_DEPTH];
+    let depth = walk_frames(ctx.rbp, ctx.rsp, &mut frames);
+    warn!("WATCHDOG: backtrace:");
+    warn!("  #0 {:#018X}", ctx.rip);
+    for (i, ret) in frames[..depth].iter().enumerate() {
+        warn!("  #{} {ret:#018X}", i + 1);
+    }
+}
+
+// タイマー割り込みのたびに割り込みハンドラから呼ぶ
+// 割り込みの中なので、ヒープは使わず、ロックも待たない
+pub fn on_timer_tick(ctx: &InterruptedContext) {
+    if !is_armed() || BITTEN.load(Ordering::Relaxed) {
+        return;
+    }
+    let elapsed = ticks().wrapping_sub(LAST_PET.load(Ordering::Relaxed));
+    let stalled_ms = elapsed * 1000 / TICK_HZ;
+    if stalled_ms < timeout_ms() {
+        return;
+    }
+    BITTEN.store(true, Ordering::Relaxed);
+    if let Some(mut last) = LAST_BITE.try_lock() {
+        *last = Some(Bite {
+            rip: ctx.rip,
+            rsp: ctx.rsp,
+            stalled_ms,
+        });
+    }
+    BITE_COUNT.fetch_add(1, Ordering::Release);
+    let action = action();
+    if action != Action::Record {
+        report(ctx, stalled_ms);
+    }
+    match action {
+        Action::Record | Action::Warn => {}
+        Action::Panic => panic!("watchdog: the main loop has not run for {stalled_ms} ms"),
+        Action::Reboot => reboot(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::arch::asm;
+    use core::ops::Range;
+
+    #[test_case]
+    fn actions_are_parsed_by_name() {
+        for action in Action::ALL {
+            assert_eq!(Action::from_name(action.name()), Ok(action));
+            assert_eq!(Action::from_u8(action as u8), action);
+        }
+        assert!(Action::from_name("explode").is_err());
+        assert!(set_timeout_ms(0).is_err());
+    }
+    #[test_case]
+    fn frames_are_walked_until_they_look_broken() {
+        // 下から順に3つのフレーム（呼び出し元のrbp, 戻りアドレス）を並べた偽のスタック
+        let mut stack = [0u64; 8];
+        let p = stack.as_mut_ptr();
+        let base = p as u64;
+        // 3つ目のフレームは下を指しているので、そこで止まる
+        let values = [base + 16, 0x1111, base + 32, 0x2222, base, 0x3333];
+        for (i, v) in values.into_iter().enumerate() {
+            unsafe { p.add(i).write(v) };
+        }
+        let mut frames = [0u64; MAX_BACKTRACE_DEPTH];
+        assert_eq!(walk_frames(base, base, &mut frames), 3);
+        assert_eq!(frames[..3], [0x1111, 0x2222, 0x3333]);
+        let mut short = [0u64; 2];
+        assert_eq!(walk_frames(base, base, &mut short), 2);
+        // rspより下や、揃っていないrbpは辿らない
+        assert_eq!(walk_frames(base, base + 8, &mut frames), 0);
+        assert_eq!(walk_frames(base + 4, base, &mut frames), 0);
+        assert_eq!(walk_frames(0, base, &mut frames), 0);
+    }
+
+    // ウォッチドッグが反応するまでpet()せずに回り、回っていたコードの範囲を返す
+    // 反応しなくても終わるように、回る回数には上限を付ける
+    #[inline(never)]
+    fn spin_until_bitten(count_before: u64) -> Range<u64> {
+        let start: u64;
+        let end: u64;
+        unsafe {
+            asm!(
+                "lea {start}, [rip + 2f]",
+                "lea {end}, [rip + 3f]",
+                "2:",
+                "pause",
+                "cmp [{count}], {before}",
+                "jne 3f",
+                "dec {limit}",
+                "jnz 2b",
+                "3:",
+                start = out(reg) start,
+                end = out(reg) end,
+                count = in(reg) BITE_COUNT.as_ptr(),
+                before = in(reg) count_before,
+                limit = inout(reg) 1u64 << 32 => _,
+                options(nostack, readonly)
+            );
+        }
+        start..end
+    }
+    #[test_case]
+    fn watchdog_reports_where_the_loop_is_stuck() {
+        let saved_timeout = timeout_ms();
+        let saved_action = action();
+        set_action(Action::Record);
+        set_timeout_ms(20).unwrap();
+        let before = bite_count();
+        arm();
+        let spin = spin_until_bitten(before);
+        disarm();
+        set_action(saved_action);
+        set_timeout_ms(saved_timeout).unwrap();
+
+        assert_eq!(bite_count(), before + 1);
+        let bite = last_bite().unwrap();
+        assert!(
+            spin.contains(&bite.rip),
+            "RIP {:#X} is not in {spin:#X?}",
+            bite.rip
+        );
+        assert!(bite.stalled_ms >= 20, "stalled_ms = {}", bite.stalled_ms);
+    }
+}
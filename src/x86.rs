@@ -1,28 +1,158 @@
 extern crate alloc;
 
+use crate::apic;
+use crate::cursor_blink;
 use crate::error;
+use crate::gdt::KERNEL_CS;
 use crate::info;
+use crate::paging;
+use crate::paging::PML4;
+use crate::pic;
+use crate::pic::IRQ_VECTOR_BASE;
+use crate::profiler;
 use crate::result::Result;
+use crate::smp;
+use crate::stack;
+use crate::timer;
+use crate::vmm;
+use crate::watchdog;
 use alloc::boxed::Box;
 use core::arch::asm;
 use core::arch::global_asm;
 use core::fmt;
-use core::marker::PhantomData;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::mem::size_of_val;
 use core::pin::Pin;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
 }
 
+// 割り込みを許可する
+pub fn enable_interrupts() {
+    unsafe { asm!("sti") }
+}
+
+// 割り込みを禁止する
+pub fn disable_interrupts() {
+    unsafe { asm!("cli") }
+}
+
+// 割り込みを許可して、次の割り込みが来るまで休む
+// stiの直後の1命令は割り込みが入らないので、stiとhltの間で割り込みを取りこぼすことはない
+pub fn hlt_with_interrupts() {
+    unsafe { asm!("sti", "hlt") }
+}
+
+// 割り込みを禁止して、Dropで元の状態に戻すガード
+// 元の状態を覚えているので、入れ子にしても外側のガードが外れるまで割り込みは禁止されたまま
+pub struct InterruptGuard {
+    was_enabled: bool,
+}
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let was_enabled = interrupts_enabled();
+        disable_interrupts();
+        Self { was_enabled }
+    }
+}
+impl Default for InterruptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        if self.was_enabled {
+            enable_interrupts();
+        }
+    }
+}
+
+// CPUIDの結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuidResult {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+// CPUID命令を実行する
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        // rbxはLLVMが使っているので退避してからcpuidを実行する
+        asm!("mov {0:r}, rbx",
+            "cpuid",
+            "xchg {0:r}, rbx",
+            out(reg) ebx,
+            inout("eax") leaf => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx)
+    }
+    CpuidResult { eax, ebx, ecx, edx }
+}
+
+// TSC（Time Stamp Counter）を読む
+// lfenceで前の命令が終わるのを待ってから読むので、計測区間の命令と入れ替わらない
+pub fn rdtsc() -> u64 {
+    let (high, low): (u32, u32);
+    unsafe {
+        asm!("lfence",
+            "rdtsc",
+            out("eax") low,
+            out("edx") high)
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+// MSR（Model Specific Register）を読む
+/// # Safety
+/// このCPUにないMSRを読むと#GPになるので、あることを確かめてから呼ぶこと
+/// 普段はmsrモジュールの型付きのラッパーを使う
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let (high, low): (u32, u32);
+    asm!("rdmsr",
+        in("ecx") msr,
+        out("eax") low,
+        out("edx") high);
+    ((high as u64) << 32) | low as u64
+}
+
+// MSRに書き込む
+/// # Safety
+/// MSRへの書き込みはCPUの動作を何でも変えられるので、書き込む値の意味を確かめてから呼ぶこと
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32)
+}
+
+// RFLAGSのIFビットを見て、割り込みが許可されているかを返す
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq",
+            "pop {}",
+            out(reg) rflags)
+    }
+    rflags & (1 << 9) != 0
+}
+
 // ポーズ状態
 pub fn busy_loop_hint() {
     unsafe { asm!("pause") }
 }
 
 // ポートから文字を入力
+#[inline]
 pub fn read_io_port_u8(port: u16) -> u8 {
     let mut data: u8;
     unsafe {
@@ -33,6 +163,7 @@ pub fn read_io_port_u8(port: u16) -> u8 {
     data
 }
 
+#[inline]
 pub fn write_io_port_u8(port: u16, data: u8) {
     unsafe {
         asm!("out dx, al",
@@ -42,148 +173,203 @@ pub fn write_io_port_u8(port: u16, data: u8) {
     }
 }
 
-pub fn read_cr3() -> *mut PML4 {
-    let mut cr3: *mut PML4;
-
+// ポートから2バイト読む
+#[inline]
+pub fn read_io_port_u16(port: u16) -> u16 {
+    let mut data: u16;
     unsafe {
-        // cr3レジスタからページテーブルの先頭アドレスをraxに入れてそれを変数のcr3に代入
-        asm!("mov rax, cr3",
-            out("rax") cr3)
+        asm!("in ax, dx",
+            out("ax") data,
+            in("dx") port)
     }
-    cr3
+    data
 }
 
-pub const PAGE_SIZE: usize = 4096; // ページサイズは4KB
-const ATTR_MASK: u64 = 0xFFF; // 右24bit全て1のマスク　（23 20 C0 BF 00 00 00 00 00 00 00 00 00 00 00 00）の時（23 20 C0 BF）を取りたい
-const ATTR_PRESENT: u64 = 1 << 0; // 内容が有効なエントリのbit
-const ATTR_WRITABLE: u64 = 1 << 1; // 書き込み可能かのbit
-const ATTR_WRITE_THROUGH: u64 = 1 << 3; // 書き込みキャッシュの挙動bit
-const ATTR_CACHE_DISABLE: u64 = 1 << 4; // キャッシュが有効かのbit
+#[inline]
+pub fn write_io_port_u16(port: u16, data: u16) {
+    unsafe {
+        asm!("out dx, ax",
+                in("ax") data,
+                in("dx") port,
+        )
+    }
+}
 
-#[derive(Debug, Copy, Clone)]
-#[repr(u64)]
-// ページ属性
-pub enum PageAttr {
-    NotPresent = 0,
-    ReadWriteKernel = ATTR_PRESENT | ATTR_WRITABLE,
-    ReadWriteIo = ATTR_PRESENT | ATTR_WRITABLE | ATTR_WRITE_THROUGH | ATTR_CACHE_DISABLE,
+// ポートから4バイト読む（PCIのコンフィギュレーション空間などで使う）
+#[inline]
+pub fn read_io_port_u32(port: u16) -> u32 {
+    let mut data: u32;
+    unsafe {
+        asm!("in eax, dx",
+            out("eax") data,
+            in("dx") port)
+    }
+    data
 }
 
-#[derive(Debug, Eq, PartialEq)]
-// 変換先の状態
-pub enum TranslationResult {
-    PageMapped4K { phys: u64 },
-    PageMapped2M { phys: u64 },
-    PageMapped1G { phys: u64 },
+#[inline]
+pub fn write_io_port_u32(port: u16, data: u32) {
+    unsafe {
+        asm!("out dx, eax",
+                in("eax") data,
+                in("dx") port,
+        )
+    }
 }
 
-#[repr(transparent)]
-pub struct Entry<const LEVEL: usize, const SHIFT: usize, NEXT> {
-    value: u64,
-    next_type: PhantomData<NEXT>,
+// 使われていないポート0x80に書き込んで、少しだけ待つ
+// 古いデバイスは連続したI/Oに追いつけないことがある
+#[inline]
+pub fn io_delay() {
+    write_io_port_u8(0x80, 0);
 }
-impl<const LEVEL: usize, const SHIFT: usize, NEXT> Entry<LEVEL, SHIFT, NEXT> {
-    fn read_value(&self) -> u64 {
-        self.value
-    }
-    // 一番右のビットの判定
-    // 1ならこのエントリの内容が有効
-    fn is_present(&self) -> bool {
-        (self.read_value() & (1 << 0)) != 0
+
+// delay_usで使う、1マイクロ秒あたりのTSCのサイクル数（0なら未キャリブレーション）
+static DELAY_TSC_PER_US: AtomicU64 = AtomicU64::new(0);
+// キャリブレーション前や失敗したときに使う値
+// 10GHzとみなしておけば、実際のCPUでは指定より長く待つことはあっても短くはならない
+const FALLBACK_TSC_PER_US: u64 = 10_000;
+
+// PITをポーリングしてdelay_us用にTSCをキャリブレーションする
+// 割り込みを使わないので、init_basic_runtimeの最初の方で呼べる
+pub fn init_delay() {
+    match timer::measure_tsc_per_us_polled() {
+        Some(per_us) => {
+            DELAY_TSC_PER_US.store(per_us, Ordering::Relaxed);
+            info!("delay_us: {per_us} TSC cycles/us");
+        }
+        None => {
+            error!("delay_us: PIT calibration failed, assuming {FALLBACK_TSC_PER_US} cycles/us");
+            DELAY_TSC_PER_US.store(FALLBACK_TSC_PER_US, Ordering::Relaxed);
+        }
     }
+}
 
-    // 右から2番目のビットの判定
-    // 1なら書き込みアクセス可能
-    fn is_writable(&self) -> bool {
-        (self.read_value() & (1 << 1)) != 0
+pub fn delay_tsc_per_us() -> u64 {
+    match DELAY_TSC_PER_US.load(Ordering::Relaxed) {
+        0 => FALLBACK_TSC_PER_US,
+        per_us => per_us,
     }
+}
 
-    // 右から3番目のビットの判定
-    // 1ならユーザーランドからのアクセス可能
-    fn is_user(&self) -> bool {
-        (self.read_value() & (1 << 2)) != 0
+// TSCを見ながら、指定したマイクロ秒だけ待つ
+// 割り込みもヒープも使わないので、どの段階からでも呼べる
+pub fn delay_us(us: u64) {
+    let start = rdtsc();
+    let cycles = us.saturating_mul(delay_tsc_per_us());
+    while rdtsc().wrapping_sub(start) < cycles {
+        busy_loop_hint();
     }
+}
 
-    // 出力フォーマットの設定
-    fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "L{}Entry @ {:#p} {{ {:#018X} {}{}{} ",
-            LEVEL,
-            self,
-            self.read_value(),
-            if self.is_present() { "P" } else { "N" },
-            if self.is_writable() { "W" } else { "R" },
-            if self.is_user() { "U" } else { "S" }
-        )?;
-        write!(f, " }}")
-    }
-
-    // 次のページテーブルを取得
-    fn table(&self) -> Result<&NEXT> {
-        if self.is_present() {
-            // マスクで下位12ビットを無視したアドレスをとってきてそれをポインタにしてOkでラップ
-            Ok(unsafe { &*((self.value & !ATTR_MASK) as *const NEXT) })
-        } else {
-            Err("Page Not Found")
-        }
+pub fn delay_ms(ms: u64) {
+    delay_us(ms.saturating_mul(1000));
+}
+
+pub fn read_cr3() -> *mut PML4 {
+    let mut cr3: *mut PML4;
+
+    unsafe {
+        // cr3レジスタからページテーブルの先頭アドレスをraxに入れてそれを変数のcr3に代入
+        asm!("mov rax, cr3",
+            out("rax") cr3)
     }
+    cr3
 }
 
-// Displayトレイトの実装
-// 構造体や列挙型のto_stringが可能になる？
-impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Display for Entry<LEVEL, SHIFT, NEXT> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.format(f)
+/// # Safety
+/// The given table must map the code, stack and data currently in use.
+/// ページテーブルを切り替える
+pub unsafe fn write_cr3(table: *mut PML4) {
+    asm!("mov cr3, rax",
+        in("rax") table)
+}
+
+// addrを含むページのTLBのエントリを捨てる（このCPUの分だけ）
+pub fn invlpg(addr: u64) {
+    unsafe { asm!("invlpg [{}]", in(reg) addr) }
+}
+
+// キャッシュの内容をメモリに書き戻してから無効にする（PATを書き換えるときなどに使う）
+pub fn wbinvd() {
+    unsafe { asm!("wbinvd") }
+}
+
+pub fn read_cr0() -> u64 {
+    let mut cr0: u64;
+    unsafe {
+        asm!("mov rax, cr0",
+            out("rax") cr0)
     }
+    cr0
 }
 
-// Debugトレイトの実装
-// println!("{:#?}")が使えるようになる？
-impl<const LEVEL: usize, const SHIFT: usize, NEXT> fmt::Debug for Entry<LEVEL, SHIFT, NEXT> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.format(f)
+pub fn read_cr4() -> u64 {
+    let mut cr4: u64;
+    unsafe {
+        asm!("mov rax, cr4",
+            out("rax") cr4)
     }
+    cr4
 }
 
-#[repr(align(4096))]
-// ページテーブル
-pub struct Table<const LEVEL: usize, const SHIFT: usize, NEXT> {
-    entry: [Entry<LEVEL, SHIFT, NEXT>; 512],
+/// # Safety
+/// Changing CR0 can disable paging or protection entirely.
+pub unsafe fn write_cr0(cr0: u64) {
+    asm!("mov cr0, rax",
+        in("rax") cr0)
 }
-impl<const LEVEL: usize, const SHIFT: usize, NEXT: core::fmt::Debug> Table<LEVEL, SHIFT, NEXT> {
-    // 出力フォーマット
-    fn format(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "L{}Table @ {:#p} {{", LEVEL, self)?;
-        for (i, e) in self.entry.iter().enumerate() {
-            if !e.is_present() {
-                continue;
-            }
-            writeln!(f, " entry[{:3}] = {:?}", i, e)?;
-        }
-        writeln!(f, "}}")
+
+// 現在のCSセレクタを読む
+pub fn read_cs() -> u16 {
+    let mut cs: u16;
+    unsafe {
+        asm!("mov {0:x}, cs",
+            out(reg) cs)
     }
+    cs
+}
 
-    // 次のテーブルを取得
-    pub fn next_level(&self, index: usize) -> Option<&NEXT> {
-        // entryから指定したindexのエントリーを取得してその中のページテーブルを取得
-        self.entry.get(index).and_then(|e| e.table().ok())
+/// # Safety
+/// [ptr, ptr + len)に書き込めること
+// [ptr, ptr + len)を0で埋める
+// 8バイト単位の部分はrep stosqでまとめて埋め、端数は1バイトずつ埋める
+pub unsafe fn fill_zero(ptr: *mut u8, len: usize) {
+    let qwords = len / 8;
+    asm!("rep stosq",
+        inout("rcx") qwords => _,
+        inout("rdi") ptr => _,
+        in("rax") 0u64,
+        options(nostack, preserves_flags));
+    for i in qwords * 8..len {
+        ptr.add(i).write(0);
     }
 }
 
-// Debugトレイトの実装
-impl<const LEVEL: usize, const SHIFT: usize, NEXT: fmt::Debug> fmt::Debug
-    for Table<LEVEL, SHIFT, NEXT>
-{
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.format(f)
+// 今の関数のフレームポインタ（rbp）とスタックポインタ（rsp）を読む
+// 呼び出し元の関数のフレームを読むように、必ずインライン展開する
+#[inline(always)]
+pub fn read_rbp_rsp() -> (u64, u64) {
+    let rbp: u64;
+    let rsp: u64;
+    unsafe {
+        asm!("mov {0}, rbp",
+            "mov {1}, rsp",
+            out(reg) rbp,
+            out(reg) rsp)
     }
+    (rbp, rsp)
 }
 
-pub type PT = Table<1, 12, [u8; PAGE_SIZE]>; // Level1
-pub type PD = Table<2, 21, PT>; // Level2
-pub type PDPT = Table<3, 30, PD>; // Level3
-pub type PML4 = Table<4, 39, PDPT>; // Level4
+// 現在のSSセレクタを読む
+pub fn read_ss() -> u16 {
+    let mut ss: u16;
+    unsafe {
+        asm!("mov {0:x}, ss",
+            out(reg) ss)
+    }
+    ss
+}
 
 /// # Safety
 /// Anything can happen if the given selector is invalid.
@@ -401,20 +587,58 @@ macro_rules! interrupt_entrypoint_with_ecode {
     };
 }
 
+interrupt_entrypoint!(0);
 interrupt_entrypoint!(3);
 interrupt_entrypoint!(6);
 interrupt_entrypoint_with_ecode!(8);
 interrupt_entrypoint_with_ecode!(13);
 interrupt_entrypoint_with_ecode!(14);
 interrupt_entrypoint!(32);
+interrupt_entrypoint!(33);
+interrupt_entrypoint!(34);
+interrupt_entrypoint!(35);
+interrupt_entrypoint!(36);
+interrupt_entrypoint!(37);
+interrupt_entrypoint!(38);
+interrupt_entrypoint!(39);
+interrupt_entrypoint!(40);
+interrupt_entrypoint!(41);
+interrupt_entrypoint!(42);
+interrupt_entrypoint!(43);
+interrupt_entrypoint!(44);
+interrupt_entrypoint!(45);
+interrupt_entrypoint!(46);
+interrupt_entrypoint!(47);
+interrupt_entrypoint!(48);
+interrupt_entrypoint!(49);
+interrupt_entrypoint!(255);
 
 extern "sysv64" {
+    fn interrupt_entrypoint0();
     fn interrupt_entrypoint3();
     fn interrupt_entrypoint6();
     fn interrupt_entrypoint8();
     fn interrupt_entrypoint13();
     fn interrupt_entrypoint14();
     fn interrupt_entrypoint32();
+    fn interrupt_entrypoint33();
+    fn interrupt_entrypoint34();
+    fn interrupt_entrypoint35();
+    fn interrupt_entrypoint36();
+    fn interrupt_entrypoint37();
+    fn interrupt_entrypoint38();
+    fn interrupt_entrypoint39();
+    fn interrupt_entrypoint40();
+    fn interrupt_entrypoint41();
+    fn interrupt_entrypoint42();
+    fn interrupt_entrypoint43();
+    fn interrupt_entrypoint44();
+    fn interrupt_entrypoint45();
+    fn interrupt_entrypoint46();
+    fn interrupt_entrypoint47();
+    fn interrupt_entrypoint48();
+    fn interrupt_entrypoint49();
+    fn interrupt_entrypoint255();
 }
 
 // 外側からでも認識できるラベルの設定
@@ -487,12 +711,82 @@ pub fn read_cr2() -> u64 {
     cr2
 }
 
+// 最後に発生した例外の番号（まだ発生していなければusize::MAX）
+// 例外ハンドラが本当に呼ばれたかをテストから確認するために使う
+static LAST_EXCEPTION: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+pub fn last_exception() -> Option<usize> {
+    match LAST_EXCEPTION.load(Ordering::SeqCst) {
+        usize::MAX => None,
+        index => Some(index),
+    }
+}
+
+// 最後に発生した例外の番号を取り出して、まだ発生していない状態に戻す
+pub fn take_last_exception() -> Option<usize> {
+    match LAST_EXCEPTION.swap(usize::MAX, Ordering::SeqCst) {
+        usize::MAX => None,
+        index => Some(index),
+    }
+}
+
+fn on_timer_tick(interrupted: &watchdog::InterruptedContext) {
+    profiler::on_timer_tick(interrupted.rip);
+    watchdog::on_timer_tick(interrupted);
+    cursor_blink::on_timer_tick(timer::ticks());
+}
+
 // 各割り込み番号に対しての処理
 #[no_mangle]
 extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
-    error!("Interrupt Info: {:?}", info);
+    // ハードウェア割り込み（IRQ）は登録されたハンドラに任せて、例外処理はしない
+    // タイマー割り込みでは、割り込まれた場所をウォッチドッグとプロファイラに渡す
+    let interrupted = watchdog::InterruptedContext {
+        rip: info.ctx.rip,
+        rsp: info.ctx.rsp,
+        rbp: info.greg.rbp,
+    };
+    if let Some(irq) = pic::irq_from_vector(index) {
+        pic::dispatch_irq(irq);
+        if irq == timer::PIT_IRQ {
+            on_timer_tick(&interrupted);
+        }
+        return;
+    }
+    match index {
+        apic::TIMER_VECTOR => {
+            apic::dispatch_timer();
+            on_timer_tick(&interrupted);
+            return;
+        }
+        apic::TLB_SHOOTDOWN_VECTOR => {
+            smp::handle_tlb_shootdown();
+            return;
+        }
+        // APICの偽の割り込みにはEOIを送らずにそのまま戻る
+        apic::SPURIOUS_VECTOR => return,
+        _ => {}
+    }
+    // 存在しないページへのフォルトは、map_lazyの範囲ならページをマップしてそのままやり直す
+    if index == 14 && info.error_code & 0b0001 == 0 && vmm::KERNEL_VMM.handle_page_fault(read_cr2())
+    {
+        return;
+    }
+    // 存在するページへの書き込みのフォルトは、コピーオンライトのページならコピーしてそのままやり直す
+    if index == 14 && info.error_code & 0b0011 == 0b0011 && paging::handle_cow_fault(read_cr2()) {
+        return;
+    }
+    LAST_EXCEPTION.store(index, Ordering::SeqCst);
     error!("Exception {index:#04X}: ");
+    error!(
+        "error_code: {:#018X}, RIP: {:#018X}, RSP: {:#018X}",
+        info.error_code, info.ctx.rip, info.ctx.rsp
+    );
+    error!("Interrupt Info: {:?}", info);
     match index {
+        0 => {
+            error!("Divide Error");
+        }
         3 => {
             error!("Breakpoint");
             return;
@@ -502,6 +796,11 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
         }
         8 => {
             error!("Double Fault");
+            // 割り込みのスタックが溢れると、ページフォルトを処理できずにダブルフォルトになる
+            let cr2 = read_cr2();
+            if stack::is_guard_page(cr2) {
+                panic!("interrupt stack overflow: CR2={cr2:#018X} is in a guard page");
+            }
         }
         13 => {
             error!("General Protection Fault");
@@ -514,7 +813,12 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
         14 => {
             crate::println!("This is page fault!");
             error!("Page Fault");
-            error!("CR2={:#018X}", read_cr2());
+            let cr2 = read_cr2();
+            error!("CR2={cr2:#018X}");
+            // ページフォルトのハンドラはISTのスタックで動くので、カーネルのスタックが溢れても呼ばれる
+            if stack::is_guard_page(cr2) {
+                panic!("kernel stack overflow: CR2={cr2:#018X} is in the guard page");
+            }
             error!(
                 "Caused by: A {} mode {} on a {} page, page structures are {}",
                 if info.error_code & 0b0000_0100 != 0 {
@@ -540,6 +844,15 @@ extern "sysv64" fn inthandler(info: &InterruptInfo, index: usize) {
                     "valid"
                 },
             );
+            // 存在するページへの書き込みや実行のフォルトは、W^Xで禁止したアクセス
+            if info.error_code & 0b0001 != 0 && info.error_code & 0b0010 != 0 {
+                panic!("W^X violation: write to a read-only page at CR2={cr2:#018X}");
+            }
+            if info.error_code & 0b0001 != 0 && info.error_code & 0b1_0000 != 0 {
+                panic!("W^X violation: instruction fetch from a no-exec page at CR2={cr2:#018X}");
+            }
+            // 記録した例外の番号とCR2をメッセージに入れて、テストで確かめられるようにする
+            panic!("fatal exception {index:#04X}: page fault at CR2={cr2:#018X}");
         }
         _ => {
             error!("Not handled");
@@ -638,6 +951,12 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             int_handler_unimplemented,
         ); 0x100];
+        entries[0] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint0,
+        );
         entries[3] = IdtDescriptor::new(
             segment_selector,
             1,
@@ -652,6 +971,7 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint6,
         );
+        // ダブルフォルトは、ページフォルトなどのハンドラのスタックが壊れていても動くように別のISTを使う
         entries[8] = IdtDescriptor::new(
             segment_selector,
             2,
@@ -664,263 +984,208 @@ impl Idt {
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint13,
         );
+        // ページフォルトはVmmの遅延マップやコピーオンライトで普通に起きて戻るので、
+        // IRQのハンドラの中で起きても割り込まれたハンドラのフレームを上書きしないように、別のISTを使う
         entries[14] = IdtDescriptor::new(
             segment_selector,
-            1,
+            3,
             IdtAttr::IntGateDPL0,
             interrupt_entrypoint14,
         );
-        entries[32] = IdtDescriptor::new(
+        // PICからのIRQ0~15（ベクタ0x20~0x2f）
+        let irq_entrypoints: [unsafe extern "sysv64" fn(); 16] = [
+            interrupt_entrypoint32,
+            interrupt_entrypoint33,
+            interrupt_entrypoint34,
+            interrupt_entrypoint35,
+            interrupt_entrypoint36,
+            interrupt_entrypoint37,
+            interrupt_entrypoint38,
+            interrupt_entrypoint39,
+            interrupt_entrypoint40,
+            interrupt_entrypoint41,
+            interrupt_entrypoint42,
+            interrupt_entrypoint43,
+            interrupt_entrypoint44,
+            interrupt_entrypoint45,
+            interrupt_entrypoint46,
+            interrupt_entrypoint47,
+        ];
+        for (i, f) in irq_entrypoints.iter().enumerate() {
+            entries[IRQ_VECTOR_BASE + i] =
+                IdtDescriptor::new(segment_selector, 1, IdtAttr::IntGateDPL0, *f);
+        }
+        entries[apic::TIMER_VECTOR] = IdtDescriptor::new(
             segment_selector,
             1,
             IdtAttr::IntGateDPL0,
-            interrupt_entrypoint32,
+            interrupt_entrypoint48,
+        );
+        entries[apic::TLB_SHOOTDOWN_VECTOR] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint49,
         );
-        let limit = size_of_val(&entries) as u16;
-        let entries = Box::pin(entries);
+        entries[apic::SPURIOUS_VECTOR] = IdtDescriptor::new(
+            segment_selector,
+            1,
+            IdtAttr::IntGateDPL0,
+            interrupt_entrypoint255,
+        );
+        let idt = Self {
+            entries: Box::pin(entries),
+        };
+        idt.load();
+        idt
+    }
+
+    // このCPUにIDTを読み込む
+    fn load(&self) {
         let params = IdtrParameters {
-            limit,
-            base: entries.as_ptr(),
+            limit: size_of_val(&*self.entries) as u16,
+            base: self.entries.as_ptr(),
         };
         info!("Loading IDT: {params:?}");
         // SAFETY: This is safe since it loads a valid IDT that is constructed
-        // in the code just above
+        // in Idt::new and kept alive in IDT
         // paramsをCPU内部のIDTに設定
         unsafe {
             asm!("lidt [rcx]",
                 in("rcx") &params);
         }
-        Self { entries }
     }
 }
 
-// 割り込みの状態管理
-// IST(Interrupt Stack Table)を持っていたりする
-#[repr(C, packed)]
-struct TaskStateSegment64Inner {
-    _reserved0: u32,
-    _rsp: [u64; 3], // for switch into ring0-2
-    _ist: [u64; 8], // ist[1]~ist[7] (ist[0] is reserved)
-    _reserved1: [u16; 5],
-    _io_map_base_addr: u16,
-}
-const _: () = assert!(size_of::<TaskStateSegment64Inner>() == 104);
-// innerを導入している理由は不明
-pub struct TaskStateSegment64 {
-    inner: Pin<Box<TaskStateSegment64Inner>>,
-}
-impl TaskStateSegment64 {
-    // 物理アドレスの取得
-    pub fn phys_addr(&self) -> u64 {
-        self.inner.as_ref().get_ref() as *const TaskStateSegment64Inner as u64
-    }
-    // istをメモリ上に配置
-    unsafe fn alloc_interrupt_stack() -> u64 {
-        const HANDLER_STACK_SIZE: usize = 64 * 1024;
-        let stack = Box::new([0u8; HANDLER_STACK_SIZE]);
-        let rsp = unsafe { stack.as_ptr().add(HANDLER_STACK_SIZE) as u64 };
-        core::mem::forget(stack);
-        // now, no one except us own the region since it is forgotten by the
-        // allocator ;)
-        rsp
-    }
-    // TSSの作成
-    pub fn new() -> Self {
-        let rsp0 = unsafe { Self::alloc_interrupt_stack() };
-        let mut ist = [0u64; 8];
-        for ist in ist[1..=7].iter_mut() {
-            *ist = unsafe { Self::alloc_interrupt_stack() };
-        }
-        let tss64 = TaskStateSegment64Inner {
-            _reserved0: 0,
-            _rsp: [rsp0, 0, 0],
-            _ist: ist,
-            _reserved1: [0; 5],
-            _io_map_base_addr: 0,
-        };
-        let this = Self {
-            inner: Box::pin(tss64),
-        };
-        info!("TSS64 created @ {:#X}", this.phys_addr(),);
-        this
-    }
+// 初期化したIDTの置き場所
+// カーネルが動いている間はずっと参照され続けるので、staticに保持して解放されないようにする
+static IDT: Mutex<Option<Idt>> = Mutex::new(None);
+
+// 例外ハンドラを設定したIDTを読み込む
+// これ以降の例外はトリプルフォルトではなくinthandlerでレジスタがダンプされる
+// ISTのスタックはTSSにあるので、先にinit_gdt()を呼んでおくこと
+pub fn init_idt() {
+    *IDT.lock() = Some(Idt::new(KERNEL_CS));
 }
 
-impl Default for TaskStateSegment64 {
-    fn default() -> Self {
-        Self::new()
-    }
+// BSPがinit_idtで作ったIDTを、このCPU（AP）にも読み込む
+// ISTのスタックはTSSにあるので、先にこのCPUのGDTとTSSを読み込んでおくこと
+pub fn load_idt() -> Result<()> {
+    IDT.lock().as_ref().ok_or("IDT is not initialized")?.load();
+    Ok(())
 }
 
-// そりゃdropしたら割り込みができなくなるからね
-// dropしたときにpanicを起こすようになっている
-impl Drop for TaskStateSegment64 {
-    fn drop(&mut self) {
-        panic!("TSS64 being dropped!");
+// 現在読み込まれているIDTの先頭アドレス
+pub fn read_idtr_base() -> u64 {
+    let mut params = IdtrParameters {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    unsafe {
+        asm!("sidt [rcx]",
+            in("rcx") &mut params);
     }
+    params.base as u64
 }
 
-// Gdtの初期化
-// cs,ss,es,ds,fs,gsを初期化している
-// それぞれがどのような値を持つのかは不明
-pub fn init_exceptions() -> (GdtWrapper, Idt) {
-    let gdt = GdtWrapper::default();
-    gdt.load();
+// 0除算を実行して#DE（Divide Error）を発生させる
+// Rustの除算は0除算をチェックしてしまうので、div命令を直接使う
+pub fn trigger_divide_error() {
     unsafe {
-        write_cs(KERNEL_CS);
-        write_ss(KERNEL_DS);
-        write_es(KERNEL_DS);
-        write_ds(KERNEL_DS);
-        write_fs(KERNEL_DS);
-        write_gs(KERNEL_DS);
+        asm!("div {0:e}",
+            in(reg) 0u32,
+            inout("eax") 1u32 => _,
+            inout("edx") 0u32 => _)
     }
-    let idt = Idt::new(KERNEL_CS);
-    (gdt, idt)
 }
 
-// ここはよくわからない
-pub const BIT_TYPE_DATA: u64 = 0b10u64 << 43;
-pub const BIT_TYPE_CODE: u64 = 0b11u64 << 43;
-
-pub const BIT_PRESENT: u64 = 1u64 << 47;
-pub const BIT_CS_LONG_MODE: u64 = 1u64 << 53;
-pub const BIT_CS_READABLE: u64 = 1u64 << 53;
-pub const BIT_DS_WRITABLE: u64 = 1u64 << 41;
-pub const BIT_DPL0: u64 = 0u64 << 45;
-pub const BIT_DPL3: u64 = 3u64 << 45;
-
-// GDTの状態
-#[repr(u64)]
-enum GdtAttr {
-    KernelCode = BIT_TYPE_CODE | BIT_PRESENT | BIT_CS_LONG_MODE | BIT_CS_READABLE,
-    KernelData = BIT_TYPE_DATA | BIT_PRESENT | BIT_DS_WRITABLE,
+// int3を実行（INT3命令）これはdebugとかに使うやつかな？
+// まあ普通に割り込みできているかの確認かな
+pub fn trigger_debug_interrupt() {
+    unsafe { asm!("int3") }
 }
 
-// GDTの容量とポインタ
-#[allow(dead_code)]
-#[repr(C, packed)]
-struct GdtrParameters {
-    limit: u16,
-    base: *const Gdt,
+// 例外ハンドラが0除算を捕まえることを確認する
+// このテストは必ずpanicで終了するので、exception_tests featureを有効にした時だけ実行する
+#[cfg(all(test, feature = "exception_tests"))]
+#[test_case]
+fn divide_error_is_handled() {
+    trigger_divide_error();
+    unreachable!("#DE handler returned");
 }
 
-// ここはわからない
-pub const KERNEL_CS: u16 = 1 << 3;
-pub const KERNEL_DS: u16 = 2 << 3;
-pub const TSS64_SEL: u16 = 3 << 3;
+#[cfg(test)]
+mod test {
+    use super::*;
 
-// GDT構造体
-// それぞれのセグメントを持っている
-#[allow(dead_code)]
-#[repr(C, packed)]
-pub struct Gdt {
-    null_segment: GdtSegmentDescriptor,
-    kernel_code_segment: GdtSegmentDescriptor,
-    kernel_data_segment: GdtSegmentDescriptor,
-    task_state_segment: TaskStateSegment64Descriptor,
-}
-const _: () = assert!(size_of::<Gdt>() == 40);
-
-// なぜかWrapしている
-// PinだからGDTもアドレス固定？
-// tssも持っている
-// おそらくだけどWrapper経由でGDTを操作するのかな
-#[allow(dead_code)]
-pub struct GdtWrapper {
-    inner: Pin<Box<Gdt>>,
-    tss64: TaskStateSegment64,
-}
-
-impl GdtWrapper {
-    // lgdt(load GDT)でgdtを取得してparamsに入れてる？？
-    // ltrってなんだ？
-    pub fn load(&self) {
-        let params = GdtrParameters {
-            limit: (size_of::<Gdt>() - 1) as u16,
-            base: self.inner.as_ref().get_ref() as *const Gdt,
-        };
-        info!("Loading GDT @ {:#018X}", params.base as u64);
-        // SAFETY: This is safe since it is loading a valid GDT just constructed
-        // in the above
-        unsafe {
-            asm!("lgdt [rcx]",
-                in("rcx") &params);
-        }
-        info!("Loading TSS ( selector = {:#X} )", TSS64_SEL);
-        unsafe {
-            asm!("ltr cx",
-                in("cx") TSS64_SEL);
+    // ガードを入れ子にしても、一番外側が外れた時に元の状態に戻ることを確認する
+    #[test_case]
+    fn nested_interrupt_guards_restore_state() {
+        assert!(interrupts_enabled());
+        {
+            let _outer = InterruptGuard::new();
+            assert!(!interrupts_enabled());
+            {
+                let _inner = InterruptGuard::new();
+                assert!(!interrupts_enabled());
+            }
+            assert!(!interrupts_enabled());
         }
+        assert!(interrupts_enabled());
     }
-}
 
-// defaultトレイト実装でdefault()でGDTが作られるようになった
-impl Default for GdtWrapper {
-    fn default() -> Self {
-        let tss64 = TaskStateSegment64::new();
-        let gdt = Gdt {
-            null_segment: GdtSegmentDescriptor::null(),
-            kernel_code_segment: GdtSegmentDescriptor::new(GdtAttr::KernelCode),
-            kernel_data_segment: GdtSegmentDescriptor::new(GdtAttr::KernelData),
-            task_state_segment: TaskStateSegment64Descriptor::new(tss64.phys_addr()),
-        };
-        let gdt = Box::pin(gdt);
-        GdtWrapper { inner: gdt, tss64 }
+    // IRQや他の例外（IST1）とダブルフォルト（IST2）とは別のスタックでページフォルトを処理する
+    #[test_case]
+    fn page_fault_uses_its_own_interrupt_stack() {
+        let idt = Idt::new(KERNEL_CS);
+        let ist = |vector: usize| idt.entries[vector].ist_index;
+        assert_eq!(ist(14), 3);
+        assert_eq!(ist(8), 2);
+        assert!([0, 13, 0x20, apic::TIMER_VECTOR]
+            .iter()
+            .all(|&v| ist(v) == 1));
     }
-}
 
-// GDTの識別子
-pub struct GdtSegmentDescriptor {
-    value: u64,
-}
-impl GdtSegmentDescriptor {
-    // nullだと0番
-    const fn null() -> Self {
-        Self { value: 0 }
-    }
-    // それ以外だとGddAttrに依存
-    const fn new(attr: GdtAttr) -> Self {
-        Self { value: attr as u64 }
-    }
-}
-// 出力フォーマット
-impl fmt::Display for GdtSegmentDescriptor {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:#18X}", self.value)
+    // タイマーの割り込みでhltから戻ってくることを確認する
+    #[test_case]
+    fn hlt_with_interrupts_wakes_on_tick() {
+        let _guard = InterruptGuard::new();
+        let start = crate::timer::ticks();
+        while crate::timer::ticks() == start {
+            hlt_with_interrupts();
+        }
     }
-}
 
-// TSS構造体
-#[repr(C, packed)]
-#[allow(dead_code)]
-struct TaskStateSegment64Descriptor {
-    limit_low: u16,
-    base_low: u16,
-    base_mid_low: u8,
-    attr: u16,
-    base_mid_high: u8,
-    base_high: u32,
-    reserved: u32,
-}
-
-impl TaskStateSegment64Descriptor {
-    // TSSの作成
-    const fn new(base_addr: u64) -> Self {
-        Self {
-            limit_low: size_of::<TaskStateSegment64Inner>() as u16,
-            base_low: (base_addr & 0xffff) as u16,
-            base_mid_low: ((base_addr >> 16) & 0xff) as u8,
-            attr: 0b1000_0000_1000_1001,
-            base_mid_high: ((base_addr >> 24) & 0xff) as u8,
-            base_high: ((base_addr >> 32) & 0xffffffff) as u32,
-            reserved: 0,
-        }
+    // delay_ms(20)がTSCとは別のタイマー（HPET、なければPITのティック）でも20ms±10%になる
+    #[test_case]
+    fn delay_ms_agrees_with_independent_timer() {
+        let measured_us = if crate::hpet::is_available() {
+            let start = crate::hpet::now_ns();
+            delay_ms(20);
+            (crate::hpet::now_ns() - start) / 1000
+        } else {
+            // ティックの境目に揃えてから測る
+            let start = crate::timer::ticks();
+            while crate::timer::ticks() == start {
+                busy_loop_hint();
+            }
+            let start = crate::timer::uptime_ms();
+            delay_ms(20);
+            (crate::timer::uptime_ms() - start) * 1000
+        };
+        assert!(
+            (18_000..=22_000).contains(&measured_us),
+            "delay_ms(20) took {measured_us} us"
+        );
     }
-}
-const _: () = assert!(size_of::<TaskStateSegment64Descriptor>() == 16);
 
-// int3を実行（INT3命令）これはdebugとかに使うやつかな？
-// まあ普通に割り込みできているかの確認かな
-pub fn trigger_debug_interrupt() {
-    unsafe { asm!("int3") }
+    // PCIのコンフィギュレーションアドレスレジスタ（0xCF8）は書いた値がそのまま読める
+    // 4バイト単位でアクセスしないと値が保持されないので、32bitの経路の確認になる
+    #[test_case]
+    fn io_port_u32_roundtrip() {
+        let saved = read_io_port_u32(0xcf8);
+        write_io_port_u32(0xcf8, 0x8000_0810);
+        assert_eq!(read_io_port_u32(0xcf8), 0x8000_0810);
+        write_io_port_u32(0xcf8, saved);
+    }
 }